@@ -9,14 +9,36 @@ use std::{
 };
 
 use log::LevelFilter;
-use nusamai::parameters::Parameters;
+use nusamai::parameters::{BooleanParameter, ParameterType, Parameters};
 use nusamai::{
     pipeline::{feedback, Canceller},
     sink::{
-        cesiumtiles::CesiumTilesSinkProvider, czml::CzmlSinkProvider, geojson::GeoJsonSinkProvider,
-        gltf::GltfSinkProvider, gpkg::GpkgSinkProvider, kml::KmlSinkProvider,
-        minecraft::MinecraftSinkProvider, mvt::MvtSinkProvider, obj::ObjSinkProvider,
-        serde::SerdeSinkProvider, shapefile::ShapefileSinkProvider, DataSinkProvider,
+        cesiumtiles::CesiumTilesSinkProvider,
+        citygml::CityGmlSinkProvider,
+        cityjson::CityJsonSinkProvider,
+        csv::CsvSinkProvider,
+        czml::CzmlSinkProvider,
+        duckdb::DuckDbSinkProvider,
+        dxf::DxfSinkProvider,
+        fbx::FbxSinkProvider,
+        geojson::GeoJsonSinkProvider,
+        gltf::GltfSinkProvider,
+        gpkg::GpkgSinkProvider,
+        i3s::I3sSinkProvider,
+        kml::KmlSinkProvider,
+        las::LasSinkProvider,
+        manifest::write_directory_manifest,
+        minecraft::MinecraftSinkProvider,
+        mvt::MvtSinkProvider,
+        obj::ObjSinkProvider,
+        overwrite::{is_unknown_directory, prepare_output, OverwritePolicy},
+        parquet::GeoParquetSinkProvider,
+        roadnetwork::RoadNetworkSinkProvider,
+        serde::SerdeSinkProvider,
+        shadow::ShadowSinkProvider,
+        shapefile::ShapefileSinkProvider,
+        terrain::{QuantizedMeshSinkProvider, TerrainSinkProvider},
+        DataSinkProvider,
     },
     source::{citygml::CityGmlSourceProvider, DataSourceProvider},
     transformer::{
@@ -105,6 +127,8 @@ enum Error {
     Io(String),
     #[error("Invalid path: {0}")]
     InvalidPath(String),
+    #[error("Output directory is not empty: {0}")]
+    OutputNotEmpty(String),
     #[error("Invalid setting: {0}")]
     InvalidSetting(String),
     #[error("Invalid mapping rules: {0}")]
@@ -127,6 +151,7 @@ fn select_sink_provider(filetype: &str) -> Option<Box<dyn DataSinkProvider>> {
         "noop" => Some(Box::new(nusamai::sink::noop::NoopSinkProvider {})),
         "serde" => Some(Box::new(SerdeSinkProvider {})),
         "geojson" => Some(Box::new(GeoJsonSinkProvider {})),
+        "cityjson" => Some(Box::new(CityJsonSinkProvider {})),
         "gpkg" => Some(Box::new(GpkgSinkProvider {})),
         "mvt" => Some(Box::new(MvtSinkProvider {})),
         "shapefile" => Some(Box::new(ShapefileSinkProvider {})),
@@ -136,6 +161,18 @@ fn select_sink_provider(filetype: &str) -> Option<Box<dyn DataSinkProvider>> {
         "cesiumtiles" => Some(Box::new(CesiumTilesSinkProvider {})),
         "minecraft" => Some(Box::new(MinecraftSinkProvider {})),
         "obj" => Some(Box::new(ObjSinkProvider {})),
+        "terrain" => Some(Box::new(TerrainSinkProvider {})),
+        "quantizedmesh" => Some(Box::new(QuantizedMeshSinkProvider {})),
+        "parquet" => Some(Box::new(GeoParquetSinkProvider {})),
+        "csv" => Some(Box::new(CsvSinkProvider {})),
+        "shadow" => Some(Box::new(ShadowSinkProvider {})),
+        "i3s" => Some(Box::new(I3sSinkProvider {})),
+        "las" => Some(Box::new(LasSinkProvider {})),
+        "fbx" => Some(Box::new(FbxSinkProvider {})),
+        "dxf" => Some(Box::new(DxfSinkProvider {})),
+        "citygml" => Some(Box::new(CityGmlSinkProvider {})),
+        "duckdb" => Some(Box::new(DuckDbSinkProvider {})),
+        "roadnetwork" => Some(Box::new(RoadNetworkSinkProvider {})),
         _ => None,
     }
 }
@@ -150,6 +187,7 @@ fn run_conversion(
     rules_path: String,
     transformer_settings: TransformerSettings,
     sink_parameters: Parameters,
+    overwrite_confirmed: bool,
     tasks_state: tauri::State<ConversionTasksState>,
     app: tauri::AppHandle,
 ) -> Result<(), Error> {
@@ -181,7 +219,17 @@ fn run_conversion(
         log::info!("Created output directory: {:?}", output_parent_dir);
     }
 
-    let sinkopt: Vec<(String, String)> = vec![("@output".into(), output_path)];
+    // The intermediate files (e.g. the chunks of the external sort) are written into the managed directory
+    if let Err(err) = nusamai::workdir::prepare(None) {
+        let msg = format!(
+            "Failed to prepare the directory of the intermediate files: {}",
+            err
+        );
+        log::error!("{}", msg);
+        return Err(Error::Io(msg));
+    }
+
+    let mut sinkopt: Vec<(String, String)> = vec![("@output".into(), output_path)];
 
     log::info!("Running pipeline with input: {:?}", input_paths);
 
@@ -193,6 +241,43 @@ fn run_conversion(
         })?;
 
         let mut sink_params = sink_parameters;
+
+        // Overwriting is confirmed in the save dialog, while a GeoPackage with `update` or `append` is merged into
+        let update = ["update", "append"].iter().any(|key| {
+            matches!(
+                sink_params.get(key).map(|entry| &entry.parameter),
+                Some(ParameterType::Boolean(BooleanParameter {
+                    value: Some(true)
+                }))
+            )
+        });
+        let policy = match update {
+            true => OverwritePolicy::Merge,
+            false => OverwritePolicy::Overwrite,
+        };
+        // A non-empty directory that is not a previous output is not cleared, but written into as it is
+        // once the user confirms it
+        let unknown_directory =
+            policy == OverwritePolicy::Overwrite && is_unknown_directory(&output_path_buf);
+        if unknown_directory && !overwrite_confirmed {
+            return Err(Error::OutputNotEmpty(output_path_buf.display().to_string()));
+        }
+        if !unknown_directory {
+            match prepare_output(
+                &output_path_buf,
+                policy,
+                &sink_provider.info().id_name,
+                &sink_params,
+            ) {
+                Ok(options) => sinkopt.extend(options),
+                Err(err) => {
+                    let msg = err.to_string();
+                    log::error!("{}", msg);
+                    return Err(Error::InvalidPath(msg));
+                }
+            }
+        }
+
         if let Err(err) = sink_params.update_values_with_str(&sinkopt) {
             let msg = format!("Error parsing sink options: {:?}", err);
             log::error!("{}", msg);
@@ -208,6 +293,7 @@ fn run_conversion(
 
     let mut requirements = sink.make_requirements(transformer_settings);
     requirements.set_output_epsg(epsg);
+    let writes_manifest = sink.writes_manifest();
 
     let source = {
         let source_provider: Box<dyn DataSourceProvider> = Box::new(CityGmlSourceProvider {
@@ -250,11 +336,14 @@ fn run_conversion(
         let request = {
             let mut request = transformer::Request::from(requirements);
             request.set_mapping_rules(mapping_rules);
+            let large_attribute_dir = transformer::large_attribute_dir(&output_path_buf);
+            request.set_large_attribute_dir(Some(large_attribute_dir));
             request
         };
         let transform_builder = NusamaiTransformBuilder::new(request);
         let mut schema = nusamai_citygml::schema::Schema::default();
         TopLevelCityObject::collect_schema(&mut schema);
+        source.transform_schema(&mut schema);
         transform_builder.transform_schema(&mut schema);
         let transformer = Box::new(MultiThreadTransformer::new(transform_builder));
         (transformer, schema)
@@ -301,6 +390,11 @@ fn run_conversion(
         return Err(Error::Canceled);
     };
 
+    // Make the manifest (sizes and SHA-256 hashes) of the output directory for integrity checks
+    if !writes_manifest {
+        write_directory_manifest(&output_path_buf)?;
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,254 @@
+//! Client of the PLATEAU data catalog API
+//!
+//! The catalog lists the CityGML files of the PLATEAU datasets of each municipality and year, so the files can be
+//! downloaded by the city code (e.g. `13101`) instead of looking for the zip packages on the G空間情報センター.
+
+use std::{collections::BTreeMap, io::Read, time::Duration};
+
+use serde::Deserialize;
+use thiserror::Error;
+use url::Url;
+
+/// The endpoint of the PLATEAU data catalog API
+pub const DEFAULT_ENDPOINT: &str = "https://api.plateauview.mlit.go.jp/datacatalog/";
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// The maximum size of a response (the catalog of a large city lists thousands of files)
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum CatalogError {
+    #[error("Invalid city code: {0} (expected 5 digits, e.g. 13101)")]
+    InvalidCityCode(String),
+    #[error("Catalog request failed: {0}")]
+    Request(String),
+    #[error("Invalid catalog response: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+    #[error("Not found in the catalog: {0}")]
+    NotFound(String),
+}
+
+pub type Result<T> = std::result::Result<T, CatalogError>;
+
+/// The CityGML datasets of a municipality (one for each year)
+#[derive(Deserialize, Debug)]
+struct CitiesResponse {
+    cities: Vec<CityDataset>,
+}
+
+/// The CityGML dataset of a municipality in a year
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CityDataset {
+    pub city_code: String,
+    pub city_name: String,
+    pub year: u16,
+    /// The version of the PLATEAU product specification (e.g. `3.4`)
+    #[serde(default)]
+    pub spec: Option<String>,
+    /// The zip package of the whole dataset
+    #[serde(default)]
+    pub url: Option<Url>,
+    /// package (e.g. `bldg`) -> files
+    #[serde(default)]
+    pub files: BTreeMap<String, Vec<CityGmlFile>>,
+}
+
+/// A CityGML file of a dataset (one for each mesh, or the whole city)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CityGmlFile {
+    /// The mesh code (e.g. `53394525`) or the name of the file
+    pub code: String,
+    #[serde(default)]
+    pub max_lod: Option<u8>,
+    pub url: Url,
+}
+
+impl CityDataset {
+    /// URLs of the files of the packages (all the packages if `packages` is empty)
+    pub fn file_urls(&self, packages: &[String]) -> Vec<Url> {
+        self.files
+            .iter()
+            .filter(|(package, _)| packages.is_empty() || packages.contains(package))
+            .flat_map(|(_, files)| files.iter().map(|file| file.url.clone()))
+            .collect()
+    }
+}
+
+pub struct Catalog {
+    agent: ureq::Agent,
+    endpoint: Url,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self::with_endpoint(Url::parse(DEFAULT_ENDPOINT).unwrap())
+    }
+}
+
+impl Catalog {
+    pub fn with_endpoint(mut endpoint: Url) -> Self {
+        // (the paths are joined to the endpoint)
+        if !endpoint.path().ends_with('/') {
+            endpoint.set_path(&format!("{}/", endpoint.path()));
+        }
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            endpoint,
+        }
+    }
+
+    /// The datasets of the municipality, in the order of the years
+    pub fn city_datasets(&self, city_code: &str) -> Result<Vec<CityDataset>> {
+        if city_code.len() != 5 || !city_code.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(CatalogError::InvalidCityCode(city_code.to_string()));
+        }
+        let url = self
+            .endpoint
+            .join(&format!("citygml/c:{city_code}"))
+            .map_err(|err| CatalogError::Request(err.to_string()))?;
+
+        let response = match self.agent.get(url.as_str()).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => {
+                return Err(CatalogError::NotFound(format!("city {city_code}")))
+            }
+            Err(err) => return Err(CatalogError::Request(err.to_string())),
+        };
+        let mut body = Vec::new();
+        response
+            .into_reader()
+            .take(MAX_RESPONSE_SIZE)
+            .read_to_end(&mut body)
+            .map_err(|err| CatalogError::Request(err.to_string()))?;
+        parse_city_datasets(&body, city_code)
+    }
+
+    /// The dataset of the municipality in the year (the latest one if `year` is None)
+    pub fn city_dataset(&self, city_code: &str, year: Option<u16>) -> Result<CityDataset> {
+        select_dataset(self.city_datasets(city_code)?, city_code, year)
+    }
+}
+
+fn parse_city_datasets(body: &[u8], city_code: &str) -> Result<Vec<CityDataset>> {
+    let response: CitiesResponse = serde_json::from_slice(body)?;
+    let mut datasets: Vec<_> = response
+        .cities
+        .into_iter()
+        .filter(|dataset| dataset.city_code == city_code)
+        .collect();
+    datasets.sort_by_key(|dataset| dataset.year);
+    Ok(datasets)
+}
+
+fn select_dataset(
+    datasets: Vec<CityDataset>,
+    city_code: &str,
+    year: Option<u16>,
+) -> Result<CityDataset> {
+    let available = datasets
+        .iter()
+        .map(|dataset| dataset.year.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let dataset = match year {
+        Some(year) => datasets.into_iter().find(|dataset| dataset.year == year),
+        None => datasets.into_iter().next_back(),
+    };
+    dataset.ok_or_else(|| match year {
+        Some(year) if !available.is_empty() => CatalogError::NotFound(format!(
+            "city {city_code} in {year} (available years: {available})"
+        )),
+        _ => CatalogError::NotFound(format!("city {city_code}")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &str = r#"{
+        "cities": [
+            {
+                "cityCode": "13101",
+                "cityName": "千代田区",
+                "year": 2023,
+                "spec": "3.3",
+                "url": "https://example.com/13101_chiyoda-ku_city_2023_citygml_1_op.zip",
+                "files": {
+                    "bldg": [
+                        {"code": "53394525", "maxLod": 2, "url": "https://example.com/2023/udx/bldg/53394525_bldg_6697_op.gml"},
+                        {"code": "53394526", "maxLod": 1, "url": "https://example.com/2023/udx/bldg/53394526_bldg_6697_op.gml"}
+                    ],
+                    "tran": [
+                        {"code": "533945", "url": "https://example.com/2023/udx/tran/533945_tran_6697_op.gml"}
+                    ]
+                }
+            },
+            {
+                "cityCode": "13101",
+                "cityName": "千代田区",
+                "year": 2022,
+                "files": {
+                    "bldg": [
+                        {"code": "53394525", "maxLod": 2, "url": "https://example.com/2022/udx/bldg/53394525_bldg_6697_op.gml"}
+                    ]
+                }
+            },
+            {
+                "cityCode": "13102",
+                "cityName": "中央区",
+                "year": 2023,
+                "files": {}
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse_city_datasets() {
+        let datasets = parse_city_datasets(RESPONSE.as_bytes(), "13101").unwrap();
+        let years: Vec<_> = datasets.iter().map(|dataset| dataset.year).collect();
+        assert_eq!(years, [2022, 2023]);
+        assert_eq!(datasets[1].city_name, "千代田区");
+        assert_eq!(datasets[1].spec.as_deref(), Some("3.3"));
+        assert_eq!(datasets[1].files["bldg"][0].max_lod, Some(2));
+        assert_eq!(datasets[1].files["tran"][0].max_lod, None);
+
+        assert!(parse_city_datasets(b"{}", "13101").is_err());
+    }
+
+    #[test]
+    fn test_select_dataset() {
+        let datasets = parse_city_datasets(RESPONSE.as_bytes(), "13101").unwrap();
+        let latest = select_dataset(datasets.clone(), "13101", None).unwrap();
+        assert_eq!(latest.year, 2023);
+        assert_eq!(latest.file_urls(&[]).len(), 3);
+        let urls = latest.file_urls(&["tran".to_string()]);
+        assert_eq!(
+            urls[0].as_str(),
+            "https://example.com/2023/udx/tran/533945_tran_6697_op.gml"
+        );
+
+        let dataset = select_dataset(datasets.clone(), "13101", Some(2022)).unwrap();
+        assert_eq!(dataset.file_urls(&["bldg".to_string()]).len(), 1);
+
+        let err = select_dataset(datasets, "13101", Some(2020)).unwrap_err();
+        assert!(err.to_string().contains("available years: 2022, 2023"));
+        assert!(select_dataset(Vec::new(), "13199", None).is_err());
+    }
+
+    #[test]
+    fn test_invalid_city_code() {
+        let catalog = Catalog::default();
+        // (rejected before the request)
+        assert!(matches!(
+            catalog.city_datasets("1310"),
+            Err(CatalogError::InvalidCityCode(_))
+        ));
+        assert!(matches!(
+            catalog.city_datasets("13/01"),
+            Err(CatalogError::InvalidCityCode(_))
+        ));
+    }
+}
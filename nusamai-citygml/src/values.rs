@@ -90,12 +90,21 @@ impl CityGmlElement for Uri {
 pub struct Code {
     value: String,
     code: String,
-    // pub code_space: Option<String>,
+    #[serde(default)]
+    code_space: Option<String>,
 }
 
 impl Code {
     pub fn new(value: String, code: String) -> Self {
-        Self { value, code }
+        Self {
+            value,
+            code,
+            code_space: None,
+        }
+    }
+    pub fn with_code_space(mut self, code_space: Option<String>) -> Self {
+        self.code_space = code_space;
+        self
     }
     pub fn value(&self) -> &str {
         &self.value
@@ -103,6 +112,10 @@ impl Code {
     pub fn code(&self) -> &str {
         &self.code
     }
+    /// The `codeSpace` attribute (e.g. the URL of the codelist, or the language of a `gml:name`)
+    pub fn code_space(&self) -> Option<&str> {
+        self.code_space.as_deref()
+    }
 }
 
 impl CityGmlElement for Code {
@@ -111,6 +124,7 @@ impl CityGmlElement for Code {
         let code_space = st.find_codespace_attr();
         let code = st.parse_text()?.to_string();
         self.code.clone_from(&code);
+        self.code_space.clone_from(&code_space);
 
         if let Some(code_space) = code_space {
             let base_url = st.context().source_url();
@@ -165,6 +165,47 @@ fn parse_basic_types_invalid() {
     expect_invalid::<ColorPlusOpacity>(r#"<root>0.0 0.0 0.0 0.0 1.0</root>"#); // not valid colorPlusOpacity
 }
 
+#[test]
+fn parse_codes_with_code_space() {
+    #[derive(CityGmlElement, Default)]
+    struct Root {
+        #[citygml(path = b"name")]
+        names: Vec<values::Code>,
+    }
+
+    let mut xml_reader = quick_xml::NsReader::from_reader(std::io::Cursor::new(
+        r#"
+        <root>
+            <name codeSpace="ja">東京都庁</name>
+            <name>Tokyo Metropolitan Government Building</name>
+            <name codeSpace="ja-Kana">トウキョウトチョウ</name>
+        </root>
+        "#,
+    ));
+    let context = ParseContext::default();
+    match CityGmlReader::new(context).start_root(&mut xml_reader) {
+        Ok(mut st) => {
+            let mut root = Root::default();
+            root.parse(&mut st).unwrap();
+            // all the names are kept in the original order
+            let names: Vec<_> = root
+                .names
+                .iter()
+                .map(|name| (name.value(), name.code_space()))
+                .collect();
+            assert_eq!(
+                names,
+                [
+                    ("東京都庁", Some("ja")),
+                    ("Tokyo Metropolitan Government Building", None),
+                    ("トウキョウトチョウ", Some("ja-Kana")),
+                ]
+            );
+        }
+        Err(e) => panic!("Err: {:?}", e),
+    }
+}
+
 #[test]
 fn parse_duplicate_content() {
     let mut xml_reader = quick_xml::NsReader::from_reader(std::io::Cursor::new(
@@ -4,6 +4,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_repr::*;
 
+use super::extensions::buffer::{ExtMeshoptCompression, ExtMeshoptCompressionBuffer};
+
 #[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Eq, Clone, Copy, Default)]
 #[repr(u16)]
 pub enum BufferViewTarget {
@@ -40,6 +42,10 @@ pub struct Buffer {
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct BufferExtensions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "EXT_meshopt_compression")]
+    pub ext_meshopt_compression: Option<ExtMeshoptCompressionBuffer>,
+
     #[serde(flatten)]
     others: HashMap<String, Value>,
 }
@@ -70,6 +76,25 @@ pub struct BufferView {
     /// The hint representing the intended GPU buffer type to use with this buffer view.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub target: Option<BufferViewTarget>,
+
+    /// JSON object with extension-specific objects.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<BufferViewExtensions>,
+
+    /// Application-specific data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extras: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BufferViewExtensions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "EXT_meshopt_compression")]
+    pub ext_meshopt_compression: Option<ExtMeshoptCompression>,
+
+    #[serde(flatten)]
+    others: HashMap<String, Value>,
 }
 
 fn is_default<T: Default + PartialEq>(value: &T) -> bool {
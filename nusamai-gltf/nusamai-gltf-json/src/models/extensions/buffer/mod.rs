@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// EXT_meshopt_compression (buffer-level): marks the buffer as the fallback of the compressed buffer views
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct ExtMeshoptCompressionBuffer {
+    /// The buffer has no data, and the compressed buffer views must be decoded
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fallback: bool,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
+
+/// The codec mode of a compressed buffer view
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum MeshoptCompressionMode {
+    /// Vertex attributes
+    #[default]
+    Attributes,
+    /// Indices of a triangle list
+    Triangles,
+    /// Other index sequences (e.g. lines, points)
+    Indices,
+}
+
+/// EXT_meshopt_compression (bufferView-level): the compressed data of the buffer view
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtMeshoptCompression {
+    /// The index of the buffer with the compressed data
+    pub buffer: u32,
+
+    /// The offset into the buffer in bytes
+    #[serde(default)]
+    pub byte_offset: u32,
+
+    /// The length of the compressed data in bytes
+    pub byte_length: u32,
+
+    /// The stride, in bytes, between the elements
+    pub byte_stride: u32,
+
+    /// The number of the elements
+    pub count: u32,
+
+    pub mode: MeshoptCompressionMode,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
@@ -4,12 +4,18 @@ pub use cesiumtiles::models::gltf_extensions::gltf::ext_structural_metadata;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use super::mesh::khr_materials_variants;
+
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Gltf {
     #[serde(rename = "EXT_structural_metadata")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ext_structural_metadata: Option<ext_structural_metadata::ExtStructuralMetadata>,
 
+    #[serde(rename = "KHR_materials_variants")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub khr_materials_variants: Option<khr_materials_variants::KhrMaterialsVariants>,
+
     #[serde(flatten)]
     pub others: HashMap<String, Value>,
 }
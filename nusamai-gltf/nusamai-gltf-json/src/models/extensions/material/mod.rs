@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::models::TextureInfo;
+
+/// The strength and the color of the specular reflection of the dielectric materials (`KHR_materials_specular`)
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+#[serde(default)]
+pub struct KhrMaterialsSpecular {
+    /// The strength of the specular reflection.
+    #[serde(skip_serializing_if = "is_one")]
+    pub specular_factor: f64,
+
+    /// A texture that defines the strength of the specular reflection, stored in the alpha (`A`) channel. This will be multiplied by `specularFactor`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub specular_texture: Option<TextureInfo>,
+
+    /// The F0 color of the specular reflection (linear RGB).
+    #[serde(skip_serializing_if = "is_white")]
+    pub specular_color_factor: [f64; 3],
+
+    /// A texture that defines the F0 color of the specular reflection, stored in the `RGB` channels and encoded in sRGB. This texture will be multiplied by `specularColorFactor`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub specular_color_texture: Option<TextureInfo>,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
+
+impl Default for KhrMaterialsSpecular {
+    fn default() -> Self {
+        Self {
+            specular_factor: 1.0,
+            specular_texture: None,
+            specular_color_factor: [1.0, 1.0, 1.0],
+            specular_color_texture: None,
+            others: HashMap::new(),
+        }
+    }
+}
+
+fn is_one(v: &f64) -> bool {
+    *v == 1.0
+}
+
+fn is_white(v: &[f64; 3]) -> bool {
+    *v == [1.0, 1.0, 1.0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_khr_materials_specular() {
+        let s: KhrMaterialsSpecular = serde_json::from_str("{}").unwrap();
+        assert_eq!(s.specular_factor, 1.0);
+        assert_eq!(s.specular_color_factor, [1.0, 1.0, 1.0]);
+        assert_eq!(serde_json::to_string(&s).unwrap(), "{}");
+
+        let s = KhrMaterialsSpecular {
+            specular_color_factor: [0.5, 0.25, 1.0],
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&s).unwrap(),
+            r#"{"specularColorFactor":[0.5,0.25,1.0]}"#
+        );
+    }
+}
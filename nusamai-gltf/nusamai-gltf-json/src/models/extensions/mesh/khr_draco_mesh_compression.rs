@@ -0,0 +1,18 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// KHR_draco_mesh_compression: the Draco-compressed geometry of the primitive
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KhrDracoMeshCompression {
+    /// The index of the bufferView containing the Draco bitstream
+    pub buffer_view: u32,
+
+    /// The unique ids of the attributes in the Draco bitstream, keyed by the attribute semantics
+    pub attributes: HashMap<String, u32>,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
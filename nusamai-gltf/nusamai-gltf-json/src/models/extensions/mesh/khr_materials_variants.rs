@@ -3,9 +3,44 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-// TODO: Implement KHR_materials_variants
+/// KHR_materials_variants (root-level): the list of the material variants
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
 pub struct KhrMaterialsVariants {
+    pub variants: Vec<Variant>,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct Variant {
+    /// The name of the material variant
+    pub name: String,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
+
+/// KHR_materials_variants (primitive-level): the materials to be used for each variant
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct KhrMaterialsVariantsPrimitive {
+    pub mappings: Vec<Mapping>,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct Mapping {
+    /// The indices of the variants (in the root-level extension) that use the material
+    pub variants: Vec<u32>,
+
+    /// The index of the material
+    pub material: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
     #[serde(flatten)]
     pub others: HashMap<String, Value>,
 }
@@ -2,6 +2,7 @@ pub use cesiumtiles::{
     gltf_extensions::mesh::ext_structural_metadata,
     models::gltf_extensions::mesh::ext_mesh_features,
 };
+pub mod khr_draco_mesh_compression;
 pub mod khr_materials_variants;
 
 use std::collections::HashMap;
@@ -19,9 +20,13 @@ pub struct MeshPrimitive {
     #[serde(rename = "EXT_structural_metadata")]
     pub ext_structural_metadata: Option<ext_structural_metadata::ExtStructuralMetadata>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "KHR_draco_mesh_compression")]
+    pub khr_draco_mesh_compression: Option<khr_draco_mesh_compression::KhrDracoMeshCompression>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "KHR_materials_variants")]
-    pub khr_materials_variants: Option<khr_materials_variants::KhrMaterialsVariants>,
+    pub khr_materials_variants: Option<khr_materials_variants::KhrMaterialsVariantsPrimitive>,
 
     #[serde(flatten)]
     pub others: HashMap<String, Value>,
@@ -1,3 +1,6 @@
+pub mod buffer;
 pub mod gltf;
+pub mod material;
 pub mod mesh;
+pub mod node;
 pub mod texture;
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct Node {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "EXT_mesh_gpu_instancing")]
+    pub ext_mesh_gpu_instancing: Option<ExtMeshGpuInstancing>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "EXT_instance_features")]
+    pub ext_instance_features: Option<ExtInstanceFeatures>,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
+
+/// EXT_mesh_gpu_instancing: the mesh of the node is drawn once for each instance
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct ExtMeshGpuInstancing {
+    /// The accessors of the attributes of the instances (`TRANSLATION`, `ROTATION`, `SCALE` and the custom ones, e.g. `_FEATURE_ID_0`)
+    pub attributes: HashMap<String, u32>,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
+
+/// EXT_instance_features: the features identified by the attributes of the instances
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtInstanceFeatures {
+    pub feature_ids: Vec<InstanceFeatureId>,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceFeatureId {
+    /// The number of unique features in the attribute
+    pub feature_count: u32,
+
+    /// A value indicating that no feature is associated with the instance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub null_feature_id: Option<u32>,
+
+    /// A label assigned to this feature ID set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+
+    /// The index of the instance attribute `_FEATURE_ID_<attribute>` containing the feature IDs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribute: Option<u32>,
+
+    /// The index of the property table containing the properties of the features
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub property_table: Option<u32>,
+
+    #[serde(flatten)]
+    pub others: HashMap<String, Value>,
+}
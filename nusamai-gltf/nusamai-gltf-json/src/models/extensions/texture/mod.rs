@@ -7,6 +7,12 @@ pub struct ExtTextureWebp {
     pub source: u32,
 }
 
+/// KTX2 image with the Basis Universal supercompression (`KHR_texture_basisu`)
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct KhrTextureBasisu {
+    pub source: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 
 pub struct TextureExtensions {
@@ -14,6 +20,10 @@ pub struct TextureExtensions {
     #[serde(rename = "EXT_texture_webp")]
     pub ext_texture_webp: Option<ExtTextureWebp>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "KHR_texture_basisu")]
+    pub khr_texture_basisu: Option<KhrTextureBasisu>,
+
     #[serde(flatten)]
     pub others: HashMap<String, Value>,
 }
@@ -12,6 +12,8 @@ pub enum MimeType {
     ImagePng,
     #[serde(rename = "image/webp")]
     ImageWebp,
+    #[serde(rename = "image/ktx2")]
+    ImageKtx2,
 }
 
 /// Image data used to create a texture. Image MAY be referenced by an URI (or IRI) or a buffer view index.
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use super::texture_info::TextureInfo;
+use super::{extensions::material::KhrMaterialsSpecular, texture_info::TextureInfo};
 
 /// The material's alpha rendering mode enumeration specifying the interpretation of the alpha value of the base color.
 #[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -219,8 +219,12 @@ impl Default for Material {
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct MaterialExtensions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "KHR_materials_specular")]
+    pub khr_materials_specular: Option<KhrMaterialsSpecular>,
+
     #[serde(flatten)]
-    others: HashMap<String, Value>,
+    pub others: HashMap<String, Value>,
 }
 
 fn one() -> f64 {
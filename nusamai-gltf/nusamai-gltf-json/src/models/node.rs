@@ -1,8 +1,8 @@
-use std::collections::HashMap;
-
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::extensions;
+
 /// A node in the node hierarchy.  When the node contains `skin`, all `mesh.primitives` **MUST** contain `JOINTS_0` and `WEIGHTS_0` attributes.  A node **MAY** have either a `matrix` or any combination of `translation`/`rotation`/`scale` (TRS) properties. TRS properties are converted to matrices and postmultiplied in the `T * R * S` order to compose the transformation matrix; first the scale is applied to the vertices, then the rotation, and then the translation. If none are provided, the transform is the identity. When a node is targeted for animation (referenced by an animation.channel.target), `matrix` **MUST NOT** be present.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde[rename_all = "camelCase"]]
@@ -56,7 +56,7 @@ pub struct Node {
 
     /// JSON object with extension-specific objects.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub extensions: Option<NodeExtensions>,
+    pub extensions: Option<extensions::node::Node>,
 
     /// Application-specific data.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -82,13 +82,6 @@ impl Default for Node {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
-#[serde(rename_all = "camelCase")]
-pub struct NodeExtensions {
-    #[serde(flatten)]
-    others: HashMap<String, Value>,
-}
-
 fn default_matrix() -> [f64; 16] {
     [
         1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
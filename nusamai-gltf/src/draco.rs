@@ -0,0 +1,1093 @@
+//! Draco geometry encoder for `KHR_draco_mesh_compression` (and the compressed geometry buffers of I3S).
+//!
+//! Writes the Draco bitstream (version 2.2) with the sequential mesh encoding and compressed indices.
+//! The face indices are delta-coded. The float attributes are either stored as raw 32-bit floats or quantized into
+//! integers of the given number of bits, and the integer ones (e.g. the colors) are kept as they are; the integers
+//! are delta-coded (the difference prediction with the wrap transform).
+//! The index deltas and the prediction corrections are entropy-coded with rANS, in the same way as the reference
+//! encoder does (`EncodeSymbols` of `draco/compression/entropy`).
+
+const DRACO_MAGIC: &[u8; 5] = b"DRACO";
+const DRACO_VERSION: (u8, u8) = (2, 2);
+
+const ENCODER_TYPE_TRIANGULAR_MESH: u8 = 1;
+const ENCODER_METHOD_MESH_SEQUENTIAL: u8 = 0;
+const SEQUENTIAL_COMPRESSED_INDICES: u8 = 0;
+
+const METADATA_FLAG_MASK: u16 = 0x8000;
+
+const SEQUENTIAL_ATTRIBUTE_ENCODER_GENERIC: u8 = 0;
+const SEQUENTIAL_ATTRIBUTE_ENCODER_INTEGER: u8 = 1;
+const SEQUENTIAL_ATTRIBUTE_ENCODER_QUANTIZATION: u8 = 2;
+const PREDICTION_DIFFERENCE: i8 = 0;
+const PREDICTION_TRANSFORM_WRAP: i8 = 1;
+
+const SYMBOL_CODING_TAGGED: u8 = 0;
+const SYMBOL_CODING_RAW: u8 = 1;
+/// The maximum bit length of the symbols (and of the number of unique symbols) coded with the raw scheme
+const MAX_RAW_ENCODING_BIT_LENGTH: u32 = 18;
+/// The bit length given to the rANS coder of the tags (the bit lengths of the values) of the tagged scheme
+const TAG_BIT_LENGTH: u32 = 5;
+const ANS_IO_BASE: u32 = 256;
+
+/// Semantic of a Draco attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeType {
+    Position = 0,
+    Normal = 1,
+    Color = 2,
+    TexCoord = 3,
+    Generic = 4,
+}
+
+/// Type of the decoded values of a Draco attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    UInt8 = 2,
+    UInt32 = 6,
+    Float32 = 9,
+}
+
+/// A vertex attribute to be encoded
+#[derive(Debug, Clone, Copy)]
+pub struct Attribute<'a> {
+    pub attribute_type: AttributeType,
+    pub data_type: DataType,
+    /// Whether the integer values are normalized to 0..1 (e.g. the colors)
+    pub normalized: bool,
+    pub num_components: u8,
+    /// The values of the vertices (`num_points * num_components` values)
+    pub values: &'a [f32],
+    /// The number of bits to quantize the float values into (1..=30), or `None` to store them losslessly
+    /// (the integer values are always stored losslessly)
+    pub quantization_bits: Option<u8>,
+    /// The entries of the attribute metadata (e.g. `i3s-attribute-type` of I3S), with the binary values
+    pub metadata: &'a [(&'a str, &'a [u8])],
+}
+
+/// Encodes a triangle mesh into a Draco bitstream.
+///
+/// The unique id of each attribute is its index in `attributes`.
+pub fn encode_mesh(indices: &[u32], num_points: u32, attributes: &[Attribute]) -> Vec<u8> {
+    assert_eq!(indices.len() % 3, 0, "indices must be a list of triangles");
+    assert!(num_points < (1 << 31), "too many points");
+    let mut buf = Vec::new();
+
+    // Header
+    let has_metadata = attributes.iter().any(|attr| !attr.metadata.is_empty());
+    buf.extend_from_slice(DRACO_MAGIC);
+    buf.extend_from_slice(&[
+        DRACO_VERSION.0,
+        DRACO_VERSION.1,
+        ENCODER_TYPE_TRIANGULAR_MESH,
+        ENCODER_METHOD_MESH_SEQUENTIAL,
+    ]);
+    let flags = if has_metadata { METADATA_FLAG_MASK } else { 0 };
+    buf.extend_from_slice(&flags.to_le_bytes());
+
+    // Metadata (of the attributes, and the empty one of the geometry)
+    if has_metadata {
+        let with_metadata = attributes
+            .iter()
+            .enumerate()
+            .filter(|(_, attr)| !attr.metadata.is_empty());
+        write_varint(&mut buf, with_metadata.clone().count() as u32);
+        for (unique_id, attr) in with_metadata {
+            write_varint(&mut buf, unique_id as u32);
+            write_varint(&mut buf, attr.metadata.len() as u32);
+            for (name, value) in attr.metadata {
+                assert!(name.len() < 256 && !value.is_empty());
+                buf.push(name.len() as u8);
+                buf.extend_from_slice(name.as_bytes());
+                write_varint(&mut buf, value.len() as u32);
+                buf.extend_from_slice(value);
+            }
+            write_varint(&mut buf, 0); // no sub-metadata
+        }
+        write_varint(&mut buf, 0);
+        write_varint(&mut buf, 0);
+    }
+
+    // Connectivity
+    let num_faces = indices.len() / 3;
+    write_varint(&mut buf, num_faces as u32);
+    write_varint(&mut buf, num_points);
+    let connectivity_start = buf.len();
+    buf.push(SEQUENTIAL_COMPRESSED_INDICES);
+    debug_assert!(indices.iter().all(|&index| index < num_points));
+    encode_symbols(&mut buf, &index_symbols(indices), 1);
+
+    // Attribute decoder (a single sequential decoder for all the attributes)
+    buf.push(1);
+    write_varint(&mut buf, attributes.len() as u32);
+    for (unique_id, attr) in attributes.iter().enumerate() {
+        assert_eq!(
+            attr.values.len(),
+            num_points as usize * attr.num_components as usize
+        );
+        buf.extend_from_slice(&[
+            attr.attribute_type as u8,
+            attr.data_type as u8,
+            attr.num_components,
+            attr.normalized as u8,
+        ]);
+        write_varint(&mut buf, unique_id as u32);
+    }
+    for attr in attributes {
+        buf.push(match (attr.data_type, attr.quantization_bits) {
+            (DataType::Float32, Some(_)) => SEQUENTIAL_ATTRIBUTE_ENCODER_QUANTIZATION,
+            (DataType::Float32, None) => SEQUENTIAL_ATTRIBUTE_ENCODER_GENERIC,
+            _ => SEQUENTIAL_ATTRIBUTE_ENCODER_INTEGER,
+        });
+    }
+
+    // Portable (quantized or integer) values
+    let quantizations: Vec<_> = attributes
+        .iter()
+        .map(|attr| match attr.data_type {
+            DataType::Float32 => attr
+                .quantization_bits
+                .map(|bits| Quantization::new(attr, bits)),
+            _ => None,
+        })
+        .collect();
+    for (attr, quantization) in attributes.iter().zip(&quantizations) {
+        let num_components = attr.num_components as usize;
+        match (attr.data_type, quantization) {
+            (DataType::Float32, Some(quantization)) => {
+                let values: Vec<i32> = attr
+                    .values
+                    .chunks_exact(num_components)
+                    .flat_map(|value| {
+                        value
+                            .iter()
+                            .zip(&quantization.min_values)
+                            .map(|(&v, &min)| quantization.quantize(v, min) as i32)
+                    })
+                    .collect();
+                encode_integer_values(&mut buf, &values, num_components);
+            }
+            (DataType::Float32, None) => {
+                for v in attr.values {
+                    buf.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            _ => {
+                let values: Vec<i32> = attr.values.iter().map(|&v| v as i32).collect();
+                encode_integer_values(&mut buf, &values, num_components);
+            }
+        }
+    }
+
+    // Quantization parameters
+    for quantization in quantizations.iter().flatten() {
+        for min in &quantization.min_values {
+            buf.extend_from_slice(&min.to_le_bytes());
+        }
+        buf.extend_from_slice(&quantization.range.to_le_bytes());
+        buf.push(quantization.bits);
+    }
+
+    // (the reference decoder rejects the meshes whose data after the connectivity header is shorter than 3 bytes
+    // per face, which a well-compressed mesh may be; the padding is not read)
+    buf.resize(buf.len().max(connectivity_start + 3 * num_faces), 0);
+
+    buf
+}
+
+/// Writes the integer values predicted by the previous ones (the first ones by zero) and entropy-coded
+fn encode_integer_values(buf: &mut Vec<u8>, values: &[i32], num_components: usize) {
+    let wrap = WrapTransform::new(values);
+    let zeros = vec![0; num_components];
+    let symbols: Vec<u32> = values
+        .chunks_exact(num_components)
+        .enumerate()
+        .flat_map(|(i, value)| {
+            let predicted = match i {
+                0 => &zeros[..],
+                _ => &values[(i - 1) * num_components..i * num_components],
+            };
+            value
+                .iter()
+                .zip(predicted)
+                .map(|(&v, &p)| signed_to_symbol(wrap.correction(v, p)))
+        })
+        .collect();
+
+    buf.push(PREDICTION_DIFFERENCE as u8);
+    buf.push(PREDICTION_TRANSFORM_WRAP as u8);
+    buf.push(1); // entropy-coded
+    encode_symbols(buf, &symbols, num_components);
+    buf.extend_from_slice(&wrap.min_value.to_le_bytes());
+    buf.extend_from_slice(&wrap.max_value.to_le_bytes());
+}
+
+/// The differences of the indices from the previous ones, with the sign in the lowest bit
+fn index_symbols(indices: &[u32]) -> Vec<u32> {
+    let mut last_index = 0;
+    indices
+        .iter()
+        .map(|&index| {
+            let diff = index as i64 - last_index as i64;
+            last_index = index;
+            ((diff.unsigned_abs() as u32) << 1) | (diff < 0) as u32
+        })
+        .collect()
+}
+
+struct Quantization {
+    min_values: Vec<f32>,
+    range: f32,
+    bits: u8,
+}
+
+impl Quantization {
+    fn new(attr: &Attribute, bits: u8) -> Self {
+        assert!((1..=30).contains(&bits), "invalid quantization bits");
+        let num_components = attr.num_components as usize;
+        let mut min_values = vec![f32::MAX; num_components];
+        let mut max_values = vec![f32::MIN; num_components];
+        for value in attr.values.chunks_exact(num_components) {
+            for (i, &v) in value.iter().enumerate() {
+                min_values[i] = min_values[i].min(v);
+                max_values[i] = max_values[i].max(v);
+            }
+        }
+        if attr.values.is_empty() {
+            min_values.fill(0.);
+            max_values.fill(0.);
+        }
+        let range = min_values
+            .iter()
+            .zip(&max_values)
+            .map(|(min, max)| max - min)
+            .fold(0f32, f32::max);
+        Self {
+            min_values,
+            range: if range > 0. { range } else { 1. },
+            bits,
+        }
+    }
+
+    fn quantize(&self, v: f32, min: f32) -> u32 {
+        let max_quantized = ((1u32 << self.bits) - 1) as f32;
+        let q = ((v - min) * (max_quantized / self.range) + 0.5).floor();
+        q.clamp(0., max_quantized) as u32
+    }
+}
+
+/// The wrap transform of the prediction corrections (`PredictionSchemeWrapEncodingTransform`).
+///
+/// The corrections are wrapped into the range of the values, so they take at most the bits of the values.
+struct WrapTransform {
+    min_value: i32,
+    max_value: i32,
+    max_dif: i32,
+    min_correction: i32,
+    max_correction: i32,
+}
+
+impl WrapTransform {
+    fn new(values: &[i32]) -> Self {
+        let min_value = values.iter().copied().min().unwrap_or(0);
+        let max_value = values.iter().copied().max().unwrap_or(0);
+        let max_dif = max_value - min_value + 1;
+        let mut max_correction = max_dif / 2;
+        let min_correction = -max_correction;
+        if max_dif % 2 == 0 {
+            max_correction -= 1;
+        }
+        Self {
+            min_value,
+            max_value,
+            max_dif,
+            min_correction,
+            max_correction,
+        }
+    }
+
+    fn correction(&self, value: i32, predicted: i32) -> i32 {
+        let correction = value - predicted.clamp(self.min_value, self.max_value);
+        if correction < self.min_correction {
+            correction + self.max_dif
+        } else if correction > self.max_correction {
+            correction - self.max_dif
+        } else {
+            correction
+        }
+    }
+}
+
+/// Maps a signed integer to a symbol (`0, -1, 1, -2, ...` to `0, 1, 2, 3, ...`)
+fn signed_to_symbol(value: i32) -> u32 {
+    match value {
+        0.. => (value as u32) << 1,
+        _ => (((-(value + 1)) as u32) << 1) | 1,
+    }
+}
+
+/// The number of bits of the value (1 for zero)
+fn bit_length(value: u32) -> u32 {
+    (32 - value.leading_zeros()).max(1)
+}
+
+/// The precision of the rANS coder for the bit length of the (unique) symbols
+fn rans_precision_bits(bit_length: u32) -> u32 {
+    (3 * bit_length / 2).clamp(12, 20)
+}
+
+/// Writes the symbols entropy-coded with rANS.
+///
+/// The symbols are either coded directly (the raw scheme), or their bit lengths are coded (for each entry of
+/// `num_components` symbols) and the symbols are appended as bits (the tagged scheme). The scheme of the fewer
+/// estimated bits is chosen, as the reference encoder does; the raw one has a large table for the sparse symbols.
+fn encode_symbols(buf: &mut Vec<u8>, symbols: &[u32], num_components: usize) {
+    if symbols.is_empty() {
+        return;
+    }
+    let mut sorted_symbols = symbols.to_vec();
+    sorted_symbols.sort_unstable();
+    let counts: Vec<u64> = sorted_symbols
+        .chunk_by(|a, b| a == b)
+        .map(|run| run.len() as u64)
+        .collect();
+    let max_value = sorted_symbols[sorted_symbols.len() - 1];
+    let unique_symbols_bit_length = bit_length(counts.len() as u32);
+
+    let bit_lengths: Vec<u32> = symbols
+        .chunks_exact(num_components)
+        .map(|entry| bit_length(entry.iter().copied().max().unwrap_or(0)))
+        .collect();
+    let mut tag_frequencies = [0; 33];
+    for &bit_length in &bit_lengths {
+        tag_frequencies[bit_length as usize] += 1;
+    }
+    let tag_counts: Vec<u64> = tag_frequencies.into_iter().filter(|&f| f > 0).collect();
+
+    let raw_bits = rans_table_bits(max_value, counts.len()) + entropy_bits(&counts);
+    let tagged_bits = rans_table_bits(32, tag_counts.len())
+        + entropy_bits(&tag_counts)
+        + bit_lengths.iter().sum::<u32>() as f64 * num_components as f64;
+    if raw_bits <= tagged_bits
+        && bit_length(max_value) <= MAX_RAW_ENCODING_BIT_LENGTH
+        && unique_symbols_bit_length <= MAX_RAW_ENCODING_BIT_LENGTH
+    {
+        let mut frequencies = vec![0; max_value as usize + 1];
+        for &symbol in symbols {
+            frequencies[symbol as usize] += 1;
+        }
+        buf.push(SYMBOL_CODING_RAW);
+        buf.push(unique_symbols_bit_length as u8);
+        let coder = RansCoder::new(rans_precision_bits(unique_symbols_bit_length), &frequencies);
+        coder.write_table(buf);
+        coder.write_symbols(buf, symbols);
+    } else {
+        let frequencies = tag_frequencies;
+        buf.push(SYMBOL_CODING_TAGGED);
+        let coder = RansCoder::new(rans_precision_bits(TAG_BIT_LENGTH), &frequencies);
+        coder.write_table(buf);
+        coder.write_symbols(buf, &bit_lengths);
+
+        // The values follow the tags, from the least significant bits
+        let mut bits = Vec::new();
+        let mut num_bits = 0;
+        for (entry, &bit_length) in symbols.chunks_exact(num_components).zip(&bit_lengths) {
+            for &value in entry {
+                for i in 0..bit_length {
+                    if num_bits % 8 == 0 {
+                        bits.push(0);
+                    }
+                    if (value >> i) & 1 != 0 {
+                        *bits.last_mut().unwrap() |= 1 << (num_bits % 8);
+                    }
+                    num_bits += 1;
+                }
+            }
+        }
+        buf.extend_from_slice(&bits);
+    }
+}
+
+/// The approximate bits of the probability table (with the runs of zero probabilities)
+fn rans_table_bits(max_value: u32, num_unique_symbols: usize) -> f64 {
+    let num_zeros = (max_value as usize + 1).saturating_sub(num_unique_symbols);
+    (8 * (2 * num_unique_symbols + num_zeros / 64)) as f64
+}
+
+/// The Shannon entropy of the symbols of the counts (bits)
+fn entropy_bits(counts: &[u64]) -> f64 {
+    let total = counts.iter().sum::<u64>() as f64;
+    counts
+        .iter()
+        .map(|&count| -(count as f64) * (count as f64 / total).log2())
+        .sum()
+}
+
+/// rANS coder with the probabilities of the symbols scaled to the precision (`RAnsSymbolEncoder`)
+struct RansCoder {
+    precision_bits: u32,
+    probabilities: Vec<u32>,
+    cumulative: Vec<u32>,
+}
+
+impl RansCoder {
+    fn new(precision_bits: u32, frequencies: &[u64]) -> Self {
+        let precision = 1u32 << precision_bits;
+        let num_symbols = frequencies
+            .iter()
+            .rposition(|&f| f > 0)
+            .map_or(0, |i| i + 1);
+        let frequencies = &frequencies[..num_symbols];
+        let total = frequencies.iter().sum::<u64>() as f64;
+        let mut probabilities: Vec<u32> = frequencies
+            .iter()
+            .map(|&freq| match freq {
+                0 => 0,
+                _ => ((freq as f64 / total * precision as f64 + 0.5) as u32).max(1),
+            })
+            .collect();
+
+        // The probabilities must sum up to the precision exactly (the number of the symbols never exceeds it)
+        let mut order: Vec<usize> = (0..num_symbols).filter(|&i| probabilities[i] > 0).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(probabilities[i]));
+        let sum: u32 = probabilities.iter().sum();
+        if sum < precision {
+            probabilities[order[0]] += precision - sum;
+        }
+        let mut excess = sum.saturating_sub(precision);
+        while excess > 0 {
+            for &i in &order {
+                let fix = ((probabilities[i] as u64 * excess as u64 / precision as u64) as u32)
+                    .max(1)
+                    .min(probabilities[i] - 1)
+                    .min(excess);
+                probabilities[i] -= fix;
+                excess -= fix;
+                if excess == 0 {
+                    break;
+                }
+            }
+        }
+
+        let cumulative = probabilities
+            .iter()
+            .scan(0, |cum, &prob| {
+                *cum += prob;
+                Some(*cum - prob)
+            })
+            .collect();
+        Self {
+            precision_bits,
+            probabilities,
+            cumulative,
+        }
+    }
+
+    /// Writes the probability table (a zero probability starts a run of the zero probabilities)
+    fn write_table(&self, buf: &mut Vec<u8>) {
+        let num_symbols = self.probabilities.len();
+        write_varint(buf, num_symbols as u32);
+        let mut i = 0;
+        while i < num_symbols {
+            let prob = self.probabilities[i];
+            if prob == 0 {
+                // (the last symbol always has a non-zero probability)
+                let mut offset = 0;
+                while offset < (1 << 6) - 1 && self.probabilities[i + offset + 1] == 0 {
+                    offset += 1;
+                }
+                buf.push(((offset << 2) | 3) as u8);
+                i += offset + 1;
+            } else {
+                let num_extra_bytes = match prob {
+                    0..0x40 => 0,
+                    0x40..0x4000 => 1,
+                    _ => 2,
+                };
+                buf.push(((prob << 2) | num_extra_bytes) as u8);
+                for b in 0..num_extra_bytes {
+                    buf.push((prob >> (8 * (b + 1) - 2)) as u8);
+                }
+                i += 1;
+            }
+        }
+    }
+
+    /// Writes the size of the coded data and the data (decoded from the end, so the symbols are coded backwards)
+    fn write_symbols(&self, buf: &mut Vec<u8>, symbols: &[u32]) {
+        let precision = 1u32 << self.precision_bits;
+        let l_rans_base = 4 * precision;
+        let mut data = Vec::new();
+        let mut state = l_rans_base;
+        for &symbol in symbols.iter().rev() {
+            let prob = self.probabilities[symbol as usize];
+            while state >= l_rans_base / precision * ANS_IO_BASE * prob {
+                data.push((state % ANS_IO_BASE) as u8);
+                state /= ANS_IO_BASE;
+            }
+            state = (state / prob) * precision + state % prob + self.cumulative[symbol as usize];
+        }
+
+        // The final state, with its size in the top 2 bits
+        let state = state - l_rans_base;
+        match state {
+            0..0x40 => data.push(state as u8),
+            0x40..0x4000 => data.extend_from_slice(&((1 << 14) + state as u16).to_le_bytes()),
+            0x4000..0x40_0000 => data.extend_from_slice(&((2 << 22) + state).to_le_bytes()[..3]),
+            _ => data.extend_from_slice(&((3 << 30) + state).to_le_bytes()),
+        }
+        write_varint(buf, data.len() as u32);
+        buf.extend_from_slice(&data);
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A decoder for the subset of the bitstream written by `encode_mesh`, following the reference decoder
+    struct Decoder<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> Decoder<'a> {
+        fn u8(&mut self) -> u8 {
+            self.pos += 1;
+            self.data[self.pos - 1]
+        }
+
+        fn bytes(&mut self, n: usize) -> &'a [u8] {
+            let data = self.data;
+            self.pos += n;
+            &data[self.pos - n..self.pos]
+        }
+
+        fn f32(&mut self) -> f32 {
+            f32::from_le_bytes(self.bytes(4).try_into().unwrap())
+        }
+
+        fn i32(&mut self) -> i32 {
+            i32::from_le_bytes(self.bytes(4).try_into().unwrap())
+        }
+
+        fn varint(&mut self) -> u32 {
+            let mut value = 0;
+            let mut shift = 0;
+            loop {
+                let byte = self.u8();
+                value |= ((byte & 0x7f) as u32) << shift;
+                if byte & 0x80 == 0 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+
+        /// `DecodeSymbols`
+        fn symbols(&mut self, num_values: usize, num_components: usize) -> Vec<u32> {
+            if num_values == 0 {
+                return vec![];
+            }
+            match self.u8() {
+                SYMBOL_CODING_TAGGED => {
+                    let mut tags = RansDecoder::new(self, rans_precision_bits(TAG_BIT_LENGTH));
+                    let bits = &self.data[self.pos..];
+                    let mut num_bits = 0;
+                    let mut values = Vec::with_capacity(num_values);
+                    for _ in 0..num_values / num_components {
+                        let bit_length = tags.read();
+                        for _ in 0..num_components {
+                            let mut value = 0;
+                            for i in 0..bit_length {
+                                let bit = (bits[num_bits / 8] >> (num_bits % 8)) & 1;
+                                value |= (bit as u32) << i;
+                                num_bits += 1;
+                            }
+                            values.push(value);
+                        }
+                    }
+                    self.pos += num_bits.div_ceil(8);
+                    values
+                }
+                SYMBOL_CODING_RAW => {
+                    let bit_length = self.u8() as u32;
+                    assert!((1..=MAX_RAW_ENCODING_BIT_LENGTH).contains(&bit_length));
+                    let mut decoder = RansDecoder::new(self, rans_precision_bits(bit_length));
+                    (0..num_values).map(|_| decoder.read()).collect()
+                }
+                scheme => panic!("unknown symbol coding {scheme}"),
+            }
+        }
+    }
+
+    /// `RAnsSymbolDecoder`
+    struct RansDecoder<'a> {
+        data: &'a [u8],
+        offset: usize,
+        state: u32,
+        precision_bits: u32,
+        probabilities: Vec<u32>,
+    }
+
+    impl<'a> RansDecoder<'a> {
+        fn new(d: &mut Decoder<'a>, precision_bits: u32) -> Self {
+            let num_symbols = d.varint() as usize;
+            let mut probabilities = vec![];
+            while probabilities.len() < num_symbols {
+                let prob_data = d.u8();
+                if prob_data & 3 == 3 {
+                    probabilities.extend(std::iter::repeat_n(0, (prob_data >> 2) as usize + 1));
+                } else {
+                    let mut prob = (prob_data >> 2) as u32;
+                    for b in 0..(prob_data & 3) as u32 {
+                        prob |= (d.u8() as u32) << (8 * (b + 1) - 2);
+                    }
+                    probabilities.push(prob);
+                }
+            }
+            assert_eq!(probabilities.len(), num_symbols);
+            assert_eq!(probabilities.iter().sum::<u32>(), 1 << precision_bits);
+
+            let size = d.varint() as usize;
+            let data = d.bytes(size);
+            let last = data[size - 1] as u32;
+            let (len, mask) = match last >> 6 {
+                0 => (1, 0x3f),
+                1 => (2, 0x3fff),
+                2 => (3, 0x3f_ffff),
+                _ => (4, 0x3fff_ffff),
+            };
+            let mut word = [0; 4];
+            word[..len].copy_from_slice(&data[size - len..]);
+            let l_rans_base = 4 << precision_bits;
+            let state = (u32::from_le_bytes(word) & mask) + l_rans_base;
+            assert!(state < l_rans_base * ANS_IO_BASE);
+            Self {
+                data,
+                offset: size - len,
+                state,
+                precision_bits,
+                probabilities,
+            }
+        }
+
+        fn read(&mut self) -> u32 {
+            let l_rans_base = 4 << self.precision_bits;
+            while self.state < l_rans_base && self.offset > 0 {
+                self.offset -= 1;
+                self.state = self.state * ANS_IO_BASE + self.data[self.offset] as u32;
+            }
+            let quo = self.state >> self.precision_bits;
+            let rem = self.state & ((1 << self.precision_bits) - 1);
+            let mut cum_prob = 0;
+            for (symbol, &prob) in self.probabilities.iter().enumerate() {
+                if rem < cum_prob + prob {
+                    self.state = quo * prob + rem - cum_prob;
+                    return symbol as u32;
+                }
+                cum_prob += prob;
+            }
+            unreachable!()
+        }
+    }
+
+    /// (name, value)
+    type MetadataEntries = Vec<(String, Vec<u8>)>;
+
+    struct Decoded {
+        indices: Vec<u32>,
+        values: Vec<Vec<f32>>,
+        /// unique id -> entries
+        metadata: Vec<(u32, MetadataEntries)>,
+    }
+
+    fn decode(data: &[u8]) -> Decoded {
+        let mut d = Decoder { data, pos: 0 };
+        assert_eq!(d.bytes(5), DRACO_MAGIC);
+        assert_eq!(d.bytes(4), [2, 2, 1, 0]);
+        let flags = u16::from_le_bytes(d.bytes(2).try_into().unwrap());
+
+        let mut metadata = vec![];
+        if flags & METADATA_FLAG_MASK != 0 {
+            let num_att_metadata = d.varint();
+            for _ in 0..num_att_metadata {
+                let unique_id = d.varint();
+                let num_entries = d.varint();
+                let entries = (0..num_entries)
+                    .map(|_| {
+                        let name_len = d.u8() as usize;
+                        let name = String::from_utf8(d.bytes(name_len).to_vec()).unwrap();
+                        let size = d.varint() as usize;
+                        (name, d.bytes(size).to_vec())
+                    })
+                    .collect();
+                assert_eq!(d.varint(), 0);
+                metadata.push((unique_id, entries));
+            }
+            // (the geometry metadata)
+            assert_eq!(d.bytes(2), [0, 0]);
+        } else {
+            assert_eq!(flags, 0);
+        }
+
+        let num_faces = d.varint() as usize;
+        let num_points = d.varint() as usize;
+        assert!(num_faces <= (data.len() - d.pos) / 3);
+        assert_eq!(d.u8(), SEQUENTIAL_COMPRESSED_INDICES);
+        let mut last_index = 0;
+        let indices: Vec<u32> = d
+            .symbols(num_faces * 3, 1)
+            .into_iter()
+            .map(|encoded| {
+                let diff = (encoded >> 1) as i64;
+                last_index += if encoded & 1 != 0 { -diff } else { diff };
+                last_index as u32
+            })
+            .collect();
+
+        assert_eq!(d.u8(), 1);
+        let num_attributes = d.varint() as usize;
+        let mut num_components = vec![];
+        for unique_id in 0..num_attributes {
+            let desc = d.bytes(4).to_vec();
+            assert!([2, 6, 9].contains(&desc[1]));
+            num_components.push(desc[2] as usize);
+            assert_eq!(d.varint(), unique_id as u32);
+        }
+        let decoder_types = d.bytes(num_attributes).to_vec();
+
+        let mut values = vec![];
+        let mut portables = vec![];
+        for (i, &decoder_type) in decoder_types.iter().enumerate() {
+            let num_components = num_components[i];
+            let n = num_points * num_components;
+            if decoder_type == SEQUENTIAL_ATTRIBUTE_ENCODER_GENERIC {
+                portables.push(None);
+                values.push((0..n).map(|_| d.f32()).collect());
+                continue;
+            }
+            assert_eq!(d.u8() as i8, PREDICTION_DIFFERENCE);
+            assert_eq!(d.u8() as i8, PREDICTION_TRANSFORM_WRAP);
+            assert_eq!(d.u8(), 1);
+            let corrections: Vec<i32> = d
+                .symbols(n, num_components)
+                .into_iter()
+                .map(|symbol| match symbol & 1 {
+                    0 => (symbol >> 1) as i32,
+                    _ => -((symbol >> 1) as i32) - 1,
+                })
+                .collect();
+            let (min_value, max_value) = (d.i32(), d.i32());
+            let max_dif = max_value - min_value + 1;
+            let mut portable: Vec<i32> = Vec::with_capacity(n);
+            for (j, &correction) in corrections.iter().enumerate() {
+                let predicted = match j < num_components {
+                    true => 0,
+                    false => portable[j - num_components],
+                };
+                let mut value = predicted.clamp(min_value, max_value) + correction;
+                if value > max_value {
+                    value -= max_dif;
+                } else if value < min_value {
+                    value += max_dif;
+                }
+                portable.push(value);
+            }
+            if decoder_type == SEQUENTIAL_ATTRIBUTE_ENCODER_QUANTIZATION {
+                portables.push(Some(portable));
+                values.push(vec![]);
+            } else {
+                assert_eq!(decoder_type, SEQUENTIAL_ATTRIBUTE_ENCODER_INTEGER);
+                portables.push(None);
+                values.push(portable.into_iter().map(|v| v as f32).collect());
+            }
+        }
+        for (i, portable) in portables.iter().enumerate() {
+            let Some(portable) = portable else {
+                continue;
+            };
+            let min_values: Vec<f32> = (0..num_components[i]).map(|_| d.f32()).collect();
+            let range = d.f32();
+            let bits = d.u8();
+            let delta = range / ((1u32 << bits) - 1) as f32;
+            values[i] = portable
+                .iter()
+                .enumerate()
+                .map(|(j, &q)| q as f32 * delta + min_values[j % num_components[i]])
+                .collect();
+        }
+        // (only the padding remains)
+        assert!(data[d.pos..].iter().all(|&b| b == 0));
+        Decoded {
+            indices,
+            values,
+            metadata,
+        }
+    }
+
+    fn float_attribute(
+        attribute_type: AttributeType,
+        num_components: u8,
+        values: &[f32],
+        quantization_bits: Option<u8>,
+    ) -> Attribute<'_> {
+        Attribute {
+            attribute_type,
+            data_type: DataType::Float32,
+            normalized: false,
+            num_components,
+            values,
+            quantization_bits,
+            metadata: &[],
+        }
+    }
+
+    /// A grid of `n * n` points with 2 triangles for each cell
+    fn grid(n: u32) -> (Vec<u32>, Vec<f32>) {
+        let positions = (0..n * n)
+            .flat_map(|i| [(i % n) as f32, (i / n) as f32, ((i % 7) as f32).sin()])
+            .collect();
+        let indices = (0..(n - 1) * (n - 1))
+            .flat_map(|cell| {
+                let i = cell / (n - 1) * n + cell % (n - 1);
+                [i, i + 1, i + n + 1, i, i + n + 1, i + n]
+            })
+            .collect();
+        (indices, positions)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let positions = [0., 0., 0., 10., 0., 0., 10., 5., 0., 0., 5., 2.5];
+        let texcoords = [0., 0., 1., 0., 1., 1., 0., 1.];
+        let feature_ids = [0., 0., 1., 1.];
+        let indices = [0, 1, 2, 0, 2, 3];
+        let data = encode_mesh(
+            &indices,
+            4,
+            &[
+                float_attribute(AttributeType::Position, 3, &positions, Some(14)),
+                float_attribute(AttributeType::TexCoord, 2, &texcoords, Some(12)),
+                float_attribute(AttributeType::Generic, 1, &feature_ids, None),
+            ],
+        );
+
+        let Decoded {
+            indices: decoded_indices,
+            values: decoded,
+            ..
+        } = decode(&data);
+        assert_eq!(decoded_indices, indices);
+        for (a, b) in decoded[0].iter().zip(positions) {
+            assert!((a - b).abs() <= 10. / (1 << 14) as f32);
+        }
+        for (a, b) in decoded[1].iter().zip(texcoords) {
+            assert!((a - b).abs() <= 1. / (1 << 12) as f32);
+        }
+        assert_eq!(decoded[2], feature_ids);
+    }
+
+    #[test]
+    fn test_compressed_indices() {
+        let (indices, positions) = grid(100);
+        let data = encode_mesh(
+            &indices,
+            100 * 100,
+            &[float_attribute(
+                AttributeType::Position,
+                3,
+                &positions,
+                Some(16),
+            )],
+        );
+        // (padded to 3 bytes per face after the 16-byte header)
+        assert_eq!(data.len(), 16 + indices.len());
+        // (the uncompressed indices would take 2 bytes each)
+        let mut buf = vec![];
+        encode_symbols(&mut buf, &index_symbols(&indices), 1);
+        assert!(buf.len() < indices.len() / 2, "{} bytes", buf.len());
+
+        let decoded = decode(&data);
+        assert_eq!(decoded.indices, indices);
+        for (a, b) in decoded.values[0].iter().zip(&positions) {
+            assert!((a - b).abs() <= 99. / (1 << 16) as f32);
+        }
+
+        // no faces and no points
+        let decoded = decode(&encode_mesh(
+            &[],
+            0,
+            &[float_attribute(AttributeType::Position, 3, &[], Some(16))],
+        ));
+        assert!(decoded.indices.is_empty() && decoded.values[0].is_empty());
+    }
+
+    #[test]
+    fn test_integer_attributes() {
+        let colors = [255., 0., 0., 255., 0., 128., 255., 255., 255., 0., 0., 255.];
+        let feature_indices = [0., 0., 1.];
+        let feature_ids: Vec<u8> = [7i32, 3].iter().flat_map(|id| id.to_le_bytes()).collect();
+        let metadata = [
+            ("i3s-attribute-type", &b"feature-index"[..]),
+            ("i3s-feature-ids", &feature_ids[..]),
+        ];
+        let data = encode_mesh(
+            &[0, 1, 2],
+            3,
+            &[
+                Attribute {
+                    attribute_type: AttributeType::Color,
+                    data_type: DataType::UInt8,
+                    normalized: true,
+                    num_components: 4,
+                    values: &colors,
+                    quantization_bits: None,
+                    metadata: &[],
+                },
+                Attribute {
+                    attribute_type: AttributeType::Generic,
+                    data_type: DataType::UInt32,
+                    normalized: false,
+                    num_components: 1,
+                    values: &feature_indices,
+                    quantization_bits: Some(8), // (ignored)
+                    metadata: &metadata,
+                },
+            ],
+        );
+        assert_eq!(&data[9..11], &METADATA_FLAG_MASK.to_le_bytes());
+
+        let decoded = decode(&data);
+        assert_eq!(decoded.indices, [0, 1, 2]);
+        assert_eq!(decoded.values[0], colors);
+        assert_eq!(decoded.values[1], feature_indices);
+        assert_eq!(decoded.metadata.len(), 1);
+        let (unique_id, entries) = &decoded.metadata[0];
+        assert_eq!(*unique_id, 1);
+        assert_eq!(
+            entries[0],
+            ("i3s-attribute-type".into(), b"feature-index".to_vec())
+        );
+        assert_eq!(entries[1], ("i3s-feature-ids".into(), feature_ids));
+    }
+
+    #[test]
+    fn test_symbol_coding() {
+        let decode_symbols = |buf: &[u8], num_values, num_components| {
+            let mut d = Decoder { data: buf, pos: 0 };
+            let symbols = d.symbols(num_values, num_components);
+            assert_eq!(d.pos, buf.len());
+            symbols
+        };
+
+        // a single symbol: the table of one full probability (4096) and the final state only
+        let mut buf = vec![];
+        encode_symbols(&mut buf, &[0, 0, 0], 1);
+        assert_eq!(buf, [SYMBOL_CODING_RAW, 1, 1, 0x01, 0x40, 1, 0]);
+        assert_eq!(decode_symbols(&buf, 3, 1), [0, 0, 0]);
+
+        // the runs of zero probabilities (longer than 64 symbols)
+        let symbols: Vec<u32> = (0..1000).map(|i| [0, 5, 100, 5000][i % 7 % 4]).collect();
+        let mut buf = vec![];
+        encode_symbols(&mut buf, &symbols, 1);
+        assert_eq!(buf[0], SYMBOL_CODING_RAW);
+        assert!(buf.len() < 400);
+        assert_eq!(decode_symbols(&buf, 1000, 1), symbols);
+
+        // too large for the raw scheme
+        let symbols = [0, 1 << 20, 5, 7, u32::MAX, 3];
+        let mut buf = vec![];
+        encode_symbols(&mut buf, &symbols, 2);
+        assert_eq!(buf[0], SYMBOL_CODING_TAGGED);
+        assert_eq!(decode_symbols(&buf, 6, 2), symbols);
+    }
+
+    #[test]
+    fn test_wrap_transform() {
+        let wrap = WrapTransform::new(&[3, 10]);
+        assert_eq!((wrap.min_correction, wrap.max_correction), (-4, 3));
+        // (predicted by zero, clamped to the minimum)
+        assert_eq!(wrap.correction(3, 0), 0);
+        assert_eq!(wrap.correction(10, 3), -1);
+        assert_eq!(wrap.correction(3, 10), 1);
+
+        for (value, symbol) in [(0, 0), (-1, 1), (1, 2), (-2, 3), (i32::MIN, u32::MAX)] {
+            assert_eq!(signed_to_symbol(value), symbol);
+        }
+    }
+
+    #[test]
+    fn test_reference_decoder() {
+        // Decodes the output with `draco_decoder` of the reference implementation if `DRACO_DECODER` is set to
+        // its path (e.g. `DRACO_DECODER=/path/to/draco_decoder cargo test -p nusamai-gltf draco`)
+        let Some(decoder) = std::env::var_os("DRACO_DECODER") else {
+            return;
+        };
+        let (indices, positions) = grid(50);
+        let data = encode_mesh(
+            &indices,
+            50 * 50,
+            &[float_attribute(
+                AttributeType::Position,
+                3,
+                &positions,
+                Some(14),
+            )],
+        );
+        let dir = std::env::temp_dir();
+        let (input, output) = (dir.join("nusamai_draco.drc"), dir.join("nusamai_draco.obj"));
+        std::fs::write(&input, data).unwrap();
+        let status = std::process::Command::new(decoder)
+            .arg("-i")
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let obj = std::fs::read_to_string(&output).unwrap();
+        let vertices: Vec<Vec<f32>> = obj
+            .lines()
+            .filter_map(|line| line.strip_prefix("v "))
+            .map(|v| v.split_whitespace().map(|c| c.parse().unwrap()).collect())
+            .collect();
+        let faces: Vec<usize> = obj
+            .lines()
+            .filter_map(|line| line.strip_prefix("f "))
+            .flat_map(|f| f.split_whitespace().map(|c| c.split('/').next().unwrap()))
+            .map(|c| c.parse::<usize>().unwrap() - 1)
+            .collect();
+        assert_eq!(faces.len(), indices.len());
+        // (compare the positions of the corners, as the decoder may reorder the vertices)
+        for (&index, &decoded) in indices.iter().zip(&faces) {
+            let original = &positions[index as usize * 3..index as usize * 3 + 3];
+            for (a, b) in vertices[decoded].iter().zip(original) {
+                assert!((a - b).abs() <= 49. / (1 << 14) as f32);
+            }
+        }
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut buf = vec![];
+        write_varint(&mut buf, 0);
+        write_varint(&mut buf, 127);
+        write_varint(&mut buf, 128);
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0, 127, 0x80, 0x01, 0xac, 0x02]);
+    }
+}
@@ -1 +1,3 @@
+pub mod draco;
 pub mod glb;
+pub mod meshopt;
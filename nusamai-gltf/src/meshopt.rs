@@ -0,0 +1,287 @@
+//! Buffer view compression with `EXT_meshopt_compression` (using meshoptimizer).
+//!
+//! The compressed buffer views refer to a fallback buffer without data, so the extension is required to load them.
+
+use std::sync::Once;
+
+use ::meshopt::ffi;
+use nusamai_gltf_json::{
+    extensions::buffer::{
+        ExtMeshoptCompression, ExtMeshoptCompressionBuffer, MeshoptCompressionMode,
+    },
+    Buffer, BufferExtensions, BufferView, BufferViewExtensions,
+};
+
+/// The buffer views are aligned to 8 bytes (as required by `EXT_structural_metadata`)
+const ALIGNMENT: usize = 8;
+
+static INIT_ENCODERS: Once = Once::new();
+
+fn init_encoders() {
+    // The versions decodable by all the EXT_meshopt_compression implementations
+    INIT_ENCODERS.call_once(|| unsafe {
+        ffi::meshopt_encodeVertexVersion(0);
+        ffi::meshopt_encodeIndexVersion(1);
+    });
+}
+
+/// Compresses the given buffer views, and rebuilds the BIN content (buffer 0).
+///
+/// The compressed data and the other buffer views are packed into the new BIN content, and the compressed buffer views
+/// are moved to the fallback buffer (buffer 1). Returns the new BIN content and the byte length of the fallback buffer.
+pub fn compress_buffer_views(
+    bin: &[u8],
+    buffer_views: &mut [BufferView],
+    compressed_views: &[(usize, MeshoptCompressionMode)],
+) -> (Vec<u8>, u32) {
+    init_encoders();
+
+    let mut new_bin = Vec::with_capacity(bin.len());
+    let mut fallback_length = 0;
+    for (view_idx, view) in buffer_views.iter_mut().enumerate() {
+        let start = view.byte_offset as usize;
+        let data = &bin[start..start + view.byte_length as usize];
+        new_bin.resize(new_bin.len().next_multiple_of(ALIGNMENT), 0);
+
+        let Some(&(_, mode)) = compressed_views.iter().find(|(idx, _)| *idx == view_idx) else {
+            view.byte_offset = new_bin.len() as u32;
+            new_bin.extend_from_slice(data);
+            continue;
+        };
+
+        let (byte_stride, encoded) = match mode {
+            MeshoptCompressionMode::Attributes => {
+                let stride = view.byte_stride.expect("vertex buffer view without stride") as usize;
+                (stride, encode_vertices(data, stride))
+            }
+            MeshoptCompressionMode::Triangles | MeshoptCompressionMode::Indices => {
+                let indices: Vec<u32> = data
+                    .chunks_exact(4)
+                    .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                    .collect();
+                (4, encode_indices(&indices, mode))
+            }
+        };
+
+        view.extensions = Some(BufferViewExtensions {
+            ext_meshopt_compression: Some(ExtMeshoptCompression {
+                buffer: 0,
+                byte_offset: new_bin.len() as u32,
+                byte_length: encoded.len() as u32,
+                byte_stride: byte_stride as u32,
+                count: (data.len() / byte_stride) as u32,
+                mode,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        new_bin.extend_from_slice(&encoded);
+
+        fallback_length = fallback_length.next_multiple_of(ALIGNMENT as u32);
+        view.buffer = 1;
+        view.byte_offset = fallback_length;
+        fallback_length += view.byte_length;
+    }
+
+    (new_bin, fallback_length)
+}
+
+/// The buffer that the compressed buffer views refer to (it has no data)
+pub fn fallback_buffer(byte_length: u32) -> Buffer {
+    Buffer {
+        byte_length,
+        extensions: Some(BufferExtensions {
+            ext_meshopt_compression: Some(ExtMeshoptCompressionBuffer {
+                fallback: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+fn encode_vertices(data: &[u8], stride: usize) -> Vec<u8> {
+    assert!(stride % 4 == 0 && stride <= 256, "invalid vertex stride");
+    let count = data.len() / stride;
+    unsafe {
+        let mut encoded = vec![0; ffi::meshopt_encodeVertexBufferBound(count, stride)];
+        let size = ffi::meshopt_encodeVertexBuffer(
+            encoded.as_mut_ptr(),
+            encoded.len(),
+            data.as_ptr().cast(),
+            count,
+            stride,
+        );
+        encoded.truncate(size);
+        encoded
+    }
+}
+
+fn encode_indices(indices: &[u32], mode: MeshoptCompressionMode) -> Vec<u8> {
+    let vertex_count = indices.iter().max().map_or(0, |max| *max as usize + 1);
+    unsafe {
+        match mode {
+            MeshoptCompressionMode::Triangles => {
+                assert_eq!(indices.len() % 3, 0, "indices must be a list of triangles");
+                let bound = ffi::meshopt_encodeIndexBufferBound(indices.len(), vertex_count);
+                let mut encoded = vec![0; bound];
+                let size = ffi::meshopt_encodeIndexBuffer(
+                    encoded.as_mut_ptr(),
+                    encoded.len(),
+                    indices.as_ptr(),
+                    indices.len(),
+                );
+                encoded.truncate(size);
+                encoded
+            }
+            _ => {
+                let bound = ffi::meshopt_encodeIndexSequenceBound(indices.len(), vertex_count);
+                let mut encoded = vec![0; bound];
+                let size = ffi::meshopt_encodeIndexSequence(
+                    encoded.as_mut_ptr(),
+                    encoded.len(),
+                    indices.as_ptr(),
+                    indices.len(),
+                );
+                encoded.truncate(size);
+                encoded
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_vertices(encoded: &[u8], count: usize, stride: usize) -> Vec<u8> {
+        let mut decoded = vec![0u8; count * stride];
+        let result = unsafe {
+            ffi::meshopt_decodeVertexBuffer(
+                decoded.as_mut_ptr().cast(),
+                count,
+                stride,
+                encoded.as_ptr(),
+                encoded.len(),
+            )
+        };
+        assert_eq!(result, 0);
+        decoded
+    }
+
+    fn decode_indices(encoded: &[u8], count: usize, mode: MeshoptCompressionMode) -> Vec<u32> {
+        let mut decoded = vec![0u32; count];
+        let result = unsafe {
+            match mode {
+                MeshoptCompressionMode::Triangles => ffi::meshopt_decodeIndexBuffer(
+                    decoded.as_mut_ptr().cast(),
+                    count,
+                    4,
+                    encoded.as_ptr(),
+                    encoded.len(),
+                ),
+                _ => ffi::meshopt_decodeIndexSequence(
+                    decoded.as_mut_ptr().cast(),
+                    count,
+                    4,
+                    encoded.as_ptr(),
+                    encoded.len(),
+                ),
+            }
+        };
+        assert_eq!(result, 0);
+        decoded
+    }
+
+    #[test]
+    fn test_compress_buffer_views() {
+        // a vertex buffer (8 vertices x 12 bytes), an uncompressed view, and triangle indices
+        let vertices: Vec<u8> = (0..96).map(|i| (i * 7 % 251) as u8).collect();
+        let metadata = [1u8, 2, 3];
+        let indices = [0u32, 1, 2, 2, 1, 3, 4, 5, 6, 6, 5, 7];
+        let mut bin = vertices.clone();
+        bin.extend_from_slice(&metadata);
+        bin.push(0);
+        bin.extend(indices.iter().flat_map(|i| i.to_le_bytes()));
+
+        let mut buffer_views = vec![
+            BufferView {
+                byte_offset: 0,
+                byte_length: 96,
+                byte_stride: Some(12),
+                ..Default::default()
+            },
+            BufferView {
+                byte_offset: 96,
+                byte_length: 3,
+                ..Default::default()
+            },
+            BufferView {
+                byte_offset: 100,
+                byte_length: 48,
+                ..Default::default()
+            },
+        ];
+        let (new_bin, fallback_length) = compress_buffer_views(
+            &bin,
+            &mut buffer_views,
+            &[
+                (0, MeshoptCompressionMode::Attributes),
+                (2, MeshoptCompressionMode::Triangles),
+            ],
+        );
+
+        // the uncompressed view is kept in buffer 0
+        let view = &buffer_views[1];
+        assert_eq!(view.buffer, 0);
+        assert_eq!(view.byte_offset % 8, 0);
+        let start = view.byte_offset as usize;
+        assert_eq!(&new_bin[start..start + 3], metadata);
+
+        // the compressed views are moved to the fallback buffer
+        assert_eq!(
+            (buffer_views[0].buffer, buffer_views[0].byte_offset),
+            (1, 0)
+        );
+        assert_eq!(
+            (buffer_views[2].buffer, buffer_views[2].byte_offset),
+            (1, 96)
+        );
+        assert_eq!(fallback_length, 96 + 48);
+
+        let ext = |idx: usize| {
+            buffer_views[idx]
+                .extensions
+                .as_ref()
+                .unwrap()
+                .ext_meshopt_compression
+                .clone()
+                .unwrap()
+        };
+        let ext_vertices = ext(0);
+        assert_eq!((ext_vertices.count, ext_vertices.byte_stride), (8, 12));
+        let start = ext_vertices.byte_offset as usize;
+        let data = &new_bin[start..start + ext_vertices.byte_length as usize];
+        assert_eq!(decode_vertices(data, 8, 12), vertices);
+
+        let ext_indices = ext(2);
+        assert_eq!(ext_indices.count, 12);
+        let start = ext_indices.byte_offset as usize;
+        let data = &new_bin[start..start + ext_indices.byte_length as usize];
+        assert_eq!(
+            decode_indices(data, 12, MeshoptCompressionMode::Triangles),
+            indices
+        );
+    }
+
+    #[test]
+    fn test_encode_index_sequence() {
+        let indices = [0u32, 1, 1, 2, 5, 3, 3];
+        let encoded = encode_indices(&indices, MeshoptCompressionMode::Indices);
+        assert_eq!(
+            decode_indices(&encoded, indices.len(), MeshoptCompressionMode::Indices),
+            indices
+        );
+    }
+}
@@ -4,7 +4,7 @@
 
 use std::io::Write;
 
-use flatgeom::{Coord, MultiPolygon, Polygon};
+use flatgeom::{Coord, MultiLineString, MultiPoint, MultiPolygon, Polygon};
 
 #[repr(u8)]
 pub enum WkbByteOrder {
@@ -95,6 +95,124 @@ pub fn write_indexed_multipolygon<W: Write>(
     Ok(())
 }
 
+/// Writes the multipolygon as a plain (ISO) WKB, without the GeoPackage header
+pub fn write_indexed_multipolygon_wkb<W: Write>(
+    writer: &mut W,
+    vertices: &[[f64; 3]],
+    mpoly: &MultiPolygon<u32>,
+) -> std::io::Result<()> {
+    write_multipolygon_body(writer, mpoly, |idx| vertices[idx as usize])
+}
+
+/// Writes a linestring (wkbLineStringZ) with the GeoPackage header
+pub fn write_linestring<W: Write>(
+    writer: &mut W,
+    coords: &[[f64; 3]],
+    srs_id: i32,
+) -> std::io::Result<()> {
+    write_geometry_header(writer, srs_id)?;
+    write_linestring_body(writer, coords.iter().copied(), coords.len())
+}
+
+/// Writes a multilinestring (wkbMultiLineStringZ) with the GeoPackage header
+pub fn write_indexed_multilinestring<W: Write>(
+    writer: &mut W,
+    vertices: &[[f64; 3]],
+    mls: &MultiLineString<u32>,
+    srs_id: i32,
+) -> std::io::Result<()> {
+    write_geometry_header(writer, srs_id)?;
+    write_multilinestring_body(writer, mls, |idx| vertices[idx as usize])
+}
+
+fn write_linestring_body<W: Write>(
+    writer: &mut W,
+    coords: impl IntoIterator<Item = [f64; 3]>,
+    num_points: usize,
+) -> std::io::Result<()> {
+    // Byte order: Little endian (1)
+    writer.write_all(&[WkbByteOrder::LittleEndian as u8])?;
+
+    // Geometry type: wkbLineStringZ (1002)
+    writer.write_all(&(WkbGeometryType::LineStringZ as u32).to_le_bytes())?;
+
+    // numPoints
+    writer.write_all(&(num_points as u32).to_le_bytes())?;
+
+    for [x, y, z] in coords {
+        writer.write_all(&f64::to_le_bytes(x))?;
+        writer.write_all(&f64::to_le_bytes(y))?;
+        writer.write_all(&f64::to_le_bytes(z))?;
+    }
+    Ok(())
+}
+
+fn write_multilinestring_body<W: Write, T: Coord>(
+    writer: &mut W,
+    mls: &MultiLineString<T>,
+    mapping: impl Fn(T) -> [f64; 3],
+) -> std::io::Result<()> {
+    // Byte order: Little endian (1)
+    writer.write_all(&[WkbByteOrder::LittleEndian as u8])?;
+
+    // Geometry type: wkbMultiLineStringZ (1005)
+    writer.write_all(&(WkbGeometryType::MultiLineStringZ as u32).to_le_bytes())?;
+
+    // numLineStrings
+    writer.write_all(&(mls.len() as u32).to_le_bytes())?;
+
+    for ls in mls.iter() {
+        write_linestring_body(writer, ls.iter().map(&mapping), ls.raw_coords().len())?;
+    }
+    Ok(())
+}
+
+/// Writes a point (wkbPointZ) with the GeoPackage header
+pub fn write_point<W: Write>(writer: &mut W, coord: [f64; 3], srs_id: i32) -> std::io::Result<()> {
+    write_geometry_header(writer, srs_id)?;
+    write_point_body(writer, coord)
+}
+
+/// Writes a multipoint (wkbMultiPointZ) with the GeoPackage header
+pub fn write_indexed_multipoint<W: Write>(
+    writer: &mut W,
+    vertices: &[[f64; 3]],
+    mpoint: &MultiPoint<u32>,
+    srs_id: i32,
+) -> std::io::Result<()> {
+    write_geometry_header(writer, srs_id)?;
+    write_multipoint_body(writer, mpoint, |idx| vertices[idx as usize])
+}
+
+fn write_point_body<W: Write>(writer: &mut W, coord: [f64; 3]) -> std::io::Result<()> {
+    writer.write_all(&[WkbByteOrder::LittleEndian as u8])?;
+    writer.write_all(&(WkbGeometryType::PointZ as u32).to_le_bytes())?;
+    for v in coord {
+        writer.write_all(&f64::to_le_bytes(v))?;
+    }
+    Ok(())
+}
+
+fn write_multipoint_body<W: Write, T: Coord>(
+    writer: &mut W,
+    mpoint: &MultiPoint<T>,
+    mapping: impl Fn(T) -> [f64; 3],
+) -> std::io::Result<()> {
+    // Byte order: Little endian (1)
+    writer.write_all(&[WkbByteOrder::LittleEndian as u8])?;
+
+    // Geometry type: wkbMultiPointZ (1004)
+    writer.write_all(&(WkbGeometryType::MultiPointZ as u32).to_le_bytes())?;
+
+    // numPoints
+    writer.write_all(&(mpoint.len() as u32).to_le_bytes())?;
+
+    for point in mpoint.iter() {
+        write_point_body(writer, mapping(point))?;
+    }
+    Ok(())
+}
+
 fn write_multipolygon_body<W: Write, T: Coord>(
     writer: &mut W,
     mpoly: &MultiPolygon<T>,
@@ -120,6 +238,77 @@ fn write_multipolygon_body<W: Write, T: Coord>(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_linestring_and_point_to_bytes() {
+        let mut bytes = Vec::new();
+        write_linestring(&mut bytes, &[[0., 0., 1.], [5., 0., 2.]], 6697).unwrap();
+        // header (8) + byte order (1) + type (4) + numPoints (4) + 2 points (48)
+        assert_eq!(bytes.len(), 65);
+        assert_eq!(bytes[4..=7].to_vec(), &i32::to_le_bytes(6697));
+        assert_eq!(bytes[9..=12].to_vec(), &1002_u32.to_le_bytes());
+        assert_eq!(bytes[13..=16].to_vec(), &2_u32.to_le_bytes());
+        assert_eq!(bytes[41..=48].to_vec(), &5_f64.to_le_bytes());
+
+        let mut bytes = Vec::new();
+        write_point(&mut bytes, [1., 2., 3.], 6697).unwrap();
+        assert_eq!(bytes.len(), 37);
+        assert_eq!(bytes[9..=12].to_vec(), &1001_u32.to_le_bytes());
+        assert_eq!(bytes[29..=36].to_vec(), &3_f64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_multilinestring_to_bytes() {
+        let vertices: Vec<[f64; 3]> = vec![[0., 0., 1.], [5., 0., 2.], [5., 5., 3.]];
+        let mut mls = MultiLineString::<u32>::new();
+        mls.add_linestring([0, 1, 2]);
+        mls.add_linestring([2, 0]);
+
+        let mut bytes = Vec::new();
+        write_indexed_multilinestring(&mut bytes, &vertices, &mls, 6697).unwrap();
+        // header (8) + byte order (1) + type (4) + numLineStrings (4)
+        // + 2 linestrings (byte order, type and numPoints: 9 each) + 5 points (120)
+        assert_eq!(bytes.len(), 155);
+        assert_eq!(bytes[9..=12].to_vec(), &1005_u32.to_le_bytes());
+        assert_eq!(bytes[13..=16].to_vec(), &2_u32.to_le_bytes());
+
+        // 1st linestring
+        assert_eq!(bytes[17], 0x01);
+        assert_eq!(bytes[18..=21].to_vec(), &1002_u32.to_le_bytes());
+        assert_eq!(bytes[22..=25].to_vec(), &3_u32.to_le_bytes());
+        assert_eq!(bytes[26..=33].to_vec(), &0_f64.to_le_bytes());
+
+        // 2nd linestring
+        assert_eq!(bytes[98], 0x01);
+        assert_eq!(bytes[103..=106].to_vec(), &2_u32.to_le_bytes());
+        assert_eq!(bytes[107..=114].to_vec(), &5_f64.to_le_bytes());
+        assert_eq!(bytes[147..=154].to_vec(), &1_f64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_multipoint_to_bytes() {
+        let vertices: Vec<[f64; 3]> = vec![[0., 0., 1.], [5., 0., 2.], [5., 5., 3.]];
+        let mut mpoint = MultiPoint::<u32>::new();
+        mpoint.push(2);
+        mpoint.push(0);
+
+        let mut bytes = Vec::new();
+        write_indexed_multipoint(&mut bytes, &vertices, &mpoint, 6697).unwrap();
+        // header (8) + byte order (1) + type (4) + numPoints (4) + 2 points (29 each)
+        assert_eq!(bytes.len(), 75);
+        assert_eq!(bytes[9..=12].to_vec(), &1004_u32.to_le_bytes());
+        assert_eq!(bytes[13..=16].to_vec(), &2_u32.to_le_bytes());
+
+        // 1st point
+        assert_eq!(bytes[17], 0x01);
+        assert_eq!(bytes[18..=21].to_vec(), &1001_u32.to_le_bytes());
+        assert_eq!(bytes[22..=29].to_vec(), &5_f64.to_le_bytes());
+        assert_eq!(bytes[38..=45].to_vec(), &3_f64.to_le_bytes());
+
+        // 2nd point
+        assert_eq!(bytes[46], 0x01);
+        assert_eq!(bytes[67..=74].to_vec(), &1_f64.to_le_bytes());
+    }
+
     #[test]
     fn test_multipolygon_to_bytes() {
         let vertices: Vec<[f64; 3]> = vec![
@@ -5,7 +5,7 @@ use sqlx::{sqlite::*, Acquire, ConnectOptions, Pool, Row};
 use thiserror::Error;
 use url::Url;
 
-use crate::table::TableInfo;
+use crate::table::{ColumnValue, TableInfo};
 
 pub struct GpkgHandler {
     pool: Pool<Sqlite>,
@@ -27,11 +27,15 @@ impl GpkgHandler {
         Self::initialize(SqliteConnectOptions::from_str(str)?).await
     }
 
+    /// Open an existing GeoPackage database (e.g. to update it), without initializing it
+    pub async fn open_str(str: &str) -> Result<Self, GpkgError> {
+        let conn_opts = tune(SqliteConnectOptions::from_str(str)?);
+        let pool = SqlitePoolOptions::new().connect_with(conn_opts).await?;
+        Ok(Self { pool })
+    }
+
     async fn initialize(conn_opts: SqliteConnectOptions) -> Result<Self, GpkgError> {
-        let conn_opts = conn_opts
-            .create_if_missing(true)
-            .synchronous(SqliteSynchronous::Normal)
-            .journal_mode(SqliteJournalMode::Wal);
+        let conn_opts = tune(conn_opts.create_if_missing(true));
         let pool = SqlitePoolOptions::new().connect_with(conn_opts).await?;
 
         // Initialize the database with minimum GeoPackage schema
@@ -59,6 +63,17 @@ impl GpkgHandler {
         Ok((min_x, min_y, max_x, max_y))
     }
 
+    /// Get the largest rowid in the table (0 if the table is empty)
+    pub async fn max_rowid(&self, table_name: &str) -> Result<i64, GpkgError> {
+        let result = sqlx::query(&format!(
+            "SELECT IFNULL(MAX(rowid), 0) FROM \"{}\";",
+            table_name
+        ))
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(result.get(0))
+    }
+
     pub async fn application_id(&self) -> u32 {
         let result = sqlx::query("PRAGMA application_id;")
             .fetch_one(&self.pool)
@@ -173,6 +188,15 @@ impl GpkgHandler {
         Ok(result)
     }
 
+    /// Write the committed pages of the WAL back into the database and truncate the WAL file,
+    /// so that the WAL does not keep growing while the transactions are committed one after another
+    pub async fn checkpoint(&self) -> Result<(), GpkgError> {
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE);")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn begin(&mut self) -> Result<GpkgTransaction, GpkgError> {
         Ok(GpkgTransaction::new(self.pool.begin().await?))
     }
@@ -251,18 +275,65 @@ impl<'c> GpkgTransaction<'c> {
         Ok(())
     }
 
-    /// Add a record to the feature table
+    /// Create a view and register it to `gpkg_contents` (and `gpkg_geometry_columns` if it has the `geometry` column)
+    pub async fn add_view(
+        &mut self,
+        view_name: &str,
+        select: &str,
+        has_geometry: bool,
+        srs_id: u16,
+    ) -> Result<(), GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+
+        // The view may already exist when updating an existing database
+        let query_string = format!("CREATE VIEW IF NOT EXISTS \"{}\" AS {};", view_name, select);
+        sqlx::query(&query_string).execute(&mut *executor).await?;
+
+        sqlx::query(
+            "INSERT OR IGNORE INTO gpkg_contents (table_name, data_type, identifier, srs_id) VALUES (?, ?, \
+             ?, ?);",
+        )
+        .bind(view_name)
+        .bind(if has_geometry {
+            "features"
+        } else {
+            "attributes"
+        })
+        .bind(view_name)
+        .bind(srs_id)
+        .execute(&mut *executor)
+        .await?;
+
+        if has_geometry {
+            sqlx::query(
+                "INSERT OR IGNORE INTO gpkg_geometry_columns (table_name, column_name, \
+                 geometry_type_name, srs_id, z, m) VALUES (?, ?, ?, ?, ?, ?);",
+            )
+            .bind(view_name)
+            .bind("geometry")
+            .bind("MULTIPOLYGON")
+            .bind(srs_id)
+            .bind(1)
+            .bind(0)
+            .execute(&mut *executor)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a record to the feature table, and returns its `fid`
     // TODO: handle MultiLineString, MultiPoint (currently only MultiPolygonZ is supported)
-    pub async fn insert_feature(
+    pub async fn insert_feature<V: Clone + Into<ColumnValue>>(
         &mut self,
         table_name: &str,
         id: &str,
         bytes: &[u8],
-        attributes: &IndexMap<String, String>,
-    ) -> Result<(), GpkgError> {
+        attributes: &IndexMap<String, V>,
+    ) -> Result<i64, GpkgError> {
         let executor = self.tx.acquire().await.unwrap();
 
-        if attributes.is_empty() {
+        let result = if attributes.is_empty() {
             let query_string = format!(
                 "INSERT INTO \"{}\" (id, geometry) VALUES (?, ?)",
                 table_name
@@ -271,7 +342,7 @@ impl<'c> GpkgTransaction<'c> {
                 .bind(id)
                 .bind(bytes)
                 .execute(&mut *executor)
-                .await?;
+                .await?
         } else {
             let query_string = format!(
                 "INSERT INTO \"{}\" (id, geometry, {}) VALUES (?, ?, {})",
@@ -285,41 +356,307 @@ impl<'c> GpkgTransaction<'c> {
             );
             let mut query = sqlx::query(&query_string).bind(id).bind(bytes);
             for value in attributes.values() {
-                query = query.bind(value);
+                query = bind_value(query, value.clone().into());
             }
-            query.execute(&mut *executor).await?;
-        }
+            query.execute(&mut *executor).await?
+        };
 
-        Ok(())
+        Ok(result.last_insert_rowid())
     }
 
-    /// Add a record to the attribute table
-    pub async fn insert_attribute(
+    /// Add a record to the attribute table, and returns its `id`
+    pub async fn insert_attribute<V: Clone + Into<ColumnValue>>(
         &mut self,
         table_name: &str,
-        attributes: &IndexMap<String, String>,
-    ) -> Result<(), GpkgError> {
+        attributes: &IndexMap<String, V>,
+    ) -> Result<i64, GpkgError> {
         let query_string = format!(
             "INSERT INTO \"{}\" ({}) VALUES ({})",
             table_name,
             attributes
                 .keys()
-                .map(|key| key.to_string())
+                .map(|key| format!("\"{}\"", key))
                 .collect::<Vec<_>>()
                 .join(", "),
             vec!["?"; attributes.len()].join(", ")
         );
         let mut query = sqlx::query(&query_string);
         for value in attributes.values() {
-            query = query.bind(value);
+            query = bind_value(query, value.clone().into());
         }
 
         let executor: &mut SqliteConnection = self.tx.acquire().await.unwrap();
-        query.execute(&mut *executor).await?;
+        let result = query.execute(&mut *executor).await?;
+
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Whether a record whose `column` equals `value` exists among the rows up to `max_rowid`
+    pub async fn row_exists(
+        &mut self,
+        table_name: &str,
+        column: &str,
+        value: &str,
+        max_rowid: i64,
+    ) -> Result<bool, GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+        let query_string = format!(
+            "SELECT EXISTS (SELECT 1 FROM \"{}\" WHERE \"{}\" = ? AND rowid <= ?);",
+            table_name, column
+        );
+        let exists: i64 = sqlx::query_scalar(&query_string)
+            .bind(value)
+            .bind(max_rowid)
+            .fetch_one(&mut *executor)
+            .await?;
+        Ok(exists != 0)
+    }
+
+    /// Create an index on the column (if not yet), e.g. to look up the rows by `row_exists` or `delete_rows`
+    pub async fn create_index(&mut self, table_name: &str, column: &str) -> Result<(), GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+        let query_string = format!(
+            "CREATE INDEX IF NOT EXISTS \"{}_{}_idx\" ON \"{}\" (\"{}\");",
+            table_name, column, table_name, column
+        );
+        sqlx::query(&query_string).execute(&mut *executor).await?;
+        Ok(())
+    }
+
+    /// Delete the records whose `column` equals `value`, only among the rows up to `max_rowid`.
+    ///
+    /// Returns the number of the deleted rows.
+    pub async fn delete_rows(
+        &mut self,
+        table_name: &str,
+        column: &str,
+        value: &str,
+        max_rowid: i64,
+    ) -> Result<u64, GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+        let query_string = format!(
+            "DELETE FROM \"{}\" WHERE \"{}\" = ? AND rowid <= ?;",
+            table_name, column
+        );
+        let result = sqlx::query(&query_string)
+            .bind(value)
+            .bind(max_rowid)
+            .execute(&mut *executor)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Delete the features whose `column` equals `value`, only among the rows up to `max_rowid`.
+    ///
+    /// Returns the `id`s of the deleted features (e.g. to delete the features referring to them by `parentId`).
+    pub async fn delete_features(
+        &mut self,
+        table_name: &str,
+        column: &str,
+        value: &str,
+        max_rowid: i64,
+    ) -> Result<Vec<String>, GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+        let query_string = format!(
+            "DELETE FROM \"{}\" WHERE \"{}\" = ? AND rowid <= ? RETURNING id;",
+            table_name, column
+        );
+        let ids = sqlx::query_scalar(&query_string)
+            .bind(value)
+            .bind(max_rowid)
+            .fetch_all(&mut *executor)
+            .await?;
+        Ok(ids)
+    }
+
+    /// Set the geometry type (e.g. `LINESTRING`, `POINT`) of a feature table in `gpkg_geometry_columns`
+    ///
+    /// The tables are registered as `MULTIPOLYGON` by `add_table`.
+    pub async fn set_geometry_type(
+        &mut self,
+        table_name: &str,
+        geometry_type_name: &str,
+    ) -> Result<(), GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+        sqlx::query(
+            "UPDATE gpkg_geometry_columns SET geometry_type_name = ? WHERE table_name = ?;",
+        )
+        .bind(geometry_type_name)
+        .bind(table_name)
+        .execute(&mut *executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Add a relation of the Related Tables extension and create its mapping table (if not yet)
+    ///
+    /// The rows are related with `insert_relation`.
+    pub async fn add_relation(
+        &mut self,
+        base_table: &str,
+        base_primary_column: &str,
+        related_table: &str,
+        relation_name: &str,
+        mapping_table: &str,
+    ) -> Result<(), GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+
+        sqlx::query(include_str!("sql/extensions.sql"))
+            .execute(&mut *executor)
+            .await?;
+        sqlx::query(include_str!("sql/related_tables.sql"))
+            .execute(&mut *executor)
+            .await?;
+
+        let query_string = format!(
+            "CREATE TABLE IF NOT EXISTS \"{}\" (base_id INTEGER NOT NULL, related_id INTEGER NOT NULL);",
+            mapping_table
+        );
+        sqlx::query(&query_string).execute(&mut *executor).await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO gpkg_contents (table_name, data_type, identifier, srs_id) \
+             VALUES (?, 'attributes', ?, 0);",
+        )
+        .bind(mapping_table)
+        .bind(mapping_table)
+        .execute(&mut *executor)
+        .await?;
+        sqlx::query(
+            "INSERT INTO gpkg_extensions (table_name, column_name, extension_name, definition, scope) \
+             SELECT ?, NULL, 'gpkg_related_tables', 'http://docs.opengeospatial.org/is/18-000/18-000.html', 'read-write' \
+             WHERE NOT EXISTS (SELECT 1 FROM gpkg_extensions WHERE table_name = ? AND extension_name = 'gpkg_related_tables');",
+        )
+        .bind(mapping_table)
+        .bind(mapping_table)
+        .execute(&mut *executor)
+        .await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO gpkgext_relations (base_table_name, base_primary_column, \
+             related_table_name, related_primary_column, relation_name, mapping_table_name) \
+             VALUES (?, ?, ?, 'id', ?, ?);",
+        )
+        .bind(base_table)
+        .bind(base_primary_column)
+        .bind(related_table)
+        .bind(relation_name)
+        .bind(mapping_table)
+        .execute(&mut *executor)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Relate a row of the base table to a row of the related table (see `add_relation`)
+    pub async fn insert_relation(
+        &mut self,
+        mapping_table: &str,
+        base_id: i64,
+        related_id: i64,
+    ) -> Result<(), GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+        let query_string = format!(
+            "INSERT INTO \"{}\" (base_id, related_id) VALUES (?, ?);",
+            mapping_table
+        );
+        sqlx::query(&query_string)
+            .bind(base_id)
+            .bind(related_id)
+            .execute(&mut *executor)
+            .await?;
+        Ok(())
+    }
+
+    /// Add the default style of a feature table to `layer_styles` (created if missing),
+    /// as QML (used by QGIS) and SLD (used by the other applications).
+    ///
+    /// The existing style with the same name is replaced.
+    pub async fn add_layer_style(
+        &mut self,
+        table_name: &str,
+        style_name: &str,
+        qml: &str,
+        sld: &str,
+    ) -> Result<(), GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+
+        sqlx::query(include_str!("sql/layer_styles.sql"))
+            .execute(&mut *executor)
+            .await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO gpkg_contents (table_name, data_type, identifier, srs_id) \
+             VALUES ('layer_styles', 'attributes', 'layer_styles', 0);",
+        )
+        .execute(&mut *executor)
+        .await?;
+
+        sqlx::query("DELETE FROM layer_styles WHERE f_table_name = ? AND styleName = ?;")
+            .bind(table_name)
+            .bind(style_name)
+            .execute(&mut *executor)
+            .await?;
+        sqlx::query(
+            "INSERT INTO layer_styles (f_table_catalog, f_table_schema, f_table_name, \
+             f_geometry_column, styleName, styleQML, styleSLD, useAsDefault, description) \
+             VALUES ('', '', ?, 'geometry', ?, ?, ?, 1, '');",
+        )
+        .bind(table_name)
+        .bind(style_name)
+        .bind(qml)
+        .bind(sld)
+        .execute(&mut *executor)
+        .await?;
 
         Ok(())
     }
 
+    /// Add a metadata document to `gpkg_metadata` (the tables of the Metadata extension are created if missing),
+    /// referenced from a table, or from the whole GeoPackage if `table_name` is None.
+    ///
+    /// Returns the id of the metadata.
+    pub async fn add_metadata(
+        &mut self,
+        md_standard_uri: &str,
+        mime_type: &str,
+        metadata: &str,
+        table_name: Option<&str>,
+    ) -> Result<i64, GpkgError> {
+        let executor = self.tx.acquire().await.unwrap();
+
+        sqlx::query(include_str!("sql/extensions.sql"))
+            .execute(&mut *executor)
+            .await?;
+        sqlx::query(include_str!("sql/metadata.sql"))
+            .execute(&mut *executor)
+            .await?;
+
+        let md_file_id = sqlx::query(
+            "INSERT INTO gpkg_metadata (md_scope, md_standard_uri, mime_type, metadata) VALUES \
+             ('dataset', ?, ?, ?);",
+        )
+        .bind(md_standard_uri)
+        .bind(mime_type)
+        .bind(metadata)
+        .execute(&mut *executor)
+        .await?
+        .last_insert_rowid();
+
+        let reference_scope = match table_name {
+            Some(_) => "table",
+            None => "geopackage",
+        };
+        sqlx::query(
+            "INSERT INTO gpkg_metadata_reference (reference_scope, table_name, md_file_id) VALUES \
+             (?, ?, ?);",
+        )
+        .bind(reference_scope)
+        .bind(table_name)
+        .bind(md_file_id)
+        .execute(&mut *executor)
+        .await?;
+
+        Ok(md_file_id)
+    }
+
     /// Update the bounding box of a table (min_x, min_y, max_x, max_y)
     pub async fn update_bbox(
         &mut self,
@@ -341,6 +678,30 @@ impl<'c> GpkgTransaction<'c> {
     }
 }
 
+/// Connection settings for writing a large number of rows
+fn tune(conn_opts: SqliteConnectOptions) -> SqliteConnectOptions {
+    conn_opts
+        .synchronous(SqliteSynchronous::Normal)
+        .journal_mode(SqliteJournalMode::Wal)
+        // 64 MiB of the page cache (negative values are in KiB)
+        .pragma("cache_size", "-65536")
+        .pragma("temp_store", "memory")
+}
+
+/// Binds the value with its storage class (not as TEXT), so that the values can be compared as numbers
+fn bind_value<'q>(
+    query: sqlx::query::Query<'q, Sqlite, SqliteArguments<'q>>,
+    value: ColumnValue,
+) -> sqlx::query::Query<'q, Sqlite, SqliteArguments<'q>> {
+    match value {
+        ColumnValue::Null => query.bind(None::<String>),
+        ColumnValue::Integer(i) => query.bind(i),
+        ColumnValue::Real(f) => query.bind(f),
+        ColumnValue::Boolean(b) => query.bind(b),
+        ColumnValue::Text(s) => query.bind(s),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -639,6 +1000,465 @@ mod tests {
         assert!(row.get::<bool, &str>("attr4"));
     }
 
+    #[tokio::test]
+    async fn test_insert_typed_values() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+        let column = |name: &str, data_type: &str| ColumnInfo {
+            name: name.into(),
+            data_type: data_type.into(),
+            mime_type: None,
+        };
+        let table_info = TableInfo {
+            name: "typed".into(),
+            has_geometry: true,
+            columns: vec![
+                column("text", "TEXT"),
+                column("integer", "INTEGER"),
+                column("real", "REAL"),
+                column("boolean", "BOOLEAN"),
+                column("date", "DATE"),
+                column("missing", "INTEGER"),
+            ],
+        };
+        let attributes = IndexMap::from([
+            ("text".to_string(), ColumnValue::from("12")),
+            ("integer".to_string(), ColumnValue::Integer(2)),
+            ("real".to_string(), ColumnValue::Real(3.5)),
+            ("boolean".to_string(), ColumnValue::Boolean(true)),
+            ("date".to_string(), ColumnValue::from("2024-01-31")),
+            ("missing".to_string(), ColumnValue::Null),
+        ]);
+
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_table(&table_info, 4326).await.unwrap();
+        tx.insert_feature("typed", "id_1", &[0, 1, 2, 3], &attributes)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let row = sqlx::query(
+            "SELECT typeof(text) AS text, typeof(integer) AS integer, typeof(real) AS real, \
+             typeof(boolean) AS boolean, typeof(date) AS date, typeof(missing) AS missing FROM typed",
+        )
+        .fetch_one(&handler.pool)
+        .await
+        .unwrap();
+        assert_eq!(row.get::<String, &str>("text"), "text");
+        assert_eq!(row.get::<String, &str>("integer"), "integer");
+        assert_eq!(row.get::<String, &str>("real"), "real");
+        assert_eq!(row.get::<String, &str>("boolean"), "integer");
+        assert_eq!(row.get::<String, &str>("date"), "text");
+        assert_eq!(row.get::<String, &str>("missing"), "null");
+    }
+
+    #[tokio::test]
+    async fn test_add_view() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+
+        let srs_id = 4326;
+        let table_info = TableInfo {
+            name: "mpoly3d".into(),
+            has_geometry: true,
+            columns: vec![ColumnInfo {
+                name: "attr1".into(),
+                data_type: "TEXT".into(),
+                mime_type: None,
+            }],
+        };
+
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_table(&table_info, srs_id).await.unwrap();
+        tx.insert_feature(
+            "mpoly3d",
+            "id_1",
+            &[0, 1, 2, 3],
+            &IndexMap::<String, String>::from([("attr1".into(), "value1".into())]),
+        )
+        .await
+        .unwrap();
+        tx.add_view(
+            "mpoly3d_view",
+            "SELECT fid, id, geometry, attr1 FROM mpoly3d",
+            true,
+            srs_id,
+        )
+        .await
+        .unwrap();
+        tx.add_view(
+            "mpoly3d_count",
+            "SELECT COUNT(*) AS feature_count FROM mpoly3d",
+            false,
+            srs_id,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let rows = handler.fetch_rows("mpoly3d_view").await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<String, &str>("attr1"), "value1");
+
+        let rows = handler.fetch_rows("mpoly3d_count").await.unwrap();
+        assert_eq!(rows[0].get::<i64, &str>("feature_count"), 1);
+
+        let gpkg_contents = handler.gpkg_contents().await.unwrap();
+        let data_type = |name: &str| {
+            gpkg_contents
+                .iter()
+                .find(|row| row.0 == name)
+                .map(|row| row.1.clone())
+        };
+        assert_eq!(gpkg_contents.len(), 3);
+        assert_eq!(data_type("mpoly3d_view").as_deref(), Some("features"));
+        assert_eq!(data_type("mpoly3d_count").as_deref(), Some("attributes"));
+        assert_eq!(handler.gpkg_geometry_columns().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_delete_rows() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+
+        let table_info = TableInfo {
+            name: "mpoly3d".into(),
+            has_geometry: true,
+            columns: vec![],
+        };
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_table(&table_info, 4326).await.unwrap();
+        for id in ["id_1", "id_2"] {
+            tx.insert_feature(
+                "mpoly3d",
+                id,
+                &[0, 1, 2, 3],
+                &IndexMap::<String, String>::new(),
+            )
+            .await
+            .unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        let max_rowid = handler.max_rowid("mpoly3d").await.unwrap();
+        assert_eq!(max_rowid, 2);
+
+        // the rows inserted after `max_rowid` are kept
+        let mut tx = handler.begin().await.unwrap();
+        let fid = tx
+            .insert_feature(
+                "mpoly3d",
+                "id_1",
+                &[4, 5, 6, 7],
+                &IndexMap::<String, String>::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(fid, 3);
+        let deleted = tx
+            .delete_rows("mpoly3d", "id", "id_1", max_rowid)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(deleted, 1);
+
+        let rows = handler.fetch_rows("mpoly3d").await.unwrap();
+        let ids: Vec<String> = rows.iter().map(|row| row.get("id")).collect();
+        assert_eq!(ids, vec!["id_2", "id_1"]);
+        assert_eq!(rows[1].get::<Vec<u8>, &str>("geometry"), vec![4, 5, 6, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_features() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+
+        let table_info = TableInfo {
+            name: "part".into(),
+            has_geometry: true,
+            columns: vec![ColumnInfo {
+                name: "parentId".into(),
+                data_type: "TEXT".into(),
+                mime_type: None,
+            }],
+        };
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_table(&table_info, 4326).await.unwrap();
+        for (id, parent_id) in [
+            ("part_1", "bldg_1"),
+            ("part_2", "bldg_1"),
+            ("part_3", "bldg_2"),
+        ] {
+            tx.insert_feature(
+                "part",
+                id,
+                &[0, 1, 2, 3],
+                &IndexMap::<String, String>::from([("parentId".into(), parent_id.into())]),
+            )
+            .await
+            .unwrap();
+        }
+        let mut deleted = tx
+            .delete_features("part", "parentId", "bldg_1", i64::MAX)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        deleted.sort();
+        assert_eq!(deleted, vec!["part_1", "part_2"]);
+
+        let rows = handler.fetch_rows("part").await.unwrap();
+        let ids: Vec<String> = rows.iter().map(|row| row.get("id")).collect();
+        assert_eq!(ids, vec!["part_3"]);
+    }
+
+    #[tokio::test]
+    async fn test_row_exists() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+        let table_info = TableInfo {
+            name: "mpoly3d".into(),
+            has_geometry: true,
+            columns: vec![],
+        };
+
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_table(&table_info, 4326).await.unwrap();
+        let attributes = IndexMap::<String, String>::new();
+        let max_rowid = tx
+            .insert_feature("mpoly3d", "id_1", &[0, 1, 2, 3], &attributes)
+            .await
+            .unwrap();
+        tx.insert_feature("mpoly3d", "id_2", &[0, 1, 2, 3], &attributes)
+            .await
+            .unwrap();
+        tx.create_index("mpoly3d", "id").await.unwrap();
+        // (again)
+        tx.create_index("mpoly3d", "id").await.unwrap();
+
+        assert!(tx
+            .row_exists("mpoly3d", "id", "id_1", max_rowid)
+            .await
+            .unwrap());
+        // after `max_rowid`
+        assert!(!tx
+            .row_exists("mpoly3d", "id", "id_2", max_rowid)
+            .await
+            .unwrap());
+        assert!(!tx
+            .row_exists("mpoly3d", "id", "id_3", i64::MAX)
+            .await
+            .unwrap());
+        tx.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+        let table_info = TableInfo {
+            name: "mpoly3d".into(),
+            has_geometry: true,
+            columns: vec![],
+        };
+        let attributes = IndexMap::<String, String>::new();
+
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_table(&table_info, 4326).await.unwrap();
+        tx.insert_feature("mpoly3d", "id_1", &[0, 1, 2, 3], &attributes)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        handler.checkpoint().await.unwrap();
+
+        // continue in another transaction
+        let mut tx = handler.begin().await.unwrap();
+        tx.insert_feature("mpoly3d", "id_2", &[0, 1, 2, 3], &attributes)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        handler.checkpoint().await.unwrap();
+
+        let rows = handler.fetch_rows("mpoly3d").await.unwrap();
+        let ids: Vec<String> = rows.iter().map(|row| row.get("id")).collect();
+        assert_eq!(ids, vec!["id_1", "id_2"]);
+    }
+
+    #[tokio::test]
+    async fn test_add_layer_style() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_layer_style("mpoly3d", "default", "<qml_1/>", "<sld_1/>")
+            .await
+            .unwrap();
+        tx.add_layer_style("mpoly3d", "default", "<qml_2/>", "<sld_2/>")
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        // replaced by the second one
+        let rows = handler.fetch_rows("layer_styles").await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<String, &str>("f_table_name"), "mpoly3d");
+        assert_eq!(rows[0].get::<String, &str>("styleQML"), "<qml_2/>");
+        assert_eq!(rows[0].get::<String, &str>("styleSLD"), "<sld_2/>");
+        assert!(rows[0].get::<bool, &str>("useAsDefault"));
+
+        let gpkg_contents = handler.gpkg_contents().await.unwrap();
+        assert_eq!(gpkg_contents[0].0, "layer_styles");
+        assert_eq!(gpkg_contents[0].1, "attributes");
+    }
+
+    #[tokio::test]
+    async fn test_add_metadata() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+
+        let mut tx = handler.begin().await.unwrap();
+        let first = tx
+            .add_metadata("https://example.com/md", "application/json", "{}", None)
+            .await
+            .unwrap();
+        let second = tx
+            .add_metadata(
+                "https://example.com/md",
+                "application/json",
+                r#"{"a":1}"#,
+                Some("mpoly3d"),
+            )
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_ne!(first, second);
+
+        let rows = handler.fetch_rows("gpkg_metadata").await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].get::<String, &str>("metadata"), r#"{"a":1}"#);
+        assert_eq!(rows[1].get::<String, &str>("mime_type"), "application/json");
+
+        let rows = handler.fetch_rows("gpkg_metadata_reference").await.unwrap();
+        assert_eq!(rows[0].get::<String, &str>("reference_scope"), "geopackage");
+        assert_eq!(rows[1].get::<String, &str>("reference_scope"), "table");
+        assert_eq!(rows[1].get::<i64, &str>("md_file_id"), second);
+
+        let rows = handler.fetch_rows("gpkg_extensions").await.unwrap();
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_add_relation() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+
+        let base = TableInfo {
+            name: "bldg_Building".into(),
+            has_geometry: true,
+            columns: vec![],
+        };
+        let related = TableInfo {
+            name: "uro_BuildingDetailAttribute".into(),
+            has_geometry: false,
+            columns: vec![ColumnInfo {
+                name: "uro:surveyYear".into(),
+                data_type: "TEXT".into(),
+                mime_type: None,
+            }],
+        };
+        let mapping_table = "bldg_Building_uro_BuildingDetailAttribute";
+
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_table(&base, 4326).await.unwrap();
+        tx.add_table(&related, 4326).await.unwrap();
+        let fid = tx
+            .insert_feature(
+                &base.name,
+                "bldg_1",
+                &[],
+                &IndexMap::<String, String>::new(),
+            )
+            .await
+            .unwrap();
+        let attributes: IndexMap<String, String> =
+            IndexMap::from([("uro:surveyYear".into(), "2020".into())]);
+        let first = tx
+            .insert_attribute(&related.name, &attributes)
+            .await
+            .unwrap();
+        let second = tx
+            .insert_attribute(&related.name, &attributes)
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+        for related_id in [first, second] {
+            // (the second call does nothing)
+            tx.add_relation(
+                &base.name,
+                "fid",
+                &related.name,
+                "attributes",
+                mapping_table,
+            )
+            .await
+            .unwrap();
+            tx.insert_relation(mapping_table, fid, related_id)
+                .await
+                .unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        let rows = handler.fetch_rows("gpkgext_relations").await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(
+            rows[0].get::<String, &str>("related_table_name"),
+            related.name
+        );
+        assert_eq!(rows[0].get::<String, &str>("base_primary_column"), "fid");
+        assert_eq!(rows[0].get::<String, &str>("relation_name"), "attributes");
+
+        let rows = handler.fetch_rows(mapping_table).await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].get::<i64, &str>("base_id"), fid);
+        assert_eq!(rows[1].get::<i64, &str>("related_id"), second);
+
+        let rows = handler.fetch_rows("gpkg_extensions").await.unwrap();
+        assert_eq!(rows.len(), 2);
+
+        let gpkg_contents = handler.gpkg_contents().await.unwrap();
+        assert!(gpkg_contents
+            .iter()
+            .any(|(name, data_type, _, _)| name == mapping_table && data_type == "attributes"));
+    }
+
+    #[tokio::test]
+    async fn test_set_geometry_type() {
+        let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+
+        let table_info = TableInfo {
+            name: "edges".into(),
+            has_geometry: true,
+            columns: vec![],
+        };
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_table(&table_info, 4326).await.unwrap();
+        tx.set_geometry_type("edges", "LINESTRING").await.unwrap();
+        tx.commit().await.unwrap();
+
+        let columns = handler.gpkg_geometry_columns().await.unwrap();
+        assert_eq!(columns[0].2, "LINESTRING");
+    }
+
     #[tokio::test]
     async fn test_bbox() {
         let mut handler = GpkgHandler::from_url(&Url::parse("sqlite::memory:").unwrap())
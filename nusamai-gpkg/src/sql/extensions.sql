@@ -0,0 +1,10 @@
+-- The table of the extensions used in the GeoPackage
+-- https://www.geopackage.org/spec131/#extensions_table_definition
+CREATE TABLE IF NOT EXISTS gpkg_extensions (
+    table_name TEXT,
+    column_name TEXT,
+    extension_name TEXT NOT NULL,
+    definition TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    CONSTRAINT ge_tce UNIQUE (table_name, column_name, extension_name)
+);
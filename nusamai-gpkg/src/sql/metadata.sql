@@ -0,0 +1,34 @@
+-- The tables of the Metadata extension (`gpkg_extensions` is created by extensions.sql)
+-- https://www.geopackage.org/spec131/#extension_metadata
+CREATE TABLE IF NOT EXISTS gpkg_metadata (
+    id INTEGER CONSTRAINT m_pk PRIMARY KEY ASC NOT NULL,
+    md_scope TEXT NOT NULL DEFAULT 'dataset',
+    md_standard_uri TEXT NOT NULL,
+    mime_type TEXT NOT NULL DEFAULT 'text/xml',
+    metadata TEXT NOT NULL DEFAULT ''
+);
+
+CREATE TABLE IF NOT EXISTS gpkg_metadata_reference (
+    reference_scope TEXT NOT NULL,
+    table_name TEXT,
+    column_name TEXT,
+    row_id_value INTEGER,
+    timestamp DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    md_file_id INTEGER NOT NULL,
+    md_parent_id INTEGER,
+    CONSTRAINT crmr_mfi_fk FOREIGN KEY (md_file_id) REFERENCES gpkg_metadata(id),
+    CONSTRAINT crmr_mpi_fk FOREIGN KEY (md_parent_id) REFERENCES gpkg_metadata(id)
+);
+
+-- (the NULL column names are not unique in the constraint)
+INSERT INTO gpkg_extensions (table_name, column_name, extension_name, definition, scope)
+SELECT 'gpkg_metadata', NULL, 'gpkg_metadata', 'http://www.geopackage.org/spec131/#extension_metadata', 'read-write'
+WHERE NOT EXISTS (
+    SELECT 1 FROM gpkg_extensions WHERE table_name = 'gpkg_metadata' AND extension_name = 'gpkg_metadata'
+);
+
+INSERT INTO gpkg_extensions (table_name, column_name, extension_name, definition, scope)
+SELECT 'gpkg_metadata_reference', NULL, 'gpkg_metadata', 'http://www.geopackage.org/spec131/#extension_metadata', 'read-write'
+WHERE NOT EXISTS (
+    SELECT 1 FROM gpkg_extensions WHERE table_name = 'gpkg_metadata_reference' AND extension_name = 'gpkg_metadata'
+);
@@ -0,0 +1,18 @@
+-- The table of the Related Tables extension (`gpkg_extensions` is created by extensions.sql)
+-- https://docs.ogc.org/is/18-000/18-000.html
+CREATE TABLE IF NOT EXISTS gpkgext_relations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    base_table_name TEXT NOT NULL,
+    base_primary_column TEXT NOT NULL DEFAULT 'id',
+    related_table_name TEXT NOT NULL,
+    related_primary_column TEXT NOT NULL DEFAULT 'id',
+    relation_name TEXT NOT NULL,
+    mapping_table_name TEXT NOT NULL UNIQUE
+);
+
+-- (the NULL column names are not unique in the constraint)
+INSERT INTO gpkg_extensions (table_name, column_name, extension_name, definition, scope)
+SELECT 'gpkgext_relations', NULL, 'gpkg_related_tables', 'http://docs.opengeospatial.org/is/18-000/18-000.html', 'read-write'
+WHERE NOT EXISTS (
+    SELECT 1 FROM gpkg_extensions WHERE table_name = 'gpkgext_relations' AND extension_name = 'gpkg_related_tables'
+);
@@ -11,3 +11,44 @@ pub struct ColumnInfo {
     pub data_type: String,
     pub mime_type: Option<String>,
 }
+
+/// Value of a column, bound to the query with its SQLite storage class
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    /// Stored as 0 or 1
+    Boolean(bool),
+    Text(String),
+}
+
+impl From<String> for ColumnValue {
+    fn from(value: String) -> Self {
+        ColumnValue::Text(value)
+    }
+}
+
+impl From<&str> for ColumnValue {
+    fn from(value: &str) -> Self {
+        ColumnValue::Text(value.into())
+    }
+}
+
+impl From<i64> for ColumnValue {
+    fn from(value: i64) -> Self {
+        ColumnValue::Integer(value)
+    }
+}
+
+impl From<f64> for ColumnValue {
+    fn from(value: f64) -> Self {
+        ColumnValue::Real(value)
+    }
+}
+
+impl From<bool> for ColumnValue {
+    fn from(value: bool) -> Self {
+        ColumnValue::Boolean(value)
+    }
+}
@@ -21,16 +21,52 @@ pub struct Material {
     pub diffuse_color: Color,
     pub specular_color: Color,
     pub ambient_intensity: f64,
+    /// 0.0 (opaque) to 1.0 (fully transparent)
+    pub transparency: f64,
+    pub shininess: f64,
+    pub emissive_color: Color,
     // TOOD: other parameters
     // Note: Adjust the Hash implementation if you add a new field
 }
 
+impl Material {
+    /// RGBA color, with the alpha derived from the transparency
+    pub fn base_color(&self) -> [f32; 4] {
+        let [r, g, b, _]: [f32; 4] = self.diffuse_color.into();
+        [r, g, b, (1. - self.transparency.clamp(0., 1.)) as f32]
+    }
+
+    /// Approximates the PBR metallic and roughness factors from the X3D (Blinn-Phong) parameters.
+    ///
+    /// The X3D materials describe dielectric surfaces (the specular color is kept in `KHR_materials_specular`),
+    /// so the metallic is 0, and the roughness is derived from the specular exponent (`shininess * 128`).
+    pub fn metallic_roughness(&self) -> [f32; 2] {
+        let shininess = self.shininess.clamp(0., 1.);
+        let roughness = (2. / (shininess * 128. + 2.)).sqrt().sqrt();
+        [0., roughness as f32]
+    }
+
+    /// Color of the specular reflection (`KHR_materials_specular`)
+    pub fn specular(&self) -> [f32; 3] {
+        let [r, g, b, _]: [f32; 4] = self.specular_color.into();
+        [r, g, b]
+    }
+
+    pub fn emissive(&self) -> [f32; 3] {
+        let [r, g, b, _]: [f32; 4] = self.emissive_color.into();
+        [r, g, b]
+    }
+}
+
 impl From<X3DMaterial> for Material {
     fn from(src: X3DMaterial) -> Self {
         Self {
             diffuse_color: src.diffuse_color.unwrap_or(Color::new(0.8, 0.8, 0.8)),
             specular_color: src.specular_color.unwrap_or(Color::new(1., 1., 1.)),
             ambient_intensity: src.ambient_intensity.unwrap_or(0.2),
+            transparency: src.transparency.unwrap_or(0.),
+            shininess: src.shininess.unwrap_or(0.2),
+            emissive_color: src.emissive_color.unwrap_or(Color::new(0., 0., 0.)),
         }
     }
 }
@@ -41,6 +77,9 @@ impl Default for Material {
             diffuse_color: Color::new(0.8, 0.8, 0.8),
             specular_color: Color::new(1., 1., 1.),
             ambient_intensity: 0.2,
+            transparency: 0.,
+            shininess: 0.2,
+            emissive_color: Color::new(0., 0., 0.),
         }
     }
 }
@@ -50,6 +89,9 @@ impl Hash for Material {
         self.diffuse_color.hash(state);
         self.specular_color.hash(state);
         self.ambient_intensity.to_bits().hash(state);
+        self.transparency.to_bits().hash(state);
+        self.shininess.to_bits().hash(state);
+        self.emissive_color.hash(state);
     }
 }
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
@@ -73,7 +115,7 @@ impl From<ParameterizedTexture> for Texture {
             .map(|uri| uri.into_inner())
             .unwrap_or_else(|| {
                 log::warn!("image_uri is not set");
-                url::Url::parse("url_not_found.jpg").unwrap()
+                url::Url::parse("file:///url_not_found.jpg").unwrap()
             });
         Self { image_url }
     }
@@ -188,6 +230,32 @@ impl AppearanceStore {
 mod tests {
     use super::*;
 
+    #[test]
+    fn material_to_pbr() {
+        let default = Material::default();
+        let [metallic, roughness] = default.metallic_roughness();
+        assert_eq!(metallic, 0.);
+        assert!((roughness - 0.52).abs() < 0.01);
+        assert_eq!(default.emissive(), [0., 0., 0.]);
+        assert_eq!(default.base_color()[3], 1.);
+
+        let shiny = Material {
+            shininess: 1.,
+            emissive_color: Color::new(1., 0.5, 0.),
+            ..Default::default()
+        };
+        let [metallic, roughness] = shiny.metallic_roughness();
+        assert_eq!(metallic, 0.);
+        assert!(roughness < 0.4);
+        assert_eq!(shiny.emissive(), [1., 0.5, 0.]);
+
+        let matte = Material {
+            shininess: 0.,
+            ..Default::default()
+        };
+        assert_eq!(matte.metallic_roughness(), [0., 1.]);
+    }
+
     #[test]
     fn merge_appearance() {
         let mut app_local = AppearanceStore::default();
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{io::Read, path::PathBuf, sync::Mutex, time::Duration};
 
 use hashbrown::HashMap;
 use nusamai_citygml::{codelist::CodeResolver, ParseError};
@@ -7,16 +7,181 @@ use url::Url;
 
 use super::xml::{parse_dictionary, Definition};
 
+/// Codelists bundled with the binary, used as the last resort (the common ones of the PLATEAU standard)
+const EMBEDDED_CODELISTS: &[(&str, &str)] = &[
+    (
+        "Building_buildingStructureType.xml",
+        include_str!("embedded/Building_buildingStructureType.xml"),
+    ),
+    (
+        "Building_class.xml",
+        include_str!("embedded/Building_class.xml"),
+    ),
+    (
+        "Building_fireproofStructureType.xml",
+        include_str!("embedded/Building_fireproofStructureType.xml"),
+    ),
+    (
+        "Building_roofType.xml",
+        include_str!("embedded/Building_roofType.xml"),
+    ),
+    (
+        "Building_usage.xml",
+        include_str!("embedded/Building_usage.xml"),
+    ),
+    (
+        "Common_prefecture.xml",
+        include_str!("embedded/Common_prefecture.xml"),
+    ),
+    (
+        "Common_validType.xml",
+        include_str!("embedded/Common_validType.xml"),
+    ),
+];
+
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum size of a downloaded codelist (bytes)
+const MAX_CODELIST_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Where the codelists are looked up when they are not found at the `codeSpace` (relative to the source file).
+///
+/// The file name of the `codeSpace` (e.g. `Building_usage.xml`) is looked up in the sources in order.
+#[derive(Debug, Clone)]
+pub enum CodelistSource {
+    /// A local directory containing the codelist files (e.g. the `codelists` folder of the original dataset)
+    Directory(PathBuf),
+    /// A base URL to download the codelist files from
+    Remote(Url),
+    /// The codelists embedded in the binary
+    Embedded,
+}
+
+/// Fetches a remote codelist into a local file (e.g. with a download cache and retries)
+pub type Fetcher = Box<dyn Fn(&Url) -> std::io::Result<PathBuf> + Send + Sync>;
+
 pub struct Resolver {
-    cache: Cache<PathBuf, HashMap<String, Definition>>,
+    cache: Cache<String, HashMap<String, Definition>>,
+    sources: Vec<CodelistSource>,
+    /// How the remote codelists are fetched (downloaded directly if None)
+    fetcher: Option<Fetcher>,
+    /// The codelists not found in any source (absolute URL -> `codeSpace`), not to look them up again
+    unresolved: Mutex<HashMap<Url, String>>,
 }
 
 impl Resolver {
     pub fn new() -> Self {
+        Self::with_sources(Vec::new())
+    }
+
+    pub fn with_sources(sources: Vec<CodelistSource>) -> Self {
+        let sources = sources
+            .into_iter()
+            .map(|source| match source {
+                // (otherwise the last segment of the base URL is replaced by `Url::join`)
+                CodelistSource::Remote(mut base) if !base.path().ends_with('/') => {
+                    base.set_path(&format!("{}/", base.path()));
+                    CodelistSource::Remote(base)
+                }
+                source => source,
+            })
+            .collect();
         Self {
             cache: Cache::new(12960, 100000).unwrap(),
+            sources,
+            fetcher: None,
+            unresolved: Default::default(),
+        }
+    }
+
+    /// Fetches the remote codelists with the given function instead of downloading them directly
+    pub fn with_fetcher(
+        mut self,
+        fetcher: impl Fn(&Url) -> std::io::Result<PathBuf> + Send + Sync + 'static,
+    ) -> Self {
+        self.fetcher = Some(Box::new(fetcher));
+        self
+    }
+
+    /// The `codeSpace`s whose codelists were not found in any source (sorted)
+    pub fn unresolved(&self) -> Vec<String> {
+        let mut code_spaces: Vec<String> =
+            self.unresolved.lock().unwrap().values().cloned().collect();
+        code_spaces.sort();
+        code_spaces.dedup();
+        code_spaces
+    }
+
+    fn load(&self, abs_url: &Url) -> Result<Option<HashMap<String, Definition>>, ParseError> {
+        if let Ok(path) = abs_url.to_file_path() {
+            if let Ok(file) = std::fs::File::open(&path) {
+                let reader = std::io::BufReader::with_capacity(128 * 1024, file);
+                return parse_dictionary(reader).map(Some);
+            }
+        }
+        // (the codelists next to the remote source files)
+        if matches!(abs_url.scheme(), "http" | "https") {
+            match self.fetch(abs_url) {
+                Ok(content) => return parse_dictionary(&content[..]).map(Some),
+                Err(err) => log::warn!("Failed to download codelist {}: {}", abs_url, err),
+            }
         }
+
+        let Some(file_name) = abs_url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+        else {
+            return Ok(None);
+        };
+        for source in &self.sources {
+            match source {
+                CodelistSource::Directory(dir) => {
+                    if let Ok(file) = std::fs::File::open(dir.join(file_name)) {
+                        let reader = std::io::BufReader::with_capacity(128 * 1024, file);
+                        return parse_dictionary(reader).map(Some);
+                    }
+                }
+                CodelistSource::Remote(base) => {
+                    let Ok(url) = base.join(file_name) else {
+                        continue;
+                    };
+                    match self.fetch(&url) {
+                        Ok(content) => return parse_dictionary(&content[..]).map(Some),
+                        Err(err) => log::warn!("Failed to download codelist {}: {}", url, err),
+                    }
+                }
+                CodelistSource::Embedded => {
+                    if let Some((_, xml)) = EMBEDDED_CODELISTS
+                        .iter()
+                        .find(|(name, _)| *name == file_name)
+                    {
+                        return parse_dictionary(xml.as_bytes()).map(Some);
+                    }
+                }
+            }
+        }
+        Ok(None)
     }
+
+    fn fetch(&self, url: &Url) -> std::io::Result<Vec<u8>> {
+        match &self.fetcher {
+            Some(fetcher) => std::fs::read(fetcher(url)?),
+            None => download(url),
+        }
+    }
+}
+
+fn download(url: &Url) -> std::io::Result<Vec<u8>> {
+    let response = ureq::get(url.as_str())
+        .timeout(DOWNLOAD_TIMEOUT)
+        .call()
+        .map_err(std::io::Error::other)?;
+    let mut content = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_CODELIST_SIZE)
+        .read_to_end(&mut content)?;
+    Ok(content)
 }
 
 impl Default for Resolver {
@@ -44,32 +209,139 @@ impl CodeResolver for Resolver {
                 base_url, code_space
             )));
         };
-        let Ok(path) = abs_url.to_file_path() else {
-            return Err(ParseError::CodelistError(format!(
-                "failed to convert url to file path: {:?}",
-                abs_url,
-            )));
-        };
-        if let Some(dict) = self.cache.get(&path) {
+        let key = abs_url.to_string();
+        if let Some(dict) = self.cache.get(&key) {
             // found in cache
             let v = dict.value().get(code).map(|d| d.value().to_string());
-            Ok(v)
-        } else {
-            // not found in cache
-            let Ok(file) = std::fs::File::open(&path) else {
+            return Ok(v);
+        }
+        if self.unresolved.lock().unwrap().contains_key(&abs_url) {
+            return Err(ParseError::CodelistError(format!(
+                "codelist not found: {:?}",
+                abs_url
+            )));
+        }
+
+        // not found in cache
+        let definitions = match self.load(&abs_url) {
+            Ok(Some(definitions)) => definitions,
+            Ok(None) => {
+                self.unresolved
+                    .lock()
+                    .unwrap()
+                    .insert(abs_url.clone(), code_space.to_string());
                 return Err(ParseError::CodelistError(format!(
-                    "failed to open file: {:?}",
-                    path
+                    "codelist not found: {:?}",
+                    abs_url
                 )));
-            };
-            let reader = std::io::BufReader::with_capacity(128 * 1024, file);
-            let definitions = parse_dictionary(reader)?;
-
-            let v = definitions.get(code).map(|d| d.value().to_string());
-            let cost = definitions.len() as i64;
-            self.cache.insert(path, definitions, cost);
-            self.cache.wait().unwrap();
-            Ok(v)
+            }
+            Err(err) => {
+                self.unresolved
+                    .lock()
+                    .unwrap()
+                    .insert(abs_url, code_space.to_string());
+                return Err(err);
+            }
+        };
+        let v = definitions.get(code).map(|d| d.value().to_string());
+        let cost = definitions.len() as i64;
+        self.cache.insert(key, definitions, cost);
+        self.cache.wait().unwrap();
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    const CODE_SPACE: &str = "../../codelists/Building_usage.xml";
+
+    /// A source file separated from its codelists
+    fn separated_url() -> Url {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        Url::from_file_path(dir.join("udx/bldg/53394525_bldg_6697_op.gml")).unwrap()
+    }
+
+    #[test]
+    fn test_relative_codelist() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/kawasaki-shi");
+        let base_url = Url::from_file_path(dir.join("udx/bldg/dummy.gml")).unwrap();
+        let resolver = Resolver::new();
+        let value = resolver.resolve(&base_url, CODE_SPACE, "401").unwrap();
+        assert_eq!(value.as_deref(), Some("業務施設"));
+        assert!(resolver.unresolved().is_empty());
+    }
+
+    #[test]
+    fn test_codelist_directory() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/kawasaki-shi/codelists");
+        let resolver = Resolver::with_sources(vec![CodelistSource::Directory(dir)]);
+        let value = resolver
+            .resolve(&separated_url(), CODE_SPACE, "401")
+            .unwrap();
+        assert_eq!(value.as_deref(), Some("業務施設"));
+        // (cached)
+        let value = resolver
+            .resolve(&separated_url(), CODE_SPACE, "411")
+            .unwrap();
+        assert_eq!(value.as_deref(), Some("住宅"));
+    }
+
+    #[test]
+    fn test_embedded_codelists() {
+        let resolver = Resolver::with_sources(vec![CodelistSource::Embedded]);
+        let value = resolver
+            .resolve(&separated_url(), CODE_SPACE, "401")
+            .unwrap();
+        assert_eq!(value.as_deref(), Some("業務施設"));
+
+        for (_, xml) in EMBEDDED_CODELISTS {
+            assert!(!parse_dictionary(xml.as_bytes()).unwrap().is_empty());
         }
     }
+
+    #[test]
+    fn test_unresolved_codelists() {
+        let resolver = Resolver::with_sources(vec![CodelistSource::Embedded]);
+        let code_space = "../../codelists/Building_unknown.xml";
+        assert!(resolver.resolve(&separated_url(), code_space, "1").is_err());
+        assert!(resolver.resolve(&separated_url(), code_space, "2").is_err());
+        assert!(resolver
+            .resolve(&separated_url(), CODE_SPACE, "401")
+            .is_ok());
+        assert_eq!(resolver.unresolved(), vec![code_space.to_string()]);
+    }
+
+    #[test]
+    fn test_fetcher() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/kawasaki-shi/codelists");
+        let resolver = Resolver::with_sources(vec![CodelistSource::Remote(
+            Url::parse("https://example.com/plateau/codelists/").unwrap(),
+        )])
+        .with_fetcher(move |url| match url.path() {
+            "/plateau/codelists/Building_usage.xml" => Ok(dir.join("Building_usage.xml")),
+            _ => Err(std::io::ErrorKind::NotFound.into()),
+        });
+        let value = resolver
+            .resolve(&separated_url(), CODE_SPACE, "401")
+            .unwrap();
+        assert_eq!(value.as_deref(), Some("業務施設"));
+    }
+
+    #[test]
+    fn test_remote_base_url() {
+        let resolver = Resolver::with_sources(vec![CodelistSource::Remote(
+            Url::parse("https://example.com/plateau/codelists").unwrap(),
+        )]);
+        let CodelistSource::Remote(base) = &resolver.sources[0] else {
+            unreachable!();
+        };
+        assert_eq!(
+            base.join("Building_usage.xml").unwrap().as_str(),
+            "https://example.com/plateau/codelists/Building_usage.xml"
+        );
+    }
 }
@@ -13,12 +13,9 @@ pub struct CityObjectGroup {
     #[citygml(path = b"grp:usage")]
     pub usage: Vec<Code>,
 
-    //
-    // TODO: not implemented yet
     #[citygml(path = b"grp:groupMember")]
     pub group_member: Vec<CityObjectOrRef>,
-    //
-    // TODO: not implemented yet
+
     #[citygml(path = b"grp:parent")]
     pub parent: Option<CityObjectOrRef>,
     //
@@ -42,5 +39,8 @@ pub struct CityObjectGroup {
 #[citygml_feature(name = "grp:_CityObjectOrRef")]
 pub struct CityObjectOrRef {
     #[citygml(path = b"@xlink:href")]
-    href: Option<String>,
+    pub href: Option<String>,
+
+    #[citygml(path = b"@role")]
+    pub role: Option<String>,
 }
@@ -0,0 +1,219 @@
+//! Quick introspection of the input CityGML datasets
+//!
+//! Reports what each input contains (CityGML version, CRS, feature types, LODs, textures and extent)
+//! without running any sink, so that users can choose the conversion options beforehand.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::mpsc,
+};
+
+use nusamai_citygml::object::{ObjectStereotype, Value};
+use nusamai_projection::crs::EpsgCode;
+use quick_xml::events::Event;
+use serde::Serialize;
+
+use crate::{
+    pipeline::{self, feedback, PipelineError},
+    source::{citygml::CityGmlSourceProvider, DataSourceProvider},
+};
+
+/// Summary of a single input file
+#[derive(Debug, Default, Serialize)]
+pub struct DatasetReport {
+    pub path: PathBuf,
+    /// Version of the CityGML core namespace (e.g. "2.0")
+    pub citygml_version: Option<String>,
+    /// EPSG code of the coordinates
+    pub epsg: Option<EpsgCode>,
+    /// Number of top-level features for each type
+    pub feature_counts: BTreeMap<String, usize>,
+    /// LODs that appear in the geometries
+    pub lods: BTreeSet<u8>,
+    pub has_materials: bool,
+    pub has_textures: bool,
+    /// Extent of all vertices in the source coordinates and axis order: `[min, max]`
+    pub envelope: Option<[[f64; 3]; 2]>,
+}
+
+/// Summary of all the input files
+#[derive(Debug, Default, Serialize)]
+pub struct InspectReport {
+    pub files: Vec<DatasetReport>,
+    /// Extent of the vertices of all the files: `[min, max]`
+    /// (None if the files are in the different CRSs)
+    pub envelope: Option<[[f64; 3]; 2]>,
+}
+
+impl InspectReport {
+    pub fn new(files: Vec<DatasetReport>) -> Self {
+        let mut envelope: Option<[[f64; 3]; 2]> = None;
+        let epsg = files.iter().find_map(|file| file.epsg);
+        let same_crs = files
+            .iter()
+            .all(|file| file.epsg.is_none() || file.epsg == epsg);
+        if same_crs {
+            for [file_min, file_max] in files.iter().filter_map(|file| file.envelope) {
+                let [min, max] = envelope.get_or_insert([file_min, file_max]);
+                for i in 0..3 {
+                    min[i] = min[i].min(file_min[i]);
+                    max[i] = max[i].max(file_max[i]);
+                }
+            }
+        }
+        Self { files, envelope }
+    }
+}
+
+/// Inspects a CityGML file.
+pub fn inspect_file(path: &Path) -> pipeline::Result<DatasetReport> {
+    let mut report = DatasetReport {
+        path: path.to_path_buf(),
+        citygml_version: detect_citygml_version(path)?,
+        ..Default::default()
+    };
+
+    let source_provider = CityGmlSourceProvider {
+        filenames: vec![path.to_path_buf()],
+    };
+    let mut source = source_provider.create(&source_provider.sink_options());
+    source.set_appearance_parsing(true);
+
+    let (sender, receiver) = mpsc::sync_channel(1000);
+    let (_, feedback, _) = feedback::watcher();
+
+    std::thread::scope(|scope| {
+        let handle = scope.spawn(move || source.run(sender, &feedback));
+
+        for parcel in receiver {
+            let entity = parcel.entity;
+            let Value::Object(obj) = &entity.root else {
+                continue;
+            };
+            *report
+                .feature_counts
+                .entry(obj.typename.to_string())
+                .or_default() += 1;
+            collect_lods(&entity.root, &mut report.lods);
+
+            let geom_store = entity.geometry_store.read().unwrap();
+            if !geom_store.vertices.is_empty() {
+                report.epsg.get_or_insert(geom_store.epsg);
+            }
+            for v in &geom_store.vertices {
+                let [min, max] = report
+                    .envelope
+                    .get_or_insert([[f64::MAX; 3], [f64::MIN; 3]]);
+                for i in 0..3 {
+                    min[i] = min[i].min(v[i]);
+                    max[i] = max[i].max(v[i]);
+                }
+            }
+
+            let appearance_store = entity.appearance_store.read().unwrap();
+            report.has_materials |= !appearance_store.materials.is_empty();
+            report.has_textures |= !appearance_store.textures.is_empty();
+        }
+
+        handle.join().unwrap()
+    })?;
+
+    Ok(report)
+}
+
+/// Reads the namespace declarations of the root element to find the version of CityGML.
+fn detect_citygml_version(path: &Path) -> pipeline::Result<Option<String>> {
+    let mut reader = quick_xml::Reader::from_reader(BufReader::new(File::open(path)?));
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                for attr in e.attributes().flatten() {
+                    if !attr.key.as_ref().starts_with(b"xmlns") {
+                        continue;
+                    }
+                    if let Some(version) = attr
+                        .value
+                        .strip_prefix(b"http://www.opengis.net/citygml/")
+                        .filter(|v| v.first().is_some_and(|c| c.is_ascii_digit()))
+                    {
+                        return Ok(Some(String::from_utf8_lossy(version).into()));
+                    }
+                }
+                return Ok(None);
+            }
+            Ok(Event::Eof) => return Ok(None),
+            Ok(_) => {}
+            Err(e) => return Err(PipelineError::Other(format!("XML error: {}", e))),
+        }
+        buf.clear();
+    }
+}
+
+fn collect_lods(value: &Value, lods: &mut BTreeSet<u8>) {
+    match value {
+        Value::Object(obj) => {
+            if let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype {
+                lods.extend(geometries.iter().map(|g| g.lod));
+            }
+            for child in obj.attributes.values() {
+                collect_lods(child, lods);
+            }
+        }
+        Value::Array(arr) => {
+            for v in arr {
+                collect_lods(v, lods);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inspect_building() {
+        let report = inspect_file(Path::new(
+            "../nusamai-plateau/tests/data/yokosuka-shi/udx/bldg/52397519_bldg_6697_op.gml",
+        ))
+        .unwrap();
+
+        assert_eq!(report.citygml_version.as_deref(), Some("2.0"));
+        assert_eq!(report.epsg, Some(6697));
+        assert!(report.feature_counts["bldg:Building"] > 0);
+        assert!(!report.lods.is_empty());
+        assert!(report.has_textures);
+
+        let [min, max] = report.envelope.unwrap();
+        assert!(min[0] <= max[0] && min[1] <= max[1] && min[2] <= max[2]);
+    }
+
+    #[test]
+    fn total_envelope() {
+        let file = |epsg, envelope| DatasetReport {
+            epsg: Some(epsg),
+            envelope: Some(envelope),
+            ..Default::default()
+        };
+        let report = InspectReport::new(vec![
+            file(6697, [[35.0, 139.0, 0.0], [35.1, 139.1, 10.0]]),
+            file(6697, [[34.9, 139.05, 5.0], [35.05, 139.2, 20.0]]),
+            DatasetReport::default(),
+        ]);
+        assert_eq!(
+            report.envelope,
+            Some([[34.9, 139.0, 0.0], [35.1, 139.2, 20.0]])
+        );
+
+        let report = InspectReport::new(vec![
+            file(6697, [[35.0, 139.0, 0.0], [35.1, 139.1, 10.0]]),
+            file(6677, [[0.0, 0.0, 0.0], [10.0, 10.0, 10.0]]),
+        ]);
+        assert!(report.envelope.is_none());
+    }
+}
@@ -1,21 +1,39 @@
+pub mod inspect;
+pub mod merge;
 pub mod parameters;
+pub mod paths;
 pub mod pipeline;
 pub mod sink;
 pub mod source;
 pub mod transformer;
+pub mod update;
+pub mod workdir;
 
 pub static BUILTIN_SINKS: &[&dyn sink::DataSinkProvider] = &[
     &sink::cesiumtiles::CesiumTilesSinkProvider {},
     &sink::gpkg::GpkgSinkProvider {},
     &sink::mvt::MvtSinkProvider {},
     &sink::geojson::GeoJsonSinkProvider {},
+    &sink::cityjson::CityJsonSinkProvider {},
     &sink::czml::CzmlSinkProvider {},
     &sink::gltf::GltfSinkProvider {},
     &sink::kml::KmlSinkProvider {},
     &sink::ply::StanfordPlySinkProvider {},
     &sink::serde::SerdeSinkProvider {},
     &sink::shapefile::ShapefileSinkProvider {},
+    &sink::terrain::TerrainSinkProvider {},
+    &sink::terrain::QuantizedMeshSinkProvider {},
     &sink::noop::NoopSinkProvider {},
     &sink::minecraft::MinecraftSinkProvider {},
     &sink::obj::ObjSinkProvider {},
+    &sink::parquet::GeoParquetSinkProvider {},
+    &sink::csv::CsvSinkProvider {},
+    &sink::shadow::ShadowSinkProvider {},
+    &sink::i3s::I3sSinkProvider {},
+    &sink::las::LasSinkProvider {},
+    &sink::fbx::FbxSinkProvider {},
+    &sink::dxf::DxfSinkProvider {},
+    &sink::citygml::CityGmlSinkProvider {},
+    &sink::duckdb::DuckDbSinkProvider {},
+    &sink::roadnetwork::RoadNetworkSinkProvider {},
 ];
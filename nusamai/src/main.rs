@@ -1,27 +1,63 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     env,
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::ExitCode,
     sync::{Arc, Mutex, OnceLock},
 };
 
 use clap::Parser;
 use nusamai::{
+    inspect::{inspect_file, InspectReport},
+    merge::merge_outputs,
+    paths,
     pipeline::Canceller,
-    sink::{DataRequirements, DataSink, DataSinkProvider},
-    source::{citygml::CityGmlSourceProvider, DataSource, DataSourceProvider},
+    sink::{
+        manifest::write_directory_manifest,
+        overwrite::{prepare_output, OverwritePolicy},
+        DataRequirements, DataSink, DataSinkProvider,
+    },
+    source::{
+        citygml::{year_from_path, CityGmlSourceProvider},
+        cityjson::{is_city_json, CityJsonSourceProvider},
+        dataset::{expand_dataset_roots, parse_packages},
+        remote::remote_url,
+        sampling::{SampledSource, Sampling},
+        serde::{is_entity_cache, SerdeSourceProvider},
+        DataSource, DataSourceProvider,
+    },
     transformer::{
-        self, MappingRules, MultiThreadTransformer, NusamaiTransformBuilder, ParameterType,
-        TransformBuilder, TransformerConfig, TransformerSettings,
+        self, AxisOrder, MappingRules, MultiThreadTransformer, NusamaiTransformBuilder,
+        ParameterType, TransformBuilder, TransformerConfig, TransformerSettings,
     },
-    BUILTIN_SINKS,
+    update::UpdateState,
+    workdir, BUILTIN_SINKS,
 };
+use nusamai_catalog::Catalog;
 use nusamai_citygml::CityGmlElement;
 use nusamai_plateau::models::TopLevelCityObject;
 
 #[derive(clap::Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Arguments of the conversion (without a subcommand)
+    #[command(flatten)]
+    args: Option<Args>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Report what the input CityGML files contain, without converting them
+    Inspect(InspectArgs),
+    /// Merge the outputs of the shards (`--shard`) into one output
+    Merge(MergeArgs),
+}
+
+#[derive(clap::Args)]
 struct Args {
     /// Specify path patterns to the input CityGML files
     #[arg()]
@@ -60,6 +96,220 @@ struct Args {
     /// Add an option for the input source (key=value)
     #[arg(short = 'i', value_parser = parse_key_val)]
     sourceopt: Vec<(String, String)>,
+
+    /// Process only the given municipalities (e.g. 13104)
+    #[arg(long)]
+    city_code: Vec<String>,
+
+    /// Process only a shard of the input files (INDEX/COUNT, e.g. 0/4)
+    /// The outputs of the shards are written separately, and merged by `nusamai merge` (GeoPackage, 3D Tiles and MVT)
+    #[arg(long, value_parser = parse_shard)]
+    shard: Option<Shard>,
+
+    /// Specify the state file for incremental updates
+    /// Only the input files changed since the previous run (recorded in the file) are converted into the
+    /// existing output (GeoPackage); the outputs of the other formats are converted again from all the input files
+    #[arg(long, conflicts_with_all = ["overwrite", "no_overwrite", "merge"])]
+    state: Option<PathBuf>,
+
+    /// Add an input group of a dataset vintage (YEAR=PATTERN, e.g. 2022=~/13104_2022/udx/bldg/*.gml)
+    /// The features get the `year` attribute, and each vintage is written to a separate output
+    #[arg(long, value_parser = parse_vintage)]
+    vintage: Vec<(u16, String)>,
+
+    /// Group the input files by the year in the PLATEAU package directory names
+    /// (e.g. 13104_shinjuku-ku_city_2023_citygml_1_op), and write each vintage to a separate output
+    #[arg(long)]
+    by_vintage: bool,
+
+    /// Convert the PLATEAU dataset of a municipality (e.g. 13101)
+    /// The CityGML files listed in the PLATEAU data catalog are downloaded
+    #[arg(long, value_name = "CITY_CODE")]
+    dataset: Option<String>,
+
+    /// Select the year of the dataset (default: the latest)
+    #[arg(long, requires = "dataset")]
+    dataset_year: Option<u16>,
+
+    /// Select the packages of the dataset (e.g. bldg,tran; default: all)
+    #[arg(long, requires = "dataset", value_delimiter = ',')]
+    dataset_package: Vec<String>,
+
+    /// Specify the directory of the intermediate files (default: `nusamai` under the system temporary directory)
+    /// Use a disk with enough free space when converting a large dataset into tiles
+    #[arg(long)]
+    tmpdir: Option<PathBuf>,
+
+    /// Replace the output if it already exists
+    /// (a directory is cleared only if it is the output of a previous conversion)
+    #[arg(long, conflicts_with_all = ["no_overwrite", "merge"])]
+    overwrite: bool,
+
+    /// Fail if the output already exists (default)
+    #[arg(long, conflicts_with = "merge")]
+    no_overwrite: bool,
+
+    /// Add the features to the output if it already exists
+    /// (supported by the outputs written into a directory, and GeoPackage)
+    #[arg(long)]
+    merge: bool,
+
+    /// Convert only a sample of the features (e.g. 1%) for a quick preview
+    /// The same features are selected every time (by the hashes of the feature ids)
+    #[arg(long, value_parser = parse_percentage)]
+    sample: Option<f64>,
+
+    /// Convert at most the given number of features (e.g. 10000) for a quick preview
+    /// The same features are selected every time, but all the input files are still parsed
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Swap the first two axes of the input coordinates (for the data not in the order of the CRS)
+    /// The geographic coordinates in the longitude-latitude order are detected and swapped without this
+    #[arg(long)]
+    swap_axes: bool,
+}
+
+impl Args {
+    fn overwrite_policy(&self, updates_in_place: bool) -> OverwritePolicy {
+        // `-o update=true` or `-o append=true` of GeoPackage implies merging
+        let update = self
+            .sinkopt
+            .iter()
+            .any(|(key, value)| (key == "update" || key == "append") && value == "true");
+        if self.state.is_some() {
+            // the changed features are replaced in place, or the previous output is replaced as a whole
+            match updates_in_place {
+                true => OverwritePolicy::Merge,
+                false => OverwritePolicy::Overwrite,
+            }
+        } else if self.overwrite {
+            OverwritePolicy::Overwrite
+        } else if self.merge || (update && !self.no_overwrite) {
+            OverwritePolicy::Merge
+        } else {
+            OverwritePolicy::Error
+        }
+    }
+
+    fn sampling(&self) -> Sampling {
+        Sampling {
+            ratio: self.sample,
+            limit: self.limit,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct InspectArgs {
+    /// Specify path patterns to the input CityGML files
+    #[arg()]
+    file_patterns: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct MergeArgs {
+    /// Specify the outputs of the shards (GeoPackage files, or directories of 3D Tiles or vector tiles)
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Specify the path of the merged output
+    #[arg(long, value_parser = parse_non_empty)]
+    output: String,
+}
+
+/// A subset of the input files assigned to a worker
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Shard {
+    index: usize,
+    count: usize,
+}
+
+impl Shard {
+    /// Keeps only the files assigned to this shard.
+    ///
+    /// The files are sorted first, so that every worker gets the same assignment from the same input.
+    fn select(&self, filenames: &mut Vec<PathBuf>) {
+        filenames.sort();
+        let mut i = 0;
+        filenames.retain(|_| {
+            i += 1;
+            (i - 1) % self.count == self.index
+        });
+    }
+}
+
+fn parse_shard(s: &str) -> Result<Shard, String> {
+    let (index, count) = s
+        .split_once('/')
+        .ok_or_else(|| format!("invalid INDEX/COUNT: no `/` found in `{s}`"))?;
+    let index: usize = index
+        .parse()
+        .map_err(|_| format!("invalid shard index: `{index}`"))?;
+    let count: usize = count
+        .parse()
+        .map_err(|_| format!("invalid shard count: `{count}`"))?;
+    if count == 0 || index >= count {
+        return Err(format!("shard index must be less than the count: `{s}`"));
+    }
+    Ok(Shard { index, count })
+}
+
+/// Parses a percentage (e.g. `1%`, `0.5%`) into a ratio
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    let percent: f64 = s
+        .strip_suffix('%')
+        .ok_or_else(|| format!("invalid percentage: no `%` found in `{s}`"))?
+        .parse()
+        .map_err(|_| format!("invalid percentage: `{s}`"))?;
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(format!("percentage must be in (0%, 100%]: `{s}`"));
+    }
+    Ok(percent / 100.0)
+}
+
+fn parse_vintage(s: &str) -> Result<(u16, String), String> {
+    let (year, pattern) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid YEAR=PATTERN: no `=` found in `{s}`"))?;
+    let year: u16 = year
+        .parse()
+        .map_err(|_| format!("invalid year: `{year}`"))?;
+    Ok((year, pattern.into()))
+}
+
+/// Output path of a vintage: `{name}_{year}.{ext}` for a file, or `{output}/{year}` for a directory
+fn vintage_output_path(output: &str, year: u16) -> String {
+    let path = Path::new(output);
+    let path = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => path.with_file_name(format!(
+            "{}_{year}.{}",
+            stem.to_string_lossy(),
+            ext.to_string_lossy()
+        )),
+        _ => path.join(year.to_string()),
+    };
+    path.to_string_lossy().into_owned()
+}
+
+/// Splits the input files into the groups of the vintages, in the order of the years.
+///
+/// The files without a vintage make a group of `None`.
+fn group_by_vintage(
+    filenames: Vec<PathBuf>,
+    years: &HashMap<PathBuf, u16>,
+) -> Vec<(Option<u16>, Vec<PathBuf>)> {
+    if years.is_empty() {
+        return vec![(None, filenames)];
+    }
+    let mut groups = BTreeMap::<Option<u16>, Vec<PathBuf>>::new();
+    for path in filenames {
+        groups
+            .entry(years.get(&path).copied())
+            .or_default()
+            .push(path);
+    }
+    groups.into_iter().collect()
 }
 
 fn parse_key_val(s: &str) -> Result<(String, String), String> {
@@ -121,11 +371,21 @@ fn main() -> ExitCode {
     }
     pretty_env_logger::init();
 
-    let args = {
-        // output path
-        let mut args = Args::parse();
-        args.sinkopt.push(("@output".into(), args.output.clone()));
-        args
+    let cli = Cli::parse();
+    let args = match (cli.command, cli.args) {
+        (Some(Command::Inspect(args)), _) => return inspect(args),
+        (Some(Command::Merge(args)), _) => return merge(args),
+        (None, Some(mut args)) => {
+            // output path
+            args.sinkopt.push(("@output".into(), args.output.clone()));
+            if !args.city_code.is_empty() {
+                args.sourceopt
+                    .push(("city_code".into(), args.city_code.join(",")));
+            }
+            args
+        }
+        // (clap requires the arguments of the conversion without a subcommand)
+        (None, None) => unreachable!(),
     };
 
     let mut canceller = Arc::new(Mutex::new(Canceller::default()));
@@ -148,19 +408,18 @@ fn main() -> ExitCode {
         log::error!("Error validating sink parameters: {:?}", err);
         return ExitCode::FAILURE;
     }
-
-    // If the directory for the output path does not exist, create it
-    if let Some(output_parent_dir) = PathBuf::from(&args.output).parent() {
-        if !output_parent_dir.exists() {
-            if std::fs::create_dir_all(output_parent_dir).is_err() {
-                log::error!("Failed to create output directory: {:?}", output_parent_dir);
-                return ExitCode::FAILURE;
-            };
-            log::info!("Created output directory: {:?}", output_parent_dir);
-        }
+    // the sinks with the `update` option (GeoPackage) replace the features of the changed input files
+    let updates_in_place = sink_params.get("update").is_some();
+    if args.state.is_some()
+        && args
+            .sinkopt
+            .iter()
+            .any(|(key, value)| key == "append" && value == "true")
+    {
+        log::error!("--state cannot be used with `-o append=true` (the changed features are to be replaced)");
+        return ExitCode::FAILURE;
     }
 
-    let mut sink = sink_provider.create(&sink_params);
     let transformer_settings = sink_provider.transformer_options();
 
     let valid_keys = transformer_settings.initialize_valid_keys();
@@ -234,12 +493,6 @@ fn main() -> ExitCode {
         }
     };
 
-    let mut requirements = sink.make_requirements(updated_transformer_registry);
-    requirements.set_output_epsg(match args.sink.0.as_ref() {
-        "kml" => 6697, // temporary hack for KML output
-        _ => args.epsg,
-    });
-
     let mapping_rules = match &args.rules {
         Some(rules_path) => {
             let Ok(file_contents) = std::fs::read_to_string(rules_path) else {
@@ -255,76 +508,386 @@ fn main() -> ExitCode {
         None => None,
     };
 
-    let source = {
-        // glob input file patterns
-        let mut filenames = vec![];
-        for file_pattern in &args.file_patterns {
-            let file_pattern = shellexpand::tilde(file_pattern);
-            let mut pattern_hits = 0;
-            for entry in glob::glob(&file_pattern).unwrap() {
-                filenames.push(entry.unwrap());
-                pattern_hits += 1;
+    // the state of the input files to be saved after the conversion
+    let mut update_state = None;
+
+    let groups = {
+        let mut filenames = glob_file_patterns(&args.file_patterns);
+
+        // the dataset roots are expanded here (as well as in the source), so that the files are
+        // sharded, tracked and grouped by vintage one by one
+        let packages = args
+            .sourceopt
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "packages")
+            .map(|(_, value)| parse_packages(value))
+            .unwrap_or_default();
+        filenames = match expand_dataset_roots(&filenames, &packages) {
+            Ok(filenames) => filenames,
+            Err(err) => {
+                log::error!("Failed to read the dataset directory: {}", err);
+                return ExitCode::FAILURE;
             }
-            if pattern_hits == 0 {
-                log::warn!("no files matched the path pattern: {}", file_pattern);
+        };
+
+        // the vintage of each input file
+        let mut years = HashMap::new();
+        for (year, pattern) in &args.vintage {
+            for path in glob_file_patterns(std::slice::from_ref(pattern)) {
+                if !filenames.contains(&path) {
+                    filenames.push(path.clone());
+                }
+                years.insert(path, *year);
             }
         }
+        if let Some(city_code) = &args.dataset {
+            let dataset = match Catalog::default().city_dataset(city_code, args.dataset_year) {
+                Ok(dataset) => dataset,
+                Err(err) => {
+                    log::error!("Failed to find the dataset: {}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let urls = dataset.file_urls(&args.dataset_package);
+            log::info!(
+                "Dataset of {} ({}) in {}: {} files",
+                dataset.city_name,
+                dataset.city_code,
+                dataset.year,
+                urls.len()
+            );
+            for url in urls {
+                // (downloaded by the source)
+                let path = PathBuf::from(url.as_str());
+                if args.by_vintage {
+                    years.insert(path.clone(), dataset.year);
+                }
+                filenames.push(path);
+            }
+        }
+        if args.by_vintage {
+            for path in &filenames {
+                if years.contains_key(path) {
+                    continue;
+                }
+                let Some(year) = year_from_path(path) else {
+                    log::error!(
+                        "Cannot determine the vintage of {:?} from the directory name (use --vintage YEAR=PATTERN)",
+                        path
+                    );
+                    return ExitCode::FAILURE;
+                };
+                years.insert(path.clone(), year);
+            }
+        }
+
+        if let Some(shard) = args.shard {
+            let num_total = filenames.len();
+            shard.select(&mut filenames);
+            log::info!(
+                "Shard {}/{}: processing {} of {} input files",
+                shard.index,
+                shard.count,
+                filenames.len(),
+                num_total
+            );
+        }
 
         if filenames.is_empty() {
             log::error!("No input CityGML files found");
             return ExitCode::FAILURE;
         }
 
-        let source_provider: Box<dyn DataSourceProvider> =
-            Box::new(CityGmlSourceProvider { filenames });
-        let mut source_params = source_provider.sink_options();
-        if let Err(err) = source_params.update_values_with_str(&args.sourceopt) {
-            log::error!("Error parsing source parameters: {:?}", err);
+        if let Some(state_path) = &args.state {
+            let (mut prev_state, state) = match (
+                UpdateState::load(state_path),
+                UpdateState::compute(&filenames),
+            ) {
+                (Ok(prev_state), Ok(state)) => (prev_state, state),
+                (Err(err), _) | (_, Err(err)) => {
+                    log::error!("Failed to prepare the update state: {}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            if !Path::new(&args.output).exists() {
+                // (the output of the previous run has been removed)
+                prev_state = UpdateState::default();
+            }
+            let changes = state.changes_from(&prev_state);
+            for path in changes.removed.iter().filter(|_| updates_in_place) {
+                log::warn!(
+                    "Input file removed since the previous run (its features are kept in the output): {:?}",
+                    path
+                );
+            }
+            if changes.changed.is_empty() {
+                log::info!("No input files changed since the previous run");
+                return ExitCode::SUCCESS;
+            }
+            if updates_in_place {
+                log::info!(
+                    "Converting {} changed files of {} input files",
+                    changes.changed.len(),
+                    filenames.len()
+                );
+                filenames = changes.changed;
+            } else {
+                // (the tiles and the files of the other formats cannot be updated partially)
+                log::info!(
+                    "{} of {} input files changed; the output of this format is converted again from all the input files",
+                    changes.changed.len(),
+                    filenames.len()
+                );
+            }
+            update_state = Some(state);
+        }
+
+        group_by_vintage(filenames, &years)
+    };
+
+    // The intermediate files (e.g. the chunks of the external sort) are written into the managed directory
+    match workdir::prepare(args.tmpdir.as_deref()) {
+        Ok(space) => {
+            let input_bytes: u64 = groups
+                .iter()
+                .flat_map(|(_, filenames)| filenames)
+                .filter_map(|path| path.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            if space.is_short_for(input_bytes) {
+                log::warn!(
+                    "The free space for the intermediate files in {:?} ({}) is less than the size of the input files ({}), consider using --tmpdir",
+                    space.dir,
+                    bytesize::to_string(space.available, true),
+                    bytesize::to_string(input_bytes, true)
+                );
+            }
+        }
+        Err(err) => {
+            log::error!(
+                "Failed to prepare the directory of the intermediate files: {}",
+                err
+            );
             return ExitCode::FAILURE;
+        }
+    }
+
+    let mut succeeded = true;
+    for (year, filenames) in groups {
+        // each vintage is written to a separate output
+        let output = match year {
+            Some(year) => {
+                log::info!(
+                    "Converting {} input files of the {} vintage",
+                    filenames.len(),
+                    year
+                );
+                vintage_output_path(&args.output, year)
+            }
+            None => args.output.clone(),
+        };
+        // the output under the deep directories (e.g. with the Japanese names) is written with the
+        // verbatim path on Windows
+        let output = paths::extended(Path::new(&output))
+            .to_string_lossy()
+            .into_owned();
+
+        // If the directory for the output path does not exist, create it
+        if let Some(output_parent_dir) = PathBuf::from(&output).parent() {
+            if !output_parent_dir.exists() {
+                if std::fs::create_dir_all(output_parent_dir).is_err() {
+                    log::error!("Failed to create output directory: {:?}", output_parent_dir);
+                    return ExitCode::FAILURE;
+                };
+                log::info!("Created output directory: {:?}", output_parent_dir);
+            }
+        }
+
+        let mut sink = {
+            let mut sink_params = sink_provider.sink_options();
+            let mut sinkopt = args.sinkopt.clone();
+            sinkopt.push(("@output".into(), output.clone()));
+            match prepare_output(
+                Path::new(&output),
+                args.overwrite_policy(updates_in_place),
+                &sink_provider.info().id_name,
+                &sink_params,
+            ) {
+                Ok(options) => sinkopt.extend(options),
+                Err(err) => {
+                    log::error!("{}", err);
+                    return ExitCode::FAILURE;
+                }
+            }
+            if let Err(err) = sink_params.update_values_with_str(&sinkopt) {
+                log::error!("Error parsing sink options: {:?}", err);
+                return ExitCode::FAILURE;
+            };
+            sink_provider.create(&sink_params)
+        };
+
+        let mut requirements = sink.make_requirements(updated_transformer_registry.clone());
+        requirements.set_output_epsg(match args.sink.0.as_ref() {
+            "kml" => 6697,     // temporary hack for KML output
+            "terrain" => 6697, // heightmaps are in the orthometric heights
+            "shadow" => 6697,
+            _ => args.epsg,
+        });
+
+        let source = {
+            // Read the entities written by the serde sink, instead of parsing CityGML
+            let is_cache = filenames.iter().all(|path| is_entity_cache(path));
+            // CityJSON and CityJSONSeq files are read into the same entities as CityGML
+            let is_city_json = !is_cache && filenames.iter().all(|path| is_city_json(path));
+            let source_provider: Box<dyn DataSourceProvider> = if is_cache {
+                Box::new(SerdeSourceProvider { filenames })
+            } else if is_city_json {
+                Box::new(CityJsonSourceProvider { filenames })
+            } else {
+                Box::new(CityGmlSourceProvider { filenames })
+            };
+            let mut sourceopt = args.sourceopt.clone();
+            match year {
+                Some(_) if is_cache => {
+                    log::warn!("The year attribute is not attached to the cached entities");
+                }
+                Some(_) if is_city_json => {
+                    log::warn!("The year attribute is not attached to the CityJSON features");
+                }
+                Some(year) => sourceopt.push(("year".into(), year.to_string())),
+                None => {}
+            }
+            let mut source_params = source_provider.sink_options();
+            if let Err(err) = source_params.update_values_with_str(&sourceopt) {
+                log::error!("Error parsing source parameters: {:?}", err);
+                return ExitCode::FAILURE;
+            };
+            if let Err(err) = source_params.validate() {
+                log::error!("Error validating source parameters: {:?}", err);
+                return ExitCode::FAILURE;
+            }
+
+            // create source
+            let mut source = source_provider.create(&source_params);
+            source.set_appearance_parsing(requirements.use_appearance);
+
+            let sampling = args.sampling();
+            if sampling.is_enabled() {
+                source = Box::new(SampledSource::new(source, sampling));
+            }
+            source
         };
-        if let Err(err) = source_params.validate() {
-            log::error!("Error validating source parameters: {:?}", err);
+
+        succeeded = run(
+            &args,
+            &output,
+            source,
+            requirements,
+            mapping_rules.clone(),
+            sink,
+            &mut canceller,
+        );
+        if !succeeded {
+            break;
+        }
+    }
+
+    // Record the state only when the output is complete, so that the failed files are retried next time
+    if let (true, Some(state), Some(state_path)) = (succeeded, update_state, &args.state) {
+        if let Err(err) = state.save(state_path) {
+            log::error!("Failed to save the update state: {}", err);
             return ExitCode::FAILURE;
         }
+    }
 
-        // create source
-        let mut source = source_provider.create(&source_params);
-        source.set_appearance_parsing(requirements.use_appearance);
-        source
-    };
+    ExitCode::SUCCESS
+}
+
+/// Expands the input file patterns
+fn glob_file_patterns(file_patterns: &[String]) -> Vec<PathBuf> {
+    let mut filenames = vec![];
+    for file_pattern in file_patterns {
+        // (the URLs are downloaded by the source)
+        if remote_url(Path::new(file_pattern)).is_some() {
+            filenames.push(PathBuf::from(file_pattern));
+            continue;
+        }
+        let file_pattern = shellexpand::tilde(file_pattern);
+        let mut pattern_hits = 0;
+        for entry in glob::glob(&file_pattern).unwrap() {
+            filenames.push(entry.unwrap());
+            pattern_hits += 1;
+        }
+        if pattern_hits == 0 {
+            log::warn!("no files matched the path pattern: {}", file_pattern);
+        }
+    }
+    filenames
+}
 
-    run(
-        &args,
-        source,
-        requirements,
-        mapping_rules,
-        sink,
-        &mut canceller,
-    );
+fn inspect(args: InspectArgs) -> ExitCode {
+    let filenames = glob_file_patterns(&args.file_patterns);
+    if filenames.is_empty() {
+        log::error!("No input CityGML files found");
+        return ExitCode::FAILURE;
+    }
 
+    let mut reports = Vec::with_capacity(filenames.len());
+    for filename in &filenames {
+        match inspect_file(filename) {
+            Ok(report) => reports.push(report),
+            Err(err) => {
+                log::error!("Failed to inspect {:?}: {}", filename, err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let report = InspectReport::new(reports);
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
     ExitCode::SUCCESS
 }
 
+fn merge(args: MergeArgs) -> ExitCode {
+    log::info!("Merging {} outputs into {}", args.inputs.len(), args.output);
+    match merge_outputs(&args.inputs, Path::new(&args.output)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            log::error!("Failed to merge the outputs: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
 fn run(
     args: &Args,
+    output: &str,
     source: Box<dyn DataSource>,
     requirements: DataRequirements,
     mapping_rules: Option<MappingRules>,
     sink: Box<dyn DataSink>,
     canceller: &mut Arc<Mutex<Canceller>>,
-) {
+) -> bool {
     let total_time = std::time::Instant::now();
+    let writes_manifest = sink.writes_manifest();
 
     // Prepare the transformer for the pipeline and transform the schema
     let (transformer, schema) = {
         let request = {
             let mut request = transformer::Request::from(requirements);
             request.set_mapping_rules(mapping_rules);
+            let large_attribute_dir = transformer::large_attribute_dir(Path::new(output));
+            request.set_large_attribute_dir(Some(large_attribute_dir));
+            if args.swap_axes {
+                request.set_axis_order(AxisOrder::Swap);
+            }
             request
         };
         let transform_builder = NusamaiTransformBuilder::new(request);
         let mut schema = nusamai_citygml::schema::Schema::default();
         TopLevelCityObject::collect_schema(&mut schema);
+        source.transform_schema(&mut schema);
         transform_builder.transform_schema(&mut schema);
 
         if let Some(schema_path) = &args.schema {
@@ -360,19 +923,127 @@ fn run(
     });
 
     // wait for the pipeline to finish
+    let mut succeeded = true;
     if let Err(msg) = handle.join() {
         log::error!("Pipeline thread panicked: {:?}", msg);
+        succeeded = false;
     }
 
+    // (fatal errors also cancel the pipeline)
     if canceller.lock().unwrap().is_canceled() {
         log::info!("Pipeline canceled");
+        succeeded = false;
+    }
+
+    // Make the manifest (sizes and SHA-256 hashes) of the output directory for integrity checks
+    if succeeded && !writes_manifest {
+        match write_directory_manifest(Path::new(output)) {
+            Ok(true) => log::info!("Wrote the manifest of the output directory"),
+            Ok(false) => {}
+            Err(err) => {
+                log::error!("Failed to write the manifest: {}", err);
+                succeeded = false;
+            }
+        }
     }
 
     log::info!("Total processing time: {:?}", total_time.elapsed());
+    succeeded
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard() {
+        assert_eq!(parse_shard("1/3"), Ok(Shard { index: 1, count: 3 }));
+        assert!(parse_shard("3/3").is_err());
+        assert!(parse_shard("0/0").is_err());
+        assert!(parse_shard("1").is_err());
+
+        let filenames: Vec<PathBuf> = ["d.gml", "a.gml", "c.gml", "b.gml", "e.gml"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let mut selected = filenames.clone();
+        Shard { index: 1, count: 2 }.select(&mut selected);
+        assert_eq!(
+            selected,
+            vec![PathBuf::from("b.gml"), PathBuf::from("d.gml")]
+        );
+
+        // every file is assigned to exactly one shard
+        let total: usize = (0..3)
+            .map(|index| {
+                let mut selected = filenames.clone();
+                Shard { index, count: 3 }.select(&mut selected);
+                selected.len()
+            })
+            .sum();
+        assert_eq!(total, filenames.len());
+    }
+
+    #[test]
+    fn test_percentage() {
+        assert_eq!(parse_percentage("1%"), Ok(0.01));
+        assert_eq!(parse_percentage("100%"), Ok(1.0));
+        assert!(parse_percentage("0.01").is_err());
+        assert!(parse_percentage("0%").is_err());
+        assert!(parse_percentage("150%").is_err());
+    }
+
+    #[test]
+    fn test_vintage() {
+        assert_eq!(
+            parse_vintage("2022=data/2022/*.gml"),
+            Ok((2022, "data/2022/*.gml".to_string()))
+        );
+        assert!(parse_vintage("data/*.gml").is_err());
+
+        assert_eq!(
+            vintage_output_path("out/city.gpkg", 2023),
+            "out/city_2023.gpkg"
+        );
+        assert_eq!(vintage_output_path("out/tiles", 2023), "out/tiles/2023");
+
+        let years = HashMap::from([
+            (PathBuf::from("a.gml"), 2023),
+            (PathBuf::from("b.gml"), 2020),
+            (PathBuf::from("c.gml"), 2023),
+        ]);
+        let groups = group_by_vintage(
+            ["a.gml", "b.gml", "c.gml", "d.gml"]
+                .iter()
+                .map(PathBuf::from)
+                .collect(),
+            &years,
+        );
+        assert_eq!(
+            groups,
+            vec![
+                (None, vec![PathBuf::from("d.gml")]),
+                (Some(2020), vec![PathBuf::from("b.gml")]),
+                (
+                    Some(2023),
+                    vec![PathBuf::from("a.gml"), PathBuf::from("c.gml")]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inspect_cmd() {
+        use assert_cmd::Command;
+
+        let mut cmd = Command::cargo_bin("nusamai").unwrap();
+        let assert = cmd
+            .arg("inspect")
+            .arg("../nusamai-plateau/tests/data/sendai-shi/udx/urf/574026_urf_6668_huchi_op.gml")
+            .assert();
+        assert.success();
+    }
+
     #[test]
     fn test_run_cmd() {
         use assert_cmd::Command;
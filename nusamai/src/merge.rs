@@ -0,0 +1,49 @@
+//! Merging of the outputs of the shards of a conversion (`--shard`) into one output
+//!
+//! Supported for GeoPackage, and the directories of 3D Tiles and vector tiles (MVT).
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    pipeline::{PipelineError, Result},
+    sink::{
+        cesiumtiles::merge::{is_tileset_directory, merge_tilesets},
+        gpkg::merge::merge_geopackages,
+        mvt::merge::{is_tile_directory, merge_tile_directories},
+    },
+};
+
+/// Merges the outputs of the shards into a new output, detecting the format from the outputs
+pub fn merge_outputs(inputs: &[PathBuf], output: &Path) -> Result<()> {
+    if inputs.is_empty() {
+        return Err(PipelineError::Other("No outputs to merge".into()));
+    }
+    if output.exists() {
+        return Err(PipelineError::Other(format!(
+            "The output already exists: {:?}",
+            output
+        )));
+    }
+    let inputs: Vec<&Path> = inputs.iter().map(|path| path.as_path()).collect();
+    let all = |predicate: fn(&Path) -> bool| inputs.iter().all(|path| predicate(path));
+
+    if all(is_geopackage) {
+        merge_geopackages(&inputs, output)
+    } else if all(is_tileset_directory) {
+        merge_tilesets(&inputs, output)
+    } else if all(is_tile_directory) {
+        merge_tile_directories(&inputs, output)
+    } else {
+        Err(PipelineError::Other(
+            "Only the outputs of the same format can be merged: GeoPackage files, or directories of 3D Tiles or vector tiles (MVT)"
+                .into(),
+        ))
+    }
+}
+
+fn is_geopackage(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gpkg"))
+}
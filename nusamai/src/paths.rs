@@ -0,0 +1,151 @@
+//! Local paths and `file:` URLs robust on Windows
+//!
+//! - `fs::canonicalize` returns the verbatim paths (`\\?\C:\...`) on Windows, which end up in the messages
+//!   and the outputs (e.g. the source files of the traces) and confuse the other tools.
+//! - The paths longer than `MAX_PATH` (260 characters) cannot be opened without the verbatim prefix unless
+//!   the long paths are enabled in the system settings, and the deep directories of the PLATEAU packages
+//!   with the Japanese names easily exceed it (especially with the texture images).
+//!
+//! So the paths are kept in the ordinary form (also in the `file:` URLs), and turned into the verbatim form
+//! with [`extended`] only to access the files. On the other platforms, the paths are used as they are.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use url::Url;
+
+/// Maximum length of the ordinary paths on Windows (including the terminating null)
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+/// The path in the ordinary form (`C:\...`, `\\server\share\...`) if it is a verbatim one
+#[cfg(windows)]
+pub fn simplified(path: &Path) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    let mut components = path.components();
+    let Some(Component::Prefix(prefix)) = components.next() else {
+        return path.to_path_buf();
+    };
+    let mut simple = match prefix.kind() {
+        Prefix::VerbatimDisk(letter) => PathBuf::from(format!("{}:\\", letter as char)),
+        Prefix::VerbatimUNC(server, share) => PathBuf::from(format!(
+            "\\\\{}\\{}\\",
+            server.to_string_lossy(),
+            share.to_string_lossy()
+        )),
+        _ => return path.to_path_buf(),
+    };
+    for component in components {
+        if let Component::Normal(name) = component {
+            simple.push(name);
+        }
+    }
+    simple
+}
+
+#[cfg(not(windows))]
+pub fn simplified(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// The path to access the file: in the verbatim form (`\\?\C:\...`) if it is too long for the ordinary one
+#[cfg(windows)]
+pub fn extended(path: &Path) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    // (the length in bytes is not less than the one in UTF-16, and the directories are limited to
+    // 12 characters shorter for the 8.3 file names)
+    if path.as_os_str().len() < MAX_PATH - 12 {
+        return path.to_path_buf();
+    }
+    let Ok(absolute) = std::path::absolute(path) else {
+        return path.to_path_buf();
+    };
+    let mut components = absolute.components();
+    let Some(Component::Prefix(prefix)) = components.next() else {
+        return absolute;
+    };
+    let mut extended = match prefix.kind() {
+        Prefix::Disk(letter) => PathBuf::from(format!("\\\\?\\{}:\\", letter as char)),
+        Prefix::UNC(server, share) => PathBuf::from(format!(
+            "\\\\?\\UNC\\{}\\{}\\",
+            server.to_string_lossy(),
+            share.to_string_lossy()
+        )),
+        // already verbatim, or a device
+        _ => return absolute,
+    };
+    // (the verbatim paths are not normalized by the system)
+    for component in components {
+        match component {
+            Component::Normal(name) => extended.push(name),
+            Component::ParentDir => {
+                extended.pop();
+            }
+            _ => {}
+        }
+    }
+    extended
+}
+
+#[cfg(not(windows))]
+pub fn extended(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// `fs::canonicalize` returning the path in the ordinary form
+pub fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    std::fs::canonicalize(extended(path)).map(|path| simplified(&path))
+}
+
+/// `file:` URL of a local path (relative to the current directory if not absolute)
+pub fn file_url(path: &Path) -> Option<Url> {
+    let absolute = std::path::absolute(path).ok()?;
+    Url::from_file_path(simplified(&absolute)).ok()
+}
+
+/// Local path of a `file:` URL, to access the file
+pub fn url_to_path(url: &Url) -> Option<PathBuf> {
+    url.to_file_path().ok().map(|path| extended(&path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_japanese_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("東京都").join("テクスチャ画像");
+        std::fs::create_dir_all(&sub).unwrap();
+        let path = sub.join("画像 1.jpg");
+        std::fs::write(&path, b"").unwrap();
+
+        let canonical = canonicalize(&path).unwrap();
+        assert!(!canonical.to_string_lossy().starts_with(r"\\?\"));
+
+        let url = file_url(&canonical).unwrap();
+        assert_eq!(url.scheme(), "file");
+        assert!(url_to_path(&url).unwrap().is_file());
+    }
+
+    #[test]
+    fn test_long_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut path = dir.path().to_path_buf();
+        for _ in 0..12 {
+            path.push("13104_新宿区_city_2023_citygml_1_op");
+        }
+        std::fs::create_dir_all(extended(&path)).unwrap();
+        let file = path.join("53394525_bldg_6697_appearance.jpg");
+        std::fs::write(extended(&file), b"").unwrap();
+
+        let url = file_url(&file).unwrap();
+        let local = url_to_path(&url).unwrap();
+        assert!(local.is_file());
+        assert_eq!(simplified(&local), file);
+    }
+}
@@ -0,0 +1,208 @@
+//! Batched 3D Model (b3dm) content for the viewers supporting only 3D Tiles 1.0
+//!
+//! The glb payload identifies the features with the `_BATCHID` vertex attribute,
+//! and their attributes are written to the JSON part of the batch table.
+
+use nusamai_citygml::object::Value;
+
+use crate::pipeline::{PipelineError, Result};
+
+const B3DM_MAGIC: &[u8; 4] = b"b3dm";
+const B3DM_VERSION: u32 = 1;
+const B3DM_HEADER_SIZE: usize = 28;
+
+/// Format of the tile contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentFormat {
+    /// glTF binary with `EXT_mesh_features` and `EXT_structural_metadata` (3D Tiles 1.1)
+    #[default]
+    Glb,
+    /// Batched 3D Model with a batch table (3D Tiles 1.0)
+    B3dm,
+}
+
+impl ContentFormat {
+    /// Parses the option (`glb`, `b3dm`)
+    pub fn negotiate(option: &str) -> Result<Self> {
+        match option {
+            "" | "glb" => Ok(Self::Glb),
+            "b3dm" => Ok(Self::B3dm),
+            _ => Err(PipelineError::Other(format!(
+                "Unknown content format: {option} (expected glb or b3dm)"
+            ))),
+        }
+    }
+
+    /// Extension of the content files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Glb => "glb",
+            Self::B3dm => "b3dm",
+        }
+    }
+
+    /// Version of 3D Tiles written to the tilesets
+    pub fn tileset_version(&self) -> &'static str {
+        match self {
+            Self::Glb => "1.1",
+            Self::B3dm => "1.0",
+        }
+    }
+
+    /// Semantic of the vertex attribute identifying the features
+    pub fn feature_id_semantic(&self) -> &'static str {
+        match self {
+            Self::Glb => "_FEATURE_ID_0",
+            Self::B3dm => "_BATCHID",
+        }
+    }
+}
+
+/// Makes the JSON part of the batch table from the attributes of the features (in the order of the batch IDs).
+///
+/// Each property is an array with an element for each feature, and `null` where the feature doesn't have it.
+pub fn batch_table<'a>(attributes: impl IntoIterator<Item = &'a Value>) -> serde_json::Value {
+    let mut table: indexmap::IndexMap<String, Vec<serde_json::Value>> = Default::default();
+    let mut batch_length = 0;
+
+    for value in attributes {
+        if let Value::Object(obj) = value {
+            let id = obj.stereotype.id().map(|id| Value::String(id.to_string()));
+            for (name, value) in id.iter().map(|id| ("id", id)).chain(
+                obj.attributes
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value)),
+            ) {
+                let column = table.entry(name.to_string()).or_default();
+                column.resize(batch_length, serde_json::Value::Null);
+                column.push(value.to_attribute_json());
+            }
+        }
+        batch_length += 1;
+    }
+
+    serde_json::Value::Object(
+        table
+            .into_iter()
+            .map(|(name, mut column)| {
+                column.resize(batch_length, serde_json::Value::Null);
+                (name, serde_json::Value::Array(column))
+            })
+            .collect(),
+    )
+}
+
+/// Wraps the glb into a b3dm with the batch table
+pub fn write_b3dm(glb: &[u8], batch_length: usize, batch_table: &serde_json::Value) -> Vec<u8> {
+    // The JSON parts are padded with spaces so that the next part starts at an 8-byte boundary
+    let padded_json = |json: String, offset: usize| {
+        let mut bytes = json.into_bytes();
+        bytes.resize((offset + bytes.len()).next_multiple_of(8) - offset, b' ');
+        bytes
+    };
+
+    let feature_table = padded_json(
+        serde_json::json!({ "BATCH_LENGTH": batch_length }).to_string(),
+        B3DM_HEADER_SIZE,
+    );
+    let batch_table = match batch_length {
+        0 => vec![],
+        _ => padded_json(
+            batch_table.to_string(),
+            B3DM_HEADER_SIZE + feature_table.len(),
+        ),
+    };
+
+    let mut glb = glb.to_vec();
+    glb.resize(glb.len().next_multiple_of(8), 0);
+
+    let byte_length = B3DM_HEADER_SIZE + feature_table.len() + batch_table.len() + glb.len();
+    let mut b3dm = Vec::with_capacity(byte_length);
+    b3dm.extend_from_slice(B3DM_MAGIC);
+    for value in [
+        B3DM_VERSION,
+        byte_length as u32,
+        feature_table.len() as u32,
+        0, // feature table binary
+        batch_table.len() as u32,
+        0, // batch table binary
+    ] {
+        b3dm.extend_from_slice(&value.to_le_bytes());
+    }
+    b3dm.extend_from_slice(&feature_table);
+    b3dm.extend_from_slice(&batch_table);
+    b3dm.extend_from_slice(&glb);
+    b3dm
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::object::{Map, Object, ObjectStereotype};
+    use serde_json::json;
+
+    use super::*;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn test_write_b3dm() {
+        let glb = [1u8; 13];
+        let table = json!({ "id": ["a", "b"] });
+        let b3dm = write_b3dm(&glb, 2, &table);
+
+        assert_eq!(&b3dm[0..4], b"b3dm");
+        assert_eq!(read_u32(&b3dm, 4), 1);
+        assert_eq!(read_u32(&b3dm, 8), b3dm.len());
+        assert_eq!(b3dm.len() % 8, 0);
+
+        let feature_table_len = read_u32(&b3dm, 12);
+        let batch_table_len = read_u32(&b3dm, 20);
+        let feature_table: serde_json::Value =
+            serde_json::from_slice(&b3dm[28..28 + feature_table_len]).unwrap();
+        assert_eq!(feature_table, json!({ "BATCH_LENGTH": 2 }));
+
+        let batch_table_start = 28 + feature_table_len;
+        assert_eq!(batch_table_start % 8, 0);
+        let batch_table: serde_json::Value =
+            serde_json::from_slice(&b3dm[batch_table_start..batch_table_start + batch_table_len])
+                .unwrap();
+        assert_eq!(batch_table, table);
+
+        let glb_start = batch_table_start + batch_table_len;
+        assert_eq!(glb_start % 8, 0);
+        assert_eq!(&b3dm[glb_start..glb_start + 13], glb);
+    }
+
+    #[test]
+    fn test_batch_table() {
+        let feature = |id: &str, attributes: &[(&str, Value)]| {
+            Value::Object(Object {
+                typename: "bldg:Building".into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: id.to_string(),
+                    geometries: Default::default(),
+                },
+                attributes: Map::from_iter(
+                    attributes
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), value.clone())),
+                ),
+            })
+        };
+        let features = [
+            feature("bldg_1", &[("height", Value::Double(12.5))]),
+            feature("bldg_2", &[("name", Value::String("A".to_string()))]),
+        ];
+
+        assert_eq!(
+            batch_table(&features),
+            json!({
+                "id": ["bldg_1", "bldg_2"],
+                "height": [12.5, null],
+                "name": [null, "A"],
+            })
+        );
+    }
+}
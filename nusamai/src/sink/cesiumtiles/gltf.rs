@@ -1,13 +1,20 @@
 use std::io::Write;
 
-use ahash::{HashMap, HashSet};
+use ahash::{HashSet, RandomState};
 use byteorder::{ByteOrder, LittleEndian};
 use flate2::{write::GzEncoder, Compression};
-use indexmap::IndexSet;
-use nusamai_gltf_json::extensions::mesh::ext_mesh_features;
-
-use super::{material, metadata::MetadataEncoder};
-use crate::pipeline::{feedback, PipelineError};
+use indexmap::{IndexMap, IndexSet};
+use nusamai_gltf::meshopt::{compress_buffer_views, fallback_buffer};
+use nusamai_gltf_json::extensions::{buffer::MeshoptCompressionMode, mesh::ext_mesh_features};
+
+use super::{b3dm::ContentFormat, material, metadata::MetadataEncoder};
+use crate::{
+    pipeline::{feedback, PipelineError},
+    sink::mesh_compression::{
+        compact_vertices, write_draco_primitive, write_quantized_vertices, Dequantization,
+        DracoPrimitive, MeshCompression, VertexAttribute, VertexAttributeKind,
+    },
+};
 
 #[derive(Default)]
 pub struct PrimitiveInfo {
@@ -15,7 +22,31 @@ pub struct PrimitiveInfo {
     pub feature_ids: HashSet<u32>,
 }
 
-pub type Primitives = HashMap<material::Material, PrimitiveInfo>;
+/// Topology of a primitive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PrimitiveKind {
+    /// Triangulated polygons
+    #[default]
+    Triangles,
+    /// Line segments (pairs of indices)
+    Lines,
+    Points,
+}
+
+impl PrimitiveKind {
+    fn mode(self) -> nusamai_gltf_json::PrimitiveMode {
+        match self {
+            PrimitiveKind::Triangles => nusamai_gltf_json::PrimitiveMode::Triangles,
+            PrimitiveKind::Lines => nusamai_gltf_json::PrimitiveMode::Lines,
+            PrimitiveKind::Points => nusamai_gltf_json::PrimitiveMode::Points,
+        }
+    }
+}
+
+pub type PrimitiveKey = (material::Material, PrimitiveKind);
+
+/// Primitives in the order of their first appearance (to keep the output deterministic)
+pub type Primitives = IndexMap<PrimitiveKey, PrimitiveInfo, RandomState>;
 
 #[allow(clippy::too_many_arguments)]
 pub fn write_gltf_glb<W: Write>(
@@ -24,19 +55,60 @@ pub fn write_gltf_glb<W: Write>(
     translation: [f64; 3],
     vertices: impl IntoIterator<Item = [u32; 9]>,
     primitives: Primitives,
-    num_features: usize,
     metadata_encoder: MetadataEncoder,
     gzip_compress: bool,
+    mesh_compression: MeshCompression,
+    content_format: ContentFormat,
 ) -> Result<(), PipelineError> {
     use nusamai_gltf_json::*;
 
+    let mut primitives = primitives;
+    let mut vertices: Vec<[u32; 9]> = vertices.into_iter().collect();
+    // With Draco, the triangles are encoded for each primitive below,
+    // and the shared vertex buffer keeps only the vertices of the lines and the points
+    let draco_vertices = match mesh_compression {
+        MeshCompression::Draco(_) => {
+            let shared = compact_vertices(
+                &vertices,
+                primitives
+                    .iter_mut()
+                    .filter(|((_, kind), _)| *kind != PrimitiveKind::Triangles)
+                    .map(|(_, primitive)| &mut primitive.indices),
+            );
+            std::mem::replace(&mut vertices, shared)
+        }
+        MeshCompression::None | MeshCompression::Meshopt(_) | MeshCompression::Quantize(_) => {
+            vec![]
+        }
+    };
+
     // The buffer for the BIN part
     let mut bin_content: Vec<u8> = Vec::new();
     let mut gltf_buffer_views = vec![];
     let mut gltf_accessors = vec![];
 
+    // the buffer views to be compressed with meshopt
+    let mut meshopt_views = vec![];
+
     // vertices
-    {
+    let mut dequantization = None;
+    if let Some(quantization) = mesh_compression.vertex_quantization() {
+        dequantization = write_quantized_vertices(
+            quantization,
+            &vertices,
+            &[],
+            0,
+            &mut bin_content,
+            &mut gltf_buffer_views,
+            &mut gltf_accessors,
+        );
+        if dequantization.is_some() && matches!(mesh_compression, MeshCompression::Meshopt(_)) {
+            meshopt_views.push((
+                gltf_buffer_views.len() - 1,
+                MeshoptCompressionMode::Attributes,
+            ));
+        }
+    } else {
         let mut vertices_count = 0;
         let mut position_max = [f64::MIN; 3];
         let mut position_min = [f64::MAX; 3];
@@ -122,66 +194,126 @@ pub fn write_gltf_glb<W: Write>(
     }
 
     let mut gltf_primitives = vec![];
+    // (the same material may be used by the primitives of different kinds)
+    let mut material_set: IndexSet<&material::Material, ahash::RandomState> = Default::default();
 
-    let structural_metadata =
-        metadata_encoder.into_metadata(&mut bin_content, &mut gltf_buffer_views);
+    // (the attributes of the b3dm content are written to the batch table)
+    let structural_metadata = match content_format {
+        ContentFormat::Glb => {
+            metadata_encoder.into_metadata(&mut bin_content, &mut gltf_buffer_views)
+        }
+        ContentFormat::B3dm => None,
+    };
+
+    // Draco-compressed triangles (written before the indices to keep the indices in a single buffer view)
+    let draco_primitives: Vec<Option<DracoPrimitive>> = primitives
+        .iter()
+        .map(|((mat, kind), primitive)| {
+            let MeshCompression::Draco(quantization) = &mesh_compression else {
+                return None;
+            };
+            if *kind != PrimitiveKind::Triangles {
+                return None;
+            }
+            let mut indices = primitive.indices.clone();
+            let local_vertices = compact_vertices(&draco_vertices, [&mut indices]);
+            Some(write_draco_primitive(
+                quantization,
+                &draco_attributes(
+                    &local_vertices,
+                    mat.base_texture.is_some(),
+                    content_format.feature_id_semantic(),
+                ),
+                &indices,
+                &mut bin_content,
+                &mut gltf_buffer_views,
+                &mut gltf_accessors,
+            ))
+        })
+        .collect();
+    let has_draco = draco_primitives.iter().any(Option::is_some);
 
     // indices
     {
         let indices_offset = bin_content.len();
 
         let mut byte_offset = 0;
-        for (mat_idx, (mat, primitive)) in primitives.iter().enumerate() {
-            let mut indices_count = 0;
-            for idx in &primitive.indices {
-                bin_content.write_all(&idx.to_le_bytes())?;
-                indices_count += 1;
-            }
+        for (((mat, kind), primitive), draco) in primitives.iter().zip(draco_primitives) {
+            let (mat_idx, _) = material_set.insert_full(mat);
+
+            let (attributes, indices, khr_draco_mesh_compression) = match draco {
+                Some(draco) => (draco.attributes, draco.indices, Some(draco.extension)),
+                None => {
+                    let mut indices_count = 0;
+                    for idx in &primitive.indices {
+                        bin_content.write_all(&idx.to_le_bytes())?;
+                        indices_count += 1;
+                    }
 
-            gltf_accessors.push(Accessor {
-                name: Some("indices".to_string()),
-                buffer_view: Some(gltf_buffer_views.len() as u32),
-                byte_offset,
-                component_type: ComponentType::UnsignedInt,
-                count: indices_count,
-                type_: AccessorType::Scalar,
-                ..Default::default()
-            });
+                    gltf_accessors.push(Accessor {
+                        name: Some("indices".to_string()),
+                        buffer_view: Some(gltf_buffer_views.len() as u32),
+                        byte_offset,
+                        component_type: ComponentType::UnsignedInt,
+                        count: indices_count,
+                        type_: AccessorType::Scalar,
+                        ..Default::default()
+                    });
+                    byte_offset += indices_count * 4;
+
+                    let mut attributes =
+                        vec![("POSITION".to_string(), 0), ("NORMAL".to_string(), 1)];
+                    // TODO: For no-texture data, it's better to exclude u, v from the vertex buffer
+                    if mat.base_texture.is_some() {
+                        attributes.push(("TEXCOORD_0".to_string(), 2));
+                    }
+                    attributes.push((content_format.feature_id_semantic().to_string(), 3));
 
-            let mut attributes = vec![("POSITION".to_string(), 0), ("NORMAL".to_string(), 1)];
-            // TODO: For no-texture data, it's better to exclude u, v from the vertex buffer
-            if mat.base_texture.is_some() {
-                attributes.push(("TEXCOORD_0".to_string(), 2));
-            }
-            attributes.push(("_FEATURE_ID_0".to_string(), 3));
+                    (
+                        attributes.into_iter().collect(),
+                        gltf_accessors.len() as u32 - 1,
+                        None,
+                    )
+                }
+            };
 
             gltf_primitives.push(MeshPrimitive {
-                attributes: attributes.into_iter().collect(),
-                indices: Some(gltf_accessors.len() as u32 - 1),
+                attributes,
+                indices: Some(indices),
                 material: Some(mat_idx as u32), // TODO
-                mode: PrimitiveMode::Triangles,
+                mode: kind.mode(),
                 extensions: extensions::mesh::MeshPrimitive {
-                    ext_mesh_features: ext_mesh_features::ExtMeshFeatures {
-                        feature_ids: vec![ext_mesh_features::FeatureId {
-                            feature_count: num_features as u32, // primitive.feature_ids.len() as u32,
-                            attribute: Some(0),
-                            property_table: Some(0),
+                    khr_draco_mesh_compression,
+                    ext_mesh_features: (content_format == ContentFormat::Glb).then(|| {
+                        ext_mesh_features::ExtMeshFeatures {
+                            feature_ids: vec![ext_mesh_features::FeatureId {
+                                feature_count: primitive.feature_ids.len() as u32,
+                                attribute: Some(0),
+                                property_table: Some(0),
+                                ..Default::default()
+                            }],
                             ..Default::default()
-                        }],
-                        ..Default::default()
-                    }
-                    .into(),
+                        }
+                    }),
                     ..Default::default()
                 }
                 .into(),
                 ..Default::default()
             });
-
-            byte_offset += indices_count * 4;
         }
 
         let indices_len = bin_content.len() - indices_offset;
         if indices_len > 0 {
+            if let MeshCompression::Meshopt(_) = mesh_compression {
+                let mode = match primitives
+                    .keys()
+                    .all(|(_, kind)| *kind == PrimitiveKind::Triangles)
+                {
+                    true => MeshoptCompressionMode::Triangles,
+                    false => MeshoptCompressionMode::Indices,
+                };
+                meshopt_views.push((gltf_buffer_views.len(), mode));
+            }
             gltf_buffer_views.push(BufferView {
                 name: Some("indices".to_string()),
                 byte_offset: indices_offset as u32,
@@ -196,10 +328,16 @@ pub fn write_gltf_glb<W: Write>(
     let mut texture_set: IndexSet<material::Texture, ahash::RandomState> = Default::default();
 
     // materials
-    let gltf_materials = primitives
-        .keys()
+    let gltf_materials: Vec<_> = material_set
+        .into_iter()
         .map(|material| material.to_gltf(&mut texture_set))
         .collect();
+    let has_specular = gltf_materials.iter().any(|material| {
+        material
+            .extensions
+            .as_ref()
+            .is_some_and(|ext| ext.khr_materials_specular.is_some())
+    });
 
     let gltf_textures: Vec<_> = texture_set
         .into_iter()
@@ -214,6 +352,13 @@ pub fn write_gltf_glb<W: Write>(
         })
         .collect::<Result<Vec<Image>, PipelineError>>()?;
 
+    // the compressed buffer views are moved to the fallback buffer (without data)
+    let mut fallback_length = 0;
+    if !meshopt_views.is_empty() {
+        (bin_content, fallback_length) =
+            compress_buffer_views(&bin_content, &mut gltf_buffer_views, &meshopt_views);
+    }
+
     let mut gltf_meshes = vec![];
     if !gltf_primitives.is_empty() {
         gltf_meshes.push(Mesh {
@@ -230,6 +375,9 @@ pub fn write_gltf_glb<W: Write>(
                 ..Default::default()
             });
         }
+        if fallback_length > 0 {
+            buffers.push(fallback_buffer(fallback_length));
+        }
         buffers
     };
 
@@ -241,19 +389,55 @@ pub fn write_gltf_glb<W: Write>(
             .map_or(false, |_| true)
     });
 
+    let has_basisu = gltf_textures.iter().any(|texture| {
+        texture
+            .extensions
+            .as_ref()
+            .is_some_and(|ext| ext.khr_texture_basisu.is_some())
+    });
+
+    let has_compressed_meshes = has_draco || dequantization.is_some();
     let extensions_used = {
-        let mut extensions_used = vec![
-            "EXT_mesh_features".to_string(),
-            "EXT_structural_metadata".to_string(),
-        ];
+        let mut extensions_used = match content_format {
+            ContentFormat::Glb => vec![
+                "EXT_mesh_features".to_string(),
+                "EXT_structural_metadata".to_string(),
+            ],
+            ContentFormat::B3dm => vec![],
+        };
 
         // Add "EXT_texture_webp" extension if WebP textures are present
         if has_webp {
             extensions_used.push("EXT_texture_webp".to_string());
         }
+        if has_basisu {
+            extensions_used.push("KHR_texture_basisu".to_string());
+        }
+        if has_specular {
+            extensions_used.push("KHR_materials_specular".to_string());
+        }
+        if has_compressed_meshes {
+            extensions_used.extend(mesh_compression.extensions().iter().map(|e| e.to_string()));
+        }
 
         extensions_used
     };
+    // the KTX2 textures and the compressed meshes have no fallbacks
+    let mut extensions_required = match has_basisu {
+        true => vec!["KHR_texture_basisu".to_string()],
+        false => vec![],
+    };
+    if has_compressed_meshes {
+        extensions_required.extend(mesh_compression.extensions().iter().map(|e| e.to_string()));
+    }
+
+    // the quantized positions are dequantized by the node transform
+    let (translation, scale) = match dequantization {
+        Some(Dequantization { offset, scale }) => {
+            ([0, 1, 2].map(|i| translation[i] + offset[i]), scale)
+        }
+        None => (translation, [1.; 3]),
+    };
 
     feedback.ensure_not_canceled()?;
 
@@ -266,6 +450,7 @@ pub fn write_gltf_glb<W: Write>(
         nodes: vec![Node {
             mesh: (!primitives.is_empty()).then_some(0),
             translation,
+            scale,
             ..Default::default()
         }],
         meshes: gltf_meshes,
@@ -281,15 +466,20 @@ pub fn write_gltf_glb<W: Write>(
         }
         .into(),
         extensions_used,
+        extensions_required,
         ..Default::default()
     };
 
+    // Some maps in the JSON part are hash maps, so the keys are sorted (through `serde_json::Value`)
+    // to make the output deterministic
+    let json = serde_json::to_vec(&serde_json::to_value(&gltf).unwrap()).unwrap();
+
     if gzip_compress {
         // Write glb to the writer with gzip compression
         let mut encoder = GzEncoder::new(writer, Compression::default());
 
         nusamai_gltf::glb::Glb {
-            json: serde_json::to_vec(&gltf).unwrap().into(),
+            json: json.into(),
             bin: Some(bin_content.into()),
         }
         .to_writer_with_alignment(&mut encoder, 8)?;
@@ -298,7 +488,7 @@ pub fn write_gltf_glb<W: Write>(
     } else {
         // Write glb to the writer
         nusamai_gltf::glb::Glb {
-            json: serde_json::to_vec(&gltf).unwrap().into(),
+            json: json.into(),
             bin: Some(bin_content.into()),
         }
         .to_writer_with_alignment(writer, 8)?;
@@ -306,3 +496,33 @@ pub fn write_gltf_glb<W: Write>(
 
     Ok(())
 }
+
+/// The attributes of the vertices `[x, y, z, nx, ny, nz, u, v, feature_id]` to be compressed
+fn draco_attributes(
+    vertices: &[[u32; 9]],
+    textured: bool,
+    feature_id_semantic: &str,
+) -> Vec<VertexAttribute> {
+    let attribute = |semantic: &str, kind, range: std::ops::Range<usize>| VertexAttribute {
+        semantic: semantic.to_string(),
+        kind,
+        values: vertices
+            .iter()
+            .flat_map(|v| v[range.clone()].iter().map(|&bits| f32::from_bits(bits)))
+            .collect(),
+    };
+
+    let mut attributes = vec![
+        attribute("POSITION", VertexAttributeKind::Position, 0..3),
+        attribute("NORMAL", VertexAttributeKind::Normal, 3..6),
+    ];
+    if textured {
+        attributes.push(attribute("TEXCOORD_0", VertexAttributeKind::TexCoord, 6..8));
+    }
+    attributes.push(attribute(
+        feature_id_semantic,
+        VertexAttributeKind::FeatureId,
+        8..9,
+    ));
+    attributes
+}
@@ -0,0 +1,157 @@
+//! Simplification of the coarser tiles (HLOD) based on vertex clustering.
+//!
+//! The tiles above the maximum zoom level are refined by replacement, so their meshes and textures only need to be
+//! as detailed as their geometric error allows.
+
+use hashbrown::HashMap;
+
+/// Ratio of the cell size of the vertex clustering to the geometric error of the tile.
+///
+/// A vertex moves by at most the diagonal of a cell (√3 × the cell size), which is kept well below the geometric error.
+pub const CELL_SIZE_RATIO: f64 = 0.125;
+
+/// Ratio of the texel size (in meters) to the geometric error of the tile.
+///
+/// The tiles are displayed until their geometric error reaches 16 pixels on the screen
+/// (the default maximum screen space error of CesiumJS), so a texel is about a pixel.
+pub const TEXEL_SIZE_RATIO: f64 = 1.0 / 16.0;
+
+type Cell = [i64; 3];
+
+/// Clusters the vertices of each feature into a grid of cells, and merges the vertices in a cell.
+///
+/// The merged position is the mean of all the vertices of the feature in the cell, so the polygons sharing an edge stay
+/// connected. The texture coordinates are averaged for each polygon, as each polygon has its own region in the atlas.
+pub struct VertexClustering {
+    cell_size: f64,
+    cells: HashMap<(u32, Cell), ([f64; 3], u32)>,
+}
+
+impl VertexClustering {
+    pub fn new(cell_size: f64) -> Self {
+        assert!(cell_size > 0.0, "cell size must be positive");
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, [x, y, z]: [f64; 3]) -> Cell {
+        [x, y, z].map(|c| (c / self.cell_size).floor() as i64)
+    }
+
+    /// Adds a vertex of the feature. All the vertices must be added before simplifying the polygons.
+    pub fn add_vertex(&mut self, feature_id: u32, position: [f64; 3]) {
+        let cell = self.cell(position);
+        let (sum, count) = self
+            .cells
+            .entry((feature_id, cell))
+            .or_insert(([0.0; 3], 0));
+        for (s, c) in sum.iter_mut().zip(position) {
+            *s += c;
+        }
+        *count += 1;
+    }
+
+    /// Simplifies the triangles of a polygon (`coords` are [x, y, z, u, v], and `indices` are the triangles).
+    ///
+    /// Returns the vertices of the remaining triangles, and the triangles collapsed into a line or a point are removed.
+    pub fn simplify_triangles(
+        &self,
+        feature_id: u32,
+        coords: &[[f64; 5]],
+        indices: &[u32],
+    ) -> Vec<[f64; 5]> {
+        let cells: Vec<Cell> = coords
+            .iter()
+            .map(|&[x, y, z, _, _]| self.cell([x, y, z]))
+            .collect();
+
+        let mut uv_sums: HashMap<Cell, ([f64; 2], u32)> = HashMap::new();
+        for (cell, [_, _, _, u, v]) in cells.iter().zip(coords) {
+            let (sum, count) = uv_sums.entry(*cell).or_insert(([0.0; 2], 0));
+            sum[0] += u;
+            sum[1] += v;
+            *count += 1;
+        }
+
+        let merged_vertex = |cell: Cell| {
+            let (pos_sum, pos_count) = self.cells[&(feature_id, cell)];
+            let (uv_sum, uv_count) = uv_sums[&cell];
+            let [x, y, z] = pos_sum.map(|c| c / pos_count as f64);
+            let [u, v] = uv_sum.map(|c| c / uv_count as f64);
+            [x, y, z, u, v]
+        };
+
+        let mut triangles = Vec::new();
+        for tri in indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| cells[tri[i] as usize]);
+            if a == b || b == c || c == a {
+                continue;
+            }
+            triangles.extend([a, b, c].map(merged_vertex));
+        }
+        triangles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simplify_triangles() {
+        // a quad with a small notch near the corner (0, 0)
+        let coords = [
+            [0.0, 0.0, 0.0, 0.0, 0.0],
+            [0.5, 0.0, 0.0, 0.5, 0.0],
+            [1.0, 0.75, 0.0, 1.0, 0.75],
+            [10.0, 0.0, 0.0, 1.0, 0.0],
+            [10.0, 10.0, 0.0, 1.0, 1.0],
+            [0.0, 10.0, 0.0, 0.0, 1.0],
+        ];
+        let indices = [0, 1, 2, 0, 2, 3, 0, 3, 4, 0, 4, 5];
+
+        let mut clustering = VertexClustering::new(5.0);
+        for c in &coords {
+            clustering.add_vertex(0, [c[0], c[1], c[2]]);
+        }
+        let triangles = clustering.simplify_triangles(0, &coords, &indices);
+
+        // only the triangles spanning three cells remain
+        assert_eq!(triangles.len(), 6);
+        // the vertices in the first cell are merged into their mean
+        assert_eq!(triangles[0][..2], [0.5, 0.25]);
+        assert_eq!(triangles[1], [10.0, 0.0, 0.0, 1.0, 0.0]);
+        assert_eq!(triangles[2], [10.0, 10.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_shared_vertices() {
+        // two polygons of a feature sharing an edge get the same merged positions
+        let mut clustering = VertexClustering::new(1.0);
+        let poly_a = [
+            [0.25, 0.25, 0.0, 0.0, 0.0],
+            [5.5, 0.5, 0.0, 1.0, 0.0],
+            [5.5, 5.5, 0.0, 1.0, 1.0],
+        ];
+        let poly_b = [
+            [0.75, 0.75, 0.0, 0.5, 0.5],
+            [5.5, 5.5, 0.0, 0.6, 0.6],
+            [0.5, 5.5, 0.0, 0.7, 0.7],
+        ];
+        for c in poly_a.iter().chain(&poly_b) {
+            clustering.add_vertex(1, [c[0], c[1], c[2]]);
+        }
+        // another feature in the same cell is not merged
+        clustering.add_vertex(2, [0.9, 0.9, 0.0]);
+
+        let a = clustering.simplify_triangles(1, &poly_a, &[0, 1, 2]);
+        let b = clustering.simplify_triangles(1, &poly_b, &[0, 1, 2]);
+        assert_eq!(a[0][..3], [0.5, 0.5, 0.0]);
+        assert_eq!(a[0][..3], b[0][..3]);
+        // (the texture coordinates are kept for each polygon)
+        assert_eq!(a[0][3..], [0.0, 0.0]);
+        assert_eq!(b[0][3..], [0.5, 0.5]);
+    }
+}
@@ -3,16 +3,20 @@
 use std::{hash::Hash, path::Path, time::Instant};
 
 use indexmap::IndexSet;
-use nusamai_gltf_json::{BufferView, MimeType};
+use nusamai_gltf_json::{extensions::material::KhrMaterialsSpecular, BufferView, MimeType};
+use nusamai_plateau::appearance;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::pipeline::Feedback;
+use crate::{paths, pipeline::Feedback, sink::texture_compression::KTX2_EXTENSION};
 
 #[derive(Debug, Serialize, Clone, PartialEq, Deserialize)]
 pub struct Material {
     pub base_color: [f32; 4],
     pub base_texture: Option<Texture>,
+    pub metallic_roughness: [f32; 2],
+    pub specular: [f32; 3],
+    pub emissive: [f32; 3],
     // NOTE: Adjust the hash implementation if you add more fields
 }
 
@@ -22,6 +26,25 @@ impl Hash for Material {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.base_color.iter().for_each(|c| c.to_bits().hash(state));
         self.base_texture.hash(state);
+        self.metallic_roughness
+            .iter()
+            .for_each(|c| c.to_bits().hash(state));
+        self.specular.iter().for_each(|c| c.to_bits().hash(state));
+        self.emissive.iter().for_each(|c| c.to_bits().hash(state));
+    }
+}
+
+impl Default for Material {
+    /// The default material of CityGML (for the geometries without appearances, e.g. the lines and the points)
+    fn default() -> Self {
+        let orig = appearance::Material::default();
+        Self {
+            base_color: orig.base_color(),
+            base_texture: None,
+            metallic_roughness: orig.metallic_roughness(),
+            specular: orig.specular(),
+            emissive: orig.emissive(),
+        }
     }
 }
 
@@ -43,14 +66,44 @@ impl Material {
         nusamai_gltf_json::Material {
             pbr_metallic_roughness: Some(nusamai_gltf_json::MaterialPbrMetallicRoughness {
                 base_color_factor: to_f64x4(self.base_color),
-                metallic_factor: 0.2,
-                roughness_factor: 0.5,
+                metallic_factor: f64::from(self.metallic_roughness[0]),
+                roughness_factor: f64::from(self.metallic_roughness[1]),
                 base_color_texture: tex,
                 ..Default::default()
             }),
+            emissive_factor: self.emissive.map(f64::from),
+            extensions: self.specular_extension(),
+            alpha_mode: self.alpha_mode(),
+            // translucent surfaces (e.g. water, glass) should be visible from both sides
+            double_sided: self.is_translucent(),
             ..Default::default()
         }
     }
+
+    /// `KHR_materials_specular` with the specular color (omitted if it is white, the default of the extension)
+    fn specular_extension(&self) -> Option<nusamai_gltf_json::MaterialExtensions> {
+        if self.specular == [1.0, 1.0, 1.0] {
+            return None;
+        }
+        Some(nusamai_gltf_json::MaterialExtensions {
+            khr_materials_specular: Some(KhrMaterialsSpecular {
+                specular_color_factor: self.specular.map(f64::from),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn is_translucent(&self) -> bool {
+        self.base_color[3] < 1.0
+    }
+
+    fn alpha_mode(&self) -> nusamai_gltf_json::AlphaMode {
+        match self.is_translucent() {
+            true => nusamai_gltf_json::AlphaMode::Blend,
+            false => nusamai_gltf_json::AlphaMode::Opaque,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Clone, Hash, PartialEq, Eq, Deserialize)]
@@ -73,8 +126,8 @@ impl Texture {
             .and_then(|ext| ext.to_str())
             .map(|ext| ext.to_lowercase());
 
-        if extension == Some("webp".to_string()) {
-            nusamai_gltf_json::Texture {
+        match extension.as_deref() {
+            Some("webp") => nusamai_gltf_json::Texture {
                 extensions: Some(nusamai_gltf_json::extensions::texture::TextureExtensions {
                     ext_texture_webp: Some(
                         nusamai_gltf_json::extensions::texture::ExtTextureWebp {
@@ -85,12 +138,23 @@ impl Texture {
                 }),
                 source: Some(image_index as u32),
                 ..Default::default()
-            }
-        } else {
-            nusamai_gltf_json::Texture {
+            },
+            // (no fallback image, so the extension is required)
+            Some(KTX2_EXTENSION) => nusamai_gltf_json::Texture {
+                extensions: Some(nusamai_gltf_json::extensions::texture::TextureExtensions {
+                    khr_texture_basisu: Some(
+                        nusamai_gltf_json::extensions::texture::KhrTextureBasisu {
+                            source: image_index as u32,
+                        },
+                    ),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            _ => nusamai_gltf_json::Texture {
                 source: Some(image_index as u32),
                 ..Default::default()
-            }
+            },
         }
     }
 }
@@ -107,7 +171,7 @@ impl Image {
         buffer_views: &mut Vec<BufferView>,
         bin_content: &mut Vec<u8>,
     ) -> std::io::Result<nusamai_gltf_json::Image> {
-        if let Ok(path) = self.uri.to_file_path() {
+        if let Some(path) = paths::url_to_path(&self.uri) {
             // NOTE: temporary implementation
             let (content, mime_type) = load_image(feedback, &path)?;
 
@@ -160,6 +224,7 @@ fn load_image(feedback: &Feedback, path: &Path) -> std::io::Result<(Vec<u8>, Mim
                 feedback.info(format!("Embedding a webp as is: {:?}", path));
                 Ok((std::fs::read(path)?, MimeType::ImageWebp))
             }
+            Some(KTX2_EXTENSION) => Ok((std::fs::read(path)?, MimeType::ImageKtx2)),
             _ => {
                 let err = format!("Unsupported image format: {:?}", path);
                 log::error!("{}", err);
@@ -0,0 +1,205 @@
+//! Merging of the tilesets written by the shards of a conversion (`--shard`)
+//!
+//! The shards are copied into the numbered subdirectories (`0`, `1`, ...) of the output, and a tileset referring to
+//! their tilesets as the external tilesets is written for each tileset of the shards (`tileset.json`, and the tilesets
+//! of the LODs such as `lod1/tileset.json`). The tiles themselves are left as they are.
+
+use std::{collections::BTreeSet, fs, path::Path};
+
+use serde_json::{json, Value};
+
+use crate::{
+    pipeline::{PipelineError, Result},
+    sink::manifest::{Manifest, MANIFEST_FILENAME},
+};
+
+const TILESET_FILENAME: &str = "tileset.json";
+
+/// Returns true if the directory has the tileset of the 3D Tiles sink
+pub fn is_tileset_directory(path: &Path) -> bool {
+    path.join(TILESET_FILENAME).is_file()
+}
+
+/// Merges the tileset directories into a new directory
+pub fn merge_tilesets(inputs: &[&Path], output: &Path) -> Result<()> {
+    let manifest = Manifest::new();
+    // relative paths of the tilesets in the shards
+    let mut tilesets = BTreeSet::new();
+    for (i, input) in inputs.iter().enumerate() {
+        if !is_tileset_directory(input) {
+            return Err(PipelineError::Other(format!(
+                "Not a directory of 3D Tiles (no {}): {:?}",
+                TILESET_FILENAME, input
+            )));
+        }
+        copy_shard(input, output, &i.to_string(), "", &manifest, &mut tilesets)?;
+    }
+
+    for relpath in tilesets {
+        // (the URIs of the external tilesets are relative to the merged tileset)
+        let up = "../".repeat(relpath.matches('/').count());
+        let mut shards = Vec::new();
+        for (i, input) in inputs.iter().enumerate() {
+            let Ok(content) = fs::read(input.join(&relpath)) else {
+                continue;
+            };
+            let tileset: Value = serde_json::from_slice(&content).map_err(|err| {
+                PipelineError::Other(format!(
+                    "Invalid tileset {:?}: {}",
+                    input.join(&relpath),
+                    err
+                ))
+            })?;
+            shards.push((format!("{up}{i}/{relpath}"), tileset));
+        }
+        let content = serde_json::to_vec_pretty(&merged_tileset(&shards)?).unwrap();
+        let path = output.join(&relpath);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, &content)?;
+        manifest.add(&relpath, &content);
+    }
+    manifest.write(output)?;
+    Ok(())
+}
+
+/// Copies the files of the shard into `{output}/{shard}` (except the manifest), collecting the tilesets
+fn copy_shard(
+    input: &Path,
+    output: &Path,
+    shard: &str,
+    subdir: &str,
+    manifest: &Manifest,
+    tilesets: &mut BTreeSet<String>,
+) -> Result<()> {
+    for entry in fs::read_dir(input.join(subdir))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let relpath = match subdir {
+            "" => name.clone(),
+            _ => format!("{subdir}/{name}"),
+        };
+        if entry.file_type()?.is_dir() {
+            copy_shard(input, output, shard, &relpath, manifest, tilesets)?;
+            continue;
+        }
+        if relpath == MANIFEST_FILENAME {
+            continue;
+        }
+        if name == TILESET_FILENAME {
+            tilesets.insert(relpath.clone());
+        }
+        let content = fs::read(entry.path())?;
+        let path = output.join(shard).join(&relpath);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, &content)?;
+        manifest.add(&format!("{shard}/{relpath}"), &content);
+    }
+    Ok(())
+}
+
+/// The tileset with the tilesets of the shards (URI, tileset) as the children of the root
+fn merged_tileset(shards: &[(String, Value)]) -> Result<Value> {
+    let mut region: Option<[f64; 6]> = None;
+    let mut geometric_error: f64 = 0.0;
+    let mut children = Vec::new();
+    for (uri, tileset) in shards {
+        let root = &tileset["root"];
+        let bounds: Vec<f64> = root["boundingVolume"]["region"]
+            .as_array()
+            .map(|values| values.iter().filter_map(|v| v.as_f64()).collect())
+            .unwrap_or_default();
+        let [west, south, east, north, min_height, max_height] = bounds[..] else {
+            return Err(PipelineError::Other(format!(
+                "The tileset without the bounding region cannot be merged: {uri}"
+            )));
+        };
+        region = Some(match region {
+            Some(r) => [
+                r[0].min(west),
+                r[1].min(south),
+                r[2].max(east),
+                r[3].max(north),
+                r[4].min(min_height),
+                r[5].max(max_height),
+            ],
+            None => [west, south, east, north, min_height, max_height],
+        });
+        let error = root["geometricError"].as_f64().unwrap_or(0.0);
+        geometric_error = geometric_error.max(error);
+        children.push(json!({
+            "boundingVolume": root["boundingVolume"],
+            "geometricError": error,
+            "content": { "uri": uri },
+        }));
+    }
+
+    // (the other properties such as the asset are taken from the first shard)
+    let mut tileset = shards.first().map(|(_, t)| t.clone()).unwrap_or_default();
+    tileset["root"] = json!({
+        "boundingVolume": { "region": region },
+        "geometricError": geometric_error,
+        "refine": "ADD",
+        "children": children,
+    });
+    Ok(tileset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_shard(dir: &Path, region: [f64; 6], geometric_error: f64) {
+        let tileset = json!({
+            "asset": { "version": "1.1" },
+            "geometricError": 1e100,
+            "root": {
+                "boundingVolume": { "region": region },
+                "geometricError": geometric_error,
+                "content": { "uri": "0/0/0.glb" },
+            },
+        });
+        for relpath in [TILESET_FILENAME, "lod1/tileset.json"] {
+            fs::create_dir_all(dir.join(relpath).parent().unwrap()).unwrap();
+            fs::write(dir.join(relpath), tileset.to_string()).unwrap();
+        }
+        fs::create_dir_all(dir.join("0/0")).unwrap();
+        fs::write(dir.join("0/0/0.glb"), b"glb").unwrap();
+        fs::write(dir.join(MANIFEST_FILENAME), b"{}").unwrap();
+    }
+
+    #[test]
+    fn test_merge_tilesets() {
+        let dir = tempfile::tempdir().unwrap();
+        let shards = [dir.path().join("a"), dir.path().join("b")];
+        write_shard(&shards[0], [0.0, 0.0, 0.1, 0.1, 0.0, 10.0], 100.0);
+        write_shard(&shards[1], [0.1, -0.1, 0.2, 0.05, -5.0, 8.0], 200.0);
+        let output = dir.path().join("merged");
+
+        merge_tilesets(&[&shards[0], &shards[1]], &output).unwrap();
+
+        assert!(output.join("1/0/0/0.glb").is_file());
+        assert!(!output.join("0").join(MANIFEST_FILENAME).exists());
+        let tileset: Value =
+            serde_json::from_slice(&fs::read(output.join(TILESET_FILENAME)).unwrap()).unwrap();
+        assert_eq!(tileset["asset"]["version"], "1.1");
+        let root = &tileset["root"];
+        assert_eq!(
+            root["boundingVolume"]["region"],
+            json!([0.0, -0.1, 0.2, 0.1, -5.0, 10.0])
+        );
+        assert_eq!(root["geometricError"], 200.0);
+        assert_eq!(root["children"][1]["content"]["uri"], "1/tileset.json");
+
+        let tileset: Value =
+            serde_json::from_slice(&fs::read(output.join("lod1/tileset.json")).unwrap()).unwrap();
+        assert_eq!(
+            tileset["root"]["children"][0]["content"]["uri"],
+            "../0/lod1/tileset.json"
+        );
+
+        let manifest: Value =
+            serde_json::from_slice(&fs::read(output.join(MANIFEST_FILENAME)).unwrap()).unwrap();
+        assert!(manifest["files"]["1/0/0/0.glb"].is_object());
+        assert!(manifest["files"][TILESET_FILENAME].is_object());
+    }
+}
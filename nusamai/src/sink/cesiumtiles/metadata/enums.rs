@@ -0,0 +1,194 @@
+//! Enums made from the codelists
+//!
+//! The codes are stored as integers, and their labels (resolved from the codelists by the parser) are the names of
+//! the enum values. There is an enum for each codelist (e.g. `Building_usage`), so the codes of different codelists
+//! don't collide. The numeric codes (most of the PLATEAU codelists) are used as the values as they are, so they are
+//! the same in all the tiles. The other codes are numbered from -1 downward.
+
+use std::collections::HashMap;
+
+use ahash::HashSet;
+use indexmap::IndexMap;
+use nusamai_gltf_json::extensions::gltf::ext_structural_metadata::{
+    Enum, EnumValue, EnumValueType,
+};
+
+/// Value of the properties without a code
+pub const ENUM_NO_DATA: i32 = 0;
+pub const ENUM_NO_DATA_NAME: &str = "";
+
+/// Enum of the codes without a `codeSpace`
+pub const DEFAULT_ENUM_NAME: &str = "Code";
+
+#[derive(Default, Debug)]
+pub struct CodelistEnums {
+    /// enum name -> enum
+    enums: IndexMap<String, CodelistEnum>,
+}
+
+#[derive(Debug)]
+struct CodelistEnum {
+    /// code -> (value, name)
+    values: IndexMap<String, (i32, String)>,
+    used_values: HashSet<i32>,
+    used_names: HashSet<String>,
+    next_value: i32,
+}
+
+impl Default for CodelistEnum {
+    fn default() -> Self {
+        Self {
+            values: Default::default(),
+            used_values: HashSet::from_iter([ENUM_NO_DATA]),
+            used_names: HashSet::from_iter([ENUM_NO_DATA_NAME.to_string()]),
+            next_value: -1,
+        }
+    }
+}
+
+impl CodelistEnums {
+    /// Name of the enum for the codes of the `codeSpace` (the file name of the codelist without the extension)
+    pub fn enum_name(code_space: Option<&str>) -> String {
+        let Some(stem) = code_space
+            .and_then(|code_space| code_space.rsplit(['/', '\\']).next())
+            .map(|name| name.rsplit_once('.').map_or(name, |(stem, _)| stem))
+            .filter(|stem| !stem.is_empty())
+        else {
+            return DEFAULT_ENUM_NAME.to_string();
+        };
+
+        // (the names must be identifiers)
+        let mut name: String = stem
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c,
+                false => '_',
+            })
+            .collect();
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            name.insert(0, '_');
+        }
+        name
+    }
+
+    /// Returns the value of the code in the enum, adding it if it is new
+    pub fn value_of(&mut self, enum_name: &str, code: &str, label: &str) -> i32 {
+        let codelist = self.enums.entry(enum_name.to_string()).or_default();
+        if let Some((value, _)) = codelist.values.get(code) {
+            return *value;
+        }
+
+        let value = match code.parse::<i32>() {
+            Ok(value) if value > 0 && !codelist.used_values.contains(&value) => value,
+            _ => {
+                while codelist.used_values.contains(&codelist.next_value) {
+                    codelist.next_value -= 1;
+                }
+                codelist.next_value
+            }
+        };
+
+        // (the names must be unique in the enum)
+        let name = match codelist.used_names.contains(label) {
+            false => label.to_string(),
+            true => format!("{label} ({code})"),
+        };
+
+        codelist.used_values.insert(value);
+        codelist.used_names.insert(name.clone());
+        codelist.values.insert(code.to_string(), (value, name));
+        value
+    }
+
+    /// Makes sure that the enum exists (it may be referred to by a property without any code)
+    pub fn ensure(&mut self, enum_name: &str) {
+        self.enums.entry(enum_name.to_string()).or_default();
+    }
+
+    pub fn into_enums(self) -> HashMap<String, Enum> {
+        self.enums
+            .into_iter()
+            .map(|(enum_name, codelist)| {
+                let no_data = EnumValue {
+                    value: ENUM_NO_DATA,
+                    name: ENUM_NO_DATA_NAME.to_string(),
+                    ..Default::default()
+                };
+                let values = std::iter::once(no_data)
+                    .chain(
+                        codelist
+                            .values
+                            .into_values()
+                            .map(|(value, name)| EnumValue {
+                                value,
+                                name,
+                                ..Default::default()
+                            }),
+                    )
+                    .collect();
+                let enum_ = Enum {
+                    value_type: EnumValueType::Int32,
+                    values,
+                    ..Default::default()
+                };
+                (enum_name, enum_)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enum_name() {
+        assert_eq!(
+            CodelistEnums::enum_name(Some("../../codelists/Building_usage.xml")),
+            "Building_usage"
+        );
+        assert_eq!(
+            CodelistEnums::enum_name(Some("https://example.com/codelists/Common-prefecture.xml")),
+            "Common_prefecture"
+        );
+        assert_eq!(CodelistEnums::enum_name(Some("1_list.xml")), "_1_list");
+        assert_eq!(CodelistEnums::enum_name(None), "Code");
+        assert_eq!(CodelistEnums::enum_name(Some("codelists/")), "Code");
+    }
+
+    #[test]
+    fn test_value_of() {
+        let mut enums = CodelistEnums::default();
+        assert_eq!(enums.value_of("Building_usage", "411", "業務施設"), 411);
+        assert_eq!(enums.value_of("Building_usage", "421", "商業施設"), 421);
+        assert_eq!(enums.value_of("Building_usage", "411", "業務施設"), 411);
+        // the non-numeric codes, and the codes colliding with noData
+        assert_eq!(enums.value_of("Building_usage", "A1", "その他"), -1);
+        assert_eq!(enums.value_of("Building_usage", "0", "不明"), -2);
+        // the codes of another codelist are in another enum
+        assert_eq!(enums.value_of("Common_prefecture", "13", "東京都"), 13);
+        // the labels are unique
+        assert_eq!(enums.value_of("Building_usage", "999", "業務施設"), 999);
+        enums.ensure("Code");
+
+        let enums = enums.into_enums();
+        assert_eq!(enums.len(), 3);
+        let names: Vec<_> = enums["Building_usage"]
+            .values
+            .iter()
+            .map(|v| (v.value, v.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (0, ""),
+                (411, "業務施設"),
+                (421, "商業施設"),
+                (-1, "その他"),
+                (-2, "不明"),
+                (999, "業務施設 (999)"),
+            ]
+        );
+        assert_eq!(enums["Code"].values.len(), 1);
+    }
+}
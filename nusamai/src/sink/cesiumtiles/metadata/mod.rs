@@ -1,21 +1,22 @@
 //! Encode feature attributes into EXT_structural_metadata format
 
+mod enums;
+
 use std::collections::HashMap;
 
-use indexmap::{IndexMap, IndexSet};
+use enums::{CodelistEnums, DEFAULT_ENUM_NAME, ENUM_NO_DATA, ENUM_NO_DATA_NAME};
+use indexmap::IndexMap;
 use nusamai_citygml::schema::{Attribute, FeatureTypeDef, Schema, TypeDef};
 use nusamai_gltf_json::{
     extensions::gltf::ext_structural_metadata::{
-        self, ClassPropertyComponentType, ClassPropertyType, Enum, EnumValue, EnumValueType,
-        ExtStructuralMetadata, PropertyTable, PropertyTableProperty,
+        self, ClassPropertyComponentType, ClassPropertyType, ExtStructuralMetadata, PropertyTable,
+        PropertyTableProperty,
     },
     BufferView,
 };
 
 use super::utils::add_padding;
 
-const ENUM_NO_DATA: u32 = 0;
-const ENUM_NO_DATA_NAME: &str = "";
 const FLOAT_NO_DATA: f64 = f64::MAX;
 const INT64_NO_DATA: i64 = i64::MIN;
 const UINT64_NO_DATA: u64 = u64::MAX;
@@ -25,20 +26,16 @@ pub struct MetadataEncoder<'a> {
     original_schema: &'a Schema,
     /// typename -> Class
     classes: IndexMap<String, Class>,
-    // Represents Code values as enum names?
-    enum_set: IndexSet<String>,
+    /// Enums of the Code values (for each codelist)
+    enums: CodelistEnums,
 }
 
 impl<'a> MetadataEncoder<'a> {
     pub fn new(original_schema: &'a Schema) -> Self {
-        // Use the first enum value as noData
-        let mut enum_set: IndexSet<String> = Default::default();
-        enum_set.insert(ENUM_NO_DATA_NAME.to_string());
-
         Self {
             original_schema,
             classes: Default::default(),
-            enum_set,
+            enums: Default::default(),
         }
     }
 
@@ -59,50 +56,29 @@ impl<'a> MetadataEncoder<'a> {
             .entry(typename)
             .or_insert_with(|| Class::from(feature_def));
 
-        class.add_feature(attributes, &mut self.enum_set)
+        class.add_feature(attributes, &mut self.enums)
     }
 
     pub fn into_metadata(
-        self,
+        mut self,
         buffer: &mut Vec<u8>,
         buffer_views: &mut Vec<BufferView>,
     ) -> Option<ExtStructuralMetadata> {
         let (schema, property_tables) = {
-            let enums = {
-                let mut enums: HashMap<String, Enum> = HashMap::new();
-                let mut values = vec![];
-
-                for (idx, name) in self.enum_set.into_iter().enumerate() {
-                    values.push(EnumValue {
-                        value: idx as i32,
-                        name,
-                        ..Default::default()
-                    });
-                }
-
-                enums.insert(
-                    "Enum01".to_string(),
-                    Enum {
-                        value_type: EnumValueType::Uint32,
-                        values,
-                        ..Default::default()
-                    },
-                );
-                enums
-            };
-
             let (classes, property_tables) = {
                 let mut classes = HashMap::new();
                 let mut property_tables = Vec::new();
                 for (typename, cls) in self.classes {
                     let (class, property_table) =
-                        cls.make_metadata(&typename, buffer, buffer_views);
+                        cls.make_metadata(&typename, buffer, buffer_views, &mut self.enums);
                     classes.insert(typename, class);
                     property_tables.push(property_table);
                 }
                 (classes, property_tables)
             };
 
+            let enums = self.enums.into_enums();
+
             let schema = ext_structural_metadata::Schema {
                 id: "Schema".to_string(),
                 classes,
@@ -149,7 +125,7 @@ impl Class {
     fn add_feature(
         &mut self,
         attributes: &nusamai_citygml::object::Value,
-        enum_set: &mut IndexSet<String>,
+        enums: &mut CodelistEnums,
     ) -> Result<usize, ()> {
         use nusamai_citygml::object::Value;
 
@@ -158,7 +134,7 @@ impl Class {
             if let Some(id) = obj.stereotype.id() {
                 let value = Value::String(id.to_string());
                 if let Some(prop) = self.properties.get_mut("id") {
-                    encode_value(&value, prop, enum_set);
+                    encode_value(&value, prop, enums);
                     prop.used = true;
                 }
             }
@@ -168,7 +144,7 @@ impl Class {
                 let Some(prop) = self.properties.get_mut(attr_name) else {
                     continue;
                 };
-                encode_value(value, prop, enum_set);
+                encode_value(value, prop, enums);
                 prop.used = true;
             }
 
@@ -225,6 +201,7 @@ impl Class {
         class_name: &str,
         buffer: &mut Vec<u8>,
         buffer_views: &mut Vec<BufferView>,
+        enums: &mut CodelistEnums,
     ) -> (
         ext_structural_metadata::Class,
         ext_structural_metadata::PropertyTable,
@@ -258,7 +235,14 @@ impl Class {
                         //PropertyType::Boolean => None,
                     },
                     enum_type: match prop.type_ {
-                        PropertyType::Enum => Some("Enum01".to_string()),
+                        PropertyType::Enum => {
+                            let enum_name = prop
+                                .enum_name
+                                .clone()
+                                .unwrap_or_else(|| DEFAULT_ENUM_NAME.to_string());
+                            enums.ensure(&enum_name);
+                            Some(enum_name)
+                        }
                         _ => None,
                     },
                     array: prop.is_array,
@@ -360,79 +344,99 @@ impl Class {
 fn encode_value(
     value: &nusamai_citygml::object::Value,
     prop: &mut Property,
-    enum_set: &mut IndexSet<String>,
+    enums: &mut CodelistEnums,
 ) {
     use nusamai_citygml::object::Value;
 
+    if !prop.is_array {
+        encode_element(value, prop, enums);
+        return;
+    }
+
     match value {
-        Value::String(s) => {
-            prop.value_buffer.extend_from_slice(s.as_bytes());
-            prop.string_offsets.push(prop.value_buffer.len() as u32);
-            prop.count += 1;
-        }
-        Value::Uri(u) => {
-            prop.value_buffer
-                .extend_from_slice(u.value().as_str().as_bytes());
-            prop.string_offsets.push(prop.value_buffer.len() as u32);
-            prop.count += 1;
-        }
-        Value::Date(d) => {
-            prop.value_buffer
-                .extend_from_slice(d.to_string().as_bytes());
-            prop.string_offsets.push(prop.value_buffer.len() as u32);
-            prop.count += 1;
+        Value::Array(arr) => {
+            for v in arr {
+                encode_element(v, prop, enums);
+            }
         }
-        Value::Code(c) => {
-            let idx = enum_set.get_index_of(c.value()).unwrap_or_else(|| {
-                let (idx, _) = enum_set.insert_full(c.value().to_string());
-                idx
-            });
-            prop.value_buffer.extend((idx as u32).to_le_bytes());
-            prop.count += 1;
+        // a single value of a property which may occur multiple times
+        _ => encode_element(value, prop, enums),
+    }
+
+    match prop.type_ {
+        PropertyType::String => {
+            prop.array_offsets
+                .push(prop.string_offsets.len() as u32 - 1);
         }
-        Value::Integer(i) => {
-            let b: [u8; 8] = (*i).to_le_bytes(); // ensure: 8 bytes
-            prop.value_buffer.extend(b);
-            prop.count += 1;
+        // PropertyType::Boolean => todo!(), // TODO
+        _ => {
+            prop.array_offsets.push(prop.count);
         }
-        Value::NonNegativeInteger(u) => {
-            let b: [u8; 8] = (*u as i64).to_le_bytes(); // ensure: 8 bytes
-            prop.value_buffer.extend(b);
-            prop.count += 1;
+    }
+}
+
+/// Encodes a value (or an element of an array) as the type of the property.
+///
+/// The nested objects (data types and property types, e.g. `gen:genericAttribute`) and the arrays in a string property
+/// are encoded as JSON strings. The values which cannot be converted to a numeric property are encoded as noData.
+fn encode_element(
+    value: &nusamai_citygml::object::Value,
+    prop: &mut Property,
+    enums: &mut CodelistEnums,
+) {
+    use nusamai_citygml::object::Value;
+
+    match prop.type_ {
+        PropertyType::String => {
+            let text = match value {
+                Value::String(s) => s.clone(),
+                Value::Code(c) => c.value().to_string(),
+                Value::Uri(u) => u.value().to_string(),
+                Value::Date(d) => d.to_string(),
+                _ => value.to_attribute_json().to_string(),
+            };
+            prop.value_buffer.extend_from_slice(text.as_bytes());
+            prop.string_offsets.push(prop.value_buffer.len() as u32);
         }
-        Value::Double(d) => {
-            prop.value_buffer.extend((*d).to_le_bytes());
-            prop.count += 1;
+        PropertyType::Enum => {
+            let (code_space, code, label) = match value {
+                Value::Code(c) => (c.code_space(), c.code().to_string(), c.value().to_string()),
+                Value::String(s) => (None, s.clone(), s.clone()),
+                _ => {
+                    let text = value.to_attribute_json().to_string();
+                    (None, text.clone(), text)
+                }
+            };
+            // (the enum of a property is the codelist of its first code)
+            let enum_name = prop
+                .enum_name
+                .get_or_insert_with(|| CodelistEnums::enum_name(code_space))
+                .clone();
+            let enum_value = enums.value_of(&enum_name, &code, &label);
+            prop.value_buffer.extend(enum_value.to_le_bytes());
         }
-        Value::Measure(m) => {
-            let b: [u8; 8] = m.value().to_le_bytes(); // ensure: 8 bytes
+        PropertyType::Int64 | PropertyType::Uint64 => {
+            let b: [u8; 8] = match value {
+                Value::Integer(i) => (*i).to_le_bytes(),
+                Value::NonNegativeInteger(u) => (*u as i64).to_le_bytes(),
+                Value::Boolean(b) => (*b as u64).to_le_bytes(),
+                _ if prop.type_ == PropertyType::Uint64 => UINT64_NO_DATA.to_le_bytes(),
+                _ => INT64_NO_DATA.to_le_bytes(),
+            }; // ensure: 8 bytes
             prop.value_buffer.extend(b);
-            prop.count += 1;
         }
-        Value::Boolean(b) => {
-            let b: [u8; 8] = (*b as u64).to_le_bytes(); // ensure: 8 bytes
-            prop.value_buffer.extend(b);
-            prop.count += 1;
+        PropertyType::Float64 => {
+            let d = match value {
+                Value::Double(d) => *d,
+                Value::Measure(m) => m.value(),
+                Value::Integer(i) => *i as f64,
+                Value::NonNegativeInteger(u) => *u as f64,
+                _ => FLOAT_NO_DATA,
+            };
+            prop.value_buffer.extend(d.to_le_bytes());
         }
-        Value::Point(_) => todo!(), // TOOD
-        Value::Array(arr) => {
-            for v in arr {
-                encode_value(v, prop, enum_set);
-            }
-
-            match prop.type_ {
-                PropertyType::String => {
-                    prop.array_offsets
-                        .push(prop.string_offsets.len() as u32 - 1);
-                }
-                // PropertyType::Boolean => todo!(), // TODO
-                _ => {
-                    prop.array_offsets.push(prop.count);
-                }
-            }
-        } // TODO
-        Value::Object(_) => unreachable!(),
     }
+    prop.count += 1;
 }
 
 #[derive(Debug)]
@@ -445,6 +449,8 @@ struct Property {
     used: bool,
     array_offsets: Vec<u32>,
     string_offsets: Vec<u32>,
+    /// The enum of the codes (for the Enum properties)
+    enum_name: Option<String>,
 }
 
 impl Property {
@@ -465,6 +471,7 @@ impl Property {
             used: false,
             string_offsets,
             array_offsets,
+            enum_name: None,
         }
     }
 }
@@ -485,8 +492,9 @@ impl From<&Attribute> for Property {
             TypeRef::DateTime => PropertyType::String,
             TypeRef::Measure => PropertyType::Float64,
             TypeRef::Point => PropertyType::String, // TODO: VEC3<f64>
-            TypeRef::Named(_) => unreachable!(),
-            TypeRef::Unknown => unreachable!(),
+            // (nested objects are usually jsonified by the transformer)
+            TypeRef::Named(_) => PropertyType::String,
+            TypeRef::Unknown => PropertyType::String,
         };
         let is_array = attr.max_occurs != Some(1);
         Property::new(type_, is_array)
@@ -502,3 +510,104 @@ enum PropertyType {
     // Boolean,
     Enum,
 }
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::{
+        object::{Map, Object, ObjectStereotype, Value},
+        schema::TypeRef,
+    };
+
+    use super::*;
+
+    fn attribute(type_ref: TypeRef, max_occurs: Option<u16>) -> Attribute {
+        Attribute {
+            max_occurs,
+            ..Attribute::new(type_ref)
+        }
+    }
+
+    fn feature(id: &str, attributes: Vec<(&str, Value)>) -> Value {
+        Value::Object(Object {
+            typename: "bldg:Building".into(),
+            stereotype: ObjectStereotype::Feature {
+                id: id.to_string(),
+                geometries: Default::default(),
+            },
+            attributes: Map::from_iter(
+                attributes
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value)),
+            ),
+        })
+    }
+
+    #[test]
+    fn test_nested_and_mismatched_values() {
+        let mut schema = Schema::default();
+        let mut feature_def = FeatureTypeDef::default();
+        for (name, attr) in [
+            (
+                "gen:genericAttribute",
+                attribute(TypeRef::Named("gen:genericAttribute".into()), Some(1)),
+            ),
+            ("name", attribute(TypeRef::String, None)),
+            ("storeys", attribute(TypeRef::Integer, Some(1))),
+        ] {
+            feature_def.attributes.insert(name.to_string(), attr);
+        }
+        schema
+            .types
+            .insert("bldg:Building".into(), TypeDef::Feature(feature_def));
+
+        let generic = Value::Object(Object {
+            typename: "gen:genericAttribute".into(),
+            stereotype: ObjectStereotype::Data,
+            attributes: Map::from_iter([("floors".to_string(), Value::Integer(3))]),
+        });
+
+        let mut encoder = MetadataEncoder::new(&schema);
+        let features = [
+            feature(
+                "bldg_1",
+                vec![
+                    ("gen:genericAttribute", generic),
+                    // (a jsonified array)
+                    ("name", Value::String("[\"A\",\"B\"]".to_string())),
+                    ("storeys", Value::String("unknown".to_string())),
+                ],
+            ),
+            feature(
+                "bldg_2",
+                vec![(
+                    "name",
+                    Value::Array(vec![
+                        Value::String("C".to_string()),
+                        Value::String("D".to_string()),
+                    ]),
+                )],
+            ),
+        ];
+        for (i, feature) in features.iter().enumerate() {
+            assert_eq!(encoder.add_feature("bldg:Building", feature), Ok(i));
+        }
+
+        let class = &encoder.classes["bldg_Building"];
+        let generic = &class.properties["gen:genericAttribute"];
+        assert_eq!(generic.type_, PropertyType::String);
+        assert_eq!(
+            String::from_utf8(generic.value_buffer.clone()).unwrap(),
+            r#"{"floors":3,"type":"gen:genericAttribute"}"#
+        );
+
+        let name = &class.properties["name"];
+        assert_eq!(name.array_offsets, [0, 1, 3]);
+        assert_eq!(name.string_offsets.len(), 4);
+
+        let storeys = &class.properties["storeys"];
+        assert_eq!(
+            storeys.value_buffer,
+            [INT64_NO_DATA.to_le_bytes(); 2].concat()
+        );
+    }
+}
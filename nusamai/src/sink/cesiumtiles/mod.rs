@@ -1,16 +1,21 @@
 //! 3D Tiles sink
 
+mod b3dm;
 mod gltf;
+mod hlod;
 mod material;
+pub mod merge;
 pub(crate) mod metadata;
 mod slice;
+mod sort;
 mod tiling;
 pub(crate) mod utils;
 
 use std::{
+    collections::BTreeMap,
     convert::Infallible,
     fs,
-    io::BufWriter,
+    io::Write,
     path::{Path, PathBuf},
     sync::{mpsc, Arc, Mutex},
 };
@@ -26,31 +31,57 @@ use atlas_packer::{
         DownsampleFactor, PolygonMappedTexture,
     },
 };
+use b3dm::{batch_table, write_b3dm, ContentFormat};
 use bytemuck::Zeroable;
 use earcut::{utils3d::project3d_to_2d, Earcut};
+use flate2::{write::GzEncoder, Compression};
 use gltf::write_gltf_glb;
+use hlod::VertexClustering;
 use indexmap::IndexSet;
 use itertools::Itertools;
-use nusamai_citygml::{object::Value, schema::Schema};
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+};
 use nusamai_projection::cartesian::geodetic_to_geocentric;
 use rayon::prelude::*;
-use slice::{slice_to_tiles, SlicedFeature};
-use tempfile::tempdir;
+use slice::{slice_to_tiles, subdivide_feature};
+use sort::{decode_feature, encode_feature, uncompressed_size};
+use tempfile::tempdir_in;
 use tiling::{TileContent, TileTree};
-use url::Url;
+use tinymvt::TileZXY;
 
 use crate::{
     get_parameter_value,
     parameters::*,
+    paths,
     pipeline::{Feedback, PipelineError, Receiver, Result},
     sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
-    transformer::{use_lod_config, TransformerSettings},
+    transformer::{
+        building_adjacency_config, name_columns_config, prefix_config, solar_attributes_config,
+        split_bridge_and_tunnel_elements_config, surface_class_config, use_lod_config,
+        vegetation_config, TransformerSettings,
+    },
+    workdir,
 };
 use utils::calculate_normal;
 
-use super::texture_resolution::get_texture_downsample_scale_of_polygon;
+use super::texture_resolution::{
+    get_texture_downsample_scale_for_meter_per_pixel, get_texture_downsample_scale_of_polygon,
+};
 use super::{
-    option::{limit_texture_resolution_parameter, output_parameter},
+    inplace::TransformInplaceExt,
+    manifest::{hashed_path, sha256_hex, Manifest},
+    mesh_compression::{mesh_compression_parameters, MeshCompression, MeshCompressionOptions},
+    normals::{normals_parameter, NormalMode, VertexNormals},
+    option::{
+        geographic_output_epsg, geoid_parameter, limit_texture_resolution_parameter,
+        output_parameter,
+    },
+    output::remove_on_cancel,
+    texture_backend::{texture_backend_parameter, AtlasCompositor, PlacedTexture, TextureBackend},
+    texture_compression::{compress_atlas_dir, texture_compression_parameter, TextureCompression},
+    texture_report::TextureUsage,
     texture_resolution::apply_downsample_factor,
 };
 
@@ -93,6 +124,49 @@ impl DataSinkProvider for CesiumTilesSinkProvider {
                 label: Some("最大ズームレベル".into()),
             },
         });
+        params.define(ParameterDefinition {
+            key: "geometric_error_scale".into(),
+            entry: ParameterEntry {
+                description: "Scale of the geometric errors of the tiles (in percent)".into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: Some(100),
+                    min: Some(1),
+                    max: Some(10000),
+                }),
+                label: Some("ジオメトリック誤差の倍率（%）".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "max_features_per_tile".into(),
+            entry: ParameterEntry {
+                description: "Subdivide the tiles at the maximum zoom level with more features"
+                    .into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: None,
+                    min: Some(1),
+                    max: None,
+                }),
+                label: Some("タイルあたりの最大地物数".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "max_tile_bytes".into(),
+            entry: ParameterEntry {
+                description:
+                    "Subdivide the tiles at the maximum zoom level with larger content (in bytes)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: None,
+                    min: Some(1),
+                    max: None,
+                }),
+                label: Some("タイルの最大データサイズ（バイト）".into()),
+            },
+        });
+        params.define(geoid_parameter());
         params.define(limit_texture_resolution_parameter(false));
         params.define(ParameterDefinition {
             key: "gzip".into(),
@@ -103,6 +177,95 @@ impl DataSinkProvider for CesiumTilesSinkProvider {
                 label: Some("gzipで圧縮する".into()),
             },
         });
+        params.define(ParameterDefinition {
+            key: "content_format".into(),
+            entry: ParameterEntry {
+                description:
+                    "Format of the tile contents: glb (3D Tiles 1.1) or b3dm (3D Tiles 1.0)".into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter {
+                    value: Some("glb".into()),
+                }),
+                label: Some("タイルのコンテンツ形式".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "lod_tilesets".into(),
+            entry: ParameterEntry {
+                description: "Also write a separate tileset for each LOD (e.g. lod1/tileset.json)"
+                    .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("LODごとのタイルセットも出力する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "hlod".into(),
+            entry: ParameterEntry {
+                description:
+                    "Simplify the meshes and downsample the textures of the coarser tiles (HLOD)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("上位タイルのメッシュとテクスチャを簡略化する (HLOD)".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "content_hash".into(),
+            entry: ParameterEntry {
+                description: "Embed the content hash in the tile filenames (for CDN caching)"
+                    .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("タイルのファイル名に内容のハッシュ値を含める".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "tmpdir".into(),
+            entry: ParameterEntry {
+                description:
+                    "Directory of the temporary files (default: the directory of the intermediate files)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::FileSystemPath(FileSystemPathParameter {
+                    value: None,
+                    must_exist: false,
+                }),
+                label: Some("一時ファイルの出力先".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "sort_memory".into(),
+            entry: ParameterEntry {
+                description: "Memory budget of the feature sorting (in MB)".into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: None,
+                    min: Some(16),
+                    max: None,
+                }),
+                label: Some("地物の並べ替えに使うメモリ量（MB）".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "sort_threads".into(),
+            entry: ParameterEntry {
+                description: "Number of the threads of the feature sorting".into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: None,
+                    min: Some(1),
+                    max: None,
+                }),
+                label: Some("地物の並べ替えのスレッド数".into()),
+            },
+        });
+        params.define(texture_compression_parameter());
+        params.define(texture_backend_parameter());
+        for param in mesh_compression_parameters() {
+            params.define(param);
+        }
+        params.define(normals_parameter());
 
         params
     }
@@ -113,6 +276,13 @@ impl DataSinkProvider for CesiumTilesSinkProvider {
             "max_lod",
             Some(&["textured_max_lod", "all_lod"]),
         ));
+        settings.insert(vegetation_config(&["billboard"]));
+        settings.insert(split_bridge_and_tunnel_elements_config());
+        settings.insert(surface_class_config());
+        settings.insert(solar_attributes_config());
+        settings.insert(building_adjacency_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
 
         settings
     }
@@ -121,18 +291,61 @@ impl DataSinkProvider for CesiumTilesSinkProvider {
         let output_path = get_parameter_value!(params, "@output", FileSystemPath);
         let min_z = get_parameter_value!(params, "min_z", Integer).unwrap() as u8;
         let max_z = get_parameter_value!(params, "max_z", Integer).unwrap() as u8;
+        let geometric_error_scale =
+            get_parameter_value!(params, "geometric_error_scale", Integer).unwrap_or(100);
+        let tile_limits = TileLimits {
+            max_features: get_parameter_value!(params, "max_features_per_tile", Integer)
+                .map(|v| v as usize),
+            max_bytes: get_parameter_value!(params, "max_tile_bytes", Integer).map(|v| v as usize),
+        };
+        let tmpdir = get_parameter_value!(params, "tmpdir", FileSystemPath).clone();
+        let sort_options = SortOptions {
+            memory: get_parameter_value!(params, "sort_memory", Integer)
+                .map(|mb| mb as usize * 1024 * 1024),
+            threads: get_parameter_value!(params, "sort_threads", Integer).map(|v| v as usize),
+        };
+        let geoid = *get_parameter_value!(params, "geoid", Boolean);
         let limit_texture_resolution =
             *get_parameter_value!(params, "limit_texture_resolution", Boolean);
         let gzip_compress = *get_parameter_value!(params, "gzip", Boolean);
+        let content_format = get_parameter_value!(params, "content_format", String)
+            .clone()
+            .unwrap_or_default();
+        let lod_tilesets = *get_parameter_value!(params, "lod_tilesets", Boolean);
+        let hlod = *get_parameter_value!(params, "hlod", Boolean);
+        let content_hash = *get_parameter_value!(params, "content_hash", Boolean);
+        let texture_compression = get_parameter_value!(params, "texture_compression", String)
+            .clone()
+            .unwrap_or_default();
+        let texture_backend = get_parameter_value!(params, "texture_backend", String)
+            .clone()
+            .unwrap_or_default();
+        let mesh_compression = MeshCompressionOptions::from_parameters(params);
+        let normals = get_parameter_value!(params, "normals", String)
+            .clone()
+            .unwrap_or_default();
         let transform_settings = self.transformer_options();
 
         Box::<CesiumTilesSink>::new(CesiumTilesSink {
             output_path: output_path.as_ref().unwrap().into(),
             transform_settings,
+            geoid,
             limit_texture_resolution,
             gzip_compress,
+            content_format,
+            lod_tilesets,
+            hlod,
+            content_hash,
+            texture_compression,
+            texture_backend,
+            mesh_compression,
+            normals,
             min_z,
             max_z,
+            geometric_error_scale: geometric_error_scale as f64 / 100.0,
+            tile_limits,
+            tmpdir,
+            sort_options,
         })
     }
 }
@@ -140,10 +353,116 @@ impl DataSinkProvider for CesiumTilesSinkProvider {
 struct CesiumTilesSink {
     output_path: PathBuf,
     transform_settings: TransformerSettings,
+    /// Convert the heights to the ellipsoidal heights with the geoid model
+    geoid: Option<bool>,
     limit_texture_resolution: Option<bool>,
     gzip_compress: Option<bool>,
+    /// Format of the tile contents (`glb`, `b3dm`)
+    content_format: String,
+    /// Write the tilesets for each LOD in addition to the main (multi-LOD) tileset
+    lod_tilesets: Option<bool>,
+    /// Simplify the tiles above the maximum zoom level (HLOD)
+    hlod: Option<bool>,
+    /// Embed the content hash in the filenames of the tiles
+    content_hash: Option<bool>,
+    /// GPU texture compression of the atlases (`none`, `etc1s`, `uastc`)
+    texture_compression: String,
+    /// Backend of the downsampling and the compositing of the atlases (`cpu`, `gpu`)
+    texture_backend: String,
+    /// Geometry compression of the meshes (`none`, `draco`, `meshopt`) and its quantization
+    mesh_compression: MeshCompressionOptions,
+    /// Vertex normals of the meshes (`flat`, `smooth`)
+    normals: String,
     min_z: u8,
     max_z: u8,
+    /// Scale of the geometric errors written to the tilesets
+    geometric_error_scale: f64,
+    /// Limits of the tiles at the maximum zoom level, above which they are subdivided
+    tile_limits: TileLimits,
+    /// Directory of the temporary files of the sink (the directory of the intermediate files if not specified)
+    tmpdir: Option<PathBuf>,
+    sort_options: SortOptions,
+}
+
+/// Limits of the content of a tile
+#[derive(Clone, Copy, Default)]
+struct TileLimits {
+    max_features: Option<usize>,
+    /// (the size of the serialized features before the compression, without the textures)
+    max_bytes: Option<usize>,
+}
+
+impl TileLimits {
+    fn is_exceeded(&self, serialized_feats: &[Vec<u8>]) -> bool {
+        self.max_features
+            .is_some_and(|max| serialized_feats.len() > max)
+            || self.max_bytes.is_some_and(|max| {
+                serialized_feats
+                    .iter()
+                    .map(|feat| uncompressed_size(feat))
+                    .sum::<usize>()
+                    > max
+            })
+    }
+}
+
+/// Settings of the external sort of the features
+#[derive(Clone, Copy, Default)]
+struct SortOptions {
+    /// Memory budget (in bytes), shared by the chunks sorted concurrently
+    memory: Option<usize>,
+    threads: Option<usize>,
+}
+
+/// Size of a chunk of the external sort if the memory budget is not specified
+const DEFAULT_SORT_CHUNK_BYTES: usize = 256 * 1024 * 1024;
+
+/// Lower limit of the chunk size (smaller chunks make too many files to merge)
+const MIN_SORT_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+
+impl SortOptions {
+    fn threads(&self) -> usize {
+        self.threads.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |threads| threads.get())
+        })
+    }
+
+    fn config(&self) -> kv_extsort::SortConfig {
+        let threads = self.threads();
+        let max_chunk_bytes = match self.memory {
+            Some(memory) => (memory / threads).max(MIN_SORT_CHUNK_BYTES),
+            None => DEFAULT_SORT_CHUNK_BYTES,
+        };
+        kv_extsort::SortConfig::default()
+            .max_chunk_bytes(max_chunk_bytes)
+            .concurrency(threads)
+    }
+}
+
+/// The tiles are not subdivided beyond this zoom level
+const MAX_SUBDIVISION_ZOOM: u8 = 24;
+
+/// The upper limit of the atlas size (widely supported by the GPUs)
+const MAX_ATLAS_SIZE: u32 = 8192;
+
+/// Extra space of the atlas for the gaps left by the texture placer
+const ATLAS_PACKING_MARGIN: f64 = 1.25;
+
+/// The size (power of two) of the square atlas to hold the textures of the given total area
+fn atlas_size_for_area(total_area: u64) -> u32 {
+    let side = (total_area as f64 * ATLAS_PACKING_MARGIN).sqrt().ceil() as u32;
+    side.next_power_of_two().clamp(1024, MAX_ATLAS_SIZE)
+}
+
+/// Identifies the tileset that a tile belongs to: 0 is the main tileset, and `lod + 1` is the tileset of each LOD.
+type TilesetSeq = u64;
+
+/// Directory of the tileset relative to the output path
+fn tileset_dir(tileset_seq: TilesetSeq) -> Option<String> {
+    match tileset_seq {
+        0 => None,
+        seq => Some(format!("lod{}", seq - 1)),
+    }
 }
 
 impl DataSink for CesiumTilesSink {
@@ -158,7 +477,15 @@ impl DataSink for CesiumTilesSink {
             let _ = &self.transform_settings.update_transformer(config.clone());
         }
 
-        self.transform_settings.build(default_requirements)
+        let mut requirements = self.transform_settings.build(default_requirements);
+        if self.lod_tilesets.unwrap_or_default() {
+            // all the LODs are needed to write the per-LOD tilesets,
+            // and the main tileset switches the LODs according to the zoom level
+            requirements.lod_filter.mode = crate::transformer::LodFilterMode::All;
+        }
+        // The tiles are in the geocentric coordinates, so the geographic CRS is required regardless of the output CRS
+        requirements.fixed_output_epsg = Some(geographic_output_epsg(self.geoid.unwrap_or(true)));
+        requirements
     }
 
     fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
@@ -172,64 +499,108 @@ impl DataSink for CesiumTilesSink {
 
         let limit_texture_resolution = self.limit_texture_resolution;
         let gzip_compress = self.gzip_compress;
+        let content_format = ContentFormat::negotiate(&self.content_format)?;
+        let lod_tilesets = self.lod_tilesets.unwrap_or_default();
+        let hlod = self.hlod.unwrap_or_default();
+        let content_hash = self.content_hash.unwrap_or_default();
+        let texture_compression = TextureCompression::negotiate(&self.texture_compression)?;
+        let texture_backend = TextureBackend::negotiate(&self.texture_backend)?;
+        let mesh_compression = self.mesh_compression.negotiate()?;
+        let normal_mode = NormalMode::negotiate(&self.normals)?;
+        let geometric_error_scale = self.geometric_error_scale;
+        let tile_limits = self.tile_limits;
+        let sort_options = self.sort_options;
+        // The chunks of the external sort go to the directory of the intermediate files (`--tmpdir`) via `tempfile`,
+        // and this directory is for the other temporary files of the sink (e.g. the atlases before being embedded)
+        let tmpdir = self.tmpdir.clone().unwrap_or_else(workdir::work_dir);
+        fs::create_dir_all(&tmpdir)?;
+        let tmpdir = &tmpdir;
+        // The tiles whose content has been moved to their children
+        let subdivided_tiles: Mutex<Vec<(TilesetSeq, u64, String)>> = Default::default();
+        let subdivided_tiles = &subdivided_tiles;
 
         // TODO: refactoring
 
-        std::thread::scope(|s| {
-            // Slicing geometry along the tile boundaries
-            {
-                s.spawn(move || {
-                    if let Err(error) = geometry_slicing_stage(
-                        feedback,
-                        upstream,
-                        tile_id_conv,
-                        sender_sliced,
-                        min_zoom,
-                        max_zoom,
-                    ) {
-                        feedback.fatal_error(error);
-                    }
-                });
-            }
-
-            // Sort features by tile_id (using external sorter)
-            {
-                s.spawn(move || {
-                    if let Err(error) =
-                        feature_sorting_stage(feedback, receiver_sliced, sender_sorted)
-                    {
-                        feedback.fatal_error(error);
-                    }
-                });
-            }
+        // the tiles written so far are removed if canceled
+        remove_on_cancel(&self.output_path, feedback, || {
+            std::thread::scope(|s| {
+                // Slicing geometry along the tile boundaries
+                {
+                    s.spawn(move || {
+                        if let Err(error) = geometry_slicing_stage(
+                            feedback,
+                            upstream,
+                            tile_id_conv,
+                            sender_sliced,
+                            min_zoom,
+                            max_zoom,
+                            lod_tilesets,
+                        ) {
+                            feedback.fatal_error(error);
+                        }
+                    });
+                }
 
-            // Group sorted features and write them into tiles
-            {
-                let output_path = &self.output_path;
-                s.spawn(move || {
-                    // Run in a separate thread pool to avoid deadlocks
-                    let pool = rayon::ThreadPoolBuilder::new()
-                        .use_current_thread()
-                        .build()
-                        .unwrap();
-                    pool.install(|| {
-                        if let Err(error) = tile_writing_stage(
-                            output_path,
+                // Sort features by tile_id (using external sorter)
+                {
+                    s.spawn(move || {
+                        if let Err(error) = feature_sorting_stage(
                             feedback,
-                            receiver_sorted,
+                            receiver_sliced,
+                            sender_sorted,
                             tile_id_conv,
-                            schema,
-                            limit_texture_resolution,
-                            gzip_compress,
+                            max_zoom,
+                            tile_limits,
+                            sort_options,
+                            subdivided_tiles,
                         ) {
                             feedback.fatal_error(error);
                         }
-                    })
-                });
-            }
-        });
+                    });
+                }
 
-        Ok(())
+                // Group sorted features and write them into tiles
+                {
+                    let output_path = &self.output_path;
+                    s.spawn(move || {
+                        // Run in a separate thread pool to avoid deadlocks
+                        let pool = rayon::ThreadPoolBuilder::new()
+                            .use_current_thread()
+                            .build()
+                            .unwrap();
+                        pool.install(|| {
+                            if let Err(error) = tile_writing_stage(
+                                output_path,
+                                tmpdir,
+                                feedback,
+                                receiver_sorted,
+                                tile_id_conv,
+                                schema,
+                                limit_texture_resolution,
+                                gzip_compress,
+                                content_format,
+                                max_zoom,
+                                hlod,
+                                geometric_error_scale,
+                                subdivided_tiles,
+                                content_hash,
+                                texture_compression,
+                                texture_backend,
+                                mesh_compression,
+                                normal_mode,
+                            ) {
+                                feedback.fatal_error(error);
+                            }
+                        })
+                    });
+                }
+            });
+            Ok(())
+        })
+    }
+
+    fn writes_manifest(&self) -> bool {
+        true
     }
 }
 
@@ -237,34 +608,55 @@ fn geometry_slicing_stage(
     feedback: &Feedback,
     upstream: mpsc::Receiver<crate::pipeline::Parcel>,
     tile_id_conv: TileIdMethod,
-    sender_sliced: mpsc::SyncSender<(u64, String, Vec<u8>)>,
+    sender_sliced: mpsc::SyncSender<(TilesetSeq, u64, String, Vec<u8>)>,
     min_zoom: u8,
     max_zoom: u8,
+    lod_tilesets: bool,
 ) -> Result<()> {
-    let bincode_config = bincode::config::standard();
-
     // Convert CityObjects to sliced features
     upstream.into_iter().par_bridge().try_for_each(|parcel| {
         feedback.ensure_not_canceled()?;
 
-        // TODO: zoom level from parameters
-        slice_to_tiles(&parcel.entity, min_zoom, max_zoom, |(z, x, y), feature| {
-            feedback.ensure_not_canceled()?;
+        let Value::Object(obj) = &parcel.entity.root else {
+            return Ok(());
+        };
 
-            if let Value::Object(obj) = &parcel.entity.root {
-                let bytes = bincode::serde::encode_to_vec(&feature, bincode_config).unwrap();
-                let serialized_feature = (
-                    tile_id_conv.zxy_to_id(z, x, y),
-                    obj.typename.to_string(),
-                    bytes,
-                );
-                if sender_sliced.send(serialized_feature).is_err() {
-                    return Err(PipelineError::Canceled);
-                };
+        // the main tileset, and the tileset for each LOD if requested
+        let mut targets: Vec<(TilesetSeq, Option<u8>)> = vec![(0, None)];
+        if lod_tilesets {
+            if let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype {
+                let lods = geometries.iter().map(|entry| entry.lod).sorted().dedup();
+                targets.extend(lods.map(|lod| (lod as TilesetSeq + 1, Some(lod))));
             }
+        }
 
-            Ok(())
-        })
+        for (tileset_seq, lod) in targets {
+            // TODO: zoom level from parameters
+            slice_to_tiles(
+                &parcel.entity,
+                min_zoom,
+                max_zoom,
+                lod,
+                |(z, x, y), feature| {
+                    feedback.ensure_not_canceled()?;
+
+                    let bytes = encode_feature(feature);
+                    let serialized_feature = (
+                        tileset_seq,
+                        tile_id_conv.zxy_to_id(z, x, y),
+                        obj.typename.to_string(),
+                        bytes,
+                    );
+                    if sender_sliced.send(serialized_feature).is_err() {
+                        return Err(PipelineError::Canceled);
+                    };
+
+                    Ok(())
+                },
+            )?;
+        }
+
+        Ok(())
     })?;
 
     Ok(())
@@ -275,28 +667,41 @@ fn geometry_slicing_stage(
 )]
 #[repr(C)]
 struct SortKey {
+    tileset_seq: TilesetSeq,
     tile_id: u64,
     type_seq: u64,
 }
 
 fn feature_sorting_stage(
     feedback: &Feedback,
-    receiver_sliced: mpsc::Receiver<(u64, String, Vec<u8>)>,
-    sender_sorted: mpsc::SyncSender<(u64, String, Vec<Vec<u8>>)>,
+    receiver_sliced: mpsc::Receiver<(TilesetSeq, u64, String, Vec<u8>)>,
+    sender_sorted: mpsc::SyncSender<(TilesetSeq, u64, String, Vec<Vec<u8>>)>,
+    tile_id_conv: TileIdMethod,
+    max_zoom: u8,
+    tile_limits: TileLimits,
+    sort_options: SortOptions,
+    subdivided_tiles: &Mutex<Vec<(TilesetSeq, u64, String)>>,
 ) -> Result<()> {
     let mut typename_to_seq: IndexSet<String, ahash::RandomState> = Default::default();
 
-    let config = kv_extsort::SortConfig::default()
-        .max_chunk_bytes(256 * 1024 * 1024) // TODO: Configurable
+    let config = sort_options
+        .config()
         .set_cancel_flag(feedback.get_cancellation_flag());
 
     let sorted_iter = kv_extsort::sort(
         receiver_sliced
             .into_iter()
-            .map(|(tile_id, typename, body)| {
+            .map(|(tileset_seq, tile_id, typename, body)| {
                 let (idx, _) = typename_to_seq.insert_full(typename);
                 let type_seq = idx as u64;
-                std::result::Result::<_, Infallible>::Ok((SortKey { tile_id, type_seq }, body))
+                std::result::Result::<_, Infallible>::Ok((
+                    SortKey {
+                        tileset_seq,
+                        tile_id,
+                        type_seq,
+                    },
+                    body,
+                ))
             }),
         config,
     );
@@ -310,15 +715,42 @@ fn feature_sorting_stage(
             .map_ok(|(_, serialized_feats)| serialized_feats)
             .collect::<kv_extsort::Result<Vec<_>, _>>();
         match grouped {
-            Ok(serialized_feats) => {
+            Ok(mut serialized_feats) => {
                 feedback.ensure_not_canceled()?;
-                let tile_id = key.tile_id;
+                // The order of the features in a tile depends on the thread scheduling,
+                // so sort them to make the tile contents deterministic
+                serialized_feats.sort_unstable();
                 let typename = typename_to_seq[key.type_seq as usize].clone();
-                if sender_sorted
-                    .send((tile_id, typename, serialized_feats))
-                    .is_err()
-                {
-                    return Err(PipelineError::Canceled);
+
+                // The tiles at the maximum zoom level exceeding the limits are subdivided
+                let zxy = tile_id_conv.id_to_zxy(key.tile_id);
+                let mut subdivided = Vec::new();
+                let tiles = match zxy.0 == max_zoom {
+                    true => subdivide_tile(
+                        feedback,
+                        zxy,
+                        serialized_feats,
+                        tile_limits,
+                        &mut subdivided,
+                    )?,
+                    false => vec![(zxy, serialized_feats)],
+                };
+                subdivided_tiles
+                    .lock()
+                    .unwrap()
+                    .extend(subdivided.into_iter().map(|(z, x, y)| {
+                        let tile_id = tile_id_conv.zxy_to_id(z, x, y);
+                        (key.tileset_seq, tile_id, typename.clone())
+                    }));
+
+                for ((z, x, y), serialized_feats) in tiles {
+                    let tile_id = tile_id_conv.zxy_to_id(z, x, y);
+                    if sender_sorted
+                        .send((key.tileset_seq, tile_id, typename.clone(), serialized_feats))
+                        .is_err()
+                    {
+                        return Err(PipelineError::Canceled);
+                    }
                 }
             }
             Err(kv_extsort::Error::Canceled) => {
@@ -336,39 +768,102 @@ fn feature_sorting_stage(
     Ok(())
 }
 
+/// Subdivides the tile recursively while its content exceeds the limits.
+///
+/// Returns the tiles and their features (or the tile itself if it is not subdivided),
+/// and the subdivided tiles are added to `subdivided`.
+fn subdivide_tile(
+    feedback: &Feedback,
+    zxy: TileZXY,
+    serialized_feats: Vec<Vec<u8>>,
+    tile_limits: TileLimits,
+    subdivided: &mut Vec<TileZXY>,
+) -> Result<Vec<(TileZXY, Vec<Vec<u8>>)>> {
+    let (zoom, _, _) = zxy;
+    if zoom >= MAX_SUBDIVISION_ZOOM || !tile_limits.is_exceeded(&serialized_feats) {
+        return Ok(vec![(zxy, serialized_feats)]);
+    }
+    feedback.ensure_not_canceled()?;
+    subdivided.push(zxy);
+
+    let mut children: BTreeMap<TileZXY, Vec<Vec<u8>>> = BTreeMap::new();
+    for serialized_feat in serialized_feats {
+        let feature = decode_feature(&serialized_feat)?;
+        for (child_zxy, child_feature) in subdivide_feature(&feature, zoom + 1) {
+            // (the slivers along the tile boundary may fall into the neighboring tiles)
+            if tiling::calc_parent_zxy(child_zxy.0, child_zxy.1, child_zxy.2) != zxy {
+                continue;
+            }
+            let bytes = encode_feature(child_feature);
+            children.entry(child_zxy).or_default().push(bytes);
+        }
+    }
+
+    let mut tiles = Vec::new();
+    for (child_zxy, mut serialized_feats) in children {
+        serialized_feats.sort_unstable();
+        tiles.extend(subdivide_tile(
+            feedback,
+            child_zxy,
+            serialized_feats,
+            tile_limits,
+            subdivided,
+        )?);
+    }
+    Ok(tiles)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn tile_writing_stage(
     output_path: &Path,
+    tmpdir: &Path,
     feedback: &Feedback,
-    receiver_sorted: mpsc::Receiver<(u64, String, Vec<Vec<u8>>)>,
+    receiver_sorted: mpsc::Receiver<(TilesetSeq, u64, String, Vec<Vec<u8>>)>,
     tile_id_conv: TileIdMethod,
     schema: &Schema,
     limit_texture_resolution: Option<bool>,
     gzip_compress: Option<bool>,
+    content_format: ContentFormat,
+    max_zoom: u8,
+    hlod: bool,
+    geometric_error_scale: f64,
+    subdivided_tiles: &Mutex<Vec<(TilesetSeq, u64, String)>>,
+    content_hash: bool,
+    texture_compression: TextureCompression,
+    texture_backend: TextureBackend,
+    mesh_compression: MeshCompression,
+    normal_mode: NormalMode,
 ) -> Result<()> {
     let ellipsoid = nusamai_projection::ellipsoid::wgs84();
-    let contents: Arc<Mutex<Vec<TileContent>>> = Default::default();
-    let bincode_config = bincode::config::standard();
+    let contents: Arc<Mutex<BTreeMap<TilesetSeq, Vec<TileContent>>>> = Default::default();
+    let manifest = Manifest::new();
 
     // Texture cache
     // use default cache size
     let texture_cache = TextureCache::new(200_000_000);
     let texture_size_cache = TextureSizeCache::new();
+    let texture_usage = TextureUsage::new();
+    let atlas_compositor = AtlasCompositor::new(texture_backend);
 
     // Use a temporary directory for embedding in glb.
-    let binding = tempdir().unwrap();
+    let binding = tempdir_in(tmpdir)?;
     let folder_path = binding.path();
     let texture_folder_name = "textures";
     let atlas_dir = folder_path.join(texture_folder_name);
     std::fs::create_dir_all(&atlas_dir)?;
 
     // Make a glTF (.glb) file for each tile
-    receiver_sorted
-        .into_iter()
-        .par_bridge()
-        .try_for_each(|(tile_id, typename, feats)| {
+    receiver_sorted.into_iter().par_bridge().try_for_each(
+        |(tileset_seq, tile_id, typename, feats)| {
             feedback.ensure_not_canceled()?;
             let (tile_zoom, tile_x, tile_y) = tile_id_conv.id_to_zxy(tile_id);
 
+            // The content paths are relative to the tileset
+            let (tileset_path, tile_atlas_dir) = match tileset_dir(tileset_seq) {
+                Some(dir) => (output_path.join(&dir), atlas_dir.join(&dir)),
+                None => (output_path.to_path_buf(), atlas_dir.clone()),
+            };
+
             // Tile information
             let (mut content, translation) = {
                 let (min_lat, max_lat) = tiling::y_slice_range(tile_zoom, tile_y);
@@ -399,10 +894,12 @@ fn tile_writing_stage(
                 ));
                 let content_path = {
                     let normalized_typename = typename.replace(':', "_");
-                    format!("{tile_zoom}/{tile_x}/{tile_y}_{normalized_typename}.glb")
+                    let extension = content_format.extension();
+                    format!("{tile_zoom}/{tile_x}/{tile_y}_{normalized_typename}.{extension}")
                 };
                 let content = TileContent {
                     zxy: (tile_zoom, tile_x, tile_y),
+                    typename: typename.clone(),
                     content_path,
                     min_lng: f64::MAX,
                     max_lng: f64::MIN,
@@ -429,41 +926,38 @@ fn tile_writing_stage(
                     feedback.ensure_not_canceled()?;
 
                     let feature = {
-                        let (mut feature, _): (SlicedFeature, _) =
-                            bincode::serde::decode_from_slice(&serialized_feat, bincode_config)
-                                .map_err(|err| {
-                                    PipelineError::Other(format!(
-                                        "Failed to deserialize a sliced feature: {:?}",
-                                        err
-                                    ))
-                                })?;
+                        let mut feature = decode_feature(&serialized_feat)?;
+
+                        let mut to_local = |lng: f64, lat: f64, height: f64| {
+                            // Update tile boundary
+                            content.min_lng = content.min_lng.min(lng);
+                            content.max_lng = content.max_lng.max(lng);
+                            content.min_lat = content.min_lat.min(lat);
+                            content.max_lat = content.max_lat.max(lat);
+                            content.min_height = content.min_height.min(height);
+                            content.max_height = content.max_height.max(height);
+
+                            // Coordinate transformation
+                            // - geographic to geocentric
+                            // - z-up to y-up
+                            // - subtract the translation
+                            // - The origin of atlas-packer is in the lower right.
+                            let (x, y, z) = geodetic_to_geocentric(&ellipsoid, lng, lat, height);
+                            [x - translation[0], z - translation[1], -y - translation[2]]
+                        };
 
                         feature
                             .polygons
                             .transform_inplace(|&[lng, lat, height, u, v]| {
-                                // Update tile boundary
-                                content.min_lng = content.min_lng.min(lng);
-                                content.max_lng = content.max_lng.max(lng);
-                                content.min_lat = content.min_lat.min(lat);
-                                content.max_lat = content.max_lat.max(lat);
-                                content.min_height = content.min_height.min(height);
-                                content.max_height = content.max_height.max(height);
-
-                                // Coordinate transformation
-                                // - geographic to geocentric
-                                // - z-up to y-up
-                                // - subtract the translation
-                                // - The origin of atlas-packer is in the lower right.
-                                let (x, y, z) =
-                                    geodetic_to_geocentric(&ellipsoid, lng, lat, height);
-                                [
-                                    x - translation[0],
-                                    z - translation[1],
-                                    -y - translation[2],
-                                    u,
-                                    v,
-                                ]
+                                let [x, y, z] = to_local(lng, lat, height);
+                                [x, y, z, u, v]
                             });
+                        for c in feature.lines.iter_mut().flatten() {
+                            *c = to_local(c[0], c[1], c[2]);
+                        }
+                        for c in feature.points.iter_mut() {
+                            *c = to_local(c[0], c[1], c[2]);
+                        }
 
                         feature
                     };
@@ -488,6 +982,21 @@ fn tile_writing_stage(
                 })
                 .collect::<Vec<_>>();
 
+            // HLOD: the vertices of the tiles above the maximum zoom level are clustered
+            //  according to the geometric error of the tile
+            let geom_error = tiling::geometric_error(tile_zoom, tile_y);
+            let clustering = (hlod && tile_zoom < max_zoom).then(|| {
+                let mut clustering = VertexClustering::new(geom_error * hlod::CELL_SIZE_RATIO);
+                for (feature_id, feature) in features.iter().enumerate() {
+                    for poly in feature.polygons.iter() {
+                        for &[x, y, z, _, _] in poly.raw_coords() {
+                            clustering.add_vertex(feature_id as u32, [x, y, z]);
+                        }
+                    }
+                }
+                clustering
+            });
+
             // A unique ID used when planning the atlas layout
             //  and when obtaining the UV coordinates after the layout has been completed
             let generate_texture_id = |z, x, y, feature_id, poly_count| {
@@ -497,6 +1006,8 @@ fn tile_writing_stage(
             // Check the size of all the textures and calculate the power of 2 of the largest size
             let mut max_width = 0;
             let mut max_height = 0;
+            // The total area of the cropped textures, to fit them into a single atlas
+            let mut total_area: u64 = 0;
 
             // Load all textures into the Packer
             for (feature_id, feature) in features.iter().enumerate() {
@@ -523,10 +1034,11 @@ fn tile_writing_stage(
                             .map(|(_, _, _, u, v)| (*u, *v))
                             .collect::<Vec<(f64, f64)>>();
 
-                        let texture_uri = base_texture.uri.to_file_path().unwrap();
+                        let texture_uri = paths::url_to_path(&base_texture.uri).unwrap();
                         let texture_size = texture_size_cache.get_or_insert(&texture_uri);
+                        texture_usage.add_source(&typename, &texture_uri);
 
-                        let downsample_scale = if limit_texture_resolution.unwrap_or(false) {
+                        let mut downsample_scale = if limit_texture_resolution.unwrap_or(false) {
                             get_texture_downsample_scale_of_polygon(
                                 &original_vertices,
                                 texture_size,
@@ -534,8 +1046,16 @@ fn tile_writing_stage(
                         } else {
                             1.0
                         };
+                        if clustering.is_some() {
+                            // the texels of the HLOD tiles don't need to be finer than a pixel
+                            let hlod_scale = get_texture_downsample_scale_for_meter_per_pixel(
+                                &original_vertices,
+                                texture_size,
+                                geom_error * hlod::TEXEL_SIZE_RATIO,
+                            ) as f32;
+                            downsample_scale = downsample_scale.min(hlod_scale);
+                        }
 
-                        let geom_error = tiling::geometric_error(tile_zoom, tile_y);
                         let factor = apply_downsample_factor(geom_error, downsample_scale as f32);
                         let downsample_factor = DownsampleFactor::new(&factor);
                         let cropped_texture = PolygonMappedTexture::new(
@@ -551,6 +1071,19 @@ fn tile_writing_stage(
                         max_width = max_width.max(scaled_width);
                         max_height = max_height.max(scaled_height);
 
+                        // (the texture is cropped to the bounding box of the UV coordinates)
+                        let (min_u, max_u, min_v, max_v) = uv_coords.iter().fold(
+                            (1.0f64, 0.0f64, 1.0f64, 0.0f64),
+                            |(min_u, max_u, min_v, max_v), &(u, v)| {
+                                let (u, v) = (u.clamp(0.0, 1.0), v.clamp(0.0, 1.0));
+                                (min_u.min(u), max_u.max(u), min_v.min(v), max_v.max(v))
+                            },
+                        );
+                        let cropped_width = ((max_u - min_u).max(0.0) * scaled_width as f64).ceil();
+                        let cropped_height =
+                            ((max_v - min_v).max(0.0) * scaled_height as f64).ceil();
+                        total_area += (cropped_width * cropped_height) as u64;
+
                         // Unique id required for placement in atlas
                         let (z, x, y) = tile_id_conv.id_to_zxy(tile_id);
                         let texture_id = generate_texture_id(z, x, y, feature_id, poly_count);
@@ -565,12 +1098,14 @@ fn tile_writing_stage(
 
             let max_width = max_width.next_power_of_two();
             let max_height = max_height.next_power_of_two();
+            let atlas_size = atlas_size_for_area(total_area);
 
             // initialize texture packer
-            // To reduce unnecessary draw calls, set the lower limit for max_width and max_height to 1024
+            // To reduce unnecessary draw calls, the atlas is large enough to hold all the textures of the tile
+            //  (the lower limit is 1024, and the upper limit is MAX_ATLAS_SIZE unless a single texture is larger)
             let config = TexturePlacerConfig {
-                width: max_width.max(1024),
-                height: max_height.max(1024),
+                width: max_width.max(atlas_size),
+                height: max_height.max(atlas_size),
                 padding: 0,
             };
 
@@ -581,11 +1116,19 @@ fn tile_writing_stage(
             let packed = packer.pack(placer);
 
             let exporter = WebpAtlasExporter::default();
-            let ext = exporter.clone().get_extension().to_string();
+            let exported_ext = exporter.clone().get_extension().to_string();
+            let ext = texture_compression
+                .atlas_extension(&exported_ext)
+                .to_string();
+
+            // The textures drawn into the atlases by the GPU backend
+            let mut placed_textures = Vec::new();
 
             // Obtain the UV coordinates placed in the atlas by specifying the ID
             //  and apply them to the original polygon.
             for (feature_id, feature) in features.iter().enumerate() {
+                let normals = VertexNormals::new(normal_mode, &feature.polygons);
+
                 for (poly_count, (mut mat, mut poly)) in feature
                     .polygons
                     .iter()
@@ -595,61 +1138,47 @@ fn tile_writing_stage(
                     })
                     .enumerate()
                 {
-                    let original_vertices = poly
-                        .raw_coords()
-                        .iter()
-                        .map(|[x, y, z, u, v]| (*x, *y, *z, *u, *v))
-                        .collect::<Vec<(f64, f64, f64, f64, f64)>>();
-
                     let (z, x, y) = tile_id_conv.id_to_zxy(tile_id);
                     let texture_id = generate_texture_id(z, x, y, feature_id, poly_count);
 
                     if let Some(info) = packed.get_texture_info(&texture_id) {
                         // Place the texture in the atlas
-                        let atlas_placed_uv_coords = info
-                            .placed_uv_coords
-                            .iter()
-                            .map(|(u, v)| ({ *u }, { *v }))
-                            .collect::<Vec<(f64, f64)>>();
-                        let updated_vertices = original_vertices
-                            .iter()
-                            .zip(atlas_placed_uv_coords.iter())
-                            .map(|((x, y, z, _, _), (u, v))| (*x, *y, *z, *u, *v))
-                            .collect::<Vec<(f64, f64, f64, f64, f64)>>();
-
-                        // Apply the UV coordinates placed in the atlas to the original polygon
-                        poly.transform_inplace(|&[x, y, z, _, _]| {
-                            let (u, v) = updated_vertices
-                                .iter()
-                                .find(|(x_, y_, z_, _, _)| {
-                                    (*x_ - x).abs() < 1e-6
-                                        && (*y_ - y).abs() < 1e-6
-                                        && (*z_ - z).abs() < 1e-6
-                                })
-                                .map(|(_, _, _, u, v)| (*u, *v))
-                                .unwrap();
-                            [x, y, z, u, v]
-                        });
+                        // (the placed UVs are in the same order as the vertices of the polygon)
+                        debug_assert_eq!(info.placed_uv_coords.len(), poly.raw_coords().len());
+                        if let Some(texture) = &mat.base_texture {
+                            placed_textures.push(PlacedTexture {
+                                source: paths::url_to_path(&texture.uri).unwrap(),
+                                atlas_id: info.atlas_id.to_string(),
+                                uv_coords: poly.raw_coords().iter().map(|c| (c[3], c[4])).collect(),
+                                placed_uv_coords: info.placed_uv_coords.clone(),
+                            });
+                        }
+                        poly.zip_transform_inplace(
+                            &info.placed_uv_coords,
+                            |&[x, y, z, _, _], &(u, v)| [x, y, z, u, v],
+                        );
 
                         let atlas_file_name = info.atlas_id.to_string();
 
-                        let atlas_uri = atlas_dir
+                        let atlas_uri = tile_atlas_dir
                             .join(format!("{}/{}/{}/{}", z, x, y, atlas_file_name))
                             .with_extension(ext.clone());
 
                         // update material
                         mat = material::Material {
-                            base_color: mat.base_color,
                             base_texture: Some(material::Texture {
-                                uri: Url::from_file_path(atlas_uri).unwrap(),
+                                uri: paths::file_url(&atlas_uri).unwrap(),
                             }),
+                            ..mat
                         };
                     }
 
-                    let primitive = primitives.entry(mat).or_default();
-                    primitive.feature_ids.insert(feature_id as u32);
+                    let primitive = primitives
+                        .entry((mat, gltf::PrimitiveKind::Triangles))
+                        .or_default();
+                    let num_indices = primitive.indices.len();
 
-                    if let Some((nx, ny, nz)) =
+                    if let Some(polygon_normal) =
                         calculate_normal(poly.exterior().iter().map(|v| [v[0], v[1], v[2]]))
                     {
                         let num_outer_points = match poly.hole_indices().first() {
@@ -673,8 +1202,8 @@ fn tile_writing_stage(
                             );
 
                             // collect triangles
-                            primitive.indices.extend(index_buf.iter().map(|&idx| {
-                                let [x, y, z, u, v] = poly.raw_coords()[idx as usize];
+                            let mut vertex_index = |[x, y, z, u, v]: [f64; 5]| {
+                                let (nx, ny, nz) = normals.normal([x, y, z], polygon_normal);
                                 let vbits = [
                                     (x as f32).to_bits(),
                                     (y as f32).to_bits(),
@@ -689,71 +1218,233 @@ fn tile_writing_stage(
                                 ];
                                 let (index, _) = vertices.insert_full(vbits);
                                 index as u32
-                            }));
+                            };
+                            match &clustering {
+                                Some(clustering) => {
+                                    let triangles = clustering.simplify_triangles(
+                                        feature_id as u32,
+                                        poly.raw_coords(),
+                                        &index_buf,
+                                    );
+                                    primitive
+                                        .indices
+                                        .extend(triangles.into_iter().map(vertex_index));
+                                }
+                                None => {
+                                    primitive.indices.extend(
+                                        index_buf.iter().map(|&idx| {
+                                            vertex_index(poly.raw_coords()[idx as usize])
+                                        }),
+                                    );
+                                }
+                            }
+                        }
+                    }
+
+                    // (the feature IDs of the polygons without any triangles are not counted)
+                    if primitive.indices.len() > num_indices {
+                        primitive.feature_ids.insert(feature_id as u32);
+                    }
+                }
+            }
+
+            // The lines and the points are drawn with the default material
+            for (feature_id, feature) in features.iter().enumerate() {
+                let mut vertex_index = |[x, y, z]: [f64; 3]| {
+                    // (the normal is the up direction of the position)
+                    let [nx, ny, nz] = {
+                        let up = [x + translation[0], y + translation[1], z + translation[2]];
+                        let len = (up[0] * up[0] + up[1] * up[1] + up[2] * up[2]).sqrt();
+                        up.map(|c| c / len)
+                    };
+                    let vbits = [
+                        (x as f32).to_bits(),
+                        (y as f32).to_bits(),
+                        (z as f32).to_bits(),
+                        (nx as f32).to_bits(),
+                        (ny as f32).to_bits(),
+                        (nz as f32).to_bits(),
+                        0,
+                        0,
+                        (feature_id as f32).to_bits(),
+                    ];
+                    let (index, _) = vertices.insert_full(vbits);
+                    index as u32
+                };
+
+                if !feature.lines.is_empty() {
+                    let primitive = primitives
+                        .entry((material::Material::default(), gltf::PrimitiveKind::Lines))
+                        .or_default();
+                    primitive.feature_ids.insert(feature_id as u32);
+                    for line in &feature.lines {
+                        for segment in line.windows(2) {
+                            primitive.indices.push(vertex_index(segment[0]));
+                            primitive.indices.push(vertex_index(segment[1]));
                         }
                     }
                 }
+
+                if !feature.points.is_empty() {
+                    let primitive = primitives
+                        .entry((material::Material::default(), gltf::PrimitiveKind::Points))
+                        .or_default();
+                    primitive.feature_ids.insert(feature_id as u32);
+                    for &point in &feature.points {
+                        primitive.indices.push(vertex_index(point));
+                    }
+                }
             }
 
             // Write to atlas
             let (z, x, y) = tile_id_conv.id_to_zxy(tile_id);
-            let atlas_path = atlas_dir.join(format!("{}/{}/{}", z, x, y));
+            let atlas_path = tile_atlas_dir.join(format!("{}/{}/{}", z, x, y));
             fs::create_dir_all(&atlas_path)?;
-            packed.export(
-                exporter,
+            if !atlas_compositor.write_atlases(
                 &atlas_path,
-                &texture_cache,
+                &exported_ext,
+                &placed_textures,
                 config.width,
                 config.height,
-            );
-
-            // Write to file
-            let path_glb = output_path.join(Path::new(&content.content_path));
-            if let Some(dir) = path_glb.parent() {
-                fs::create_dir_all(dir)?;
+            ) {
+                packed.export(
+                    exporter,
+                    &atlas_path,
+                    &texture_cache,
+                    config.width,
+                    config.height,
+                );
             }
+            compress_atlas_dir(&atlas_path, &exported_ext, texture_compression)?;
+            texture_usage.add_atlas_dir(&atlas_path);
 
-            contents.lock().unwrap().push(content);
+            // (all the triangles of a primitive may have been removed by the simplification)
+            primitives.retain(|_, primitive| !primitive.indices.is_empty());
 
-            let mut file = std::fs::File::create(path_glb)?;
+            // The content is built in memory to compute its hash
+            let gzip_compress = gzip_compress.unwrap_or_default();
+            let mut glb = Vec::new();
             write_gltf_glb(
                 feedback,
-                &mut BufWriter::new(&mut file),
+                &mut glb,
                 translation,
                 vertices,
                 primitives,
-                features.len(),
                 metadata_encoder,
-                gzip_compress.unwrap_or_default(),
+                gzip_compress && content_format == ContentFormat::Glb,
+                mesh_compression,
+                content_format,
             )?;
+            if content_format == ContentFormat::B3dm {
+                // The whole b3dm (not the glb inside) is compressed
+                let batch_table = batch_table(features.iter().map(|feature| &feature.attributes));
+                let b3dm = write_b3dm(&glb, features.len(), &batch_table);
+                glb = match gzip_compress {
+                    true => {
+                        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                        encoder.write_all(&b3dm)?;
+                        encoder.finish()?
+                    }
+                    false => b3dm,
+                };
+            }
+
+            let hash = sha256_hex(&glb);
+            if content_hash {
+                content.content_path = hashed_path(&content.content_path, &hash);
+            }
+            // Paths in the manifest are relative to the output directory
+            let manifest_path = match tileset_dir(tileset_seq) {
+                Some(dir) => format!("{dir}/{}", content.content_path),
+                None => content.content_path.clone(),
+            };
+            manifest.insert(manifest_path, glb.len() as u64, hash);
+
+            // Write to file
+            let path_glb = tileset_path.join(Path::new(&content.content_path));
+            if let Some(dir) = path_glb.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path_glb, &glb)?;
+
+            contents
+                .lock()
+                .unwrap()
+                .entry(tileset_seq)
+                .or_default()
+                .push(content);
 
             Ok::<(), PipelineError>(())
-        })?;
+        },
+    )?;
 
     feedback.ensure_not_canceled()?;
+    texture_usage.report(feedback);
+
+    // Generate tileset.json for each tileset (the main one is always written)
+    let mut contents = std::mem::take(&mut *contents.lock().unwrap());
+    contents.entry(0).or_default();
+    for (tileset_seq, mut tileset_contents) in contents {
+        // sort the contents to make the tileset deterministic
+        tileset_contents.sort_by(|a, b| (a.zxy, &a.content_path).cmp(&(b.zxy, &b.content_path)));
+
+        // 3D Tiles 1.0 doesn't allow multiple contents in a tile, so a tree is made for each type
+        let mut trees: BTreeMap<String, TileTree> = BTreeMap::new();
+        for content in tileset_contents {
+            let key = match content_format {
+                ContentFormat::Glb => String::new(),
+                ContentFormat::B3dm => content.typename.clone(),
+            };
+            trees
+                .entry(key)
+                .or_insert_with(|| TileTree::new(geometric_error_scale))
+                .add_content(content);
+        }
+        for (_, tile_id, typename) in subdivided_tiles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(seq, _, _)| *seq == tileset_seq)
+        {
+            let key = match content_format {
+                ContentFormat::Glb => "",
+                ContentFormat::B3dm => typename.as_str(),
+            };
+            if let Some(tree) = trees.get_mut(key) {
+                tree.mark_subdivided(tile_id_conv.id_to_zxy(*tile_id));
+            }
+        }
+        let root = match content_format {
+            ContentFormat::Glb => trees
+                .pop_first()
+                .map(|(_, tree)| tree)
+                .unwrap_or_else(|| TileTree::new(geometric_error_scale))
+                .into_tileset_root(),
+            ContentFormat::B3dm => TileTree::merge_into_tileset_root(trees.into_values().collect()),
+        };
+
+        let tileset = cesiumtiles::tileset::Tileset {
+            asset: cesiumtiles::tileset::Asset {
+                version: content_format.tileset_version().to_string(),
+                ..Default::default()
+            },
+            root,
+            geometric_error: 1e+100,
+            ..Default::default()
+        };
 
-    // Generate tileset.json
-    let mut tree = TileTree::default();
-    for content in contents.lock().unwrap().drain(..) {
-        tree.add_content(content);
+        let tileset_relpath = match tileset_dir(tileset_seq) {
+            Some(dir) => format!("{dir}/tileset.json"),
+            None => "tileset.json".to_string(),
+        };
+        let tileset_path = output_path.join(&tileset_relpath);
+        fs::create_dir_all(tileset_path.parent().unwrap())?;
+        let tileset_json = serde_json::to_string_pretty(&tileset).unwrap();
+        manifest.add(&tileset_relpath, tileset_json.as_bytes());
+        fs::write(tileset_path, tileset_json)?;
     }
 
-    let tileset = cesiumtiles::tileset::Tileset {
-        asset: cesiumtiles::tileset::Asset {
-            version: "1.1".to_string(),
-            ..Default::default()
-        },
-        root: tree.into_tileset_root(),
-        geometric_error: 1e+100,
-        ..Default::default()
-    };
-
-    let root_tileset_path = output_path.join(Path::new("tileset.json"));
-    fs::create_dir_all(root_tileset_path.parent().unwrap())?;
-    fs::write(
-        root_tileset_path,
-        serde_json::to_string_pretty(&tileset).unwrap(),
-    )?;
+    manifest.write(output_path)?;
 
     Ok(())
 }
@@ -24,14 +24,24 @@ pub struct SlicedFeature {
     pub polygon_material_ids: Vec<u32>,
     // materials
     pub materials: IndexSet<Material>,
+    // line strings [x, y, z]
+    pub lines: Vec<Vec<[f64; 3]>>,
+    // points [x, y, z]
+    pub points: Vec<[f64; 3]>,
     // attribute values
     pub attributes: nusamai_citygml::object::Value,
 }
 
+/// Slices the polygons and the line strings of the entity along the tile boundaries, and puts the points
+/// into the tiles containing them.
+///
+/// If `lod` is given, only the geometries of the LOD are sliced (for the per-LOD tilesets).
+/// Otherwise, the LOD is chosen according to the geometric error of each zoom level.
 pub fn slice_to_tiles<E>(
     entity: &Entity,
     min_zoom: u8,
     max_zoom: u8,
+    lod: Option<u8>,
     send_feature: impl Fn(TileZXY, SlicedFeature) -> Result<(), E>,
 ) -> Result<(), E> {
     let ellipsoid = nusamai_projection::ellipsoid::wgs84();
@@ -52,7 +62,10 @@ pub fn slice_to_tiles<E>(
     };
 
     let geom_store = entity.geometry_store.read().unwrap();
-    if geom_store.multipolygon.is_empty() {
+    if geom_store.multipolygon.is_empty()
+        && geom_store.multilinestring.is_empty()
+        && geom_store.multipoint.is_empty()
+    {
         return Ok(());
     }
     let appearance_store = entity.appearance_store.read().unwrap();
@@ -97,7 +110,38 @@ pub fn slice_to_tiles<E>(
         .dedup()
         .collect();
 
+    let new_feature = || SlicedFeature {
+        polygons: MultiPolygon::new(),
+        attributes: entity.root.clone(),
+        polygon_material_ids: Default::default(),
+        materials: Default::default(), // set later
+        lines: Vec::new(),
+        points: Vec::new(),
+    };
+
+    // Whether to put the geometries of the LOD into the tiles of the zoom level
+    let should_slice = |zoom: u8, entry_lod: u8, check_size: bool| {
+        let geom_error = {
+            let (_, _, y) = tiling::scheme::zxy_from_lng_lat(zoom, lng_center, lat_center);
+            tiling::scheme::geometric_error(zoom, y)
+        };
+
+        // If you have multiple LODs, extract the appropriate LOD according to the geometricError.
+        // This works when the "All LOD" option is used.
+        if lod.is_none() && !should_process_entry(entry_lod, geom_error, &available_lods) {
+            return false;
+        }
+
+        // Skip the feature if the size is small for geometricError.
+        let threshold = geom_error * 0.5;
+        !(check_size && approx_dx < threshold && approx_dy < threshold && approx_dh < threshold)
+    };
+
     geometries.iter().for_each(|entry| {
+        if lod.is_some_and(|lod| entry.lod != lod) {
+            return;
+        }
+
         match entry.ty {
             GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle => {
                 // for each polygon
@@ -129,7 +173,10 @@ pub fn slice_to_tiles<E>(
                         poly_tex.and_then(|idx| appearance_store.textures.get(idx as usize));
 
                     let mat = Material {
-                        base_color: orig_mat.diffuse_color.into(),
+                        base_color: orig_mat.base_color(),
+                        metallic_roughness: orig_mat.metallic_roughness(),
+                        specular: orig_mat.specular(),
+                        emissive: orig_mat.emissive(),
                         base_texture: orig_tex.map(|tex| Texture {
                             uri: tex.image_url.clone(),
                         }),
@@ -138,41 +185,15 @@ pub fn slice_to_tiles<E>(
 
                     // Slice polygon for each zoom level
                     for zoom in min_zoom..=max_zoom {
-                        if zoom <= max_zoom {
-                            let geom_error = {
-                                let (_, _, y) =
-                                    tiling::scheme::zxy_from_lng_lat(zoom, lng_center, lat_center);
-                                tiling::scheme::geometric_error(zoom, y)
-                            };
-
-                            // If you have multiple LODs, extract the appropriate LOD according to the geometricError.
-                            // This works when the "All LOD" option is used.
-                            if !should_process_entry(entry.lod, geom_error, &available_lods) {
-                                continue;
-                            }
-
-                            // Skip the feature if the size is small for geometricError.
-                            let threshold = geom_error * 0.5;
-                            if approx_dx < threshold
-                                && approx_dy < threshold
-                                && approx_dh < threshold
-                            {
-                                continue;
-                            }
+                        if !should_slice(zoom, entry.lod, true) {
+                            continue;
                         }
 
                         if slicing_enabled {
                             // slicing enabled
                             slice_polygon(zoom, &poly, &poly_uv, |(z, x, y), poly| {
                                 let sliced_feature =
-                                    sliced_tiles.entry((z, x, y)).or_insert_with(|| {
-                                        SlicedFeature {
-                                            polygons: MultiPolygon::new(),
-                                            attributes: entity.root.clone(),
-                                            polygon_material_ids: Default::default(),
-                                            materials: Default::default(), // set later
-                                        }
-                                    });
+                                    sliced_tiles.entry((z, x, y)).or_insert_with(new_feature);
                                 sliced_feature.polygons.push(poly);
                                 sliced_feature.polygon_material_ids.push(mat_idx as u32);
                             });
@@ -180,14 +201,7 @@ pub fn slice_to_tiles<E>(
                             // slicing disabled
                             let (z, x, y) = zxy_from_lng_lat(zoom, lng_center, lat_center);
                             let sliced_feature =
-                                sliced_tiles
-                                    .entry((z, x, y))
-                                    .or_insert_with(|| SlicedFeature {
-                                        polygons: MultiPolygon::new(),
-                                        attributes: entity.root.clone(),
-                                        polygon_material_ids: Default::default(),
-                                        materials: Default::default(), // set later
-                                    });
+                                sliced_tiles.entry((z, x, y)).or_insert_with(new_feature);
                             poly.rings().zip_eq(poly_uv.rings()).enumerate().for_each(
                                 |(ri, (ring, uv_ring))| {
                                     ring.iter_closed().zip_eq(uv_ring.iter_closed()).for_each(
@@ -208,10 +222,48 @@ pub fn slice_to_tiles<E>(
                 }
             }
             GeometryType::Curve => {
-                // TODO: implement
+                for idx_line in geom_store
+                    .multilinestring
+                    .iter_range(entry.pos as usize..(entry.pos + entry.len) as usize)
+                {
+                    let line: Vec<[f64; 3]> = idx_line
+                        .iter()
+                        .map(|idx| geom_store.vertices[idx as usize])
+                        .collect();
+
+                    for zoom in min_zoom..=max_zoom {
+                        if !should_slice(zoom, entry.lod, true) {
+                            continue;
+                        }
+                        slice_line(zoom, &line, |(z, x, y), line| {
+                            sliced_tiles
+                                .entry((z, x, y))
+                                .or_insert_with(new_feature)
+                                .lines
+                                .push(line);
+                        });
+                    }
+                }
             }
             GeometryType::Point => {
-                // TODO: implement
+                for idx in geom_store
+                    .multipoint
+                    .iter_range(entry.pos as usize..(entry.pos + entry.len) as usize)
+                {
+                    let [lng, lat, height] = geom_store.vertices[idx as usize];
+                    for zoom in min_zoom..=max_zoom {
+                        // (the points have no size)
+                        if !should_slice(zoom, entry.lod, false) {
+                            continue;
+                        }
+                        let (z, x, y) = zxy_from_lng_lat(zoom, lng, lat);
+                        sliced_tiles
+                            .entry((z, x, y))
+                            .or_insert_with(new_feature)
+                            .points
+                            .push([lng, lat, height]);
+                    }
+                }
             }
         }
     });
@@ -222,8 +274,60 @@ pub fn slice_to_tiles<E>(
         send_feature((z, x, y), sliced_feature)?;
     }
     Ok(())
+}
 
-    // TODO: linestring, point
+/// Slices a sliced feature again into the tiles of a finer zoom level (to subdivide a tile).
+pub fn subdivide_feature(feature: &SlicedFeature, zoom: u8) -> HashMap<TileZXY, SlicedFeature> {
+    let mut sliced_tiles: HashMap<TileZXY, SlicedFeature> = HashMap::new();
+
+    let new_feature = || SlicedFeature {
+        polygons: MultiPolygon::new(),
+        attributes: feature.attributes.clone(),
+        polygon_material_ids: Default::default(),
+        materials: feature.materials.clone(),
+        lines: Vec::new(),
+        points: Vec::new(),
+    };
+
+    let mut poly: Polygon3 = Polygon3::new();
+    let mut poly_uv: Polygon2 = Polygon2::new();
+    for (sliced_poly, &mat_idx) in feature
+        .polygons
+        .iter()
+        .zip_eq(feature.polygon_material_ids.iter())
+    {
+        poly.clear();
+        poly_uv.clear();
+        for ring in sliced_poly.rings() {
+            poly.add_ring(ring.iter().map(|c| [c[0], c[1], c[2]]));
+            poly_uv.add_ring(ring.iter().map(|c| [c[3], c[4]]));
+        }
+        slice_polygon(zoom, &poly, &poly_uv, |(z, x, y), poly| {
+            let sliced_feature = sliced_tiles.entry((z, x, y)).or_insert_with(new_feature);
+            sliced_feature.polygons.push(poly);
+            sliced_feature.polygon_material_ids.push(mat_idx);
+        });
+    }
+
+    for line in &feature.lines {
+        slice_line(zoom, line, |(z, x, y), line| {
+            sliced_tiles
+                .entry((z, x, y))
+                .or_insert_with(new_feature)
+                .lines
+                .push(line);
+        });
+    }
+
+    for &[lng, lat, height] in &feature.points {
+        sliced_tiles
+            .entry(zxy_from_lng_lat(zoom, lng, lat))
+            .or_insert_with(new_feature)
+            .points
+            .push([lng, lat, height]);
+    }
+
+    sliced_tiles
 }
 
 /// Slice a polygon into tiles. The slicing algorithm is based on [geojson-vt](https://github.com/mapbox/geojson-vt).
@@ -404,6 +508,96 @@ fn slice_polygon(
     }
 }
 
+/// Slices a line string into tiles
+fn slice_line(zoom: u8, line: &[[f64; 3]], mut send_line: impl FnMut(TileZXY, Vec<[f64; 3]>)) {
+    if line.len() < 2 {
+        return;
+    }
+
+    // Slice along Y-axis
+    let (min_y, max_y) = line.iter().fold((f64::MAX, f64::MIN), |(min_y, max_y), c| {
+        (min_y.min(c[1]), max_y.max(c[1]))
+    });
+    for yi in tiling::iter_y_slice(zoom, min_y, max_y) {
+        let (k1, k2) = tiling::y_slice_range(zoom, yi);
+
+        // Slice along X-axis
+        for y_sliced_line in clip_line(line, 1, k1, k2) {
+            let (min_x, max_x) = y_sliced_line
+                .iter()
+                .fold((f64::MAX, f64::MIN), |(min_x, max_x), c| {
+                    (min_x.min(c[0]), max_x.max(c[0]))
+                });
+
+            for (xi, xs) in tiling::iter_x_slice(zoom, yi, min_x, max_x) {
+                let (k1, k2) = tiling::x_slice_range(zoom, xi, xs);
+                let key = (
+                    zoom,
+                    xi.rem_euclid(1 << zoom) as u32, // handling geometry crossing the antimeridian
+                    yi,
+                );
+                for sliced_line in clip_line(&y_sliced_line, 0, k1, k2) {
+                    send_line(key, sliced_line);
+                }
+            }
+        }
+    }
+}
+
+/// Clips a line string to the range `[k1, k2]` of the axis, and returns the parts inside the range
+fn clip_line(line: &[[f64; 3]], axis: usize, k1: f64, k2: f64) -> Vec<Vec<[f64; 3]>> {
+    let lerp = |a: &[f64; 3], b: &[f64; 3], t: f64| {
+        [
+            (b[0] - a[0]) * t + a[0],
+            (b[1] - a[1]) * t + a[1],
+            (b[2] - a[2]) * t + a[2],
+        ]
+    };
+
+    let mut parts = Vec::new();
+    let mut part: Vec<[f64; 3]> = Vec::new();
+    let mut finish = |part: &mut Vec<[f64; 3]>| {
+        // (a part touching the boundary at a point is dropped)
+        if part.len() >= 2 {
+            parts.push(std::mem::take(part));
+        } else {
+            part.clear();
+        }
+    };
+
+    for segment in line.windows(2) {
+        let (a, b) = (&segment[0], &segment[1]);
+
+        // the range of the segment parameter inside the range
+        let d = b[axis] - a[axis];
+        let (t0, t1) = if d == 0.0 {
+            match (k1..=k2).contains(&a[axis]) {
+                true => (0.0, 1.0),
+                false => (1.0, 0.0),
+            }
+        } else {
+            let (ta, tb) = ((k1 - a[axis]) / d, (k2 - a[axis]) / d);
+            (ta.min(tb).max(0.0), ta.max(tb).min(1.0))
+        };
+        if t0 > t1 {
+            finish(&mut part);
+            continue;
+        }
+
+        for p in [lerp(a, b, t0), lerp(a, b, t1)] {
+            if part.last() != Some(&p) {
+                part.push(p);
+            }
+        }
+        if t1 < 1.0 {
+            // the segment goes out of the range
+            finish(&mut part);
+        }
+    }
+    finish(&mut part);
+    parts
+}
+
 fn desired_lod(geom_error: f64) -> u8 {
     if geom_error >= 30.0 {
         1
@@ -433,3 +627,80 @@ fn should_process_entry(entry_lod: u8, geom_error: f64, available_lods: &HashSet
         entry_lod == selected_lod
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clip_line() {
+        // goes out of the range and comes back
+        let line = [[0.0, 0.0, 0.0], [4.0, 0.0, 4.0], [0.0, 1.0, 0.0]];
+        let parts = clip_line(&line, 0, 1.0, 2.0);
+        assert_eq!(
+            parts,
+            [
+                vec![[1.0, 0.0, 1.0], [2.0, 0.0, 2.0]],
+                vec![[2.0, 0.5, 2.0], [1.0, 0.75, 1.0]],
+            ]
+        );
+
+        // entirely inside, outside, and touching the boundary
+        assert_eq!(clip_line(&line, 1, -1.0, 2.0), [line.to_vec()]);
+        assert!(clip_line(&line, 1, 2.0, 3.0).is_empty());
+        assert!(clip_line(&line, 0, 4.0, 5.0).is_empty());
+    }
+
+    #[test]
+    fn test_slice_line() {
+        // crosses the boundary of the tiles at the longitude 135.0 (zoom 3)
+        let line = [[134.0, 35.0, 10.0], [136.0, 35.0, 20.0]];
+        let mut tiles = Vec::new();
+        slice_line(3, &line, |zxy, line| tiles.push((zxy, line)));
+        tiles.sort_by_key(|(zxy, _)| *zxy);
+
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(tiles[0].0, zxy_from_lng_lat(3, 134.0, 35.0));
+        assert_eq!(tiles[1].0, zxy_from_lng_lat(3, 136.0, 35.0));
+        assert_eq!(tiles[0].1, [[134.0, 35.0, 10.0], [135.0, 35.0, 15.0]]);
+        assert_eq!(tiles[1].1, [[135.0, 35.0, 15.0], [136.0, 35.0, 20.0]]);
+    }
+
+    #[test]
+    fn test_subdivide_feature() {
+        let mut polygons = MultiPolygon::new();
+        polygons.add_exterior([
+            [134.0, 34.5, 0.0, 0.0, 0.0],
+            [136.0, 34.5, 0.0, 1.0, 0.0],
+            [136.0, 35.5, 0.0, 1.0, 1.0],
+            [134.0, 35.5, 0.0, 0.0, 1.0],
+        ]);
+        let feature = SlicedFeature {
+            polygons,
+            polygon_material_ids: vec![0],
+            materials: IndexSet::new(),
+            lines: vec![vec![[134.0, 35.0, 10.0], [136.0, 35.0, 20.0]]],
+            points: vec![[134.5, 35.0, 0.0]],
+            attributes: Value::String("bldg".to_string()),
+        };
+
+        let tiles = subdivide_feature(&feature, 3);
+        assert_eq!(tiles.len(), 2);
+        let west = &tiles[&zxy_from_lng_lat(3, 134.5, 35.0)];
+        let east = &tiles[&zxy_from_lng_lat(3, 135.5, 35.0)];
+        for tile in [west, east] {
+            assert_eq!(tile.polygons.len(), 1);
+            assert_eq!(tile.polygon_material_ids, [0]);
+            assert_eq!(tile.lines.len(), 1);
+        }
+        assert_eq!(west.points, [[134.5, 35.0, 0.0]]);
+        assert!(east.points.is_empty());
+
+        // the texture coordinates are interpolated at the boundary
+        let poly = west.polygons.iter().next().unwrap();
+        assert!(poly
+            .raw_coords()
+            .iter()
+            .any(|c| c[0] == 135.0 && c[3] == 0.5));
+    }
+}
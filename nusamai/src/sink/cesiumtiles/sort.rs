@@ -0,0 +1,215 @@
+//! Encoding of the sliced features passed through the external sort
+//!
+//! The sort of a large dataset is dominated by writing and reading its temporary chunks, so the features are
+//! compressed with zstd. The coordinates are stored in columns (all the x values, then all the y values, ...),
+//! each value as the XOR with the previous one, and with their bytes transposed. The neighboring coordinates
+//! share the sign, the exponent and the upper bits of the mantissa, which then become long runs of zero bytes.
+
+use super::slice::SlicedFeature;
+use crate::{
+    pipeline::{PipelineError, Result},
+    sink::inplace::TransformInplaceExt,
+};
+
+/// Compression level of zstd (the chunks are short-lived, so the speed is preferred)
+const ZSTD_LEVEL: i32 = 1;
+
+/// Size of the header (the size of the feature before the compression)
+const HEADER_SIZE: usize = 4;
+
+/// Encodes the feature into a compressed byte sequence
+pub fn encode_feature(mut feature: SlicedFeature) -> Vec<u8> {
+    let line_coords: Vec<[f64; 3]> = feature.lines.iter().flatten().copied().collect();
+    let mut values = Vec::new();
+    push_columns(&mut values, feature.polygons.coords());
+    push_columns(&mut values, &line_coords);
+    push_columns(&mut values, &feature.points);
+
+    // The coordinates are left in the structure as zeros (which are compressed away)
+    feature.polygons.map_inplace(|_| [0.0; 5]);
+    feature
+        .lines
+        .iter_mut()
+        .flatten()
+        .for_each(|c| *c = [0.0; 3]);
+    feature.points.iter_mut().for_each(|c| *c = [0.0; 3]);
+
+    let mut buf = bincode::serde::encode_to_vec(&feature, bincode::config::standard()).unwrap();
+    write_columns(&values, &mut buf);
+
+    let mut encoded = Vec::with_capacity(HEADER_SIZE + buf.len() / 2);
+    encoded.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+    encoded.extend(zstd::bulk::compress(&buf, ZSTD_LEVEL).unwrap());
+    encoded
+}
+
+/// Decodes the feature encoded by [`encode_feature`]
+pub fn decode_feature(encoded: &[u8]) -> Result<SlicedFeature> {
+    let error =
+        |msg: String| PipelineError::Other(format!("Failed to decode a sliced feature: {msg}"));
+
+    if encoded.len() < HEADER_SIZE {
+        return Err(error("truncated".to_string()));
+    }
+    let buf = zstd::bulk::decompress(&encoded[HEADER_SIZE..], uncompressed_size(encoded))?;
+    let (mut feature, read): (SlicedFeature, _) =
+        bincode::serde::decode_from_slice(&buf, bincode::config::standard())
+            .map_err(|err| error(format!("{:?}", err)))?;
+
+    let num_polygon_coords = feature.polygons.coords().len();
+    let num_line_coords = feature.lines.iter().map(|line| line.len()).sum::<usize>();
+    let num_points = feature.points.len();
+    let values = read_columns(
+        &buf[read..],
+        num_polygon_coords * 5 + (num_line_coords + num_points) * 3,
+    )
+    .ok_or_else(|| error("the number of the coordinates doesn't match".to_string()))?;
+
+    let (polygon_values, rest) = values.split_at(num_polygon_coords * 5);
+    let (line_values, point_values) = rest.split_at(num_line_coords * 3);
+    feature
+        .polygons
+        .zip_transform_inplace(0..num_polygon_coords, |_, i| {
+            std::array::from_fn(|k| polygon_values[k * num_polygon_coords + i])
+        });
+    for (i, c) in feature.lines.iter_mut().flatten().enumerate() {
+        *c = std::array::from_fn(|k| line_values[k * num_line_coords + i]);
+    }
+    for (i, c) in feature.points.iter_mut().enumerate() {
+        *c = std::array::from_fn(|k| point_values[k * num_points + i]);
+    }
+
+    Ok(feature)
+}
+
+/// Size of the encoded feature before the compression
+pub fn uncompressed_size(encoded: &[u8]) -> usize {
+    match encoded.first_chunk::<HEADER_SIZE>() {
+        Some(header) => u32::from_le_bytes(*header) as usize,
+        None => 0,
+    }
+}
+
+fn push_columns<const N: usize>(values: &mut Vec<f64>, coords: &[[f64; N]]) {
+    for k in 0..N {
+        values.extend(coords.iter().map(|c| c[k]));
+    }
+}
+
+/// Writes the values as the XOR with the previous values, with the bytes transposed
+fn write_columns(values: &[f64], buf: &mut Vec<u8>) {
+    let mut prev = 0;
+    let xored: Vec<u64> = values
+        .iter()
+        .map(|v| {
+            let bits = v.to_bits();
+            let xored = bits ^ prev;
+            prev = bits;
+            xored
+        })
+        .collect();
+    for byte in 0..8 {
+        buf.extend(xored.iter().map(|x| (x >> (byte * 8)) as u8));
+    }
+}
+
+fn read_columns(bytes: &[u8], len: usize) -> Option<Vec<f64>> {
+    if bytes.len() != len * 8 {
+        return None;
+    }
+    let mut prev = 0;
+    let values = (0..len)
+        .map(|i| {
+            let xored = (0..8).fold(0u64, |acc, byte| {
+                acc | (bytes[byte * len + i] as u64) << (byte * 8)
+            });
+            prev ^= xored;
+            f64::from_bits(prev)
+        })
+        .collect();
+    Some(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use flatgeom::MultiPolygon;
+    use nusamai_citygml::object::Value;
+
+    use super::*;
+
+    fn feature() -> SlicedFeature {
+        let mut polygons = MultiPolygon::new();
+        polygons.add_exterior([
+            [139.7, 35.6, 10.0, 0.0, 0.0],
+            [139.8, 35.6, 10.5, 1.0, 0.0],
+            [139.8, 35.7, 11.0, 1.0, 1.0],
+            [139.7, 35.7, 11.5, 0.0, 1.0],
+        ]);
+        polygons.add_interior([
+            [139.72, 35.62, 10.0, 0.2, 0.2],
+            [139.74, 35.62, 10.0, 0.4, 0.2],
+            [139.74, 35.64, 10.0, 0.4, 0.4],
+        ]);
+        polygons.add_exterior([
+            [-1.0, -2.0, -3.0, 0.5, 0.5],
+            [f64::MAX, f64::MIN_POSITIVE, 0.0, 0.25, 0.75],
+            [0.1, 0.2, 0.3, 0.0, 0.0],
+        ]);
+        SlicedFeature {
+            polygons,
+            polygon_material_ids: vec![0, 1],
+            materials: Default::default(),
+            lines: vec![
+                vec![[139.7, 35.6, 1.0], [139.71, 35.61, 2.0]],
+                vec![],
+                vec![[139.9, 35.9, 3.0]],
+            ],
+            points: vec![[139.5, 35.5, 0.0], [139.6, 35.4, -1.0]],
+            attributes: Value::String("bldg_1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let original = feature();
+        let encoded = encode_feature(feature());
+        let decoded = decode_feature(&encoded).unwrap();
+
+        assert_eq!(decoded.polygons.coords(), original.polygons.coords());
+        assert_eq!(
+            decoded
+                .polygons
+                .iter()
+                .map(|poly| poly.interiors().count())
+                .collect::<Vec<_>>(),
+            [1, 0]
+        );
+        assert_eq!(decoded.polygon_material_ids, original.polygon_material_ids);
+        assert_eq!(decoded.lines, original.lines);
+        assert_eq!(decoded.points, original.points);
+        assert_eq!(decoded.attributes, original.attributes);
+
+        assert!(decode_feature(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut feature = feature();
+        feature.polygons = MultiPolygon::new();
+        let coords: Vec<[f64; 5]> = (0..1000)
+            .map(|i| {
+                let t = i as f64 / 1000.0;
+                [139.7 + t * 0.001, 35.6 + t * 0.001, 10.0 + t, t, 1.0 - t]
+            })
+            .collect();
+        feature.polygons.add_exterior(coords);
+
+        let serialized =
+            bincode::serde::encode_to_vec(&feature, bincode::config::standard()).unwrap();
+        let encoded = encode_feature(feature);
+        assert!(uncompressed_size(&encoded) >= serialized.len());
+        assert!(encoded.len() < serialized.len() / 2);
+        // (smaller than compressing the row-oriented serialization)
+        assert!(encoded.len() < zstd::bulk::compress(&serialized, ZSTD_LEVEL).unwrap().len());
+    }
+}
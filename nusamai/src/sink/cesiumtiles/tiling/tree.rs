@@ -8,6 +8,7 @@ use super::scheme::{calc_parent_zxy, geometric_error};
 #[derive(Debug)]
 pub struct TileContent {
     pub zxy: TileZXY,
+    pub typename: String,
     pub content_path: String,
     pub min_lng: f64,
     pub max_lng: f64,
@@ -21,6 +22,7 @@ impl Default for TileContent {
     fn default() -> Self {
         TileContent {
             zxy: (0, u32::MAX, u32::MAX),
+            typename: String::new(),
             content_path: String::new(),
             min_lng: f64::MAX,
             max_lng: f64::MIN,
@@ -36,6 +38,8 @@ impl Default for TileContent {
 pub struct Tile {
     zxy: TileZXY,
     contents: Vec<TileContent>,
+    /// The content was too large and has been moved to the children
+    subdivided: bool,
     child00: Option<Box<Tile>>,
     child01: Option<Box<Tile>>,
     child10: Option<Box<Tile>>,
@@ -57,6 +61,7 @@ impl Default for Tile {
             child10: None,
             child11: None,
             contents: vec![],
+            subdivided: false,
             min_lng: f64::MAX,
             max_lng: f64::MIN,
             min_lat: f64::MAX,
@@ -96,14 +101,22 @@ impl Tile {
         }
     }
 
-    fn into_tileset_tile(mut self) -> tileset::Tile {
+    fn into_tileset_tile(mut self, error_scale: f64, parent_error: f64) -> tileset::Tile {
         self.update_boundary();
 
+        let (z, _, y) = self.zxy;
+        // A subdivided tile has no content to show, so it is refined together with its parent
+        // (unless the contents of the other types remain in it)
+        let geometric_error = match self.subdivided && self.contents.is_empty() {
+            true => parent_error,
+            false => geometric_error(z, y) * error_scale,
+        };
+
         let children = {
             let children: Vec<_> = [self.child00, self.child01, self.child10, self.child11]
                 .into_iter()
                 .flatten()
-                .map(|child| child.into_tileset_tile())
+                .map(|child| child.into_tileset_tile(error_scale, geometric_error))
                 .collect();
             if children.is_empty() {
                 None
@@ -136,9 +149,8 @@ impl Tile {
             }
         };
 
-        let (z, _, y) = self.zxy;
         tileset::Tile {
-            geometric_error: geometric_error(z, y),
+            geometric_error,
             refine: Some(tileset::Refine::Replace),
             bounding_volume: tileset::BoundingVolume::new_region([
                 self.min_lng.to_radians(),
@@ -166,6 +178,8 @@ impl Tile {
 #[derive(Debug)]
 pub struct TileTree {
     root: Tile,
+    /// Scale of the geometric errors of the tiles
+    error_scale: f64,
 }
 
 impl Default for TileTree {
@@ -175,13 +189,23 @@ impl Default for TileTree {
                 zxy: (0, 0, 0),
                 ..Default::default()
             },
+            error_scale: 1.0,
         }
     }
 }
 
 impl TileTree {
+    pub fn new(error_scale: f64) -> Self {
+        Self {
+            error_scale,
+            ..Default::default()
+        }
+    }
+
     pub fn into_tileset_root(self) -> tileset::Tile {
-        self.root.into_tileset_tile()
+        let (z, _, y) = self.root.zxy;
+        let root_error = geometric_error(z, y) * self.error_scale;
+        self.root.into_tileset_tile(self.error_scale, root_error)
     }
 
     pub fn add_content(&mut self, content: TileContent) {
@@ -189,6 +213,54 @@ impl TileTree {
         node.contents.push(content);
     }
 
+    /// Makes the root tile containing the trees (for 3D Tiles 1.0, which doesn't allow multiple contents in a tile).
+    ///
+    /// Each tree is a child of the root, which is refined by addition.
+    pub fn merge_into_tileset_root(trees: Vec<TileTree>) -> tileset::Tile {
+        let mut root = Tile {
+            zxy: (0, 0, 0),
+            ..Default::default()
+        };
+        let children: Vec<_> = trees
+            .into_iter()
+            .map(|mut tree| {
+                tree.root.update_boundary();
+                root.min_lng = root.min_lng.min(tree.root.min_lng);
+                root.max_lng = root.max_lng.max(tree.root.max_lng);
+                root.min_lat = root.min_lat.min(tree.root.min_lat);
+                root.max_lat = root.max_lat.max(tree.root.max_lat);
+                root.min_height = root.min_height.min(tree.root.min_height);
+                root.max_height = root.max_height.max(tree.root.max_height);
+                tree.into_tileset_root()
+            })
+            .collect();
+
+        let geometric_error = children
+            .iter()
+            .map(|child| child.geometric_error)
+            .fold(0.0, f64::max);
+
+        tileset::Tile {
+            geometric_error,
+            refine: Some(tileset::Refine::Add),
+            bounding_volume: tileset::BoundingVolume::new_region([
+                root.min_lng.to_radians(),
+                root.min_lat.to_radians(),
+                root.max_lng.to_radians(),
+                root.max_lat.to_radians(),
+                root.min_height,
+                root.max_height,
+            ]),
+            children: (!children.is_empty()).then_some(children),
+            ..Default::default()
+        }
+    }
+
+    /// Marks the tile whose content has been subdivided into its children
+    pub fn mark_subdivided(&mut self, zxy: TileZXY) {
+        self.get_node(zxy).subdivided = true;
+    }
+
     fn get_node(&mut self, zxy: TileZXY) -> &mut Tile {
         let (zoom, x, y) = zxy;
         if zoom == 0 {
@@ -0,0 +1,385 @@
+//! CityGML sink
+//!
+//! Writes the entities back into a CityGML 2.0 file (a single `core:CityModel`), keeping the `gml:id`s,
+//! the geometries and the thematic and generic attributes, so that nusamai can be used to extract a subset
+//! of the city models (e.g. only the LOD2 buildings within a bounding box).
+//!
+//! The coordinates are written in JGD2011 (EPSG:6697, latitude, longitude and height).
+//! The appearances (textures and colors) are not written, and the `gml:id`s of the polygons are not kept.
+
+mod writer;
+
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, Write},
+    path::PathBuf,
+};
+
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+};
+use nusamai_projection::crs::EPSG_JGD2011_GEOGRAPHIC_3D;
+use rayon::prelude::*;
+use writer::{city_model_footer, city_model_header, expand_extent, MemberWriter};
+
+use super::option::output_parameter;
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer::{self, LodFilterMode, LodMask, PrefixPolicy, TransformerSettings},
+};
+
+pub struct CityGmlSinkProvider {}
+
+impl DataSinkProvider for CityGmlSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "citygml".to_string(),
+            name: "CityGML".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "lods".into(),
+            entry: ParameterEntry {
+                description:
+                    "LODs to write, separated by commas (e.g. `2` or `1,2`). All LODs if empty"
+                        .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter {
+                    value: Some(String::new()),
+                }),
+                label: Some("出力するLOD（カンマ区切り、空欄ですべて）".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "bbox".into(),
+            entry: ParameterEntry {
+                description: "Write only the features intersecting the bounding box `min_lng,min_lat,max_lng,max_lat`. All features if empty".into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter {
+                    value: Some(String::new()),
+                }),
+                label: Some("出力する範囲（最小経度,最小緯度,最大経度,最大緯度、空欄で全域）".into()),
+            },
+        });
+
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        TransformerSettings::new()
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let lods = get_parameter_value!(params, "lods", String)
+            .clone()
+            .unwrap_or_default();
+        let bbox = get_parameter_value!(params, "bbox", String)
+            .clone()
+            .unwrap_or_default();
+        let transform_settings = self.transformer_options();
+
+        Box::<CityGmlSink>::new(CityGmlSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            transform_settings,
+            lods,
+            bbox,
+        })
+    }
+}
+
+pub struct CityGmlSink {
+    output_path: PathBuf,
+    transform_settings: TransformerSettings,
+    /// LODs to write (e.g. `1,2`)
+    lods: String,
+    /// Bounding box of the features to write (`min_lng,min_lat,max_lng,max_lat`)
+    bbox: String,
+}
+
+impl DataSink for CityGmlSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        let default_requirements = DataRequirements {
+            output_epsg: EPSG_JGD2011_GEOGRAPHIC_3D,
+            // keep the attribute tree and the child features as they are
+            key_value: transformer::KeyValueSpec::None,
+            mergedown: transformer::MergedownSpec::NoMergedown,
+            lod_filter: transformer::LodFilterSpec {
+                mode: LodFilterMode::All,
+                ..Default::default()
+            },
+            prefix: PrefixPolicy::Keep,
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
+        let lods = parse_lods(&self.lods)?;
+        let bbox = parse_bbox(&self.bbox)?;
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+
+        let (ra, rb) = rayon::join(
+            || {
+                upstream
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(sender, |sender, parcel| {
+                        feedback.ensure_not_canceled()?;
+
+                        let mut entity = parcel.entity;
+                        if let Some(lods) = &lods {
+                            if !retain_lods(&mut entity.root, lods) {
+                                return Ok(());
+                            }
+                        }
+                        let Value::Object(obj) = &entity.root else {
+                            return Ok(());
+                        };
+                        if !matches!(obj.stereotype, ObjectStereotype::Feature { .. }) {
+                            return Ok(());
+                        }
+
+                        let geom_store = entity.geometry_store.read().unwrap();
+                        let mut writer = MemberWriter::new(&geom_store);
+                        writer.write_member(obj);
+                        let (xml, extent) = writer.finish();
+
+                        if let Some(bbox) = &bbox {
+                            if !extent.is_some_and(|extent| intersects(bbox, &extent)) {
+                                return Ok(());
+                            }
+                        }
+                        if sender.send((xml, extent)).is_err() {
+                            return Err(PipelineError::Canceled);
+                        }
+                        Ok(())
+                    })
+            },
+            || {
+                // The envelope of the city model is written before the members,
+                // so the members are written into a temporary file first.
+                let mut members = BufWriter::new(tempfile::tempfile()?);
+                let mut extent: Option<[[f64; 3]; 2]> = None;
+                let mut count = 0;
+                for (i, (xml, member_extent)) in receiver.into_iter().enumerate() {
+                    if i % 1000 == 0 {
+                        feedback.ensure_not_canceled()?;
+                    }
+                    members.write_all(xml.as_bytes())?;
+                    if let Some([min, max]) = member_extent {
+                        expand_extent(&mut extent, min);
+                        expand_extent(&mut extent, max);
+                    }
+                    count += 1;
+                }
+                let mut members = members.into_inner().map_err(|err| err.into_error())?;
+                members.rewind()?;
+
+                let mut writer =
+                    BufWriter::with_capacity(1024 * 1024, File::create(&self.output_path)?);
+                let srs_name = format!(
+                    "http://www.opengis.net/def/crs/EPSG/0/{}",
+                    EPSG_JGD2011_GEOGRAPHIC_3D
+                );
+                writer.write_all(city_model_header(extent, &srs_name).as_bytes())?;
+                std::io::copy(&mut members, &mut writer)?;
+                writer.write_all(city_model_footer().as_bytes())?;
+                writer.flush()?;
+
+                feedback.info(format!("Wrote {count} city objects"));
+                Ok::<(), PipelineError>(())
+            },
+        );
+
+        match ra {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => return Err(error),
+        }
+        match rb {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => return Err(error),
+        }
+        Ok(())
+    }
+}
+
+/// Parses the LODs separated by commas (e.g. `1,2`). Returns `None` if empty.
+fn parse_lods(s: &str) -> Result<Option<LodMask>> {
+    if s.trim().is_empty() {
+        return Ok(None);
+    }
+    let mut mask = LodMask::default();
+    for lod in s.split(',') {
+        match lod.trim().parse::<u8>() {
+            Ok(lod @ 0..=4) => mask.add_lod(lod),
+            _ => {
+                return Err(PipelineError::Other(format!(
+                    "Invalid LOD: '{}' (expected 0 to 4)",
+                    lod.trim()
+                )))
+            }
+        }
+    }
+    Ok(Some(mask))
+}
+
+/// Parses the bounding box `min_lng,min_lat,max_lng,max_lat`. Returns `None` if empty.
+fn parse_bbox(s: &str) -> Result<Option<[f64; 4]>> {
+    if s.trim().is_empty() {
+        return Ok(None);
+    }
+    let values: Vec<f64> = s
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|_| PipelineError::Other(format!("Invalid bounding box: '{s}'")))?;
+    match values[..] {
+        [min_lng, min_lat, max_lng, max_lat] if min_lng <= max_lng && min_lat <= max_lat => {
+            Ok(Some([min_lng, min_lat, max_lng, max_lat]))
+        }
+        _ => Err(PipelineError::Other(format!(
+            "Invalid bounding box: '{s}' (expected min_lng,min_lat,max_lng,max_lat)"
+        ))),
+    }
+}
+
+fn intersects(bbox: &[f64; 4], [min, max]: &[[f64; 3]; 2]) -> bool {
+    let [min_lng, min_lat, max_lng, max_lat] = *bbox;
+    min[0] <= max_lng && max[0] >= min_lng && min[1] <= max_lat && max[1] >= min_lat
+}
+
+/// Removes the geometries of the other LODs, and the child features without any geometries left.
+///
+/// Returns whether the value has the geometries of the LODs.
+fn retain_lods(value: &mut Value, lods: &LodMask) -> bool {
+    match value {
+        Value::Object(obj) => {
+            let mut has_geometries = false;
+            if let ObjectStereotype::Feature { geometries, .. } = &mut obj.stereotype {
+                geometries.retain(|geom| lods.has_lod(geom.lod));
+                has_geometries = !geometries.is_empty();
+            }
+            obj.attributes.retain(|_, value| {
+                let retained = retain_lods(value, lods);
+                has_geometries |= retained;
+                // the data types (e.g. `uro:BuildingDetailAttribute`) are kept
+                retained || !is_feature(value)
+            });
+            has_geometries
+        }
+        Value::Array(arr) => {
+            let mut has_geometries = false;
+            arr.retain_mut(|value| {
+                let retained = retain_lods(value, lods);
+                has_geometries |= retained;
+                retained || !is_feature(value)
+            });
+            has_geometries
+        }
+        _ => false,
+    }
+}
+
+fn is_feature(value: &Value) -> bool {
+    matches!(value, Value::Object(obj) if matches!(obj.stereotype, ObjectStereotype::Feature { .. }))
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::{
+        object::{Map, Object},
+        GeometryRef, GeometryType,
+    };
+
+    use super::*;
+
+    fn feature(typename: &str, lods: &[u8], attributes: Map) -> Value {
+        Value::Object(Object {
+            typename: typename.to_string().into(),
+            stereotype: ObjectStereotype::Feature {
+                id: format!("{typename}_1"),
+                geometries: lods
+                    .iter()
+                    .map(|&lod| GeometryRef {
+                        ty: GeometryType::Surface,
+                        lod,
+                        pos: 0,
+                        len: 1,
+                    })
+                    .collect(),
+            },
+            attributes,
+        })
+    }
+
+    #[test]
+    fn test_retain_lods() {
+        let wall = feature("bldg:WallSurface", &[2], Map::default());
+        let mut attributes = Map::default();
+        attributes.insert("bldg:boundedBy".into(), Value::Array(vec![wall]));
+        attributes.insert("bldg:measuredHeight".into(), Value::Double(10.0));
+        let building = feature("bldg:Building", &[0, 1], attributes);
+
+        let lods = parse_lods("2").unwrap().unwrap();
+        let mut value = building.clone();
+        assert!(retain_lods(&mut value, &lods));
+        let Value::Object(obj) = &value else {
+            unreachable!()
+        };
+        let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype else {
+            unreachable!()
+        };
+        assert!(geometries.is_empty());
+        assert_eq!(obj.attributes.len(), 2);
+
+        // the boundary surfaces are removed, and the building has no geometries of LOD3
+        let lods = parse_lods("1,3").unwrap().unwrap();
+        let mut value = building.clone();
+        assert!(retain_lods(&mut value, &lods));
+        let lods = parse_lods("3").unwrap().unwrap();
+        let mut value = building;
+        assert!(!retain_lods(&mut value, &lods));
+        let Value::Object(obj) = &value else {
+            unreachable!()
+        };
+        assert_eq!(obj.attributes["bldg:boundedBy"], Value::Array(Vec::new()));
+        assert_eq!(obj.attributes["bldg:measuredHeight"], Value::Double(10.0));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert!(parse_lods("").unwrap().is_none());
+        assert!(parse_lods("5").is_err());
+        assert!(parse_lods("a").is_err());
+        let lods = parse_lods(" 0, 2").unwrap().unwrap();
+        assert!(lods.has_lod(0) && !lods.has_lod(1) && lods.has_lod(2));
+
+        assert!(parse_bbox("").unwrap().is_none());
+        assert!(parse_bbox("139.7,35.6,139.8").is_err());
+        assert!(parse_bbox("139.8,35.6,139.7,35.7").is_err());
+        let bbox = parse_bbox("139.7, 35.6, 139.8, 35.7").unwrap().unwrap();
+        assert!(intersects(
+            &bbox,
+            &[[139.75, 35.65, 0.0], [139.9, 35.9, 10.0]]
+        ));
+        assert!(!intersects(
+            &bbox,
+            &[[139.85, 35.65, 0.0], [139.9, 35.9, 10.0]]
+        ));
+    }
+}
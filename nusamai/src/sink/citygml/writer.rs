@@ -0,0 +1,675 @@
+//! Serialization of the entities into CityGML 2.0
+
+use flatgeom::LineString;
+use nusamai_citygml::{
+    geometry::GeometryStore,
+    object::{Map, Object, ObjectStereotype, Value},
+    GeometryRef, GeometryType,
+};
+use quick_xml::escape::escape;
+
+/// Namespaces of CityGML 2.0 and i-UR 3.1 (PLATEAU)
+pub const NAMESPACES: &[(&str, &str)] = &[
+    ("core", "http://www.opengis.net/citygml/2.0"),
+    ("gml", "http://www.opengis.net/gml"),
+    ("xlink", "http://www.w3.org/1999/xlink"),
+    ("xsi", "http://www.w3.org/2001/XMLSchema-instance"),
+    ("app", "http://www.opengis.net/citygml/appearance/2.0"),
+    ("bldg", "http://www.opengis.net/citygml/building/2.0"),
+    ("brid", "http://www.opengis.net/citygml/bridge/2.0"),
+    ("dem", "http://www.opengis.net/citygml/relief/2.0"),
+    ("frn", "http://www.opengis.net/citygml/cityfurniture/2.0"),
+    ("gen", "http://www.opengis.net/citygml/generics/2.0"),
+    ("grp", "http://www.opengis.net/citygml/cityobjectgroup/2.0"),
+    ("luse", "http://www.opengis.net/citygml/landuse/2.0"),
+    ("tran", "http://www.opengis.net/citygml/transportation/2.0"),
+    ("tun", "http://www.opengis.net/citygml/tunnel/2.0"),
+    ("veg", "http://www.opengis.net/citygml/vegetation/2.0"),
+    ("wtr", "http://www.opengis.net/citygml/waterbody/2.0"),
+    ("uro", "https://www.geospatial.jp/iur/uro/3.1"),
+    ("urf", "https://www.geospatial.jp/iur/urf/3.1"),
+];
+
+/// The unit of measure written for the `gml:MeasureType` values (the parser does not keep the original `uom`)
+const DEFAULT_UOM: &str = "m";
+
+/// Simple indenting XML writer into a `String`
+#[derive(Default)]
+pub struct XmlWriter {
+    buf: String,
+    depth: usize,
+}
+
+impl XmlWriter {
+    pub fn with_depth(depth: usize) -> Self {
+        Self {
+            buf: String::new(),
+            depth,
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        self.buf
+    }
+
+    fn indent(&mut self) {
+        for _ in 0..self.depth {
+            self.buf.push_str("  ");
+        }
+    }
+
+    fn open_tag(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.indent();
+        self.buf.push('<');
+        self.buf.push_str(name);
+        for (key, value) in attrs {
+            self.buf.push(' ');
+            self.buf.push_str(key);
+            self.buf.push_str("=\"");
+            self.buf.push_str(&escape(*value));
+            self.buf.push('"');
+        }
+    }
+
+    pub fn start(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.open_tag(name, attrs);
+        self.buf.push_str(">\n");
+        self.depth += 1;
+    }
+
+    pub fn end(&mut self, name: &str) {
+        self.depth -= 1;
+        self.indent();
+        self.buf.push_str("</");
+        self.buf.push_str(name);
+        self.buf.push_str(">\n");
+    }
+
+    pub fn empty(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.open_tag(name, attrs);
+        self.buf.push_str("/>\n");
+    }
+
+    pub fn text(&mut self, name: &str, attrs: &[(&str, &str)], text: &str) {
+        self.open_tag(name, attrs);
+        self.buf.push('>');
+        self.buf.push_str(&escape(text));
+        self.buf.push_str("</");
+        self.buf.push_str(name);
+        self.buf.push_str(">\n");
+    }
+}
+
+/// The XML elements of the geometries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GeometryElement {
+    Solid,
+    MultiSurface,
+    CompositeSurface,
+    TriangulatedSurface,
+    MultiCurve,
+    GeometricComplex,
+    Point,
+}
+
+/// Prefix of the geometry properties (e.g. `bldg:lod1Solid`) of the feature type.
+/// Some of the i-UR features use the properties of the CityGML modules they extend.
+fn geometry_prefix(typename: &str) -> &str {
+    match typename {
+        "uro:UndergroundBuilding" => "bldg",
+        "uro:Waterway" => "tran",
+        "uro:Appurtenance"
+        | "uro:Manhole"
+        | "uro:Handhole"
+        | "uro:Pipe"
+        | "uro:WaterPipe"
+        | "uro:SewerPipe"
+        | "uro:OilGasChemicalsPipe"
+        | "uro:ThermalPipe"
+        | "uro:Cable"
+        | "uro:ElectricityCable"
+        | "uro:TelecommunicationsCable"
+        | "uro:Duct" => "frn",
+        _ => typename
+            .split_once(':')
+            .map_or("core", |(prefix, _)| prefix),
+    }
+}
+
+/// The property name (e.g. `bldg:lod2Solid`) and the geometry element for the geometry of the feature type
+fn geometry_property(typename: &str, ty: GeometryType, lod: u8) -> (String, GeometryElement) {
+    let prefix = geometry_prefix(typename);
+    let (local, element) = match (prefix, ty, lod) {
+        (_, GeometryType::Solid, _) => (format!("lod{lod}Solid"), GeometryElement::Solid),
+        // PLATEAU uses the roof edges for LOD0 buildings
+        ("bldg", GeometryType::Surface, 0) => {
+            ("lod0RoofEdge".to_string(), GeometryElement::MultiSurface)
+        }
+        ("wtr", GeometryType::Surface, 2..) if typename != "wtr:WaterBody" => (
+            format!("lod{lod}Surface"),
+            GeometryElement::CompositeSurface,
+        ),
+        ("dem", GeometryType::Triangle, _) => {
+            ("tin".to_string(), GeometryElement::TriangulatedSurface)
+        }
+        (_, GeometryType::Surface | GeometryType::Triangle, _) => (
+            format!("lod{lod}MultiSurface"),
+            GeometryElement::MultiSurface,
+        ),
+        ("tran", GeometryType::Curve, 0) => {
+            ("lod0Network".to_string(), GeometryElement::GeometricComplex)
+        }
+        ("uro", GeometryType::Curve, _) => {
+            (format!("lod{lod}Network"), GeometryElement::MultiCurve)
+        }
+        (_, GeometryType::Curve, _) => (format!("lod{lod}MultiCurve"), GeometryElement::MultiCurve),
+        (_, GeometryType::Point, _) => (format!("lod{lod}Point"), GeometryElement::Point),
+    };
+    (format!("{prefix}:{local}"), element)
+}
+
+/// Writes the city objects as `core:cityObjectMember`s, collecting the extent of the written coordinates
+pub struct MemberWriter<'a> {
+    xml: XmlWriter,
+    geom_store: &'a GeometryStore,
+    /// [min, max] of the written vertices ([longitude, latitude, height])
+    extent: Option<[[f64; 3]; 2]>,
+}
+
+impl<'a> MemberWriter<'a> {
+    pub fn new(geom_store: &'a GeometryStore) -> Self {
+        Self {
+            xml: XmlWriter::with_depth(1),
+            geom_store,
+            extent: None,
+        }
+    }
+
+    pub fn finish(self) -> (String, Option<[[f64; 3]; 2]>) {
+        (self.xml.into_string(), self.extent)
+    }
+
+    pub fn write_member(&mut self, obj: &Object) {
+        self.xml.start("core:cityObjectMember", &[]);
+        self.write_object(obj);
+        self.xml.end("core:cityObjectMember");
+    }
+
+    fn write_object(&mut self, obj: &Object) {
+        let typename = obj.typename.as_ref();
+        match obj.stereotype.id() {
+            Some(id) if !id.is_empty() => self.xml.start(typename, &[("gml:id", id)]),
+            _ => self.xml.start(typename, &[]),
+        }
+
+        // Roughly follow the order of the CityGML schema:
+        // the GML and core properties, the thematic attributes, the geometries,
+        // the child features (e.g. `bldg:boundedBy`), and the ADE (i-UR) properties.
+        let own_prefix = typename.split_once(':').map_or("", |(prefix, _)| prefix);
+        let is_child_object = |value: &Value| {
+            match value {
+            Value::Object(obj) => !matches!(obj.stereotype, ObjectStereotype::Data),
+            Value::Array(arr) => arr.iter().any(|v| {
+                matches!(v, Value::Object(obj) if !matches!(obj.stereotype, ObjectStereotype::Data))
+            }),
+            _ => false,
+        }
+        };
+
+        for (name, value) in &obj.attributes {
+            if matches!(module_of(name), Some("gml" | "core" | "gen")) {
+                self.write_property(name, value);
+            }
+        }
+        for (name, value) in &obj.attributes {
+            if module_of(name) == Some(own_prefix) && !is_child_object(value) {
+                self.write_property(name, value);
+            }
+        }
+        if let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype {
+            let mut geometries: Vec<&GeometryRef> = geometries.iter().collect();
+            geometries.sort_by_key(|geom| geom.lod);
+            for geom in geometries {
+                self.write_geometry(typename, geom);
+            }
+        }
+        for (name, value) in &obj.attributes {
+            if module_of(name) == Some(own_prefix) && is_child_object(value) {
+                self.write_property(name, value);
+            }
+        }
+        for (name, value) in &obj.attributes {
+            // the names without a prefix are added by the transforms, and they are not CityGML properties
+            match module_of(name) {
+                None | Some("gml" | "core" | "gen") => {}
+                Some(prefix) if prefix == own_prefix => {}
+                Some(_) => self.write_property(name, value),
+            }
+        }
+
+        self.xml.end(typename);
+    }
+
+    fn write_property(&mut self, name: &str, value: &Value) {
+        match value {
+            Value::Array(arr) => {
+                for v in arr {
+                    self.write_property(name, v);
+                }
+            }
+            Value::Object(obj) if obj.typename == "gen:genericAttribute" => {
+                self.write_generic_attributes(&obj.attributes);
+            }
+            Value::Object(obj) if is_reference(obj) => {
+                // e.g. <grp:groupMember xlink:href="#bldg_..." role="..."/>
+                let attrs: Vec<(&str, &str)> = obj
+                    .attributes
+                    .iter()
+                    .filter_map(|(key, value)| match value {
+                        Value::String(s) if key == "href" => Some(("xlink:href", s.as_str())),
+                        Value::String(s) if !key.contains(':') => Some((key.as_str(), s.as_str())),
+                        _ => None,
+                    })
+                    .collect();
+                self.xml.empty(name, &attrs);
+            }
+            Value::Object(obj) => {
+                self.xml.start(name, &[]);
+                self.write_object(obj);
+                self.xml.end(name);
+            }
+            Value::Code(code) => match code.code_space() {
+                Some(code_space) => self
+                    .xml
+                    .text(name, &[("codeSpace", code_space)], code.code()),
+                None => self.xml.text(name, &[], code.code()),
+            },
+            Value::Measure(m) => {
+                self.xml
+                    .text(name, &[("uom", DEFAULT_UOM)], &m.value().to_string())
+            }
+            Value::Point(p) => {
+                self.xml.start(name, &[]);
+                self.xml.start("gml:Point", &[("srsDimension", "3")]);
+                let [x, y, z] = p.coordinates();
+                self.xml.text("gml:pos", &[], &format!("{x} {y} {z}"));
+                self.xml.end("gml:Point");
+                self.xml.end(name);
+            }
+            _ => {
+                if let Some(text) = scalar_text(value) {
+                    self.xml.text(name, &[], &text);
+                }
+            }
+        }
+    }
+
+    /// `gen:stringAttribute`, `gen:intAttribute`, ... and `gen:genericAttributeSet`
+    fn write_generic_attributes(&mut self, attributes: &Map) {
+        for (name, value) in attributes {
+            let attrs = [("name", name.as_str())];
+            let (element, value_attrs, text) = match value {
+                Value::Object(obj) => {
+                    self.xml.start("gen:genericAttributeSet", &attrs);
+                    self.write_generic_attributes(&obj.attributes);
+                    self.xml.end("gen:genericAttributeSet");
+                    continue;
+                }
+                Value::Integer(_) | Value::NonNegativeInteger(_) => {
+                    ("gen:intAttribute", None, scalar_text(value))
+                }
+                Value::Double(_) => ("gen:doubleAttribute", None, scalar_text(value)),
+                Value::Measure(m) => (
+                    "gen:measureAttribute",
+                    Some(("uom", DEFAULT_UOM)),
+                    Some(m.value().to_string()),
+                ),
+                Value::Date(_) => ("gen:dateAttribute", None, scalar_text(value)),
+                Value::Uri(_) => ("gen:uriAttribute", None, scalar_text(value)),
+                // CityGML 2.0 has no code attribute, so the code is written as a string
+                _ => ("gen:stringAttribute", None, scalar_text(value)),
+            };
+            let Some(text) = text else {
+                continue;
+            };
+            self.xml.start(element, &attrs);
+            match value_attrs {
+                Some(attr) => self.xml.text("gen:value", &[attr], &text),
+                None => self.xml.text("gen:value", &[], &text),
+            }
+            self.xml.end(element);
+        }
+    }
+
+    fn write_geometry(&mut self, typename: &str, geom: &GeometryRef) {
+        if geom.len == 0 {
+            return;
+        }
+        let (property, element) = geometry_property(typename, geom.ty, geom.lod);
+        let range = geom.pos as usize..(geom.pos + geom.len) as usize;
+        let geom_store = self.geom_store;
+
+        match element {
+            GeometryElement::Point => {
+                // one property for each point
+                for idx in geom_store.multipoint.iter_range(range) {
+                    self.xml.start(&property, &[]);
+                    self.xml.start("gml:Point", &[]);
+                    let pos = self.pos_list(std::iter::once(idx));
+                    self.xml.text("gml:pos", &[], &pos);
+                    self.xml.end("gml:Point");
+                    self.xml.end(&property);
+                }
+            }
+            GeometryElement::MultiCurve | GeometryElement::GeometricComplex => {
+                let (container, member) = match element {
+                    GeometryElement::MultiCurve => ("gml:MultiCurve", "gml:curveMember"),
+                    _ => ("gml:GeometricComplex", "gml:element"),
+                };
+                self.xml.start(&property, &[]);
+                self.xml.start(container, &[]);
+                for line in geom_store.multilinestring.iter_range(range) {
+                    self.xml.start(member, &[]);
+                    self.xml.start("gml:LineString", &[]);
+                    let pos = self.pos_list(line.raw_coords().iter().copied());
+                    self.xml.text("gml:posList", &[], &pos);
+                    self.xml.end("gml:LineString");
+                    self.xml.end(member);
+                }
+                self.xml.end(container);
+                self.xml.end(&property);
+            }
+            _ => {
+                self.xml.start(&property, &[]);
+                let (member, patch) = match element {
+                    GeometryElement::Solid => {
+                        self.xml.start("gml:Solid", &[]);
+                        self.xml.start("gml:exterior", &[]);
+                        self.xml.start("gml:CompositeSurface", &[]);
+                        ("gml:surfaceMember", "gml:Polygon")
+                    }
+                    GeometryElement::CompositeSurface => {
+                        self.xml.start("gml:CompositeSurface", &[]);
+                        ("gml:surfaceMember", "gml:Polygon")
+                    }
+                    GeometryElement::TriangulatedSurface => {
+                        self.xml.start("gml:TriangulatedSurface", &[]);
+                        self.xml.start("gml:trianglePatches", &[]);
+                        ("", "gml:Triangle")
+                    }
+                    _ => {
+                        self.xml.start("gml:MultiSurface", &[]);
+                        ("gml:surfaceMember", "gml:Polygon")
+                    }
+                };
+                for poly in geom_store.multipolygon.iter_range(range) {
+                    if !member.is_empty() {
+                        self.xml.start(member, &[]);
+                    }
+                    self.xml.start(patch, &[]);
+                    self.write_ring("gml:exterior", &poly.exterior());
+                    for interior in poly.interiors() {
+                        self.write_ring("gml:interior", &interior);
+                    }
+                    self.xml.end(patch);
+                    if !member.is_empty() {
+                        self.xml.end(member);
+                    }
+                }
+                match element {
+                    GeometryElement::Solid => {
+                        self.xml.end("gml:CompositeSurface");
+                        self.xml.end("gml:exterior");
+                        self.xml.end("gml:Solid");
+                    }
+                    GeometryElement::CompositeSurface => self.xml.end("gml:CompositeSurface"),
+                    GeometryElement::TriangulatedSurface => {
+                        self.xml.end("gml:trianglePatches");
+                        self.xml.end("gml:TriangulatedSurface");
+                    }
+                    _ => self.xml.end("gml:MultiSurface"),
+                }
+                self.xml.end(&property);
+            }
+        }
+    }
+
+    fn write_ring(&mut self, name: &str, ring: &LineString<'_, u32>) {
+        let indices = ring.raw_coords();
+        let Some(&first) = indices.first() else {
+            return;
+        };
+        // the linear rings of GML are closed explicitly
+        let closing = (indices.last() != Some(&first)).then_some(first);
+        let pos = self.pos_list(indices.iter().copied().chain(closing));
+        self.xml.start(name, &[]);
+        self.xml.start("gml:LinearRing", &[]);
+        self.xml.text("gml:posList", &[], &pos);
+        self.xml.end("gml:LinearRing");
+        self.xml.end(name);
+    }
+
+    /// Coordinates in the axis order of EPSG:6697 (latitude, longitude, height)
+    fn pos_list(&mut self, indices: impl Iterator<Item = u32>) -> String {
+        let mut pos = String::new();
+        for idx in indices {
+            let v = self.geom_store.vertices[idx as usize];
+            expand_extent(&mut self.extent, v);
+            if !pos.is_empty() {
+                pos.push(' ');
+            }
+            let [lng, lat, height] = v;
+            pos.push_str(&format!("{lat} {lng} {height}"));
+        }
+        pos
+    }
+}
+
+/// Namespace prefix of the property name (`None` for the names added by the transforms)
+fn module_of(name: &str) -> Option<&str> {
+    name.split_once(':').map(|(prefix, _)| prefix)
+}
+
+/// References to the other objects (e.g. `grp:_CityObjectOrRef`) only have the XML attributes
+fn is_reference(obj: &Object) -> bool {
+    obj.typename
+        .split_once(':')
+        .is_some_and(|(_, local)| local.starts_with('_'))
+}
+
+fn scalar_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Code(c) => Some(c.code().to_string()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::NonNegativeInteger(i) => Some(i.to_string()),
+        Value::Double(d) => Some(d.to_string()),
+        Value::Measure(m) => Some(m.value().to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        Value::Uri(u) => Some(u.value().to_string()),
+        Value::Date(d) => Some(d.to_string()),
+        Value::Point(_) | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+pub fn expand_extent(extent: &mut Option<[[f64; 3]; 2]>, v: [f64; 3]) {
+    match extent {
+        Some([min, max]) => {
+            for i in 0..3 {
+                min[i] = min[i].min(v[i]);
+                max[i] = max[i].max(v[i]);
+            }
+        }
+        None => *extent = Some([v, v]),
+    }
+}
+
+/// Writes the start of the `core:CityModel` with its envelope
+pub fn city_model_header(extent: Option<[[f64; 3]; 2]>, srs_name: &str) -> String {
+    let mut xml = XmlWriter::default();
+    xml.buf
+        .push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let xmlns: Vec<(String, &str)> = NAMESPACES
+        .iter()
+        .map(|(prefix, uri)| (format!("xmlns:{prefix}"), *uri))
+        .collect();
+    let attrs: Vec<(&str, &str)> = xmlns.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+    xml.start("core:CityModel", &attrs);
+    if let Some([min, max]) = extent {
+        xml.start("gml:boundedBy", &[]);
+        xml.start(
+            "gml:Envelope",
+            &[("srsName", srs_name), ("srsDimension", "3")],
+        );
+        xml.text(
+            "gml:lowerCorner",
+            &[],
+            &format!("{} {} {}", min[1], min[0], min[2]),
+        );
+        xml.text(
+            "gml:upperCorner",
+            &[],
+            &format!("{} {} {}", max[1], max[0], max[2]),
+        );
+        xml.end("gml:Envelope");
+        xml.end("gml:boundedBy");
+    }
+    xml.into_string()
+}
+
+pub fn city_model_footer() -> &'static str {
+    "</core:CityModel>\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::{object::Object, Code, Measure};
+
+    use super::*;
+
+    #[test]
+    fn test_geometry_property() {
+        assert_eq!(
+            geometry_property("bldg:Building", GeometryType::Solid, 1),
+            ("bldg:lod1Solid".to_string(), GeometryElement::Solid)
+        );
+        assert_eq!(
+            geometry_property("bldg:WallSurface", GeometryType::Surface, 2),
+            (
+                "bldg:lod2MultiSurface".to_string(),
+                GeometryElement::MultiSurface
+            )
+        );
+        assert_eq!(
+            geometry_property("uro:Pipe", GeometryType::Solid, 3),
+            ("frn:lod3Solid".to_string(), GeometryElement::Solid)
+        );
+        assert_eq!(
+            geometry_property("dem:TINRelief", GeometryType::Triangle, 1),
+            ("dem:tin".to_string(), GeometryElement::TriangulatedSurface)
+        );
+        assert_eq!(
+            geometry_property("tran:Road", GeometryType::Curve, 0),
+            (
+                "tran:lod0Network".to_string(),
+                GeometryElement::GeometricComplex
+            )
+        );
+    }
+
+    #[test]
+    fn test_write_member() {
+        let mut geom_store = GeometryStore::default();
+        geom_store.vertices = vec![
+            [139.0, 35.0, 10.0],
+            [139.001, 35.0, 10.0],
+            [139.001, 35.001, 12.0],
+        ];
+        geom_store.multipolygon.add_exterior([0, 1, 2]);
+
+        let mut generics = Map::default();
+        generics.insert("名称".into(), Value::String("A&B".into()));
+        generics.insert("延床面積".into(), Value::Measure(Measure::new(120.5)));
+
+        let mut attributes = Map::default();
+        attributes.insert(
+            "uro:buildingIDAttribute".into(),
+            Value::Object(Object {
+                typename: "uro:BuildingIDAttribute".into(),
+                stereotype: ObjectStereotype::Data,
+                attributes: Map::from_iter([(
+                    "uro:buildingID".to_string(),
+                    Value::String("13101-bldg-1".into()),
+                )]),
+            }),
+        );
+        attributes.insert(
+            "bldg:usage".into(),
+            Value::Code(
+                Code::new("業務施設".into(), "401".into())
+                    .with_code_space(Some("../../codelists/Building_usage.xml".into())),
+            ),
+        );
+        attributes.insert(
+            "bldg:measuredHeight".into(),
+            Value::Measure(Measure::new(12.0)),
+        );
+        attributes.insert(
+            "gen:genericAttribute".into(),
+            Value::Object(Object {
+                typename: "gen:genericAttribute".into(),
+                stereotype: ObjectStereotype::Data,
+                attributes: generics,
+            }),
+        );
+        // added by a transform
+        attributes.insert("groupIds".into(), Value::String("grp_1".into()));
+
+        let building = Object {
+            typename: "bldg:Building".into(),
+            stereotype: ObjectStereotype::Feature {
+                id: "bldg_1".into(),
+                geometries: vec![GeometryRef {
+                    ty: GeometryType::Solid,
+                    lod: 1,
+                    pos: 0,
+                    len: 1,
+                }],
+            },
+            attributes,
+        };
+
+        let mut writer = MemberWriter::new(&geom_store);
+        writer.write_member(&building);
+        let (xml, extent) = writer.finish();
+
+        assert_eq!(extent, Some([[139.0, 35.0, 10.0], [139.001, 35.001, 12.0]]));
+        assert!(xml.contains(r#"<bldg:Building gml:id="bldg_1">"#));
+        assert!(xml.contains(
+            r#"<bldg:usage codeSpace="../../codelists/Building_usage.xml">401</bldg:usage>"#
+        ));
+        assert!(xml.contains(r#"<gen:value>A&amp;B</gen:value>"#));
+        assert!(xml.contains(r#"<gen:measureAttribute name="延床面積">"#));
+        assert!(xml.contains(
+            "<gml:posList>35 139 10 35 139.001 10 35.001 139.001 12 35 139 10</gml:posList>"
+        ));
+        assert!(!xml.contains("groupIds"));
+
+        // the order of the schema: generic attributes, thematic attributes, geometries and ADE
+        let position = |s: &str| xml.find(s).unwrap();
+        assert!(position("gen:stringAttribute") < position("bldg:usage"));
+        assert!(position("bldg:measuredHeight") < position("bldg:lod1Solid"));
+        assert!(position("bldg:lod1Solid") < position("uro:buildingIDAttribute"));
+    }
+
+    #[test]
+    fn test_city_model_header() {
+        let header = city_model_header(
+            Some([[139.0, 35.0, 0.0], [140.0, 36.0, 10.0]]),
+            "http://www.opengis.net/def/crs/EPSG/0/6697",
+        );
+        assert!(header.contains(r#"xmlns:bldg="http://www.opengis.net/citygml/building/2.0""#));
+        assert!(header.contains("<gml:lowerCorner>35 139 0</gml:lowerCorner>"));
+        assert!(header.contains("<gml:upperCorner>36 140 10</gml:upperCorner>"));
+    }
+}
@@ -0,0 +1,594 @@
+//! CityJSON sink
+//!
+//! Writes a single CityJSON file, or a CityJSON Text Sequence (CityJSONSeq) with one `CityJSONFeature` per line.
+//! In both cases the features are written as soon as they arrive, so the whole dataset is never held in memory.
+
+use std::{
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use hashbrown::HashMap;
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+    GeometryType,
+};
+use nusamai_plateau::Entity;
+use nusamai_projection::crs::{
+    EpsgCode, EPSG_JGD2011_GEOGRAPHIC_2D, EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_WGS84_GEOGRAPHIC_2D,
+    EPSG_WGS84_GEOGRAPHIC_3D,
+};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::json;
+
+use super::{
+    option::output_parameter,
+    output::{compression_parameter, Compression, OutputWriter},
+};
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer,
+    transformer::{prefix_config, underground_config, use_lod_config, TransformerSettings},
+};
+
+const CITYJSON_VERSION: &str = "2.0";
+
+pub struct CityJsonSinkProvider {}
+
+impl DataSinkProvider for CityJsonSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "cityjson".to_string(),
+            name: "CityJSON".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "seq".into(),
+            entry: ParameterEntry {
+                description: "Write newline-delimited CityJSONFeatures (CityJSONSeq) instead of a single CityJSON object".into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("1行1地物の形式（CityJSONSeq）で出力する".into()),
+            },
+        });
+        params.define(compression_parameter());
+
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(use_lod_config("max_lod", Some(&["all_lod"])));
+        settings.insert(underground_config());
+        settings.insert(prefix_config(&[]));
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let transform_settings = self.transformer_options();
+        let seq = get_parameter_value!(params, "seq", Boolean).unwrap();
+        let compression = get_parameter_value!(params, "compression", String)
+            .clone()
+            .unwrap_or_default();
+
+        Box::<CityJsonSink>::new(CityJsonSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            transform_settings,
+            seq,
+            compression,
+        })
+    }
+}
+
+pub struct CityJsonSink {
+    output_path: PathBuf,
+    transform_settings: TransformerSettings,
+    /// Write one `CityJSONFeature` per line (CityJSONSeq) instead of a single CityJSON object
+    seq: bool,
+    /// Compression option (`auto` follows the extension of the output path)
+    compression: String,
+}
+
+impl DataSink for CityJsonSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        let default_requirements = DataRequirements {
+            // CityJSON can hold nested attributes as they are
+            key_value: transformer::KeyValueSpec::None,
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        let epsg = schema.epsg.unwrap_or(EPSG_WGS84_GEOGRAPHIC_3D);
+        let scale = transform_scale(epsg);
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+        let seq = self.seq;
+        let compression = Compression::negotiate(&self.compression, &self.output_path)?;
+
+        let (ra, rb) = rayon::join(
+            || {
+                // Convert the entities into CityJSON features
+                upstream
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(sender, |sender, parcel| {
+                        feedback.ensure_not_canceled()?;
+
+                        let Some(feature) = entity_to_feature(&parcel.entity, scale) else {
+                            return Ok(());
+                        };
+                        if sender.send(feature).is_err() {
+                            return Err(PipelineError::Canceled);
+                        };
+                        Ok(())
+                    })
+            },
+            || {
+                let header = Header { epsg, scale };
+                let mut writer = OutputWriter::create(&self.output_path, compression)?;
+
+                if seq {
+                    write_city_json_seq(&mut writer, &header, receiver, feedback)?;
+                } else {
+                    write_city_json(&mut writer, &header, receiver, feedback)?;
+                }
+                writer.finish()?;
+                Ok(())
+            },
+        );
+
+        match ra {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+        match rb {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+
+        Ok(())
+    }
+}
+
+/// Resolution of the integer vertex coordinates: 1e-7 degrees (about 1 cm) or 1 mm
+fn transform_scale(epsg: EpsgCode) -> [f64; 3] {
+    match epsg {
+        EPSG_WGS84_GEOGRAPHIC_2D
+        | EPSG_WGS84_GEOGRAPHIC_3D
+        | EPSG_JGD2011_GEOGRAPHIC_2D
+        | EPSG_JGD2011_GEOGRAPHIC_3D => [1e-7, 1e-7, 0.001],
+        _ => [0.001, 0.001, 0.001],
+    }
+}
+
+/// The common properties of the CityJSON object and the first line of CityJSONSeq
+struct Header {
+    epsg: EpsgCode,
+    scale: [f64; 3],
+}
+
+impl Header {
+    fn to_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        let serde_json::Value::Object(map) = json!({
+            "type": "CityJSON",
+            "version": CITYJSON_VERSION,
+            // the translation is not needed since the coordinates are small enough for i64
+            "transform": {
+                "scale": self.scale,
+                "translate": [0.0, 0.0, 0.0],
+            },
+            "metadata": {
+                "referenceSystem": format!("https://www.opengis.net/def/crs/EPSG/0/{}", self.epsg),
+            },
+        }) else {
+            unreachable!()
+        };
+        map
+    }
+}
+
+/// A city object with its own vertices (the indices in the boundaries are local to the feature)
+struct Feature {
+    id: String,
+    object: CityObject,
+    vertices: Vec<[i64; 3]>,
+}
+
+#[derive(Serialize)]
+struct CityObject {
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    attributes: serde_json::Map<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    geometry: Vec<Geometry>,
+}
+
+#[derive(Serialize)]
+struct Geometry {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    lod: String,
+    boundaries: Boundaries,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Boundaries {
+    MultiPoint(Vec<u64>),
+    MultiLineString(Vec<Vec<u64>>),
+    MultiSurface(Vec<Vec<Vec<u64>>>),
+    Solid(Vec<Vec<Vec<Vec<u64>>>>),
+}
+
+impl Boundaries {
+    /// Shifts the vertex indices (to make them global in a single CityJSON object)
+    fn offset(&mut self, offset: u64) {
+        fn shift(ring: &mut [u64], offset: u64) {
+            ring.iter_mut().for_each(|idx| *idx += offset);
+        }
+        match self {
+            Boundaries::MultiPoint(points) => shift(points, offset),
+            Boundaries::MultiLineString(lines) => {
+                lines.iter_mut().for_each(|line| shift(line, offset))
+            }
+            Boundaries::MultiSurface(surfaces) => surfaces
+                .iter_mut()
+                .flatten()
+                .for_each(|ring| shift(ring, offset)),
+            Boundaries::Solid(shells) => shells
+                .iter_mut()
+                .flatten()
+                .flatten()
+                .for_each(|ring| shift(ring, offset)),
+        }
+    }
+}
+
+/// City object types defined in CityJSON (the other types are written as `GenericCityObject`)
+const CITYJSON_TYPES: [&str; 33] = [
+    "Bridge",
+    "BridgePart",
+    "BridgeInstallation",
+    "BridgeConstructiveElement",
+    "BridgeRoom",
+    "BridgeFurniture",
+    "Building",
+    "BuildingPart",
+    "BuildingInstallation",
+    "BuildingConstructiveElement",
+    "BuildingFurniture",
+    "BuildingStorey",
+    "BuildingRoom",
+    "BuildingUnit",
+    "CityFurniture",
+    "CityObjectGroup",
+    "GenericCityObject",
+    "LandUse",
+    "OtherConstruction",
+    "PlantCover",
+    "SolitaryVegetationObject",
+    "TINRelief",
+    "WaterBody",
+    "Road",
+    "Railway",
+    "Waterway",
+    "TransportSquare",
+    "Tunnel",
+    "TunnelPart",
+    "TunnelInstallation",
+    "TunnelConstructiveElement",
+    "TunnelHollowSpace",
+    "TunnelFurniture",
+];
+
+/// The CityJSON type of the city object, or None if the type is not defined in CityJSON
+fn city_object_type(typename: &str) -> Option<&str> {
+    let name = typename.split_once(':').map_or(typename, |(_, name)| name);
+    let name = match name {
+        "ReliefFeature" => "TINRelief",
+        "HollowSpace" => "TunnelHollowSpace",
+        "BridgeConstructionElement" => "BridgeConstructiveElement",
+        _ => name,
+    };
+    CITYJSON_TYPES.contains(&name).then_some(name)
+}
+
+fn entity_to_feature(entity: &Entity, scale: [f64; 3]) -> Option<Feature> {
+    let Value::Object(obj) = &entity.root else {
+        return None;
+    };
+    let ObjectStereotype::Feature { id, geometries } = &obj.stereotype else {
+        return None;
+    };
+
+    let mut attributes = match entity.root.to_attribute_json() {
+        serde_json::Value::Object(map) => map,
+        _ => unreachable!(),
+    };
+    attributes.remove("id");
+    let typename = attributes.remove("type");
+    let ty = city_object_type(&obj.typename);
+    if let (None, Some(typename)) = (ty, typename) {
+        // (the extension types such as `+UndergroundBuilding` are not valid without the schemas of the extensions,
+        // so the original type is kept in the attributes of `GenericCityObject`)
+        attributes.insert("type".into(), typename);
+    }
+
+    let geom_store = entity.geometry_store.read().unwrap();
+    let mut vertices = Vec::new();
+    let mut vertex_map: HashMap<u32, u64> = HashMap::new();
+    let mut local_index = |idx: u32| -> u64 {
+        *vertex_map.entry(idx).or_insert_with(|| {
+            let v = geom_store.vertices[idx as usize];
+            vertices.push(std::array::from_fn(|i| (v[i] / scale[i]).round() as i64));
+            vertices.len() as u64 - 1
+        })
+    };
+
+    let mut geometry = Vec::with_capacity(geometries.len());
+    for entry in geometries {
+        let range = entry.pos as usize..(entry.pos + entry.len) as usize;
+        let (ty, boundaries) = match entry.ty {
+            GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle => {
+                let surfaces: Vec<Vec<Vec<u64>>> = geom_store
+                    .multipolygon
+                    .iter_range(range)
+                    .map(|poly| {
+                        poly.rings()
+                            .map(|ring| ring.iter().map(&mut local_index).collect())
+                            .collect()
+                    })
+                    .collect();
+                match entry.ty {
+                    // the shells are not distinguished in the geometry store, so the solid has only the exterior shell
+                    GeometryType::Solid => ("Solid", Boundaries::Solid(vec![surfaces])),
+                    _ => ("MultiSurface", Boundaries::MultiSurface(surfaces)),
+                }
+            }
+            GeometryType::Curve => (
+                "MultiLineString",
+                Boundaries::MultiLineString(
+                    geom_store
+                        .multilinestring
+                        .iter_range(range)
+                        .map(|ls| ls.iter().map(&mut local_index).collect())
+                        .collect(),
+                ),
+            ),
+            GeometryType::Point => (
+                "MultiPoint",
+                Boundaries::MultiPoint(
+                    geom_store
+                        .multipoint
+                        .iter_range(range)
+                        .map(&mut local_index)
+                        .collect(),
+                ),
+            ),
+        };
+        geometry.push(Geometry {
+            ty,
+            lod: entry.lod.to_string(),
+            boundaries,
+        });
+    }
+
+    Some(Feature {
+        id: id.to_string(),
+        object: CityObject {
+            ty: ty.unwrap_or("GenericCityObject").to_string(),
+            attributes,
+            geometry,
+        },
+        vertices,
+    })
+}
+
+/// Writes a CityJSON object. The vertices are buffered in a temporary file until all the city objects are written.
+fn write_city_json<W: Write>(
+    writer: &mut W,
+    header: &Header,
+    features: impl IntoIterator<Item = Feature>,
+    feedback: &Feedback,
+) -> Result<()> {
+    let mut vertices_file = BufWriter::new(tempfile::tempfile()?);
+    let mut num_vertices: u64 = 0;
+
+    let mut header_bytes = serde_json::to_vec(&header.to_json()).unwrap();
+    header_bytes.pop(); // remove the closing brace
+    writer.write_all(&header_bytes)?;
+    writer.write_all(b",\"CityObjects\":{")?;
+
+    for (i, mut feature) in features.into_iter().enumerate() {
+        feedback.ensure_not_canceled()?;
+
+        for geom in feature.object.geometry.iter_mut() {
+            geom.boundaries.offset(num_vertices);
+        }
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut *writer, &feature.id).unwrap();
+        writer.write_all(b":")?;
+        serde_json::to_writer(&mut *writer, &feature.object).unwrap();
+
+        for v in &feature.vertices {
+            if num_vertices > 0 {
+                vertices_file.write_all(b",")?;
+            }
+            write!(vertices_file, "[{},{},{}]", v[0], v[1], v[2])?;
+            num_vertices += 1;
+        }
+    }
+
+    writer.write_all(b"},\"vertices\":[")?;
+    let mut vertices_file = vertices_file.into_inner().map_err(|err| err.into_error())?;
+    vertices_file.seek(SeekFrom::Start(0))?;
+    std::io::copy(&mut vertices_file, writer)?;
+    writer.write_all(b"]}\n")?;
+
+    Ok(())
+}
+
+/// Writes a CityJSON Text Sequence: the header object followed by one `CityJSONFeature` per line
+fn write_city_json_seq<W: Write>(
+    writer: &mut W,
+    header: &Header,
+    features: impl IntoIterator<Item = Feature>,
+    feedback: &Feedback,
+) -> Result<()> {
+    let mut first_line = header.to_json();
+    first_line.insert("CityObjects".into(), json!({}));
+    first_line.insert("vertices".into(), json!([]));
+    serde_json::to_writer(&mut *writer, &first_line).unwrap();
+    writer.write_all(b"\n")?;
+
+    for feature in features {
+        feedback.ensure_not_canceled()?;
+
+        let line = json!({
+            "type": "CityJSONFeature",
+            "id": feature.id,
+            "CityObjects": { &feature.id: feature.object },
+            "vertices": feature.vertices,
+        });
+        serde_json::to_writer(&mut *writer, &line).unwrap();
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::{
+        object::{Map, Object},
+        GeometryRef, GeometryStore,
+    };
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    fn building(id: &str, offset: f64) -> Entity {
+        let mut geoms = GeometryStore {
+            epsg: 6677,
+            vertices: vec![
+                [offset, 0., 0.],
+                [offset + 1., 0., 0.],
+                [offset + 1., 1., 0.],
+                [offset, 1., 0.],
+            ],
+            ..Default::default()
+        };
+        geoms.multipolygon.add_exterior([0, 1, 2, 3]);
+        geoms.ring_ids.push(None);
+
+        let mut attributes = Map::default();
+        attributes.insert("bldg:class".into(), Value::String("普通建物".into()));
+        Entity {
+            root: Value::Object(Object {
+                typename: "bldg:Building".into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: id.into(),
+                    geometries: vec![GeometryRef {
+                        ty: GeometryType::Surface,
+                        lod: 0,
+                        pos: 0,
+                        len: 1,
+                    }],
+                },
+                attributes,
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(geoms).into(),
+            appearance_store: Default::default(),
+        }
+    }
+
+    fn features() -> Vec<Feature> {
+        let scale = transform_scale(6677);
+        vec![
+            entity_to_feature(&building("bldg_1", 0.), scale).unwrap(),
+            entity_to_feature(&building("bldg_2", 10.), scale).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_city_object_type() {
+        assert_eq!(city_object_type("bldg:Building"), Some("Building"));
+        assert_eq!(city_object_type("dem:ReliefFeature"), Some("TINRelief"));
+        assert_eq!(city_object_type("uro:UndergroundBuilding"), None);
+    }
+
+    #[test]
+    fn test_write_city_json() {
+        let (_, feedback, _) = feedback::watcher();
+        let header = Header {
+            epsg: 6677,
+            scale: transform_scale(6677),
+        };
+        let mut buf = Vec::new();
+        write_city_json(&mut buf, &header, features(), &feedback).unwrap();
+
+        let city_json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(city_json["type"], "CityJSON");
+        assert_eq!(city_json["vertices"].as_array().unwrap().len(), 8);
+        assert_eq!(city_json["vertices"][4], json!([10000, 0, 0]));
+
+        let obj = &city_json["CityObjects"]["bldg_2"];
+        assert_eq!(obj["type"], "Building");
+        assert_eq!(obj["attributes"]["bldg:class"], "普通建物");
+        // the indices point to the vertices of the second feature
+        assert_eq!(obj["geometry"][0]["boundaries"], json!([[[4, 5, 6, 7]]]));
+    }
+
+    #[test]
+    fn test_write_city_json_seq() {
+        let (_, feedback, _) = feedback::watcher();
+        let header = Header {
+            epsg: 6677,
+            scale: transform_scale(6677),
+        };
+        let mut buf = Vec::new();
+        write_city_json_seq(&mut buf, &header, features(), &feedback).unwrap();
+
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&buf)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["type"], "CityJSON");
+        assert_eq!(lines[0]["transform"]["scale"], json!([0.001, 0.001, 0.001]));
+
+        let feature = &lines[2];
+        assert_eq!(feature["type"], "CityJSONFeature");
+        assert_eq!(feature["id"], "bldg_2");
+        assert_eq!(feature["vertices"].as_array().unwrap().len(), 4);
+        // the indices are local to each feature
+        assert_eq!(
+            feature["CityObjects"]["bldg_2"]["geometry"][0]["boundaries"],
+            json!([[[0, 1, 2, 3]]])
+        );
+    }
+}
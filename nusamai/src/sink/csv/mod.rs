@@ -0,0 +1,268 @@
+//! CSV sink
+//!
+//! Writes the attributes of each feature (or data) type to a separate CSV file, without the geometries
+//! (optionally with a WKT column). This is useful for checking the attributes in spreadsheets.
+
+mod wkt;
+
+use std::{io::Write, path::PathBuf};
+
+use flatgeom::MultiPolygon;
+use indexmap::IndexMap;
+use nusamai_citygml::schema::{Schema, TypeDef};
+use rayon::prelude::*;
+use wkt::indexed_multipolygon_to_wkt;
+
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer,
+    transformer::{
+        building_adjacency_config, name_columns_config, prefix_config, solar_attributes_config,
+        underground_config, use_lod_config, TransformerSettings,
+    },
+};
+
+use super::{
+    option::output_parameter,
+    output::{compression_parameter, Compression, OutputWriter},
+    parquet::{columnar::value_to_string, entity_to_row_with},
+};
+
+/// Byte order mark for Excel to recognize the files as UTF-8
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+pub struct CsvSinkProvider {}
+
+impl DataSinkProvider for CsvSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "csv".to_string(),
+            name: "CSV".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "wkt".into(),
+            entry: ParameterEntry {
+                description: "Add the geometries as a WKT column".into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("ジオメトリをWKT形式の列として出力する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "bom".into(),
+            entry: ParameterEntry {
+                description: "Write the UTF-8 byte order mark (for Microsoft Excel)".into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(true) }),
+                label: Some("BOM付きUTF-8で出力する（Excel向け）".into()),
+            },
+        });
+        params.define(compression_parameter());
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(use_lod_config("max_lod", None));
+        settings.insert(underground_config());
+        settings.insert(solar_attributes_config());
+        settings.insert(building_adjacency_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let wkt = get_parameter_value!(params, "wkt", Boolean).unwrap();
+        let bom = get_parameter_value!(params, "bom", Boolean).unwrap();
+        let compression = get_parameter_value!(params, "compression", String)
+            .clone()
+            .unwrap_or_default();
+        let transform_settings = self.transformer_options();
+
+        Box::<CsvSink>::new(CsvSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            transform_settings,
+            wkt,
+            bom,
+            compression,
+        })
+    }
+}
+
+pub struct CsvSink {
+    output_path: PathBuf,
+    transform_settings: TransformerSettings,
+    /// Add the `wkt` column
+    wkt: bool,
+    /// Write the UTF-8 BOM at the beginning of the files
+    bom: bool,
+    /// Compression option (`auto` follows the extension of the output path)
+    compression: String,
+}
+
+/// A row of a CSV file
+impl DataSink for CsvSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        let default_requirements = DataRequirements {
+            tree_flattening: transformer::TreeFlatteningSpec::Flatten {
+                feature: transformer::FeatureFlatteningOption::AllExceptThematicSurfaces,
+                data: transformer::DataFlatteningOption::TopLevelOnly,
+                object: transformer::ObjectFlatteningOption::None,
+            },
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+        let with_wkt = self.wkt;
+        let compression = Compression::negotiate(&self.compression, &self.output_path)?;
+
+        let (ra, rb) = rayon::join(
+            || {
+                upstream
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(sender, |sender, parcel| {
+                        feedback.ensure_not_canceled()?;
+
+                        let encode_wkt = |vertices: &[[f64; 3]], mpoly: &MultiPolygon<u32>| {
+                            Some(indexed_multipolygon_to_wkt(vertices, mpoly))
+                        };
+                        let Some((typename, record)) = entity_to_row_with(
+                            feedback,
+                            parcel.entity,
+                            with_wkt.then_some(encode_wkt),
+                        ) else {
+                            return Ok(());
+                        };
+                        if sender.send((typename, record)).is_err() {
+                            return Err(PipelineError::Canceled);
+                        };
+                        Ok(())
+                    })
+            },
+            || {
+                std::fs::create_dir_all(&self.output_path)?;
+
+                // (writer, attribute columns) for each type
+                let mut writers =
+                    IndexMap::<String, (csv::Writer<OutputWriter>, Vec<String>)>::new();
+                for (typename, record) in receiver {
+                    feedback.ensure_not_canceled()?;
+
+                    if !writers.contains_key(&typename) {
+                        let path = compression.apply_extension(
+                            &self
+                                .output_path
+                                .join(format!("{}.csv", typename.replace(':', "_"))),
+                        );
+                        let mut file = OutputWriter::create(&path, compression)?;
+                        if self.bom {
+                            file.write_all(UTF8_BOM)?;
+                        }
+                        let mut writer = csv::Writer::from_writer(file);
+
+                        let columns = attribute_columns(schema.types.get(&typename));
+                        let header = std::iter::once("id")
+                            .chain(columns.iter().map(|c| c.as_str()))
+                            .chain(with_wkt.then_some("wkt"));
+                        writer.write_record(header).map_err(csv_error)?;
+                        writers.insert(typename.clone(), (writer, columns));
+                    }
+
+                    let (writer, columns) = writers.get_mut(&typename).unwrap();
+                    let id = record.id.unwrap_or_default();
+                    let values = columns.iter().map(|name| {
+                        record
+                            .attributes
+                            .get(name)
+                            .and_then(value_to_string)
+                            .unwrap_or_default()
+                    });
+                    let row = std::iter::once(id)
+                        .chain(values)
+                        .chain(with_wkt.then(|| record.geometry.unwrap_or_default()));
+                    writer.write_record(row).map_err(csv_error)?;
+                }
+
+                for (_, (writer, _)) in writers {
+                    let file = writer.into_inner().map_err(|err| err.into_error())?;
+                    file.finish()?;
+                }
+
+                Ok::<(), PipelineError>(())
+            },
+        );
+
+        match ra {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+        match rb {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+
+        Ok(())
+    }
+}
+
+/// Attribute columns of a type (in the order of the schema)
+fn attribute_columns(typedef: Option<&TypeDef>) -> Vec<String> {
+    let attributes = match typedef {
+        Some(TypeDef::Feature(feature)) => &feature.attributes,
+        Some(TypeDef::Data(data)) => &data.attributes,
+        _ => return vec![],
+    };
+    attributes
+        .keys()
+        .filter(|name| name.as_str() != "id" && name.as_str() != "wkt")
+        .cloned()
+        .collect()
+}
+
+fn csv_error(err: csv::Error) -> PipelineError {
+    PipelineError::Other(format!("CSV error: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::schema::{Attribute, DataTypeDef, TypeRef};
+
+    use super::*;
+
+    #[test]
+    fn test_attribute_columns() {
+        let mut data = DataTypeDef::default();
+        data.attributes
+            .insert("uro:rank".into(), Attribute::new(TypeRef::Code));
+        data.attributes
+            .insert("id".into(), Attribute::new(TypeRef::String));
+        data.attributes
+            .insert("uro:depth".into(), Attribute::new(TypeRef::Measure));
+
+        assert_eq!(
+            attribute_columns(Some(&TypeDef::Data(data))),
+            vec!["uro:rank", "uro:depth"]
+        );
+        assert!(attribute_columns(None).is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+//! WKT representation of the geometries
+
+use std::fmt::Write;
+
+use flatgeom::MultiPolygon;
+
+/// Writes the multipolygon as a WKT `MULTIPOLYGON Z`
+pub fn indexed_multipolygon_to_wkt(vertices: &[[f64; 3]], mpoly: &MultiPolygon<u32>) -> String {
+    let mut wkt = String::from("MULTIPOLYGON Z (");
+    for (i, poly) in mpoly.iter().enumerate() {
+        if i > 0 {
+            wkt.push_str(", ");
+        }
+        wkt.push('(');
+        for (j, ring) in poly.rings().enumerate() {
+            if j > 0 {
+                wkt.push_str(", ");
+            }
+            wkt.push('(');
+            for (k, idx) in ring.iter_closed().enumerate() {
+                if k > 0 {
+                    wkt.push_str(", ");
+                }
+                let [x, y, z] = vertices[idx as usize];
+                let _ = write!(wkt, "{x} {y} {z}");
+            }
+            wkt.push(')');
+        }
+        wkt.push(')');
+    }
+    wkt.push(')');
+    wkt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multipolygon_to_wkt() {
+        let vertices = vec![
+            [0., 0., 1.],
+            [5., 0., 1.],
+            [5., 5., 1.],
+            [1., 1., 1.],
+            [2., 1., 1.],
+            [2., 2., 1.],
+        ];
+        let mut mpoly = MultiPolygon::<u32>::new();
+        mpoly.add_exterior([0, 1, 2]);
+        mpoly.add_interior([3, 4, 5]);
+        mpoly.add_exterior([3, 4, 5]);
+
+        assert_eq!(
+            indexed_multipolygon_to_wkt(&vertices, &mpoly),
+            "MULTIPOLYGON Z (((0 0 1, 5 0 1, 5 5 1, 0 0 1), (1 1 1, 2 1 1, 2 2 1, 1 1 1)), \
+             ((1 1 1, 2 1 1, 2 2 1, 1 1 1)))"
+        );
+    }
+}
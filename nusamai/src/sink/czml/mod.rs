@@ -1,6 +1,9 @@
 //! czml sink
+//!
+//! The polygons and the points are colored by the styling profile (see the `style` module).
 
 use std::{
+    collections::HashMap,
     fs::File,
     io::{BufWriter, Write},
     path::PathBuf,
@@ -12,8 +15,9 @@ use nusamai_citygml::{
     GeometryType,
 };
 use nusamai_czml::{
-    conversion::indexed_multipolygon_to_czml_polygon, indexed_polygon_to_czml_polygon, CzmlBoolean,
-    Packet, StringProperties, StringValueType,
+    conversion::indexed_multipolygon_to_czml_polygon, indexed_polygon_to_czml_polygon, Color,
+    ColorProperties, CzmlBoolean, CzmlDouble, CzmlPolygon, Material, MaterialProperties, Packet,
+    RgbaValue, SolidColorMaterial, StringProperties, StringValueType,
 };
 use nusamai_plateau::Entity;
 use rayon::prelude::*;
@@ -23,10 +27,16 @@ use crate::{
     parameters::*,
     pipeline::{Feedback, PipelineError, Receiver, Result},
     sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
-    transformer::{use_lod_config, TransformerSettings},
+    transformer::{
+        name_columns_config, prefix_config, split_bridge_and_tunnel_elements_config,
+        underground_config, use_lod_config, vegetation_config, TransformerSettings,
+    },
 };
 
-use super::option::output_parameter;
+use super::{
+    option::{output_parameter, style_parameter},
+    style::{self, Style, StyleProfile},
+};
 
 pub struct CzmlSinkProvider {}
 
@@ -41,6 +51,7 @@ impl DataSinkProvider for CzmlSinkProvider {
     fn sink_options(&self) -> Parameters {
         let mut params = Parameters::new();
         params.define(output_parameter());
+        params.define(style_parameter());
 
         params
     }
@@ -48,6 +59,11 @@ impl DataSinkProvider for CzmlSinkProvider {
     fn transformer_options(&self) -> TransformerSettings {
         let mut settings: TransformerSettings = TransformerSettings::new();
         settings.insert(use_lod_config("max_lod", None));
+        settings.insert(vegetation_config(&["point"]));
+        settings.insert(underground_config());
+        settings.insert(split_bridge_and_tunnel_elements_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
 
         settings
     }
@@ -55,10 +71,12 @@ impl DataSinkProvider for CzmlSinkProvider {
     fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
         let output_path = get_parameter_value!(params, "@output", FileSystemPath);
         let transform_settings = self.transformer_options();
+        let style_path = get_parameter_value!(params, "style", FileSystemPath);
 
         Box::<CzmlSink>::new(CzmlSink {
             output_path: output_path.as_ref().unwrap().into(),
             transform_settings,
+            style_path: style_path.clone(),
         })
     }
 }
@@ -66,6 +84,8 @@ impl DataSinkProvider for CzmlSinkProvider {
 pub struct CzmlSink {
     output_path: PathBuf,
     transform_settings: TransformerSettings,
+    /// Styling profile (the built-in one if not specified)
+    style_path: Option<PathBuf>,
 }
 
 impl DataSink for CzmlSink {
@@ -80,6 +100,7 @@ impl DataSink for CzmlSink {
     }
 
     fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
+        let profile = StyleProfile::load(self.style_path.as_deref())?;
         let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
 
         let (ra, rb) = rayon::join(
@@ -91,7 +112,11 @@ impl DataSink for CzmlSink {
                     .try_for_each_with(sender, |sender, parcel| {
                         feedback.ensure_not_canceled()?;
 
-                        let packets = entity_to_packets(parcel.entity, true);
+                        let style = match &parcel.entity.root {
+                            Value::Object(obj) => profile.style_of(obj),
+                            _ => profile.base_style(),
+                        };
+                        let packets = entity_to_packets(parcel.entity, true, &style);
                         for packet in packets {
                             let bytes = serde_json::to_vec(&packet).unwrap();
                             if sender.send(bytes).is_err() {
@@ -165,8 +190,28 @@ fn map_to_html_table(map: &serde_json::Map<String, serde_json::Value>) -> String
     html
 }
 
+fn czml_color(color: &style::Color) -> Color {
+    Color::Object(ColorProperties {
+        rgba: Some(RgbaValue::Constant(color.rgba())),
+        ..Default::default()
+    })
+}
+
+/// Applies the fill color and the outline to the polygon
+fn apply_style(czml_polygon: &mut CzmlPolygon, style: &Style) {
+    czml_polygon.material = Material::Object(MaterialProperties {
+        solid_color: Some(SolidColorMaterial {
+            color: czml_color(&style.fill),
+        }),
+        ..Default::default()
+    });
+    czml_polygon.outline = CzmlBoolean::Boolean(true);
+    czml_polygon.outline_color = czml_color(&style.stroke);
+    czml_polygon.outline_width = CzmlDouble::Double(style.width as f32);
+}
+
 /// Create CZML Packet from a Entity
-pub fn entity_to_packets(entity: Entity, single_part: bool) -> Vec<Packet> {
+pub fn entity_to_packets(entity: Entity, single_part: bool, style: &Style) -> Vec<Packet> {
     let properties = extract_properties(&entity.root);
     let geom_store = entity.geometry_store.read().unwrap();
 
@@ -183,6 +228,7 @@ pub fn entity_to_packets(entity: Entity, single_part: bool) -> Vec<Packet> {
     };
 
     let mut mpoly = flatgeom::MultiPolygon::<u32>::new();
+    let mut points = Vec::new();
 
     geometries.iter().for_each(|entry| match entry.ty {
         GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle => {
@@ -193,8 +239,17 @@ pub fn entity_to_packets(entity: Entity, single_part: bool) -> Vec<Packet> {
                 mpoly.push(&idx_poly);
             }
         }
-        GeometryType::Curve => unimplemented!(),
-        GeometryType::Point => unimplemented!(),
+        GeometryType::Curve => {
+            // TODO: implement
+        }
+        GeometryType::Point => {
+            for idx in geom_store
+                .multipoint
+                .iter_range(entry.pos as usize..(entry.pos + entry.len) as usize)
+            {
+                points.push(geom_store.vertices[idx as usize]);
+            }
+        }
     });
 
     // Create a Packet that retains attributes and references it from child features
@@ -212,6 +267,7 @@ pub fn entity_to_packets(entity: Entity, single_part: bool) -> Vec<Packet> {
                 let mut czml_polygon = indexed_polygon_to_czml_polygon(&geom_store.vertices, &poly);
                 // In Cesium, if perPositionHeight is false, the polygon height is fixed
                 czml_polygon.per_position_height = CzmlBoolean::Boolean(true);
+                apply_style(&mut czml_polygon, style);
 
                 let packet = Packet {
                     polygon: Some(czml_polygon),
@@ -226,7 +282,9 @@ pub fn entity_to_packets(entity: Entity, single_part: bool) -> Vec<Packet> {
             }
         } else {
             // TODO: Multi-part polygons are used in the glTF model
-            let czml_polygon = indexed_multipolygon_to_czml_polygon(&geom_store.vertices, &mpoly);
+            let mut czml_polygon =
+                indexed_multipolygon_to_czml_polygon(&geom_store.vertices, &mpoly);
+            apply_style(&mut czml_polygon, style);
             let packet = Packet {
                 polygon: Some(czml_polygon),
                 ..Default::default()
@@ -235,6 +293,29 @@ pub fn entity_to_packets(entity: Entity, single_part: bool) -> Vec<Packet> {
         }
     }
 
+    for [lng, lat, height] in points {
+        let packet = Packet {
+            position: Some(HashMap::from([(
+                "cartographicDegrees".to_string(),
+                serde_json::json!([lng, lat, height]),
+            )])),
+            point: Some(HashMap::from([
+                ("pixelSize".to_string(), serde_json::json!(8)),
+                (
+                    "color".to_string(),
+                    serde_json::json!({ "rgba": style.fill.rgba() }),
+                ),
+            ])),
+            description: Some(StringValueType::Object(StringProperties {
+                reference: Some(format!("{parent_id}#description")),
+                ..Default::default()
+            })),
+            parent: Some(parent_id.clone()),
+            ..Default::default()
+        };
+        packets.push(packet);
+    }
+
     packets
 }
 
@@ -335,7 +416,8 @@ mod tests {
             appearance_store: Default::default(),
         };
 
-        let packets = entity_to_packets(entity, true);
+        let style = StyleProfile::builtin().base_style();
+        let packets = entity_to_packets(entity, true, &style);
         assert_eq!(packets.len(), 4);
 
         // test parent packet
@@ -353,6 +435,15 @@ mod tests {
         assert!(first_polygon.is_some());
 
         let first_polygon = first_polygon.as_ref().unwrap();
+        assert_eq!(
+            first_polygon.material,
+            Material::Object(MaterialProperties {
+                solid_color: Some(SolidColorMaterial {
+                    color: czml_color(&style.fill),
+                }),
+                ..Default::default()
+            })
+        );
         let first_polygon_positions = PositionListProperties {
             cartographic_degrees: Some(vec![
                 0., 0., 111., 5., 0., 111., 5., 5., 111., 0., 5., 111.,
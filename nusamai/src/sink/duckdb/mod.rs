@@ -0,0 +1,258 @@
+//! DuckDB sink
+//!
+//! Creates a table for each feature (or data) type with typed attribute columns.
+//! The geometries are stored as `GEOMETRY` of the spatial extension if it can be loaded, or as WKB (`BLOB`) otherwise.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use ::duckdb::Connection;
+use arrow_schema::{DataType, Schema as ArrowSchema};
+use indexmap::IndexMap;
+use nusamai_citygml::schema::{Schema, TypeDef};
+use rayon::prelude::*;
+
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer,
+    transformer::{
+        building_adjacency_config, name_columns_config, prefix_config, solar_attributes_config,
+        underground_config, use_lod_config, TransformerSettings,
+    },
+};
+
+use super::{
+    option::output_parameter,
+    parquet::{
+        arrow_error,
+        columnar::{ColumnarTable, GEOMETRY_COLUMN},
+        entity_to_row, ROWS_PER_BATCH,
+    },
+};
+
+pub struct DuckDbSinkProvider {}
+
+impl DataSinkProvider for DuckDbSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "duckdb".to_string(),
+            name: "DuckDB".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(use_lod_config("max_lod", None));
+        settings.insert(underground_config());
+        settings.insert(solar_attributes_config());
+        settings.insert(building_adjacency_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let transform_settings = self.transformer_options();
+
+        Box::<DuckDbSink>::new(DuckDbSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            transform_settings,
+        })
+    }
+}
+
+pub struct DuckDbSink {
+    output_path: PathBuf,
+    transform_settings: TransformerSettings,
+}
+
+impl DataSink for DuckDbSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        let default_requirements = DataRequirements {
+            tree_flattening: transformer::TreeFlatteningSpec::Flatten {
+                feature: transformer::FeatureFlatteningOption::AllExceptThematicSurfaces,
+                data: transformer::DataFlatteningOption::TopLevelOnly,
+                object: transformer::ObjectFlatteningOption::None,
+            },
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+
+        let (ra, rb) = rayon::join(
+            || {
+                upstream
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(sender, |sender, parcel| {
+                        feedback.ensure_not_canceled()?;
+
+                        let Some((typename, row)) = entity_to_row(feedback, parcel.entity) else {
+                            return Ok(());
+                        };
+                        if sender.send((typename, row)).is_err() {
+                            return Err(PipelineError::Canceled);
+                        };
+                        Ok(())
+                    })
+            },
+            || {
+                if let Some(dir) = self.output_path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                if self.output_path.exists() {
+                    std::fs::remove_file(&self.output_path)?;
+                }
+                let conn = Connection::open(&self.output_path).map_err(duckdb_error)?;
+
+                let spatial = load_spatial(&conn);
+                if !spatial {
+                    feedback.warn(
+                        "The spatial extension of DuckDB is not available; geometries are stored as WKB"
+                            .into(),
+                    );
+                }
+
+                // table name and the rows of each type
+                let mut tables = IndexMap::<String, (String, ColumnarTable)>::new();
+                for (typename, row) in receiver {
+                    feedback.ensure_not_canceled()?;
+
+                    if !tables.contains_key(&typename) {
+                        let table_name = typename.replace(':', "_");
+                        let table = ColumnarTable::new(schema.types.get(&typename), HashMap::new());
+                        conn.execute_batch(&create_table_sql(&table_name, &table.schema()))
+                            .map_err(duckdb_error)?;
+                        tables.insert(typename.clone(), (table_name, table));
+                    }
+
+                    let (table_name, table) = tables.get_mut(&typename).unwrap();
+                    table.append(&row);
+                    if table.num_rows() >= ROWS_PER_BATCH {
+                        append_batch(&conn, table_name, table)?;
+                    }
+                }
+
+                for (typename, (table_name, mut table)) in tables {
+                    feedback.ensure_not_canceled()?;
+
+                    if table.num_rows() > 0 {
+                        append_batch(&conn, &table_name, &mut table)?;
+                    }
+                    if spatial && matches!(schema.types.get(&typename), Some(TypeDef::Feature(_))) {
+                        conn.execute_batch(&to_geometry_sql(&table_name))
+                            .map_err(duckdb_error)?;
+                    }
+                }
+
+                conn.close().map_err(|(_, err)| duckdb_error(err))?;
+                Ok::<(), PipelineError>(())
+            },
+        );
+
+        match ra {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+        match rb {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+
+        Ok(())
+    }
+}
+
+/// Loads the spatial extension, installing it if needed. Returns `false` if it is not available (e.g. offline).
+fn load_spatial(conn: &Connection) -> bool {
+    conn.execute_batch("LOAD spatial;").is_ok()
+        || conn.execute_batch("INSTALL spatial; LOAD spatial;").is_ok()
+}
+
+fn append_batch(conn: &Connection, table_name: &str, table: &mut ColumnarTable) -> Result<()> {
+    let batch = table.take_batch().map_err(arrow_error)?;
+    let mut appender = conn.appender(table_name).map_err(duckdb_error)?;
+    appender.append_record_batch(batch).map_err(duckdb_error)?;
+    appender.flush().map_err(duckdb_error)
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Converts the WKB column into the `GEOMETRY` type of the spatial extension
+fn to_geometry_sql(table_name: &str) -> String {
+    let column = quote_identifier(GEOMETRY_COLUMN);
+    format!(
+        "ALTER TABLE {} ALTER {column} TYPE GEOMETRY USING ST_GeomFromWKB({column});",
+        quote_identifier(table_name)
+    )
+}
+
+/// Makes the `CREATE TABLE` statement with the column types matching the Arrow schema
+fn create_table_sql(table_name: &str, schema: &ArrowSchema) -> String {
+    let columns: Vec<String> = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let ty = match field.data_type() {
+                DataType::Int64 => "BIGINT",
+                DataType::Float64 => "DOUBLE",
+                DataType::Boolean => "BOOLEAN",
+                DataType::Binary => "BLOB",
+                _ => "VARCHAR",
+            };
+            format!("{} {ty}", quote_identifier(field.name()))
+        })
+        .collect();
+    format!(
+        "CREATE TABLE {} ({});",
+        quote_identifier(table_name),
+        columns.join(", ")
+    )
+}
+
+fn duckdb_error(err: ::duckdb::Error) -> PipelineError {
+    PipelineError::Other(format!("DuckDB error: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_schema::Field;
+
+    use super::*;
+
+    #[test]
+    fn test_create_table_sql() {
+        let schema = ArrowSchema::new(vec![
+            Field::new("id", DataType::Utf8, true),
+            Field::new("bldg:measuredHeight", DataType::Float64, true),
+            Field::new("bldg:storeysAboveGround", DataType::Int64, true),
+            Field::new("uro:\"flag\"", DataType::Boolean, true),
+            Field::new(GEOMETRY_COLUMN, DataType::Binary, true),
+        ]);
+        assert_eq!(
+            create_table_sql("bldg_Building", &schema),
+            "CREATE TABLE \"bldg_Building\" (\"id\" VARCHAR, \"bldg:measuredHeight\" DOUBLE, \
+             \"bldg:storeysAboveGround\" BIGINT, \"uro:\"\"flag\"\"\" BOOLEAN, \"geometry\" BLOB);"
+        );
+    }
+}
@@ -0,0 +1,465 @@
+//! DXF sink
+//!
+//! Writes the building footprints (LOD0) as closed 3D polylines and the LOD1 solids as 3DFACE entities
+//! into an ASCII DXF (R12) file, so that the city models can be used in CAD software such as AutoCAD.
+//! The entities are put on the layers for each feature type and LOD (e.g. `bldg_Building_LOD1`),
+//! and the feature IDs are attached as the extended data.
+//!
+//! A projected CRS (e.g. the Japan Plane Rectangular CS) should be used, since CAD software works in meters.
+
+mod writer;
+
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{BufWriter, Seek},
+    path::PathBuf,
+};
+
+use earcut::{utils3d::project3d_to_2d, Earcut};
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+    GeometryType,
+};
+use nusamai_plateau::Entity;
+use nusamai_projection::crs::{
+    EPSG_JGD2011_GEOGRAPHIC_2D, EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_WGS84_GEOGRAPHIC_2D,
+    EPSG_WGS84_GEOGRAPHIC_3D,
+};
+use rayon::prelude::*;
+use writer::{DxfEntity, DxfWriter, Layer};
+
+use super::option::output_parameter;
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer::{self, underground_config, LodFilterMode, LodMask, TransformerSettings},
+};
+
+pub struct DxfSinkProvider {}
+
+impl DataSinkProvider for DxfSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "dxf".to_string(),
+            name: "DXF".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(underground_config());
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let transform_settings = self.transformer_options();
+
+        Box::<DxfSink>::new(DxfSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            transform_settings,
+        })
+    }
+}
+
+pub struct DxfSink {
+    output_path: PathBuf,
+    transform_settings: TransformerSettings,
+}
+
+/// Entities of a feature with their layers
+struct DxfFeature {
+    id: String,
+    entities: Vec<(Layer, DxfEntity)>,
+}
+
+impl DataSink for DxfSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        // the footprints (LOD0) and the solids (LOD1) are both written
+        let mut mask = LodMask::default();
+        mask.add_lod(0);
+        mask.add_lod(1);
+        let default_requirements = DataRequirements {
+            key_value: transformer::KeyValueSpec::None,
+            lod_filter: transformer::LodFilterSpec {
+                mask,
+                mode: LodFilterMode::All,
+            },
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        if schema.epsg.is_some_and(|epsg| {
+            matches!(
+                epsg,
+                EPSG_WGS84_GEOGRAPHIC_2D
+                    | EPSG_WGS84_GEOGRAPHIC_3D
+                    | EPSG_JGD2011_GEOGRAPHIC_2D
+                    | EPSG_JGD2011_GEOGRAPHIC_3D
+            )
+        }) {
+            feedback.warn(
+                "The coordinates are written in degrees. Use a projected CRS (e.g. the Japan Plane Rectangular CS) for CAD software".to_string(),
+            );
+        }
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+
+        let (ra, rb) = rayon::join(
+            || {
+                upstream
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(sender, |sender, parcel| {
+                        feedback.ensure_not_canceled()?;
+                        if let Some(feature) = make_feature(&parcel.entity) {
+                            if sender.send(feature).is_err() {
+                                return Err(PipelineError::Canceled);
+                            }
+                        }
+                        Ok(())
+                    })
+            },
+            || {
+                // The layers and the extent are written before the entities,
+                // so the entities are written into a temporary file first.
+                let mut entities = DxfWriter::new(BufWriter::new(tempfile::tempfile()?));
+                let mut layers = BTreeSet::new();
+                let mut extent: Option<[[f64; 3]; 2]> = None;
+                let mut count = 0;
+                for (i, feature) in receiver.into_iter().enumerate() {
+                    if i % 1000 == 0 {
+                        feedback.ensure_not_canceled()?;
+                    }
+                    for (layer, entity) in &feature.entities {
+                        entities.write_entity(&layer.name, &feature.id, entity)?;
+                        expand_extent(&mut extent, entity);
+                        if !layers.contains(layer) {
+                            layers.insert(layer.clone());
+                        }
+                    }
+                    count += 1;
+                }
+                let mut entities = entities
+                    .into_inner()
+                    .into_inner()
+                    .map_err(|err| err.into_error())?;
+                entities.rewind()?;
+
+                let mut writer = DxfWriter::new(BufWriter::with_capacity(
+                    1024 * 1024,
+                    File::create(&self.output_path)?,
+                ));
+                let layers: Vec<Layer> = layers.into_iter().collect();
+                writer.write_preamble(&layers, extent)?;
+                let mut output = writer.into_inner();
+                std::io::copy(&mut entities, &mut output)?;
+                DxfWriter::new(output).finish()?;
+
+                feedback.info(format!("Wrote {count} features in {} layers", layers.len()));
+                Ok::<(), PipelineError>(())
+            },
+        );
+
+        match ra {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => return Err(error),
+        }
+        match rb {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => return Err(error),
+        }
+        Ok(())
+    }
+}
+
+/// Layer name for the feature type and LOD (`bldg:Building` -> `bldg_Building_LOD1`).
+/// The characters not allowed in the layer names (`<>/\":;?*|,=` etc.) are replaced with `_`.
+fn layer_name(typename: &str, lod: u8) -> String {
+    let name: String = typename
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '$' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("{name}_LOD{lod}")
+}
+
+/// Color of the layer (AutoCAD Color Index) by the package of the feature type
+fn layer_color(typename: &str) -> i16 {
+    match typename.split_once(':').map(|(prefix, _)| prefix) {
+        Some("bldg") => 7,                  // white (black on a white background)
+        Some("tran" | "rwy" | "trk") => 8,  // gray
+        Some("brid" | "tun") => 30,         // orange
+        Some("veg") => 3,                   // green
+        Some("wtr") => 5,                   // blue
+        Some("luse" | "urf" | "lsld") => 2, // yellow
+        Some("dem") => 9,                   // light gray
+        Some("frn") => 6,                   // magenta
+        _ => 4,                             // cyan
+    }
+}
+
+/// Converts the geometries of the entity to the DXF entities. Returns `None` if it has no geometries.
+fn make_feature(entity: &Entity) -> Option<DxfFeature> {
+    let Value::Object(obj) = &entity.root else {
+        return None;
+    };
+    let ObjectStereotype::Feature { id, geometries } = &obj.stereotype else {
+        return None;
+    };
+
+    let geom_store = entity.geometry_store.read().unwrap();
+    let vertex = |idx: u32| geom_store.vertices[idx as usize];
+    let color = layer_color(&obj.typename);
+    let mut entities = Vec::new();
+
+    let mut earcutter = Earcut::new();
+    let mut buf3d: Vec<[f64; 3]> = Vec::new();
+    let mut buf2d: Vec<[f64; 2]> = Vec::new();
+    let mut index_buf: Vec<u32> = Vec::new();
+
+    for entry in geometries {
+        let layer = Layer {
+            name: layer_name(&obj.typename, entry.lod),
+            color,
+        };
+        let range = entry.pos as usize..(entry.pos + entry.len) as usize;
+        match entry.ty {
+            GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle => {
+                for poly in geom_store.multipolygon.iter_range(range) {
+                    if entry.lod == 0 {
+                        // footprints as the outlines (including the holes)
+                        for ring in poly.rings() {
+                            let vertices = open_ring(ring.raw_coords().iter().map(|&i| vertex(i)));
+                            if vertices.len() >= 3 {
+                                entities.push((
+                                    layer.clone(),
+                                    DxfEntity::Polyline {
+                                        vertices,
+                                        closed: true,
+                                    },
+                                ));
+                            }
+                        }
+                        continue;
+                    }
+
+                    // simple quadrilaterals are kept as they are
+                    let exterior =
+                        open_ring(poly.exterior().raw_coords().iter().map(|&i| vertex(i)));
+                    if poly.hole_indices().is_empty() && exterior.len() <= 4 {
+                        match exterior.len() {
+                            3 => entities.push((
+                                layer.clone(),
+                                DxfEntity::Face([
+                                    exterior[0],
+                                    exterior[1],
+                                    exterior[2],
+                                    exterior[2],
+                                ]),
+                            )),
+                            4 => entities.push((
+                                layer.clone(),
+                                DxfEntity::Face([
+                                    exterior[0],
+                                    exterior[1],
+                                    exterior[2],
+                                    exterior[3],
+                                ]),
+                            )),
+                            _ => {}
+                        }
+                        continue;
+                    }
+
+                    let indices = poly.raw_coords();
+                    buf3d.clear();
+                    buf3d.extend(indices.iter().map(|&idx| vertex(idx)));
+                    let num_outer = match poly.hole_indices().first() {
+                        Some(&v) => v as usize,
+                        None => indices.len(),
+                    };
+                    if !project3d_to_2d(&buf3d, num_outer, &mut buf2d) {
+                        continue;
+                    }
+                    earcutter.earcut(buf2d.iter().cloned(), poly.hole_indices(), &mut index_buf);
+                    for tri in index_buf.chunks_exact(3) {
+                        let [a, b, c] = [0, 1, 2].map(|i| buf3d[tri[i] as usize]);
+                        entities.push((layer.clone(), DxfEntity::Face([a, b, c, c])));
+                    }
+                }
+            }
+            GeometryType::Curve => {
+                for line in geom_store.multilinestring.iter_range(range) {
+                    let vertices: Vec<[f64; 3]> =
+                        line.raw_coords().iter().map(|&i| vertex(i)).collect();
+                    if vertices.len() >= 2 {
+                        entities.push((
+                            layer.clone(),
+                            DxfEntity::Polyline {
+                                vertices,
+                                closed: false,
+                            },
+                        ));
+                    }
+                }
+            }
+            GeometryType::Point => {
+                for idx in geom_store.multipoint.iter_range(range) {
+                    entities.push((layer.clone(), DxfEntity::Point(vertex(idx))));
+                }
+            }
+        }
+    }
+
+    if entities.is_empty() {
+        return None;
+    }
+    Some(DxfFeature {
+        id: id.clone(),
+        entities,
+    })
+}
+
+/// Vertices of the ring without the closing vertex
+fn open_ring(vertices: impl Iterator<Item = [f64; 3]>) -> Vec<[f64; 3]> {
+    let mut vertices: Vec<[f64; 3]> = vertices.collect();
+    if vertices.len() > 1 && vertices.first() == vertices.last() {
+        vertices.pop();
+    }
+    vertices
+}
+
+fn expand_extent(extent: &mut Option<[[f64; 3]; 2]>, entity: &DxfEntity) {
+    let vertices: &[[f64; 3]] = match entity {
+        DxfEntity::Face(vertices) => vertices,
+        DxfEntity::Polyline { vertices, .. } => vertices,
+        DxfEntity::Point(v) => std::slice::from_ref(v),
+    };
+    for v in vertices {
+        let [min, max] = extent.get_or_insert([*v, *v]);
+        for ((min, max), v) in min.iter_mut().zip(max.iter_mut()).zip(v) {
+            *min = min.min(*v);
+            *max = max.max(*v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use flatgeom::MultiPolygon;
+    use nusamai_citygml::{
+        geometry::{GeometryRef, GeometryStore},
+        object::{Map, Object},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_make_feature() {
+        let mut mpoly = MultiPolygon::<u32>::new();
+        // footprint
+        mpoly.add_exterior([0, 1, 2, 3, 0]);
+        // a wall (quadrilateral) and the roof (pentagon)
+        mpoly.add_exterior([0, 1, 5, 4, 0]);
+        mpoly.add_exterior([4, 5, 6, 7, 8, 4]);
+        let entity = Entity {
+            root: Value::Object(Object {
+                typename: "bldg:Building".into(),
+                attributes: Map::default(),
+                stereotype: ObjectStereotype::Feature {
+                    id: "bldg_1".into(),
+                    geometries: vec![
+                        GeometryRef {
+                            ty: GeometryType::Surface,
+                            lod: 0,
+                            pos: 0,
+                            len: 1,
+                        },
+                        GeometryRef {
+                            ty: GeometryType::Solid,
+                            lod: 1,
+                            pos: 1,
+                            len: 2,
+                        },
+                    ],
+                },
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(GeometryStore {
+                vertices: vec![
+                    [0., 0., 0.],
+                    [10., 0., 0.],
+                    [10., 10., 0.],
+                    [0., 10., 0.],
+                    [0., 0., 5.],
+                    [10., 0., 5.],
+                    [10., 10., 5.],
+                    [5., 12., 5.],
+                    [0., 10., 5.],
+                ],
+                multipolygon: mpoly,
+                ..Default::default()
+            })
+            .into(),
+            appearance_store: Default::default(),
+        };
+
+        let feature = make_feature(&entity).unwrap();
+        assert_eq!(feature.id, "bldg_1");
+        let (layer, footprint) = &feature.entities[0];
+        assert_eq!(layer.name, "bldg_Building_LOD0");
+        assert!(
+            matches!(footprint, DxfEntity::Polyline { vertices, closed: true } if vertices.len() == 4)
+        );
+        let faces = &feature.entities[1..];
+        // 1 quadrilateral + 3 triangles
+        assert_eq!(faces.len(), 4);
+        assert!(faces.iter().all(
+            |(layer, e)| layer.name == "bldg_Building_LOD1" && matches!(e, DxfEntity::Face(_))
+        ));
+        assert_eq!(
+            faces[0].1,
+            DxfEntity::Face([[0., 0., 0.], [10., 0., 0.], [10., 0., 5.], [0., 0., 5.]])
+        );
+
+        let mut extent = None;
+        for (_, e) in &feature.entities {
+            expand_extent(&mut extent, e);
+        }
+        assert_eq!(extent, Some([[0., 0., 0.], [10., 12., 5.]]));
+    }
+
+    #[test]
+    fn test_layer_name() {
+        assert_eq!(layer_name("bldg:Building", 1), "bldg_Building_LOD1");
+        assert_eq!(
+            layer_name("uro:UndergroundBuilding", 0),
+            "uro_UndergroundBuilding_LOD0"
+        );
+        assert_eq!(layer_color("bldg:Building"), 7);
+    }
+}
@@ -0,0 +1,243 @@
+//! Minimal ASCII DXF (AutoCAD R12) writer
+//!
+//! R12 (AC1009) is the most widely supported revision of DXF, and it does not require the object handles.
+
+use std::{
+    fmt::Display,
+    io::{self, Write},
+};
+
+/// Registered application name of the extended data holding the feature IDs
+pub const APP_NAME: &str = "PLATEAU";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DxfEntity {
+    /// Triangle or quadrilateral (the 4th vertex is the same as the 3rd for a triangle)
+    Face([[f64; 3]; 4]),
+    /// 3D polyline
+    Polyline {
+        vertices: Vec<[f64; 3]>,
+        closed: bool,
+    },
+    Point([f64; 3]),
+}
+
+/// A layer and its color (AutoCAD Color Index)
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Layer {
+    pub name: String,
+    pub color: i16,
+}
+
+pub struct DxfWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> DxfWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn pair(&mut self, code: i32, value: impl Display) -> io::Result<()> {
+        write!(self.writer, "{code:>3}\r\n{value}\r\n")
+    }
+
+    fn point(&mut self, code: i32, [x, y, z]: [f64; 3]) -> io::Result<()> {
+        self.pair(code, x)?;
+        self.pair(code + 10, y)?;
+        self.pair(code + 20, z)
+    }
+
+    fn begin_section(&mut self, name: &str) -> io::Result<()> {
+        self.pair(0, "SECTION")?;
+        self.pair(2, name)
+    }
+
+    fn end_section(&mut self) -> io::Result<()> {
+        self.pair(0, "ENDSEC")
+    }
+
+    /// Writes the HEADER, TABLES and BLOCKS sections, and begins the ENTITIES section
+    pub fn write_preamble(
+        &mut self,
+        layers: &[Layer],
+        extent: Option<[[f64; 3]; 2]>,
+    ) -> io::Result<()> {
+        self.begin_section("HEADER")?;
+        self.pair(9, "$ACADVER")?;
+        self.pair(1, "AC1009")?;
+        if let Some([min, max]) = extent {
+            self.pair(9, "$EXTMIN")?;
+            self.point(10, min)?;
+            self.pair(9, "$EXTMAX")?;
+            self.point(10, max)?;
+        }
+        self.end_section()?;
+
+        self.begin_section("TABLES")?;
+        self.pair(0, "TABLE")?;
+        self.pair(2, "LTYPE")?;
+        self.pair(70, 1)?;
+        self.pair(0, "LTYPE")?;
+        self.pair(2, "CONTINUOUS")?;
+        self.pair(70, 0)?;
+        self.pair(3, "Solid line")?;
+        self.pair(72, 65)?;
+        self.pair(73, 0)?;
+        self.pair(40, 0.0)?;
+        self.pair(0, "ENDTAB")?;
+
+        self.pair(0, "TABLE")?;
+        self.pair(2, "LAYER")?;
+        self.pair(70, layers.len() + 1)?;
+        let default_layer = Layer {
+            name: "0".to_string(),
+            color: 7,
+        };
+        for layer in std::iter::once(&default_layer).chain(layers) {
+            self.pair(0, "LAYER")?;
+            self.pair(2, &layer.name)?;
+            self.pair(70, 0)?;
+            self.pair(62, layer.color)?;
+            self.pair(6, "CONTINUOUS")?;
+        }
+        self.pair(0, "ENDTAB")?;
+
+        self.pair(0, "TABLE")?;
+        self.pair(2, "APPID")?;
+        self.pair(70, 1)?;
+        self.pair(0, "APPID")?;
+        self.pair(2, APP_NAME)?;
+        self.pair(70, 0)?;
+        self.pair(0, "ENDTAB")?;
+        self.end_section()?;
+
+        self.begin_section("BLOCKS")?;
+        self.end_section()?;
+
+        self.begin_section("ENTITIES")
+    }
+
+    /// Writes an entity with the feature ID as the extended data
+    pub fn write_entity(&mut self, layer: &str, id: &str, entity: &DxfEntity) -> io::Result<()> {
+        match entity {
+            DxfEntity::Face(vertices) => {
+                self.pair(0, "3DFACE")?;
+                self.pair(8, layer)?;
+                for (i, v) in vertices.iter().enumerate() {
+                    self.point(10 + i as i32, *v)?;
+                }
+                self.write_id(id)?;
+            }
+            DxfEntity::Polyline { vertices, closed } => {
+                self.pair(0, "POLYLINE")?;
+                self.pair(8, layer)?;
+                self.pair(66, 1)?;
+                self.point(10, [0.0; 3])?;
+                // 8: 3D polyline, 1: closed
+                self.pair(70, if *closed { 9 } else { 8 })?;
+                self.write_id(id)?;
+                for v in vertices {
+                    self.pair(0, "VERTEX")?;
+                    self.pair(8, layer)?;
+                    self.point(10, *v)?;
+                    // 32: 3D polyline vertex
+                    self.pair(70, 32)?;
+                }
+                self.pair(0, "SEQEND")?;
+                self.pair(8, layer)?;
+            }
+            DxfEntity::Point(v) => {
+                self.pair(0, "POINT")?;
+                self.pair(8, layer)?;
+                self.point(10, *v)?;
+                self.write_id(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_id(&mut self, id: &str) -> io::Result<()> {
+        self.pair(1001, APP_NAME)?;
+        // a string in the extended data is limited to 255 bytes
+        self.pair(1000, truncate(id, 255))
+    }
+
+    /// Ends the ENTITIES section and the file
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.end_section()?;
+        self.pair(0, "EOF")?;
+        self.writer.flush()
+    }
+}
+
+fn truncate(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_dxf() {
+        let mut writer = DxfWriter::new(Vec::new());
+        let layers = vec![Layer {
+            name: "bldg_Building_LOD1".to_string(),
+            color: 7,
+        }];
+        writer
+            .write_preamble(&layers, Some([[0.0; 3], [1.0, 1.0, 2.0]]))
+            .unwrap();
+        writer
+            .write_entity(
+                "bldg_Building_LOD1",
+                "bldg_1",
+                &DxfEntity::Face([[0., 0., 0.], [1., 0., 0.], [1., 1., 0.], [1., 1., 0.]]),
+            )
+            .unwrap();
+        writer
+            .write_entity(
+                "bldg_Building_LOD1",
+                "bldg_1",
+                &DxfEntity::Polyline {
+                    vertices: vec![[0., 0., 0.], [1., 0., 0.], [1., 1., 0.]],
+                    closed: true,
+                },
+            )
+            .unwrap();
+        writer.finish().unwrap();
+
+        let content = String::from_utf8(writer.into_inner()).unwrap();
+        let lines: Vec<&str> = content.split("\r\n").map(|line| line.trim()).collect();
+        // pairs of the group code and the value
+        assert_eq!(lines.len() % 2, 1);
+        assert_eq!(&lines[lines.len() - 3..], ["0", "EOF", ""]);
+        let pairs: Vec<(&str, &str)> = lines.chunks_exact(2).map(|c| (c[0], c[1])).collect();
+        assert!(pairs.contains(&("1", "AC1009")));
+        assert!(pairs.contains(&("2", "bldg_Building_LOD1")));
+        assert_eq!(pairs.iter().filter(|p| **p == ("0", "VERTEX")).count(), 3);
+        assert_eq!(
+            pairs.iter().filter(|p| **p == ("1000", "bldg_1")).count(),
+            2
+        );
+        assert!(pairs.contains(&("70", "9")));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("abc", 255), "abc");
+        assert_eq!(truncate("建物", 4), "建");
+    }
+}
@@ -0,0 +1,530 @@
+//! FBX sink
+//!
+//! Writes a binary FBX (7.4) file with a mesh model for each feature, grouped by the feature types,
+//! so that the city models can be imported into Maya or 3ds Max keeping the hierarchy and the feature IDs.
+//! The textures are copied into the `{name}_textures` directory next to the FBX file and referenced by relative paths.
+
+mod scene;
+mod writer;
+
+use std::{
+    fs::File,
+    hash::Hash,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use earcut::{utils3d::project3d_to_2d, Earcut};
+use hashbrown::HashMap;
+use indexmap::IndexSet;
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+    GeometryType,
+};
+use nusamai_plateau::{appearance, Entity};
+use nusamai_projection::{
+    cartesian::geodetic_to_geocentric,
+    crs::{
+        EpsgCode, EPSG_JGD2011_GEOGRAPHIC_2D, EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_WGS84_GEOGRAPHIC_2D,
+        EPSG_WGS84_GEOGRAPHIC_3D,
+    },
+    ellipsoid::Ellipsoid,
+};
+use rayon::prelude::*;
+use scene::{build_scene, texture_relative_path, TextureFile};
+use url::Url;
+use writer::write_fbx;
+
+use super::option::output_parameter;
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    paths,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer::{underground_config, use_lod_config, KeyValueSpec, TransformerSettings},
+};
+
+pub struct FbxSinkProvider {}
+
+impl DataSinkProvider for FbxSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "fbx".to_string(),
+            name: "FBX".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(use_lod_config("max_lod", Some(&["textured_max_lod"])));
+        settings.insert(underground_config());
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let transform_settings = self.transformer_options();
+
+        Box::<FbxSink>::new(FbxSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            transform_settings,
+        })
+    }
+}
+
+pub struct FbxSink {
+    output_path: PathBuf,
+    transform_settings: TransformerSettings,
+}
+
+/// Material shared by the features (the base color and the texture)
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FbxMaterial {
+    pub base_color: [f32; 4],
+    pub texture: Option<Url>,
+}
+
+impl Eq for FbxMaterial {}
+
+impl Hash for FbxMaterial {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.base_color.iter().for_each(|c| c.to_bits().hash(state));
+        self.texture.hash(state);
+    }
+}
+
+/// Triangulated mesh of a feature
+pub struct FbxFeature {
+    pub typename: String,
+    pub id: String,
+    /// Attributes as strings (written as the user properties of the model)
+    pub attributes: Vec<(String, String)>,
+    /// Vertices of the triangles (3 vertices per triangle)
+    pub positions: Vec<[f64; 3]>,
+    pub uvs: Vec<[f64; 2]>,
+    /// Index of the material (in the shared materials) of each triangle
+    pub materials: Vec<u32>,
+}
+
+impl DataSink for FbxSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        let default_requirements = DataRequirements {
+            resolve_appearance: true,
+            key_value: KeyValueSpec::JsonifyObjectsAndArrays,
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        let ellipsoid = nusamai_projection::ellipsoid::wgs84();
+        let geographic = is_geographic(schema.epsg.unwrap_or(EPSG_WGS84_GEOGRAPHIC_3D));
+
+        let materials: Mutex<IndexSet<FbxMaterial>> = Default::default();
+        let features: Mutex<Vec<FbxFeature>> = Default::default();
+
+        upstream.into_iter().par_bridge().try_for_each(|parcel| {
+            feedback.ensure_not_canceled()?;
+
+            if let Some(feature) = make_feature(&parcel.entity, &materials, geographic, &ellipsoid)
+            {
+                features.lock().unwrap().push(feature);
+            }
+            Ok::<(), PipelineError>(())
+        })?;
+
+        let mut features = features.into_inner().unwrap();
+        let materials: Vec<FbxMaterial> = materials.into_inner().unwrap().into_iter().collect();
+        if features.is_empty() {
+            feedback.warn("No features with polygons to write".to_string());
+        }
+        // group the features by the types (and make the output reproducible)
+        features.par_sort_by(|a, b| (&a.typename, &a.id).cmp(&(&b.typename, &b.id)));
+
+        // Move the origin to the center of the features (Y-up, meters)
+        let to_local = LocalFrame::new(&features, geographic, &ellipsoid);
+        features.par_iter_mut().for_each(|feature| {
+            feature
+                .positions
+                .iter_mut()
+                .for_each(|position| *position = to_local.transform(*position, &ellipsoid));
+        });
+
+        feedback.ensure_not_canceled()?;
+        let textures = copy_textures(&self.output_path, &materials, feedback)?;
+
+        feedback.ensure_not_canceled()?;
+        let nodes = build_scene(&features, &materials, &textures);
+        let mut writer = BufWriter::with_capacity(1024 * 1024, File::create(&self.output_path)?);
+        write_fbx(&mut writer, &nodes)?;
+        writer.flush()?;
+
+        feedback.info(format!(
+            "Wrote {} features with {} materials",
+            features.len(),
+            materials.len()
+        ));
+        Ok(())
+    }
+}
+
+fn is_geographic(epsg: EpsgCode) -> bool {
+    matches!(
+        epsg,
+        EPSG_WGS84_GEOGRAPHIC_2D
+            | EPSG_WGS84_GEOGRAPHIC_3D
+            | EPSG_JGD2011_GEOGRAPHIC_2D
+            | EPSG_JGD2011_GEOGRAPHIC_3D
+    )
+}
+
+/// Triangulates the polygons of the entity with their materials. Returns `None` if it has no polygons.
+fn make_feature(
+    entity: &Entity,
+    materials: &Mutex<IndexSet<FbxMaterial>>,
+    geographic: bool,
+    ellipsoid: &Ellipsoid,
+) -> Option<FbxFeature> {
+    let Value::Object(obj) = &entity.root else {
+        return None;
+    };
+    let ObjectStereotype::Feature { id, geometries } = &obj.stereotype else {
+        return None;
+    };
+
+    let geom_store = entity.geometry_store.read().unwrap();
+    let appearance_store = entity.appearance_store.read().unwrap();
+    let default_material = appearance::Material::default();
+
+    let mut feature = FbxFeature {
+        typename: obj.typename.to_string(),
+        id: id.clone(),
+        attributes: Vec::new(),
+        positions: Vec::new(),
+        uvs: Vec::new(),
+        materials: Vec::new(),
+    };
+    // materials of this feature (mapped to the shared materials at the end)
+    let mut local_materials: IndexSet<FbxMaterial> = IndexSet::new();
+
+    let mut earcutter = Earcut::new();
+    let mut buf3d: Vec<[f64; 3]> = Vec::new();
+    let mut buf2d: Vec<[f64; 2]> = Vec::new();
+    let mut index_buf: Vec<u32> = Vec::new();
+
+    for entry in geometries {
+        if !matches!(
+            entry.ty,
+            GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+        ) {
+            continue;
+        }
+        let range = entry.pos as usize..(entry.pos + entry.len) as usize;
+        for ((poly_idx, poly), poly_uv) in range
+            .clone()
+            .zip(geom_store.multipolygon.iter_range(range.clone()))
+            .zip(geom_store.polygon_uvs.iter_range(range))
+        {
+            let orig_mat = geom_store
+                .polygon_materials
+                .get(poly_idx)
+                .copied()
+                .flatten()
+                .and_then(|idx| appearance_store.materials.get(idx as usize))
+                .unwrap_or(&default_material);
+            let orig_tex = geom_store
+                .polygon_textures
+                .get(poly_idx)
+                .copied()
+                .flatten()
+                .and_then(|idx| appearance_store.textures.get(idx as usize));
+            let (material, _) = local_materials.insert_full(FbxMaterial {
+                base_color: orig_mat.diffuse_color.into(),
+                texture: orig_tex.map(|tex| tex.image_url.clone()),
+            });
+
+            // Earcut does not work in the geographic space
+            let indices = poly.raw_coords();
+            buf3d.clear();
+            buf3d.extend(indices.iter().map(|&idx| {
+                let [x, y, z] = geom_store.vertices[idx as usize];
+                match geographic {
+                    true => {
+                        let (x, y, z) = geodetic_to_geocentric(ellipsoid, x, y, z);
+                        [x, y, z]
+                    }
+                    false => [x, y, z],
+                }
+            }));
+            let num_outer = match poly.hole_indices().first() {
+                Some(&v) => v as usize,
+                None => indices.len(),
+            };
+            if !project3d_to_2d(&buf3d, num_outer, &mut buf2d) {
+                continue;
+            }
+            earcutter.earcut(buf2d.iter().cloned(), poly.hole_indices(), &mut index_buf);
+
+            let uvs = poly_uv.raw_coords();
+            for tri in index_buf.chunks_exact(3) {
+                for &i in tri {
+                    feature
+                        .positions
+                        .push(geom_store.vertices[indices[i as usize] as usize]);
+                    feature
+                        .uvs
+                        .push(uvs.get(i as usize).copied().unwrap_or_default());
+                }
+                feature.materials.push(material as u32);
+            }
+        }
+    }
+    if feature.positions.is_empty() {
+        return None;
+    }
+
+    let mapping: Vec<u32> = {
+        let mut materials = materials.lock().unwrap();
+        local_materials
+            .into_iter()
+            .map(|material| materials.insert_full(material).0 as u32)
+            .collect()
+    };
+    feature
+        .materials
+        .iter_mut()
+        .for_each(|material| *material = mapping[*material as usize]);
+
+    feature.attributes = obj
+        .attributes
+        .iter()
+        .filter_map(|(key, value)| match value.to_attribute_json() {
+            serde_json::Value::Null => None,
+            serde_json::Value::String(s) => Some((key.clone(), s)),
+            value => Some((key.clone(), value.to_string())),
+        })
+        .collect();
+    Some(feature)
+}
+
+/// Transforms the coordinates into the local Y-up coordinates (meters) around the center of the features
+struct LocalFrame {
+    geographic: bool,
+    center: [f64; 3],
+    /// Geocentric coordinates of the center (for geographic CRSs)
+    origin: [f64; 3],
+    /// (sin, cos) of the latitude and the longitude of the center
+    lat: (f64, f64),
+    lng: (f64, f64),
+}
+
+impl LocalFrame {
+    fn new(features: &[FbxFeature], geographic: bool, ellipsoid: &Ellipsoid) -> Self {
+        let mut min = [f64::MAX; 3];
+        let mut max = [f64::MIN; 3];
+        for position in features.iter().flat_map(|f| &f.positions) {
+            for ((min, max), v) in min.iter_mut().zip(max.iter_mut()).zip(position) {
+                *min = min.min(*v);
+                *max = max.max(*v);
+            }
+        }
+        let center = match features.is_empty() {
+            true => [0.; 3],
+            // (the ground level is kept as the origin)
+            false => [(min[0] + max[0]) / 2., (min[1] + max[1]) / 2., 0.],
+        };
+        let (x, y, z) = match geographic {
+            true => geodetic_to_geocentric(ellipsoid, center[0], center[1], 0.),
+            false => (0., 0., 0.),
+        };
+        Self {
+            geographic,
+            center,
+            origin: [x, y, z],
+            lat: center[1].to_radians().sin_cos(),
+            lng: center[0].to_radians().sin_cos(),
+        }
+    }
+
+    fn transform(&self, position: [f64; 3], ellipsoid: &Ellipsoid) -> [f64; 3] {
+        let [east, north, up] = match self.geographic {
+            true => {
+                let (x, y, z) =
+                    geodetic_to_geocentric(ellipsoid, position[0], position[1], position[2]);
+                let [dx, dy, dz] = [x - self.origin[0], y - self.origin[1], z - self.origin[2]];
+                let ((sin_lat, cos_lat), (sin_lng, cos_lng)) = (self.lat, self.lng);
+                [
+                    -sin_lng * dx + cos_lng * dy,
+                    -sin_lat * cos_lng * dx - sin_lat * sin_lng * dy + cos_lat * dz,
+                    cos_lat * cos_lng * dx + cos_lat * sin_lng * dy + sin_lat * dz,
+                ]
+            }
+            false => [
+                position[0] - self.center[0],
+                position[1] - self.center[1],
+                position[2],
+            ],
+        };
+        // Y-up, and the north is -Z
+        [east, up, -north]
+    }
+}
+
+/// Copies the texture images into the `{name}_textures` directory next to the FBX file.
+/// The materials whose images cannot be copied are written without the textures.
+fn copy_textures(
+    output_path: &Path,
+    materials: &[FbxMaterial],
+    feedback: &Feedback,
+) -> Result<Vec<Option<TextureFile>>> {
+    let stem = output_path
+        .file_stem()
+        .ok_or_else(|| PipelineError::Other("Invalid output path".to_string()))?
+        .to_string_lossy();
+    let texture_dir = format!("{stem}_textures");
+    let base_dir = output_path.parent().unwrap_or(Path::new(""));
+
+    let mut copied: HashMap<&Url, Option<String>> = HashMap::new();
+    let mut failures = 0;
+    let mut textures = Vec::with_capacity(materials.len());
+    for material in materials {
+        let Some(url) = &material.texture else {
+            textures.push(None);
+            continue;
+        };
+        let relative_path = copied.entry(url).or_insert_with(|| {
+            let source = paths::url_to_path(url)?;
+            let relative_path = texture_relative_path(&texture_dir, &source)?;
+            let dest = paths::extended(&base_dir.join(&relative_path));
+            let result = dest
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::copy(&source, &dest));
+            match result {
+                Ok(_) => Some(relative_path),
+                Err(_) => {
+                    failures += 1;
+                    None
+                }
+            }
+        });
+        textures.push(relative_path.as_ref().map(|relative_path| {
+            let absolute_path = base_dir.join(relative_path);
+            TextureFile {
+                relative_path: relative_path.clone(),
+                absolute_path: std::path::absolute(&absolute_path)
+                    .unwrap_or(absolute_path)
+                    .to_string_lossy()
+                    .into_owned(),
+            }
+        }));
+    }
+    if failures > 0 {
+        feedback.warn(format!(
+            "{failures} texture images could not be copied, and the materials are written without them"
+        ));
+    }
+    Ok(textures)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::{
+        object::{Map, Object},
+        GeometryRef, GeometryStore,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_make_feature() {
+        let mut geoms = GeometryStore {
+            epsg: 6677,
+            vertices: vec![[0., 0., 5.], [10., 0., 5.], [10., 10., 5.], [0., 10., 5.]],
+            ..Default::default()
+        };
+        geoms.multipolygon.add_exterior([0, 1, 2, 3]);
+        geoms
+            .polygon_uvs
+            .add_exterior([[0., 0.], [1., 0.], [1., 1.], [0., 1.]]);
+        geoms.polygon_materials.push(None);
+        geoms.polygon_textures.push(None);
+        geoms.ring_ids.push(None);
+
+        let mut attributes = Map::default();
+        attributes.insert("bldg:measuredHeight".into(), Value::Double(12.5));
+        let entity = Entity {
+            root: Value::Object(Object {
+                typename: "bldg:Building".into(),
+                attributes,
+                stereotype: ObjectStereotype::Feature {
+                    id: "bldg_1".into(),
+                    geometries: vec![GeometryRef {
+                        ty: GeometryType::Surface,
+                        lod: 1,
+                        pos: 0,
+                        len: 1,
+                    }],
+                },
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(geoms).into(),
+            appearance_store: Default::default(),
+        };
+
+        let ellipsoid = nusamai_projection::ellipsoid::wgs84();
+        let materials = Mutex::default();
+        let feature = make_feature(&entity, &materials, false, &ellipsoid).unwrap();
+        assert_eq!(feature.positions.len(), 6);
+        assert_eq!(feature.uvs.len(), 6);
+        assert_eq!(feature.materials, [0, 0]);
+        assert_eq!(materials.lock().unwrap().len(), 1);
+        assert_eq!(
+            feature.attributes,
+            [("bldg:measuredHeight".to_string(), "12.5".to_string())]
+        );
+
+        let frame = LocalFrame::new(&[feature], false, &ellipsoid);
+        assert_eq!(frame.transform([10., 10., 5.], &ellipsoid), [5., 5., -5.]);
+    }
+
+    #[test]
+    fn test_local_frame_geographic() {
+        let ellipsoid = nusamai_projection::ellipsoid::wgs84();
+        let feature = FbxFeature {
+            typename: "bldg:Building".into(),
+            id: "bldg_1".into(),
+            attributes: vec![],
+            positions: vec![[139.0, 35.0, 0.], [139.002, 35.002, 30.]],
+            uvs: vec![],
+            materials: vec![],
+        };
+        let frame = LocalFrame::new(&[feature], true, &ellipsoid);
+
+        let [x, y, z] = frame.transform([139.001, 35.001, 10.], &ellipsoid);
+        assert!(x.abs() < 1e-6 && z.abs() < 1e-6);
+        assert!((y - 10.).abs() < 1e-6);
+        // about 91 m to the east and 111 m to the north (-Z)
+        let [x, _, z] = frame.transform([139.002, 35.002, 10.], &ellipsoid);
+        assert!((x - 91.).abs() < 1.0, "{x}");
+        assert!((z + 111.).abs() < 1.0, "{z}");
+    }
+}
@@ -0,0 +1,502 @@
+//! FBX scene graph: a `Null` model for each feature type, and a `Mesh` model for each feature under it
+
+use std::path::Path;
+
+use indexmap::IndexMap;
+use rayon::prelude::*;
+
+use super::{
+    writer::{p, Node, Property, FBX_VERSION},
+    FbxFeature, FbxMaterial,
+};
+
+/// Constants of the files made by the FBX SDK, to keep the output reproducible
+const FILE_ID: &[u8] = b"\x28\xb3\x2a\xeb\xb6\x24\xcc\xc2\xbf\xc8\xb0\x2a\xa9\x2b\xfc\xf1";
+const CREATION_TIME: &str = "1970-01-01 10:00:00:000";
+const CREATOR: &str = "PLATEAU GIS Converter (nusamai)";
+
+/// A texture image copied next to the FBX file
+pub struct TextureFile {
+    /// Path relative to the FBX file (separated by `/`)
+    pub relative_path: String,
+    pub absolute_path: String,
+}
+
+/// Builds the top-level nodes of the FBX file.
+///
+/// `features` must be sorted by the type names. The positions of the features are in the local Y-up coordinates (meters).
+/// `textures` are the texture files of the materials (`None` if the material has no texture).
+pub fn build_scene(
+    features: &[FbxFeature],
+    materials: &[FbxMaterial],
+    textures: &[Option<TextureFile>],
+) -> Vec<Node> {
+    let mut ids = IdAllocator::default();
+    let mut objects = Node::new("Objects");
+    let mut connections = Node::new("Connections");
+
+    // feature types
+    let mut type_ids: IndexMap<&str, i64> = IndexMap::new();
+    for feature in features {
+        if type_ids.contains_key(feature.typename.as_str()) {
+            continue;
+        }
+        let model_id = ids.allocate();
+        let attribute_id = ids.allocate();
+        let name = feature.typename.replace(':', "_");
+        objects.add_child(
+            Node::new("NodeAttribute")
+                .prop(attribute_id)
+                .prop(Property::object_name(&name, "NodeAttribute"))
+                .prop("Null")
+                .child(Node::new("TypeFlags").prop("Null"))
+                .child(Node::new("Properties70")),
+        );
+        objects.add_child(model_node(
+            model_id,
+            &name,
+            "Null",
+            Node::new("Properties70"),
+        ));
+        connections.add_child(connection_oo(model_id, 0));
+        connections.add_child(connection_oo(attribute_id, model_id));
+        type_ids.insert(&feature.typename, model_id);
+    }
+
+    // materials and textures
+    let mut material_ids = Vec::with_capacity(materials.len());
+    let mut num_textures = 0;
+    for (i, (material, texture)) in materials.iter().zip(textures).enumerate() {
+        let material_id = ids.allocate();
+        let name = format!("material_{i}");
+        objects.add_child(material_node(material_id, &name, material));
+        material_ids.push(material_id);
+
+        if let Some(texture) = texture {
+            let texture_id = ids.allocate();
+            let video_id = ids.allocate();
+            let name = format!("texture_{i}");
+            objects.add_child(texture_node(texture_id, &name, texture));
+            objects.add_child(video_node(video_id, &name, texture));
+            connections.add_child(
+                Node::new("C")
+                    .prop("OP")
+                    .prop(texture_id)
+                    .prop(material_id)
+                    .prop("DiffuseColor"),
+            );
+            connections.add_child(connection_oo(video_id, texture_id));
+            num_textures += 1;
+        }
+    }
+
+    // features (the geometries are encoded in parallel)
+    let feature_ids: Vec<(i64, i64)> = features
+        .iter()
+        .map(|_| (ids.allocate(), ids.allocate()))
+        .collect();
+    let feature_nodes: Vec<(Node, Node, Vec<u32>)> = features
+        .par_iter()
+        .zip(&feature_ids)
+        .map(|(feature, &(model_id, geometry_id))| {
+            let (geometry, used_materials) = geometry_node(geometry_id, feature);
+            let mut properties = Node::new("Properties70");
+            for (key, value) in &feature.attributes {
+                properties.add_child(p(key, "KString", "", "U", vec![value.as_str().into()]));
+            }
+            let model = model_node(model_id, &feature.id, "Mesh", properties);
+            (model, geometry, used_materials)
+        })
+        .collect();
+    for ((feature, (model, geometry, used_materials)), &(model_id, geometry_id)) in
+        features.iter().zip(feature_nodes).zip(&feature_ids)
+    {
+        objects.add_child(geometry);
+        objects.add_child(model);
+        connections.add_child(connection_oo(model_id, type_ids[feature.typename.as_str()]));
+        connections.add_child(connection_oo(geometry_id, model_id));
+        // (the order of the materials connected to the model is the index in the geometry)
+        for material in used_materials {
+            connections.add_child(connection_oo(material_ids[material as usize], model_id));
+        }
+    }
+
+    let definitions = Node::new("Definitions")
+        .child(Node::new("Version").prop(100))
+        .child(Node::new("Count").prop(
+            (1 + type_ids.len() * 2 + features.len() * 2 + materials.len() + num_textures * 2)
+                as i32,
+        ))
+        .child(object_type("GlobalSettings", 1))
+        .child(object_type("NodeAttribute", type_ids.len()))
+        .child(object_type("Model", type_ids.len() + features.len()))
+        .child(object_type("Geometry", features.len()))
+        .child(object_type("Material", materials.len()))
+        .child(object_type("Texture", num_textures))
+        .child(object_type("Video", num_textures));
+
+    vec![
+        header_extension(),
+        Node::new("FileId").prop(Property::Raw(FILE_ID.to_vec())),
+        Node::new("CreationTime").prop(CREATION_TIME),
+        Node::new("Creator").prop(CREATOR),
+        global_settings(),
+        Node::new("Documents")
+            .child(Node::new("Count").prop(1))
+            .child(
+                Node::new("Document")
+                    .prop(ids.allocate())
+                    .prop("")
+                    .prop("Scene")
+                    .child(
+                        Node::new("Properties70")
+                            .child(p("SourceObject", "object", "", "", vec![]))
+                            .child(p("ActiveAnimStackName", "KString", "", "", vec!["".into()])),
+                    )
+                    .child(Node::new("RootNode").prop(0_i64)),
+            ),
+        Node::new("References"),
+        definitions,
+        objects,
+        connections,
+    ]
+}
+
+/// Unique IDs of the objects (0 is the root node)
+#[derive(Default)]
+struct IdAllocator {
+    last: i64,
+}
+
+impl IdAllocator {
+    fn allocate(&mut self) -> i64 {
+        self.last += 1;
+        1_000_000 + self.last
+    }
+}
+
+fn connection_oo(child: i64, parent: i64) -> Node {
+    Node::new("C").prop("OO").prop(child).prop(parent)
+}
+
+fn object_type(name: &str, count: usize) -> Node {
+    Node::new("ObjectType")
+        .prop(name)
+        .child(Node::new("Count").prop(count as i32))
+}
+
+fn header_extension() -> Node {
+    Node::new("FBXHeaderExtension")
+        .child(Node::new("FBXHeaderVersion").prop(1003))
+        .child(Node::new("FBXVersion").prop(FBX_VERSION as i32))
+        .child(
+            Node::new("CreationTimeStamp")
+                .child(Node::new("Version").prop(1000))
+                .child(Node::new("Year").prop(1970))
+                .child(Node::new("Month").prop(1))
+                .child(Node::new("Day").prop(1))
+                .child(Node::new("Hour").prop(10))
+                .child(Node::new("Minute").prop(0))
+                .child(Node::new("Second").prop(0))
+                .child(Node::new("Millisecond").prop(0)),
+        )
+        .child(Node::new("Creator").prop(CREATOR))
+}
+
+/// Y-up, right-handed, and 1 unit = 1 meter (100 cm)
+fn global_settings() -> Node {
+    let int = |name: &str, value: i32| p(name, "int", "Integer", "", vec![value.into()]);
+    let double = |name: &str, value: f64| p(name, "double", "Number", "", vec![value.into()]);
+    Node::new("GlobalSettings")
+        .child(Node::new("Version").prop(1000))
+        .child(
+            Node::new("Properties70")
+                .child(int("UpAxis", 1))
+                .child(int("UpAxisSign", 1))
+                .child(int("FrontAxis", 2))
+                .child(int("FrontAxisSign", 1))
+                .child(int("CoordAxis", 0))
+                .child(int("CoordAxisSign", 1))
+                .child(int("OriginalUpAxis", 1))
+                .child(int("OriginalUpAxisSign", 1))
+                .child(double("UnitScaleFactor", 100.0))
+                .child(double("OriginalUnitScaleFactor", 100.0)),
+        )
+}
+
+fn model_node(id: i64, name: &str, ty: &str, properties: Node) -> Node {
+    Node::new("Model")
+        .prop(id)
+        .prop(Property::object_name(name, "Model"))
+        .prop(ty)
+        .child(Node::new("Version").prop(232))
+        .child(properties)
+        .child(Node::new("Shading").prop(true))
+        .child(Node::new("Culling").prop("CullingOff"))
+}
+
+fn material_node(id: i64, name: &str, material: &FbxMaterial) -> Node {
+    let [r, g, b, a] = material.base_color.map(f64::from);
+    Node::new("Material")
+        .prop(id)
+        .prop(Property::object_name(name, "Material"))
+        .prop("")
+        .child(Node::new("Version").prop(102))
+        .child(Node::new("ShadingModel").prop("lambert"))
+        .child(Node::new("MultiLayer").prop(0))
+        .child(
+            Node::new("Properties70")
+                .child(p(
+                    "DiffuseColor",
+                    "Color",
+                    "",
+                    "A",
+                    vec![r.into(), g.into(), b.into()],
+                ))
+                .child(p(
+                    "TransparencyFactor",
+                    "Number",
+                    "",
+                    "A",
+                    vec![(1.0 - a).into()],
+                ))
+                .child(p("Opacity", "double", "Number", "", vec![a.into()])),
+        )
+}
+
+fn texture_node(id: i64, name: &str, texture: &TextureFile) -> Node {
+    Node::new("Texture")
+        .prop(id)
+        .prop(Property::object_name(name, "Texture"))
+        .prop("")
+        .child(Node::new("Type").prop("TextureVideoClip"))
+        .child(Node::new("Version").prop(202))
+        .child(Node::new("TextureName").prop(Property::object_name(name, "Texture")))
+        .child(Node::new("Media").prop(Property::object_name(name, "Video")))
+        .child(Node::new("FileName").prop(texture.absolute_path.as_str()))
+        .child(Node::new("RelativeFilename").prop(texture.relative_path.as_str()))
+        .child(Node::new("ModelUVTranslation").prop(0.0).prop(0.0))
+        .child(Node::new("ModelUVScaling").prop(1.0).prop(1.0))
+        .child(Node::new("Texture_Alpha_Source").prop("None"))
+        .child(Node::new("Cropping").prop(0).prop(0).prop(0).prop(0))
+}
+
+fn video_node(id: i64, name: &str, texture: &TextureFile) -> Node {
+    Node::new("Video")
+        .prop(id)
+        .prop(Property::object_name(name, "Video"))
+        .prop("Clip")
+        .child(Node::new("Type").prop("Clip"))
+        .child(Node::new("Properties70").child(p(
+            "Path",
+            "KString",
+            "XRefUrl",
+            "",
+            vec![texture.absolute_path.as_str().into()],
+        )))
+        .child(Node::new("UseMipMap").prop(0))
+        .child(Node::new("Filename").prop(texture.absolute_path.as_str()))
+        .child(Node::new("RelativeFilename").prop(texture.relative_path.as_str()))
+}
+
+/// Encodes the triangles of a feature. Also returns the (global) materials used by the feature,
+/// whose positions are the material indices in the geometry.
+fn geometry_node(id: i64, feature: &FbxFeature) -> (Node, Vec<u32>) {
+    // deduplicate the vertices
+    let mut vertex_map: IndexMap<[u64; 3], i32> = IndexMap::new();
+    let mut polygon_vertex_index = Vec::with_capacity(feature.positions.len());
+    for (i, position) in feature.positions.iter().enumerate() {
+        let next = vertex_map.len() as i32;
+        let idx = *vertex_map.entry(position.map(f64::to_bits)).or_insert(next);
+        // the last index of each polygon is negated (bitwise NOT)
+        polygon_vertex_index.push(if i % 3 == 2 { !idx } else { idx });
+    }
+    let vertices: Vec<f64> = vertex_map
+        .keys()
+        .flat_map(|bits| bits.map(f64::from_bits))
+        .collect();
+
+    let normals: Vec<f64> = feature
+        .positions
+        .chunks_exact(3)
+        .flat_map(|tri| {
+            let [a, b, c] = [0, 1, 2].map(|i| glam::DVec3::from_array(tri[i]));
+            let n = (b - a).cross(c - a).normalize_or_zero().to_array();
+            [n; 3].into_iter().flatten()
+        })
+        .collect();
+    let uvs: Vec<f64> = feature.uvs.iter().flatten().copied().collect();
+    let uv_index: Vec<i32> = (0..feature.uvs.len() as i32).collect();
+
+    let mut used_materials: IndexMap<u32, i32> = IndexMap::new();
+    let material_index: Vec<i32> = feature
+        .materials
+        .iter()
+        .map(|&material| {
+            let next = used_materials.len() as i32;
+            *used_materials.entry(material).or_insert(next)
+        })
+        .collect();
+
+    let layer_element = |ty: &str| {
+        Node::new("LayerElement")
+            .child(Node::new("Type").prop(ty))
+            .child(Node::new("TypedIndex").prop(0))
+    };
+    let node = Node::new("Geometry")
+        .prop(id)
+        .prop(Property::object_name(&feature.id, "Geometry"))
+        .prop("Mesh")
+        .child(Node::new("Properties70"))
+        .child(Node::new("GeometryVersion").prop(124))
+        .child(Node::new("Vertices").prop(Property::doubles(&vertices)))
+        .child(Node::new("PolygonVertexIndex").prop(Property::ints(&polygon_vertex_index)))
+        .child(
+            Node::new("LayerElementNormal")
+                .prop(0)
+                .child(Node::new("Version").prop(101))
+                .child(Node::new("Name").prop(""))
+                .child(Node::new("MappingInformationType").prop("ByPolygonVertex"))
+                .child(Node::new("ReferenceInformationType").prop("Direct"))
+                .child(Node::new("Normals").prop(Property::doubles(&normals))),
+        )
+        .child(
+            Node::new("LayerElementUV")
+                .prop(0)
+                .child(Node::new("Version").prop(101))
+                .child(Node::new("Name").prop("UVMap"))
+                .child(Node::new("MappingInformationType").prop("ByPolygonVertex"))
+                .child(Node::new("ReferenceInformationType").prop("IndexToDirect"))
+                .child(Node::new("UV").prop(Property::doubles(&uvs)))
+                .child(Node::new("UVIndex").prop(Property::ints(&uv_index))),
+        )
+        .child(
+            Node::new("LayerElementMaterial")
+                .prop(0)
+                .child(Node::new("Version").prop(101))
+                .child(Node::new("Name").prop(""))
+                .child(Node::new("MappingInformationType").prop("ByPolygon"))
+                .child(Node::new("ReferenceInformationType").prop("IndexToDirect"))
+                .child(Node::new("Materials").prop(Property::ints(&material_index))),
+        )
+        .child(
+            Node::new("Layer")
+                .prop(0)
+                .child(Node::new("Version").prop(100))
+                .child(layer_element("LayerElementNormal"))
+                .child(layer_element("LayerElementMaterial"))
+                .child(layer_element("LayerElementUV")),
+        );
+    (node, used_materials.into_keys().collect())
+}
+
+/// Relative path of a texture file copied next to the FBX file, keeping the name of the parent directory
+/// of the source (the image names are not unique among the PLATEAU appearance directories)
+pub fn texture_relative_path(texture_dir: &str, source: &Path) -> Option<String> {
+    let file_name = source.file_name()?.to_str()?;
+    match source
+        .parent()
+        .and_then(|dir| dir.file_name())
+        .and_then(|name| name.to_str())
+    {
+        Some(dir) => Some(format!("{texture_dir}/{dir}/{file_name}")),
+        None => Some(format!("{texture_dir}/{file_name}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature(id: &str, typename: &str, materials: Vec<u32>) -> FbxFeature {
+        let positions: Vec<[f64; 3]> = materials
+            .iter()
+            .flat_map(|_| [[0., 0., 0.], [1., 0., 0.], [0., 1., 0.]])
+            .collect();
+        FbxFeature {
+            typename: typename.into(),
+            id: id.into(),
+            attributes: vec![("bldg:class".into(), "普通建物".into())],
+            uvs: vec![[0., 0.]; positions.len()],
+            positions,
+            materials,
+        }
+    }
+
+    fn find<'a>(node: &'a Node, name: &str) -> Vec<&'a Node> {
+        node.children.iter().filter(|n| n.name == name).collect()
+    }
+
+    #[test]
+    fn test_build_scene() {
+        let features = [
+            feature("bldg_1", "bldg:Building", vec![1, 1, 0]),
+            feature("bldg_2", "bldg:Building", vec![0]),
+            feature("tran_1", "tran:Road", vec![0]),
+        ];
+        let materials = [FbxMaterial::default(), FbxMaterial::default()];
+        let textures = [
+            None,
+            Some(TextureFile {
+                relative_path: "city_textures/appearance/a.jpg".into(),
+                absolute_path: "/tmp/city_textures/appearance/a.jpg".into(),
+            }),
+        ];
+        let nodes = build_scene(&features, &materials, &textures);
+        let objects = nodes.iter().find(|n| n.name == "Objects").unwrap();
+
+        // 2 types + 3 features
+        assert_eq!(find(objects, "Model").len(), 5);
+        assert_eq!(find(objects, "Geometry").len(), 3);
+        assert_eq!(find(objects, "Material").len(), 2);
+        assert_eq!(find(objects, "Texture").len(), 1);
+        assert_eq!(find(objects, "Video").len(), 1);
+
+        // the first geometry: 3 triangles sharing the vertices, with local material indices
+        let geometry = find(objects, "Geometry")[0];
+        let vertices = &find(geometry, "Vertices")[0].properties[0];
+        assert_eq!(
+            vertices,
+            &Property::doubles(&[0., 0., 0., 1., 0., 0., 0., 1., 0.])
+        );
+        let materials = &find(find(geometry, "LayerElementMaterial")[0], "Materials")[0];
+        assert_eq!(materials.properties[0], Property::ints(&[0, 0, 1]));
+        let indices = &find(geometry, "PolygonVertexIndex")[0].properties[0];
+        assert_eq!(indices, &Property::ints(&[0, 1, !2, 0, 1, !2, 0, 1, !2]));
+
+        // the materials are connected in the order of the local indices
+        let connections = nodes.iter().find(|n| n.name == "Connections").unwrap();
+        let model_id = find(objects, "Model")[2].properties[0].clone();
+        let connected: Vec<_> = connections
+            .children
+            .iter()
+            .filter(|c| c.properties[2] == model_id)
+            .map(|c| c.properties[1].clone())
+            .collect();
+        let material_ids: Vec<_> = find(objects, "Material")
+            .iter()
+            .map(|m| m.properties[0].clone())
+            .collect();
+        let geometry_id = geometry.properties[0].clone();
+        assert_eq!(
+            connected,
+            [
+                geometry_id,
+                material_ids[1].clone(),
+                material_ids[0].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_texture_relative_path() {
+        assert_eq!(
+            texture_relative_path(
+                "city_textures",
+                Path::new("/data/udx/bldg/52397519_bldg_6697_appearance/hnap0001.jpg")
+            )
+            .unwrap(),
+            "city_textures/52397519_bldg_6697_appearance/hnap0001.jpg"
+        );
+    }
+}
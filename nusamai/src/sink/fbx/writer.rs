@@ -0,0 +1,377 @@
+//! Binary FBX (version 7.4) encoder
+//!
+//! The sizes of the nodes are computed beforehand (the arrays are compressed when the properties are made),
+//! so the file is written sequentially without seeking back to fill in the offsets.
+
+use std::io::{self, Write};
+
+use flate2::{write::ZlibEncoder, Compression};
+
+pub const FBX_VERSION: u32 = 7400;
+
+const MAGIC: &[u8] = b"Kaydara FBX Binary  \x00\x1a\x00";
+/// Length of the null record terminating the nested nodes (three u32 and a u8 in 7.4)
+const NULL_RECORD_LEN: usize = 13;
+const FOOTER_ID: [u8; 16] = [
+    0xfa, 0xbc, 0xab, 0x09, 0xd0, 0xc8, 0xd4, 0x66, 0xb1, 0x76, 0xfb, 0x83, 0x1c, 0xf7, 0x26, 0x7e,
+];
+const FOOTER_MAGIC: [u8; 16] = [
+    0xf8, 0x5a, 0x8c, 0x6a, 0xde, 0xf5, 0xd9, 0x7e, 0xec, 0xe9, 0x0c, 0xe3, 0x75, 0x8f, 0x29, 0x0b,
+];
+/// Arrays larger than this (in bytes) are compressed with zlib
+const COMPRESSION_THRESHOLD: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Property {
+    Bool(bool),
+    Int(i32),
+    Long(i64),
+    Double(f64),
+    String(Vec<u8>),
+    Raw(Vec<u8>),
+    Array {
+        type_code: u8,
+        len: u32,
+        /// 0: raw, 1: zlib
+        encoding: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl Property {
+    /// Name of an object with its class (e.g. `bldg_1\x00\x01Model`)
+    pub fn object_name(name: &str, class: &str) -> Self {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.extend_from_slice(b"\x00\x01");
+        bytes.extend_from_slice(class.as_bytes());
+        Self::String(bytes)
+    }
+
+    pub fn ints(values: &[i32]) -> Self {
+        Self::array(
+            b'i',
+            values.len(),
+            values.iter().flat_map(|v| v.to_le_bytes()),
+        )
+    }
+
+    pub fn doubles(values: &[f64]) -> Self {
+        Self::array(
+            b'd',
+            values.len(),
+            values.iter().flat_map(|v| v.to_le_bytes()),
+        )
+    }
+
+    fn array(type_code: u8, len: usize, bytes: impl Iterator<Item = u8>) -> Self {
+        let data: Vec<u8> = bytes.collect();
+        if data.len() <= COMPRESSION_THRESHOLD {
+            return Self::Array {
+                type_code,
+                len: len as u32,
+                encoding: 0,
+                data,
+            };
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        Self::Array {
+            type_code,
+            len: len as u32,
+            encoding: 1,
+            data: encoder.finish().unwrap(),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + match self {
+            Self::Bool(_) => 1,
+            Self::Int(_) => 4,
+            Self::Long(_) | Self::Double(_) => 8,
+            Self::String(bytes) | Self::Raw(bytes) => 4 + bytes.len(),
+            Self::Array { data, .. } => 12 + data.len(),
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::Bool(v) => writer.write_all(&[b'C', *v as u8]),
+            Self::Int(v) => {
+                writer.write_all(b"I")?;
+                writer.write_all(&v.to_le_bytes())
+            }
+            Self::Long(v) => {
+                writer.write_all(b"L")?;
+                writer.write_all(&v.to_le_bytes())
+            }
+            Self::Double(v) => {
+                writer.write_all(b"D")?;
+                writer.write_all(&v.to_le_bytes())
+            }
+            Self::String(bytes) | Self::Raw(bytes) => {
+                let type_code = match self {
+                    Self::String(_) => b'S',
+                    _ => b'R',
+                };
+                writer.write_all(&[type_code])?;
+                writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                writer.write_all(bytes)
+            }
+            Self::Array {
+                type_code,
+                len,
+                encoding,
+                data,
+            } => {
+                writer.write_all(&[*type_code])?;
+                writer.write_all(&len.to_le_bytes())?;
+                writer.write_all(&encoding.to_le_bytes())?;
+                writer.write_all(&(data.len() as u32).to_le_bytes())?;
+                writer.write_all(data)
+            }
+        }
+    }
+}
+
+impl From<bool> for Property {
+    fn from(v: bool) -> Self {
+        Self::Bool(v)
+    }
+}
+
+impl From<i32> for Property {
+    fn from(v: i32) -> Self {
+        Self::Int(v)
+    }
+}
+
+impl From<i64> for Property {
+    fn from(v: i64) -> Self {
+        Self::Long(v)
+    }
+}
+
+impl From<f64> for Property {
+    fn from(v: f64) -> Self {
+        Self::Double(v)
+    }
+}
+
+impl From<&str> for Property {
+    fn from(v: &str) -> Self {
+        Self::String(v.as_bytes().to_vec())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    name: &'static str,
+    properties: Vec<Property>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            properties: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn prop(mut self, property: impl Into<Property>) -> Self {
+        self.properties.push(property.into());
+        self
+    }
+
+    pub fn child(mut self, node: Node) -> Self {
+        self.children.push(node);
+        self
+    }
+
+    pub fn add_child(&mut self, node: Node) {
+        self.children.push(node);
+    }
+
+    /// The nested list is terminated by a null record if the node has children (or has nothing at all)
+    fn has_null_record(&self) -> bool {
+        !self.children.is_empty() || self.properties.is_empty()
+    }
+
+    fn encoded_len(&self) -> usize {
+        13 + self.name.len()
+            + self
+                .properties
+                .iter()
+                .map(Property::encoded_len)
+                .sum::<usize>()
+            + self.children.iter().map(Node::encoded_len).sum::<usize>()
+            + if self.has_null_record() {
+                NULL_RECORD_LEN
+            } else {
+                0
+            }
+    }
+
+    /// Writes the node at `offset` (from the beginning of the file), and returns the offset of the end
+    fn write<W: Write>(&self, writer: &mut W, offset: usize) -> io::Result<usize> {
+        let end = offset + self.encoded_len();
+        let properties_len: usize = self.properties.iter().map(Property::encoded_len).sum();
+        writer.write_all(&(end as u32).to_le_bytes())?;
+        writer.write_all(&(self.properties.len() as u32).to_le_bytes())?;
+        writer.write_all(&(properties_len as u32).to_le_bytes())?;
+        writer.write_all(&[self.name.len() as u8])?;
+        writer.write_all(self.name.as_bytes())?;
+        for property in &self.properties {
+            property.write(writer)?;
+        }
+
+        let mut child_offset = offset + 13 + self.name.len() + properties_len;
+        for child in &self.children {
+            child_offset = child.write(writer, child_offset)?;
+        }
+        if self.has_null_record() {
+            writer.write_all(&[0; NULL_RECORD_LEN])?;
+        }
+        Ok(end)
+    }
+}
+
+/// A property in `Properties70` (e.g. `P: "UpAxis", "int", "Integer", "", 1`)
+pub fn p(name: &str, ty: &str, label: &str, flags: &str, values: Vec<Property>) -> Node {
+    let mut node = Node::new("P").prop(name).prop(ty).prop(label).prop(flags);
+    node.properties.extend(values);
+    node
+}
+
+/// Writes the top-level nodes as a binary FBX file
+pub fn write_fbx<W: Write>(writer: &mut W, nodes: &[Node]) -> io::Result<()> {
+    let header_len = MAGIC.len() + 4;
+    let total_len = header_len + nodes.iter().map(Node::encoded_len).sum::<usize>();
+    if total_len > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The scene is too large for FBX 7.4 (4 GB). Split the input into smaller areas.",
+        ));
+    }
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FBX_VERSION.to_le_bytes())?;
+    let mut offset = header_len;
+    for node in nodes {
+        offset = node.write(writer, offset)?;
+    }
+    writer.write_all(&[0; NULL_RECORD_LEN])?;
+    offset += NULL_RECORD_LEN;
+
+    // footer
+    writer.write_all(&FOOTER_ID)?;
+    writer.write_all(&[0; 4])?;
+    offset += FOOTER_ID.len() + 4;
+    let padding = match offset % 16 {
+        0 => 16,
+        rem => 16 - rem,
+    };
+    writer.write_all(&vec![0; padding])?;
+    writer.write_all(&FBX_VERSION.to_le_bytes())?;
+    writer.write_all(&[0; 120])?;
+    writer.write_all(&FOOTER_MAGIC)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    fn read_u32(buf: &[u8], pos: usize) -> usize {
+        u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize
+    }
+
+    /// Parses a node and checks the offsets. Returns (name, number of properties, children, end offset).
+    fn parse_node(buf: &[u8], pos: usize) -> (String, usize, Vec<String>, usize) {
+        let end = read_u32(buf, pos);
+        let num_properties = read_u32(buf, pos + 4);
+        let properties_len = read_u32(buf, pos + 8);
+        let name_len = buf[pos + 12] as usize;
+        let name = String::from_utf8(buf[pos + 13..pos + 13 + name_len].to_vec()).unwrap();
+
+        let mut children = Vec::new();
+        let mut child_pos = pos + 13 + name_len + properties_len;
+        while child_pos < end {
+            if read_u32(buf, child_pos) == 0 {
+                assert_eq!(child_pos + NULL_RECORD_LEN, end);
+                break;
+            }
+            let (child_name, _, _, child_end) = parse_node(buf, child_pos);
+            children.push(child_name);
+            child_pos = child_end;
+        }
+        (name, num_properties, children, end)
+    }
+
+    #[test]
+    fn test_write_fbx() {
+        let values: Vec<f64> = (0..100).map(|v| v as f64).collect();
+        let nodes = [
+            Node::new("FBXHeaderExtension").child(Node::new("FBXVersion").prop(7400)),
+            Node::new("Objects").child(
+                Node::new("Geometry")
+                    .prop(1_i64)
+                    .prop(Property::object_name("bldg_1", "Geometry"))
+                    .prop("Mesh")
+                    .child(Node::new("Vertices").prop(Property::doubles(&values)))
+                    .child(Node::new("Properties70")),
+            ),
+        ];
+        let mut buf = Vec::new();
+        write_fbx(&mut buf, &nodes).unwrap();
+
+        assert!(buf.starts_with(MAGIC));
+        assert_eq!(read_u32(&buf, MAGIC.len()), 7400);
+        assert!(buf.ends_with(&FOOTER_MAGIC));
+
+        let (name, _, children, end) = parse_node(&buf, 27);
+        assert_eq!(name, "FBXHeaderExtension");
+        assert_eq!(children, ["FBXVersion"]);
+        let (name, _, children, end) = parse_node(&buf, end);
+        assert_eq!(name, "Objects");
+        assert_eq!(children, ["Geometry"]);
+        // the null record at the end of the top-level nodes
+        assert_eq!(&buf[end..end + NULL_RECORD_LEN], &[0; NULL_RECORD_LEN]);
+        assert_eq!(
+            &buf[end + NULL_RECORD_LEN..end + NULL_RECORD_LEN + 16],
+            &FOOTER_ID
+        );
+        assert_eq!((buf.len() - 16 - 120 - 4) % 16, 0);
+    }
+
+    #[test]
+    fn test_array_compression() {
+        let Property::Array { encoding, .. } = Property::ints(&[1, 2, 3]) else {
+            unreachable!();
+        };
+        assert_eq!(encoding, 0);
+
+        let values: Vec<i32> = (0..1000).collect();
+        let Property::Array {
+            type_code,
+            len,
+            encoding,
+            data,
+        } = Property::ints(&values)
+        else {
+            unreachable!();
+        };
+        assert_eq!((type_code, len, encoding), (b'i', 1000, 1));
+        let mut decompressed = Vec::new();
+        ZlibDecoder::new(data.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed.len(), 4000);
+        assert_eq!(&decompressed[4..8], &1_i32.to_le_bytes());
+    }
+}
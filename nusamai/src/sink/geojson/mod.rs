@@ -1,10 +1,6 @@
 //! GeoJSON sink
 
-use std::{
-    fs::File,
-    io::{BufWriter, Write},
-    path::PathBuf,
-};
+use std::{io::Write, path::PathBuf};
 
 use hashbrown::HashMap;
 use nusamai_citygml::{
@@ -24,10 +20,16 @@ use crate::{
     pipeline::{Feedback, PipelineError, Receiver, Result},
     sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
     transformer,
-    transformer::{use_lod_config, TransformerSettings},
+    transformer::{
+        building_adjacency_config, name_columns_config, prefix_config, solar_attributes_config,
+        underground_config, use_lod_config, vegetation_config, TransformerSettings,
+    },
 };
 
-use super::option::output_parameter;
+use super::{
+    option::output_parameter,
+    output::{compression_parameter, Compression, OutputWriter},
+};
 
 pub struct GeoJsonSinkProvider {}
 
@@ -42,6 +44,25 @@ impl DataSinkProvider for GeoJsonSinkProvider {
     fn sink_options(&self) -> Parameters {
         let mut params = Parameters::new();
         params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "seq".into(),
+            entry: ParameterEntry {
+                description: "Write newline-delimited features (GeoJSONSeq / NDJSON) instead of FeatureCollections".into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("1行1地物の形式（GeoJSONSeq）で出力する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "split_data".into(),
+            entry: ParameterEntry {
+                description: "Write the data objects (e.g. disaster risks) into separate files without geometry, like the GeoPackage tables".into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("属性データを別ファイルに分ける".into()),
+            },
+        });
+        params.define(compression_parameter());
 
         params
     }
@@ -49,6 +70,12 @@ impl DataSinkProvider for GeoJsonSinkProvider {
     fn transformer_options(&self) -> TransformerSettings {
         let mut settings: TransformerSettings = TransformerSettings::new();
         settings.insert(use_lod_config("max_lod", None));
+        settings.insert(vegetation_config(&["point"]));
+        settings.insert(underground_config());
+        settings.insert(solar_attributes_config());
+        settings.insert(building_adjacency_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
 
         settings
     }
@@ -56,10 +83,18 @@ impl DataSinkProvider for GeoJsonSinkProvider {
     fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
         let output_path = get_parameter_value!(params, "@output", FileSystemPath);
         let transform_settings = self.transformer_options();
+        let seq = get_parameter_value!(params, "seq", Boolean).unwrap();
+        let split_data = get_parameter_value!(params, "split_data", Boolean).unwrap();
+        let compression = get_parameter_value!(params, "compression", String)
+            .clone()
+            .unwrap_or_default();
 
         Box::<GeoJsonSink>::new(GeoJsonSink {
             output_path: output_path.as_ref().unwrap().into(),
             transform_settings,
+            seq,
+            split_data,
+            compression,
         })
     }
 }
@@ -67,6 +102,12 @@ impl DataSinkProvider for GeoJsonSinkProvider {
 pub struct GeoJsonSink {
     output_path: PathBuf,
     transform_settings: TransformerSettings,
+    /// Write one feature per line (`.geojsonl`) instead of a FeatureCollection (`.geojson`)
+    seq: bool,
+    /// Flatten the top-level data objects into their own files, as the GeoPackage sink does
+    split_data: bool,
+    /// Compression option (`auto` follows the extension of the output path)
+    compression: String,
 }
 
 impl DataSink for GeoJsonSink {
@@ -74,7 +115,10 @@ impl DataSink for GeoJsonSink {
         let default_requirements = DataRequirements {
             tree_flattening: transformer::TreeFlatteningSpec::Flatten {
                 feature: transformer::FeatureFlatteningOption::AllExceptThematicSurfaces,
-                data: transformer::DataFlatteningOption::None,
+                data: match self.split_data {
+                    true => transformer::DataFlatteningOption::TopLevelOnly,
+                    false => transformer::DataFlatteningOption::None,
+                },
                 object: transformer::ObjectFlatteningOption::None,
             },
             ..Default::default()
@@ -89,6 +133,8 @@ impl DataSink for GeoJsonSink {
 
     fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
         let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+        let seq = self.seq;
+        let compression = Compression::negotiate(&self.compression, &self.output_path)?;
 
         let (ra, rb) = rayon::join(
             || {
@@ -131,29 +177,17 @@ impl DataSink for GeoJsonSink {
 
                         let mut file_path = self.output_path.clone();
                         let c_name = typename.split_once(':').map(|v| v.1).unwrap_or(typename);
-                        file_path.push(format!("{}.geojson", c_name));
-
-                        let mut file = File::create(&file_path)?;
-                        let mut writer = BufWriter::with_capacity(1024 * 1024, &mut file);
-
-                        // Write the FeatureCollection header
-                        writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[")?;
-
-                        // Write each Feature
-                        let mut iter = features.iter().peekable();
-                        while let Some(feature) = iter.next() {
-                            feedback.ensure_not_canceled()?;
-
-                            let bytes = serde_json::to_vec(&feature).unwrap();
-                            writer.write_all(&bytes)?;
-                            if iter.peek().is_some() {
-                                writer.write_all(b",")?;
-                            };
+                        let ext = if seq { "geojsonl" } else { "geojson" };
+                        file_path.push(format!("{}.{}", c_name, ext));
+                        let file_path = compression.apply_extension(&file_path);
+
+                        let mut writer = OutputWriter::create(&file_path, compression)?;
+                        if seq {
+                            write_feature_sequence(&mut writer, features, feedback)?;
+                        } else {
+                            write_feature_collection(&mut writer, features, feedback)?;
                         }
-
-                        // Write the FeautureCollection footer and EOL
-                        writer.write_all(b"]}\n")?;
-
+                        writer.finish()?;
                         Ok(())
                     },
                 );
@@ -175,6 +209,49 @@ impl DataSink for GeoJsonSink {
     }
 }
 
+fn write_feature_collection<W: Write>(
+    writer: &mut W,
+    features: &[geojson::Feature],
+    feedback: &Feedback,
+) -> Result<()> {
+    // Write the FeatureCollection header
+    writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[")?;
+
+    // Write each Feature
+    let mut iter = features.iter().peekable();
+    while let Some(feature) = iter.next() {
+        feedback.ensure_not_canceled()?;
+
+        let bytes = serde_json::to_vec(&feature).unwrap();
+        writer.write_all(&bytes)?;
+        if iter.peek().is_some() {
+            writer.write_all(b",")?;
+        };
+    }
+
+    // Write the FeautureCollection footer and EOL
+    writer.write_all(b"]}\n")?;
+
+    Ok(())
+}
+
+/// Writes newline-delimited features (GeoJSONSeq without the RS separators, a.k.a. NDJSON)
+fn write_feature_sequence<W: Write>(
+    writer: &mut W,
+    features: &[geojson::Feature],
+    feedback: &Feedback,
+) -> Result<()> {
+    for feature in features {
+        feedback.ensure_not_canceled()?;
+
+        let bytes = serde_json::to_vec(&feature).unwrap();
+        writer.write_all(&bytes)?;
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
 fn extract_properties(value: &nusamai_citygml::object::Value) -> Option<geojson::JsonObject> {
     match &value {
         obj @ nusamai_citygml::Value::Object(_) => match obj.to_attribute_json() {
@@ -195,8 +272,19 @@ pub fn entity_to_geojson_features(entity: &Entity) -> Vec<geojson::Feature> {
     let Value::Object(obj) = &entity.root else {
         return Vec::default();
     };
-    let ObjectStereotype::Feature { id, geometries } = &obj.stereotype else {
-        return Vec::default();
+    let (id, geometries) = match &obj.stereotype {
+        ObjectStereotype::Feature { id, geometries } => (id, geometries),
+        // data objects flattened from the features are written without geometry
+        ObjectStereotype::Data => {
+            return vec![geojson::Feature {
+                bbox: None,
+                geometry: None,
+                id: None,
+                properties,
+                foreign_members: None,
+            }];
+        }
+        ObjectStereotype::Object { .. } => return Vec::default(),
     };
 
     let mut polygons = Vec::new();
@@ -355,4 +443,32 @@ mod tests {
             unreachable!("The result is not a GeoJSON MultiPolygon");
         };
     }
+
+    #[test]
+    fn test_write_feature_sequence() {
+        let (_, feedback, _) = crate::pipeline::feedback::watcher();
+        let features: Vec<geojson::Feature> = (0..2)
+            .map(|i| geojson::Feature {
+                bbox: None,
+                geometry: Some(geojson::Value::Point(vec![i as f64, 0.]).into()),
+                id: Some(geojson::feature::Id::String(format!("id_{i}"))),
+                properties: None,
+                foreign_members: None,
+            })
+            .collect();
+
+        let mut buf = Vec::new();
+        write_feature_sequence(&mut buf, &features, &feedback).unwrap();
+        let lines: Vec<&str> = std::str::from_utf8(&buf).unwrap().lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, feature) in lines.iter().zip(&features) {
+            assert_eq!(&line.parse::<geojson::Feature>().unwrap(), feature);
+        }
+
+        let mut buf = Vec::new();
+        write_feature_collection(&mut buf, &features, &feedback).unwrap();
+        let collection: geojson::FeatureCollection =
+            std::str::from_utf8(&buf).unwrap().parse().unwrap();
+        assert_eq!(collection.features, features);
+    }
 }
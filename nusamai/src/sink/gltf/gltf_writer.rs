@@ -1,40 +1,115 @@
-use std::io::Write;
+use std::{io::Write, path::Path};
 
 use byteorder::{ByteOrder, LittleEndian};
 use indexmap::IndexSet;
-use nusamai_gltf_json::extensions::mesh::ext_mesh_features;
+use nusamai_gltf::meshopt::{compress_buffer_views, fallback_buffer};
+use nusamai_gltf_json::extensions::{
+    buffer::MeshoptCompressionMode,
+    mesh::{ext_mesh_features, khr_materials_variants},
+    node::{ExtInstanceFeatures, ExtMeshGpuInstancing, InstanceFeatureId},
+};
 
-use super::{material, Primitives};
+use super::{hierarchy::SceneNode, instancing::Instance, material, Primitives, Vertex};
 use crate::{
     pipeline::{feedback, PipelineError},
-    sink::cesiumtiles::metadata,
+    sink::{
+        cesiumtiles::{metadata, utils::add_padding},
+        mesh_compression::{
+            compact_vertices, write_draco_primitive, write_quantized_vertices, Dequantization,
+            MeshCompression, VertexAttribute, VertexAttributeKind,
+        },
+    },
 };
 
+/// The binary buffer and the images written as separate files, referred by a `.gltf`
+pub struct ExternalFiles<'a> {
+    /// URI of the binary buffer (relative to the `.gltf`)
+    pub bin_uri: String,
+    pub bin_writer: &'a mut dyn Write,
+    /// Directory of the `.gltf`, to which the URIs of the images are relative
+    pub base_dir: &'a Path,
+}
+
+/// Writes a `.glb`, or a `.gltf` (the JSON part) if the `external` files are given
+#[allow(clippy::too_many_arguments)]
 pub fn write_gltf_glb<W: Write>(
     feedback: &feedback::Feedback,
     writer: W,
-    vertices: impl IntoIterator<Item = [u32; 9]>,
-    primitives: Primitives,
+    external: Option<ExternalFiles<'_>>,
+    vertices: impl IntoIterator<Item = Vertex>,
+    scene: SceneNode,
+    instanced: Vec<(Primitives, Vec<Instance>)>,
     metadata_encoder: metadata::MetadataEncoder,
+    variant_names: &[String],
+    mesh_compression: MeshCompression,
 ) -> Result<(), PipelineError> {
     use nusamai_gltf_json::*;
 
+    // The nodes of the scene (in the depth-first order, with the indices of their children),
+    // followed by the meshes shared by the instances
+    let mut scene_nodes = Vec::new();
+    flatten_nodes(&scene, &mut scene_nodes);
+    let instanced: Vec<_> = instanced
+        .into_iter()
+        .filter(|(primitives, _)| !primitives.is_empty())
+        .collect();
+    let meshes: Vec<(&Primitives, bool)> = scene_nodes
+        .iter()
+        .map(|(node, _)| (&node.primitives, false))
+        .chain(instanced.iter().map(|(primitives, _)| (primitives, true)))
+        .collect();
+
+    let mut vertices: Vec<Vertex> = vertices.into_iter().collect();
+    // With Draco, all the primitives (triangles) are encoded with their own vertices
+    let draco_vertices = match mesh_compression {
+        MeshCompression::Draco(_) => std::mem::take(&mut vertices),
+        MeshCompression::None | MeshCompression::Meshopt(_) | MeshCompression::Quantize(_) => {
+            vec![]
+        }
+    };
+
     // The buffer for the BIN part
     let mut bin_content: Vec<u8> = Vec::new();
     let mut gltf_buffer_views = vec![];
     let mut gltf_accessors = vec![];
 
+    // the texture coordinates of the material variants (except the main one)
+    let num_variant_uvs = variant_names.len().saturating_sub(1);
+
+    // the buffer views to be compressed with meshopt
+    let mut meshopt_views = vec![];
+
     // vertices
-    {
+    let mut dequantization = None;
+    if let Some(quantization) = mesh_compression.vertex_quantization() {
+        let (vertices, variant_uvs): (Vec<_>, Vec<_>) = vertices.into_iter().unzip();
+        dequantization = write_quantized_vertices(
+            quantization,
+            &vertices,
+            &variant_uvs,
+            num_variant_uvs,
+            &mut bin_content,
+            &mut gltf_buffer_views,
+            &mut gltf_accessors,
+        );
+        if dequantization.is_some() && matches!(mesh_compression, MeshCompression::Meshopt(_)) {
+            meshopt_views.push((
+                gltf_buffer_views.len() - 1,
+                MeshoptCompressionMode::Attributes,
+            ));
+        }
+    } else {
         let mut vertices_count = 0;
         let mut position_max = [f64::MIN; 3];
         let mut position_min = [f64::MAX; 3];
 
-        const VERTEX_BYTE_STRIDE: usize = 4 * 9; // 4-bytes (f32) x 9
+        // 4-bytes (f32) x 9, followed by the texture coordinates of the material variants
+        let vertex_byte_stride = 4 * 9 + 4 * 2 * num_variant_uvs;
 
         let buffer_offset = bin_content.len();
-        let mut buf = [0; VERTEX_BYTE_STRIDE];
-        for v in vertices {
+        let mut buf = [0; 4 * 9];
+        let mut uv_buf = [0; 4 * 2];
+        for (v, variant_uvs) in vertices {
             let [x, y, z, nx, ny, nz, u, v, feature_id] = v;
             position_min = [
                 f64::min(position_min[0], f32::from_bits(x) as f64),
@@ -49,6 +124,11 @@ pub fn write_gltf_glb<W: Write>(
 
             LittleEndian::write_u32_into(&[x, y, z, nx, ny, nz, u, v, feature_id], &mut buf);
             bin_content.write_all(&buf)?;
+            debug_assert_eq!(variant_uvs.len(), num_variant_uvs);
+            for uv in variant_uvs {
+                LittleEndian::write_u32_into(&uv, &mut uv_buf);
+                bin_content.write_all(&uv_buf)?;
+            }
             vertices_count += 1;
         }
 
@@ -58,7 +138,7 @@ pub fn write_gltf_glb<W: Write>(
                 name: Some("vertices".to_string()),
                 byte_offset: buffer_offset as u32,
                 byte_length: len_vertices as u32,
-                byte_stride: Some(VERTEX_BYTE_STRIDE as u8),
+                byte_stride: Some(vertex_byte_stride as u8),
                 target: Some(BufferViewTarget::ArrayBuffer),
                 ..Default::default()
             });
@@ -107,70 +187,159 @@ pub fn write_gltf_glb<W: Write>(
                 type_: AccessorType::Scalar,
                 ..Default::default()
             });
+
+            // accessors (texcoords of the material variants)
+            for i in 0..num_variant_uvs {
+                gltf_accessors.push(Accessor {
+                    name: Some(format!("texcoords_{}", i + 1)),
+                    buffer_view: Some(gltf_buffer_views.len() as u32 - 1),
+                    byte_offset: (4 * 9 + 4 * 2 * i) as u32,
+                    component_type: ComponentType::Float,
+                    count: vertices_count,
+                    type_: AccessorType::Vec2,
+                    ..Default::default()
+                });
+            }
         }
     }
 
-    let mut gltf_primitives = vec![];
+    let mut gltf_mesh_primitives = vec![];
+    // (material, index of the texcoord set)
+    let mut material_set: IndexSet<(material::Material, u32), ahash::RandomState> =
+        Default::default();
 
     let structural_metadata =
         metadata_encoder.into_metadata(&mut bin_content, &mut gltf_buffer_views);
 
+    // Draco-compressed primitives (written before the indices to keep the indices in a single buffer view)
+    let mut draco_primitives = vec![];
+    if let MeshCompression::Draco(quantization) = &mesh_compression {
+        for ((mat, _), primitive) in meshes.iter().flat_map(|(primitives, _)| primitives.iter()) {
+            let mut indices = primitive.indices.clone();
+            let local_vertices = compact_vertices(&draco_vertices, [&mut indices]);
+            draco_primitives.push(write_draco_primitive(
+                quantization,
+                &draco_attributes(&local_vertices, num_variant_uvs, mat.base_texture.is_some()),
+                &indices,
+                &mut bin_content,
+                &mut gltf_buffer_views,
+                &mut gltf_accessors,
+            ));
+        }
+    }
+    let has_draco = !draco_primitives.is_empty();
+    let mut draco_primitives = draco_primitives.into_iter();
+
     // indices
     {
         let indices_offset = bin_content.len();
 
         let mut byte_offset = 0;
-        for (mat_idx, (mat, primitive)) in primitives.iter().enumerate() {
-            let mut indices_count = 0;
-            for idx in &primitive.indices {
-                bin_content.write_all(&idx.to_le_bytes())?;
-                indices_count += 1;
-            }
-
-            gltf_accessors.push(Accessor {
-                name: Some("indices".to_string()),
-                buffer_view: Some(gltf_buffer_views.len() as u32),
-                byte_offset,
-                component_type: ComponentType::UnsignedInt,
-                count: indices_count,
-                type_: AccessorType::Scalar,
-                ..Default::default()
-            });
-
-            let mut attributes = vec![("POSITION".to_string(), 0), ("NORMAL".to_string(), 1)];
-            // TODO: For no-texture data, it's better to exclude u, v from the vertex buffer
-            if mat.base_texture.is_some() {
-                attributes.push(("TEXCOORD_0".to_string(), 2));
-            }
-            attributes.push(("_FEATURE_ID_0".to_string(), 3));
-
-            gltf_primitives.push(MeshPrimitive {
-                attributes: attributes.into_iter().collect(),
-                indices: Some(gltf_accessors.len() as u32 - 1),
-                material: Some(mat_idx as u32), // TODO
-                mode: PrimitiveMode::Triangles,
-                extensions: extensions::mesh::MeshPrimitive {
-                    ext_mesh_features: ext_mesh_features::ExtMeshFeatures {
-                        feature_ids: vec![ext_mesh_features::FeatureId {
-                            feature_count: primitive.feature_ids.len() as u32,
-                            attribute: Some(0),
-                            property_table: Some(0),
+        for &(primitives, is_instanced) in &meshes {
+            let mut gltf_primitives = vec![];
+            for ((mat, variant_mats), primitive) in primitives.iter() {
+                let (attributes, indices, khr_draco_mesh_compression) =
+                    match draco_primitives.next() {
+                        Some(draco) => (draco.attributes, draco.indices, Some(draco.extension)),
+                        None => {
+                            let mut indices_count = 0;
+                            for idx in &primitive.indices {
+                                bin_content.write_all(&idx.to_le_bytes())?;
+                                indices_count += 1;
+                            }
+
+                            gltf_accessors.push(Accessor {
+                                name: Some("indices".to_string()),
+                                buffer_view: Some(gltf_buffer_views.len() as u32),
+                                byte_offset,
+                                component_type: ComponentType::UnsignedInt,
+                                count: indices_count,
+                                type_: AccessorType::Scalar,
+                                ..Default::default()
+                            });
+                            byte_offset += indices_count * 4;
+
+                            let mut attributes =
+                                vec![("POSITION".to_string(), 0), ("NORMAL".to_string(), 1)];
+                            if num_variant_uvs > 0 {
+                                // the texcoord sets must be consecutive
+                                attributes.push(("TEXCOORD_0".to_string(), 2));
+                                for i in 0..num_variant_uvs {
+                                    attributes.push((format!("TEXCOORD_{}", i + 1), 4 + i as u32));
+                                }
+                            } else if mat.base_texture.is_some() {
+                                // TODO: For no-texture data, it's better to exclude u, v from the vertex buffer
+                                attributes.push(("TEXCOORD_0".to_string(), 2));
+                            }
+                            // (the instances are identified by their own attribute)
+                            if !is_instanced {
+                                attributes.push(("_FEATURE_ID_0".to_string(), 3));
+                            }
+
+                            (
+                                attributes.into_iter().collect(),
+                                gltf_accessors.len() as u32 - 1,
+                                None,
+                            )
+                        }
+                    };
+
+                let (mat_idx, _) = material_set.insert_full((mat.clone(), 0));
+
+                // the first variant is the main material, and the others use their own texcoord sets
+                let khr_materials_variants = (!variant_mats.is_empty()).then(|| {
+                    let mappings = std::iter::once(mat_idx)
+                        .chain(variant_mats.iter().enumerate().map(|(i, variant_mat)| {
+                            let (idx, _) =
+                                material_set.insert_full((variant_mat.clone(), i as u32 + 1));
+                            idx
+                        }))
+                        .enumerate()
+                        .map(|(variant_idx, mat_idx)| khr_materials_variants::Mapping {
+                            variants: vec![variant_idx as u32],
+                            material: mat_idx as u32,
                             ..Default::default()
-                        }],
+                        })
+                        .collect();
+                    khr_materials_variants::KhrMaterialsVariantsPrimitive {
+                        mappings,
+                        ..Default::default()
+                    }
+                });
+
+                gltf_primitives.push(MeshPrimitive {
+                    attributes,
+                    indices: Some(indices),
+                    material: Some(mat_idx as u32),
+                    mode: PrimitiveMode::Triangles,
+                    extensions: extensions::mesh::MeshPrimitive {
+                        khr_draco_mesh_compression,
+                        ext_mesh_features: (!is_instanced).then(|| {
+                            ext_mesh_features::ExtMeshFeatures {
+                                feature_ids: vec![ext_mesh_features::FeatureId {
+                                    feature_count: primitive.feature_ids.len() as u32,
+                                    attribute: Some(0),
+                                    property_table: Some(0),
+                                    ..Default::default()
+                                }],
+                                ..Default::default()
+                            }
+                        }),
+                        khr_materials_variants,
                         ..Default::default()
                     }
                     .into(),
                     ..Default::default()
-                }
-                .into(),
-                ..Default::default()
-            });
-
-            byte_offset += indices_count * 4;
+                });
+            }
+            gltf_mesh_primitives.push(gltf_primitives);
         }
 
         let indices_len = bin_content.len() - indices_offset;
         if indices_len > 0 {
+            if let MeshCompression::Meshopt(_) = mesh_compression {
+                meshopt_views.push((gltf_buffer_views.len(), MeshoptCompressionMode::Triangles));
+            }
             gltf_buffer_views.push(BufferView {
                 name: Some("indices".to_string()),
                 byte_offset: indices_offset as u32,
@@ -181,14 +350,104 @@ pub fn write_gltf_glb<W: Write>(
         }
     }
 
+    // the quantized positions are dequantized by the node transform
+    let (translation, scale) = match dequantization {
+        Some(Dequantization { offset, scale }) => (offset, scale),
+        None => ([0.; 3], [1.; 3]),
+    };
+
+    // the attributes of the instances
+    let mut instance_extensions = vec![];
+    for (_, instances) in &instanced {
+        let mut write_attribute = |name: &str, type_: AccessorType, values: Vec<f32>| {
+            add_padding(&mut bin_content, 4);
+            let byte_offset = bin_content.len();
+            for value in &values {
+                bin_content.extend_from_slice(&value.to_le_bytes());
+            }
+            gltf_buffer_views.push(BufferView {
+                name: Some(name.to_string()),
+                byte_offset: byte_offset as u32,
+                byte_length: (bin_content.len() - byte_offset) as u32,
+                ..Default::default()
+            });
+            gltf_accessors.push(Accessor {
+                name: Some(name.to_string()),
+                buffer_view: Some(gltf_buffer_views.len() as u32 - 1),
+                component_type: ComponentType::Float,
+                count: instances.len() as u32,
+                type_,
+                ..Default::default()
+            });
+            gltf_accessors.len() as u32 - 1
+        };
+
+        // (the instances are not under the node transform, so the dequantization is applied to them)
+        let translations = instances
+            .iter()
+            .flat_map(|instance| {
+                (0..3)
+                    .map(|i| (instance.translation[i] + instance.scale[i] * translation[i]) as f32)
+            })
+            .collect();
+        let scales = instances
+            .iter()
+            .flat_map(|instance| (0..3).map(|i| (instance.scale[i] * scale[i]) as f32))
+            .collect();
+        let feature_ids = instances
+            .iter()
+            .map(|instance| instance.feature_id as f32)
+            .collect();
+        let attributes = [
+            (
+                "TRANSLATION",
+                write_attribute("instance_translations", AccessorType::Vec3, translations),
+            ),
+            (
+                "SCALE",
+                write_attribute("instance_scales", AccessorType::Vec3, scales),
+            ),
+            (
+                "_FEATURE_ID_0",
+                write_attribute("instance_feature_ids", AccessorType::Scalar, feature_ids),
+            ),
+        ];
+
+        instance_extensions.push(extensions::node::Node {
+            ext_mesh_gpu_instancing: Some(ExtMeshGpuInstancing {
+                attributes: attributes
+                    .into_iter()
+                    .map(|(name, accessor)| (name.to_string(), accessor))
+                    .collect(),
+                ..Default::default()
+            }),
+            ext_instance_features: Some(ExtInstanceFeatures {
+                feature_ids: vec![InstanceFeatureId {
+                    feature_count: instances.len() as u32,
+                    attribute: Some(0),
+                    property_table: Some(0),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+
     let mut image_set: IndexSet<material::Image, ahash::RandomState> = Default::default();
     let mut texture_set: IndexSet<material::Texture, ahash::RandomState> = Default::default();
 
     // materials
-    let gltf_materials = primitives
-        .keys()
-        .map(|material| material.to_gltf(&mut texture_set))
+    let gltf_materials: Vec<_> = material_set
+        .iter()
+        .map(|(material, tex_coord)| material.to_gltf(&mut texture_set, *tex_coord))
         .collect();
+    let has_specular = gltf_materials.iter().any(|material| {
+        material
+            .extensions
+            .as_ref()
+            .is_some_and(|ext| ext.khr_materials_specular.is_some())
+    });
 
     let gltf_textures: Vec<_> = texture_set
         .into_iter()
@@ -199,14 +458,53 @@ pub fn write_gltf_glb<W: Write>(
         .into_iter()
         .map(|img| {
             feedback.ensure_not_canceled()?;
-            Ok(img.to_gltf(feedback, &mut gltf_buffer_views, &mut bin_content)?)
+            match &external {
+                Some(external) => Ok(img.to_gltf_uri(external.base_dir)),
+                None => Ok(img.to_gltf(feedback, &mut gltf_buffer_views, &mut bin_content)?),
+            }
         })
         .collect::<Result<Vec<Image>, PipelineError>>()?;
 
+    // the compressed buffer views are moved to the fallback buffer (without data)
+    let mut fallback_length = 0;
+    if !meshopt_views.is_empty() {
+        (bin_content, fallback_length) =
+            compress_buffer_views(&bin_content, &mut gltf_buffer_views, &meshopt_views);
+    }
+
     let mut gltf_meshes = vec![];
-    if !gltf_primitives.is_empty() {
-        gltf_meshes.push(Mesh {
-            primitives: gltf_primitives,
+    let mut add_mesh = |primitives: Vec<MeshPrimitive>| {
+        (!primitives.is_empty()).then(|| {
+            gltf_meshes.push(Mesh {
+                primitives,
+                ..Default::default()
+            });
+            gltf_meshes.len() as u32 - 1
+        })
+    };
+    let mut gltf_mesh_primitives = gltf_mesh_primitives.into_iter();
+    let mut gltf_nodes = vec![];
+    for (idx, (scene_node, children)) in scene_nodes.iter().enumerate() {
+        let mesh = add_mesh(gltf_mesh_primitives.next().unwrap_or_default());
+        // (the descendants of the root node are also dequantized by its transform)
+        let (translation, scale) = match idx {
+            0 => (translation, scale),
+            _ => ([0.; 3], [1.; 3]),
+        };
+        gltf_nodes.push(Node {
+            name: scene_node.name.clone(),
+            mesh,
+            children: (!children.is_empty()).then(|| children.clone()),
+            translation,
+            scale,
+            ..Default::default()
+        });
+    }
+    for extensions in instance_extensions {
+        let mesh = add_mesh(gltf_mesh_primitives.next().unwrap_or_default());
+        gltf_nodes.push(Node {
+            mesh,
+            extensions: Some(extensions),
             ..Default::default()
         });
     }
@@ -215,25 +513,66 @@ pub fn write_gltf_glb<W: Write>(
         let mut buffers = vec![];
         if !bin_content.is_empty() {
             buffers.push(Buffer {
+                uri: external.as_ref().map(|external| external.bin_uri.clone()),
                 byte_length: bin_content.len() as u32,
                 ..Default::default()
             });
         }
+        if fallback_length > 0 {
+            buffers.push(fallback_buffer(fallback_length));
+        }
         buffers
     };
 
+    let mut extensions_used = vec![
+        "EXT_mesh_features".to_string(),
+        "EXT_structural_metadata".to_string(),
+        "EXT_texture_webp".to_string(),
+    ];
+    if !variant_names.is_empty() {
+        extensions_used.push("KHR_materials_variants".to_string());
+    }
+    if has_specular {
+        extensions_used.push("KHR_materials_specular".to_string());
+    }
+    // the KTX2 textures have no fallback images
+    let mut extensions_required = vec![];
+    if gltf_textures.iter().any(|texture| {
+        texture
+            .extensions
+            .as_ref()
+            .is_some_and(|ext| ext.khr_texture_basisu.is_some())
+    }) {
+        extensions_used.push("KHR_texture_basisu".to_string());
+        extensions_required.push("KHR_texture_basisu".to_string());
+    }
+    // the compressed meshes have no fallback either
+    if has_draco || dequantization.is_some() {
+        for extension in mesh_compression.extensions() {
+            extensions_used.push(extension.to_string());
+            extensions_required.push(extension.to_string());
+        }
+    }
+    // (nor the instances)
+    if !instanced.is_empty() {
+        extensions_used.push("EXT_mesh_gpu_instancing".to_string());
+        extensions_used.push("EXT_instance_features".to_string());
+        extensions_required.push("EXT_mesh_gpu_instancing".to_string());
+    }
+
     feedback.ensure_not_canceled()?;
 
     // Build the JSON part of glTF
     let gltf = Gltf {
         scenes: vec![Scene {
-            nodes: Some(vec![0]),
-            ..Default::default()
-        }],
-        nodes: vec![Node {
-            mesh: (!primitives.is_empty()).then_some(0),
+            nodes: Some(
+                std::iter::once(0)
+                    .chain(scene_nodes.len() as u32..gltf_nodes.len() as u32)
+                    .collect(),
+            ),
             ..Default::default()
         }],
+        nodes: gltf_nodes,
         meshes: gltf_meshes,
         materials: gltf_materials,
         textures: gltf_textures,
@@ -243,23 +582,108 @@ pub fn write_gltf_glb<W: Write>(
         buffers: gltf_buffers,
         extensions: nusamai_gltf_json::extensions::gltf::Gltf {
             ext_structural_metadata: structural_metadata,
+            khr_materials_variants: (!variant_names.is_empty()).then(|| {
+                khr_materials_variants::KhrMaterialsVariants {
+                    variants: variant_names
+                        .iter()
+                        .map(|name| khr_materials_variants::Variant {
+                            name: name.clone(),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                }
+            }),
             ..Default::default()
         }
         .into(),
-        extensions_used: vec![
-            "EXT_mesh_features".to_string(),
-            "EXT_structural_metadata".to_string(),
-            "EXT_texture_webp".to_string(),
-        ],
+        extensions_used,
+        extensions_required,
         ..Default::default()
     };
 
-    // Write glb to the writer
-    nusamai_gltf::glb::Glb {
-        json: serde_json::to_vec(&gltf).unwrap().into(),
-        bin: Some(bin_content.into()),
+    match external {
+        // Write the JSON and the binary buffer to their own files
+        Some(external) => {
+            serde_json::to_writer(writer, &gltf).map_err(std::io::Error::from)?;
+            external.bin_writer.write_all(&bin_content)?;
+            external.bin_writer.flush()?;
+        }
+        // Write glb to the writer
+        None => {
+            nusamai_gltf::glb::Glb {
+                json: serde_json::to_vec(&gltf).unwrap().into(),
+                bin: Some(bin_content.into()),
+            }
+            .to_writer_with_alignment(writer, 8)?;
+        }
     }
-    .to_writer_with_alignment(writer, 8)?;
 
     Ok(())
 }
+
+/// The attributes of the vertices to be compressed: `[x, y, z, nx, ny, nz, u, v, feature_id]`
+/// and the texture coordinates of the material variants
+fn draco_attributes(
+    vertices: &[Vertex],
+    num_variant_uvs: usize,
+    textured: bool,
+) -> Vec<VertexAttribute> {
+    let attribute = |semantic: String, kind, values: Vec<f32>| VertexAttribute {
+        semantic,
+        kind,
+        values,
+    };
+    let values = |range: std::ops::Range<usize>| {
+        vertices
+            .iter()
+            .flat_map(|(v, _)| v[range.clone()].iter().map(|&bits| f32::from_bits(bits)))
+            .collect()
+    };
+
+    let mut attributes = vec![
+        attribute(
+            "POSITION".into(),
+            VertexAttributeKind::Position,
+            values(0..3),
+        ),
+        attribute("NORMAL".into(), VertexAttributeKind::Normal, values(3..6)),
+    ];
+    if num_variant_uvs > 0 || textured {
+        attributes.push(attribute(
+            "TEXCOORD_0".into(),
+            VertexAttributeKind::TexCoord,
+            values(6..8),
+        ));
+    }
+    for i in 0..num_variant_uvs {
+        let uvs = vertices
+            .iter()
+            .flat_map(|(_, variant_uvs)| variant_uvs[i].map(f32::from_bits))
+            .collect();
+        attributes.push(attribute(
+            format!("TEXCOORD_{}", i + 1),
+            VertexAttributeKind::TexCoord,
+            uvs,
+        ));
+    }
+    attributes.push(attribute(
+        "_FEATURE_ID_0".into(),
+        VertexAttributeKind::FeatureId,
+        values(8..9),
+    ));
+    attributes
+}
+
+/// Flattens the tree of the nodes in the depth-first order, returning the index of the node
+fn flatten_nodes<'a>(node: &'a SceneNode, nodes: &mut Vec<(&'a SceneNode, Vec<u32>)>) -> u32 {
+    let idx = nodes.len();
+    nodes.push((node, Vec::new()));
+    let children = node
+        .children
+        .iter()
+        .map(|child| flatten_nodes(child, nodes))
+        .collect();
+    nodes[idx].1 = children;
+    idx as u32
+}
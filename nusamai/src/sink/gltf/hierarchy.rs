@@ -0,0 +1,212 @@
+//! Scene hierarchy of the features
+//!
+//! Without the hierarchy, all the features of a type are merged into a mesh. With the hierarchy, the scene has a
+//! node for the feature type, its children for the features, and their children for the descendant features (e.g.
+//! the building parts and the semantic surfaces), so the features can be selected separately in the DCC tools.
+
+use nusamai_citygml::{
+    object::{Object, ObjectStereotype, Value},
+    GeometryRefs,
+};
+use serde::{Deserialize, Serialize};
+
+use super::Primitives;
+
+/// A feature or one of its descendant features
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeatureNode {
+    /// Name of the node (the gml:id, or the type name of the feature without it)
+    pub name: String,
+    /// Index of the parent node (None for the feature itself, which is the first node)
+    pub parent: Option<usize>,
+}
+
+/// A node of the scene, with the mesh of its own polygons
+#[derive(Default)]
+pub struct SceneNode {
+    pub name: Option<String>,
+    pub primitives: Primitives,
+    pub children: Vec<SceneNode>,
+}
+
+impl SceneNode {
+    /// Makes the nodes of a feature (`primitives` for each of the `nodes`)
+    pub fn from_feature_nodes(nodes: &[FeatureNode], primitives: Vec<Primitives>) -> Self {
+        let mut scene_nodes: Vec<Option<SceneNode>> = nodes
+            .iter()
+            .zip(primitives)
+            .map(|(node, primitives)| {
+                Some(SceneNode {
+                    name: Some(node.name.clone()),
+                    primitives,
+                    children: Vec::new(),
+                })
+            })
+            .collect();
+
+        // (the parents precede their children)
+        for (idx, node) in nodes.iter().enumerate().skip(1).rev() {
+            let scene_node = scene_nodes[idx].take().unwrap();
+            let parent = node.parent.unwrap_or(0);
+            scene_nodes[parent]
+                .as_mut()
+                .unwrap()
+                .children
+                .insert(0, scene_node);
+        }
+        scene_nodes.into_iter().next().flatten().unwrap_or_default()
+    }
+}
+
+/// Collects the feature and its descendant features in the depth-first order, with their geometries
+pub fn collect_nodes(obj: &Object) -> Vec<(FeatureNode, &GeometryRefs)> {
+    let mut nodes = Vec::new();
+    collect_object(obj, None, &mut nodes);
+    nodes
+}
+
+fn collect_object<'a>(
+    obj: &'a Object,
+    parent: Option<usize>,
+    nodes: &mut Vec<(FeatureNode, &'a GeometryRefs)>,
+) {
+    let parent = match &obj.stereotype {
+        ObjectStereotype::Feature { id, geometries } => {
+            let name = match id.is_empty() {
+                true => obj.typename.to_string(),
+                false => id.clone(),
+            };
+            nodes.push((FeatureNode { name, parent }, geometries));
+            Some(nodes.len() - 1)
+        }
+        _ => parent,
+    };
+    for value in obj.attributes.values() {
+        collect_value(value, parent, nodes);
+    }
+}
+
+fn collect_value<'a>(
+    value: &'a Value,
+    parent: Option<usize>,
+    nodes: &mut Vec<(FeatureNode, &'a GeometryRefs)>,
+) {
+    match value {
+        Value::Object(obj) => collect_object(obj, parent, nodes),
+        Value::Array(arr) => {
+            for value in arr {
+                collect_value(value, parent, nodes);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Removes the descendant features from the attributes (as they are the nodes)
+pub fn remove_descendant_features(obj: &mut Object) {
+    obj.attributes.retain(|_, value| retain_value(value));
+}
+
+fn retain_value(value: &mut Value) -> bool {
+    match value {
+        Value::Object(child) => match child.stereotype {
+            ObjectStereotype::Feature { .. } => false,
+            _ => {
+                remove_descendant_features(child);
+                true
+            }
+        },
+        Value::Array(arr) => {
+            arr.retain_mut(retain_value);
+            !arr.is_empty()
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::object::Map;
+
+    use super::*;
+
+    fn feature(typename: &str, id: &str, attributes: &[(&str, Value)]) -> Object {
+        Object {
+            typename: typename.to_string().into(),
+            stereotype: ObjectStereotype::Feature {
+                id: id.to_string(),
+                geometries: Default::default(),
+            },
+            attributes: Map::from_iter(
+                attributes
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.clone())),
+            ),
+        }
+    }
+
+    fn building() -> Object {
+        let surfaces = Value::Array(vec![
+            Value::Object(feature("bldg:WallSurface", "wall_1", &[])),
+            Value::Object(feature("bldg:RoofSurface", "", &[])),
+        ]);
+        let part = feature(
+            "bldg:BuildingPart",
+            "part_1",
+            &[("bldg:boundedBy", surfaces)],
+        );
+        feature(
+            "bldg:Building",
+            "bldg_1",
+            &[
+                ("bldg:measuredHeight", Value::Double(10.0)),
+                ("bldg:consistsOfBuildingPart", Value::Object(part)),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_collect_nodes() {
+        let building = building();
+        let nodes: Vec<_> = collect_nodes(&building)
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect();
+        let node = |name: &str, parent| FeatureNode {
+            name: name.to_string(),
+            parent,
+        };
+        assert_eq!(
+            nodes,
+            [
+                node("bldg_1", None),
+                node("part_1", Some(0)),
+                node("wall_1", Some(1)),
+                node("bldg:RoofSurface", Some(1)),
+            ]
+        );
+
+        let scene_node = SceneNode::from_feature_nodes(
+            &nodes,
+            nodes.iter().map(|_| Default::default()).collect(),
+        );
+        assert_eq!(scene_node.name.as_deref(), Some("bldg_1"));
+        assert_eq!(scene_node.children.len(), 1);
+        let part = &scene_node.children[0];
+        assert_eq!(part.name.as_deref(), Some("part_1"));
+        let names: Vec<_> = part
+            .children
+            .iter()
+            .map(|child| child.name.as_deref().unwrap())
+            .collect();
+        assert_eq!(names, ["wall_1", "bldg:RoofSurface"]);
+    }
+
+    #[test]
+    fn test_remove_descendant_features() {
+        let mut building = building();
+        remove_descendant_features(&mut building);
+        let names: Vec<_> = building.attributes.keys().collect();
+        assert_eq!(names, ["bldg:measuredHeight"]);
+    }
+}
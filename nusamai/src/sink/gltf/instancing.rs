@@ -0,0 +1,231 @@
+//! GPU instancing of the repeated small features (`EXT_mesh_gpu_instancing`)
+//!
+//! The trees and the city furniture are often the copies of a few models (or the same billboard) placed at many
+//! positions. The features of the same shape, up to the translation and the scale along the axes, share a mesh
+//! (the prototype), and are written as the transforms of its instances.
+
+use ahash::HashMap;
+use indexmap::IndexMap;
+
+use super::{material::Material, Feature};
+
+/// The types of the features to be instanced
+pub const INSTANCED_TYPES: &[&str] = &["veg:SolitaryVegetationObject", "frn:CityFurniture"];
+
+/// The precision of the comparison of the shapes (relative to the size of the features)
+const SHAPE_QUANTIZATION: f64 = 1000.0;
+
+/// The sizes below this (in meters) are regarded as flat
+const MIN_SIZE: f64 = 1e-3;
+
+/// The coordinates normalized by the size of the feature (quantized), and the materials of the polygons
+type Shape = (Vec<i64>, Vec<Material>);
+
+/// The transform of an instance of a prototype
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instance {
+    pub translation: [f64; 3],
+    pub scale: [f64; 3],
+    pub feature_id: u32,
+}
+
+/// A shape shared by the instances
+#[derive(Debug)]
+pub struct Prototype {
+    /// The feature whose geometry is written as the mesh
+    pub feature_id: usize,
+    /// The base point of the feature, which is the origin of the mesh
+    pub anchor: [f64; 3],
+    pub instances: Vec<Instance>,
+}
+
+#[derive(Debug, Default)]
+pub struct InstancePlan {
+    pub prototypes: Vec<Prototype>,
+    /// feature index -> prototype index (only for the instanced features)
+    assignments: HashMap<usize, usize>,
+}
+
+impl InstancePlan {
+    /// The prototype of the feature, if the feature is instanced
+    pub fn prototype_of(&self, feature_id: usize) -> Option<(usize, &Prototype)> {
+        self.assignments
+            .get(&feature_id)
+            .map(|&idx| (idx, &self.prototypes[idx]))
+    }
+}
+
+/// Finds the features sharing a shape (the coordinates are the local coordinates with the y-axis up).
+///
+/// The textured features are not instanced, as their texture coordinates differ in the atlas.
+pub fn plan_instances(features: &[&Feature]) -> InstancePlan {
+    // shape -> (feature index, anchor, size)
+    let mut groups: IndexMap<Shape, Vec<(usize, [f64; 3], [f64; 3])>> = Default::default();
+
+    for (feature_id, feature) in features.iter().enumerate() {
+        if feature
+            .materials
+            .iter()
+            .any(|mat| mat.base_texture.is_some())
+        {
+            continue;
+        }
+        let Some([min, max]) = extent(feature) else {
+            continue;
+        };
+        // (the center of the bottom)
+        let anchor = [(min[0] + max[0]) / 2.0, min[1], (min[2] + max[2]) / 2.0];
+        let size: [f64; 3] = std::array::from_fn(|i| match max[i] - min[i] {
+            d if d < MIN_SIZE => 1.0,
+            d => d,
+        });
+
+        let mut shape = Vec::new();
+        let mut materials = Vec::new();
+        for (poly, &mat_id) in feature
+            .polygons
+            .iter()
+            .zip(feature.polygon_material_ids.iter())
+        {
+            materials.push(feature.materials[mat_id as usize].clone());
+            for ring in poly.rings() {
+                shape.push(ring.raw_coords().len() as i64);
+                for c in ring.raw_coords() {
+                    shape.extend((0..3).map(|i| {
+                        ((c[i] - anchor[i]) / size[i] * SHAPE_QUANTIZATION).round() as i64
+                    }));
+                }
+            }
+        }
+        groups
+            .entry((shape, materials))
+            .or_default()
+            .push((feature_id, anchor, size));
+    }
+
+    let mut plan = InstancePlan::default();
+    for members in groups.into_values().filter(|members| members.len() > 1) {
+        let (feature_id, anchor, size) = members[0];
+        let instances = members
+            .iter()
+            .map(|&(instance_id, translation, instance_size)| {
+                plan.assignments.insert(instance_id, plan.prototypes.len());
+                Instance {
+                    translation,
+                    scale: std::array::from_fn(|i| instance_size[i] / size[i]),
+                    feature_id: instance_id as u32,
+                }
+            })
+            .collect();
+        plan.prototypes.push(Prototype {
+            feature_id,
+            anchor,
+            instances,
+        });
+    }
+    plan
+}
+
+fn extent(feature: &Feature) -> Option<[[f64; 3]; 2]> {
+    let mut coords = feature.polygons.coords().iter();
+    let &[x, y, z, _, _] = coords.next()?;
+    Some(coords.fold([[x, y, z], [x, y, z]], |[min, max], c| {
+        [
+            std::array::from_fn(|i| min[i].min(c[i])),
+            std::array::from_fn(|i| max[i].max(c[i])),
+        ]
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use flatgeom::MultiPolygon;
+    use indexmap::IndexSet;
+    use nusamai_citygml::object::Value;
+
+    use super::*;
+    use crate::sink::gltf::material::Texture;
+
+    fn tree(base: [f64; 3], scale: f64, textured: bool) -> Feature {
+        let [x, y, z] = base;
+        let mut polygons = MultiPolygon::new();
+        for [dx, dz] in [[1.0, 0.0], [0.0, 1.0]] {
+            polygons.add_exterior([
+                [x - dx * scale, y, z - dz * scale, 0.0, 0.0],
+                [x + dx * scale, y, z + dz * scale, 1.0, 0.0],
+                [x + dx * scale, y + 4.0 * scale, z + dz * scale, 1.0, 1.0],
+                [x - dx * scale, y + 4.0 * scale, z - dz * scale, 0.0, 1.0],
+            ]);
+        }
+        let material = Material {
+            base_color: [0.3, 0.5, 0.25, 1.0],
+            base_texture: textured.then(|| Texture {
+                uri: url::Url::parse("file:///tree.jpg").unwrap(),
+            }),
+            metallic_roughness: [0.0, 1.0],
+            specular: [1.0, 1.0, 1.0],
+            emissive: [0.0, 0.0, 0.0],
+        };
+        Feature {
+            polygons,
+            polygon_material_ids: vec![0, 0],
+            materials: IndexSet::from_iter([material]),
+            attributes: Value::String("tree".to_string()),
+            feature_id: None,
+            theme: None,
+            variants: Vec::new(),
+            nodes: Vec::new(),
+            polygon_node_ids: vec![0, 0],
+        }
+    }
+
+    #[test]
+    fn test_plan_instances() {
+        let features = [
+            tree([0.0, 0.0, 0.0], 1.0, false),
+            tree([10.0, 1.0, 5.0], 1.5, false),
+            tree([20.0, 0.0, 0.0], 1.0, true),
+            tree([30.0, 2.0, -5.0], 1.0, false),
+        ];
+        let mut single = tree([40.0, 0.0, 0.0], 1.0, false);
+        single.polygons = {
+            let mut polygons = MultiPolygon::new();
+            polygons.add_exterior(single.polygons.iter().next().unwrap().raw_coords().to_vec());
+            polygons
+        };
+        single.polygon_material_ids = vec![0];
+        single.polygon_node_ids = vec![0];
+        let features: Vec<_> = features.iter().chain([&single]).collect();
+
+        let plan = plan_instances(&features);
+        assert_eq!(plan.prototypes.len(), 1);
+        let prototype = &plan.prototypes[0];
+        assert_eq!(prototype.feature_id, 0);
+        assert_eq!(prototype.anchor, [0.0, 0.0, 0.0]);
+        assert_eq!(
+            prototype.instances,
+            [
+                Instance {
+                    translation: [0.0, 0.0, 0.0],
+                    scale: [1.0, 1.0, 1.0],
+                    feature_id: 0,
+                },
+                Instance {
+                    translation: [10.0, 1.0, 5.0],
+                    scale: [1.5, 1.5, 1.5],
+                    feature_id: 1,
+                },
+                Instance {
+                    translation: [30.0, 2.0, -5.0],
+                    scale: [1.0, 1.0, 1.0],
+                    feature_id: 3,
+                },
+            ]
+        );
+
+        assert!(plan.prototype_of(1).is_some_and(|(idx, _)| idx == 0));
+        // the textured feature and the different shape are not instanced
+        assert!(plan.prototype_of(2).is_none());
+        assert!(plan.prototype_of(4).is_none());
+    }
+}
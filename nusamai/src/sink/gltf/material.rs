@@ -3,16 +3,64 @@
 use std::{hash::Hash, path::Path, time::Instant};
 
 use indexmap::IndexSet;
-use nusamai_gltf_json::{BufferView, MimeType};
+use nusamai_gltf_json::{extensions::material::KhrMaterialsSpecular, BufferView, MimeType};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::pipeline::Feedback;
+use crate::{
+    parameters::{ParameterDefinition, ParameterEntry, ParameterType, StringParameter},
+    paths,
+    pipeline::{Feedback, PipelineError, Result},
+    sink::texture_compression::KTX2_EXTENSION,
+};
+
+/// How the textures are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextureMode {
+    /// Embedded in a self-contained `.glb`
+    #[default]
+    Embed,
+    /// A `.gltf` referring to the `.bin` buffer and the image files
+    External,
+    /// No textures (only the colors of the materials)
+    None,
+}
+
+impl TextureMode {
+    /// Parses the option (`embed`, `external`, `none`)
+    pub fn negotiate(option: &str) -> Result<Self> {
+        match option {
+            "" | "embed" => Ok(Self::Embed),
+            "external" => Ok(Self::External),
+            "none" => Ok(Self::None),
+            _ => Err(PipelineError::Other(format!(
+                "Unknown textures: {option} (expected embed, external or none)"
+            ))),
+        }
+    }
+}
+
+pub fn textures_parameter() -> ParameterDefinition {
+    ParameterDefinition {
+        key: "textures".into(),
+        entry: ParameterEntry {
+            description: "Output of the textures: embed (a .glb with the images), external (a .gltf with the .bin and the image files) or none".into(),
+            required: false,
+            parameter: ParameterType::String(StringParameter {
+                value: Some("embed".into()),
+            }),
+            label: Some("テクスチャの出力（embed, external, none）".into()),
+        },
+    }
+}
 
 #[derive(Debug, Serialize, Clone, PartialEq, Deserialize)]
 pub struct Material {
     pub base_color: [f32; 4],
     pub base_texture: Option<Texture>,
+    pub metallic_roughness: [f32; 2],
+    pub specular: [f32; 3],
+    pub emissive: [f32; 3],
     // NOTE: You MUST adjust the Hash implementation if you add more fields
 }
 
@@ -22,19 +70,26 @@ impl Hash for Material {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.base_color.iter().for_each(|c| c.to_bits().hash(state));
         self.base_texture.hash(state);
+        self.metallic_roughness
+            .iter()
+            .for_each(|c| c.to_bits().hash(state));
+        self.specular.iter().for_each(|c| c.to_bits().hash(state));
+        self.emissive.iter().for_each(|c| c.to_bits().hash(state));
     }
 }
 
 impl Material {
+    /// `tex_coord` is the index of the texcoord set (`TEXCOORD_n`) used by the base color texture.
     pub fn to_gltf(
         &self,
         texture_set: &mut IndexSet<Texture, ahash::RandomState>,
+        tex_coord: u32,
     ) -> nusamai_gltf_json::Material {
         let tex = if let Some(texture) = &self.base_texture {
             let (tex_idx, _) = texture_set.insert_full(texture.clone());
             Some(nusamai_gltf_json::TextureInfo {
                 index: tex_idx as u32,
-                tex_coord: 0,
+                tex_coord,
                 ..Default::default()
             })
         } else {
@@ -43,14 +98,44 @@ impl Material {
         nusamai_gltf_json::Material {
             pbr_metallic_roughness: Some(nusamai_gltf_json::MaterialPbrMetallicRoughness {
                 base_color_factor: to_f64x4(self.base_color),
-                metallic_factor: 0.2,
-                roughness_factor: 0.5,
+                metallic_factor: f64::from(self.metallic_roughness[0]),
+                roughness_factor: f64::from(self.metallic_roughness[1]),
                 base_color_texture: tex,
                 ..Default::default()
             }),
+            emissive_factor: self.emissive.map(f64::from),
+            extensions: self.specular_extension(),
+            alpha_mode: self.alpha_mode(),
+            // translucent surfaces (e.g. water, glass) should be visible from both sides
+            double_sided: self.is_translucent(),
             ..Default::default()
         }
     }
+
+    /// `KHR_materials_specular` with the specular color (omitted if it is white, the default of the extension)
+    fn specular_extension(&self) -> Option<nusamai_gltf_json::MaterialExtensions> {
+        if self.specular == [1.0, 1.0, 1.0] {
+            return None;
+        }
+        Some(nusamai_gltf_json::MaterialExtensions {
+            khr_materials_specular: Some(KhrMaterialsSpecular {
+                specular_color_factor: self.specular.map(f64::from),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn is_translucent(&self) -> bool {
+        self.base_color[3] < 1.0
+    }
+
+    fn alpha_mode(&self) -> nusamai_gltf_json::AlphaMode {
+        match self.is_translucent() {
+            true => nusamai_gltf_json::AlphaMode::Blend,
+            false => nusamai_gltf_json::AlphaMode::Opaque,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Clone, Hash, PartialEq, Eq, Deserialize)]
@@ -66,9 +151,26 @@ impl Texture {
         let (image_index, _) = images.insert_full(Image {
             uri: self.uri.clone(),
         });
-        nusamai_gltf_json::Texture {
-            source: Some(image_index as u32),
-            ..Default::default()
+        let is_ktx2 = Path::new(self.uri.path())
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(KTX2_EXTENSION));
+        match is_ktx2 {
+            // (no fallback image, so the extension is required)
+            true => nusamai_gltf_json::Texture {
+                extensions: Some(nusamai_gltf_json::extensions::texture::TextureExtensions {
+                    khr_texture_basisu: Some(
+                        nusamai_gltf_json::extensions::texture::KhrTextureBasisu {
+                            source: image_index as u32,
+                        },
+                    ),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            false => nusamai_gltf_json::Texture {
+                source: Some(image_index as u32),
+                ..Default::default()
+            },
         }
     }
 }
@@ -85,7 +187,7 @@ impl Image {
         buffer_views: &mut Vec<BufferView>,
         bin_content: &mut Vec<u8>,
     ) -> std::io::Result<nusamai_gltf_json::Image> {
-        if let Ok(path) = self.uri.to_file_path() {
+        if let Some(path) = paths::url_to_path(&self.uri) {
             // NOTE: temporary implementation
             let (content, mime_type) = load_image(feedback, &path)?;
 
@@ -109,6 +211,19 @@ impl Image {
             })
         }
     }
+
+    /// The image referred by its URI relative to the glTF file in `base_dir` (not embedded)
+    pub fn to_gltf_uri(&self, base_dir: &Path) -> nusamai_gltf_json::Image {
+        let uri = std::path::absolute(base_dir)
+            .ok()
+            .and_then(|dir| Url::from_directory_path(paths::simplified(&dir)).ok())
+            .and_then(|base| base.make_relative(&self.uri))
+            .unwrap_or_else(|| self.uri.to_string());
+        nusamai_gltf_json::Image {
+            uri: Some(uri),
+            ..Default::default()
+        }
+    }
 }
 
 // NOTE: temporary implementation
@@ -137,6 +252,7 @@ fn load_image(feedback: &Feedback, path: &Path) -> std::io::Result<(Vec<u8>, Mim
                 feedback.info(format!("Embedding a jpeg as is: {:?}", path));
                 Ok((std::fs::read(path)?, MimeType::ImageJpeg))
             }
+            Some(KTX2_EXTENSION) => Ok((std::fs::read(path)?, MimeType::ImageKtx2)),
             _ => {
                 let err = format!("Unsupported image format: {:?}", path);
                 Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
@@ -157,3 +273,42 @@ fn to_f64x4(c: [f32; 4]) -> [f64; 4] {
         f64::from(c[3]),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_texture_mode() {
+        assert_eq!(TextureMode::negotiate("").unwrap(), TextureMode::Embed);
+        assert_eq!(
+            TextureMode::negotiate("external").unwrap(),
+            TextureMode::External
+        );
+        assert_eq!(TextureMode::negotiate("none").unwrap(), TextureMode::None);
+        assert!(TextureMode::negotiate("link").is_err());
+    }
+
+    #[test]
+    fn test_image_uri() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = Image {
+            uri: paths::file_url(&dir.path().join("textures/bldg_Building/0.jpg")).unwrap(),
+        };
+        let gltf_image = image.to_gltf_uri(dir.path());
+        assert_eq!(
+            gltf_image.uri.as_deref(),
+            Some("textures/bldg_Building/0.jpg")
+        );
+        assert_eq!(gltf_image.buffer_view, None);
+
+        // (the remote images as they are)
+        let image = Image {
+            uri: Url::parse("https://example.com/0.jpg").unwrap(),
+        };
+        assert_eq!(
+            image.to_gltf_uri(dir.path()).uri.as_deref(),
+            Some("https://example.com/0.jpg")
+        );
+    }
+}
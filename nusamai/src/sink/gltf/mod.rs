@@ -1,8 +1,13 @@
 //! gltf sink poc
 mod gltf_writer;
+mod hierarchy;
+mod instancing;
 mod material;
 
-use std::{f64::consts::FRAC_PI_2, fs::File, io::BufWriter, path::PathBuf, sync::Mutex};
+use std::{
+    collections::BTreeSet, f64::consts::FRAC_PI_2, fs::File, io::BufWriter, path::PathBuf,
+    sync::Mutex,
+};
 
 use crate::sink::cesiumtiles::utils::calculate_normal;
 use ahash::{HashMap, HashSet, RandomState};
@@ -18,27 +23,43 @@ use atlas_packer::{
 use earcut::{utils3d::project3d_to_2d, Earcut};
 use flatgeom::MultiPolygon;
 use glam::{DMat4, DVec3, DVec4};
-use gltf_writer::write_gltf_glb;
+use gltf_writer::{write_gltf_glb, ExternalFiles};
+use hierarchy::{collect_nodes, remove_descendant_features, FeatureNode, SceneNode};
 use indexmap::IndexSet;
+use instancing::{plan_instances, InstancePlan, INSTANCED_TYPES};
 use itertools::Itertools;
-use material::{Material, Texture};
+use material::{textures_parameter, Material, Texture, TextureMode};
 use nusamai_citygml::{object::ObjectStereotype, schema::Schema, GeometryType, Value};
 use nusamai_plateau::appearance;
 use nusamai_projection::cartesian::geodetic_to_geocentric;
 use rayon::iter::{IntoParallelIterator, ParallelBridge, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use tempfile::tempdir;
-use url::Url;
 
 use crate::{
     get_parameter_value,
     parameters::*,
+    paths,
     pipeline::{Feedback, PipelineError, Receiver, Result},
     sink::{cesiumtiles::metadata, DataRequirements, DataSink, DataSinkProvider, SinkInfo},
-    transformer::{use_lod_config, TransformerSettings},
+    transformer::{
+        name_columns_config, prefix_config, surface_class_config,
+        transform::{primary_theme, resolve_theme},
+        use_lod_config, vegetation_config, TransformerSettings,
+    },
 };
 
-use super::option::{limit_texture_resolution_parameter, output_parameter};
+use super::inplace::TransformInplaceExt;
+use super::mesh_compression::{mesh_compression_parameters, MeshCompressionOptions};
+use super::normals::{normals_parameter, NormalMode, VertexNormals};
+use super::option::{
+    geographic_output_epsg, geoid_parameter, limit_texture_resolution_parameter, output_parameter,
+};
+use super::output::remove_on_cancel;
+use super::texture_compression::{
+    compress_atlas_dir, texture_compression_parameter, TextureCompression,
+};
+use super::texture_report::TextureUsage;
 use super::texture_resolution::get_texture_downsample_scale_of_polygon;
 pub struct GltfSinkProvider {}
 
@@ -53,7 +74,47 @@ impl DataSinkProvider for GltfSinkProvider {
     fn sink_options(&self) -> Parameters {
         let mut params = Parameters::new();
         params.define(output_parameter());
+        params.define(geoid_parameter());
         params.define(limit_texture_resolution_parameter(false));
+        params.define(ParameterDefinition {
+            key: "material_variants".into(),
+            entry: ParameterEntry {
+                description:
+                    "Export the other texture themes as material variants (KHR_materials_variants)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("他のテクスチャテーマを切り替え可能なマテリアルとして出力する".into()),
+            },
+        });
+        params.define(textures_parameter());
+        params.define(texture_compression_parameter());
+        for param in mesh_compression_parameters() {
+            params.define(param);
+        }
+        params.define(normals_parameter());
+        params.define(ParameterDefinition {
+            key: "instancing".into(),
+            entry: ParameterEntry {
+                description:
+                    "Export the repeated vegetation and city furniture as GPU instances (EXT_mesh_gpu_instancing)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("同じ形状の植生・都市設備をGPUインスタンシングで出力する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "hierarchy".into(),
+            entry: ParameterEntry {
+                description:
+                    "Export the features (and their parts and surfaces) as the nodes named by gml:id"
+                        .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("地物ごとのノード階層を出力する".into()),
+            },
+        });
 
         params
     }
@@ -61,19 +122,45 @@ impl DataSinkProvider for GltfSinkProvider {
     fn transformer_options(&self) -> TransformerSettings {
         let mut settings: TransformerSettings = TransformerSettings::new();
         settings.insert(use_lod_config("max_lod", Some(&["textured_max_lod"])));
+        settings.insert(vegetation_config(&["billboard"]));
+        settings.insert(surface_class_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
 
         settings
     }
     fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
         let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let geoid = *get_parameter_value!(params, "geoid", Boolean);
         let limit_texture_resolution =
             *get_parameter_value!(params, "limit_texture_resolution", Boolean);
         let transform_settings = self.transformer_options();
+        let material_variants = get_parameter_value!(params, "material_variants", Boolean).unwrap();
+        let textures = get_parameter_value!(params, "textures", String)
+            .clone()
+            .unwrap_or_default();
+        let texture_compression = get_parameter_value!(params, "texture_compression", String)
+            .clone()
+            .unwrap_or_default();
+        let mesh_compression = MeshCompressionOptions::from_parameters(params);
+        let normals = get_parameter_value!(params, "normals", String)
+            .clone()
+            .unwrap_or_default();
+        let instancing = get_parameter_value!(params, "instancing", Boolean).unwrap_or(false);
+        let hierarchy = get_parameter_value!(params, "hierarchy", Boolean).unwrap_or(false);
 
         Box::<GltfSink>::new(GltfSink {
             output_path: output_path.as_ref().unwrap().into(),
             transform_settings,
+            geoid,
             limit_texture_resolution,
+            material_variants,
+            textures,
+            texture_compression,
+            mesh_compression,
+            normals,
+            instancing,
+            hierarchy,
         })
     }
 }
@@ -81,7 +168,23 @@ impl DataSinkProvider for GltfSinkProvider {
 pub struct GltfSink {
     output_path: PathBuf,
     transform_settings: TransformerSettings,
+    /// Convert the heights to the ellipsoidal heights with the geoid model
+    geoid: Option<bool>,
     limit_texture_resolution: Option<bool>,
+    /// Export the texture themes other than the main one as KHR_materials_variants
+    material_variants: bool,
+    /// Output of the textures (`embed`, `external`, `none`)
+    textures: String,
+    /// GPU texture compression of the atlases (`none`, `etc1s`, `uastc`)
+    texture_compression: String,
+    /// Geometry compression of the meshes (`none`, `draco`, `meshopt`) and its quantization
+    mesh_compression: MeshCompressionOptions,
+    /// Vertex normals of the meshes (`flat`, `smooth`)
+    normals: String,
+    /// Export the features of the same shape as the instances of a mesh (EXT_mesh_gpu_instancing)
+    instancing: bool,
+    /// Export the node hierarchy of the features (feature type → feature → descendant features)
+    hierarchy: bool,
 }
 
 pub struct BoundingVolume {
@@ -129,6 +232,44 @@ pub struct Feature {
     pub attributes: nusamai_citygml::object::Value,
     // feature_id
     pub feature_id: Option<u32>,
+    // name of the theme of the main appearance
+    pub theme: Option<String>,
+    // appearances of the other themes (theme name, appearance)
+    pub variants: Vec<(String, FeatureVariant)>,
+    // the feature and its descendant features (only with the hierarchy)
+    pub nodes: Vec<FeatureNode>,
+    // node ids for each polygon (indices of `nodes`)
+    pub polygon_node_ids: Vec<u32>,
+}
+
+/// Appearance of the polygons of a feature in an alternative theme
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FeatureVariant {
+    // texture coordinates of the polygons (in the same layout as `Feature::polygons`)
+    pub polygon_uvs: MultiPolygon<'static, [f64; 2]>,
+    // material ids for each polygon (indices of `Feature::materials`)
+    pub polygon_material_ids: Vec<u32>,
+}
+
+impl Feature {
+    /// The main appearance as a variant, used for the features without the theme
+    fn main_variant(&self) -> FeatureVariant {
+        let mut polygon_uvs = MultiPolygon::new();
+        for poly in self.polygons.iter() {
+            for (ri, ring) in poly.rings().enumerate() {
+                let uvs = ring.iter().map(|[_, _, _, u, v]| [u, v]);
+                if ri == 0 {
+                    polygon_uvs.add_exterior(uvs);
+                } else {
+                    polygon_uvs.add_interior(uvs);
+                }
+            }
+        }
+        FeatureVariant {
+            polygon_uvs,
+            polygon_material_ids: self.polygon_material_ids.clone(),
+        }
+    }
 }
 
 type ClassifiedFeatures = HashMap<String, ClassFeatures>;
@@ -145,7 +286,12 @@ pub struct PrimitiveInfo {
     pub feature_ids: HashSet<u32>,
 }
 
-pub type Primitives = HashMap<material::Material, PrimitiveInfo>;
+/// The material of a primitive and its alternatives (for each material variant)
+pub type PrimitiveKey = (material::Material, Vec<material::Material>);
+pub type Primitives = HashMap<PrimitiveKey, PrimitiveInfo>;
+
+/// [x, y, z, nx, ny, nz, u, v, feature_id] and the texture coordinates of the material variants
+pub type Vertex = ([u32; 9], Vec<[u32; 2]>);
 
 impl DataSink for GltfSink {
     fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
@@ -159,11 +305,39 @@ impl DataSink for GltfSink {
             let _ = &self.transform_settings.update_transformer(config.clone());
         }
 
-        self.transform_settings.build(default_requirements)
+        let mut requirements = self.transform_settings.build(default_requirements);
+        // The descendant features are kept in the tree to be the nodes
+        if self.hierarchy {
+            requirements.mergedown = crate::transformer::MergedownSpec::NoMergedown;
+            requirements.key_value = crate::transformer::KeyValueSpec::None;
+        }
+        // The vertices are converted to the geocentric coordinates,
+        // so the geographic CRS is required regardless of the output CRS
+        requirements.fixed_output_epsg = Some(geographic_output_epsg(self.geoid.unwrap_or(true)));
+        requirements
     }
 
     fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        // the files written so far are removed if canceled
+        let output_path = self.output_path.clone();
+        remove_on_cancel(&output_path, feedback, || {
+            self.write_features(upstream, feedback, schema)
+        })
+    }
+}
+
+impl GltfSink {
+    fn write_features(
+        &self,
+        upstream: Receiver,
+        feedback: &Feedback,
+        schema: &Schema,
+    ) -> Result<()> {
         let ellipsoid = nusamai_projection::ellipsoid::wgs84();
+        let texture_compression = TextureCompression::negotiate(&self.texture_compression)?;
+        let mesh_compression = self.mesh_compression.negotiate()?;
+        let normal_mode = NormalMode::negotiate(&self.normals)?;
+        let texture_mode = TextureMode::negotiate(&self.textures)?;
 
         let classified_features: Mutex<ClassifiedFeatures> = Default::default();
 
@@ -192,17 +366,57 @@ impl DataSink for GltfSink {
             let mut materials: IndexSet<Material> = IndexSet::new();
             let default_material = appearance::Material::default();
 
+            // (the descendant features are the child nodes with their own geometries)
+            let (nodes, node_geometries): (Vec<_>, Vec<_>) = match self.hierarchy {
+                true => collect_nodes(obj).into_iter().unzip(),
+                false => (Vec::new(), vec![geometries]),
+            };
+
+            let mut attributes = obj.clone();
+            if self.hierarchy {
+                remove_descendant_features(&mut attributes);
+            }
+
             let mut feature = Feature {
                 polygons: MultiPolygon::new(),
-                attributes: entity.root.clone(),
+                attributes: Value::Object(attributes),
                 polygon_material_ids: Default::default(),
                 materials: Default::default(),
                 feature_id: None, // feature_id is set later
+                theme: primary_theme(&appearance_store).map(|name| name.to_string()),
+                variants: Vec::new(),
+                nodes,
+                polygon_node_ids: Default::default(),
+            };
+
+            let to_material = |poly_mat: &Option<u32>, poly_tex: &Option<u32>| {
+                let orig_mat = poly_mat
+                    .and_then(|idx| appearance_store.materials.get(idx as usize))
+                    .unwrap_or(&default_material);
+                let orig_tex = poly_tex.and_then(|idx| appearance_store.textures.get(idx as usize));
+                Material {
+                    base_color: orig_mat.base_color(),
+                    metallic_roughness: orig_mat.metallic_roughness(),
+                    specular: orig_mat.specular(),
+                    emissive: orig_mat.emissive(),
+                    base_texture: orig_tex.filter(|_| texture_mode != TextureMode::None).map(
+                        |tex| Texture {
+                            uri: tex.image_url.clone(),
+                        },
+                    ),
+                }
             };
 
             let mut local_bvol = BoundingVolume::default();
 
-            geometries.iter().for_each(|entry| {
+            let geometry_entries =
+                node_geometries
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(node_id, geometries)| {
+                        geometries.iter().map(move |entry| (node_id as u32, entry))
+                    });
+            geometry_entries.clone().for_each(|(node_id, entry)| {
                 match entry.ty {
                     GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle => {
                         // extract the polygon, material, and texture
@@ -226,19 +440,7 @@ impl DataSink for GltfSink {
                         {
                             // convert to idx_poly to polygon
                             let poly = idx_poly.transform(|c| geom_store.vertices[*c as usize]);
-                            let orig_mat = poly_mat
-                                .and_then(|idx| appearance_store.materials.get(idx as usize))
-                                .unwrap_or(&default_material)
-                                .clone();
-                            let orig_tex = poly_tex
-                                .and_then(|idx| appearance_store.textures.get(idx as usize));
-
-                            let mat = Material {
-                                base_color: orig_mat.diffuse_color.into(),
-                                base_texture: orig_tex.map(|tex| Texture {
-                                    uri: tex.image_url.clone(),
-                                }),
-                            };
+                            let mat = to_material(poly_mat, poly_tex);
                             let (mat_idx, _) = materials.insert_full(mat);
 
                             let mut ring_buffer: Vec<[f64; 5]> = Vec::new();
@@ -263,6 +465,7 @@ impl DataSink for GltfSink {
                                     if ri == 0 {
                                         feature.polygons.add_exterior(ring_buffer.drain(..));
                                         feature.polygon_material_ids.push(mat_idx as u32);
+                                        feature.polygon_node_ids.push(node_id);
                                     } else {
                                         feature.polygons.add_interior(ring_buffer.drain(..));
                                     }
@@ -279,6 +482,43 @@ impl DataSink for GltfSink {
                 }
             });
 
+            if self.material_variants {
+                // resolve the other themes in the same order of the polygons as the main appearance
+                for (name, theme) in appearance_store.themes.iter() {
+                    if feature.theme.as_ref() == Some(name) {
+                        continue;
+                    }
+                    let resolved = resolve_theme(feedback, Some(theme), &geom_store);
+                    let mut variant = FeatureVariant::default();
+                    for (_, entry) in geometry_entries.clone().filter(|(_, entry)| {
+                        matches!(
+                            entry.ty,
+                            GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+                        )
+                    }) {
+                        let range = entry.pos as usize..(entry.pos + entry.len) as usize;
+                        for ((poly_uv, poly_mat), poly_tex) in resolved
+                            .uvs
+                            .iter_range(range.clone())
+                            .zip_eq(resolved.materials[range.clone()].iter())
+                            .zip_eq(resolved.textures[range].iter())
+                        {
+                            let (mat_idx, _) =
+                                materials.insert_full(to_material(poly_mat, poly_tex));
+                            variant.polygon_material_ids.push(mat_idx as u32);
+                            for (ri, uv_ring) in poly_uv.rings().enumerate() {
+                                if ri == 0 {
+                                    variant.polygon_uvs.add_exterior(uv_ring.iter_closed());
+                                } else {
+                                    variant.polygon_uvs.add_interior(uv_ring.iter_closed());
+                                }
+                            }
+                        }
+                    }
+                    feature.variants.push((name.clone(), variant));
+                }
+            }
+
             feature.materials = materials;
 
             {
@@ -291,6 +531,8 @@ impl DataSink for GltfSink {
             Ok::<(), PipelineError>(())
         });
 
+        // (the errors of the parallel loop above are not propagated)
+        feedback.ensure_not_canceled()?;
         let classified_features = classified_features.into_inner().unwrap();
 
         // Bounding volume for the entire dataset
@@ -320,6 +562,8 @@ impl DataSink for GltfSink {
         };
         let _ = transform_matrix.inverse();
 
+        let texture_usage = TextureUsage::new();
+        let texture_folder_name = "textures";
         classified_features
             .into_par_iter()
             .try_for_each(|(typename, features)| {
@@ -330,7 +574,7 @@ impl DataSink for GltfSink {
                 // The image size is cached to avoid unnecessary decoding
                 let texture_size_cache = TextureSizeCache::new();
 
-                let mut vertices: IndexSet<[u32; 9], RandomState> = IndexSet::default(); // [x, y, z, nx, ny, nz, u, v, feature_id]
+                let mut vertices: IndexSet<Vertex, RandomState> = IndexSet::default();
                 let mut primitives: Primitives = Default::default();
 
                 let mut metadata_encoder = metadata::MetadataEncoder::new(schema);
@@ -340,8 +584,13 @@ impl DataSink for GltfSink {
                 let folder_path = binding.path();
                 let base_name = typename.replace(':', "_");
 
-                let texture_folder_name = "textures";
-                let atlas_dir = folder_path.join(texture_folder_name);
+                // (the external atlases are written next to the .gltf files)
+                let atlas_dir = match texture_mode {
+                    TextureMode::External => {
+                        self.output_path.join(texture_folder_name).join(&base_name)
+                    }
+                    TextureMode::Embed | TextureMode::None => folder_path.join(texture_folder_name),
+                };
                 std::fs::create_dir_all(&atlas_dir)?;
 
                 // Check the size of all the textures and calculate the power of 2 of the largest size
@@ -350,16 +599,12 @@ impl DataSink for GltfSink {
                 for feature in features.features.iter() {
                     feedback.ensure_not_canceled()?;
 
-                    for (_, orig_mat_id) in feature
-                        .polygons
-                        .iter()
-                        .zip_eq(feature.polygon_material_ids.iter())
-                    {
-                        let mat = feature.materials[*orig_mat_id as usize].clone();
-                        let t = mat.base_texture.clone();
-                        if let Some(base_texture) = t {
-                            let texture_uri = base_texture.uri.to_file_path().unwrap();
+                    // (including the materials of the variants)
+                    for mat in feature.materials.iter() {
+                        if let Some(base_texture) = &mat.base_texture {
+                            let texture_uri = paths::url_to_path(&base_texture.uri).unwrap();
                             let texture_size = texture_size_cache.get_or_insert(&texture_uri);
+                            texture_usage.add_source(&typename, &texture_uri);
                             max_width = max_width.max(texture_size.0);
                             max_height = max_height.max(texture_size.1);
                         }
@@ -378,23 +623,60 @@ impl DataSink for GltfSink {
 
                 let packer = Mutex::new(AtlasPacker::default());
 
+                // Names of the material variants: the main appearance followed by the other themes
+                let variant_names: Vec<String> = {
+                    let other_themes: BTreeSet<&String> = features
+                        .features
+                        .iter()
+                        .flat_map(|feature| feature.variants.iter().map(|(name, _)| name))
+                        .collect();
+                    match other_themes.is_empty() {
+                        true => Vec::new(),
+                        false => {
+                            let main_theme = features
+                                .features
+                                .iter()
+                                .find_map(|feature| feature.theme.clone())
+                                .unwrap_or_else(|| "default".to_string());
+                            std::iter::once(main_theme)
+                                .chain(other_themes.into_iter().cloned())
+                                .collect()
+                        }
+                    }
+                };
+
                 // Transform features
                 let features = {
                     let mut features = features.features;
+                    let mut scratch = Vec::new();
                     features.iter_mut().for_each(|feature| {
+                        // align the variants with `variant_names`, using the main appearance for the missing themes
+                        if !variant_names.is_empty() {
+                            let mut variants = std::mem::take(&mut feature.variants);
+                            feature.variants = variant_names[1..]
+                                .iter()
+                                .map(|name| match variants.iter().position(|(n, _)| n == name) {
+                                    Some(i) => variants.swap_remove(i),
+                                    None => (name.clone(), feature.main_variant()),
+                                })
+                                .collect();
+                        }
+
                         feature
                             .polygons
-                            .transform_inplace(|&[lng, lat, height, u, v]| {
-                                // geographic to geocentric
-                                let (x, y, z) =
-                                    geodetic_to_geocentric(&ellipsoid, lng, lat, height);
-                                // z-up to y-up
-                                let v_xyz = DVec4::new(x, z, -y, 1.0);
-                                // local ENU coordinate
-                                let v_enu = transform_matrix * v_xyz;
-                                // println!("enu: {:?}", v_enu);
-
-                                [v_enu[0], v_enu[1], v_enu[2], u, v]
+                            .transform_chunks_inplace(&mut scratch, |chunk| {
+                                for c in chunk {
+                                    let [lng, lat, height, u, v] = *c;
+                                    // geographic to geocentric
+                                    let (x, y, z) =
+                                        geodetic_to_geocentric(&ellipsoid, lng, lat, height);
+                                    // z-up to y-up
+                                    let v_xyz = DVec4::new(x, z, -y, 1.0);
+                                    // local ENU coordinate
+                                    let v_enu = transform_matrix * v_xyz;
+
+                                    *c = [v_enu[0], v_enu[1], v_enu[2], u, v];
+                                }
                             });
                     });
                     features
@@ -423,8 +705,46 @@ impl DataSink for GltfSink {
                         format!("{}_{}_{}", folder_name, feature_id, poly_count)
                     };
 
+                // Texture ids of the material variants
+                let variant_texture_id = |texture_id: &str, variant_idx: usize| {
+                    format!("{}_v{}", texture_id, variant_idx)
+                };
+
+                let add_texture =
+                    |texture_id: String,
+                     texture: &Texture,
+                     original_vertices: &[(f64, f64, f64, f64, f64)]| {
+                        let uv_coords = original_vertices
+                            .iter()
+                            .map(|(_, _, _, u, v)| (*u, *v))
+                            .collect::<Vec<(f64, f64)>>();
+
+                        let texture_uri = paths::url_to_path(&texture.uri).unwrap();
+                        let texture_size = texture_size_cache.get_or_insert(&texture_uri);
+
+                        let downsample_scale = if self.limit_texture_resolution.unwrap_or(false) {
+                            get_texture_downsample_scale_of_polygon(original_vertices, texture_size)
+                                as f32
+                        } else {
+                            1.0
+                        };
+
+                        let downsample_factor = DownsampleFactor::new(&downsample_scale);
+
+                        let texture = PolygonMappedTexture::new(
+                            &texture_uri,
+                            texture_size,
+                            &uv_coords,
+                            downsample_factor,
+                        );
+
+                        packer.lock().unwrap().add_texture(texture_id, texture);
+                    };
+
                 // Load all textures into the Packer
                 for (feature_id, feature) in features.iter().enumerate() {
+                    feedback.ensure_not_canceled()?;
+
                     for (poly_count, (mat, poly)) in feature
                         .polygons
                         .iter()
@@ -434,48 +754,35 @@ impl DataSink for GltfSink {
                         })
                         .enumerate()
                     {
-                        let t = mat.base_texture.clone();
-                        if let Some(base_texture) = t {
+                        // Unique id required for placement in atlas
+                        let texture_id = generate_texture_id(&base_name, feature_id, poly_count);
+
+                        if let Some(base_texture) = &mat.base_texture {
                             // texture packing
                             let original_vertices = poly
                                 .raw_coords()
                                 .iter()
                                 .map(|[x, y, z, u, v]| (*x, *y, *z, *u, *v))
                                 .collect::<Vec<(f64, f64, f64, f64, f64)>>();
+                            add_texture(texture_id.clone(), base_texture, &original_vertices);
+                        }
 
-                            let uv_coords = original_vertices
-                                .iter()
-                                .map(|(_, _, _, u, v)| (*u, *v))
-                                .collect::<Vec<(f64, f64)>>();
-
-                            let texture_uri = base_texture.uri.to_file_path().unwrap();
-                            let texture_size = texture_size_cache.get_or_insert(&texture_uri);
-
-                            let downsample_scale = if self.limit_texture_resolution.unwrap_or(false)
-                            {
-                                get_texture_downsample_scale_of_polygon(
+                        for (variant_idx, (_, variant)) in feature.variants.iter().enumerate() {
+                            let variant_mat = &feature.materials
+                                [variant.polygon_material_ids[poly_count] as usize];
+                            if let Some(base_texture) = &variant_mat.base_texture {
+                                let original_vertices = poly
+                                    .raw_coords()
+                                    .iter()
+                                    .zip_eq(variant.polygon_uvs.get(poly_count).raw_coords().iter())
+                                    .map(|([x, y, z, _, _], [u, v])| (*x, *y, *z, *u, *v))
+                                    .collect::<Vec<(f64, f64, f64, f64, f64)>>();
+                                add_texture(
+                                    variant_texture_id(&texture_id, variant_idx),
+                                    base_texture,
                                     &original_vertices,
-                                    texture_size,
-                                ) as f32
-                            } else {
-                                1.0
-                            };
-
-                            let downsample_factor = DownsampleFactor::new(&downsample_scale);
-
-                            let texture = PolygonMappedTexture::new(
-                                &texture_uri,
-                                texture_size,
-                                &uv_coords,
-                                downsample_factor,
-                            );
-
-                            // Unique id required for placement in atlas
-
-                            let texture_id =
-                                generate_texture_id(&base_name, feature_id, poly_count);
-
-                            packer.lock().unwrap().add_texture(texture_id, texture);
+                                );
+                            }
                         }
                     }
                 }
@@ -484,14 +791,48 @@ impl DataSink for GltfSink {
                 let packer = packer.into_inner().unwrap();
 
                 // Packing the loaded textures into an atlas
+                feedback.ensure_not_canceled()?;
                 let packed = packer.pack(placer);
 
                 let exporter = JpegAtlasExporter::default();
-                let ext = exporter.clone().get_extension().to_string();
+                let exported_ext = exporter.clone().get_extension().to_string();
+                let ext = texture_compression
+                    .atlas_extension(&exported_ext)
+                    .to_string();
+
+                // The features of the same shape are written as the instances of a shared mesh
+                // (not with the material variants, which may differ between the instances)
+                let instance_plan = match self.instancing
+                    && variant_names.is_empty()
+                    && INSTANCED_TYPES.contains(&typename.as_str())
+                {
+                    true => plan_instances(&features),
+                    false => InstancePlan::default(),
+                };
+                let mut instanced_primitives: Vec<Primitives> = instance_plan
+                    .prototypes
+                    .iter()
+                    .map(|_| Default::default())
+                    .collect();
+
+                // The nodes of the features (with the hierarchy)
+                let mut feature_nodes: Vec<SceneNode> = Vec::new();
 
                 // Obtain the UV coordinates placed in the atlas by specifying the ID
                 //  and apply them to the original polygon.
                 for (feature_id, feature) in features.iter().enumerate() {
+                    feedback.ensure_not_canceled()?;
+
+                    // (an instanced shape is written once, with its origin at the anchor of the prototype)
+                    let prototype = match instance_plan.prototype_of(feature_id) {
+                        Some((_, prototype)) if prototype.feature_id != feature_id => continue,
+                        prototype => prototype.map(|(idx, prototype)| (idx, prototype.anchor)),
+                    };
+                    let mut node_primitives: Vec<Primitives> =
+                        feature.nodes.iter().map(|_| Default::default()).collect();
+
+                    let normals = VertexNormals::new(normal_mode, &feature.polygons);
+
                     for (poly_count, (mut mat, mut poly)) in feature
                         .polygons
                         .iter()
@@ -501,40 +842,16 @@ impl DataSink for GltfSink {
                         })
                         .enumerate()
                     {
-                        let original_vertices = poly
-                            .raw_coords()
-                            .iter()
-                            .map(|[x, y, z, u, v]| (*x, *y, *z, *u, *v))
-                            .collect::<Vec<(f64, f64, f64, f64, f64)>>();
-
                         let texture_id = generate_texture_id(&base_name, feature_id, poly_count);
 
                         if let Some(info) = packed.get_texture_info(&texture_id) {
                             // Place the texture in the atlas
-                            let atlas_placed_uv_coords = info
-                                .placed_uv_coords
-                                .iter()
-                                .map(|(u, v)| ({ *u }, { *v }))
-                                .collect::<Vec<(f64, f64)>>();
-                            let updated_vertices = original_vertices
-                                .iter()
-                                .zip(atlas_placed_uv_coords.iter())
-                                .map(|((x, y, z, _, _), (u, v))| (*x, *y, *z, *u, *v))
-                                .collect::<Vec<(f64, f64, f64, f64, f64)>>();
-
-                            // Apply the UV coordinates placed in the atlas to the original polygon
-                            poly.transform_inplace(|&[x, y, z, _, _]| {
-                                let (u, v) = updated_vertices
-                                    .iter()
-                                    .find(|(x_, y_, z_, _, _)| {
-                                        (*x_ - x).abs() < 1e-6
-                                            && (*y_ - y).abs() < 1e-6
-                                            && (*z_ - z).abs() < 1e-6
-                                    })
-                                    .map(|(_, _, _, u, v)| (*u, *v))
-                                    .unwrap();
-                                [x, y, z, u, v]
-                            });
+                            // (the placed UVs are in the same order as the vertices of the polygon)
+                            debug_assert_eq!(info.placed_uv_coords.len(), poly.raw_coords().len());
+                            poly.zip_transform_inplace(
+                                &info.placed_uv_coords,
+                                |&[x, y, z, _, _], &(u, v)| [x, y, z, u, v],
+                            );
 
                             let atlas_file_name = info.atlas_id.to_string();
 
@@ -543,17 +860,51 @@ impl DataSink for GltfSink {
 
                             // update material
                             mat = material::Material {
-                                base_color: mat.base_color,
                                 base_texture: Some(material::Texture {
-                                    uri: Url::from_file_path(atlas_uri).unwrap(),
+                                    uri: paths::file_url(&atlas_uri).unwrap(),
                                 }),
+                                ..mat
                             };
                         }
 
-                        let primitive = primitives.entry(mat).or_default();
+                        // the materials and the texture coordinates of the variants
+                        let mut variant_mats = Vec::with_capacity(feature.variants.len());
+                        let mut variant_uvs = Vec::with_capacity(feature.variants.len());
+                        for (variant_idx, (_, variant)) in feature.variants.iter().enumerate() {
+                            let mut variant_mat = feature.materials
+                                [variant.polygon_material_ids[poly_count] as usize]
+                                .clone();
+                            let mut uvs = variant.polygon_uvs.get(poly_count).raw_coords().to_vec();
+                            if let Some(info) = packed
+                                .get_texture_info(&variant_texture_id(&texture_id, variant_idx))
+                            {
+                                uvs = info.placed_uv_coords.iter().map(|&(u, v)| [u, v]).collect();
+                                let atlas_uri = atlas_dir
+                                    .join(info.atlas_id.to_string())
+                                    .with_extension(ext.clone());
+                                variant_mat = material::Material {
+                                    base_texture: Some(material::Texture {
+                                        uri: paths::file_url(&atlas_uri).unwrap(),
+                                    }),
+                                    ..variant_mat
+                                };
+                            }
+                            variant_mats.push(variant_mat);
+                            variant_uvs.push(uvs);
+                        }
+
+                        let (primitives, origin, vertex_feature_id) = match prototype {
+                            Some((idx, anchor)) => (&mut instanced_primitives[idx], anchor, 0),
+                            None if self.hierarchy => {
+                                let node_id = feature.polygon_node_ids[poly_count] as usize;
+                                (&mut node_primitives[node_id], [0.0; 3], feature_id)
+                            }
+                            None => (&mut primitives, [0.0; 3], feature_id),
+                        };
+                        let primitive = primitives.entry((mat, variant_mats)).or_default();
                         primitive.feature_ids.insert(feature_id as u32);
 
-                        if let Some((nx, ny, nz)) =
+                        if let Some(polygon_normal) =
                             calculate_normal(poly.exterior().iter().map(|v| [v[0], v[1], v[2]]))
                         {
                             let num_outer_points = match poly.hole_indices().first() {
@@ -579,6 +930,8 @@ impl DataSink for GltfSink {
                                 // collect triangles
                                 primitive.indices.extend(index_buf.iter().map(|&idx| {
                                     let [x, y, z, u, v] = poly.raw_coords()[idx as usize];
+                                    let (nx, ny, nz) = normals.normal([x, y, z], polygon_normal);
+                                    let [x, y, z] = [x - origin[0], y - origin[1], z - origin[2]];
                                     let vbits = [
                                         (x as f32).to_bits(),
                                         (y as f32).to_bits(),
@@ -589,16 +942,45 @@ impl DataSink for GltfSink {
                                         (u as f32).to_bits(),
                                         // flip the texture v-coordinate
                                         ((1.0 - v) as f32).to_bits(),
-                                        (feature_id as f32).to_bits(), // UNSIGNED_INT can't be used for vertex attribute
+                                        (vertex_feature_id as f32).to_bits(), // UNSIGNED_INT can't be used for vertex attribute
                                     ];
-                                    let (index, _) = vertices.insert_full(vbits);
+                                    let variant_vbits = variant_uvs
+                                        .iter()
+                                        .map(|uvs| {
+                                            let [u, v] = uvs[idx as usize];
+                                            [(u as f32).to_bits(), ((1.0 - v) as f32).to_bits()]
+                                        })
+                                        .collect();
+                                    let (index, _) = vertices.insert_full((vbits, variant_vbits));
                                     index as u32
                                 }));
                             }
                         }
                     }
+
+                    if self.hierarchy && prototype.is_none() {
+                        feature_nodes.push(SceneNode::from_feature_nodes(
+                            &feature.nodes,
+                            node_primitives,
+                        ));
+                    }
                 }
 
+                // The root node of the scene (the feature type, with the hierarchy)
+                let scene = match self.hierarchy {
+                    true => SceneNode {
+                        name: Some(typename.clone()),
+                        primitives,
+                        children: feature_nodes,
+                    },
+                    false => SceneNode {
+                        primitives,
+                        ..Default::default()
+                    },
+                };
+
+                feedback.ensure_not_canceled()?;
+
                 // Ensure that the parent directory exists
                 std::fs::create_dir_all(&self.output_path)?;
 
@@ -609,23 +991,63 @@ impl DataSink for GltfSink {
                     config.width,
                     config.height,
                 );
+                compress_atlas_dir(&atlas_dir, &exported_ext, texture_compression)?;
+                texture_usage.add_atlas_dir(&atlas_dir);
+                if texture_mode == TextureMode::External {
+                    // (removed if the features have no textures)
+                    let _ = std::fs::remove_dir(&atlas_dir);
+                }
 
-                // Write glTF (.glb)
+                // Write glTF (.glb, or .gltf and .bin with the external textures)
+                let extension = match texture_mode {
+                    TextureMode::External => "gltf",
+                    TextureMode::Embed | TextureMode::None => "glb",
+                };
                 let file_path = {
-                    let filename = format!("{}.glb", typename.replace(':', "_"));
+                    let filename = format!("{}.{}", base_name, extension);
                     // Save the filename to the content list of the tileset.json (3D Tiles)
                     tileset_content_files.lock().unwrap().push(filename.clone());
 
                     self.output_path.join(filename)
                 };
 
-                let mut file = File::create(file_path)?;
+                let mut file = File::create(&file_path)?;
                 let writer = BufWriter::with_capacity(1024 * 1024, &mut file);
 
-                write_gltf_glb(feedback, writer, vertices, primitives, metadata_encoder)?;
+                let mut bin_file = match texture_mode {
+                    TextureMode::External => Some(BufWriter::new(File::create(
+                        file_path.with_extension("bin"),
+                    )?)),
+                    TextureMode::Embed | TextureMode::None => None,
+                };
+                let external = bin_file.as_mut().map(|bin_writer| ExternalFiles {
+                    bin_uri: format!("{}.bin", base_name),
+                    bin_writer,
+                    base_dir: &self.output_path,
+                });
+
+                write_gltf_glb(
+                    feedback,
+                    writer,
+                    external,
+                    vertices,
+                    scene,
+                    instanced_primitives
+                        .into_iter()
+                        .zip(instance_plan.prototypes)
+                        .map(|(primitives, prototype)| (primitives, prototype.instances))
+                        .collect(),
+                    metadata_encoder,
+                    &variant_names,
+                    mesh_compression,
+                )?;
 
                 Ok::<(), PipelineError>(())
             })?;
+        texture_usage.report(feedback);
+        if texture_mode == TextureMode::External {
+            let _ = std::fs::remove_dir(self.output_path.join(texture_folder_name));
+        }
 
         Ok(())
     }
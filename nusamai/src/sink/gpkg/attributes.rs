@@ -1,55 +1,230 @@
 use indexmap::IndexMap;
-use nusamai_citygml::object::{Object, Value};
+use nusamai_citygml::object::{Object, ObjectStereotype, Value};
+use nusamai_gpkg::table::ColumnValue;
 
-/// Prepare the attribute values for the GeoPackage
-pub fn prepare_object_attributes(obj: &Object) -> IndexMap<String, String> {
-    let mut attributes = IndexMap::<String, String>::new();
+/// A nested data object (e.g. `uro:BuildingDetailAttribute`) written into the attribute table of its type,
+/// and related to the row of the parent with the Related Tables extension
+#[derive(Debug, PartialEq)]
+pub struct RelatedRecord {
+    pub table_name: String,
+    pub attributes: IndexMap<String, Value>,
+    pub children: Vec<RelatedRecord>,
+}
+
+/// Prepare the attribute values for the GeoPackage (the values are converted by `column_values` on insert)
+pub fn prepare_object_attributes(obj: &Object) -> IndexMap<String, Value> {
+    let mut attributes = IndexMap::<String, Value>::new();
 
     for (attr_name, attr_value) in &obj.attributes {
         match attr_value {
-            Value::String(s) => {
-                attributes.insert(attr_name.into(), s.into());
-            }
-            Value::Code(c) => {
-                // value of the code
-                attributes.insert(attr_name.into(), c.value().into());
-            }
-            Value::Integer(i) => {
-                attributes.insert(attr_name.into(), i.to_string());
-            }
-            Value::NonNegativeInteger(i) => {
-                attributes.insert(attr_name.into(), i.to_string());
-            }
-            Value::Double(d) => {
-                attributes.insert(attr_name.into(), d.to_string());
-            }
-            Value::Measure(m) => {
-                attributes.insert(attr_name.into(), m.value().to_string());
-            }
-            Value::Boolean(b) => {
-                // 0 for false and 1 for true in SQLite
-                attributes.insert(attr_name.into(), if *b { "1".into() } else { "0".into() });
-            }
-            Value::Uri(u) => {
-                // value of the URI
-                attributes.insert(attr_name.into(), u.value().to_string());
-            }
-            Value::Date(d) => {
-                // Date represented as an ISO8601 string
-                attributes.insert(attr_name.into(), d.to_string());
-            }
-            Value::Point(_p) => {
-                // TODO: implement
-                // Point struct currently does not contain any data
-            }
             Value::Array(_arr) => {
                 // TODO: handle multiple values
             }
             Value::Object(_obj) => {
                 // TODO: handle nested objects
             }
+            _ => {
+                attributes.insert(attr_name.into(), attr_value.clone());
+            }
         };
     }
 
     attributes
 }
+
+/// Convert the attribute values into the values bound to the columns, keeping the numbers and the booleans
+pub fn column_values(attributes: &IndexMap<String, Value>) -> IndexMap<String, ColumnValue> {
+    attributes
+        .iter()
+        .map(|(name, value)| (name.clone(), column_value(value)))
+        .collect()
+}
+
+fn column_value(value: &Value) -> ColumnValue {
+    match value {
+        Value::String(s) => ColumnValue::Text(s.clone()),
+        // value of the code
+        Value::Code(c) => ColumnValue::Text(c.value().into()),
+        Value::Integer(i) => ColumnValue::Integer(*i),
+        Value::NonNegativeInteger(i) => match i64::try_from(*i) {
+            Ok(i) => ColumnValue::Integer(i),
+            Err(_) => ColumnValue::Text(i.to_string()),
+        },
+        Value::Double(d) => ColumnValue::Real(*d),
+        Value::Measure(m) => ColumnValue::Real(m.value()),
+        // 0 for false and 1 for true in SQLite
+        Value::Boolean(b) => ColumnValue::Boolean(*b),
+        // value of the URI
+        Value::Uri(u) => ColumnValue::Text(u.value().to_string()),
+        // Date represented as an ISO8601 string
+        Value::Date(d) => ColumnValue::Text(d.to_string()),
+        // GeoJSON geometry, or JSON of the nested values
+        Value::Point(_) | Value::Array(_) | Value::Object(_) => {
+            ColumnValue::Text(value.to_attribute_json().to_string())
+        }
+    }
+}
+
+/// Prepare the attribute values for the GeoPackage, separating the nested data objects as related records.
+///
+/// The other nested values (e.g. the arrays of the codes, the generic attributes) are stored as JSON.
+pub fn prepare_related_attributes(obj: &Object) -> (IndexMap<String, Value>, Vec<RelatedRecord>) {
+    let mut attributes = prepare_object_attributes(obj);
+    let mut children = Vec::new();
+
+    for (attr_name, attr_value) in &obj.attributes {
+        match attr_value {
+            Value::Object(child) if is_related(child) => {
+                children.push(related_record(child));
+            }
+            Value::Array(arr)
+                if !arr.is_empty()
+                    && arr
+                        .iter()
+                        .all(|v| matches!(v, Value::Object(child) if is_related(child))) =>
+            {
+                children.extend(arr.iter().filter_map(|v| match v {
+                    Value::Object(child) => Some(related_record(child)),
+                    _ => None,
+                }));
+            }
+            Value::Object(_) | Value::Array(_) => {
+                let json = attr_value.to_attribute_json().to_string();
+                attributes.insert(attr_name.into(), Value::String(json));
+            }
+            _ => {}
+        }
+    }
+
+    (attributes, children)
+}
+
+/// Whether the nested object is written as a related record.
+///
+/// The generic attributes are excluded since their attributes are not known in advance (no table can be created).
+fn is_related(obj: &Object) -> bool {
+    matches!(obj.stereotype, ObjectStereotype::Data) && obj.typename != "gen:genericAttribute"
+}
+
+fn related_record(obj: &Object) -> RelatedRecord {
+    let (attributes, children) = prepare_related_attributes(obj);
+    RelatedRecord {
+        table_name: obj.typename.to_string(),
+        attributes,
+        children,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::{object::Map, values::Code};
+
+    use super::*;
+
+    fn data(typename: &'static str, attributes: Map) -> Value {
+        Value::Object(Object {
+            typename: typename.into(),
+            stereotype: ObjectStereotype::Data,
+            attributes,
+        })
+    }
+
+    #[test]
+    fn test_column_values() {
+        let mut attributes = Map::default();
+        attributes.insert("name".into(), Value::String("1".into()));
+        attributes.insert("storeys".into(), Value::NonNegativeInteger(3));
+        attributes.insert("offset".into(), Value::Integer(-2));
+        attributes.insert("height".into(), Value::Double(12.5));
+        attributes.insert("flag".into(), Value::Boolean(true));
+        attributes.insert(
+            "usage".into(),
+            Value::Code(Code::new("業務施設".into(), "401".into())),
+        );
+        attributes.insert("codes".into(), Value::Array(vec![Value::Integer(1)]));
+        let obj = Object {
+            typename: "bldg:Building".into(),
+            stereotype: ObjectStereotype::Data,
+            attributes,
+        };
+
+        let values = column_values(&prepare_object_attributes(&obj));
+        assert_eq!(values["name"], ColumnValue::Text("1".into()));
+        assert_eq!(values["storeys"], ColumnValue::Integer(3));
+        assert_eq!(values["offset"], ColumnValue::Integer(-2));
+        assert_eq!(values["height"], ColumnValue::Real(12.5));
+        assert_eq!(values["flag"], ColumnValue::Boolean(true));
+        assert_eq!(values["usage"], ColumnValue::Text("業務施設".into()));
+        // (not handled without `related_tables`)
+        assert!(!values.contains_key("codes"));
+        assert_eq!(
+            column_value(&Value::NonNegativeInteger(u64::MAX)),
+            ColumnValue::Text(u64::MAX.to_string())
+        );
+    }
+
+    #[test]
+    fn test_prepare_related_attributes() {
+        let mut detail = Map::default();
+        detail.insert("uro:surveyYear".into(), Value::String("2020".into()));
+        detail.insert(
+            "uro:fireproofStructureType".into(),
+            Value::Code(Code::new("耐火".into(), "1001".into())),
+        );
+        let mut generic = Map::default();
+        generic.insert("note".into(), Value::String("a".into()));
+
+        let mut attributes = Map::default();
+        attributes.insert("gml:name".into(), Value::String("building".into()));
+        attributes.insert(
+            "bldg:usage".into(),
+            Value::Array(vec![Value::Code(Code::new(
+                "業務施設".into(),
+                "401".into(),
+            ))]),
+        );
+        attributes.insert(
+            "uro:buildingDetailAttribute".into(),
+            Value::Array(vec![
+                data("uro:BuildingDetailAttribute", detail.clone()),
+                data("uro:BuildingDetailAttribute", detail),
+            ]),
+        );
+        attributes.insert(
+            "gen:genericAttribute".into(),
+            data("gen:genericAttribute", generic),
+        );
+        let obj = Object {
+            typename: "bldg:Building".into(),
+            stereotype: ObjectStereotype::Feature {
+                id: "bldg_1".into(),
+                geometries: Default::default(),
+            },
+            attributes,
+        };
+
+        let (attributes, children) = prepare_related_attributes(&obj);
+        let attributes = column_values(&attributes);
+        assert_eq!(attributes["gml:name"], ColumnValue::from("building"));
+        assert_eq!(
+            attributes["bldg:usage"],
+            ColumnValue::from(r#"["業務施設"]"#)
+        );
+        let ColumnValue::Text(generic) = &attributes["gen:genericAttribute"] else {
+            unreachable!();
+        };
+        let generic: serde_json::Value = serde_json::from_str(generic).unwrap();
+        assert_eq!(generic["note"], "a");
+        assert!(!attributes.contains_key("uro:buildingDetailAttribute"));
+
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[0].table_name, "uro:BuildingDetailAttribute");
+        let detail = column_values(&children[0].attributes);
+        assert_eq!(detail["uro:surveyYear"], ColumnValue::from("2020"));
+        assert_eq!(
+            detail["uro:fireproofStructureType"],
+            ColumnValue::from("耐火")
+        );
+        assert!(children[0].children.is_empty());
+    }
+}
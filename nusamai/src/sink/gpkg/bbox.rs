@@ -1,4 +1,4 @@
-use flatgeom::MultiPolygon;
+use flatgeom::{MultiLineString, MultiPoint, MultiPolygon};
 
 pub struct Bbox {
     min_x: f64,
@@ -19,6 +19,16 @@ impl Default for Bbox {
 }
 
 impl Bbox {
+    /// From a tuple (min_x, min_y, max_x, max_y)
+    pub fn from_tuple((min_x, min_y, max_x, max_y): (f64, f64, f64, f64)) -> Self {
+        Bbox {
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+        }
+    }
+
     /// To a tuple (min_x, min_y, max_x, max_y)
     pub fn to_tuple(&self) -> (f64, f64, f64, f64) {
         (self.min_x, self.min_y, self.max_x, self.max_y)
@@ -54,6 +64,30 @@ pub fn get_indexed_multipolygon_bbox(vertices: &[[f64; 3]], mpoly: &MultiPolygon
     bbox
 }
 
+// Get Bounding box of a MultiLineString
+pub fn get_indexed_multilinestring_bbox(vertices: &[[f64; 3]], mls: &MultiLineString<u32>) -> Bbox {
+    let mut bbox: Bbox = Default::default();
+
+    for ls in mls.iter() {
+        for point_idx in ls.iter() {
+            let [x, y, _z] = vertices[point_idx as usize];
+            bbox.update(x, y);
+        }
+    }
+    bbox
+}
+
+// Get Bounding box of a MultiPoint
+pub fn get_indexed_multipoint_bbox(vertices: &[[f64; 3]], mpoint: &MultiPoint<u32>) -> Bbox {
+    let mut bbox: Bbox = Default::default();
+
+    for point_idx in mpoint.iter() {
+        let [x, y, _z] = vertices[point_idx as usize];
+        bbox.update(x, y);
+    }
+    bbox
+}
+
 #[cfg(test)]
 mod tests {
     use nusamai_projection::crs::EPSG_JGD2011_GEOGRAPHIC_3D;
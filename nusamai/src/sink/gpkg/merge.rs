@@ -0,0 +1,386 @@
+//! Merging of the GeoPackages written by the shards of a conversion (`--shard`)
+//!
+//! The first GeoPackage is copied to the output (by `VACUUM INTO`), and the rows of the others are appended to it
+//! table by table. The primary keys (`fid` of the features and `id` of the attributes) are shifted not to collide, together with
+//! the keys referring to them (the mapping tables of the Related Tables extension and `feature_sources`).
+//! The metadata (provenance) of the first GeoPackage is kept.
+
+use std::{collections::HashMap, path::Path};
+
+use sqlx::{sqlite::SqliteConnectOptions, Connection, SqliteConnection};
+
+use super::table::FEATURE_SOURCES_TABLE_NAME;
+use crate::pipeline::{PipelineError, Result};
+
+const LAYER_STYLES_TABLE_NAME: &str = "layer_styles";
+
+/// Merges the GeoPackages into a new GeoPackage
+pub fn merge_geopackages(inputs: &[&Path], output: &Path) -> Result<()> {
+    let Some((first, rest)) = inputs.split_first() else {
+        return Err(PipelineError::Other("No GeoPackages to merge".into()));
+    };
+    if output.exists() {
+        return Err(PipelineError::Other(format!(
+            "The output already exists: {:?}",
+            output
+        )));
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime
+        .block_on(async {
+            // (copied with the changes in the WAL file of the first one)
+            let options = SqliteConnectOptions::new().filename(first).read_only(true);
+            let mut conn = SqliteConnection::connect_with(&options).await?;
+            sqlx::query("VACUUM INTO ?;")
+                .bind(output.to_string_lossy())
+                .execute(&mut conn)
+                .await?;
+            conn.close().await?;
+
+            let options = SqliteConnectOptions::new().filename(output);
+            let mut conn = SqliteConnection::connect_with(&options).await?;
+            for input in rest {
+                sqlx::query("ATTACH DATABASE ? AS shard;")
+                    .bind(input.to_string_lossy())
+                    .execute(&mut conn)
+                    .await?;
+                let mut tx = conn.begin().await?;
+                append_shard(&mut *tx).await?;
+                tx.commit().await?;
+                sqlx::query("DETACH DATABASE shard;")
+                    .execute(&mut conn)
+                    .await?;
+            }
+            conn.close().await
+        })
+        .map_err(|err| PipelineError::Other(format!("Failed to merge the GeoPackages: {err}")))
+}
+
+/// Appends the tables of the attached `shard` database to the main database
+async fn append_shard(conn: &mut SqliteConnection) -> std::result::Result<(), sqlx::Error> {
+    // (the views are created after the tables)
+    let tables: Vec<String> = sqlx::query_scalar(
+        "SELECT c.table_name FROM shard.gpkg_contents AS c JOIN shard.sqlite_master AS m ON m.name = c.table_name \
+         ORDER BY m.type = 'view', c.table_name;",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let relations = relations(conn).await?;
+
+    // The offsets of the primary keys are determined before any rows are appended
+    let mut primary_keys = HashMap::new();
+    let mut offsets = HashMap::new();
+    for table in &tables {
+        let Some(pk) = primary_key(conn, table).await? else {
+            continue;
+        };
+        let offset: i64 = match has_table(conn, "main", table).await? {
+            true => {
+                sqlx::query_scalar(&format!(
+                    "SELECT COALESCE(MAX(\"{pk}\"), 0) FROM main.\"{table}\";"
+                ))
+                .fetch_one(&mut *conn)
+                .await?
+            }
+            false => 0,
+        };
+        offsets.insert(table.clone(), offset);
+        primary_keys.insert(table.clone(), pk);
+    }
+
+    for table in &tables {
+        if !has_table(conn, "main", table).await? {
+            create_table(conn, table).await?;
+        } else {
+            extend_extent(conn, table).await?;
+        }
+
+        let is_view: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM shard.sqlite_master WHERE name = ? AND type = 'view');",
+        )
+        .bind(table)
+        .fetch_one(&mut *conn)
+        .await?;
+        if is_view {
+            continue;
+        }
+
+        let columns: Vec<String> = sqlx::query_scalar(&format!(
+            "SELECT name FROM pragma_table_info('{}', 'shard');",
+            table.replace('\'', "''")
+        ))
+        .fetch_all(&mut *conn)
+        .await?;
+        let offset_of = |table: &str| offsets.get(table).copied().unwrap_or(0);
+        let expressions: Vec<String> = columns
+            .iter()
+            .map(|column| {
+                let shifted = |offset: i64| format!("\"{column}\" + {offset}");
+                if primary_keys.get(table) == Some(column) {
+                    shifted(offset_of(table))
+                } else if let Some((base, related)) = relations.get(table) {
+                    match column.as_str() {
+                        "base_id" => shifted(offset_of(base)),
+                        "related_id" => shifted(offset_of(related)),
+                        _ => format!("\"{column}\""),
+                    }
+                } else if table == FEATURE_SOURCES_TABLE_NAME && column == "fid" {
+                    let cases: String = offsets
+                        .iter()
+                        .map(|(name, offset)| {
+                            format!(" WHEN '{}' THEN {offset}", name.replace('\'', "''"))
+                        })
+                        .collect();
+                    format!("\"fid\" + CASE \"table_name\"{cases} ELSE 0 END")
+                } else {
+                    format!("\"{column}\"")
+                }
+            })
+            .collect();
+        let column_list = columns
+            .iter()
+            .map(|column| format!("\"{column}\""))
+            .collect::<Vec<_>>()
+            .join(", ");
+        // (the default styles of the same tables are not duplicated)
+        let filter = match table.as_str() {
+            LAYER_STYLES_TABLE_NAME => {
+                " WHERE NOT EXISTS (SELECT 1 FROM main.layer_styles AS m \
+                 WHERE m.f_table_name = s.f_table_name AND m.styleName = s.styleName)"
+            }
+            _ => "",
+        };
+        sqlx::query(&format!(
+            "INSERT INTO main.\"{table}\" ({column_list}) SELECT {} FROM shard.\"{table}\" AS s{filter};",
+            expressions.join(", ")
+        ))
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// The base and the related tables of the mapping tables of the Related Tables extension
+async fn relations(
+    conn: &mut SqliteConnection,
+) -> std::result::Result<HashMap<String, (String, String)>, sqlx::Error> {
+    if !has_table(conn, "shard", "gpkgext_relations").await? {
+        return Ok(HashMap::new());
+    }
+    let rows: Vec<(String, String, String)> = sqlx::query_as(
+        "SELECT mapping_table_name, base_table_name, related_table_name FROM shard.gpkgext_relations;",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(mapping, base, related)| (mapping, (base, related)))
+        .collect())
+}
+
+async fn has_table(
+    conn: &mut SqliteConnection,
+    schema: &str,
+    name: &str,
+) -> std::result::Result<bool, sqlx::Error> {
+    sqlx::query_scalar(&format!(
+        "SELECT EXISTS (SELECT 1 FROM {schema}.sqlite_master WHERE name = ?);"
+    ))
+    .bind(name)
+    .fetch_one(&mut *conn)
+    .await
+}
+
+/// The integer primary key of the table in the shard (None for the views and the mapping tables)
+async fn primary_key(
+    conn: &mut SqliteConnection,
+    table: &str,
+) -> std::result::Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(&format!(
+        "SELECT name FROM pragma_table_info('{}', 'shard') WHERE pk = 1 AND type = 'INTEGER';",
+        table.replace('\'', "''")
+    ))
+    .fetch_optional(&mut *conn)
+    .await
+}
+
+/// Creates the table (or the view) of the shard in the main database, and registers it as in the shard
+async fn create_table(
+    conn: &mut SqliteConnection,
+    table: &str,
+) -> std::result::Result<(), sqlx::Error> {
+    let sql: String = sqlx::query_scalar("SELECT sql FROM shard.sqlite_master WHERE name = ?;")
+        .bind(table)
+        .fetch_one(&mut *conn)
+        .await?;
+    // (the unqualified statement creates the table in the main database)
+    sqlx::query(&sql).execute(&mut *conn).await?;
+
+    sqlx::query(
+        "INSERT INTO main.gpkg_contents SELECT * FROM shard.gpkg_contents WHERE table_name = ?;",
+    )
+    .bind(table)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        "INSERT OR IGNORE INTO main.gpkg_geometry_columns SELECT * FROM shard.gpkg_geometry_columns WHERE table_name = ?;",
+    )
+    .bind(table)
+    .execute(&mut *conn)
+    .await?;
+    for registry in ["gpkg_data_columns", "gpkg_extensions", "gpkgext_relations"] {
+        if !has_table(conn, "shard", registry).await? {
+            continue;
+        }
+        if !has_table(conn, "main", registry).await? {
+            let sql: String =
+                sqlx::query_scalar("SELECT sql FROM shard.sqlite_master WHERE name = ?;")
+                    .bind(registry)
+                    .fetch_one(&mut *conn)
+                    .await?;
+            sqlx::query(&sql).execute(&mut *conn).await?;
+        }
+        let key = match registry {
+            "gpkgext_relations" => "mapping_table_name",
+            _ => "table_name",
+        };
+        let columns: Vec<String> = sqlx::query_scalar(&format!(
+            "SELECT name FROM pragma_table_info('{registry}', 'shard') WHERE pk = 0;"
+        ))
+        .fetch_all(&mut *conn)
+        .await?;
+        let columns = columns
+            .iter()
+            .map(|column| format!("\"{column}\""))
+            .collect::<Vec<_>>()
+            .join(", ");
+        sqlx::query(&format!(
+            "INSERT OR IGNORE INTO main.{registry} ({columns}) SELECT {columns} FROM shard.{registry} WHERE {key} = ?;"
+        ))
+        .bind(table)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Extends the extent of the table in `gpkg_contents` with the extent in the shard
+async fn extend_extent(
+    conn: &mut SqliteConnection,
+    table: &str,
+) -> std::result::Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE main.gpkg_contents AS m SET \
+         min_x = MIN(COALESCE(m.min_x, s.min_x), COALESCE(s.min_x, m.min_x)), \
+         min_y = MIN(COALESCE(m.min_y, s.min_y), COALESCE(s.min_y, m.min_y)), \
+         max_x = MAX(COALESCE(m.max_x, s.max_x), COALESCE(s.max_x, m.max_x)), \
+         max_y = MAX(COALESCE(m.max_y, s.max_y), COALESCE(s.max_y, m.max_y)) \
+         FROM shard.gpkg_contents AS s WHERE m.table_name = s.table_name AND m.table_name = ?;",
+    )
+    .bind(table)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use nusamai_gpkg::{
+        table::{ColumnValue, TableInfo},
+        GpkgHandler,
+    };
+    use sqlx::Row;
+    use url::Url;
+
+    use super::*;
+    use crate::sink::gpkg::table::feature_sources_table_info;
+
+    async fn write_shard(path: &Path, ids: &[&str], bbox: (f64, f64, f64, f64)) {
+        let url = Url::parse(&format!("sqlite://{}", path.to_str().unwrap())).unwrap();
+        let mut handler = GpkgHandler::from_url(&url).await.unwrap();
+        let table_info = TableInfo {
+            name: "building".into(),
+            has_geometry: true,
+            columns: vec![],
+        };
+        let mut tx = handler.begin().await.unwrap();
+        tx.add_table(&table_info, 4326).await.unwrap();
+        tx.add_table(&feature_sources_table_info(), 4326)
+            .await
+            .unwrap();
+        for id in ids {
+            let fid = tx
+                .insert_feature(
+                    "building",
+                    id,
+                    &[0, 1, 2, 3],
+                    &IndexMap::<String, String>::new(),
+                )
+                .await
+                .unwrap();
+            tx.insert_attribute(
+                FEATURE_SOURCES_TABLE_NAME,
+                &IndexMap::<String, ColumnValue>::from([
+                    ("table_name".into(), "building".to_string().into()),
+                    ("fid".into(), ColumnValue::Integer(fid)),
+                    ("gml_id".into(), id.to_string().into()),
+                    ("source".into(), "a.gml".to_string().into()),
+                ]),
+            )
+            .await
+            .unwrap();
+        }
+        tx.update_bbox("building", bbox).await.unwrap();
+        tx.commit().await.unwrap();
+    }
+
+    #[test]
+    fn test_merge_geopackages() {
+        let dir = tempfile::tempdir().unwrap();
+        let shards = [dir.path().join("0.gpkg"), dir.path().join("1.gpkg")];
+        let output = dir.path().join("merged.gpkg");
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            write_shard(&shards[0], &["a", "b"], (0.0, 0.0, 1.0, 1.0)).await;
+            write_shard(&shards[1], &["c"], (0.5, -1.0, 2.0, 0.5)).await;
+        });
+
+        merge_geopackages(&[&shards[0], &shards[1]], &output).unwrap();
+
+        runtime.block_on(async {
+            let handler = GpkgHandler::open_str(&format!("sqlite://{}", output.to_str().unwrap()))
+                .await
+                .unwrap();
+            let rows = handler.fetch_rows("building").await.unwrap();
+            let features: Vec<(i64, String)> = rows
+                .iter()
+                .map(|row| (row.get("fid"), row.get("id")))
+                .collect();
+            assert_eq!(
+                features,
+                vec![(1, "a".into()), (2, "b".into()), (3, "c".into())]
+            );
+
+            // the fids in `feature_sources` follow the shifted fids
+            let rows = handler
+                .fetch_rows(FEATURE_SOURCES_TABLE_NAME)
+                .await
+                .unwrap();
+            let sources: Vec<(i64, String)> = rows
+                .iter()
+                .map(|row| (row.get("fid"), row.get("gml_id")))
+                .collect();
+            assert_eq!(sources, features);
+
+            assert_eq!(
+                handler.bbox("building").await.unwrap(),
+                (0.0, -1.0, 2.0, 1.0)
+            );
+        });
+    }
+}
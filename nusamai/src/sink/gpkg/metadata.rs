@@ -0,0 +1,201 @@
+//! Dataset information written into the `gpkg_metadata` table
+//!
+//! The source files (with their CityGML versions), the codelists referred by the code values and the
+//! envelope of the features are recorded as a JSON document referenced from the whole GeoPackage.
+
+use std::{
+    collections::BTreeSet,
+    io::Read,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use nusamai_citygml::object::Value;
+use serde_json::json;
+
+use super::bbox::Bbox;
+use crate::{pipeline::Parcel, sink::trace::source_path};
+
+/// `md_standard_uri` of the document (the format is defined by this tool)
+pub const METADATA_STANDARD_URI: &str = "https://github.com/MIERUNE/PLATEAU-GIS-Converter";
+pub const METADATA_MIME_TYPE: &str = "application/json";
+
+/// Number of bytes read from the head of a source file to find the CityGML namespace
+const HEAD_SIZE: usize = 8192;
+
+/// Source files and codelists of the features
+#[derive(Default)]
+pub struct Provenance {
+    sources: BTreeSet<String>,
+    codelists: BTreeSet<String>,
+}
+
+impl Provenance {
+    pub fn add(&mut self, parcel: &Parcel) {
+        let entity = &parcel.entity;
+        self.sources.insert(source_path(&entity.base_url));
+        collect_code_spaces(&entity.root, &mut self.codelists);
+    }
+
+    pub fn merge(&mut self, other: Provenance) {
+        self.sources.extend(other.sources);
+        self.codelists.extend(other.codelists);
+    }
+
+    /// The JSON document with the envelope of the whole dataset
+    pub fn to_document(&self, srs_id: u16, envelope: Option<&Bbox>) -> String {
+        let sources: Vec<_> = self
+            .sources
+            .iter()
+            .map(|path| {
+                json!({
+                    "path": path,
+                    "citygml_version": citygml_version(Path::new(path)),
+                })
+            })
+            .collect();
+        let envelope = envelope.map(|bbox| {
+            let (min_x, min_y, max_x, max_y) = bbox.to_tuple();
+            [min_x, min_y, max_x, max_y]
+        });
+
+        json!({
+            "generator": format!("nusamai {}", env!("CARGO_PKG_VERSION")),
+            "sources": sources,
+            "codelists": self.codelists,
+            "srs_id": srs_id,
+            "envelope": envelope,
+        })
+        .to_string()
+    }
+}
+
+/// Collects the provenance of a producer thread and merges it into the shared one when it is dropped
+pub struct ProvenanceCollector {
+    shared: Arc<Mutex<Provenance>>,
+    local: Provenance,
+}
+
+impl ProvenanceCollector {
+    pub fn new(shared: Arc<Mutex<Provenance>>) -> Self {
+        Self {
+            shared,
+            local: Provenance::default(),
+        }
+    }
+
+    pub fn add(&mut self, parcel: &Parcel) {
+        self.local.add(parcel);
+    }
+}
+
+impl Drop for ProvenanceCollector {
+    fn drop(&mut self) {
+        let local = std::mem::take(&mut self.local);
+        self.shared.lock().unwrap().merge(local);
+    }
+}
+
+fn collect_code_spaces(value: &Value, code_spaces: &mut BTreeSet<String>) {
+    match value {
+        Value::Code(code) => {
+            if let Some(code_space) = code.code_space() {
+                if !code_spaces.contains(code_space) {
+                    code_spaces.insert(code_space.to_string());
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_code_spaces(value, code_spaces);
+            }
+        }
+        Value::Object(obj) => {
+            for value in obj.attributes.values() {
+                collect_code_spaces(value, code_spaces);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// CityGML version of a local source file, found by the namespace declared in the root element
+fn citygml_version(path: &Path) -> Option<&'static str> {
+    let file = std::fs::File::open(crate::paths::extended(path)).ok()?;
+    let mut head = Vec::with_capacity(HEAD_SIZE);
+    file.take(HEAD_SIZE as u64).read_to_end(&mut head).ok()?;
+    let head = String::from_utf8_lossy(&head);
+    ["3.0", "2.0", "1.0"]
+        .into_iter()
+        .find(|version| head.contains(&format!("http://www.opengis.net/citygml/{version}\"")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use nusamai_citygml::{
+        object::{Map, Object, ObjectStereotype},
+        values::Code,
+    };
+    use nusamai_plateau::Entity;
+    use url::Url;
+
+    use super::*;
+
+    #[test]
+    fn test_provenance_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("53394525_bldg_6697_op.gml");
+        std::fs::write(
+            &path,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<core:CityModel xmlns:core="http://www.opengis.net/citygml/2.0"></core:CityModel>"#,
+        )
+        .unwrap();
+
+        let mut attributes = Map::default();
+        attributes.insert(
+            "bldg:usage".into(),
+            Value::Array(vec![Value::Code(
+                Code::new("業務施設".into(), "401".into())
+                    .with_code_space(Some("../../codelists/Building_usage.xml".into())),
+            )]),
+        );
+        let parcel = Parcel {
+            entity: Entity {
+                root: Value::Object(Object {
+                    typename: Cow::Borrowed("bldg:Building"),
+                    stereotype: ObjectStereotype::Feature {
+                        id: "bldg_1".into(),
+                        geometries: Default::default(),
+                    },
+                    attributes,
+                }),
+                base_url: Url::from_file_path(&path).unwrap(),
+                geometry_store: Default::default(),
+                appearance_store: Default::default(),
+            },
+        };
+
+        let shared = Arc::new(Mutex::new(Provenance::default()));
+        {
+            let mut collector = ProvenanceCollector::new(shared.clone());
+            collector.add(&parcel);
+            collector.add(&parcel);
+        }
+
+        let provenance = shared.lock().unwrap();
+        let bbox = Bbox::from_tuple((139.0, 35.0, 140.0, 36.0));
+        let document: serde_json::Value =
+            serde_json::from_str(&provenance.to_document(6697, Some(&bbox))).unwrap();
+        assert_eq!(document["sources"].as_array().unwrap().len(), 1);
+        assert_eq!(document["sources"][0]["citygml_version"], "2.0");
+        assert_eq!(
+            document["codelists"],
+            json!(["../../codelists/Building_usage.xml"])
+        );
+        assert_eq!(document["srs_id"], 6697);
+        assert_eq!(document["envelope"], json!([139.0, 35.0, 140.0, 36.0]));
+    }
+}
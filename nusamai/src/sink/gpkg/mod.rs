@@ -2,22 +2,47 @@
 
 mod attributes;
 mod bbox;
+pub mod merge;
+mod metadata;
+mod style;
 mod table;
+mod view;
 
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
-use attributes::prepare_object_attributes;
-use bbox::{get_indexed_multipolygon_bbox, Bbox};
-use indexmap::IndexMap;
+use attributes::{
+    column_values, prepare_object_attributes, prepare_related_attributes, RelatedRecord,
+};
+use bbox::{
+    get_indexed_multilinestring_bbox, get_indexed_multipoint_bbox, get_indexed_multipolygon_bbox,
+    Bbox,
+};
+use indexmap::{IndexMap, IndexSet};
+use metadata::{Provenance, ProvenanceCollector, METADATA_MIME_TYPE, METADATA_STANDARD_URI};
 use nusamai_citygml::{
     object::{ObjectStereotype, Value},
     schema::Schema,
     GeometryType,
 };
-use nusamai_gpkg::{geometry::write_indexed_multipolygon, GpkgHandler};
+use nusamai_gpkg::{
+    geometry::{
+        write_indexed_multilinestring, write_indexed_multipoint, write_indexed_multipolygon,
+    },
+    table::ColumnValue,
+    GpkgHandler,
+};
 use rayon::prelude::*;
-use table::schema_to_table_infos;
+use table::{
+    add_lod_columns, feature_sources_table_info, lod_table_suffix, renamed_table_info,
+    schema_to_related_table_infos, schema_to_table_infos, strip_lod_suffix,
+    FEATURE_SOURCES_TABLE_NAME, LINES_TABLE_SUFFIX, LOD_COLUMN_NAME, POINTS_TABLE_SUFFIX,
+};
 use url::Url;
+use view::{joined_views, meshcode, meshcode_table_info, meshcode_views, MESHCODE_TABLE_NAME};
 
 use crate::{
     get_parameter_value,
@@ -25,10 +50,20 @@ use crate::{
     pipeline::{Feedback, PipelineError, Receiver, Result},
     sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
     transformer,
-    transformer::{use_lod_config, TransformerSettings},
+    transformer::{
+        building_adjacency_config, code_values_config, large_attribute_limit_config,
+        large_attributes_config, name_columns_config, prefix_config, solar_attributes_config,
+        surface_class_config, underground_config, use_lod_config, LodFilterMode,
+        TransformerSettings,
+    },
 };
 
-use super::option::output_parameter;
+use super::{
+    option::{output_parameter, style_parameter},
+    output::remove_on_cancel,
+    style::{resolve_attribute_name, StyleProfile},
+    trace::{source_path, trace_parameter},
+};
 
 pub struct GpkgSinkProvider {}
 
@@ -43,13 +78,101 @@ impl DataSinkProvider for GpkgSinkProvider {
     fn sink_options(&self) -> Parameters {
         let mut params = Parameters::new();
         params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "sql_views".into(),
+            entry: ParameterEntry {
+                description:
+                    "Create SQL views for common analyses (joined attributes, per-meshcode counts)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("分析用のビューを作成する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "update".into(),
+            entry: ParameterEntry {
+                description:
+                    "Update the existing GeoPackage, replacing the features with the same IDs"
+                        .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("既存のファイルを更新する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "append".into(),
+            entry: ParameterEntry {
+                description:
+                    "Append to the existing GeoPackage, skipping the features whose IDs are already in it"
+                        .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("既存のファイルに追記する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "lod_layers".into(),
+            entry: ParameterEntry {
+                description: "Write the geometries of each LOD into a separate table (e.g. bldg:Building_lod2)"
+                    .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("LODごとにテーブルを分けて出力する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "related_tables".into(),
+            entry: ParameterEntry {
+                description: "Write the nested attributes (e.g. uro:BuildingDetailAttribute) into the related tables instead of JSON"
+                    .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("入れ子の属性を関連テーブルとして出力する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "qgis_styles".into(),
+            entry: ParameterEntry {
+                description: "Write the default styles (QML and SLD) of the feature tables into layer_styles, with the built-in profile if no style is specified"
+                    .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("QGIS用のスタイルを埋め込む".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "commit_interval".into(),
+            entry: ParameterEntry {
+                description: "Commit the transaction every N features to limit the growth of the WAL and the memory (0: commit once at the end)"
+                    .into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: Some(DEFAULT_COMMIT_INTERVAL as i64),
+                    min: Some(0),
+                    max: None,
+                }),
+                label: Some("コミットする地物数の間隔（0: 最後に一括）".into()),
+            },
+        });
+        params.define(style_parameter());
+        params.define(trace_parameter());
 
         params
     }
 
     fn transformer_options(&self) -> TransformerSettings {
         let mut settings: TransformerSettings = TransformerSettings::new();
-        settings.insert(use_lod_config("max_lod", None));
+        settings.insert(use_lod_config("max_lod", Some(&["all_lod"])));
+        settings.insert(underground_config());
+        settings.insert(surface_class_config());
+        settings.insert(solar_attributes_config());
+        settings.insert(building_adjacency_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
+        settings.insert(code_values_config());
+        settings.insert(large_attributes_config());
+        settings.insert(large_attribute_limit_config("1048576"));
 
         settings
     }
@@ -57,10 +180,30 @@ impl DataSinkProvider for GpkgSinkProvider {
     fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
         let output_path = get_parameter_value!(params, "@output", FileSystemPath);
         let transform_settings = self.transformer_options();
+        let sql_views = get_parameter_value!(params, "sql_views", Boolean).unwrap();
+        let update = get_parameter_value!(params, "update", Boolean).unwrap();
+        let append = get_parameter_value!(params, "append", Boolean).unwrap();
+        let lod_layers = get_parameter_value!(params, "lod_layers", Boolean).unwrap();
+        let related_tables = get_parameter_value!(params, "related_tables", Boolean).unwrap();
+        let qgis_styles = get_parameter_value!(params, "qgis_styles", Boolean).unwrap();
+        let commit_interval =
+            get_parameter_value!(params, "commit_interval", Integer).unwrap() as usize;
+        let style_path = get_parameter_value!(params, "style", FileSystemPath);
+        let trace = get_parameter_value!(params, "trace", Boolean).unwrap();
 
         Box::<GpkgSink>::new(GpkgSink {
             output_path: output_path.as_ref().unwrap().into(),
             transform_settings,
+            sql_views,
+            update,
+            append,
+            lod_layers,
+            all_lods: false,
+            related_tables,
+            qgis_styles,
+            commit_interval,
+            style_path: style_path.clone(),
+            trace,
         })
     }
 }
@@ -68,6 +211,123 @@ impl DataSinkProvider for GpkgSinkProvider {
 pub struct GpkgSink {
     output_path: PathBuf,
     transform_settings: TransformerSettings,
+    /// Create convenience views (see the `view` module)
+    sql_views: bool,
+    /// Update the existing database instead of recreating it.
+    ///
+    /// The features with the same IDs as the incoming ones are deleted together with their attribute records.
+    /// Note that the extents of the tables are only expanded.
+    update: bool,
+    /// Append to the existing database, keeping the features already in it (takes precedence over `update`).
+    ///
+    /// The incoming features with the same IDs as the existing ones are skipped together with their attribute records,
+    /// e.g. to convert the cities of a prefecture one by one into a database.
+    append: bool,
+    /// Write the geometries of each LOD into a separate table (`{typename}_lod{n}`)
+    lod_layers: bool,
+    /// All the LODs are passed from the transformer (`lod_layers`, or `use_lod=all_lod`).
+    /// Without `lod_layers`, the feature has a row for each LOD with the `lod` column.
+    all_lods: bool,
+    /// Write the nested data objects into the attribute tables of their types, instead of JSON,
+    /// and relate them to the parent rows with the Related Tables extension (`gpkgext_relations`)
+    related_tables: bool,
+    /// Write the default styles of the feature tables into `layer_styles` (with the built-in profile without `style_path`)
+    qgis_styles: bool,
+    /// Commit the transaction every N features (0: a single transaction), at the end of the batch reaching it.
+    ///
+    /// The WAL is checkpointed after each commit, so that it does not grow with the whole output.
+    /// The features committed before a cancellation or an error are kept in the database.
+    commit_interval: usize,
+    /// Styling profile written into `layer_styles` as the default styles of the feature tables
+    style_path: Option<PathBuf>,
+    /// Record the `fid`, the gml:id and the source file of each feature in the `feature_sources` table
+    trace: bool,
+}
+
+/// Kind of the geometries of a feature table.
+///
+/// A table has a single geometry type, so the lines of a feature type (e.g. the centerlines of `tran:Road`)
+/// and the points (e.g. the city furniture, the LOD0 trees) are written into separate tables with the
+/// `_lines` and `_points` suffixes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FeatureLayer {
+    Polygons,
+    Lines,
+    Points,
+}
+
+impl FeatureLayer {
+    fn suffix(self) -> &'static str {
+        match self {
+            FeatureLayer::Polygons => "",
+            FeatureLayer::Lines => LINES_TABLE_SUFFIX,
+            FeatureLayer::Points => POINTS_TABLE_SUFFIX,
+        }
+    }
+
+    fn table_name(self, typename: &str) -> String {
+        format!("{typename}{}", self.suffix())
+    }
+
+    /// The feature type and the kind of a feature table (also of the tables of the LODs)
+    fn of_table(table_name: &str) -> (&str, FeatureLayer) {
+        let (typename, layer) = [FeatureLayer::Lines, FeatureLayer::Points]
+            .into_iter()
+            .find_map(|layer| {
+                let typename = table_name.strip_suffix(layer.suffix())?;
+                Some((typename, layer))
+            })
+            .unwrap_or((table_name, FeatureLayer::Polygons));
+        (strip_lod_suffix(typename), layer)
+    }
+
+    fn geometry_type_name(self) -> &'static str {
+        match self {
+            FeatureLayer::Polygons => "MULTIPOLYGON",
+            FeatureLayer::Lines => "MULTILINESTRING",
+            FeatureLayer::Points => "MULTIPOINT",
+        }
+    }
+}
+
+/// Geometries of a feature (or of a LOD of a feature) to be written into the tables of the layers
+#[derive(Default)]
+struct FeatureGeometries {
+    mpoly: flatgeom::MultiPolygon<'static, u32>,
+    mls: flatgeom::MultiLineString<'static, u32>,
+    mpoint: flatgeom::MultiPoint<'static, u32>,
+}
+
+impl FeatureGeometries {
+    /// Encodes the geometries of the non-empty layers
+    fn encode(&self, vertices: &[[f64; 3]]) -> Vec<(FeatureLayer, Vec<u8>, Bbox)> {
+        let mut layers = Vec::new();
+        if !self.mpoly.is_empty() {
+            let mut bytes = Vec::new();
+            if write_indexed_multipolygon(&mut bytes, vertices, &self.mpoly, 4326).is_err() {
+                // TODO: fatal error
+            }
+            let bbox = get_indexed_multipolygon_bbox(vertices, &self.mpoly);
+            layers.push((FeatureLayer::Polygons, bytes, bbox));
+        }
+        if !self.mls.is_empty() {
+            let mut bytes = Vec::new();
+            if write_indexed_multilinestring(&mut bytes, vertices, &self.mls, 4326).is_err() {
+                // TODO: fatal error
+            }
+            let bbox = get_indexed_multilinestring_bbox(vertices, &self.mls);
+            layers.push((FeatureLayer::Lines, bytes, bbox));
+        }
+        if !self.mpoint.is_empty() {
+            let mut bytes = Vec::new();
+            if write_indexed_multipoint(&mut bytes, vertices, &self.mpoint, 4326).is_err() {
+                // TODO: fatal error
+            }
+            let bbox = get_indexed_multipoint_bbox(vertices, &self.mpoint);
+            layers.push((FeatureLayer::Points, bytes, bbox));
+        }
+        layers
+    }
 }
 
 // An ephimeral container to wrap and pass the data in the pipeline
@@ -75,28 +335,272 @@ pub struct GpkgSink {
 enum Record {
     Feature {
         obj_id: String,
+        layer: FeatureLayer,
         geometry: Vec<u8>,
         bbox: Bbox,
-        attributes: IndexMap<String, String>,
+        attributes: IndexMap<String, Value>,
+        meshcode: Option<String>,
+        /// Path of the source file (if traced)
+        source: Option<String>,
+        /// Nested data objects related to the row (only with `related_tables`)
+        children: Vec<RelatedRecord>,
     },
     Attribute {
-        attributes: IndexMap<String, String>,
+        attributes: IndexMap<String, Value>,
     },
 }
 
+/// `relation_name` of the relations between the rows and their nested data objects
+const RELATION_NAME: &str = "attributes";
+
+/// Number of records sent to the writer at once
+const BATCH_SIZE: usize = 256;
+/// Maximum number of batches waiting to be written.
+/// The producers are blocked only when the writer falls behind by this many batches.
+const QUEUE_CAPACITY: usize = 64;
+
+/// Default number of features committed at once (16 batches)
+const DEFAULT_COMMIT_INTERVAL: usize = BATCH_SIZE * 16;
+
+type RecordBatch = Vec<(String, Record)>;
+
+/// Collects the records of a producer thread and sends them to the writer in batches.
+///
+/// The remaining records are sent when it is dropped.
+struct RecordBatcher {
+    sender: tokio::sync::mpsc::Sender<RecordBatch>,
+    batch: RecordBatch,
+}
+
+impl RecordBatcher {
+    fn new(sender: tokio::sync::mpsc::Sender<RecordBatch>) -> Self {
+        Self {
+            sender,
+            batch: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    fn push(&mut self, table_name: String, record: Record) -> Result<()> {
+        self.batch.push((table_name, record));
+        if self.batch.len() >= BATCH_SIZE {
+            let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+            if self.sender.blocking_send(batch).is_err() {
+                return Err(PipelineError::Canceled);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RecordBatcher {
+    fn drop(&mut self) {
+        if !self.batch.is_empty() {
+            // the writer may have already stopped
+            let _ = self.sender.blocking_send(std::mem::take(&mut self.batch));
+        }
+    }
+}
+
+/// Converts the entities into the records on the threads of the pipeline
+#[derive(Clone, Copy)]
+struct RecordProducer {
+    all_lods: bool,
+    lod_layers: bool,
+    related_tables: bool,
+    sql_views: bool,
+    trace: bool,
+}
+
+impl RecordProducer {
+    /// Sends the records to the writer until the upstream is exhausted
+    fn produce(
+        self,
+        upstream: Receiver,
+        feedback: &Feedback,
+        sender: tokio::sync::mpsc::Sender<RecordBatch>,
+        provenance: &Arc<Mutex<Provenance>>,
+    ) -> Result<()> {
+        let RecordProducer {
+            all_lods,
+            lod_layers,
+            related_tables,
+            sql_views,
+            trace,
+        } = self;
+        // (the channel is closed when `sender` and the batchers are dropped, after the batchers are flushed)
+        upstream.into_iter().par_bridge().try_for_each_init(
+            || {
+                (
+                    RecordBatcher::new(sender.clone()),
+                    ProvenanceCollector::new(provenance.clone()),
+                )
+            },
+            |(batcher, collector), parcel| {
+                feedback.ensure_not_canceled()?;
+                collector.add(&parcel);
+
+                let entity = parcel.entity;
+                let geom_store = entity.geometry_store.read().unwrap();
+
+                let Value::Object(obj) = &entity.root else {
+                    return Ok(());
+                };
+
+                match &obj.stereotype {
+                    ObjectStereotype::Feature {
+                        id: obj_id,
+                        geometries,
+                    } => {
+                        // the geometries are grouped by the LOD only if all the LODs are written
+                        let mut groups = BTreeMap::<Option<u8>, FeatureGeometries>::new();
+                        geometries.iter().for_each(|entry| {
+                            let group = groups.entry(all_lods.then_some(entry.lod)).or_default();
+                            let range = entry.pos as usize..(entry.pos + entry.len) as usize;
+                            match entry.ty {
+                                GeometryType::Solid
+                                | GeometryType::Surface
+                                | GeometryType::Triangle => {
+                                    for idx_poly in geom_store.multipolygon.iter_range(range) {
+                                        group.mpoly.push(&idx_poly);
+                                    }
+                                }
+                                GeometryType::Curve => {
+                                    for idx_ls in geom_store.multilinestring.iter_range(range) {
+                                        group.mls.add_linestring(idx_ls.iter());
+                                    }
+                                }
+                                GeometryType::Point => {
+                                    for idx in geom_store.multipoint.iter_range(range) {
+                                        group.mpoint.push(idx);
+                                    }
+                                }
+                            }
+                        });
+
+                        let (attributes, mut children) = match related_tables {
+                            true => prepare_related_attributes(obj),
+                            false => (prepare_object_attributes(obj), Vec::new()),
+                        };
+
+                        for (lod, group) in groups {
+                            for (layer, bytes, bbox) in group.encode(&geom_store.vertices) {
+                                let meshcode = match sql_views {
+                                    true => {
+                                        let (min_x, min_y, max_x, max_y) = bbox.to_tuple();
+                                        meshcode((min_x + max_x) / 2.0, (min_y + max_y) / 2.0)
+                                    }
+                                    false => None,
+                                };
+                                let mut attributes = attributes.clone();
+                                let table_name = match lod {
+                                    Some(lod) if lod_layers => layer.table_name(&format!(
+                                        "{}{}",
+                                        obj.typename,
+                                        lod_table_suffix(lod)
+                                    )),
+                                    Some(lod) => {
+                                        attributes.insert(
+                                            LOD_COLUMN_NAME.into(),
+                                            Value::Integer(lod.into()),
+                                        );
+                                        layer.table_name(&obj.typename)
+                                    }
+                                    None => layer.table_name(&obj.typename),
+                                };
+                                let record = Record::Feature {
+                                    obj_id: obj_id.clone(),
+                                    layer,
+                                    geometry: bytes,
+                                    bbox,
+                                    attributes,
+                                    meshcode,
+                                    source: trace.then(|| source_path(&entity.base_url)),
+                                    // (related to the first row of the feature)
+                                    children: std::mem::take(&mut children),
+                                };
+                                batcher.push(table_name, record)?;
+                            }
+                        }
+                    }
+                    ObjectStereotype::Data => {
+                        let table_name = obj.typename.to_string();
+                        let record = Record::Attribute {
+                            attributes: prepare_object_attributes(obj),
+                        };
+                        batcher.push(table_name, record)?;
+                    }
+                    ObjectStereotype::Object { id: obj_id } => {
+                        // TODO: implement (you will also need the corresponding TypeDef::Object in the schema)
+                        feedback.warn(format!(
+                            "ObjectStereotype::Object is not supported yet: id = {}",
+                            obj_id
+                        ));
+                    }
+                }
+
+                Ok(())
+            },
+        )
+    }
+}
+
 impl GpkgSink {
-    pub async fn run_async(
+    /// Writes the records on a dedicated thread, while the producers convert the entities on the threads of the
+    /// pipeline, so that the producers are not stalled by the inserts
+    fn write(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        let producer = RecordProducer {
+            all_lods: self.all_lods,
+            lod_layers: self.lod_layers,
+            related_tables: self.related_tables,
+            sql_views: self.sql_views,
+            trace: self.trace,
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel::<RecordBatch>(QUEUE_CAPACITY);
+        let provenance = Arc::new(Mutex::new(Provenance::default()));
+
+        std::thread::scope(|scope| {
+            let writer = std::thread::Builder::new()
+                .name("gpkg-writer".into())
+                .spawn_scoped(scope, || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?;
+                    runtime.block_on(self.write_records(receiver, feedback, schema, &provenance))
+                })?;
+            // (the producers stop if the writer fails, as the channel is closed)
+            let produced = producer.produce(upstream, feedback, sender, &provenance);
+            writer.join().unwrap()?;
+            match produced {
+                Ok(_) | Err(PipelineError::Canceled) => Ok(()),
+                error @ Err(_) => error,
+            }
+        })
+    }
+
+    async fn write_records(
         &mut self,
-        upstream: Receiver,
+        mut receiver: tokio::sync::mpsc::Receiver<RecordBatch>,
         feedback: &Feedback,
         schema: &Schema,
+        provenance: &Mutex<Provenance>,
     ) -> Result<()> {
+        let profile = match (&self.style_path, self.qgis_styles) {
+            (Some(path), _) => Some(StyleProfile::load(Some(path))?),
+            (None, true) => Some(StyleProfile::builtin()),
+            (None, false) => None,
+        };
+
         let mut handler = if self.output_path.to_string_lossy().starts_with("sqlite:") {
             // note: unlike the case of the file system path, the database is not cleared even if it already exists
             // this is mainly expected to be used with `sqlite::memory:` for the testing purpose
             GpkgHandler::from_url(&Url::parse(self.output_path.to_str().unwrap()).unwrap())
                 .await
                 .map_err(|e| PipelineError::Other(e.to_string()))?
+        } else if (self.update || self.append) && self.output_path.exists() {
+            let conn_str = format!("file:{}", self.output_path.to_string_lossy());
+            GpkgHandler::open_str(&conn_str)
+                .await
+                .map_err(|e| PipelineError::Other(e.to_string()))?
         } else {
             // delete the db file first if already exists
             if self.output_path.exists() {
@@ -109,139 +613,423 @@ impl GpkgSink {
                 .map_err(|e| PipelineError::Other(e.to_string()))?
         };
 
-        let table_infos = schema_to_table_infos(schema);
+        let mut table_infos = match self.related_tables {
+            true => schema_to_related_table_infos(schema),
+            false => schema_to_table_infos(schema),
+        };
+        if self.all_lods && !self.lod_layers {
+            add_lod_columns(&mut table_infos);
+        }
         let mut created_tables = HashSet::<String>::new();
+        // mapping tables of the Related Tables extension
+        let mut created_relations = HashSet::<String>::new();
         let srs_id = schema.epsg.unwrap_or(0); // 0 means 'Undefined Geographic'
 
         let mut table_bboxes = IndexMap::<String, Bbox>::new();
+        // (feature table, data table) pairs linked by `parentId`, for the joined views
+        let mut table_links = IndexSet::<(String, String)>::new();
+        let sql_views = self.sql_views;
 
-        let (sender, mut receiver) = tokio::sync::mpsc::channel(100);
+        // Tables written in the previous runs and their last rowids.
+        // The rows up to the rowid are replaced (or kept with `append`) if the features with the same IDs come in.
+        let mut prev_tables = IndexMap::<String, i64>::new();
+        let append = self.append;
+        // tables indexed to look up the existing features
+        let mut indexed_tables = HashSet::<String>::new();
+        let mut skipped_features = 0;
+        if self.update || append {
+            let table_names = handler.table_names().await;
+            let contents = handler
+                .gpkg_contents()
+                .await
+                .map_err(|e| PipelineError::Other(e.to_string()))?;
+            // (views are also in `gpkg_contents`, but not in `table_names`)
+            for (table_name, data_type, _, _) in contents {
+                if !table_names.contains(&table_name) {
+                    continue;
+                }
+                let max_rowid = handler
+                    .max_rowid(&table_name)
+                    .await
+                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+                if data_type == "features" {
+                    let bbox = handler
+                        .bbox(&table_name)
+                        .await
+                        .map_err(|e| PipelineError::Other(e.to_string()))?;
+                    table_bboxes.insert(table_name.clone(), Bbox::from_tuple(bbox));
+                }
+                created_tables.insert(table_name.clone());
+                prev_tables.insert(table_name, max_rowid);
+            }
+        }
+        // Attribute tables that refer to the features by `parentId`
+        let prev_child_tables: Vec<(String, i64)> = prev_tables
+            .iter()
+            .filter(|(table_name, _)| {
+                table_infos.get(*table_name).is_some_and(|tf| {
+                    !tf.has_geometry && tf.columns.iter().any(|c| c.name == "parentId")
+                })
+            })
+            .map(|(table_name, max_rowid)| (table_name.clone(), *max_rowid))
+            .collect();
+        // Feature tables that refer to the parent features by `parentId` (e.g. the building parts)
+        let prev_child_feature_tables: Vec<(String, i64)> = prev_tables
+            .iter()
+            .filter(|(table_name, _)| {
+                let (typename, _) = FeatureLayer::of_table(table_name);
+                table_infos.get(typename).is_some_and(|tf| {
+                    tf.has_geometry && tf.columns.iter().any(|c| c.name == "parentId")
+                })
+            })
+            .map(|(table_name, max_rowid)| (table_name.clone(), *max_rowid))
+            .collect();
 
-        let producers = {
-            let feedback = feedback.clone();
-            tokio::task::spawn_blocking(move || {
-                upstream
-                    .into_iter()
-                    .par_bridge()
-                    .try_for_each_with(sender, |sender, parcel| {
-                        feedback.ensure_not_canceled()?;
+        let commit_interval = self.commit_interval;
+        let mut written_features = 0;
+        let mut uncommitted_features = 0;
+        let mut tx = handler
+            .begin()
+            .await
+            .map_err(|e| PipelineError::Other(e.to_string()))?;
+        while let Some(batch) = receiver.recv().await {
+            feedback.ensure_not_canceled()?;
 
-                        let entity = parcel.entity;
-                        let geom_store = entity.geometry_store.read().unwrap();
+            // (committed between the batches)
+            if commit_interval > 0 && uncommitted_features >= commit_interval {
+                tx.commit()
+                    .await
+                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+                handler
+                    .checkpoint()
+                    .await
+                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+                feedback.info(format!(
+                    "Committed {} features to the GeoPackage",
+                    written_features
+                ));
+                uncommitted_features = 0;
+                tx = handler
+                    .begin()
+                    .await
+                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+            }
 
-                        let Value::Object(obj) = &entity.root else {
-                            return Ok(());
-                        };
+            for (table_name, record) in batch {
+                if !created_tables.contains(&table_name) {
+                    match &record {
+                        Record::Feature { layer, .. } => {
+                            let (typename, _) = FeatureLayer::of_table(&table_name);
+                            let tf =
+                                renamed_table_info(table_infos.get(typename).unwrap(), &table_name);
+                            tx.add_table(&tf, srs_id)
+                                .await
+                                .map_err(|e| PipelineError::Other(e.to_string()))?;
+                            if *layer != FeatureLayer::Polygons {
+                                tx.set_geometry_type(&table_name, layer.geometry_type_name())
+                                    .await
+                                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+                            }
+                        }
+                        Record::Attribute { .. } => {
+                            let tf = table_infos.get(&table_name).unwrap();
+                            tx.add_table(tf, srs_id)
+                                .await
+                                .map_err(|e| PipelineError::Other(e.to_string()))?;
+                        }
+                    }
+                    created_tables.insert(table_name.clone());
+                }
 
-                        match &obj.stereotype {
-                            ObjectStereotype::Feature {
-                                id: obj_id,
-                                geometries,
-                            } => {
-                                let mut mpoly = flatgeom::MultiPolygon::new();
-
-                                geometries.iter().for_each(|entry| match entry.ty {
-                                    GeometryType::Solid
-                                    | GeometryType::Surface
-                                    | GeometryType::Triangle => {
-                                        for idx_poly in geom_store.multipolygon.iter_range(
-                                            entry.pos as usize..(entry.pos + entry.len) as usize,
-                                        ) {
-                                            mpoly.push(&idx_poly);
+                match record {
+                    Record::Feature {
+                        obj_id,
+                        layer: _,
+                        geometry,
+                        bbox,
+                        attributes,
+                        meshcode,
+                        source,
+                        children,
+                    } => {
+                        if let Some(&max_rowid) = prev_tables.get(&table_name) {
+                            if append {
+                                if indexed_tables.insert(table_name.clone()) {
+                                    tx.create_index(&table_name, "id")
+                                        .await
+                                        .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                }
+                                let exists = tx
+                                    .row_exists(&table_name, "id", &obj_id, max_rowid)
+                                    .await
+                                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                if exists {
+                                    // already converted in a previous run
+                                    skipped_features += 1;
+                                    continue;
+                                }
+                            } else {
+                                let deleted = tx
+                                    .delete_rows(&table_name, "id", &obj_id, max_rowid)
+                                    .await
+                                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                if deleted > 0 {
+                                    // the rows under the feature are deleted down to all the levels
+                                    // (e.g. the attributes of the building parts of a building)
+                                    let mut deleted_ids = vec![obj_id.clone()];
+                                    while let Some(id) = deleted_ids.pop() {
+                                        for (child_table, max_rowid) in &prev_child_tables {
+                                            tx.delete_rows(
+                                                child_table,
+                                                "parentId",
+                                                &id,
+                                                *max_rowid,
+                                            )
+                                            .await
+                                            .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                        }
+                                        for (child_table, max_rowid) in &prev_child_feature_tables {
+                                            let child_ids = tx
+                                                .delete_features(
+                                                    child_table,
+                                                    "parentId",
+                                                    &id,
+                                                    *max_rowid,
+                                                )
+                                                .await
+                                                .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                            deleted_ids.extend(child_ids);
+                                        }
+                                        if let Some(&max_rowid) =
+                                            prev_tables.get(MESHCODE_TABLE_NAME)
+                                        {
+                                            tx.delete_rows(
+                                                MESHCODE_TABLE_NAME,
+                                                "feature_id",
+                                                &id,
+                                                max_rowid,
+                                            )
+                                            .await
+                                            .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                        }
+                                        if let Some(&max_rowid) =
+                                            prev_tables.get(FEATURE_SOURCES_TABLE_NAME)
+                                        {
+                                            tx.delete_rows(
+                                                FEATURE_SOURCES_TABLE_NAME,
+                                                "gml_id",
+                                                &id,
+                                                max_rowid,
+                                            )
+                                            .await
+                                            .map_err(|e| PipelineError::Other(e.to_string()))?;
                                         }
                                     }
-                                    GeometryType::Curve => unimplemented!(),
-                                    GeometryType::Point => unimplemented!(),
-                                });
-
-                                if mpoly.is_empty() {
-                                    return Ok(());
                                 }
+                            }
+                        }
+
+                        let fid = tx
+                            .insert_feature(
+                                &table_name,
+                                &obj_id,
+                                &geometry,
+                                &column_values(&attributes),
+                            )
+                            .await
+                            .map_err(|e| PipelineError::Other(e.to_string()))?;
+
+                        if let Some(source) = source {
+                            if !created_tables.contains(FEATURE_SOURCES_TABLE_NAME) {
+                                tx.add_table(&feature_sources_table_info(), srs_id)
+                                    .await
+                                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                created_tables.insert(FEATURE_SOURCES_TABLE_NAME.to_string());
+                            }
+                            let source_attributes = IndexMap::from([
+                                ("table_name".to_string(), table_name.clone().into()),
+                                ("fid".to_string(), ColumnValue::Integer(fid)),
+                                ("gml_id".to_string(), obj_id.clone().into()),
+                                ("source".to_string(), source.into()),
+                            ]);
+                            tx.insert_attribute(FEATURE_SOURCES_TABLE_NAME, &source_attributes)
+                                .await
+                                .map_err(|e| PipelineError::Other(e.to_string()))?;
+                        }
 
-                                let mut bytes = Vec::new();
-                                if write_indexed_multipolygon(
-                                    &mut bytes,
-                                    &geom_store.vertices,
-                                    &mpoly,
-                                    4326,
+                        if let Some(meshcode) = meshcode {
+                            if !created_tables.contains(MESHCODE_TABLE_NAME) {
+                                tx.add_table(&meshcode_table_info(), srs_id)
+                                    .await
+                                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                created_tables.insert(MESHCODE_TABLE_NAME.to_string());
+                            }
+                            let meshcode_attributes = IndexMap::from([
+                                ("table_name".to_string(), table_name.clone()),
+                                ("feature_id".to_string(), obj_id),
+                                ("meshcode".to_string(), meshcode),
+                            ]);
+                            tx.insert_attribute(MESHCODE_TABLE_NAME, &meshcode_attributes)
+                                .await
+                                .map_err(|e| PipelineError::Other(e.to_string()))?;
+                        }
+
+                        // (base table, its primary key column, base row id, record)
+                        let mut related: Vec<(String, &str, i64, RelatedRecord)> = children
+                            .into_iter()
+                            .rev()
+                            .map(|child| (table_name.clone(), "fid", fid, child))
+                            .collect();
+                        while let Some((base_table, base_primary_column, base_id, record)) =
+                            related.pop()
+                        {
+                            let RelatedRecord {
+                                table_name: related_table,
+                                attributes,
+                                children,
+                            } = record;
+                            let Some(tf) = table_infos.get(&related_table) else {
+                                feedback.warn(format!("Unknown data type: {}", related_table));
+                                continue;
+                            };
+                            if !created_tables.contains(&related_table) {
+                                tx.add_table(tf, srs_id)
+                                    .await
+                                    .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                created_tables.insert(related_table.clone());
+                            }
+                            let related_id = tx
+                                .insert_attribute(&related_table, &column_values(&attributes))
+                                .await
+                                .map_err(|e| PipelineError::Other(e.to_string()))?;
+
+                            let mapping_table = format!("{}_{}", base_table, related_table);
+                            if !created_relations.contains(&mapping_table) {
+                                tx.add_relation(
+                                    &base_table,
+                                    base_primary_column,
+                                    &related_table,
+                                    RELATION_NAME,
+                                    &mapping_table,
                                 )
-                                .is_err()
-                                {
-                                    // TODO: fatal error
-                                }
+                                .await
+                                .map_err(|e| PipelineError::Other(e.to_string()))?;
+                                created_relations.insert(mapping_table.clone());
+                            }
+                            tx.insert_relation(&mapping_table, base_id, related_id)
+                                .await
+                                .map_err(|e| PipelineError::Other(e.to_string()))?;
 
-                                let table_name = obj.typename.to_string();
-                                let record = Record::Feature {
-                                    obj_id: obj_id.clone(),
-                                    geometry: bytes,
-                                    bbox: get_indexed_multipolygon_bbox(
-                                        &geom_store.vertices,
-                                        &mpoly,
-                                    ),
-                                    attributes: prepare_object_attributes(obj),
-                                };
-                                if sender.blocking_send((table_name, record)).is_err() {
-                                    return Err(PipelineError::Canceled);
-                                };
+                            related.extend(
+                                children
+                                    .into_iter()
+                                    .rev()
+                                    .map(|child| (related_table.clone(), "id", related_id, child)),
+                            );
+                        }
+
+                        table_bboxes.entry(table_name).or_default().merge(&bbox);
+                        written_features += 1;
+                        uncommitted_features += 1;
+                    }
+                    Record::Attribute { attributes } => {
+                        if let (true, Some(&max_rowid), Some(Value::String(parent_id))) = (
+                            append,
+                            prev_tables.get(&table_name),
+                            attributes.get("parentId"),
+                        ) {
+                            if indexed_tables.insert(table_name.clone()) {
+                                tx.create_index(&table_name, "parentId")
+                                    .await
+                                    .map_err(|e| PipelineError::Other(e.to_string()))?;
                             }
-                            ObjectStereotype::Data => {
-                                let table_name = obj.typename.to_string();
-                                let record = Record::Attribute {
-                                    attributes: prepare_object_attributes(obj),
-                                };
-                                if sender.blocking_send((table_name, record)).is_err() {
-                                    return Err(PipelineError::Canceled);
-                                };
+                            let exists = tx
+                                .row_exists(&table_name, "parentId", parent_id, max_rowid)
+                                .await
+                                .map_err(|e| PipelineError::Other(e.to_string()))?;
+                            if exists {
+                                // the parent feature has been converted in a previous run
+                                continue;
                             }
-                            ObjectStereotype::Object { id: obj_id } => {
-                                // TODO: implement (you will also need the corresponding TypeDef::Object in the schema)
-                                feedback.warn(format!(
-                                    "ObjectStereotype::Object is not supported yet: id = {}",
-                                    obj_id
-                                ));
+                        }
+
+                        tx.insert_attribute(&table_name, &column_values(&attributes))
+                            .await
+                            .map_err(|e| PipelineError::Other(e.to_string()))?;
+
+                        if sql_views && attributes.contains_key("parentId") {
+                            if let Some(Value::String(parent_type)) = attributes.get("parentType") {
+                                table_links.insert((parent_type.clone(), table_name));
                             }
                         }
+                    }
+                }
+            }
+        }
 
-                        Ok(())
-                    })
-            })
-        };
+        if skipped_features > 0 {
+            feedback.info(format!(
+                "Skipped {} feature rows already in the GeoPackage",
+                skipped_features
+            ));
+        }
 
-        let mut tx = handler
-            .begin()
-            .await
-            .map_err(|e| PipelineError::Other(e.to_string()))?;
-        while let Some((table_name, record)) = receiver.recv().await {
-            feedback.ensure_not_canceled()?;
+        if sql_views {
+            // only the links to the feature tables can be joined
+            table_links.retain(|(parent_table, _)| table_bboxes.contains_key(parent_table));
+            let mut views = joined_views(&table_links, &table_infos);
+            if created_tables.contains(MESHCODE_TABLE_NAME) {
+                let feature_tables: IndexSet<String> = table_bboxes.keys().cloned().collect();
+                views.extend(meshcode_views(&feature_tables));
+            }
+
+            for view in views {
+                feedback.ensure_not_canceled()?;
 
-            if !created_tables.contains(&table_name) {
-                let tf = table_infos.get(&table_name).unwrap();
-                tx.add_table(tf, srs_id)
+                tx.add_view(&view.name, &view.select, view.has_geometry, srs_id)
                     .await
                     .map_err(|e| PipelineError::Other(e.to_string()))?;
-                created_tables.insert(table_name.clone());
+                if view.has_geometry {
+                    if let Some(bbox) = table_bboxes.get(&view.base_table) {
+                        tx.update_bbox(&view.name, bbox.to_tuple())
+                            .await
+                            .map_err(|e| PipelineError::Other(e.to_string()))?;
+                    }
+                }
             }
+        }
 
-            match record {
-                Record::Feature {
-                    obj_id,
-                    geometry,
-                    bbox,
-                    attributes,
-                } => {
-                    tx.insert_feature(&table_name, &obj_id, &geometry, &attributes)
-                        .await
-                        .map_err(|e| PipelineError::Other(e.to_string()))?;
-                    table_bboxes.entry(table_name).or_default().merge(&bbox);
-                }
-                Record::Attribute { attributes } => {
-                    tx.insert_attribute(&table_name, &attributes)
-                        .await
-                        .map_err(|e| PipelineError::Other(e.to_string()))?;
-                }
+        if let Some(profile) = &profile {
+            for table_name in table_bboxes.keys() {
+                feedback.ensure_not_canceled()?;
+
+                let (typename, layer) = FeatureLayer::of_table(table_name);
+                let rules = profile.type_rules(typename);
+                let column = rules.attribute.and_then(|name| {
+                    let columns = table_infos.get(typename)?.columns.iter();
+                    resolve_attribute_name(columns.map(|c| &c.name), name)
+                });
+                let qml = style::qml(layer, &rules, column);
+                let sld = style::sld(table_name, layer, &rules, column);
+                tx.add_layer_style(table_name, table_name, &qml, &sld)
+                    .await
+                    .map_err(|e| PipelineError::Other(e.to_string()))?;
             }
         }
 
+        // (the producers have merged their provenance, as they have closed the channel)
+        let mut envelope: Option<Bbox> = None;
+        for bbox in table_bboxes.values() {
+            envelope.get_or_insert_with(Bbox::default).merge(bbox);
+        }
+        let document = provenance
+            .lock()
+            .unwrap()
+            .to_document(srs_id, envelope.as_ref());
+        tx.add_metadata(METADATA_STANDARD_URI, METADATA_MIME_TYPE, &document, None)
+            .await
+            .map_err(|e| PipelineError::Other(e.to_string()))?;
+
         for (table_name, bbox) in table_bboxes {
             feedback.ensure_not_canceled()?;
 
@@ -250,14 +1038,11 @@ impl GpkgSink {
                 .map_err(|e| PipelineError::Other(e.to_string()))?;
         }
 
+        // the last chance to cancel the conversion without changing the database
+        feedback.ensure_not_canceled()?;
         tx.commit()
             .await
-            .map_err(|e| PipelineError::Other(e.to_string()))?;
-
-        match producers.await.unwrap() {
-            Ok(_) | Err(PipelineError::Canceled) => Ok(()),
-            error @ Err(_) => error,
-        }
+            .map_err(|e| PipelineError::Other(e.to_string()))
     }
 }
 
@@ -278,11 +1063,38 @@ impl DataSink for GpkgSink {
             let _ = &self.transform_settings.update_transformer(config.clone());
         }
 
-        self.transform_settings.build(default_requirements)
+        let default_requirements = match self.related_tables {
+            // the nested data objects are kept in the tree for the sink
+            true => DataRequirements {
+                tree_flattening: transformer::TreeFlatteningSpec::Flatten {
+                    feature: transformer::FeatureFlatteningOption::AllExceptThematicSurfaces,
+                    data: transformer::DataFlatteningOption::None,
+                    object: transformer::ObjectFlatteningOption::None,
+                },
+                key_value: transformer::KeyValueSpec::None,
+                ..default_requirements
+            },
+            false => default_requirements,
+        };
+        let mut requirements = self.transform_settings.build(default_requirements);
+        if self.lod_layers {
+            requirements.lod_filter.mode = LodFilterMode::All;
+        }
+        self.all_lods = matches!(requirements.lod_filter.mode, LodFilterMode::All);
+        requirements
     }
 
     fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
-        let runtime = tokio::runtime::Runtime::new().unwrap();
-        runtime.block_on(self.run_async(upstream, feedback, schema))
+        let is_url = self.output_path.to_string_lossy().starts_with("sqlite:");
+        if is_url || ((self.update || self.append) && self.output_path.exists()) {
+            // the existing database is left as it was since the transaction is not committed
+            // (except for the batches already committed with `commit_interval`)
+            self.write(upstream, feedback, schema)
+        } else {
+            let output_path = self.output_path.clone();
+            remove_on_cancel(&output_path, feedback, || {
+                self.write(upstream, feedback, schema)
+            })
+        }
     }
 }
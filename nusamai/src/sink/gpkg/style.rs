@@ -0,0 +1,309 @@
+//! QML and SLD styles of the feature tables made from the styling profile (stored in `layer_styles`)
+
+use std::fmt::Write;
+
+use quick_xml::escape::escape;
+
+use super::FeatureLayer;
+use crate::sink::style::{Color, Style, TypeRules};
+
+/// Size of the point markers (in pixels)
+const POINT_SIZE: u32 = 6;
+
+/// QGIS version written in the QML (the format is read by the later versions as well)
+const QGIS_VERSION: &str = "3.28.0";
+
+/// Makes the SLD (Symbology Encoding 1.1) of a feature table.
+///
+/// `column` is the column of the attribute that selects the style (`None` if the table doesn't have it).
+pub fn sld(
+    table_name: &str,
+    layer: FeatureLayer,
+    rules: &TypeRules,
+    column: Option<&str>,
+) -> String {
+    let symbolizer = match layer {
+        FeatureLayer::Polygons => polygon_symbolizer,
+        FeatureLayer::Lines => line_symbolizer,
+        FeatureLayer::Points => point_symbolizer,
+    };
+    let name = escape(table_name);
+    let mut sld = String::new();
+    sld.push_str(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<StyledLayerDescriptor version="1.1.0" xmlns="http://www.opengis.net/sld" xmlns:se="http://www.opengis.net/se" xmlns:ogc="http://www.opengis.net/ogc" xmlns:xlink="http://www.w3.org/1999/xlink" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.opengis.net/sld http://schemas.opengis.net/sld/1.1.0/StyledLayerDescriptor.xsd">
+"#,
+    );
+    let _ = write!(
+        sld,
+        "<NamedLayer><se:Name>{name}</se:Name><UserStyle><se:Name>{name}</se:Name><se:FeatureTypeStyle>"
+    );
+
+    if let Some(column) = column {
+        let column = escape(column);
+        for (value, style) in &rules.values {
+            let value = escape(*value);
+            let _ = write!(
+                sld,
+                "<se:Rule><se:Name>{value}</se:Name><ogc:Filter><ogc:PropertyIsEqualTo>\
+                 <ogc:PropertyName>{column}</ogc:PropertyName><ogc:Literal>{value}</ogc:Literal>\
+                 </ogc:PropertyIsEqualTo></ogc:Filter>{}</se:Rule>",
+                symbolizer(style)
+            );
+        }
+    }
+    let else_filter = match column.is_some() && !rules.values.is_empty() {
+        true => "<se:ElseFilter/>",
+        false => "",
+    };
+    let _ = write!(
+        sld,
+        "<se:Rule><se:Name>{name}</se:Name>{else_filter}{}</se:Rule>",
+        symbolizer(&rules.base)
+    );
+
+    sld.push_str("</se:FeatureTypeStyle></UserStyle></NamedLayer>\n</StyledLayerDescriptor>\n");
+    sld
+}
+
+/// Makes the QML (QGIS layer style) of a feature table.
+///
+/// With the `column`, the features are categorized by its values (the others are drawn with the base style).
+pub fn qml(layer: FeatureLayer, rules: &TypeRules, column: Option<&str>) -> String {
+    let mut qml = String::new();
+    let _ = write!(
+        qml,
+        "<!DOCTYPE qgis PUBLIC 'http://mrcc.com/qgis.dtd' 'SYSTEM'>\n\
+         <qgis version=\"{QGIS_VERSION}\" styleCategories=\"Symbology\">\n"
+    );
+
+    match column {
+        Some(column) if !rules.values.is_empty() => {
+            let _ = write!(
+                qml,
+                "<renderer-v2 type=\"categorizedSymbol\" attr=\"{}\" symbollevels=\"0\" \
+                 enableorderby=\"0\" forceraster=\"0\"><categories>",
+                escape(column)
+            );
+            for (idx, (value, _)) in rules.values.iter().enumerate() {
+                let value = escape(*value);
+                let _ = write!(
+                    qml,
+                    "<category render=\"true\" symbol=\"{idx}\" value=\"{value}\" label=\"{value}\"/>"
+                );
+            }
+            // (the empty value matches all the other values)
+            let _ = write!(
+                qml,
+                "<category render=\"true\" symbol=\"{}\" value=\"\" label=\"\"/></categories><symbols>",
+                rules.values.len()
+            );
+            for (idx, (_, style)) in rules.values.iter().enumerate() {
+                qml.push_str(&qml_symbol(layer, &idx.to_string(), style));
+            }
+            qml.push_str(&qml_symbol(
+                layer,
+                &rules.values.len().to_string(),
+                &rules.base,
+            ));
+            qml.push_str("</symbols></renderer-v2>\n");
+        }
+        _ => {
+            let _ = write!(
+                qml,
+                "<renderer-v2 type=\"singleSymbol\" symbollevels=\"0\" enableorderby=\"0\" \
+                 forceraster=\"0\"><symbols>{}</symbols></renderer-v2>\n",
+                qml_symbol(layer, "0", &rules.base)
+            );
+        }
+    }
+
+    qml.push_str("</qgis>\n");
+    qml
+}
+
+fn qml_symbol(layer: FeatureLayer, name: &str, style: &Style) -> String {
+    let (symbol_type, class, options) = match layer {
+        FeatureLayer::Polygons => (
+            "fill",
+            "SimpleFill",
+            vec![
+                ("color", qml_color(&style.fill)),
+                ("style", "solid".into()),
+                ("outline_color", qml_color(&style.stroke)),
+                ("outline_style", "solid".into()),
+                ("outline_width", style.width.to_string()),
+                ("outline_width_unit", "Pixel".into()),
+            ],
+        ),
+        FeatureLayer::Lines => (
+            "line",
+            "SimpleLine",
+            vec![
+                ("line_color", qml_color(&style.stroke)),
+                ("line_style", "solid".into()),
+                ("line_width", style.width.to_string()),
+                ("line_width_unit", "Pixel".into()),
+            ],
+        ),
+        FeatureLayer::Points => (
+            "marker",
+            "SimpleMarker",
+            vec![
+                ("name", "circle".into()),
+                ("color", qml_color(&style.fill)),
+                ("outline_color", qml_color(&style.stroke)),
+                ("outline_style", "solid".into()),
+                ("outline_width", style.width.to_string()),
+                ("outline_width_unit", "Pixel".into()),
+                ("size", POINT_SIZE.to_string()),
+                ("size_unit", "Pixel".into()),
+            ],
+        ),
+    };
+
+    let mut symbol = format!(
+        "<symbol type=\"{symbol_type}\" name=\"{name}\" alpha=\"1\" clip_to_extent=\"1\" \
+         force_rhr=\"0\"><layer class=\"{class}\" enabled=\"1\" pass=\"0\" locked=\"0\">\
+         <Option type=\"Map\">"
+    );
+    for (key, value) in options {
+        let _ = write!(
+            symbol,
+            "<Option name=\"{key}\" type=\"QString\" value=\"{value}\"/>"
+        );
+    }
+    symbol.push_str("</Option></layer></symbol>");
+    symbol
+}
+
+/// `r,g,b,a` (in 0 - 255)
+fn qml_color(color: &Color) -> String {
+    let [r, g, b, a] = color.rgba();
+    format!("{r},{g},{b},{a}")
+}
+
+fn polygon_symbolizer(style: &Style) -> String {
+    format!(
+        "<se:PolygonSymbolizer><se:Fill><se:SvgParameter name=\"fill\">{}</se:SvgParameter>\
+         <se:SvgParameter name=\"fill-opacity\">{:.3}</se:SvgParameter></se:Fill><se:Stroke>\
+         <se:SvgParameter name=\"stroke\">{}</se:SvgParameter>\
+         <se:SvgParameter name=\"stroke-opacity\">{:.3}</se:SvgParameter>\
+         <se:SvgParameter name=\"stroke-width\">{}</se:SvgParameter></se:Stroke>\
+         </se:PolygonSymbolizer>",
+        style.fill.hex(),
+        style.fill.opacity(),
+        style.stroke.hex(),
+        style.stroke.opacity(),
+        style.width
+    )
+}
+
+fn line_symbolizer(style: &Style) -> String {
+    format!(
+        "<se:LineSymbolizer><se:Stroke>\
+         <se:SvgParameter name=\"stroke\">{}</se:SvgParameter>\
+         <se:SvgParameter name=\"stroke-opacity\">{:.3}</se:SvgParameter>\
+         <se:SvgParameter name=\"stroke-width\">{}</se:SvgParameter></se:Stroke>\
+         </se:LineSymbolizer>",
+        style.stroke.hex(),
+        style.stroke.opacity(),
+        style.width
+    )
+}
+
+fn point_symbolizer(style: &Style) -> String {
+    format!(
+        "<se:PointSymbolizer><se:Graphic><se:Mark><se:WellKnownName>circle</se:WellKnownName>\
+         <se:Fill><se:SvgParameter name=\"fill\">{}</se:SvgParameter>\
+         <se:SvgParameter name=\"fill-opacity\">{:.3}</se:SvgParameter></se:Fill><se:Stroke>\
+         <se:SvgParameter name=\"stroke\">{}</se:SvgParameter>\
+         <se:SvgParameter name=\"stroke-opacity\">{:.3}</se:SvgParameter>\
+         <se:SvgParameter name=\"stroke-width\">{}</se:SvgParameter></se:Stroke></se:Mark>\
+         <se:Size>{}</se:Size></se:Graphic></se:PointSymbolizer>",
+        style.fill.hex(),
+        style.fill.opacity(),
+        style.stroke.hex(),
+        style.stroke.opacity(),
+        style.width,
+        POINT_SIZE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::style::StyleProfile;
+
+    #[test]
+    fn test_sld() {
+        let profile = StyleProfile::builtin();
+        let rules = profile.type_rules("bldg:Building");
+
+        let with_column = sld(
+            "bldg:Building",
+            FeatureLayer::Polygons,
+            &rules,
+            Some("usage"),
+        );
+        assert_eq!(
+            with_column.matches("<se:Rule>").count(),
+            rules.values.len() + 1
+        );
+        assert!(with_column.contains("<ogc:PropertyName>usage</ogc:PropertyName>"));
+        assert!(with_column.contains("<se:ElseFilter/>"));
+
+        let without_column = sld("bldg:Building", FeatureLayer::Polygons, &rules, None);
+        assert_eq!(without_column.matches("<se:Rule>").count(), 1);
+        assert!(!without_column.contains("ElseFilter"));
+
+        let rules = profile.type_rules("tran:Road");
+        let lines = sld("tran:Road_lines", FeatureLayer::Lines, &rules, None);
+        assert!(lines.contains("<se:LineSymbolizer>"));
+        assert!(!lines.contains("PolygonSymbolizer"));
+
+        let rules = profile.type_rules("frn:CityFurniture");
+        let points = sld(
+            "frn:CityFurniture_points",
+            FeatureLayer::Points,
+            &rules,
+            None,
+        );
+        assert!(points.contains("<se:PointSymbolizer>"));
+    }
+
+    #[test]
+    fn test_qml() {
+        let profile = StyleProfile::builtin();
+        let rules = profile.type_rules("bldg:Building");
+
+        let categorized = qml(FeatureLayer::Polygons, &rules, Some("usage"));
+        assert!(categorized.contains(r#"type="categorizedSymbol" attr="usage""#));
+        assert_eq!(
+            categorized.matches("<category ").count(),
+            rules.values.len() + 1
+        );
+        assert_eq!(
+            categorized.matches("<symbol ").count(),
+            rules.values.len() + 1
+        );
+        assert!(categorized.contains(r#"value="業務施設""#));
+        // #ff7f7f
+        assert!(categorized
+            .contains(r#"<Option name="color" type="QString" value="255,127,127,255"/>"#));
+
+        let single = qml(FeatureLayer::Polygons, &rules, None);
+        assert!(single.contains(r#"type="singleSymbol""#));
+        assert_eq!(single.matches("<symbol ").count(), 1);
+        assert!(single.contains(r#"class="SimpleFill""#));
+
+        let rules = profile.type_rules("tran:Road");
+        let lines = qml(FeatureLayer::Lines, &rules, Some("function"));
+        assert!(lines.contains(r#"class="SimpleLine""#));
+        assert!(!lines.contains("SimpleFill"));
+
+        let rules = profile.type_rules("frn:CityFurniture");
+        let points = qml(FeatureLayer::Points, &rules, None);
+        assert!(points.contains(r#"<symbol type="marker""#));
+    }
+}
@@ -2,6 +2,78 @@ use indexmap::IndexMap;
 use nusamai_citygml::schema::{Attribute, Schema, TypeDef, TypeRef};
 use nusamai_gpkg::table::{ColumnInfo, TableInfo};
 
+/// Attribute table that associates the features with their `fid`s and the source files (see the `trace` module)
+pub const FEATURE_SOURCES_TABLE_NAME: &str = "feature_sources";
+
+pub fn feature_sources_table_info() -> TableInfo {
+    let column = |name: &str, data_type: &str| ColumnInfo {
+        name: name.into(),
+        data_type: data_type.into(),
+        mime_type: None,
+    };
+    TableInfo {
+        name: FEATURE_SOURCES_TABLE_NAME.into(),
+        has_geometry: false,
+        columns: vec![
+            column("table_name", "TEXT"),
+            column("fid", "INTEGER"),
+            column("gml_id", "TEXT"),
+            column("source", "TEXT"),
+        ],
+    }
+}
+
+/// Suffix of the tables of the line geometries of the feature types (e.g. `tran:Road_lines`)
+pub const LINES_TABLE_SUFFIX: &str = "_lines";
+/// Suffix of the tables of the point geometries of the feature types (e.g. `frn:CityFurniture_points`)
+pub const POINTS_TABLE_SUFFIX: &str = "_points";
+
+/// Column of the LOD of the geometries, added to the feature tables when all the LODs are written into them
+pub const LOD_COLUMN_NAME: &str = "lod";
+
+/// Suffix of the tables of the geometries of a LOD (e.g. `bldg:Building_lod2`)
+pub fn lod_table_suffix(lod: u8) -> String {
+    format!("_lod{lod}")
+}
+
+/// Removes the suffix of the LOD from the table name, if any
+pub fn strip_lod_suffix(table_name: &str) -> &str {
+    match table_name.rsplit_once("_lod") {
+        Some((typename, lod)) if lod.len() == 1 && lod.bytes().all(|b| b.is_ascii_digit()) => {
+            typename
+        }
+        _ => table_name,
+    }
+}
+
+/// Table with the same columns as the feature table, for a subset of the geometries (e.g. the lines, a LOD)
+pub fn renamed_table_info(feature_table: &TableInfo, table_name: &str) -> TableInfo {
+    TableInfo {
+        name: table_name.into(),
+        has_geometry: true,
+        columns: feature_table
+            .columns
+            .iter()
+            .map(|column| ColumnInfo {
+                name: column.name.clone(),
+                data_type: column.data_type.clone(),
+                mime_type: column.mime_type.clone(),
+            })
+            .collect(),
+    }
+}
+
+/// Adds the LOD column to the feature tables
+pub fn add_lod_columns(table_infos: &mut IndexMap<String, TableInfo>) {
+    for tf in table_infos.values_mut().filter(|tf| tf.has_geometry) {
+        tf.columns.push(ColumnInfo {
+            name: LOD_COLUMN_NAME.into(),
+            data_type: "INTEGER".into(),
+            mime_type: None,
+        });
+    }
+}
+
 /// Check the schema, and prepare the information for the SQLite table
 #[must_use]
 pub fn schema_to_table_infos(schema: &Schema) -> IndexMap<String, TableInfo> {
@@ -21,6 +93,55 @@ pub fn schema_to_table_infos(schema: &Schema) -> IndexMap<String, TableInfo> {
     table_infos
 }
 
+/// Prepare the information for the SQLite tables, with the nested data objects in their own tables (see `attributes::prepare_related_attributes`).
+///
+/// The attributes of the related data types have no columns, and the other nested values are stored as JSON.
+#[must_use]
+pub fn schema_to_related_table_infos(schema: &Schema) -> IndexMap<String, TableInfo> {
+    let mut table_infos = IndexMap::<String, TableInfo>::new();
+
+    schema.types.iter().for_each(|(name, ty)| {
+        let attributes = match ty {
+            TypeDef::Feature(feat_td) => &feat_td.attributes,
+            TypeDef::Data(data_td) => &data_td.attributes,
+            TypeDef::Property(_) => return,
+        };
+        let columns = attributes
+            .iter()
+            .filter(|(_, attr)| !is_related_type(schema, attr))
+            .filter_map(|(attr_name, attr)| {
+                if matches!(attr.type_ref, TypeRef::Named(_)) || attr.max_occurs != Some(1) {
+                    let json = Attribute::new(TypeRef::JsonString(attr.clone().into()));
+                    attribute_to_column(attr_name, &json)
+                } else {
+                    attribute_to_column(attr_name, attr)
+                }
+            })
+            .collect();
+        table_infos.insert(
+            name.clone(),
+            TableInfo {
+                name: name.clone(),
+                has_geometry: matches!(ty, TypeDef::Feature(_)),
+                columns,
+            },
+        );
+    });
+
+    table_infos
+}
+
+/// Whether the attribute refers to a data type written into its own table (not the generic attributes, whose attributes are not known)
+fn is_related_type(schema: &Schema, attr: &Attribute) -> bool {
+    let TypeRef::Named(name) = &attr.type_ref else {
+        return false;
+    };
+    matches!(
+        schema.types.get(name),
+        Some(TypeDef::Data(data_td)) if !data_td.additional_attributes
+    )
+}
+
 #[must_use]
 fn typedef_to_columns(ty: &TypeDef) -> Vec<ColumnInfo> {
     let mut columns: Vec<ColumnInfo> = vec![];
@@ -102,16 +223,12 @@ fn attribute_to_column(attr_name: &str, attr: &Attribute) -> Option<ColumnInfo>
             data_type: "REAL".into(),
             mime_type: None,
         }),
-        TypeRef::Point => {
-            // TODO: implement
-            // Point struct currently does not contain any data
-            log::warn!(
-                "TypeDef::Feature - Unsupported attribute type: {:?} ('{}')",
-                attr.type_ref,
-                attr_name
-            );
-            None
-        }
+        TypeRef::Point => Some(ColumnInfo {
+            // GeoJSON geometry (e.g. `{"type":"Point","coordinates":[...]}`)
+            name: attr_name.to_string(),
+            data_type: "TEXT".into(),
+            mime_type: Some("application/geo+json".into()),
+        }),
         TypeRef::Named(_name) => {
             // Note: expected to be handled by the tranformer in the earlier step (flatten)
             log::warn!(
@@ -322,4 +439,85 @@ mod tests {
         let result_3 = attribute_to_column("unknown", &Attribute::new(TypeRef::Unknown));
         assert_eq!(result_3, None);
     }
+
+    #[test]
+    fn test_point_attribute_column() {
+        let column = attribute_to_column("pos", &Attribute::new(TypeRef::Point)).unwrap();
+        assert_eq!(column.data_type, "TEXT");
+        assert_eq!(column.mime_type.as_deref(), Some("application/geo+json"));
+    }
+
+    #[test]
+    fn test_schema_to_related_table_infos() {
+        let mut types = IndexMap::with_hasher(ahash::RandomState::default());
+
+        let mut attrs_1 = IndexMap::with_hasher(ahash::RandomState::default());
+        attrs_1.insert("text".into(), Attribute::new(TypeRef::String));
+        attrs_1.insert(
+            "codes".into(),
+            Attribute {
+                max_occurs: None,
+                ..Attribute::new(TypeRef::Code)
+            },
+        );
+        attrs_1.insert(
+            "detail".into(),
+            Attribute {
+                max_occurs: None,
+                ..Attribute::new(TypeRef::Named("detail".into()))
+            },
+        );
+        attrs_1.insert(
+            "generic".into(),
+            Attribute::new(TypeRef::Named("generic".into())),
+        );
+        types.insert(
+            "feature".into(),
+            TypeDef::Feature(FeatureTypeDef {
+                attributes: attrs_1,
+                additional_attributes: false,
+            }),
+        );
+
+        let mut attrs_2 = IndexMap::with_hasher(ahash::RandomState::default());
+        attrs_2.insert("year".into(), Attribute::new(TypeRef::Integer));
+        types.insert(
+            "detail".into(),
+            TypeDef::Data(DataTypeDef {
+                attributes: attrs_2,
+                additional_attributes: false,
+            }),
+        );
+        types.insert(
+            "generic".into(),
+            TypeDef::Data(DataTypeDef {
+                attributes: Default::default(),
+                additional_attributes: true,
+            }),
+        );
+        let schema = Schema {
+            types,
+            epsg: Some(4326),
+        };
+
+        let table_infos = schema_to_related_table_infos(&schema);
+        let columns = &table_infos.get("feature").unwrap().columns;
+        let names: Vec<_> = columns.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["text", "codes", "generic"]);
+        assert_eq!(columns[1].mime_type.as_deref(), Some("application/json"));
+        assert_eq!(columns[2].mime_type.as_deref(), Some("application/json"));
+
+        let detail = table_infos.get("detail").unwrap();
+        assert!(!detail.has_geometry);
+        assert_eq!(detail.columns[0].data_type, "INTEGER");
+    }
+
+    #[test]
+    fn test_lod_tables() {
+        let name = format!("bldg:Building{}", lod_table_suffix(2));
+        assert_eq!(name, "bldg:Building_lod2");
+        assert_eq!(strip_lod_suffix(&name), "bldg:Building");
+        assert_eq!(strip_lod_suffix("bldg:Building"), "bldg:Building");
+        assert_eq!(strip_lod_suffix("uro:lodType"), "uro:lodType");
+    }
 }
@@ -0,0 +1,171 @@
+//! Convenience SQL views for common analyses
+
+use indexmap::{IndexMap, IndexSet};
+use nusamai_gpkg::table::{ColumnInfo, TableInfo};
+
+/// Attribute table that associates the features with the meshcodes they belong to
+pub const MESHCODE_TABLE_NAME: &str = "feature_meshcodes";
+
+/// Columns added by the tree flattening, which are not useful in the joined views
+const LINK_COLUMNS: [&str; 2] = ["parentId", "parentType"];
+
+pub struct ViewDef {
+    pub name: String,
+    pub select: String,
+    pub has_geometry: bool,
+    /// The table that the view is mainly based on
+    pub base_table: String,
+}
+
+pub fn meshcode_table_info() -> TableInfo {
+    TableInfo {
+        name: MESHCODE_TABLE_NAME.into(),
+        has_geometry: false,
+        columns: ["table_name", "feature_id", "meshcode"]
+            .into_iter()
+            .map(|name| ColumnInfo {
+                name: name.into(),
+                data_type: "TEXT".into(),
+                mime_type: None,
+            })
+            .collect(),
+    }
+}
+
+/// Views of the features joined with the data records (e.g. disaster risks) that refer to them.
+///
+/// `links` is a set of (feature table, data table).
+pub fn joined_views(
+    links: &IndexSet<(String, String)>,
+    table_infos: &IndexMap<String, TableInfo>,
+) -> Vec<ViewDef> {
+    links
+        .iter()
+        .filter_map(|(feature_table, data_table)| {
+            let feature_info = table_infos.get(feature_table)?;
+            let data_info = table_infos.get(data_table)?;
+
+            let feature_columns: Vec<&str> = feature_info
+                .columns
+                .iter()
+                .map(|c| c.name.as_str())
+                .filter(|name| !LINK_COLUMNS.contains(name))
+                .collect();
+
+            let mut columns = vec![
+                "d.id AS fid".to_string(),
+                "f.id AS id".to_string(),
+                "f.geometry AS geometry".to_string(),
+            ];
+            columns.extend(feature_columns.iter().map(|name| format!("f.\"{name}\"")));
+            columns.extend(
+                data_info
+                    .columns
+                    .iter()
+                    .map(|c| c.name.as_str())
+                    .filter(|name| !LINK_COLUMNS.contains(name))
+                    .map(|name| match feature_columns.contains(&name) {
+                        true => format!("d.\"{name}\" AS \"{data_table}.{name}\""),
+                        false => format!("d.\"{name}\""),
+                    }),
+            );
+
+            Some(ViewDef {
+                name: format!("{feature_table}_{data_table}"),
+                select: format!(
+                    "SELECT {} FROM \"{feature_table}\" AS f JOIN \"{data_table}\" AS d ON d.parentId = f.id WHERE d.parentType = '{feature_table}'",
+                    columns.join(", ")
+                ),
+                has_geometry: true,
+                base_table: feature_table.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Views that count the features of each table per meshcode
+pub fn meshcode_views(feature_tables: &IndexSet<String>) -> Vec<ViewDef> {
+    feature_tables
+        .iter()
+        .map(|table| ViewDef {
+            name: format!("{table}_by_meshcode"),
+            select: format!(
+                "SELECT meshcode, COUNT(*) AS feature_count FROM \"{MESHCODE_TABLE_NAME}\" WHERE table_name = '{table}' GROUP BY meshcode"
+            ),
+            has_geometry: false,
+            base_table: table.clone(),
+        })
+        .collect()
+}
+
+/// Third-level (1km) standard regional mesh code (JIS X 0410) of the point.
+///
+/// The axis order does not matter since the longitude is always larger than the latitude in Japan.
+pub fn meshcode(x: f64, y: f64) -> Option<String> {
+    let (lng, lat) = (x.max(y), x.min(y));
+    if !(100.0..180.0).contains(&lng) || !(0.0..66.0).contains(&lat) {
+        return None;
+    }
+
+    // first level (80km), second level (10km) and third level (1km)
+    let (lat, lng) = (lat * 1.5, lng - 100.0);
+    let (p, u) = (lat.trunc(), lng.trunc());
+    let (lat, lng) = (lat.fract() * 8.0, lng.fract() * 8.0);
+    let (q, v) = (lat.trunc(), lng.trunc());
+    let (lat, lng) = (lat.fract() * 10.0, lng.fract() * 10.0);
+    let (r, w) = (lat.trunc(), lng.trunc());
+
+    Some(format!(
+        "{:02}{:02}{}{}{}{}",
+        p as u32, u as u32, q as u32, v as u32, r as u32, w as u32
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meshcode() {
+        // Tokyo Station
+        assert_eq!(meshcode(139.7671, 35.6812).as_deref(), Some("53394611"));
+        // latitude-first order
+        assert_eq!(meshcode(35.6812, 139.7671).as_deref(), Some("53394611"));
+        assert_eq!(meshcode(0.0, 0.0), None);
+    }
+
+    #[test]
+    fn test_joined_views() {
+        let column = |name: &str| ColumnInfo {
+            name: name.into(),
+            data_type: "TEXT".into(),
+            mime_type: None,
+        };
+        let mut table_infos = IndexMap::new();
+        table_infos.insert(
+            "bldg:Building".to_string(),
+            TableInfo {
+                name: "bldg:Building".into(),
+                has_geometry: true,
+                columns: vec![column("name"), column("parentId"), column("parentType")],
+            },
+        );
+        table_infos.insert(
+            "uro:Risk".to_string(),
+            TableInfo {
+                name: "uro:Risk".into(),
+                has_geometry: false,
+                columns: vec![column("name"), column("rank"), column("parentId")],
+            },
+        );
+
+        let links = IndexSet::from([("bldg:Building".to_string(), "uro:Risk".to_string())]);
+        let views = joined_views(&links, &table_infos);
+        assert_eq!(views.len(), 1);
+        assert_eq!(views[0].name, "bldg:Building_uro:Risk");
+        assert_eq!(
+            views[0].select,
+            "SELECT d.id AS fid, f.id AS id, f.geometry AS geometry, f.\"name\", d.\"name\" AS \"uro:Risk.name\", d.\"rank\" FROM \"bldg:Building\" AS f JOIN \"uro:Risk\" AS d ON d.parentId = f.id WHERE d.parentType = 'bldg:Building'"
+        );
+    }
+}
@@ -0,0 +1,432 @@
+//! Geometry and attribute buffers of the I3S nodes
+
+use byteorder::{ByteOrder, LittleEndian};
+use indexmap::IndexMap;
+use nusamai_citygml::schema::{Schema, TypeDef, TypeRef};
+use nusamai_gltf::draco;
+
+use super::{node::METERS_PER_DEGREE, Feature};
+
+/// Null value of the integer attributes
+const NULL_INTEGER: i32 = i32::MIN;
+/// Bits of the quantized positions of the compressed geometry (centimeters in a node of a kilometer)
+const POSITION_QUANTIZATION_BITS: u8 = 16;
+const NORMAL_QUANTIZATION_BITS: u8 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Oid,
+    Integer,
+    Double,
+    String,
+}
+
+impl FieldType {
+    fn esri_type(&self) -> &'static str {
+        match self {
+            Self::Oid => "esriFieldTypeOID",
+            Self::Integer => "esriFieldTypeInteger",
+            Self::Double => "esriFieldTypeDouble",
+            Self::String => "esriFieldTypeString",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub ty: FieldType,
+}
+
+impl Field {
+    fn new(name: &str, ty: FieldType) -> Self {
+        Self {
+            name: name.to_string(),
+            ty,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "type": self.ty.esri_type(),
+            "alias": self.name,
+        })
+    }
+
+    /// Layout of the attribute buffer (`attributeStorageInfo` of the layer)
+    pub fn storage_info(&self, key: &str) -> serde_json::Value {
+        let value_type = match self.ty {
+            FieldType::Oid => "UInt32",
+            FieldType::Integer => "Int32",
+            FieldType::Double => "Float64",
+            FieldType::String => {
+                return serde_json::json!({
+                    "key": key,
+                    "name": self.name,
+                    "header": [
+                        { "property": "count", "valueType": "UInt32" },
+                        { "property": "attributeValuesByteCount", "valueType": "UInt32" },
+                    ],
+                    "ordering": ["attributeByteCounts", "attributeValues"],
+                    "attributeByteCounts": { "valueType": "UInt32", "valuesPerElement": 1 },
+                    "attributeValues": {
+                        "valueType": "String",
+                        "encoding": "UTF-8",
+                        "valuesPerElement": 1,
+                    },
+                });
+            }
+        };
+        serde_json::json!({
+            "key": key,
+            "name": self.name,
+            "header": [{ "property": "count", "valueType": "UInt32" }],
+            "ordering": ["attributeValues"],
+            "attributeValues": { "valueType": value_type, "valuesPerElement": 1 },
+        })
+    }
+}
+
+/// The fields of the layer: `OBJECTID`, `gml_id`, `feature_type`, and the attributes of all the feature types.
+/// The values of the attributes are written as strings if the types are not numeric or differ among the feature types.
+pub fn fields_from_schema(schema: &Schema) -> Vec<Field> {
+    let mut fields = vec![
+        Field::new("OBJECTID", FieldType::Oid),
+        Field::new("gml_id", FieldType::String),
+        Field::new("feature_type", FieldType::String),
+    ];
+
+    let mut attributes: IndexMap<&str, FieldType> = IndexMap::new();
+    for ty in schema.types.values() {
+        let TypeDef::Feature(feature) = ty else {
+            continue;
+        };
+        for (name, attr) in &feature.attributes {
+            if fields.iter().any(|field| &field.name == name) {
+                continue;
+            }
+            let ty = match attr.type_ref {
+                TypeRef::Integer | TypeRef::NonNegativeInteger if attr.max_occurs == Some(1) => {
+                    FieldType::Integer
+                }
+                TypeRef::Double | TypeRef::Measure if attr.max_occurs == Some(1) => {
+                    FieldType::Double
+                }
+                _ => FieldType::String,
+            };
+            attributes
+                .entry(name)
+                .and_modify(|existing| {
+                    if *existing != ty {
+                        *existing = FieldType::String;
+                    }
+                })
+                .or_insert(ty);
+        }
+    }
+    fields.extend(
+        attributes
+            .into_iter()
+            .map(|(name, ty)| Field::new(name, ty)),
+    );
+    fields
+}
+
+/// The attributes of the compressed geometry buffer (`compressedAttributes` of the layer)
+pub const COMPRESSED_ATTRIBUTES: &[&str] = &["position", "normal", "color", "feature-index"];
+
+/// Encodes the uncompressed geometry buffer of the features (`geometryDefinitions[0].geometryBuffers[0]`).
+///
+/// The positions are the offsets from the center of the node (degrees for longitude and latitude, meters for height).
+/// `features` are the pairs of the feature IDs (`OBJECTID`) and the features.
+pub fn geometry_buffer(features: &[(u64, &Feature)], center: [f64; 3]) -> Vec<u8> {
+    let vertex_count: usize = features.iter().map(|(_, f)| f.positions.len()).sum();
+    let mut buf = Vec::with_capacity(8 + vertex_count * 36 + features.len() * 16);
+    push_u32(&mut buf, vertex_count as u32);
+    push_u32(&mut buf, features.len() as u32);
+
+    for (_, feature) in features {
+        for position in &feature.positions {
+            for (v, c) in position.iter().zip(center) {
+                push_f32(&mut buf, (v - c) as f32);
+            }
+        }
+    }
+    for (_, feature) in features {
+        for normal in &feature.normals {
+            for v in normal {
+                push_f32(&mut buf, *v);
+            }
+        }
+    }
+    // no textures
+    buf.resize(buf.len() + vertex_count * 8, 0);
+    for (_, feature) in features {
+        for color in &feature.colors {
+            buf.extend_from_slice(color);
+        }
+    }
+    for (id, _) in features {
+        let mut bytes = [0; 8];
+        LittleEndian::write_u64(&mut bytes, *id);
+        buf.extend_from_slice(&bytes);
+    }
+    // the first and the last triangles of the features
+    let mut first_triangle = 0;
+    for (_, feature) in features {
+        let triangle_count = (feature.positions.len() / 3) as u32;
+        push_u32(&mut buf, first_triangle);
+        push_u32(&mut buf, first_triangle + triangle_count.max(1) - 1);
+        first_triangle += triangle_count;
+    }
+    buf
+}
+
+/// Encodes the Draco-compressed geometry buffer of the features (`geometryDefinitions[0].geometryBuffers[1]`).
+///
+/// The vertices are the same as the uncompressed buffer. The feature of each vertex is given by the `feature-index`
+/// attribute, the index into the `i3s-feature-ids` metadata. The offsets of longitude and latitude are scaled to
+/// about meters to be quantized evenly with the height, and `i3s-scale_x` and `i3s-scale_y` of the position scale
+/// them back to degrees.
+pub fn compressed_geometry_buffer(features: &[(u64, &Feature)], center: [f64; 3]) -> Vec<u8> {
+    let scale_y = 1.0 / METERS_PER_DEGREE;
+    let scale_x = scale_y / center[1].to_radians().cos().max(0.01);
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut colors = Vec::new();
+    let mut feature_indices = Vec::new();
+    for (index, (_, feature)) in features.iter().enumerate() {
+        for position in &feature.positions {
+            positions.extend([
+                ((position[0] - center[0]) / scale_x) as f32,
+                ((position[1] - center[1]) / scale_y) as f32,
+                (position[2] - center[2]) as f32,
+            ]);
+        }
+        normals.extend(feature.normals.iter().flatten());
+        colors.extend(feature.colors.iter().flatten().map(|&c| c as f32));
+        feature_indices.extend(std::iter::repeat_n(index as f32, feature.positions.len()));
+    }
+    let vertex_count = feature_indices.len() as u32;
+    // (the triangles are not indexed)
+    let indices: Vec<u32> = (0..vertex_count).collect();
+
+    let (scale_x, scale_y) = (scale_x.to_le_bytes(), scale_y.to_le_bytes());
+    let feature_ids: Vec<u8> = features
+        .iter()
+        .flat_map(|(id, _)| (*id as i32).to_le_bytes())
+        .collect();
+    fn attribute<'a>(
+        attribute_type: draco::AttributeType,
+        data_type: draco::DataType,
+        num_components: u8,
+        values: &'a [f32],
+    ) -> draco::Attribute<'a> {
+        draco::Attribute {
+            attribute_type,
+            data_type,
+            normalized: false,
+            num_components,
+            values,
+            quantization_bits: None,
+            metadata: &[],
+        }
+    }
+    draco::encode_mesh(
+        &indices,
+        vertex_count,
+        &[
+            draco::Attribute {
+                quantization_bits: Some(POSITION_QUANTIZATION_BITS),
+                metadata: &[("i3s-scale_x", &scale_x), ("i3s-scale_y", &scale_y)],
+                ..attribute(
+                    draco::AttributeType::Position,
+                    draco::DataType::Float32,
+                    3,
+                    &positions,
+                )
+            },
+            draco::Attribute {
+                quantization_bits: Some(NORMAL_QUANTIZATION_BITS),
+                ..attribute(
+                    draco::AttributeType::Normal,
+                    draco::DataType::Float32,
+                    3,
+                    &normals,
+                )
+            },
+            draco::Attribute {
+                normalized: true,
+                ..attribute(
+                    draco::AttributeType::Color,
+                    draco::DataType::UInt8,
+                    4,
+                    &colors,
+                )
+            },
+            draco::Attribute {
+                metadata: &[
+                    ("i3s-attribute-type", b"feature-index"),
+                    ("i3s-feature-ids", &feature_ids),
+                ],
+                ..attribute(
+                    draco::AttributeType::Generic,
+                    draco::DataType::UInt32,
+                    1,
+                    &feature_indices,
+                )
+            },
+        ],
+    )
+}
+
+/// Encodes the attribute buffer of a field
+pub fn attribute_buffer<'a>(
+    field: &Field,
+    values: impl ExactSizeIterator<Item = &'a serde_json::Value>,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    push_u32(&mut buf, values.len() as u32);
+    match field.ty {
+        FieldType::Oid => {
+            for value in values {
+                push_u32(&mut buf, value.as_u64().unwrap_or_default() as u32);
+            }
+        }
+        FieldType::Integer => {
+            for value in values {
+                let value = value
+                    .as_i64()
+                    .and_then(|v| i32::try_from(v).ok())
+                    .unwrap_or(NULL_INTEGER);
+                let mut bytes = [0; 4];
+                LittleEndian::write_i32(&mut bytes, value);
+                buf.extend_from_slice(&bytes);
+            }
+        }
+        FieldType::Double => {
+            // (the values are aligned to 8 bytes)
+            push_u32(&mut buf, 0);
+            for value in values {
+                let mut bytes = [0; 8];
+                LittleEndian::write_f64(&mut bytes, value.as_f64().unwrap_or(f64::NAN));
+                buf.extend_from_slice(&bytes);
+            }
+        }
+        FieldType::String => {
+            let strings: Vec<Option<String>> = values
+                .map(|value| match value {
+                    serde_json::Value::Null => None,
+                    serde_json::Value::String(s) => Some(s.clone()),
+                    value => Some(value.to_string()),
+                })
+                .collect();
+            // (null-terminated)
+            let byte_counts: Vec<u32> = strings
+                .iter()
+                .map(|s| s.as_ref().map_or(0, |s| s.len() as u32 + 1))
+                .collect();
+            push_u32(&mut buf, byte_counts.iter().sum());
+            for count in &byte_counts {
+                push_u32(&mut buf, *count);
+            }
+            for s in strings.iter().flatten() {
+                buf.extend_from_slice(s.as_bytes());
+                buf.push(0);
+            }
+        }
+    }
+    buf
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    let mut bytes = [0; 4];
+    LittleEndian::write_u32(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+fn push_f32(buf: &mut Vec<u8>, value: f32) {
+    let mut bytes = [0; 4];
+    LittleEndian::write_f32(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::node::Bounds;
+    use super::*;
+
+    #[test]
+    fn test_geometry_buffer() {
+        let feature = Feature {
+            positions: vec![
+                [139.0, 35.0, 10.0],
+                [139.001, 35.0, 10.0],
+                [139.0, 35.001, 10.0],
+            ],
+            normals: vec![[0.0, 0.0, 1.0]; 3],
+            colors: vec![[255, 0, 0, 255]; 3],
+            attributes: Vec::new(),
+            bounds: Bounds::default(),
+        };
+        let buf = geometry_buffer(&[(7, &feature)], [139.0, 35.0, 0.0]);
+
+        // header + positions, normals, uvs, colors + feature ids, face ranges
+        assert_eq!(buf.len(), 8 + 3 * (12 + 12 + 8 + 4) + 8 + 8);
+        assert_eq!(LittleEndian::read_u32(&buf[0..]), 3);
+        assert_eq!(LittleEndian::read_u32(&buf[4..]), 1);
+        assert_eq!(LittleEndian::read_f32(&buf[8 + 12..]), 0.001);
+        assert_eq!(LittleEndian::read_f32(&buf[8 + 8..]), 10.0);
+        assert_eq!(&buf[8 + 3 * 32..8 + 3 * 32 + 4], &[255, 0, 0, 255]);
+        assert_eq!(LittleEndian::read_u64(&buf[buf.len() - 16..]), 7);
+        assert_eq!(LittleEndian::read_u32(&buf[buf.len() - 8..]), 0);
+        assert_eq!(LittleEndian::read_u32(&buf[buf.len() - 4..]), 0);
+    }
+
+    #[test]
+    fn test_compressed_geometry_buffer() {
+        let feature = Feature {
+            positions: vec![
+                [139.0, 35.0, 10.0],
+                [139.001, 35.0, 10.0],
+                [139.0, 35.001, 10.0],
+            ],
+            normals: vec![[0.0, 0.0, 1.0]; 3],
+            colors: vec![[255, 0, 0, 255]; 3],
+            attributes: Vec::new(),
+            bounds: Bounds::default(),
+        };
+        let buf = compressed_geometry_buffer(&[(7, &feature)], [139.0, 35.0, 0.0]);
+
+        assert_eq!(&buf[..5], b"DRACO");
+        // (with the metadata)
+        assert_eq!(LittleEndian::read_u16(&buf[9..]), 0x8000);
+        let contains = |bytes: &[u8]| buf.windows(bytes.len()).any(|w| w == bytes);
+        assert!(contains(b"i3s-scale_x"));
+        assert!(contains(b"feature-index"));
+        assert!(contains(&[b's', b'-', b'i', b'd', b's', 4, 7, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_attribute_buffer() {
+        let values = [
+            serde_json::json!("建物"),
+            serde_json::Value::Null,
+            serde_json::json!(12.5),
+        ];
+        let buf = attribute_buffer(&Field::new("name", FieldType::String), values.iter());
+        assert_eq!(LittleEndian::read_u32(&buf[0..]), 3);
+        assert_eq!(LittleEndian::read_u32(&buf[4..]), 7 + 5);
+        assert_eq!(LittleEndian::read_u32(&buf[8..]), 7);
+        assert_eq!(LittleEndian::read_u32(&buf[12..]), 0);
+        assert_eq!(LittleEndian::read_u32(&buf[16..]), 5);
+        assert_eq!(&buf[20..], "建物\012.5\0".as_bytes());
+
+        let buf = attribute_buffer(&Field::new("height", FieldType::Double), values.iter());
+        assert_eq!(buf.len(), 8 + 3 * 8);
+        assert!(LittleEndian::read_f64(&buf[8..]).is_nan());
+        assert_eq!(LittleEndian::read_f64(&buf[24..]), 12.5);
+    }
+}
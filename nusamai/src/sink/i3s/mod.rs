@@ -0,0 +1,351 @@
+//! I3S sink
+//!
+//! Writes the features into a Scene Layer Package (`.slpk`) of an I3S 1.8 `3DObject` layer with node pages,
+//! which can be added to ArcGIS Pro and published to ArcGIS Online as it is.
+//!
+//! The features are grouped into the nodes of a bounding volume hierarchy. The parent nodes hold the largest features
+//! of their descendants as the coarse level of detail (mesh pyramid). The meshes are colored by the vertex colors
+//! (from the materials) without textures. Each node has the uncompressed geometry buffer and the Draco-compressed one
+//! (`compressedAttributes`), which the clients prefer.
+
+mod buffer;
+mod node;
+mod slpk;
+
+use std::{path::PathBuf, sync::Mutex};
+
+use buffer::{
+    attribute_buffer, compressed_geometry_buffer, fields_from_schema, geometry_buffer, Field,
+};
+use earcut::{utils3d::project3d_to_2d, Earcut};
+use glam::DVec3;
+use node::{build_nodes, Bounds, Node, Obb};
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+    GeometryType,
+};
+use nusamai_plateau::{appearance, Entity};
+use nusamai_projection::{cartesian::geodetic_to_geocentric, ellipsoid::Ellipsoid};
+use rayon::prelude::*;
+use slpk::{gzip, node_page_entry, package_metadata, scene_layer, SlpkWriter, NODES_PER_PAGE};
+
+use super::option::output_parameter;
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer::{
+        name_columns_config, prefix_config, underground_config, use_lod_config, TransformerSettings,
+    },
+};
+
+/// Number of the nodes encoded in parallel at a time
+const NODE_BATCH_SIZE: usize = 256;
+
+pub struct I3sSinkProvider {}
+
+impl DataSinkProvider for I3sSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "i3s".to_string(),
+            name: "I3S (Scene Layer Package)".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(use_lod_config("max_lod", None));
+        settings.insert(underground_config());
+        // ArcGIS does not accept `:` in the field names
+        settings.insert(prefix_config(&["keep"]));
+        settings.insert(name_columns_config());
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let transform_settings = self.transformer_options();
+
+        Box::<I3sSink>::new(I3sSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            transform_settings,
+        })
+    }
+}
+
+pub struct I3sSink {
+    output_path: PathBuf,
+    transform_settings: TransformerSettings,
+}
+
+/// Triangulated feature (the triangles are not indexed)
+struct Feature {
+    /// [lng, lat, height]
+    positions: Vec<[f64; 3]>,
+    /// Normals in the earth-centered frame
+    normals: Vec<[f32; 3]>,
+    colors: Vec<[u8; 4]>,
+    /// Values of the fields (except `OBJECTID`)
+    attributes: Vec<serde_json::Value>,
+    bounds: Bounds,
+}
+
+impl DataSink for I3sSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        let default_requirements = DataRequirements {
+            resolve_appearance: true,
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        let ellipsoid = nusamai_projection::ellipsoid::wgs84();
+        let fields = fields_from_schema(schema);
+
+        let features = Mutex::new(Vec::new());
+        let result = upstream.into_iter().par_bridge().try_for_each(|parcel| {
+            feedback.ensure_not_canceled()?;
+            if let Some(feature) = make_feature(&parcel.entity, &fields[1..], &ellipsoid) {
+                features.lock().unwrap().push(feature);
+            }
+            Ok::<(), PipelineError>(())
+        });
+        match result {
+            Ok(_) => {}
+            Err(PipelineError::Canceled) => return Ok(()),
+            Err(error) => return Err(error),
+        }
+        let features = features.into_inner().unwrap();
+        if features.is_empty() {
+            feedback.warn("No features to write".into());
+            return Ok(());
+        }
+
+        let nodes = build_nodes(&features);
+        let obbs: Vec<Obb> = nodes
+            .par_iter()
+            .map(|node| Obb::new(&node.bounds, &ellipsoid))
+            .collect();
+        feedback.info(format!(
+            "Writing {} features in {} nodes",
+            features.len(),
+            nodes.len()
+        ));
+
+        let name = self
+            .output_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut writer = SlpkWriter::create(&self.output_path)?;
+        writer.write(
+            "metadata.json",
+            package_metadata(nodes.len()).to_string().as_bytes(),
+        )?;
+        writer.write_gzipped(
+            "3dSceneLayer.json",
+            scene_layer(&name, &fields, &nodes[0].bounds)
+                .to_string()
+                .as_bytes(),
+        )?;
+
+        for (page, chunk) in nodes.chunks(NODES_PER_PAGE).enumerate() {
+            let entries: Vec<_> = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, node)| {
+                    let index = page * NODES_PER_PAGE + i;
+                    let mesh = (!node.features.is_empty()).then(|| {
+                        let vertex_count = node
+                            .features
+                            .iter()
+                            .map(|&f| features[f].positions.len())
+                            .sum();
+                        (vertex_count, node.features.len())
+                    });
+                    node_page_entry(index, node, &obbs[index], mesh)
+                })
+                .collect();
+            writer.write_gzipped(
+                &format!("nodepages/{page}.json"),
+                serde_json::json!({ "nodes": entries })
+                    .to_string()
+                    .as_bytes(),
+            )?;
+        }
+
+        for start in (0..nodes.len()).step_by(NODE_BATCH_SIZE) {
+            feedback.ensure_not_canceled()?;
+            let end = (start + NODE_BATCH_SIZE).min(nodes.len());
+            let resources = (start..end)
+                .into_par_iter()
+                .map(|index| encode_node(index, &nodes[index], &obbs[index], &features, &fields))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            for (path, content) in resources.into_iter().flatten() {
+                writer.write(&path, &content)?;
+            }
+        }
+
+        writer.finish()
+    }
+}
+
+/// Triangulates the polygons of the entity. Returns `None` if it has no polygons.
+fn make_feature(entity: &Entity, fields: &[Field], ellipsoid: &Ellipsoid) -> Option<Feature> {
+    let Value::Object(obj) = &entity.root else {
+        return None;
+    };
+    let ObjectStereotype::Feature { id, geometries } = &obj.stereotype else {
+        return None;
+    };
+
+    let geom_store = entity.geometry_store.read().unwrap();
+    let appearance_store = entity.appearance_store.read().unwrap();
+    let default_material = appearance::Material::default();
+
+    let mut feature = Feature {
+        positions: Vec::new(),
+        normals: Vec::new(),
+        colors: Vec::new(),
+        attributes: Vec::new(),
+        bounds: Bounds::default(),
+    };
+
+    let mut earcutter = Earcut::new();
+    let mut buf3d: Vec<[f64; 3]> = Vec::new();
+    let mut buf2d: Vec<[f64; 2]> = Vec::new();
+    let mut index_buf: Vec<u32> = Vec::new();
+
+    for entry in geometries {
+        if !matches!(
+            entry.ty,
+            GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+        ) {
+            continue;
+        }
+        let range = entry.pos as usize..(entry.pos + entry.len) as usize;
+        for (poly_idx, poly) in range.clone().zip(geom_store.multipolygon.iter_range(range)) {
+            let material = geom_store
+                .polygon_materials
+                .get(poly_idx)
+                .copied()
+                .flatten()
+                .and_then(|idx| appearance_store.materials.get(idx as usize))
+                .unwrap_or(&default_material);
+            let color = material
+                .base_color()
+                .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+
+            // Convert to geocentric (x, y, z) coordinate (Earcut do not work in geographic space)
+            let indices = poly.raw_coords();
+            buf3d.clear();
+            buf3d.extend(indices.iter().map(|&idx| {
+                let [lng, lat, height] = geom_store.vertices[idx as usize];
+                let (x, y, z) = geodetic_to_geocentric(ellipsoid, lng, lat, height);
+                [x, y, z]
+            }));
+            let num_outer = match poly.hole_indices().first() {
+                Some(&v) => v as usize,
+                None => indices.len(),
+            };
+            if !project3d_to_2d(&buf3d, num_outer, &mut buf2d) {
+                continue;
+            }
+            earcutter.earcut(buf2d.iter().cloned(), poly.hole_indices(), &mut index_buf);
+
+            for tri in index_buf.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| DVec3::from_array(buf3d[tri[i] as usize]));
+                let normal = (b - a).cross(c - a).normalize_or_zero();
+                if normal == DVec3::ZERO {
+                    continue;
+                }
+                for &i in tri {
+                    let position = geom_store.vertices[indices[i as usize] as usize];
+                    feature.bounds.add_point(position);
+                    feature.positions.push(position);
+                    feature.normals.push(normal.as_vec3().to_array());
+                    feature.colors.push(color);
+                }
+            }
+        }
+    }
+    if feature.positions.is_empty() {
+        return None;
+    }
+
+    feature.attributes = fields
+        .iter()
+        .map(|field| match field.name.as_str() {
+            "gml_id" => serde_json::Value::String(id.clone()),
+            "feature_type" => serde_json::Value::String(obj.typename.to_string()),
+            name => obj
+                .attributes
+                .get(name)
+                .map(Value::to_attribute_json)
+                .unwrap_or_default(),
+        })
+        .collect();
+    Some(feature)
+}
+
+/// Encodes the geometry and the attributes of a node. Returns the (gzipped) resources.
+fn encode_node(
+    index: usize,
+    node: &Node,
+    obb: &Obb,
+    features: &[Feature],
+    fields: &[Field],
+) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    if node.features.is_empty() {
+        return Ok(Vec::new());
+    }
+    // OBJECTID starts from 1
+    let node_features: Vec<(u64, &Feature)> = node
+        .features
+        .iter()
+        .map(|&f| (f as u64 + 1, &features[f]))
+        .collect();
+
+    let mut resources = Vec::with_capacity(fields.len() + 2);
+    resources.push((
+        format!("nodes/{index}/geometries/0.bin.gz"),
+        gzip(&geometry_buffer(&node_features, obb.center))?,
+    ));
+    resources.push((
+        format!("nodes/{index}/geometries/1.bin.gz"),
+        gzip(&compressed_geometry_buffer(&node_features, obb.center))?,
+    ));
+    for (i, field) in fields.iter().enumerate() {
+        let buf = match i {
+            0 => {
+                let ids: Vec<serde_json::Value> =
+                    node_features.iter().map(|(id, _)| (*id).into()).collect();
+                attribute_buffer(field, ids.iter())
+            }
+            _ => attribute_buffer(
+                field,
+                node_features.iter().map(|(_, f)| &f.attributes[i - 1]),
+            ),
+        };
+        resources.push((
+            format!("nodes/{index}/attributes/f_{i}/0.bin.gz"),
+            gzip(&buf)?,
+        ));
+    }
+    Ok(resources)
+}
@@ -0,0 +1,309 @@
+//! Bounding volume hierarchy of the I3S nodes
+
+use std::collections::VecDeque;
+
+use glam::{DMat3, DQuat, DVec3};
+use nusamai_projection::{cartesian::geodetic_to_geocentric, ellipsoid::Ellipsoid};
+
+use super::Feature;
+
+/// Maximum number of the features in a node
+const MAX_FEATURES_PER_NODE: usize = 256;
+/// Maximum number of the vertices in a node (the geometry buffers are not indexed)
+const MAX_VERTICES_PER_NODE: usize = 300_000;
+/// The parent nodes hold the features larger than this ratio of the node size
+const MIN_REPRESENTATIVE_RATIO: f64 = 0.05;
+pub const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Extent of the features: [lng, lat, height]
+#[derive(Debug, Clone, Copy)]
+pub struct Bounds {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+
+impl Default for Bounds {
+    fn default() -> Self {
+        Self {
+            min: [f64::MAX; 3],
+            max: [f64::MIN; 3],
+        }
+    }
+}
+
+impl Bounds {
+    pub fn add_point(&mut self, point: [f64; 3]) {
+        for ((min, max), v) in self.min.iter_mut().zip(self.max.iter_mut()).zip(point) {
+            *min = min.min(v);
+            *max = max.max(v);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Bounds) {
+        self.add_point(other.min);
+        self.add_point(other.max);
+    }
+
+    pub fn center(&self) -> [f64; 3] {
+        [0, 1, 2].map(|i| (self.min[i] + self.max[i]) / 2.0)
+    }
+
+    /// Approximate horizontal size in meters
+    fn horizontal_size(&self) -> f64 {
+        let lat = self.center()[1].to_radians();
+        let dx = (self.max[0] - self.min[0]) * METERS_PER_DEGREE * lat.cos();
+        let dy = (self.max[1] - self.min[1]) * METERS_PER_DEGREE;
+        dx.hypot(dy)
+    }
+}
+
+/// Oriented bounding box in the global (WGS 84) scene
+#[derive(Debug, Clone, Copy)]
+pub struct Obb {
+    /// [lng, lat, height]
+    pub center: [f64; 3],
+    /// Half sizes in meters along the east, north and up axes
+    pub half_size: [f64; 3],
+    /// Rotation from the local east-north-up frame to the earth-centered frame: [x, y, z, w]
+    pub quaternion: [f64; 4],
+}
+
+impl Obb {
+    pub fn new(bounds: &Bounds, ellipsoid: &Ellipsoid) -> Self {
+        let center = bounds.center();
+        let (lng, lat) = (center[0].to_radians(), center[1].to_radians());
+        let east = DVec3::new(-lng.sin(), lng.cos(), 0.0);
+        let north = DVec3::new(-lat.sin() * lng.cos(), -lat.sin() * lng.sin(), lat.cos());
+        let up = DVec3::new(lat.cos() * lng.cos(), lat.cos() * lng.sin(), lat.sin());
+
+        let to_ecef = |[lng, lat, height]: [f64; 3]| {
+            let (x, y, z) = geodetic_to_geocentric(ellipsoid, lng, lat, height);
+            DVec3::new(x, y, z)
+        };
+        let origin = to_ecef(center);
+
+        // the corners, and the top and the bottom of the center (the surface bulges at the center)
+        let mut points = Vec::with_capacity(10);
+        for lng in [bounds.min[0], bounds.max[0]] {
+            for lat in [bounds.min[1], bounds.max[1]] {
+                for height in [bounds.min[2], bounds.max[2]] {
+                    points.push([lng, lat, height]);
+                }
+            }
+        }
+        points.push([center[0], center[1], bounds.min[2]]);
+        points.push([center[0], center[1], bounds.max[2]]);
+
+        let mut half_size = DVec3::ZERO;
+        for point in points {
+            let v = to_ecef(point) - origin;
+            half_size = half_size.max(DVec3::new(
+                v.dot(east).abs(),
+                v.dot(north).abs(),
+                v.dot(up).abs(),
+            ));
+        }
+
+        Self {
+            center,
+            half_size: half_size.to_array(),
+            quaternion: DQuat::from_mat3(&DMat3::from_cols(east, north, up)).to_array(),
+        }
+    }
+}
+
+pub struct Node {
+    /// Indices of the features in this node
+    pub features: Vec<usize>,
+    /// Indices of the child nodes
+    pub children: Vec<usize>,
+    pub parent: Option<usize>,
+    /// Extent of the features of this node and its descendants
+    pub bounds: Bounds,
+}
+
+struct Cluster {
+    features: Vec<usize>,
+    children: Vec<Cluster>,
+    bounds: Bounds,
+}
+
+/// Builds the nodes (in the breadth-first order, the root is the first one).
+///
+/// The leaf nodes hold all the features. The parent nodes hold the largest features of their descendants
+/// as the coarse level of detail, which are replaced by the children when the viewer zooms in (node switching).
+pub fn build_nodes(features: &[Feature]) -> Vec<Node> {
+    let root = cluster(features, (0..features.len()).collect());
+
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut queue = VecDeque::from([(root, None)]);
+    while let Some((cluster, parent)) = queue.pop_front() {
+        let index = nodes.len();
+        nodes.push(Node {
+            features: cluster.features,
+            children: Vec::new(),
+            parent,
+            bounds: cluster.bounds,
+        });
+        if let Some(parent) = parent {
+            nodes[parent].children.push(index);
+        }
+        for child in cluster.children {
+            queue.push_back((child, Some(index)));
+        }
+    }
+    nodes
+}
+
+fn cluster(features: &[Feature], indices: Vec<usize>) -> Cluster {
+    let mut bounds = Bounds::default();
+    for &i in &indices {
+        bounds.merge(&features[i].bounds);
+    }
+    let vertex_count: usize = indices.iter().map(|&i| features[i].positions.len()).sum();
+
+    if indices.len() <= 1
+        || (indices.len() <= MAX_FEATURES_PER_NODE && vertex_count <= MAX_VERTICES_PER_NODE)
+    {
+        return Cluster {
+            features: indices,
+            children: Vec::new(),
+            bounds,
+        };
+    }
+
+    let representatives = select_representatives(features, &indices, &bounds);
+    let children = split(features, indices)
+        .into_iter()
+        .flat_map(|half| split(features, half))
+        .filter(|part| !part.is_empty())
+        .map(|part| cluster(features, part))
+        .collect();
+
+    Cluster {
+        features: representatives,
+        children,
+        bounds,
+    }
+}
+
+/// Splits the features into two halves along the longer axis
+fn split(features: &[Feature], mut indices: Vec<usize>) -> [Vec<usize>; 2] {
+    if indices.len() <= 1 {
+        return [indices, Vec::new()];
+    }
+    let mut bounds = Bounds::default();
+    for &i in &indices {
+        bounds.merge(&features[i].bounds);
+    }
+    let lat = bounds.center()[1].to_radians();
+    let axis = match (bounds.max[0] - bounds.min[0]) * lat.cos() > bounds.max[1] - bounds.min[1] {
+        true => 0,
+        false => 1,
+    };
+    indices.sort_by(|&a, &b| {
+        let a = features[a].bounds.center()[axis];
+        let b = features[b].bounds.center()[axis];
+        a.total_cmp(&b)
+    });
+    let second = indices.split_off(indices.len() / 2);
+    [indices, second]
+}
+
+/// The largest features (relative to the node) up to a quarter of the capacity of a node
+fn select_representatives(features: &[Feature], indices: &[usize], bounds: &Bounds) -> Vec<usize> {
+    let min_size = bounds.horizontal_size() * MIN_REPRESENTATIVE_RATIO;
+    let mut candidates: Vec<(usize, f64)> = indices
+        .iter()
+        .map(|&i| (i, features[i].bounds.horizontal_size()))
+        .filter(|&(_, size)| size >= min_size)
+        .collect();
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut vertex_count = 0;
+    let mut selected = Vec::new();
+    for (i, _) in candidates {
+        vertex_count += features[i].positions.len();
+        if selected.len() >= MAX_FEATURES_PER_NODE / 4 || vertex_count > MAX_VERTICES_PER_NODE / 4 {
+            break;
+        }
+        selected.push(i);
+    }
+    selected.sort_unstable();
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature(lng: f64, lat: f64, size: f64) -> Feature {
+        let mut bounds = Bounds::default();
+        bounds.add_point([lng, lat, 0.0]);
+        bounds.add_point([lng + size, lat + size, 10.0]);
+        Feature {
+            positions: vec![[lng, lat, 0.0]; 3],
+            normals: vec![[0.0, 0.0, 1.0]; 3],
+            colors: vec![[255; 4]; 3],
+            attributes: Vec::new(),
+            bounds,
+        }
+    }
+
+    #[test]
+    fn test_build_nodes() {
+        let mut features = vec![feature(139.70, 35.60, 0.05)];
+        for i in 0..1000 {
+            let (x, y) = ((i % 40) as f64, (i / 40) as f64);
+            features.push(feature(139.7 + x * 0.001, 35.6 + y * 0.001, 0.0001));
+        }
+        let nodes = build_nodes(&features);
+
+        let root = &nodes[0];
+        assert!(root.parent.is_none());
+        assert!(!root.children.is_empty());
+        // the largest feature represents the root
+        assert_eq!(root.features, vec![0]);
+
+        // the leaves hold all the features exactly once
+        let mut leaf_features: Vec<usize> = nodes
+            .iter()
+            .filter(|node| node.children.is_empty())
+            .flat_map(|node| node.features.iter().copied())
+            .collect();
+        leaf_features.sort_unstable();
+        assert_eq!(leaf_features, (0..features.len()).collect::<Vec<_>>());
+
+        // breadth-first order
+        for (index, node) in nodes.iter().enumerate() {
+            assert!(node.features.len() <= MAX_FEATURES_PER_NODE);
+            for &child in &node.children {
+                assert!(child > index);
+                assert_eq!(nodes[child].parent, Some(index));
+            }
+        }
+    }
+
+    #[test]
+    fn test_obb() {
+        let ellipsoid = nusamai_projection::ellipsoid::wgs84();
+        let mut bounds = Bounds::default();
+        bounds.add_point([139.0, 35.0, 0.0]);
+        bounds.add_point([139.001, 35.001, 100.0]);
+        let obb = Obb::new(&bounds, &ellipsoid);
+
+        let [lng, lat, height] = obb.center;
+        assert!((lng - 139.0005).abs() < 1e-9 && (lat - 35.0005).abs() < 1e-9);
+        assert_eq!(height, 50.0);
+        let [east, north, up] = obb.half_size;
+        assert!((east - 45.6).abs() < 1.0, "{east}");
+        assert!((north - 55.5).abs() < 1.0, "{north}");
+        assert!((up - 50.0).abs() < 0.1, "{up}");
+
+        // the up axis of the box points outward
+        let rotation = DQuat::from_array(obb.quaternion);
+        let up_axis = rotation * DVec3::Z;
+        let (x, y, z) = geodetic_to_geocentric(&ellipsoid, 139.0, 35.0, 0.0);
+        assert!(up_axis.dot(DVec3::new(x, y, z).normalize()) > 0.99);
+    }
+}
@@ -0,0 +1,268 @@
+//! Scene Layer Package (SLPK) writer
+//!
+//! An SLPK is a ZIP archive (without ZIP compression) of the gzipped resources of a scene layer.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use super::{
+    buffer::{Field, COMPRESSED_ATTRIBUTES},
+    node::{Bounds, Node, Obb},
+};
+use crate::pipeline::{PipelineError, Result};
+
+pub const I3S_VERSION: &str = "1.8";
+pub const NODES_PER_PAGE: usize = 64;
+/// Nodes are replaced by their children when their projected areas exceed this
+/// (pixels squared, a circle of 500 pixels in diameter)
+const LOD_THRESHOLD: f64 = std::f64::consts::PI * 250.0 * 250.0;
+
+pub struct SlpkWriter {
+    zip: ZipWriter<BufWriter<File>>,
+}
+
+impl SlpkWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::create(path)?;
+        Ok(Self {
+            zip: ZipWriter::new(BufWriter::with_capacity(1024 * 1024, file)),
+        })
+    }
+
+    /// Writes a resource as it is
+    pub fn write(&mut self, path: &str, content: &[u8]) -> Result<()> {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(content.len() >= u32::MAX as usize);
+        self.zip.start_file(path, options).map_err(map_zip_error)?;
+        self.zip.write_all(content)?;
+        Ok(())
+    }
+
+    /// Writes a resource compressed with gzip (`.gz` is appended to the path)
+    pub fn write_gzipped(&mut self, path: &str, content: &[u8]) -> Result<()> {
+        self.write(&format!("{path}.gz"), &gzip(content)?)
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.zip.finish().map_err(map_zip_error)?.flush()?;
+        Ok(())
+    }
+}
+
+pub fn gzip(content: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(content)?;
+    encoder.finish()
+}
+
+fn map_zip_error(err: zip::result::ZipError) -> PipelineError {
+    match err {
+        zip::result::ZipError::Io(err) => PipelineError::IoError(err),
+        err => PipelineError::Other(err.to_string()),
+    }
+}
+
+/// `metadata.json` of the package
+pub fn package_metadata(node_count: usize) -> serde_json::Value {
+    serde_json::json!({
+        "folderPattern": "BASIC",
+        "archiveCompressionType": "STORE",
+        "resourceCompressionType": "GZIP",
+        "I3SVersion": I3S_VERSION,
+        "nodeCount": node_count,
+    })
+}
+
+/// Layer document (`3dSceneLayer.json`) of a vertex-colored `3DObject` layer in WGS 84
+pub fn scene_layer(name: &str, fields: &[Field], extent: &Bounds) -> serde_json::Value {
+    let id = guid(name);
+    let attribute_storage_info: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| field.storage_info(&format!("f_{i}")))
+        .collect();
+    serde_json::json!({
+        "id": 0,
+        "version": id,
+        "name": name,
+        "alias": name,
+        "href": "./layers/0",
+        "layerType": "3DObject",
+        "spatialReference": {
+            "wkid": 4326,
+            "latestWkid": 4326,
+            "vcsWkid": 115700,
+            "latestVcsWkid": 115700,
+        },
+        "heightModelInfo": {
+            "heightModel": "ellipsoidal",
+            "vertCRS": "WGS_84",
+            "heightUnit": "meter",
+        },
+        "capabilities": ["View", "Query"],
+        "store": {
+            "id": id,
+            "profile": "meshpyramids",
+            "version": I3S_VERSION,
+            "resourcePattern": ["Geometry", "Attributes"],
+            "extent": [extent.min[0], extent.min[1], extent.max[0], extent.max[1]],
+            "indexCRS": "http://www.opengis.net/def/crs/EPSG/0/4326",
+            "vertexCRS": "http://www.opengis.net/def/crs/EPSG/0/4326",
+            "normalReferenceFrame": "earth-centered",
+            "lodType": "MeshPyramid",
+            "lodModel": "node-switching",
+            "defaultGeometrySchema": {
+                "geometryType": "triangles",
+                "header": [
+                    { "property": "vertexCount", "type": "UInt32" },
+                    { "property": "featureCount", "type": "UInt32" },
+                ],
+                "topology": "PerAttributeArray",
+                "ordering": ["position", "normal", "uv0", "color"],
+                "vertexAttributes": {
+                    "position": { "valueType": "Float32", "valuesPerElement": 3 },
+                    "normal": { "valueType": "Float32", "valuesPerElement": 3 },
+                    "uv0": { "valueType": "Float32", "valuesPerElement": 2 },
+                    "color": { "valueType": "UInt8", "valuesPerElement": 4 },
+                },
+                "featureAttributeOrder": ["id", "faceRange"],
+                "featureAttributes": {
+                    "id": { "valueType": "UInt64", "valuesPerElement": 1 },
+                    "faceRange": { "valueType": "UInt32", "valuesPerElement": 2 },
+                },
+            },
+        },
+        "nodePages": {
+            "nodesPerPage": NODES_PER_PAGE,
+            "lodSelectionMetricType": "maxScreenThresholdSQ",
+        },
+        "materialDefinitions": [{
+            "doubleSided": true,
+            "pbrMetallicRoughness": {
+                "baseColorFactor": [1.0, 1.0, 1.0, 1.0],
+                "metallicFactor": 0.0,
+                "roughnessFactor": 1.0,
+            },
+        }],
+        "geometryDefinitions": [{
+            "geometryBuffers": [{
+                "offset": 8,
+                "position": { "type": "Float32", "component": 3 },
+                "normal": { "type": "Float32", "component": 3 },
+                "uv0": { "type": "Float32", "component": 2 },
+                "color": { "type": "UInt8", "component": 4 },
+                "featureId": { "type": "UInt64", "component": 1, "binding": "per-feature" },
+                "faceRange": { "type": "UInt32", "component": 2, "binding": "per-feature" },
+            }, {
+                "compressedAttributes": {
+                    "encoding": "draco",
+                    "attributes": COMPRESSED_ATTRIBUTES,
+                },
+            }],
+        }],
+        "fields": fields.iter().map(Field::to_json).collect::<Vec<_>>(),
+        "attributeStorageInfo": attribute_storage_info,
+    })
+}
+
+/// An entry of the node pages. `mesh` is the number of the vertices and the features if the node has a mesh.
+pub fn node_page_entry(
+    index: usize,
+    node: &Node,
+    obb: &Obb,
+    mesh: Option<(usize, usize)>,
+) -> serde_json::Value {
+    let mut entry = serde_json::json!({
+        "index": index,
+        "lodThreshold": LOD_THRESHOLD,
+        "obb": {
+            "center": obb.center,
+            "halfSize": obb.half_size,
+            "quaternion": obb.quaternion,
+        },
+        "children": node.children,
+    });
+    if let Some(parent) = node.parent {
+        entry["parentIndex"] = parent.into();
+    }
+    if let Some((vertex_count, feature_count)) = mesh {
+        entry["mesh"] = serde_json::json!({
+            "material": { "definition": 0 },
+            "geometry": {
+                "definition": 0,
+                "resource": index,
+                "vertexCount": vertex_count,
+                "featureCount": feature_count,
+            },
+            "attribute": { "resource": index },
+        });
+    }
+    entry
+}
+
+/// A GUID-like identifier derived from the name (so that the same output is produced for the same input)
+fn guid(name: &str) -> String {
+    let hash = Sha256::digest(name.as_bytes());
+    let hex: String = hash[..16].iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed_geometry() {
+        let mut bounds = Bounds::default();
+        bounds.add_point([139.0, 35.0, 0.0]);
+        bounds.add_point([139.01, 35.01, 50.0]);
+        let layer = scene_layer("buildings", &[], &bounds);
+        let buffers = &layer["geometryDefinitions"][0]["geometryBuffers"];
+        assert_eq!(buffers.as_array().unwrap().len(), 2);
+        assert_eq!(buffers[0]["offset"], 8);
+        let compressed = &buffers[1]["compressedAttributes"];
+        assert_eq!(compressed["encoding"], "draco");
+        assert_eq!(
+            compressed["attributes"],
+            serde_json::json!(["position", "normal", "color", "feature-index"])
+        );
+
+        // (the geometry of the nodes is of the definition, whose buffers are `geometries/0` and `geometries/1`)
+        let node = Node {
+            features: vec![0],
+            children: Vec::new(),
+            parent: None,
+            bounds,
+        };
+        let obb = Obb::new(&bounds, &nusamai_projection::ellipsoid::wgs84());
+        let entry = node_page_entry(0, &node, &obb, Some((3, 1)));
+        assert_eq!(entry["mesh"]["geometry"]["definition"], 0);
+        assert_eq!(entry["mesh"]["geometry"]["resource"], 0);
+    }
+
+    #[test]
+    fn test_guid() {
+        let id = guid("buildings");
+        assert_eq!(id.len(), 36);
+        assert_eq!(id.matches('-').count(), 4);
+        assert_eq!(id, guid("buildings"));
+    }
+}
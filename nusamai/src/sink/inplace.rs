@@ -0,0 +1,125 @@
+//! In-place transformations of the flatgeom geometries
+//!
+//! `transform_inplace()` of flatgeom calls a closure for each coordinate. These extensions pair the coordinates
+//! with precomputed values (e.g. the UVs placed in a texture atlas) or process them in chunks in parallel,
+//! without allocating temporary `Vec`s for each polygon.
+
+use flatgeom::{Coord, MultiPolygon, Polygon};
+use rayon::prelude::*;
+
+/// Number of the coordinates processed at once by [`TransformInplaceExt::transform_chunks_inplace`]
+const CHUNK_SIZE: usize = 4096;
+
+pub trait TransformInplaceExt<T: Coord + Copy> {
+    /// All the coordinates in order (the same order as `transform_inplace()` visits them)
+    fn coords(&self) -> &[T];
+
+    /// Replaces each coordinate with `f(coord)`
+    fn map_inplace(&mut self, f: impl FnMut(&T) -> T);
+
+    /// Replaces the coordinates in order with `f(coord, item)`, pairing them with the items of `iter`.
+    ///
+    /// The coordinates after the end of `iter` are left as they are.
+    fn zip_transform_inplace<I: IntoIterator>(
+        &mut self,
+        iter: I,
+        mut f: impl FnMut(&T, I::Item) -> T,
+    ) {
+        let mut iter = iter.into_iter();
+        self.map_inplace(|c| match iter.next() {
+            Some(item) => f(c, item),
+            None => *c,
+        });
+    }
+
+    /// Transforms the coordinates in chunks, in parallel if there are many of them.
+    ///
+    /// `scratch` is a buffer reused across the calls (e.g. for all the features of a tile).
+    fn transform_chunks_inplace(&mut self, scratch: &mut Vec<T>, f: impl Fn(&mut [T]) + Sync)
+    where
+        T: Send + Sync,
+    {
+        scratch.clear();
+        scratch.extend_from_slice(self.coords());
+        if scratch.len() <= CHUNK_SIZE {
+            f(scratch);
+        } else {
+            scratch.par_chunks_mut(CHUNK_SIZE).for_each(&f);
+        }
+        self.zip_transform_inplace(scratch.iter(), |_, c| *c);
+    }
+}
+
+impl<T: Coord + Copy> TransformInplaceExt<T> for MultiPolygon<'_, T> {
+    fn coords(&self) -> &[T] {
+        self.raw_coords()
+    }
+
+    fn map_inplace(&mut self, f: impl FnMut(&T) -> T) {
+        self.transform_inplace(f);
+    }
+}
+
+impl<T: Coord + Copy> TransformInplaceExt<T> for Polygon<'_, T> {
+    fn coords(&self) -> &[T] {
+        self.raw_coords()
+    }
+
+    fn map_inplace(&mut self, f: impl FnMut(&T) -> T) {
+        self.transform_inplace(f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zip_transform_inplace() {
+        let mut mpoly = MultiPolygon::<[f64; 5]>::new();
+        mpoly.add_exterior([
+            [0., 0., 0., 0., 0.],
+            [1., 0., 0., 0., 0.],
+            [1., 1., 0., 0., 0.],
+        ]);
+        mpoly.add_interior([
+            [0.2, 0.2, 0., 0., 0.],
+            [0.4, 0.2, 0., 0., 0.],
+            [0.4, 0.4, 0., 0., 0.],
+        ]);
+
+        let uvs = [(0.1, 0.2), (0.3, 0.4), (0.5, 0.6), (0.7, 0.8)];
+        let mut poly = mpoly.iter().next().unwrap();
+        poly.zip_transform_inplace(&uvs, |&[x, y, z, _, _], &(u, v)| [x, y, z, u, v]);
+
+        let coords = poly.raw_coords();
+        assert_eq!(coords[0], [0., 0., 0., 0.1, 0.2]);
+        assert_eq!(coords[3], [0.2, 0.2, 0., 0.7, 0.8]);
+        // left as they are after the end of the iterator
+        assert_eq!(coords[4], [0.4, 0.2, 0., 0., 0.]);
+    }
+
+    #[test]
+    fn test_transform_chunks_inplace() {
+        let mut mpoly = MultiPolygon::<[f64; 3]>::new();
+        for i in 0..(CHUNK_SIZE * 3) {
+            let x = i as f64;
+            mpoly.add_exterior([[x, 0., 0.], [x + 1., 0., 0.], [x + 1., 1., 0.]]);
+        }
+        let expected: Vec<[f64; 3]> = mpoly
+            .raw_coords()
+            .iter()
+            .map(|&[x, y, z]| [x * 2., y + 1., z - 1.])
+            .collect();
+
+        let mut scratch = Vec::new();
+        mpoly.transform_chunks_inplace(&mut scratch, |chunk| {
+            for c in chunk {
+                let [x, y, z] = *c;
+                *c = [x * 2., y + 1., z - 1.];
+            }
+        });
+        assert_eq!(mpoly.raw_coords(), &expected[..]);
+        assert_eq!(mpoly.len(), CHUNK_SIZE * 3);
+    }
+}
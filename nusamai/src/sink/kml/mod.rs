@@ -1,8 +1,17 @@
+//! KML sink
+//!
+//! Writes a KML document, or a KMZ archive if the output path ends with `.kmz`.
+//! For large cities, the features can be regionated into tiled documents loaded on demand (superoverlay).
+//! The placemarks refer to the shared `<Style>`s made from the styling profile (see the `style` module).
+
+mod region;
+
 use std::{
     collections::HashMap,
     fs::File,
     io::{BufWriter, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::mpsc,
 };
 
 use kml::{
@@ -17,16 +26,24 @@ use nusamai_citygml::{
 use nusamai_kml::conversion::indexed_multipolygon_to_kml;
 use nusamai_plateau::Entity;
 use rayon::prelude::*;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
 
 use crate::{
     get_parameter_value,
     parameters::*,
     pipeline::{Feedback, PipelineError, Receiver, Result},
     sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
-    transformer::{use_lod_config, TransformerSettings},
+    transformer::{
+        name_columns_config, prefix_config, split_bridge_and_tunnel_elements_config,
+        surface_class_config, underground_config, use_lod_config, TransformerSettings,
+    },
 };
 
-use super::option::output_parameter;
+use super::{
+    mvt::{feature_sorting_stage, tileid::TileIdMethod},
+    option::{output_parameter, style_parameter},
+    style::{Color, StyleProfile},
+};
 
 pub struct KmlSinkProvider {}
 
@@ -41,12 +58,52 @@ impl DataSinkProvider for KmlSinkProvider {
     fn sink_options(&self) -> Parameters {
         let mut params = Parameters::new();
         params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "extrude".into(),
+            entry: ParameterEntry {
+                description: "Output only the upward-facing surfaces, extruded to the ground"
+                    .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("上向きの面のみを地面まで押し出して出力する（軽量）".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "regionate".into(),
+            entry: ParameterEntry {
+                description:
+                    "Split the features into tiled documents loaded on demand (Region/NetworkLink)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("タイルに分割し、表示範囲に応じて読み込む".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "region_zoom".into(),
+            entry: ParameterEntry {
+                description: "Zoom level of the tiles for the regionation".into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: Some(15),
+                    min: Some(8),
+                    max: Some(18),
+                }),
+                label: Some("分割するタイルのズームレベル".into()),
+            },
+        });
+        params.define(style_parameter());
         params
     }
 
     fn transformer_options(&self) -> TransformerSettings {
         let mut settings: TransformerSettings = TransformerSettings::new();
         settings.insert(use_lod_config("max_lod", None));
+        settings.insert(underground_config());
+        settings.insert(split_bridge_and_tunnel_elements_config());
+        settings.insert(surface_class_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
 
         settings
     }
@@ -54,10 +111,17 @@ impl DataSinkProvider for KmlSinkProvider {
     fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
         let output_path = get_parameter_value!(params, "@output", FileSystemPath);
         let transform_settings = self.transformer_options();
+        let extrude = get_parameter_value!(params, "extrude", Boolean).unwrap();
+        let regionate = get_parameter_value!(params, "regionate", Boolean).unwrap();
+        let region_zoom = get_parameter_value!(params, "region_zoom", Integer).unwrap() as u8;
+        let style_path = get_parameter_value!(params, "style", FileSystemPath);
 
         Box::<KmlSink>::new(KmlSink {
             output_path: output_path.as_ref().unwrap().into(),
             transform_settings,
+            extrude,
+            region_zoom: regionate.then_some(region_zoom),
+            style_path: style_path.clone(),
         })
     }
 }
@@ -65,6 +129,12 @@ impl DataSinkProvider for KmlSinkProvider {
 pub struct KmlSink {
     output_path: PathBuf,
     transform_settings: TransformerSettings,
+    /// Output the upward-facing surfaces extruded to the ground instead of the original surfaces
+    extrude: bool,
+    /// Zoom level of the tiles if the output is regionated
+    region_zoom: Option<u8>,
+    /// Styling profile (the built-in one if not specified)
+    style_path: Option<PathBuf>,
 }
 
 impl DataSink for KmlSink {
@@ -79,7 +149,24 @@ impl DataSink for KmlSink {
     }
 
     fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
+        let profile = StyleProfile::load(self.style_path.as_deref())?;
+        match self.region_zoom {
+            None => self.run_single(upstream, feedback, &profile),
+            Some(zoom) => self.run_regionated(upstream, feedback, zoom, &profile),
+        }
+    }
+}
+
+impl KmlSink {
+    /// Writes all the features into a single document
+    fn run_single(
+        &self,
+        upstream: Receiver,
+        feedback: &Feedback,
+        profile: &StyleProfile,
+    ) -> Result<()> {
         let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+        let extrude = self.extrude;
 
         let (ra, rb) = rayon::join(
             || {
@@ -90,49 +177,7 @@ impl DataSink for KmlSink {
                     .try_for_each_with(sender, |sender, parcel| {
                         feedback.ensure_not_canceled()?;
 
-                        let polygons = entity_to_kml_polygons(&parcel.entity);
-
-                        let simple_data_items =
-                            property_to_schema_data_entries(&parcel.entity.root);
-
-                        let schema_data = Element {
-                            name: "SchemaData".to_string(),
-                            attrs: {
-                                let mut attrs = HashMap::new();
-                                attrs.insert("schemaUrl".to_string(), "#Schema_1".to_string());
-                                attrs
-                            },
-                            content: None,
-                            children: simple_data_items
-                                .into_iter()
-                                .map(|simple_data| Element {
-                                    name: "SimpleData".to_string(),
-                                    attrs: simple_data.attrs,
-                                    content: Some(simple_data.value),
-                                    children: Vec::new(),
-                                })
-                                .collect::<Vec<_>>(),
-                        };
-
-                        let extended_data_entry = Element {
-                            name: "ExtendedData".to_string(),
-                            attrs: HashMap::new(),
-                            content: None,
-                            children: vec![schema_data],
-                        };
-
-                        let geoms = polygons.into_iter().map(Geometry::Polygon).collect();
-                        let multi_geom = MultiGeometry {
-                            geometries: geoms,
-                            ..Default::default()
-                        };
-
-                        let placemark = Placemark {
-                            geometry: Some(Geometry::MultiGeometry(multi_geom)),
-                            children: vec![extended_data_entry],
-                            ..Default::default()
-                        };
-
+                        let placemark = entity_to_placemark(&parcel.entity, extrude, profile);
                         if sender.send(placemark).is_err() {
                             return Err(PipelineError::Canceled);
                         }
@@ -140,77 +185,22 @@ impl DataSink for KmlSink {
                     })
             },
             || {
-                // TODO?:QGIS attribute
-                let schema_element = Element {
-                    name: "Schema".to_string(),
-                    attrs: {
-                        let mut attrs = HashMap::new();
-                        attrs.insert("name".to_string(), "Schema_1".to_string());
-                        attrs.insert("id".to_string(), "Schema_1".to_string());
-                        attrs
-                    },
-                    content: None,
-                    children: Vec::new(),
-                    // children: {
-                    //     let mut children = Vec::new();
-                    //     children.push(Element {
-                    //         name: "SimpleField".to_string(),
-                    //         attrs: {
-                    //             let mut attrs = HashMap::new();
-                    //             attrs.insert("name".to_string(), "id".to_string());
-                    //             attrs.insert("type".to_string(), "string".to_string());
-                    //             attrs
-                    //         },
-                    //         content: None,
-                    //         children: Vec::new(),
-                    //     });
-                    //     children.push(Element {
-                    //         name: "SimpleField".to_string(),
-                    //         attrs: {
-                    //             let mut attrs = HashMap::new();
-                    //             attrs.insert("name".to_string(), "type".to_string());
-                    //             attrs.insert("type".to_string(), "string".to_string());
-                    //             attrs
-                    //         },
-                    //         content: None,
-                    //         children: Vec::new(),
-                    //     });
-                    //     children
-                    // },
-                };
-
-                let mut file = File::create(&self.output_path)?;
-                let mut buf_writer = BufWriter::with_capacity(1024 * 1024, &mut file);
-
-                writeln!(buf_writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
-                writeln!(
-                    buf_writer,
-                    r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#
-                )?;
-                writeln!(buf_writer, r#"<Document>"#)?;
-
-                let mut kml_writer = KmlWriter::from_writer(&mut buf_writer);
-
-                kml_writer
-                    .write(&Kml::<f64>::Element(schema_element))
-                    .map_err(|err| match err {
-                        kml::Error::IoError(err) => PipelineError::IoError(err),
-                        err => PipelineError::Other(err.to_string()),
-                    })?;
-
-                for placemark in receiver {
-                    kml_writer
-                        .write(&Kml::<f64>::Placemark(placemark))
-                        .map_err(|err| match err {
-                            kml::Error::IoError(err) => PipelineError::IoError(err),
-                            err => PipelineError::Other(err.to_string()),
-                        })?;
-                }
-
-                writeln!(buf_writer, "</Document>")?;
-                writeln!(buf_writer, "</kml>")?;
+                let (mut container, root_name) = KmlContainer::create(&self.output_path)?;
+                container.write_document(&root_name, |writer| {
+                    write_document_header(writer)?;
+                    write_styles(writer, profile)?;
+
+                    {
+                        let mut kml_writer = KmlWriter::from_writer(&mut *writer);
+                        write_kml(&mut kml_writer, &Kml::<f64>::Element(schema_element()))?;
+                        for placemark in receiver {
+                            write_kml(&mut kml_writer, &Kml::<f64>::Placemark(placemark))?;
+                        }
+                    }
 
-                Ok(())
+                    write_document_footer(writer)
+                })?;
+                container.finish()
             },
         );
 
@@ -225,6 +215,323 @@ impl DataSink for KmlSink {
 
         Ok(())
     }
+
+    /// Groups the features into tiles, and writes a document for each tile and the root document linking them
+    fn run_regionated(
+        &self,
+        upstream: Receiver,
+        feedback: &Feedback,
+        zoom: u8,
+        profile: &StyleProfile,
+    ) -> Result<()> {
+        let (sender_tiled, receiver_tiled) = mpsc::sync_channel(2000);
+        let (sender_sorted, receiver_sorted) = mpsc::sync_channel(2000);
+        let tile_id_conv = TileIdMethod::Hilbert;
+        let extrude = self.extrude;
+        let output_path = &self.output_path;
+
+        std::thread::scope(|s| {
+            // Assign the features to the tiles by their centroids
+            s.spawn(move || {
+                let result = upstream.into_iter().par_bridge().try_for_each_with(
+                    sender_tiled,
+                    |sender, parcel| {
+                        feedback.ensure_not_canceled()?;
+
+                        let Some([lng, lat]) = entity_centroid(&parcel.entity) else {
+                            return Ok(());
+                        };
+                        let (z, x, y) = region::tile_of_point(lng, lat, zoom);
+
+                        let placemark = entity_to_placemark(&parcel.entity, extrude, profile);
+                        let mut bytes = Vec::new();
+                        write_kml(
+                            &mut KmlWriter::from_writer(&mut bytes),
+                            &Kml::<f64>::Placemark(placemark),
+                        )?;
+
+                        if sender
+                            .send((tile_id_conv.zxy_to_id(z, x, y), bytes))
+                            .is_err()
+                        {
+                            return Err(PipelineError::Canceled);
+                        }
+                        Ok(())
+                    },
+                );
+                if let Err(error) = result {
+                    feedback.fatal_error(error);
+                }
+            });
+
+            // Sort features by tile_id (using external sorter)
+            s.spawn(move || {
+                if let Err(error) = feature_sorting_stage(feedback, receiver_tiled, sender_sorted) {
+                    feedback.fatal_error(error);
+                }
+            });
+
+            // Write the documents
+            s.spawn(move || {
+                if let Err(error) = Self::write_regionated(
+                    output_path,
+                    feedback,
+                    receiver_sorted,
+                    tile_id_conv,
+                    profile,
+                ) {
+                    feedback.fatal_error(error);
+                }
+            });
+        });
+
+        Ok(())
+    }
+
+    fn write_regionated(
+        output_path: &Path,
+        feedback: &Feedback,
+        receiver_sorted: mpsc::Receiver<(u64, Vec<Vec<u8>>)>,
+        tile_id_conv: TileIdMethod,
+        profile: &StyleProfile,
+    ) -> Result<()> {
+        let (mut container, root_name) = KmlContainer::create(output_path)?;
+        let tiles_dir = match container {
+            KmlContainer::Kmz(_) => "tiles".to_string(),
+            KmlContainer::Files(_) => Path::new(&root_name)
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        };
+
+        let mut tiles = Vec::new();
+        for (tile_id, placemarks) in receiver_sorted {
+            feedback.ensure_not_canceled()?;
+
+            let zxy = tile_id_conv.id_to_zxy(tile_id);
+            let path = region::tile_document_path(&tiles_dir, zxy);
+            feedback.info(format!("Writing a tile: {path}"));
+            container.write_document(&path, |writer| {
+                write_document_header(writer)?;
+                region::write_region(writer, zxy)?;
+                write_styles(writer, profile)?;
+                write_kml(
+                    &mut KmlWriter::from_writer(&mut *writer),
+                    &Kml::<f64>::Element(schema_element()),
+                )?;
+                for placemark in &placemarks {
+                    writer.write_all(placemark)?;
+                }
+                write_document_footer(writer)
+            })?;
+            tiles.push((zxy, path));
+        }
+
+        feedback.ensure_not_canceled()?;
+
+        container.write_document(&root_name, |writer| {
+            write_document_header(writer)?;
+            for (zxy, path) in &tiles {
+                region::write_network_link(writer, *zxy, path)?;
+            }
+            write_document_footer(writer)
+        })?;
+        container.finish()
+    }
+}
+
+/// Destination of the KML documents
+enum KmlContainer {
+    /// KMZ (zip) archive
+    Kmz(ZipWriter<BufWriter<File>>),
+    /// Plain files (the base directory)
+    Files(PathBuf),
+}
+
+impl KmlContainer {
+    /// Creates the container for the output path, and returns it with the name of the root document
+    fn create(output_path: &Path) -> Result<(Self, String)> {
+        let is_kmz = output_path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("kmz"));
+        if is_kmz {
+            let file = File::create(output_path)?;
+            let zip = ZipWriter::new(BufWriter::with_capacity(1024 * 1024, file));
+            Ok((Self::Kmz(zip), "doc.kml".to_string()))
+        } else {
+            let dir = output_path.parent().unwrap_or(Path::new("")).to_path_buf();
+            let name = output_path
+                .file_name()
+                .ok_or_else(|| PipelineError::Other("Invalid output path".to_string()))?
+                .to_string_lossy()
+                .to_string();
+            Ok((Self::Files(dir), name))
+        }
+    }
+
+    /// Writes a document at the path relative to the root document
+    fn write_document(
+        &mut self,
+        path: &str,
+        write: impl FnOnce(&mut dyn Write) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            Self::Kmz(zip) => {
+                let options =
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+                zip.start_file(path, options).map_err(map_zip_error)?;
+                write(zip)
+            }
+            Self::Files(dir) => {
+                let path = dir.join(path);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut file = File::create(path)?;
+                let mut buf_writer = BufWriter::with_capacity(1024 * 1024, &mut file);
+                write(&mut buf_writer)?;
+                buf_writer.flush()?;
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Self::Kmz(zip) = self {
+            zip.finish().map_err(map_zip_error)?.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn map_zip_error(err: zip::result::ZipError) -> PipelineError {
+    match err {
+        zip::result::ZipError::Io(err) => PipelineError::IoError(err),
+        err => PipelineError::Other(err.to_string()),
+    }
+}
+
+fn write_kml<W: Write>(kml_writer: &mut KmlWriter<W>, kml: &Kml<f64>) -> Result<()> {
+    kml_writer.write(kml).map_err(|err| match err {
+        kml::Error::IoError(err) => PipelineError::IoError(err),
+        err => PipelineError::Other(err.to_string()),
+    })
+}
+
+fn write_document_header(writer: &mut dyn Write) -> Result<()> {
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(writer, r#"<Document>"#)?;
+    Ok(())
+}
+
+fn write_document_footer(writer: &mut dyn Write) -> Result<()> {
+    writeln!(writer, "</Document>")?;
+    writeln!(writer, "</kml>")?;
+    Ok(())
+}
+
+/// Writes the styles referred by the placemarks (`styleUrl`)
+fn write_styles(writer: &mut dyn Write, profile: &StyleProfile) -> Result<()> {
+    for (id, style) in profile.styles() {
+        writeln!(
+            writer,
+            "<Style id=\"{id}\"><LineStyle><color>{}</color><width>{}</width></LineStyle>\
+             <PolyStyle><color>{}</color></PolyStyle></Style>",
+            kml_color(&style.stroke),
+            style.width,
+            kml_color(&style.fill),
+        )?;
+    }
+    Ok(())
+}
+
+/// KML colors are in `aabbggrr`
+fn kml_color(color: &Color) -> String {
+    format!(
+        "{:02x}{:02x}{:02x}{:02x}",
+        color.a, color.b, color.g, color.r
+    )
+}
+
+fn schema_element() -> Element {
+    // TODO?:QGIS attribute
+    Element {
+        name: "Schema".to_string(),
+        attrs: {
+            let mut attrs = HashMap::new();
+            attrs.insert("name".to_string(), "Schema_1".to_string());
+            attrs.insert("id".to_string(), "Schema_1".to_string());
+            attrs
+        },
+        content: None,
+        children: Vec::new(),
+    }
+}
+
+pub fn entity_to_placemark(entity: &Entity, extrude: bool, profile: &StyleProfile) -> Placemark {
+    let polygons = entity_to_kml_polygons(entity, extrude);
+
+    let simple_data_items = property_to_schema_data_entries(&entity.root);
+
+    let schema_data = Element {
+        name: "SchemaData".to_string(),
+        attrs: {
+            let mut attrs = HashMap::new();
+            attrs.insert("schemaUrl".to_string(), "#Schema_1".to_string());
+            attrs
+        },
+        content: None,
+        children: simple_data_items
+            .into_iter()
+            .map(|simple_data| Element {
+                name: "SimpleData".to_string(),
+                attrs: simple_data.attrs,
+                content: Some(simple_data.value),
+                children: Vec::new(),
+            })
+            .collect::<Vec<_>>(),
+    };
+
+    let extended_data_entry = Element {
+        name: "ExtendedData".to_string(),
+        attrs: HashMap::new(),
+        content: None,
+        children: vec![schema_data],
+    };
+
+    let geoms = polygons.into_iter().map(Geometry::Polygon).collect();
+    let multi_geom = MultiGeometry {
+        geometries: geoms,
+        ..Default::default()
+    };
+
+    let style_url = match &entity.root {
+        Value::Object(obj) => Some(format!("#{}", profile.style_id(obj))),
+        _ => None,
+    };
+
+    Placemark {
+        geometry: Some(Geometry::MultiGeometry(multi_geom)),
+        style_url,
+        children: vec![extended_data_entry],
+        ..Default::default()
+    }
+}
+
+/// Centroid (the average of the vertices) of the entity in [lng, lat]
+fn entity_centroid(entity: &Entity) -> Option<[f64; 2]> {
+    let geom_store = entity.geometry_store.read().unwrap();
+    if geom_store.vertices.is_empty() {
+        return None;
+    }
+    let n = geom_store.vertices.len() as f64;
+    let [lng, lat] = geom_store
+        .vertices
+        .iter()
+        .fold([0.0, 0.0], |[lng, lat], v| [lng + v[0], lat + v[1]]);
+    Some([lng / n, lat / n])
 }
 
 pub fn property_to_schema_data_entries(root: &Value) -> Vec<SimpleData> {
@@ -261,7 +568,11 @@ pub fn property_to_schema_data_entries(root: &Value) -> Vec<SimpleData> {
     simple_data_entries
 }
 
-pub fn entity_to_kml_polygons(entity: &Entity) -> Vec<KmlPolygon> {
+/// Converts the surfaces of the entity into KML polygons.
+///
+/// If `extrude` is true, only the upward-facing surfaces (e.g. roofs) are kept and extruded to the ground,
+/// which is much lighter than the original surfaces and looks similar for buildings.
+pub fn entity_to_kml_polygons(entity: &Entity, extrude: bool) -> Vec<KmlPolygon> {
     let geom_store = entity.geometry_store.read().unwrap();
 
     let Value::Object(obj) = &entity.root else {
@@ -279,6 +590,9 @@ pub fn entity_to_kml_polygons(entity: &Entity) -> Vec<KmlPolygon> {
                 .multipolygon
                 .iter_range(entry.pos as usize..(entry.pos + entry.len) as usize)
             {
+                if extrude && !is_upward(&geom_store.vertices, idx_poly.exterior().iter()) {
+                    continue;
+                }
                 mpoly.push(&idx_poly);
             }
         }
@@ -286,5 +600,24 @@ pub fn entity_to_kml_polygons(entity: &Entity) -> Vec<KmlPolygon> {
         GeometryType::Point => unimplemented!(),
     });
 
-    indexed_multipolygon_to_kml(&geom_store.vertices, &mpoly)
+    let mut polygons = indexed_multipolygon_to_kml(&geom_store.vertices, &mpoly);
+    if extrude {
+        for polygon in polygons.iter_mut() {
+            polygon.extrude = true;
+        }
+    }
+    polygons
+}
+
+/// Whether the ring is counter-clockwise when seen from above (i.e. the surface faces upward)
+fn is_upward(vertices: &[[f64; 3]], ring: impl Iterator<Item = u32>) -> bool {
+    let ring: Vec<[f64; 3]> = ring.map(|idx| vertices[idx as usize]).collect();
+    let signed_area: f64 = (0..ring.len())
+        .map(|i| {
+            let [x0, y0, _] = ring[i];
+            let [x1, y1, _] = ring[(i + 1) % ring.len()];
+            x0 * y1 - x1 * y0
+        })
+        .sum();
+    signed_area > 0.0
 }
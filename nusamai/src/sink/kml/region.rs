@@ -0,0 +1,92 @@
+//! Regionation (superoverlay) of the KML output
+//!
+//! The features are grouped into Web Mercator tiles by their centroids, and each tile is written as a separate
+//! document. The root document loads them with `<NetworkLink>`s only when their `<Region>`s are in view,
+//! so that Google Earth can open the data of a whole city.
+
+use std::{f64::consts::PI, io::Write};
+
+use tinymvt::TileZXY;
+
+/// Minimum size (in pixels) of a region on the screen to load its document
+const MIN_LOD_PIXELS: u32 = 128;
+
+/// Bounds of a tile (west, south, east, north) in degrees
+pub fn tile_bounds((z, x, y): TileZXY) -> [f64; 4] {
+    let n = (1u64 << z) as f64;
+    let lng = |x: f64| x / n * 360.0 - 180.0;
+    let lat = |y: f64| (PI * (1.0 - 2.0 * y / n)).sinh().atan().to_degrees();
+    [
+        lng(x as f64),
+        lat(y as f64 + 1.0),
+        lng(x as f64 + 1.0),
+        lat(y as f64),
+    ]
+}
+
+/// The tile that contains the point at the zoom level
+pub fn tile_of_point(lng: f64, lat: f64, z: u8) -> TileZXY {
+    let n = 1u64 << z;
+    let lat = lat.clamp(-85.051_128_78, 85.051_128_78).to_radians();
+    let x = ((lng + 180.0) / 360.0 * n as f64).floor();
+    let y = ((1.0 - lat.tan().asinh() / PI) / 2.0 * n as f64).floor();
+    (
+        z,
+        (x.max(0.0) as u64).min(n - 1) as u32,
+        (y.max(0.0) as u64).min(n - 1) as u32,
+    )
+}
+
+/// Relative path of the document of a tile (from the root document)
+pub fn tile_document_path(tiles_dir: &str, (z, x, y): TileZXY) -> String {
+    format!("{tiles_dir}/{z}/{x}/{y}.kml")
+}
+
+/// Writes the `<Region>` element of a tile
+pub fn write_region<W: Write + ?Sized>(writer: &mut W, zxy: TileZXY) -> std::io::Result<()> {
+    let [west, south, east, north] = tile_bounds(zxy);
+    writeln!(
+        writer,
+        "<Region><LatLonAltBox><north>{north}</north><south>{south}</south><east>{east}</east>\
+         <west>{west}</west></LatLonAltBox><Lod><minLodPixels>{MIN_LOD_PIXELS}</minLodPixels>\
+         <maxLodPixels>-1</maxLodPixels></Lod></Region>"
+    )
+}
+
+/// Writes the `<NetworkLink>` element that loads the document of a tile
+pub fn write_network_link<W: Write + ?Sized>(
+    writer: &mut W,
+    zxy: TileZXY,
+    href: &str,
+) -> std::io::Result<()> {
+    let (z, x, y) = zxy;
+    writeln!(writer, "<NetworkLink><name>{z}/{x}/{y}</name>")?;
+    write_region(writer, zxy)?;
+    writeln!(
+        writer,
+        "<Link><href>{href}</href><viewRefreshMode>onRegion</viewRefreshMode></Link></NetworkLink>"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles() {
+        let [west, south, east, north] = tile_bounds((0, 0, 0));
+        assert_eq!((west, east), (-180.0, 180.0));
+        assert!((north - 85.0511).abs() < 1e-4);
+        assert!((south + 85.0511).abs() < 1e-4);
+
+        // Tokyo Station
+        let zxy = tile_of_point(139.7671, 35.6812, 15);
+        assert_eq!(zxy, (15, 29105, 12903));
+        let [west, south, east, north] = tile_bounds(zxy);
+        assert!((west..east).contains(&139.7671));
+        assert!((south..north).contains(&35.6812));
+
+        assert_eq!(tile_of_point(180.0, -90.0, 1), (1, 1, 1));
+        assert_eq!(tile_document_path("tiles", zxy), "tiles/15/29105/12903.kml");
+    }
+}
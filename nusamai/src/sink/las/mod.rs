@@ -0,0 +1,377 @@
+//! LAS / LAZ point cloud sink
+//!
+//! Samples the surfaces of the features (buildings, terrain, roads, etc.) into a point cloud at the given density,
+//! for the simulation tools that consume point clouds rather than meshes. The type of the feature is written as
+//! the ASPRS classification of the points. The output is compressed (LAZ) if the file name ends with `.laz`.
+
+mod writer;
+
+use std::path::PathBuf;
+
+use earcut::{utils3d::project3d_to_2d, Earcut};
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+    GeometryType,
+};
+use nusamai_plateau::Entity;
+use nusamai_projection::crs::EPSG_WGS84_GEOGRAPHIC_3D;
+use rayon::prelude::*;
+use writer::{is_geographic, LasWriter};
+
+use super::option::output_parameter;
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer,
+    transformer::{underground_config, use_lod_config, TransformerSettings},
+};
+
+const DEFAULT_DENSITY: i64 = 4;
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// ASPRS standard point classes
+mod class {
+    pub const UNCLASSIFIED: u8 = 1;
+    pub const GROUND: u8 = 2;
+    pub const HIGH_VEGETATION: u8 = 5;
+    pub const BUILDING: u8 = 6;
+    pub const WATER: u8 = 9;
+    pub const RAIL: u8 = 10;
+    pub const ROAD_SURFACE: u8 = 11;
+    pub const BRIDGE_DECK: u8 = 17;
+}
+
+pub struct LasSinkProvider {}
+
+impl DataSinkProvider for LasSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "las".to_string(),
+            name: "LAS / LAZ (Point Cloud)".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "density".into(),
+            entry: ParameterEntry {
+                description: "Number of the points per square meter of the surfaces".into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: Some(DEFAULT_DENSITY),
+                    min: Some(1),
+                    max: Some(400),
+                }),
+                label: Some("点密度（点/m²）".into()),
+            },
+        });
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(use_lod_config("max_lod", None));
+        settings.insert(underground_config());
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let density = get_parameter_value!(params, "density", Integer).unwrap();
+        let transform_settings = self.transformer_options();
+
+        Box::<LasSink>::new(LasSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            density: density as f64,
+            transform_settings,
+        })
+    }
+}
+
+pub struct LasSink {
+    output_path: PathBuf,
+    /// Points per square meter
+    density: f64,
+    transform_settings: TransformerSettings,
+}
+
+impl DataSink for LasSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        let default_requirements = DataRequirements {
+            key_value: transformer::KeyValueSpec::None,
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        let epsg = schema.epsg.unwrap_or(EPSG_WGS84_GEOGRAPHIC_3D);
+        let geographic = is_geographic(epsg);
+        let density = self.density;
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+
+        let (ra, rb) = rayon::join(
+            || {
+                upstream
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(sender, |sender, parcel| {
+                        feedback.ensure_not_canceled()?;
+                        let points = sample_entity(&parcel.entity, density, geographic);
+                        if !points.is_empty() && sender.send(points).is_err() {
+                            return Err(PipelineError::Canceled);
+                        }
+                        Ok(())
+                    })
+            },
+            || {
+                let mut writer = LasWriter::create(&self.output_path, epsg)?;
+                for (i, points) in receiver.into_iter().enumerate() {
+                    if i % 1000 == 0 {
+                        feedback.ensure_not_canceled()?;
+                    }
+                    for (point, classification) in points {
+                        writer.write_point(point, classification)?;
+                    }
+                }
+                feedback.info(format!("Writing {} points", writer.count()));
+                writer.finish()
+            },
+        );
+
+        match ra {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => return Err(error),
+        }
+        match rb {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => return Err(error),
+        }
+        Ok(())
+    }
+}
+
+/// ASPRS classification of the feature type
+fn classify(typename: &str) -> u8 {
+    match typename {
+        "dem:ReliefFeature" => class::GROUND,
+        "tran:Railway" => class::RAIL,
+        _ => match typename.split_once(':').map(|(prefix, _)| prefix) {
+            Some("bldg") => class::BUILDING,
+            Some("veg") => class::HIGH_VEGETATION,
+            Some("wtr") => class::WATER,
+            Some("rwy") => class::RAIL,
+            Some("tran") => class::ROAD_SURFACE,
+            Some("brid") => class::BRIDGE_DECK,
+            _ => class::UNCLASSIFIED,
+        },
+    }
+}
+
+/// Samples the points on the surfaces of the entity. The points are scattered randomly (but reproducibly)
+/// in the triangles so that their expected number is proportional to the area.
+fn sample_entity(entity: &Entity, density: f64, geographic: bool) -> Vec<([f64; 3], u8)> {
+    let Value::Object(obj) = &entity.root else {
+        return Vec::new();
+    };
+    let ObjectStereotype::Feature { id, geometries } = &obj.stereotype else {
+        return Vec::new();
+    };
+    let classification = classify(&obj.typename);
+    let mut rng = SplitMix64::from_id(id);
+
+    let geom_store = entity.geometry_store.read().unwrap();
+    let mut earcutter = Earcut::new();
+    let mut buf3d: Vec<[f64; 3]> = Vec::new();
+    let mut buf2d: Vec<[f64; 2]> = Vec::new();
+    let mut index_buf: Vec<u32> = Vec::new();
+    let mut points = Vec::new();
+
+    for entry in geometries {
+        if !matches!(
+            entry.ty,
+            GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+        ) {
+            continue;
+        }
+        for poly in geom_store
+            .multipolygon
+            .iter_range(entry.pos as usize..(entry.pos + entry.len) as usize)
+        {
+            let indices = poly.raw_coords();
+            let Some(&first) = indices.first() else {
+                continue;
+            };
+            // Local coordinates in meters
+            let origin = geom_store.vertices[first as usize];
+            buf3d.clear();
+            buf3d.extend(
+                indices
+                    .iter()
+                    .map(|&idx| to_meters(geom_store.vertices[idx as usize], origin, geographic)),
+            );
+            let num_outer = match poly.hole_indices().first() {
+                Some(&v) => v as usize,
+                None => indices.len(),
+            };
+            if !project3d_to_2d(&buf3d, num_outer, &mut buf2d) {
+                continue;
+            }
+            earcutter.earcut(buf2d.iter().cloned(), poly.hole_indices(), &mut index_buf);
+
+            for tri in index_buf.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| buf3d[tri[i] as usize]);
+                let expected = triangle_area(a, b, c) * density;
+                let mut count = expected.floor() as usize;
+                if rng.next_f64() < expected.fract() {
+                    count += 1;
+                }
+                // Interpolate in the original coordinates
+                let [a, b, c] =
+                    [0, 1, 2].map(|i| geom_store.vertices[indices[tri[i] as usize] as usize]);
+                for _ in 0..count {
+                    let (mut u, mut v) = (rng.next_f64(), rng.next_f64());
+                    if u + v > 1.0 {
+                        (u, v) = (1.0 - u, 1.0 - v);
+                    }
+                    let point = [0, 1, 2].map(|i| a[i] + u * (b[i] - a[i]) + v * (c[i] - a[i]));
+                    points.push((point, classification));
+                }
+            }
+        }
+    }
+    points
+}
+
+/// Converts the coordinates into the local coordinates in meters around the origin
+fn to_meters(v: [f64; 3], origin: [f64; 3], geographic: bool) -> [f64; 3] {
+    match geographic {
+        true => {
+            let scale_x = METERS_PER_DEGREE * origin[1].to_radians().cos();
+            [
+                (v[0] - origin[0]) * scale_x,
+                (v[1] - origin[1]) * METERS_PER_DEGREE,
+                v[2] - origin[2],
+            ]
+        }
+        false => [v[0] - origin[0], v[1] - origin[1], v[2] - origin[2]],
+    }
+}
+
+fn triangle_area(a: [f64; 3], b: [f64; 3], c: [f64; 3]) -> f64 {
+    let ab = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    let ac = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+    let cross = [
+        ab[1] * ac[2] - ab[2] * ac[1],
+        ab[2] * ac[0] - ab[0] * ac[2],
+        ab[0] * ac[1] - ab[1] * ac[0],
+    ];
+    0.5 * (cross[0] * cross[0] + cross[1] * cross[1] + cross[2] * cross[2]).sqrt()
+}
+
+/// Small deterministic random number generator (the same input gives the same points)
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    /// Seeded with the ID of the feature (FNV-1a)
+    fn from_id(id: &str) -> Self {
+        let seed = id.bytes().fold(0xcbf29ce484222325, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        Self(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use flatgeom::MultiPolygon;
+    use nusamai_citygml::{
+        geometry::{GeometryRef, GeometryStore},
+        object::{Map, Object},
+    };
+
+    use super::*;
+
+    /// A 10 m x 10 m square in a projected CRS
+    fn square_entity(typename: &str) -> Entity {
+        let mut mpoly = MultiPolygon::<u32>::new();
+        mpoly.add_exterior([0, 1, 2, 3, 0]);
+        Entity {
+            root: Value::Object(Object {
+                typename: typename.to_string().into(),
+                attributes: Map::default(),
+                stereotype: ObjectStereotype::Feature {
+                    id: "feature_1".into(),
+                    geometries: vec![GeometryRef {
+                        ty: GeometryType::Surface,
+                        lod: 1,
+                        pos: 0,
+                        len: 1,
+                    }],
+                },
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(GeometryStore {
+                vertices: vec![
+                    [0.0, 0.0, 5.0],
+                    [10.0, 0.0, 5.0],
+                    [10.0, 10.0, 5.0],
+                    [0.0, 10.0, 5.0],
+                ],
+                multipolygon: mpoly,
+                ..Default::default()
+            })
+            .into(),
+            appearance_store: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_sample_entity() {
+        let entity = square_entity("bldg:Building");
+        let points = sample_entity(&entity, 4.0, false);
+        assert_eq!(points.len(), 400);
+        for ([x, y, z], classification) in &points {
+            assert!((0.0..=10.0).contains(x) && (0.0..=10.0).contains(y));
+            assert_eq!(*z, 5.0);
+            assert_eq!(*classification, class::BUILDING);
+        }
+        // reproducible
+        assert_eq!(points, sample_entity(&entity, 4.0, false));
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("bldg:Building"), class::BUILDING);
+        assert_eq!(classify("dem:ReliefFeature"), class::GROUND);
+        assert_eq!(classify("tran:Road"), class::ROAD_SURFACE);
+        assert_eq!(classify("tran:Railway"), class::RAIL);
+        assert_eq!(classify("luse:LandUse"), class::UNCLASSIFIED);
+    }
+}
@@ -0,0 +1,366 @@
+//! LAS 1.2 writer (point data record format 0), optionally compressed into LAZ
+//!
+//! The points are streamed into the file, and the header (the number of the points and the extent)
+//! is rewritten when the writer is finished.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use chrono::Datelike;
+use laz::{LasZipCompressor, LazItemRecordBuilder, LazVlr};
+use nusamai_projection::crs::*;
+
+use crate::pipeline::{PipelineError, Result};
+
+const HEADER_SIZE: u16 = 227;
+const VLR_HEADER_SIZE: usize = 54;
+const POINT_FORMAT: u8 = 0;
+const POINT_RECORD_LENGTH: u16 = 20;
+/// Point data format ID with the compression bit (LAZ)
+const COMPRESSED_FLAG: u8 = 0x80;
+const GEOKEY_DIRECTORY_RECORD_ID: u16 = 34735;
+const JGD2011_VERTICAL_HEIGHT: u16 = 6695;
+
+/// Returns true if the path has the `.laz` extension
+pub fn is_laz_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("laz"))
+}
+
+enum PointWriter {
+    Las(BufWriter<File>),
+    Laz(Box<LasZipCompressor<'static, BufWriter<File>>>),
+}
+
+pub struct LasWriter {
+    points: PointWriter,
+    compressed: bool,
+    offset_to_point_data: u32,
+    scale: [f64; 3],
+    /// The offset is taken from the first point
+    offset: Option<[f64; 3]>,
+    min: [f64; 3],
+    max: [f64; 3],
+    count: u64,
+    buf: Vec<u8>,
+}
+
+impl LasWriter {
+    /// Creates the file. The points are in the CRS of `epsg` (the GeoTIFF keys are written for it).
+    pub fn create(path: &Path, epsg: EpsgCode) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let compressed = is_laz_path(path);
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        let mut vlrs = vec![vlr(
+            "LASF_Projection",
+            GEOKEY_DIRECTORY_RECORD_ID,
+            "GeoKeyDirectoryTag",
+            &geokey_directory(epsg),
+        )];
+        let laz_vlr = match compressed {
+            true => {
+                let items = LazItemRecordBuilder::default_for_point_format_id(POINT_FORMAT, 0)
+                    .map_err(map_laz_error)?;
+                let laz_vlr = LazVlr::from_laz_items(items);
+                let mut data = Vec::new();
+                laz_vlr.write_to(&mut data)?;
+                vlrs.push(vlr(
+                    LazVlr::USER_ID,
+                    LazVlr::RECORD_ID,
+                    LazVlr::DESCRIPTION,
+                    &data,
+                ));
+                Some(laz_vlr)
+            }
+            false => None,
+        };
+        let offset_to_point_data =
+            HEADER_SIZE as u32 + vlrs.iter().map(Vec::len).sum::<usize>() as u32;
+
+        // (the header is written when finished)
+        writer.write_all(&[0; HEADER_SIZE as usize])?;
+        for vlr in &vlrs {
+            writer.write_all(vlr)?;
+        }
+
+        let points = match laz_vlr {
+            Some(laz_vlr) => PointWriter::Laz(Box::new(
+                LasZipCompressor::new(writer, laz_vlr).map_err(map_laz_error)?,
+            )),
+            None => PointWriter::Las(writer),
+        };
+
+        let scale = match is_geographic(epsg) {
+            // about 1 cm at the latitude of Japan
+            true => [1e-7, 1e-7, 0.001],
+            false => [0.001; 3],
+        };
+
+        Ok(Self {
+            points,
+            compressed,
+            offset_to_point_data,
+            scale,
+            offset: None,
+            min: [f64::MAX; 3],
+            max: [f64::MIN; 3],
+            count: 0,
+            buf: Vec::with_capacity(POINT_RECORD_LENGTH as usize),
+        })
+    }
+
+    /// Writes a point with the ASPRS classification
+    pub fn write_point(&mut self, point: [f64; 3], classification: u8) -> Result<()> {
+        let offset = *self.offset.get_or_insert_with(|| point.map(|v| v.round()));
+        for ((min, max), v) in self.min.iter_mut().zip(self.max.iter_mut()).zip(point) {
+            *min = min.min(v);
+            *max = max.max(v);
+        }
+
+        let buf = &mut self.buf;
+        buf.clear();
+        for ((v, offset), scale) in point.iter().zip(offset).zip(self.scale) {
+            buf.write_i32::<LittleEndian>(((v - offset) / scale).round() as i32)?;
+        }
+        buf.write_u16::<LittleEndian>(0)?; // intensity
+        buf.write_u8(0b0000_1001)?; // return number 1 of 1
+        buf.write_u8(classification & 0x1f)?;
+        buf.write_i8(0)?; // scan angle rank
+        buf.write_u8(0)?; // user data
+        buf.write_u16::<LittleEndian>(0)?; // point source ID
+
+        match &mut self.points {
+            PointWriter::Las(writer) => writer.write_all(buf)?,
+            PointWriter::Laz(compressor) => compressor.compress_one(buf)?,
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Finishes the points and writes the header
+    pub fn finish(self) -> Result<()> {
+        let count = u32::try_from(self.count).map_err(|_| {
+            PipelineError::Other(format!(
+                "LAS: too many points ({}) for a LAS 1.2 file",
+                self.count
+            ))
+        })?;
+        let header = self.header(count)?;
+        let mut writer = match self.points {
+            PointWriter::Las(writer) => writer,
+            PointWriter::Laz(mut compressor) => {
+                compressor.done()?;
+                compressor.into_inner()
+            }
+        };
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_all(&header)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn header(&self, count: u32) -> std::io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE as usize);
+        buf.write_all(b"LASF")?;
+        buf.write_u16::<LittleEndian>(0)?; // file source ID
+        buf.write_u16::<LittleEndian>(0)?; // global encoding
+        buf.write_all(&[0; 16])?; // project ID (GUID)
+        buf.write_u8(1)?;
+        buf.write_u8(2)?;
+        buf.write_all(&fixed_str::<32>("PLATEAU GIS Converter"))?; // system identifier
+        buf.write_all(&fixed_str::<32>(&format!(
+            "nusamai {}",
+            env!("CARGO_PKG_VERSION")
+        )))?;
+        let today = chrono::Utc::now().date_naive();
+        buf.write_u16::<LittleEndian>(today.ordinal() as u16)?;
+        buf.write_u16::<LittleEndian>(today.year() as u16)?;
+        buf.write_u16::<LittleEndian>(HEADER_SIZE)?;
+        buf.write_u32::<LittleEndian>(self.offset_to_point_data)?;
+        buf.write_u32::<LittleEndian>(match self.compressed {
+            true => 2,
+            false => 1,
+        })?;
+        buf.write_u8(match self.compressed {
+            true => POINT_FORMAT | COMPRESSED_FLAG,
+            false => POINT_FORMAT,
+        })?;
+        buf.write_u16::<LittleEndian>(POINT_RECORD_LENGTH)?;
+        buf.write_u32::<LittleEndian>(count)?;
+        // number of the points by return (all the points are the first returns)
+        buf.write_u32::<LittleEndian>(count)?;
+        for _ in 0..4 {
+            buf.write_u32::<LittleEndian>(0)?;
+        }
+        for scale in self.scale {
+            buf.write_f64::<LittleEndian>(scale)?;
+        }
+        for offset in self.offset.unwrap_or_default() {
+            buf.write_f64::<LittleEndian>(offset)?;
+        }
+        let (min, max) = match self.count {
+            0 => ([0.0; 3], [0.0; 3]),
+            _ => (self.min, self.max),
+        };
+        for (max, min) in max.into_iter().zip(min) {
+            buf.write_f64::<LittleEndian>(max)?;
+            buf.write_f64::<LittleEndian>(min)?;
+        }
+        debug_assert_eq!(buf.len(), HEADER_SIZE as usize);
+        Ok(buf)
+    }
+}
+
+/// Whether the coordinates are in degrees
+pub fn is_geographic(epsg: EpsgCode) -> bool {
+    matches!(
+        epsg,
+        EPSG_WGS84_GEOGRAPHIC_2D
+            | EPSG_WGS84_GEOGRAPHIC_3D
+            | EPSG_JGD2011_GEOGRAPHIC_2D
+            | EPSG_JGD2011_GEOGRAPHIC_3D
+    )
+}
+
+/// The GeoTIFF keys of the CRS (GeoKeyDirectoryTag)
+fn geokey_directory(epsg: EpsgCode) -> Vec<u8> {
+    const MODEL_TYPE: u16 = 1024;
+    const RASTER_TYPE: u16 = 1025;
+    const GEOGRAPHIC_TYPE: u16 = 2048;
+    const PROJECTED_CS_TYPE: u16 = 3072;
+    const VERTICAL_CS_TYPE: u16 = 4096;
+    const PROJECTED: u16 = 1;
+    const GEOGRAPHIC: u16 = 2;
+
+    let mut keys: Vec<[u16; 2]> = Vec::new();
+    match epsg {
+        EPSG_WGS84_GEOGRAPHIC_2D | EPSG_WGS84_GEOGRAPHIC_3D => {
+            keys.push([MODEL_TYPE, GEOGRAPHIC]);
+            keys.push([GEOGRAPHIC_TYPE, EPSG_WGS84_GEOGRAPHIC_2D]);
+        }
+        EPSG_JGD2011_GEOGRAPHIC_2D | EPSG_JGD2011_GEOGRAPHIC_3D => {
+            keys.push([MODEL_TYPE, GEOGRAPHIC]);
+            keys.push([GEOGRAPHIC_TYPE, EPSG_JGD2011_GEOGRAPHIC_2D]);
+            if epsg == EPSG_JGD2011_GEOGRAPHIC_3D {
+                keys.push([VERTICAL_CS_TYPE, JGD2011_VERTICAL_HEIGHT]);
+            }
+        }
+        EPSG_JGD2011_JPRECT_I_JGD2011_HEIGHT..=EPSG_JGD2011_JPRECT_XIII_JGD2011_HEIGHT => {
+            // (compound CRS) the horizontal part and the vertical part
+            let horizontal = EPSG_JGD2011_JPRECT_I + (epsg - EPSG_JGD2011_JPRECT_I_JGD2011_HEIGHT);
+            keys.push([MODEL_TYPE, PROJECTED]);
+            keys.push([PROJECTED_CS_TYPE, horizontal]);
+            keys.push([VERTICAL_CS_TYPE, JGD2011_VERTICAL_HEIGHT]);
+        }
+        epsg => {
+            keys.push([MODEL_TYPE, PROJECTED]);
+            keys.push([PROJECTED_CS_TYPE, epsg]);
+        }
+    }
+    keys.insert(1, [RASTER_TYPE, 1]); // RasterPixelIsArea
+
+    // header: version, revision, minor revision, number of the keys
+    let mut values: Vec<u16> = vec![1, 1, 0, keys.len() as u16];
+    for [key, value] in keys {
+        // (the value is stored in the entry itself)
+        values.extend([key, 0, 1, value]);
+    }
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// A variable length record (header and data)
+fn vlr(user_id: &str, record_id: u16, description: &str, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(VLR_HEADER_SIZE + data.len());
+    buf.extend_from_slice(&[0; 2]); // reserved
+    buf.extend_from_slice(&fixed_str::<16>(user_id));
+    buf.extend_from_slice(&record_id.to_le_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&fixed_str::<32>(description));
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Null-padded fixed-length string
+fn fixed_str<const N: usize>(s: &str) -> [u8; N] {
+    let mut buf = [0; N];
+    let len = s.len().min(N);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    buf
+}
+
+fn map_laz_error(err: laz::LasZipError) -> PipelineError {
+    PipelineError::Other(format!("LAZ: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::*;
+
+    #[test]
+    fn test_write_las() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("points.las");
+        let mut writer = LasWriter::create(&path, EPSG_JGD2011_JPRECT_IX_JGD2011_HEIGHT).unwrap();
+        writer.write_point([-1000.25, 2000.5, 10.0], 6).unwrap();
+        writer.write_point([-999.75, 2001.5, 12.5], 2).unwrap();
+        writer.finish().unwrap();
+
+        let buf = std::fs::read(&path).unwrap();
+        assert_eq!(&buf[0..4], b"LASF");
+        assert_eq!(LittleEndian::read_u16(&buf[94..]), HEADER_SIZE);
+        let offset_to_point_data = LittleEndian::read_u32(&buf[96..]) as usize;
+        assert_eq!(LittleEndian::read_u32(&buf[100..]), 1); // number of the VLRs
+        assert_eq!(buf[104], POINT_FORMAT);
+        assert_eq!(LittleEndian::read_u32(&buf[107..]), 2); // number of the points
+        assert_eq!(LittleEndian::read_f64(&buf[179..]), -999.75); // max x
+        assert_eq!(LittleEndian::read_f64(&buf[187..]), -1000.25); // min x
+        assert_eq!(LittleEndian::read_f64(&buf[211..]), 12.5); // max z
+
+        // the projected CRS and the vertical CRS in the GeoTIFF keys
+        let geokeys = &buf[HEADER_SIZE as usize + VLR_HEADER_SIZE..offset_to_point_data];
+        let geokeys: Vec<u16> = geokeys.chunks(2).map(LittleEndian::read_u16).collect();
+        assert!(geokeys.chunks(4).any(|key| key == [3072, 0, 1, 6677]));
+        assert!(geokeys.chunks(4).any(|key| key == [4096, 0, 1, 6695]));
+
+        assert_eq!(buf.len(), offset_to_point_data + 2 * 20);
+        let second = &buf[offset_to_point_data + 20..];
+        let x_offset = LittleEndian::read_f64(&buf[155..]);
+        let x = LittleEndian::read_i32(second) as f64 * 0.001 + x_offset;
+        assert_eq!(x, -999.75);
+        assert_eq!(second[15], 2); // classification
+    }
+
+    #[test]
+    fn test_write_laz() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("points.laz");
+        let mut writer = LasWriter::create(&path, EPSG_WGS84_GEOGRAPHIC_3D).unwrap();
+        for i in 0..1000 {
+            let v = i as f64 * 1e-5;
+            writer.write_point([139.0 + v, 35.0 + v, v], 6).unwrap();
+        }
+        assert_eq!(writer.count(), 1000);
+        writer.finish().unwrap();
+
+        let buf = std::fs::read(&path).unwrap();
+        assert_eq!(buf[104], POINT_FORMAT | COMPRESSED_FLAG);
+        assert_eq!(LittleEndian::read_u32(&buf[100..]), 2);
+        assert_eq!(LittleEndian::read_u32(&buf[107..]), 1000);
+        // compressed
+        let offset_to_point_data = LittleEndian::read_u32(&buf[96..]) as usize;
+        assert!(buf.len() < offset_to_point_data + 1000 * 20);
+    }
+}
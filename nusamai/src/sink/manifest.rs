@@ -0,0 +1,203 @@
+//! Manifest of the output files
+//!
+//! The tiled sinks record the size and the SHA-256 hash of each file while writing it,
+//! so that CDNs and upload scripts can re-upload only the files changed by a re-conversion.
+//! For the other sinks writing into a directory (see [`DataSink::writes_manifest`](super::DataSink::writes_manifest)),
+//! the manifest is made by [`write_directory_manifest`] after the conversion, so that every delivered directory can be
+//! integrity-checked.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Filename of the manifest, written in the output directory
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Number of hex digits of the content hash embedded in the filenames
+const FILENAME_HASH_LEN: usize = 16;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Collects the files written by a sink (can be shared between the writer threads)
+#[derive(Default)]
+pub struct Manifest {
+    files: Mutex<BTreeMap<String, ManifestEntry>>,
+}
+
+#[derive(Serialize)]
+struct ManifestJson<'a> {
+    files: &'a BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Records a file (`path` is relative to the output directory, separated by `/`).
+    pub fn add(&self, path: &str, content: &[u8]) {
+        self.insert(path.to_string(), content.len() as u64, sha256_hex(content));
+    }
+
+    /// Records a file whose hash is already computed.
+    pub fn insert(&self, path: String, size: u64, sha256: String) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path, ManifestEntry { size, sha256 });
+    }
+
+    /// Writes the manifest into the output directory. The files are sorted by their paths.
+    pub fn write(&self, output_dir: &Path) -> std::io::Result<()> {
+        let files = self.files.lock().unwrap();
+        fs::create_dir_all(output_dir)?;
+        fs::write(
+            output_dir.join(MANIFEST_FILENAME),
+            serde_json::to_string_pretty(&ManifestJson { files: &files }).unwrap(),
+        )
+    }
+}
+
+/// Makes the manifest of all the files in the output directory (for the sinks not writing it by themselves).
+/// Does nothing if the output is a single file.
+///
+/// Returns `true` if the manifest is written.
+pub fn write_directory_manifest(output_dir: &Path) -> io::Result<bool> {
+    if !output_dir.is_dir() {
+        return Ok(false);
+    }
+
+    let mut files = Vec::new();
+    collect_files(output_dir, output_dir, &mut files)?;
+
+    let manifest = Manifest::new();
+    files.par_iter().try_for_each(|(relpath, path)| {
+        let (size, sha256) = hash_file(path)?;
+        manifest.insert(relpath.clone(), size, sha256);
+        Ok::<(), io::Error>(())
+    })?;
+    manifest.write(output_dir)?;
+    Ok(true)
+}
+
+/// Collects the files under `dir` with their paths relative to `root` (separated by `/`)
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(root, &path, files)?;
+            continue;
+        }
+        let relpath = path
+            .strip_prefix(root)
+            .unwrap()
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if relpath != MANIFEST_FILENAME {
+            files.push((relpath, path));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the size and the SHA-256 hash of a file, reading it in chunks
+fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+pub fn sha256_hex(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Inserts the (shortened) content hash before the extension of the filename.
+///
+/// e.g. `15/1/2_bldg_Building.glb` -> `15/1/2_bldg_Building.0123456789abcdef.glb`
+pub fn hashed_path(path: &str, hash: &str) -> String {
+    let hash = &hash[..hash.len().min(FILENAME_HASH_LEN)];
+    let filename_start = path.rfind('/').map_or(0, |pos| pos + 1);
+    match path[filename_start..].rfind('.') {
+        Some(pos) if pos > 0 => {
+            let (stem, ext) = path.split_at(filename_start + pos);
+            format!("{stem}.{hash}{ext}")
+        }
+        _ => format!("{path}.{hash}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashed_path() {
+        let hash = sha256_hex(b"");
+        assert_eq!(
+            hash,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hashed_path("15/1/2_bldg_Building.glb", &hash),
+            "15/1/2_bldg_Building.e3b0c44298fc1c14.glb"
+        );
+        assert_eq!(
+            hashed_path("lod1.0/2/tile", &hash),
+            "lod1.0/2/tile.e3b0c44298fc1c14"
+        );
+    }
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest::new();
+        manifest.add("1/0/0.pbf", b"b");
+        manifest.add("0/0/0.pbf", b"a");
+
+        let dir = tempfile::tempdir().unwrap();
+        manifest.write(dir.path()).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.path().join(MANIFEST_FILENAME)).unwrap()).unwrap();
+        let files = json["files"].as_object().unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["0/0/0.pbf", "1/0/0.pbf"]);
+        assert_eq!(files["0/0/0.pbf"]["size"], 1);
+        assert_eq!(files["0/0/0.pbf"]["sha256"], sha256_hex(b"a"));
+    }
+
+    #[test]
+    fn test_write_directory_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("bldg_Building.csv"), b"id\n").unwrap();
+        fs::write(dir.path().join("sub/a.txt"), b"abc").unwrap();
+        // a stale manifest of the previous conversion is replaced
+        fs::write(dir.path().join(MANIFEST_FILENAME), b"{}").unwrap();
+
+        assert!(write_directory_manifest(dir.path()).unwrap());
+        let json: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.path().join(MANIFEST_FILENAME)).unwrap()).unwrap();
+        let files = json["files"].as_object().unwrap();
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            ["bldg_Building.csv", "sub/a.txt"]
+        );
+        assert_eq!(files["sub/a.txt"]["size"], 3);
+        assert_eq!(files["sub/a.txt"]["sha256"], sha256_hex(b"abc"));
+
+        // single-file outputs have no manifest
+        assert!(!write_directory_manifest(&dir.path().join("sub/a.txt")).unwrap());
+    }
+}
@@ -0,0 +1,288 @@
+//! MBTiles (v1.3) writer
+//!
+//! Writes the tiles into the `tiles` table of a SQLite database.
+//! See <https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md> for the format.
+//!
+//! The tiles are inserted by a dedicated thread in a single transaction,
+//! which is committed (with the metadata) when the writer is finished.
+
+use std::{
+    path::Path,
+    sync::{mpsc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode},
+    ConnectOptions, Connection,
+};
+
+use super::pmtiles::{gzip, tile_bounds, TileCompression};
+use crate::pipeline::{PipelineError, Result};
+
+const SCHEMA: &str = "
+CREATE TABLE metadata (name TEXT NOT NULL, value TEXT);
+CREATE TABLE tiles (zoom_level INTEGER NOT NULL, tile_column INTEGER NOT NULL, tile_row INTEGER NOT NULL, tile_data BLOB);
+CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);
+";
+
+/// Number of the tiles waiting to be inserted
+const QUEUE_CAPACITY: usize = 256;
+
+/// Returns true if the path has the `.mbtiles` extension
+pub fn is_mbtiles_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mbtiles"))
+}
+
+enum Message {
+    Tile {
+        zoom: u8,
+        x: u32,
+        y: u32,
+        data: Vec<u8>,
+    },
+    Finish(Vec<(String, String)>),
+}
+
+struct Extent {
+    min_zoom: u8,
+    max_zoom: u8,
+    /// [min_lon, min_lat, max_lon, max_lat]
+    bounds: [f64; 4],
+}
+
+/// Writes tiles into an MBTiles database (can be shared between the writer threads)
+pub struct MbtilesWriter {
+    sender: mpsc::SyncSender<Message>,
+    handle: JoinHandle<std::result::Result<(), sqlx::Error>>,
+    tile_compression: TileCompression,
+    extent: Mutex<Option<Extent>>,
+}
+
+impl MbtilesWriter {
+    /// Creates the database (an existing file is replaced). The tiles given to `add_tile` are compressed with `tile_compression`.
+    pub fn create(path: &Path, tile_compression: TileCompression) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Delete);
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let handle = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(sqlx::Error::Io)?;
+            runtime.block_on(insert_tiles(options, receiver))
+        });
+
+        Ok(Self {
+            sender,
+            handle,
+            tile_compression,
+            extent: Mutex::new(None),
+        })
+    }
+
+    /// Adds a tile (`y` is in the XYZ scheme, and it is flipped to the TMS scheme of MBTiles)
+    pub fn add_tile(&self, zoom: u8, x: u32, y: u32, content: &[u8]) -> Result<()> {
+        let data = match self.tile_compression {
+            TileCompression::None => content.to_vec(),
+            TileCompression::Gzip => gzip(content)?,
+        };
+
+        {
+            let [min_lon, min_lat, max_lon, max_lat] = tile_bounds(zoom, x, y);
+            let mut extent = self.extent.lock().unwrap();
+            let extent = extent.get_or_insert(Extent {
+                min_zoom: zoom,
+                max_zoom: zoom,
+                bounds: [min_lon, min_lat, max_lon, max_lat],
+            });
+            extent.min_zoom = extent.min_zoom.min(zoom);
+            extent.max_zoom = extent.max_zoom.max(zoom);
+            let bounds = &mut extent.bounds;
+            bounds[0] = bounds[0].min(min_lon);
+            bounds[1] = bounds[1].min(min_lat);
+            bounds[2] = bounds[2].max(max_lon);
+            bounds[3] = bounds[3].max(max_lat);
+        }
+
+        let y = (1u32 << zoom) - 1 - y;
+        if self
+            .sender
+            .send(Message::Tile { zoom, x, y, data })
+            .is_err()
+        {
+            // The writer thread has stopped because of an error
+            return Err(self.stopped());
+        }
+        Ok(())
+    }
+
+    /// Writes the metadata and commits the tiles.
+    ///
+    /// The scalar values of the metadata (JSON object) are stored as they are, and the others
+    /// (e.g. `vector_layers`) are stored in the `json` row. The bounds, the center and the zoom levels
+    /// are derived from the tiles unless they are given.
+    pub fn finish(self, metadata: &serde_json::Value) -> Result<()> {
+        let mut rows = Vec::new();
+        if let Some(extent) = self.extent.lock().unwrap().as_ref() {
+            let [min_lon, min_lat, max_lon, max_lat] = extent.bounds;
+            rows.push((
+                "bounds".to_string(),
+                format!("{min_lon},{min_lat},{max_lon},{max_lat}"),
+            ));
+            rows.push((
+                "center".to_string(),
+                format!(
+                    "{},{},{}",
+                    (min_lon + max_lon) / 2.0,
+                    (min_lat + max_lat) / 2.0,
+                    extent.min_zoom
+                ),
+            ));
+            rows.push(("minzoom".to_string(), extent.min_zoom.to_string()));
+            rows.push(("maxzoom".to_string(), extent.max_zoom.to_string()));
+        }
+
+        let mut json = serde_json::Map::new();
+        if let Some(object) = metadata.as_object() {
+            for (name, value) in object {
+                let value = match value {
+                    serde_json::Value::Null => continue,
+                    serde_json::Value::String(s) => s.clone(),
+                    serde_json::Value::Bool(_) | serde_json::Value::Number(_) => value.to_string(),
+                    _ => {
+                        json.insert(name.clone(), value.clone());
+                        continue;
+                    }
+                };
+                rows.retain(|(n, _)| n != name);
+                rows.push((name.clone(), value));
+            }
+        }
+        if !json.is_empty() {
+            rows.push((
+                "json".to_string(),
+                serde_json::Value::Object(json).to_string(),
+            ));
+        }
+
+        // (if the writer thread has stopped, its error is returned below)
+        let _ = self.sender.send(Message::Finish(rows));
+        drop(self.sender);
+        match self.handle.join() {
+            Ok(result) => result.map_err(|err| PipelineError::Other(format!("MBTiles: {err}"))),
+            Err(_) => Err(PipelineError::Other(
+                "MBTiles: the writer thread panicked".to_string(),
+            )),
+        }
+    }
+
+    fn stopped(&self) -> PipelineError {
+        PipelineError::Other("MBTiles: failed to write the tiles".to_string())
+    }
+}
+
+async fn insert_tiles(
+    options: SqliteConnectOptions,
+    receiver: mpsc::Receiver<Message>,
+) -> std::result::Result<(), sqlx::Error> {
+    let mut conn = options.connect().await?;
+    sqlx::raw_sql(SCHEMA).execute(&mut conn).await?;
+
+    let mut tx = conn.begin().await?;
+    // The sender is dropped without `Finish` if the conversion has failed (the transaction is rolled back)
+    while let Ok(message) = receiver.recv() {
+        match message {
+            Message::Tile { zoom, x, y, data } => {
+                sqlx::query(
+                    "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?, ?, ?, ?)",
+                )
+                .bind(zoom)
+                .bind(x)
+                .bind(y)
+                .bind(data)
+                .execute(&mut *tx)
+                .await?;
+            }
+            Message::Finish(rows) => {
+                for (name, value) in rows {
+                    sqlx::query("INSERT INTO metadata (name, value) VALUES (?, ?)")
+                        .bind(name)
+                        .bind(value)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                tx.commit().await?;
+                return conn.close().await;
+            }
+        }
+    }
+    tx.rollback().await
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Row;
+
+    use super::*;
+
+    #[test]
+    fn test_write_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.mbtiles");
+        assert!(is_mbtiles_path(&path));
+
+        let writer = MbtilesWriter::create(&path, TileCompression::None).unwrap();
+        writer.add_tile(1, 1, 0, b"tile-a").unwrap();
+        writer.add_tile(2, 3, 1, b"tile-b").unwrap();
+        writer
+            .finish(&serde_json::json!({
+                "name": "test",
+                "format": "pbf",
+                "minzoom": 0,
+                "vector_layers": [{ "id": "bldg:Building", "fields": {} }],
+            }))
+            .unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut conn = SqliteConnectOptions::new()
+                .filename(&path)
+                .connect()
+                .await
+                .unwrap();
+
+            // TMS scheme (the y axis is flipped)
+            let row = sqlx::query("SELECT tile_data FROM tiles WHERE zoom_level = 2 AND tile_column = 3 AND tile_row = 2")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(row.get::<Vec<u8>, _>(0), b"tile-b");
+
+            let rows = sqlx::query("SELECT name, value FROM metadata")
+                .fetch_all(&mut conn)
+                .await
+                .unwrap();
+            let metadata: std::collections::HashMap<String, String> = rows
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect();
+            assert_eq!(metadata["name"], "test");
+            assert_eq!(metadata["format"], "pbf");
+            assert_eq!(metadata["minzoom"], "0");
+            assert_eq!(metadata["maxzoom"], "2");
+            assert!(metadata["bounds"].starts_with("0,0,180,85.05"));
+            assert!(metadata["json"].contains("bldg:Building"));
+        });
+    }
+}
@@ -0,0 +1,653 @@
+//! Geometry compression of the glTF meshes (`KHR_draco_mesh_compression` or `EXT_meshopt_compression`)
+//!
+//! With Draco, each triangle primitive is encoded into its own Draco bitstream by [`write_draco_primitive`].
+//! With meshopt, the shared vertex buffer is quantized by [`write_quantized_vertices`] (`KHR_mesh_quantization`),
+//! and then the vertex and index buffer views are compressed as a whole. The vertex buffer can also be only quantized,
+//! which is readable by more viewers than the compressed ones.
+//! In both cases, the vertex attributes are quantized into the given number of bits, and the feature ids are stored losslessly.
+
+use std::collections::HashMap;
+
+use nusamai_gltf::draco;
+use nusamai_gltf_json::{
+    extensions::mesh::khr_draco_mesh_compression::KhrDracoMeshCompression, Accessor, AccessorType,
+    BufferView, ComponentType,
+};
+
+use crate::{
+    get_parameter_value,
+    parameters::{
+        IntegerParameter, ParameterDefinition, ParameterEntry, ParameterType, Parameters,
+        StringParameter,
+    },
+    pipeline::{PipelineError, Result},
+};
+
+pub const KHR_DRACO_MESH_COMPRESSION: &str = "KHR_draco_mesh_compression";
+pub const EXT_MESHOPT_COMPRESSION: &str = "EXT_meshopt_compression";
+pub const KHR_MESH_QUANTIZATION: &str = "KHR_mesh_quantization";
+
+/// The byte size of a quantized vertex: position (u16 x 3 + padding), normal (i8 x 3 + padding), texcoord (u16 x 2),
+/// and feature id (f32). The texture coordinates of the material variants (u16 x 2) follow.
+const QUANTIZED_VERTEX_SIZE: usize = 8 + 4 + 4 + 4;
+
+/// The number of bits to quantize the vertex attributes into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quantization {
+    pub position_bits: u8,
+    pub normal_bits: u8,
+    pub texcoord_bits: u8,
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        Self {
+            position_bits: 14,
+            normal_bits: 10,
+            texcoord_bits: 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MeshCompression {
+    /// The vertices and the indices are written as they are
+    #[default]
+    None,
+    /// `KHR_draco_mesh_compression`
+    Draco(Quantization),
+    /// `EXT_meshopt_compression` with `KHR_mesh_quantization`
+    Meshopt(Quantization),
+    /// `KHR_mesh_quantization` only (the quantized vertex buffer is not compressed)
+    Quantize(Quantization),
+}
+
+impl MeshCompression {
+    /// Parses the option (`none`, `draco`, `meshopt`, `quantize`)
+    pub fn negotiate(option: &str, quantization: Quantization) -> Result<Self> {
+        match option {
+            "" | "none" => Ok(Self::None),
+            "draco" => Ok(Self::Draco(quantization)),
+            "meshopt" => Ok(Self::Meshopt(quantization)),
+            "quantize" => Ok(Self::Quantize(quantization)),
+            _ => Err(PipelineError::Other(format!(
+                "Unknown mesh compression: {option} (expected none, draco, meshopt or quantize)"
+            ))),
+        }
+    }
+
+    /// The quantization of the shared vertex buffer (with [`write_quantized_vertices`])
+    pub fn vertex_quantization(&self) -> Option<&Quantization> {
+        match self {
+            Self::Meshopt(quantization) | Self::Quantize(quantization) => Some(quantization),
+            Self::None | Self::Draco(_) => None,
+        }
+    }
+
+    /// The glTF extensions required by the compressed meshes
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            Self::None => &[],
+            Self::Draco(_) => &[KHR_DRACO_MESH_COMPRESSION],
+            Self::Meshopt(_) => &[EXT_MESHOPT_COMPRESSION, KHR_MESH_QUANTIZATION],
+            Self::Quantize(_) => &[KHR_MESH_QUANTIZATION],
+        }
+    }
+}
+
+/// The mesh compression options given to a sink, validated when the sink runs
+#[derive(Debug, Clone, Default)]
+pub struct MeshCompressionOptions {
+    pub method: String,
+    pub quantization: Quantization,
+}
+
+impl MeshCompressionOptions {
+    pub fn from_parameters(params: &Parameters) -> Self {
+        let bits = |value: &Option<i64>, default: u8| value.map_or(default, |v| v as u8);
+        let default = Quantization::default();
+        Self {
+            method: get_parameter_value!(params, "mesh_compression", String)
+                .clone()
+                .unwrap_or_default(),
+            quantization: Quantization {
+                position_bits: bits(
+                    get_parameter_value!(params, "position_bits", Integer),
+                    default.position_bits,
+                ),
+                normal_bits: bits(
+                    get_parameter_value!(params, "normal_bits", Integer),
+                    default.normal_bits,
+                ),
+                texcoord_bits: bits(
+                    get_parameter_value!(params, "texcoord_bits", Integer),
+                    default.texcoord_bits,
+                ),
+            },
+        }
+    }
+
+    pub fn negotiate(&self) -> Result<MeshCompression> {
+        MeshCompression::negotiate(&self.method, self.quantization)
+    }
+}
+
+fn bits_parameter(key: &str, description: &str, value: u8, label: &str) -> ParameterDefinition {
+    ParameterDefinition {
+        key: key.into(),
+        entry: ParameterEntry {
+            description: description.into(),
+            required: false,
+            parameter: ParameterType::Integer(IntegerParameter {
+                value: Some(value as i64),
+                min: Some(1),
+                max: Some(30),
+            }),
+            label: Some(label.into()),
+        },
+    }
+}
+
+/// The parameters of the mesh compression: the method and the quantization bits of the attributes
+pub fn mesh_compression_parameters() -> Vec<ParameterDefinition> {
+    let default = Quantization::default();
+    vec![
+        ParameterDefinition {
+            key: "mesh_compression".into(),
+            entry: ParameterEntry {
+                description:
+                    "Geometry compression of the meshes: none, draco (KHR_draco_mesh_compression), meshopt (EXT_meshopt_compression) or quantize (KHR_mesh_quantization only)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter {
+                    value: Some("none".into()),
+                }),
+                label: Some("メッシュの圧縮（none, draco, meshopt, quantize）".into()),
+            },
+        },
+        bits_parameter(
+            "position_bits",
+            "Quantization bits of the positions (for the mesh compression)",
+            default.position_bits,
+            "位置の量子化ビット数",
+        ),
+        bits_parameter(
+            "normal_bits",
+            "Quantization bits of the normals (for the mesh compression)",
+            default.normal_bits,
+            "法線の量子化ビット数",
+        ),
+        bits_parameter(
+            "texcoord_bits",
+            "Quantization bits of the texture coordinates (for the mesh compression)",
+            default.texcoord_bits,
+            "テクスチャ座標の量子化ビット数",
+        ),
+    ]
+}
+
+/// Keeps only the vertices referred by the indices, and remaps the indices to them
+pub fn compact_vertices<'a, V: Clone>(
+    vertices: &[V],
+    indices: impl IntoIterator<Item = &'a mut Vec<u32>>,
+) -> Vec<V> {
+    let mut remap = vec![u32::MAX; vertices.len()];
+    let mut compacted = Vec::new();
+    for indices in indices {
+        for idx in indices.iter_mut() {
+            let new_idx = &mut remap[*idx as usize];
+            if *new_idx == u32::MAX {
+                *new_idx = compacted.len() as u32;
+                compacted.push(vertices[*idx as usize].clone());
+            }
+            *idx = *new_idx;
+        }
+    }
+    compacted
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VertexAttributeKind {
+    Position,
+    Normal,
+    TexCoord,
+    /// Stored losslessly
+    FeatureId,
+}
+
+impl VertexAttributeKind {
+    fn num_components(self) -> u8 {
+        match self {
+            Self::Position | Self::Normal => 3,
+            Self::TexCoord => 2,
+            Self::FeatureId => 1,
+        }
+    }
+
+    fn accessor_type(self) -> AccessorType {
+        match self {
+            Self::Position | Self::Normal => AccessorType::Vec3,
+            Self::TexCoord => AccessorType::Vec2,
+            Self::FeatureId => AccessorType::Scalar,
+        }
+    }
+}
+
+/// A vertex attribute of a primitive, with the values of all its vertices
+pub struct VertexAttribute {
+    /// The attribute semantic in glTF (e.g. `POSITION`, `TEXCOORD_0`)
+    pub semantic: String,
+    pub kind: VertexAttributeKind,
+    pub values: Vec<f32>,
+}
+
+/// A primitive whose geometry is in a Draco bitstream
+pub struct DracoPrimitive {
+    /// The accessors (without buffer views) of the attributes
+    pub attributes: HashMap<String, u32>,
+    /// The accessor (without buffer view) of the indices
+    pub indices: u32,
+    pub extension: KhrDracoMeshCompression,
+}
+
+/// Encodes a triangle primitive into a Draco bitstream and appends it to the BIN content as a new buffer view.
+pub fn write_draco_primitive(
+    quantization: &Quantization,
+    attributes: &[VertexAttribute],
+    indices: &[u32],
+    bin_content: &mut Vec<u8>,
+    buffer_views: &mut Vec<BufferView>,
+    accessors: &mut Vec<Accessor>,
+) -> DracoPrimitive {
+    let num_vertices = attributes.first().map_or(0, |attr| {
+        attr.values.len() / attr.kind.num_components() as usize
+    });
+
+    let draco_attributes: Vec<_> = attributes
+        .iter()
+        .map(|attr| {
+            let (attribute_type, quantization_bits) = match attr.kind {
+                VertexAttributeKind::Position => (
+                    draco::AttributeType::Position,
+                    Some(quantization.position_bits),
+                ),
+                VertexAttributeKind::Normal => {
+                    (draco::AttributeType::Normal, Some(quantization.normal_bits))
+                }
+                VertexAttributeKind::TexCoord => (
+                    draco::AttributeType::TexCoord,
+                    Some(quantization.texcoord_bits),
+                ),
+                VertexAttributeKind::FeatureId => (draco::AttributeType::Generic, None),
+            };
+            draco::Attribute {
+                attribute_type,
+                data_type: draco::DataType::Float32,
+                normalized: false,
+                num_components: attr.kind.num_components(),
+                values: &attr.values,
+                quantization_bits,
+                metadata: &[],
+            }
+        })
+        .collect();
+    let encoded = draco::encode_mesh(indices, num_vertices as u32, &draco_attributes);
+
+    let byte_offset = bin_content.len();
+    bin_content.extend_from_slice(&encoded);
+    // keep the following buffer views aligned
+    bin_content.resize(bin_content.len().next_multiple_of(4), 0);
+    buffer_views.push(BufferView {
+        name: Some("draco".to_string()),
+        byte_offset: byte_offset as u32,
+        byte_length: encoded.len() as u32,
+        ..Default::default()
+    });
+    let buffer_view = buffer_views.len() as u32 - 1;
+
+    let mut gltf_attributes = HashMap::new();
+    let mut draco_ids = HashMap::new();
+    for (unique_id, attr) in attributes.iter().enumerate() {
+        let (min, max) = match attr.kind {
+            VertexAttributeKind::Position => {
+                let mut min = vec![f64::MAX; 3];
+                let mut max = vec![f64::MIN; 3];
+                for position in attr.values.chunks_exact(3) {
+                    for ((min, max), &v) in min.iter_mut().zip(max.iter_mut()).zip(position) {
+                        *min = min.min(v as f64);
+                        *max = max.max(v as f64);
+                    }
+                }
+                (Some(min), Some(max))
+            }
+            _ => (None, None),
+        };
+        accessors.push(Accessor {
+            name: Some(attr.semantic.to_lowercase()),
+            component_type: ComponentType::Float,
+            count: num_vertices as u32,
+            type_: attr.kind.accessor_type(),
+            min,
+            max,
+            ..Default::default()
+        });
+        gltf_attributes.insert(attr.semantic.clone(), accessors.len() as u32 - 1);
+        draco_ids.insert(attr.semantic.clone(), unique_id as u32);
+    }
+
+    accessors.push(Accessor {
+        name: Some("indices".to_string()),
+        component_type: ComponentType::UnsignedInt,
+        count: indices.len() as u32,
+        type_: AccessorType::Scalar,
+        ..Default::default()
+    });
+
+    DracoPrimitive {
+        attributes: gltf_attributes,
+        indices: accessors.len() as u32 - 1,
+        extension: KhrDracoMeshCompression {
+            buffer_view,
+            attributes: draco_ids,
+            ..Default::default()
+        },
+    }
+}
+
+/// The transform of the node to dequantize the positions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dequantization {
+    pub offset: [f64; 3],
+    pub scale: [f64; 3],
+}
+
+/// Writes the vertices `[x, y, z, nx, ny, nz, u, v, feature_id]` quantized for `KHR_mesh_quantization` as a new buffer view.
+///
+/// The accessors are added in the same order as the unquantized vertices: positions, normals, texcoords, feature ids,
+/// and the texcoords of the material variants (`variant_uvs[vertex]`).
+/// The positions are quantized relative to their bounding box, and the node must be transformed with the returned
+/// offset and scale. The texture coordinates are clamped to [0, 1].
+#[allow(clippy::too_many_arguments)]
+pub fn write_quantized_vertices(
+    quantization: &Quantization,
+    vertices: &[[u32; 9]],
+    variant_uvs: &[Vec<[u32; 2]>],
+    num_variant_uvs: usize,
+    bin_content: &mut Vec<u8>,
+    buffer_views: &mut Vec<BufferView>,
+    accessors: &mut Vec<Accessor>,
+) -> Option<Dequantization> {
+    if vertices.is_empty() {
+        return None;
+    }
+
+    // the attributes are stored in 16 bits (positions, texcoords) or 8 bits (normals)
+    let position_max = ((1u32 << quantization.position_bits.min(16)) - 1) as f64;
+    let normal_max = ((1u32 << (quantization.normal_bits.clamp(2, 8) - 1)) - 1) as f32;
+    let texcoord_max = ((1u32 << quantization.texcoord_bits.min(16)) - 1) as f32;
+
+    let position = |v: &[u32; 9]| [v[0], v[1], v[2]].map(|bits| f32::from_bits(bits) as f64);
+    let mut min = [f64::MAX; 3];
+    let mut max = [f64::MIN; 3];
+    for v in vertices {
+        for ((min, max), p) in min.iter_mut().zip(max.iter_mut()).zip(position(v)) {
+            *min = min.min(p);
+            *max = max.max(p);
+        }
+    }
+    let mut scale = [1.; 3];
+    for ((scale, min), max) in scale.iter_mut().zip(min).zip(max) {
+        if max > min {
+            *scale = (max - min) / position_max;
+        }
+    }
+
+    let quantize_normal = |bits: u32| {
+        let q = (f32::from_bits(bits).clamp(-1., 1.) * normal_max).round() / normal_max;
+        (q * 127.).round() as i8
+    };
+    let quantize_uv = |uv: [u32; 2]| {
+        uv.map(|bits| {
+            let q = (f32::from_bits(bits).clamp(0., 1.) * texcoord_max).round() / texcoord_max;
+            (q * 65535.).round() as u16
+        })
+    };
+
+    bin_content.resize(bin_content.len().next_multiple_of(4), 0);
+    let buffer_offset = bin_content.len();
+    for (i, v) in vertices.iter().enumerate() {
+        for ((p, min), scale) in position(v).into_iter().zip(min).zip(scale) {
+            let q = ((p - min) / scale).round() as u16;
+            bin_content.extend_from_slice(&q.to_le_bytes());
+        }
+        bin_content.extend_from_slice(&[0; 2]);
+        for &bits in &v[3..6] {
+            bin_content.push(quantize_normal(bits) as u8);
+        }
+        bin_content.push(0);
+        for q in quantize_uv([v[6], v[7]]) {
+            bin_content.extend_from_slice(&q.to_le_bytes());
+        }
+        bin_content.extend_from_slice(&v[8].to_le_bytes());
+        if num_variant_uvs > 0 {
+            debug_assert_eq!(variant_uvs[i].len(), num_variant_uvs);
+            for &uv in &variant_uvs[i] {
+                for q in quantize_uv(uv) {
+                    bin_content.extend_from_slice(&q.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    let vertex_byte_stride = QUANTIZED_VERTEX_SIZE + 4 * num_variant_uvs;
+    buffer_views.push(BufferView {
+        name: Some("vertices".to_string()),
+        byte_offset: buffer_offset as u32,
+        byte_length: (bin_content.len() - buffer_offset) as u32,
+        byte_stride: Some(vertex_byte_stride as u8),
+        target: Some(BufferViewTarget::ArrayBuffer),
+        ..Default::default()
+    });
+    let buffer_view = Some(buffer_views.len() as u32 - 1);
+    let count = vertices.len() as u32;
+
+    let position_q_max = (0..3)
+        .map(|i| ((max[i] - min[i]) / scale[i]).round())
+        .collect();
+    accessors.push(Accessor {
+        name: Some("positions".to_string()),
+        buffer_view,
+        component_type: ComponentType::UnsignedShort,
+        count,
+        min: Some(vec![0.; 3]),
+        max: Some(position_q_max),
+        type_: AccessorType::Vec3,
+        ..Default::default()
+    });
+    accessors.push(Accessor {
+        name: Some("normals".to_string()),
+        buffer_view,
+        byte_offset: 8,
+        component_type: ComponentType::Byte,
+        normalized: true,
+        count,
+        type_: AccessorType::Vec3,
+        ..Default::default()
+    });
+    accessors.push(Accessor {
+        name: Some("texcoords".to_string()),
+        buffer_view,
+        byte_offset: 12,
+        component_type: ComponentType::UnsignedShort,
+        normalized: true,
+        count,
+        type_: AccessorType::Vec2,
+        ..Default::default()
+    });
+    accessors.push(Accessor {
+        name: Some("_feature_ids".to_string()),
+        buffer_view,
+        byte_offset: 16,
+        component_type: ComponentType::Float,
+        count,
+        type_: AccessorType::Scalar,
+        ..Default::default()
+    });
+    for i in 0..num_variant_uvs {
+        accessors.push(Accessor {
+            name: Some(format!("texcoords_{}", i + 1)),
+            buffer_view,
+            byte_offset: (QUANTIZED_VERTEX_SIZE + 4 * i) as u32,
+            component_type: ComponentType::UnsignedShort,
+            normalized: true,
+            count,
+            type_: AccessorType::Vec2,
+            ..Default::default()
+        });
+    }
+
+    Some(Dequantization { offset: min, scale })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let quantization = Quantization::default();
+        assert_eq!(
+            MeshCompression::negotiate("none", quantization).unwrap(),
+            MeshCompression::None
+        );
+        assert_eq!(
+            MeshCompression::negotiate("draco", quantization).unwrap(),
+            MeshCompression::Draco(quantization)
+        );
+        assert_eq!(
+            MeshCompression::negotiate("meshopt", quantization).unwrap(),
+            MeshCompression::Meshopt(quantization)
+        );
+        assert_eq!(
+            MeshCompression::negotiate("quantize", quantization).unwrap(),
+            MeshCompression::Quantize(quantization)
+        );
+        assert!(MeshCompression::negotiate("lzma", quantization).is_err());
+
+        assert_eq!(
+            MeshCompression::Quantize(quantization).vertex_quantization(),
+            Some(&quantization)
+        );
+        assert_eq!(
+            MeshCompression::Quantize(quantization).extensions(),
+            [KHR_MESH_QUANTIZATION]
+        );
+        assert_eq!(
+            MeshCompression::Draco(quantization).vertex_quantization(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_compact_vertices() {
+        let vertices = ['a', 'b', 'c', 'd', 'e'];
+        let mut lines = vec![4, 2, 2, 0];
+        let mut points = vec![0, 3];
+        let compacted = compact_vertices(&vertices, [&mut lines, &mut points]);
+        assert_eq!(compacted, ['e', 'c', 'a', 'd']);
+        assert_eq!(lines, [0, 1, 1, 2]);
+        assert_eq!(points, [2, 3]);
+    }
+
+    #[test]
+    fn test_write_draco_primitive() {
+        let attributes = [
+            VertexAttribute {
+                semantic: "POSITION".into(),
+                kind: VertexAttributeKind::Position,
+                values: vec![0., 0., 0., 2., 0., 0., 2., 3., 1.],
+            },
+            VertexAttribute {
+                semantic: "_FEATURE_ID_0".into(),
+                kind: VertexAttributeKind::FeatureId,
+                values: vec![0., 0., 0.],
+            },
+        ];
+        let mut bin_content = vec![0; 4];
+        let mut buffer_views = vec![];
+        let mut accessors = vec![];
+        let primitive = write_draco_primitive(
+            &Quantization::default(),
+            &attributes,
+            &[0, 1, 2],
+            &mut bin_content,
+            &mut buffer_views,
+            &mut accessors,
+        );
+
+        assert_eq!(bin_content.len() % 4, 0);
+        assert_eq!(buffer_views[0].byte_offset, 4);
+        assert_eq!(&bin_content[4..9], b"DRACO");
+        assert_eq!(primitive.extension.buffer_view, 0);
+        assert_eq!(primitive.extension.attributes["POSITION"], 0);
+        assert_eq!(primitive.extension.attributes["_FEATURE_ID_0"], 1);
+
+        let position = &accessors[primitive.attributes["POSITION"] as usize];
+        assert_eq!(position.buffer_view, None);
+        assert_eq!(position.count, 3);
+        assert_eq!(position.min, Some(vec![0., 0., 0.]));
+        assert_eq!(position.max, Some(vec![2., 3., 1.]));
+        assert_eq!(accessors[primitive.indices as usize].count, 3);
+    }
+
+    #[test]
+    fn test_write_quantized_vertices() {
+        let vertex = |p: [f32; 3], n: [f32; 3], uv: [f32; 2], id: f32| {
+            let mut v = [0; 9];
+            for (dst, src) in v
+                .iter_mut()
+                .zip(p.into_iter().chain(n).chain(uv).chain([id]))
+            {
+                *dst = src.to_bits();
+            }
+            v
+        };
+        let vertices = [
+            vertex([-10., 0., 5.], [0., 0., 1.], [0., 1.], 0.),
+            vertex([10., 2., 5.], [0., -1., 0.], [1., 0.], 3.),
+        ];
+        let variant_uvs = [vec![[0f32.to_bits(); 2]], vec![[1f32.to_bits(); 2]]];
+        let mut bin_content = vec![0; 2];
+        let mut buffer_views = vec![];
+        let mut accessors = vec![];
+        let dequantization = write_quantized_vertices(
+            &Quantization::default(),
+            &vertices,
+            &variant_uvs,
+            1,
+            &mut bin_content,
+            &mut buffer_views,
+            &mut accessors,
+        )
+        .unwrap();
+
+        let max_q = ((1 << 14) - 1) as f64;
+        assert_eq!(dequantization.offset, [-10., 0., 5.]);
+        assert_eq!(dequantization.scale, [20. / max_q, 2. / max_q, 1.]);
+
+        let view = &buffer_views[0];
+        assert_eq!(view.byte_offset, 4);
+        assert_eq!(view.byte_stride, Some(24));
+        assert_eq!(view.byte_length, 48);
+        assert_eq!(accessors.len(), 5);
+        assert_eq!(accessors[0].max, Some(vec![max_q, max_q, 0.]));
+
+        let v1 = &bin_content[4 + 24..];
+        let u16_at = |i: usize| u16::from_le_bytes([v1[i], v1[i + 1]]);
+        assert_eq!([u16_at(0), u16_at(2), u16_at(4)], [16383, 16383, 0]);
+        assert_eq!(&v1[8..12], &[0, (-127i8) as u8, 0, 0]);
+        assert_eq!([u16_at(12), u16_at(14)], [65535, 0]);
+        assert_eq!(&v1[16..20], &3f32.to_le_bytes());
+        assert_eq!([u16_at(20), u16_at(22)], [65535, 65535]);
+    }
+}
@@ -1,20 +1,46 @@
 //! Output format drivers (sinks)
 
 pub mod cesiumtiles;
+pub mod citygml;
+pub mod cityjson;
+pub mod csv;
 pub mod czml;
+pub mod duckdb;
+pub mod dxf;
+pub mod fbx;
 pub mod geojson;
 pub mod gltf;
 pub mod gpkg;
+pub mod i3s;
+pub mod inplace;
 pub mod kml;
+pub mod las;
+pub mod manifest;
+pub mod mbtiles;
+mod mesh_compression;
 pub mod minecraft;
 pub mod mvt;
 pub mod noop;
+mod normals;
 pub mod obj;
 pub mod option;
+pub mod output;
+pub mod overwrite;
+pub mod parquet;
 pub mod ply;
+pub mod pmtiles;
+pub mod roadnetwork;
 pub mod serde;
+pub mod shadow;
 pub mod shapefile;
+pub mod style;
+pub mod terrain;
+mod texture_backend;
+mod texture_compression;
+mod texture_report;
 mod texture_resolution;
+pub mod tile_output;
+pub mod trace;
 
 use nusamai_citygml::schema::Schema;
 use nusamai_projection::crs;
@@ -55,10 +81,18 @@ pub trait DataSink: Send {
 
     /// Make a transform requirements with options
     fn make_requirements(&mut self, property: TransformerSettings) -> DataRequirements;
+
+    /// Whether the sink writes the manifest of the output directory by itself
+    /// (otherwise it is made by [`manifest::write_directory_manifest`] after the conversion)
+    fn writes_manifest(&self) -> bool {
+        false
+    }
 }
 
 pub struct DataRequirements {
     pub output_epsg: crs::EpsgCode,
+    /// The output CRS required by the sink, which is not overridden by `set_output_epsg`
+    pub fixed_output_epsg: Option<crs::EpsgCode>,
     /// Whether to shorten field names to 10 characters or less for Shapefiles.
     pub shorten_names_for_shapefile: bool,
     pub tree_flattening: transformer::TreeFlatteningSpec,
@@ -70,12 +104,33 @@ pub struct DataRequirements {
     pub key_value: transformer::KeyValueSpec,
     pub lod_filter: transformer::LodFilterSpec,
     pub geom_stats: transformer::GeometryStatsSpec,
+    /// Simplified shape of the solitary vegetation objects (None: keep the original meshes)
+    pub vegetation: Option<transformer::VegetationShape>,
+    /// How to handle the underground structures (None: no tagging and no height adjustment)
+    pub underground: Option<transformer::UndergroundMode>,
+    /// How to classify the polygons of the features without semantic surfaces (None: no classification)
+    pub surface_class: Option<transformer::SurfaceClassMode>,
+    /// Whether to add the roof attributes for the screening of the rooftop solar potential
+    pub solar_attributes: bool,
+    /// Whether to add the ids of the adjacent buildings and the length of the shared walls
+    pub building_adjacency: bool,
+    /// How to handle the namespace prefixes of the field names
+    pub prefix: transformer::PrefixPolicy,
+    /// Whether to expand the `gml:name` arrays into the `name`, `name_2`, ... columns
+    pub name_columns: bool,
+    /// How to output the values of the codes (the labels, the raw codes, or both)
+    pub code_values: transformer::CodeValueMode,
+    /// How to handle the string values larger than the limit (e.g. huge JSON of the flattened `uro` trees)
+    pub large_attributes: transformer::LargeAttributeSpec,
+    /// Whether to pass the parsed entities to the sink without any transformation
+    pub passthrough: bool,
 }
 
 impl Default for DataRequirements {
     fn default() -> Self {
         Self {
             output_epsg: crs::EPSG_WGS84_GEOGRAPHIC_3D,
+            fixed_output_epsg: None,
             shorten_names_for_shapefile: false,
             tree_flattening: transformer::TreeFlatteningSpec::None,
             use_appearance: false,
@@ -84,13 +139,23 @@ impl Default for DataRequirements {
             key_value: transformer::KeyValueSpec::JsonifyObjectsAndArrays,
             lod_filter: transformer::LodFilterSpec::default(),
             geom_stats: transformer::GeometryStatsSpec::None,
+            vegetation: None,
+            underground: None,
+            surface_class: None,
+            solar_attributes: false,
+            building_adjacency: false,
+            prefix: transformer::PrefixPolicy::Strip,
+            name_columns: false,
+            code_values: transformer::CodeValueMode::Label,
+            large_attributes: transformer::LargeAttributeSpec::default(),
+            passthrough: false,
         }
     }
 }
 
 impl DataRequirements {
     pub fn set_output_epsg(&mut self, epsg: crs::EpsgCode) {
-        self.output_epsg = epsg;
+        self.output_epsg = self.fixed_output_epsg.unwrap_or(epsg);
     }
 
     pub fn set_appearance(&mut self, use_appearance: bool) {
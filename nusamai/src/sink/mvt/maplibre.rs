@@ -0,0 +1,190 @@
+//! MapLibre style JSON made from the styling profile
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use nusamai_citygml::schema::{Schema, TypeDef};
+use serde_json::{json, Value};
+
+use crate::sink::{
+    mbtiles::is_mbtiles_path,
+    pmtiles::is_pmtiles_path,
+    style::{resolve_attribute_name, Color, Style, StyleProfile},
+};
+
+/// Name of the source in the style
+const SOURCE_NAME: &str = "plateau";
+
+/// Path of the style file: `style.json` in the tile directory, or `{name}.style.json` next to the archive
+pub fn style_path(output_path: &Path) -> PathBuf {
+    if is_pmtiles_path(output_path) || is_mbtiles_path(output_path) {
+        output_path.with_extension("style.json")
+    } else {
+        output_path.join("style.json")
+    }
+}
+
+/// Source of the tiles.
+///
+/// The tiles in a directory are referred by a relative URL, which should be replaced with the URL they are served at.
+fn source(output_path: &Path, min_z: u8, max_z: u8) -> Value {
+    let file_name = output_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    if is_pmtiles_path(output_path) {
+        json!({ "type": "vector", "url": format!("pmtiles://{file_name}") })
+    } else if is_mbtiles_path(output_path) {
+        let stem = output_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        json!({ "type": "vector", "url": format!("mbtiles://{stem}") })
+    } else {
+        json!({
+            "type": "vector",
+            "tiles": ["{z}/{x}/{y}.pbf"],
+            "minzoom": min_z,
+            "maxzoom": max_z,
+        })
+    }
+}
+
+fn css_color(color: &Color) -> String {
+    format!(
+        "rgba({}, {}, {}, {})",
+        color.r,
+        color.g,
+        color.b,
+        (color.opacity() * 1000.0).round() / 1000.0
+    )
+}
+
+/// Makes the style with the fill, line and circle layers for each MVT layer (feature type)
+pub fn style_json(
+    profile: &StyleProfile,
+    schema: &Schema,
+    layer_names: &BTreeSet<String>,
+    output_path: &Path,
+    (min_z, max_z): (u8, u8),
+) -> Value {
+    let mut layers = Vec::new();
+    for layer_name in layer_names {
+        let rules = profile.type_rules(layer_name);
+
+        // the attribute may have been renamed by the transformer
+        let key = rules.attribute.map(|name| {
+            let keys: Vec<&String> = match schema.types.get(layer_name) {
+                Some(TypeDef::Feature(feature)) => feature.attributes.keys().collect(),
+                Some(TypeDef::Data(data)) => data.attributes.keys().collect(),
+                _ => Vec::new(),
+            };
+            resolve_attribute_name(keys, name)
+                .unwrap_or(name)
+                .to_string()
+        });
+        let expr = |prop: fn(&Style) -> Value| match &key {
+            Some(key) if !rules.values.is_empty() => {
+                let mut expr = vec![json!("match"), json!(["get", key])];
+                for (value, style) in &rules.values {
+                    expr.push(json!(value));
+                    expr.push(prop(style));
+                }
+                expr.push(prop(&rules.base));
+                Value::Array(expr)
+            }
+            _ => prop(&rules.base),
+        };
+        let fill = expr(|style| json!(css_color(&style.fill)));
+        let stroke = expr(|style| json!(css_color(&style.stroke)));
+        let width = expr(|style| json!(style.width));
+
+        layers.push(json!({
+            "id": format!("{layer_name}-fill"),
+            "type": "fill",
+            "source": SOURCE_NAME,
+            "source-layer": layer_name,
+            "filter": ["==", ["geometry-type"], "Polygon"],
+            "paint": { "fill-color": fill },
+        }));
+        layers.push(json!({
+            "id": format!("{layer_name}-line"),
+            "type": "line",
+            "source": SOURCE_NAME,
+            "source-layer": layer_name,
+            "filter": ["==", ["geometry-type"], "Polygon"],
+            "paint": { "line-color": stroke, "line-width": width },
+        }));
+        layers.push(json!({
+            "id": format!("{layer_name}-point"),
+            "type": "circle",
+            "source": SOURCE_NAME,
+            "source-layer": layer_name,
+            "filter": ["==", ["geometry-type"], "Point"],
+            "paint": {
+                "circle-radius": 4,
+                "circle-color": fill,
+                "circle-stroke-color": stroke,
+                "circle-stroke-width": 1,
+            },
+        }));
+    }
+
+    json!({
+        "version": 8,
+        "name": output_path.file_stem().map(|s| s.to_string_lossy()),
+        "sources": { SOURCE_NAME: source(output_path, min_z, max_z) },
+        "layers": layers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::schema::{Attribute, FeatureTypeDef, TypeRef};
+
+    use super::*;
+
+    #[test]
+    fn test_style_json() {
+        let mut schema = Schema::default();
+        let mut feature = FeatureTypeDef::default();
+        feature
+            .attributes
+            .insert("usage".into(), Attribute::new(TypeRef::Code));
+        schema
+            .types
+            .insert("bldg:Building".into(), TypeDef::Feature(feature));
+
+        let profile = StyleProfile::builtin();
+        let layer_names = BTreeSet::from(["bldg:Building".to_string(), "foo:Bar".to_string()]);
+        let style = style_json(
+            &profile,
+            &schema,
+            &layer_names,
+            Path::new("/tmp/out.pmtiles"),
+            (7, 15),
+        );
+
+        assert_eq!(style["sources"]["plateau"]["url"], "pmtiles://out.pmtiles");
+        let layers = style["layers"].as_array().unwrap();
+        assert_eq!(layers.len(), 6);
+        assert_eq!(layers[0]["id"], "bldg:Building-fill");
+        // matched by the renamed attribute
+        let fill = &layers[0]["paint"]["fill-color"];
+        assert_eq!(fill[0], "match");
+        assert_eq!(fill[1], json!(["get", "usage"]));
+        // constant for the types without the attribute
+        assert!(layers[3]["paint"]["fill-color"].is_string());
+
+        assert_eq!(
+            style_path(Path::new("/tmp/out.pmtiles")),
+            Path::new("/tmp/out.style.json")
+        );
+        assert_eq!(
+            style_path(Path::new("/tmp/out")),
+            Path::new("/tmp/out/style.json")
+        );
+    }
+}
@@ -0,0 +1,363 @@
+//! Merging of the tile directories written by the shards of a conversion (`--shard`)
+//!
+//! The tiles at the same position are merged into one tile: the features of the layers with the same name are put
+//! into one layer (with the tags encoded again, and the geometries scaled up to the largest extent).
+//! The vector layers and the bounds of the TileJSON, and the layers of the styles are merged as well.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use prost::Message;
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use tinymvt::vector_tile::{self, tile::Layer};
+
+use super::tilejson::TILEJSON_FILENAME;
+use crate::{
+    pipeline::{PipelineError, Result},
+    sink::manifest::Manifest,
+};
+
+const STYLE_FILENAME: &str = "style.json";
+
+/// Returns true if the directory has the tiles of the MVT sink
+pub fn is_tile_directory(path: &Path) -> bool {
+    path.join(TILEJSON_FILENAME).is_file()
+}
+
+/// Merges the tile directories into a new directory
+pub fn merge_tile_directories(inputs: &[&Path], output: &Path) -> Result<()> {
+    // relative path of the tile -> the tiles of the shards
+    let mut tiles: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for input in inputs {
+        if !is_tile_directory(input) {
+            return Err(PipelineError::Other(format!(
+                "Not a directory of the vector tiles (no {}): {:?}",
+                TILEJSON_FILENAME, input
+            )));
+        }
+        for relpath in tile_paths(input)? {
+            let path = input.join(&relpath);
+            tiles.entry(relpath).or_default().push(path);
+        }
+    }
+
+    let manifest = Manifest::new();
+    tiles.par_iter().try_for_each(|(relpath, paths)| {
+        let content = match paths.as_slice() {
+            [path] => fs::read(path)?,
+            _ => {
+                let mut decoded = Vec::new();
+                for path in paths {
+                    let tile =
+                        vector_tile::Tile::decode(fs::read(path)?.as_slice()).map_err(|err| {
+                            PipelineError::Other(format!("Invalid tile {:?}: {}", path, err))
+                        })?;
+                    decoded.push(tile);
+                }
+                merge_tiles(decoded).encode_to_vec()
+            }
+        };
+        let path = output.join(relpath);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, &content)?;
+        manifest.add(relpath, &content);
+        Ok::<(), PipelineError>(())
+    })?;
+
+    for (filename, merge) in [
+        (TILEJSON_FILENAME, merge_tilejson as fn(&mut Value, Value)),
+        (STYLE_FILENAME, merge_style),
+    ] {
+        let mut merged: Option<Value> = None;
+        for input in inputs {
+            let Ok(content) = fs::read(input.join(filename)) else {
+                continue;
+            };
+            let value = serde_json::from_slice(&content).map_err(|err| {
+                PipelineError::Other(format!("Invalid {} in {:?}: {}", filename, input, err))
+            })?;
+            match &mut merged {
+                Some(merged) => merge(merged, value),
+                None => merged = Some(value),
+            }
+        }
+        if let Some(merged) = merged {
+            let content = serde_json::to_vec_pretty(&merged).unwrap();
+            fs::write(output.join(filename), &content)?;
+            manifest.add(filename, &content);
+        }
+    }
+    manifest.write(output)?;
+    Ok(())
+}
+
+/// The relative paths (`{z}/{x}/{y}.pbf`) of the tiles in the directory
+fn tile_paths(dir: &Path) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    let numbered_dirs = |dir: &Path| -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && name.parse::<u32>().is_ok() {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    };
+    for z in numbered_dirs(dir)? {
+        for x in numbered_dirs(&dir.join(&z))? {
+            for entry in fs::read_dir(dir.join(&z).join(&x))? {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                if name.ends_with(".pbf") {
+                    paths.push(format!("{z}/{x}/{name}"));
+                }
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Merges the tiles at the same position into one tile
+fn merge_tiles(tiles: Vec<vector_tile::Tile>) -> vector_tile::Tile {
+    let mut layers: Vec<Layer> = Vec::new();
+    for layer in tiles.into_iter().flat_map(|tile| tile.layers) {
+        match layers.iter_mut().find(|merged| merged.name == layer.name) {
+            Some(merged) => merge_layer(merged, layer),
+            None => layers.push(layer),
+        }
+    }
+    vector_tile::Tile { layers }
+}
+
+/// Appends the features of the layer to the layer with the same name
+fn merge_layer(merged: &mut Layer, mut layer: Layer) {
+    let (merged_extent, extent) = (merged.extent(), layer.extent());
+    if extent > merged_extent {
+        scale_geometries(merged, extent / merged_extent);
+        merged.extent = Some(extent);
+    } else if extent < merged_extent {
+        scale_geometries(&mut layer, merged_extent / extent);
+    }
+
+    let mut key_index: HashMap<String, u32> = merged.keys.iter().cloned().zip(0..).collect();
+    let key_indices: Vec<u32> = layer
+        .keys
+        .into_iter()
+        .map(|key| {
+            *key_index.entry(key.clone()).or_insert_with(|| {
+                merged.keys.push(key);
+                merged.keys.len() as u32 - 1
+            })
+        })
+        .collect();
+    // (the values are compared by their encoding, as they may be floats)
+    let mut value_index: HashMap<Vec<u8>, u32> = merged
+        .values
+        .iter()
+        .map(|value| value.encode_to_vec())
+        .zip(0..)
+        .collect();
+    let value_indices: Vec<u32> = layer
+        .values
+        .into_iter()
+        .map(|value| {
+            *value_index.entry(value.encode_to_vec()).or_insert_with(|| {
+                merged.values.push(value);
+                merged.values.len() as u32 - 1
+            })
+        })
+        .collect();
+    for mut feature in layer.features {
+        for pair in feature.tags.chunks_exact_mut(2) {
+            pair[0] = key_indices[pair[0] as usize];
+            pair[1] = value_indices[pair[1] as usize];
+        }
+        merged.features.push(feature);
+    }
+}
+
+/// Scales the coordinates of the features by the factor (the extents are powers of 2, so no precision is lost)
+fn scale_geometries(layer: &mut Layer, factor: u32) {
+    let factor = factor as i32;
+    for feature in &mut layer.features {
+        let mut i = 0;
+        while i < feature.geometry.len() {
+            let command = feature.geometry[i];
+            let (id, count) = (command & 0x7, (command >> 3) as usize);
+            i += 1;
+            // (MoveTo and LineTo have the pairs of the zigzag-encoded deltas, ClosePath has none)
+            if id == 1 || id == 2 {
+                let end = (i + count * 2).min(feature.geometry.len());
+                for param in &mut feature.geometry[i..end] {
+                    let delta = ((*param >> 1) as i32) ^ -((*param & 1) as i32);
+                    let scaled = delta * factor;
+                    *param = ((scaled << 1) ^ (scaled >> 31)) as u32;
+                }
+                i += count * 2;
+            }
+        }
+    }
+}
+
+/// Merges the TileJSON: the vector layers (with their fields), the zoom levels and the bounds
+fn merge_tilejson(merged: &mut Value, tilejson: Value) {
+    if let (Some(merged_layers), Some(layers)) = (
+        merged["vector_layers"].as_array_mut(),
+        tilejson["vector_layers"].as_array(),
+    ) {
+        for layer in layers {
+            match merged_layers.iter_mut().find(|l| l["id"] == layer["id"]) {
+                Some(merged_layer) => {
+                    if let (Some(fields), Some(new_fields)) = (
+                        merged_layer["fields"].as_object_mut(),
+                        layer["fields"].as_object(),
+                    ) {
+                        for (name, ty) in new_fields {
+                            fields.entry(name).or_insert_with(|| ty.clone());
+                        }
+                    }
+                }
+                None => merged_layers.push(layer.clone()),
+            }
+        }
+        merged_layers.sort_by(|a, b| a["id"].as_str().cmp(&b["id"].as_str()));
+    }
+    for (key, pick) in [
+        ("minzoom", u64::min as fn(u64, u64) -> u64),
+        ("maxzoom", u64::max),
+    ] {
+        if let (Some(a), Some(b)) = (merged[key].as_u64(), tilejson[key].as_u64()) {
+            merged[key] = json!(pick(a, b));
+        }
+    }
+    let bounds = |value: &Value| -> Option<Vec<f64>> {
+        let bounds: Vec<f64> = value["bounds"]
+            .as_array()?
+            .iter()
+            .filter_map(|v| v.as_f64())
+            .collect();
+        (bounds.len() == 4).then_some(bounds)
+    };
+    let merged_bounds = match (bounds(merged), bounds(&tilejson)) {
+        (Some(a), Some(b)) => Some([
+            a[0].min(b[0]),
+            a[1].min(b[1]),
+            a[2].max(b[2]),
+            a[3].max(b[3]),
+        ]),
+        (Some(a), None) | (None, Some(a)) => Some([a[0], a[1], a[2], a[3]]),
+        (None, None) => None,
+    };
+    if let Some(bounds @ [min_lng, min_lat, max_lng, max_lat]) = merged_bounds {
+        merged["bounds"] = json!(bounds);
+        merged["center"] = json!([
+            (min_lng + max_lng) / 2.0,
+            (min_lat + max_lat) / 2.0,
+            merged["minzoom"]
+        ]);
+    }
+}
+
+/// Merges the styles: the layers missing in the first style are added
+fn merge_style(merged: &mut Value, style: Value) {
+    let (Some(merged_layers), Some(layers)) =
+        (merged["layers"].as_array_mut(), style["layers"].as_array())
+    else {
+        return;
+    };
+    for layer in layers {
+        if !merged_layers.iter().any(|l| l["id"] == layer["id"]) {
+            merged_layers.push(layer.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(name: &str, extent: u32, key: &str, value: &str, geometry: Vec<u32>) -> Layer {
+        Layer {
+            version: 2,
+            name: name.into(),
+            features: vec![vector_tile::tile::Feature {
+                id: None,
+                tags: vec![0, 0],
+                r#type: Some(vector_tile::tile::GeomType::Point as i32),
+                geometry,
+            }],
+            keys: vec![key.into()],
+            values: vec![vector_tile::tile::Value {
+                string_value: Some(value.into()),
+                ..Default::default()
+            }],
+            extent: Some(extent),
+        }
+    }
+
+    #[test]
+    fn test_merge_tiles() {
+        // MoveTo(1), (+2, -3)
+        let point = vec![9, 4, 5];
+        let a = vector_tile::Tile {
+            layers: vec![layer("bldg:Building", 2048, "name", "a", point.clone())],
+        };
+        let b = vector_tile::Tile {
+            layers: vec![
+                layer("bldg:Building", 4096, "height", "a", point.clone()),
+                layer("tran:Road", 4096, "name", "b", point.clone()),
+            ],
+        };
+        let merged = merge_tiles(vec![a, b]);
+        assert_eq!(merged.layers.len(), 2);
+
+        let buildings = &merged.layers[0];
+        assert_eq!(buildings.extent, Some(4096));
+        assert_eq!(buildings.keys, vec!["name", "height"]);
+        assert_eq!(buildings.values.len(), 1);
+        assert_eq!(buildings.features[0].tags, vec![0, 0]);
+        assert_eq!(buildings.features[1].tags, vec![1, 0]);
+        // (the first feature is scaled up to the extent of 4096: (+4, -6))
+        assert_eq!(buildings.features[0].geometry, vec![9, 8, 11]);
+        assert_eq!(buildings.features[1].geometry, point);
+
+        assert_eq!(merged.layers[1].name, "tran:Road");
+    }
+
+    #[test]
+    fn test_merge_tilejson() {
+        let mut merged = json!({
+            "minzoom": 10,
+            "maxzoom": 16,
+            "bounds": [139.0, 35.0, 139.5, 35.5],
+            "vector_layers": [{ "id": "bldg:Building", "fields": { "name": "String" } }],
+        });
+        merge_tilejson(
+            &mut merged,
+            json!({
+                "minzoom": 8,
+                "maxzoom": 16,
+                "bounds": [139.4, 34.5, 140.0, 35.2],
+                "vector_layers": [
+                    { "id": "tran:Road", "fields": {} },
+                    { "id": "bldg:Building", "fields": { "height": "Number" } },
+                ],
+            }),
+        );
+        assert_eq!(merged["minzoom"], 8);
+        assert_eq!(merged["bounds"], json!([139.0, 34.5, 140.0, 35.5]));
+        assert_eq!(merged["center"], json!([139.5, 35.0, 8]));
+        assert_eq!(
+            merged["vector_layers"],
+            json!([
+                { "id": "bldg:Building", "fields": { "name": "String", "height": "Number" } },
+                { "id": "tran:Road", "fields": {} },
+            ])
+        );
+    }
+}
@@ -1,15 +1,21 @@
 //! Mapbox Vector Tiles (MVT) sink
+//!
+//! A MapLibre style made from the styling profile is written together with the tiles (see the `maplibre` module).
 
+mod maplibre;
+pub mod merge;
 mod slice;
 mod tags;
 pub mod tileid;
+mod tilejson;
 
 use std::{
+    collections::BTreeSet,
     convert::Infallible,
     fs,
     io::prelude::*,
     path::{Path, PathBuf},
-    sync::mpsc,
+    sync::{mpsc, Mutex},
 };
 
 use flate2::{write::ZlibEncoder, Compression};
@@ -20,9 +26,10 @@ use nusamai_citygml::{object, schema::Schema};
 use prost::Message;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use slice::slice_cityobj_geoms;
+use slice::{slice_cityobj_geoms, slice_cityobj_points, tiny_polygon_area, TinyPolygons};
 use tags::convert_properties;
 use tileid::TileIdMethod;
+use tilejson::{tilejson, Bounds, TILEJSON_FILENAME};
 use tinymvt::{geometry::GeometryEncoder, tag::TagsEncoder, vector_tile};
 
 use crate::{
@@ -31,10 +38,26 @@ use crate::{
     pipeline::{Feedback, PipelineError, Receiver, Result},
     sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
     transformer,
-    transformer::{use_lod_config, TransformerSettings},
+    transformer::{
+        building_adjacency_config, large_attribute_limit_config, large_attributes_config,
+        name_columns_config, prefix_config, solar_attributes_config,
+        split_bridge_and_tunnel_elements_config, underground_config, use_lod_config,
+        vegetation_config, TransformerSettings,
+    },
 };
 
-use super::option::output_parameter;
+use super::{
+    mbtiles::is_mbtiles_path,
+    option::{output_parameter, style_parameter},
+    output::remove_on_cancel,
+    pmtiles::{is_pmtiles_path, TileCompression, TileType},
+    style::StyleProfile,
+    tile_output::TileOutput,
+    trace::{source_path, trace_parameter, trace_path, TraceWriter},
+};
+
+/// Detail level of the slicing and the tiles (extent: 4096)
+const MAX_DETAIL: u32 = 12;
 
 pub struct MvtSinkProvider {}
 
@@ -52,7 +75,7 @@ impl DataSinkProvider for MvtSinkProvider {
         params.define(ParameterDefinition {
             key: "min_z".into(),
             entry: ParameterEntry {
-                description: "Minumum zoom level".into(),
+                description: "Minimum zoom level".into(),
                 required: true,
                 parameter: ParameterType::Integer(IntegerParameter {
                     value: Some(7),
@@ -75,6 +98,8 @@ impl DataSinkProvider for MvtSinkProvider {
                 label: Some("最大ズームレベル".into()),
             },
         });
+        params.define(style_parameter());
+        params.define(trace_parameter());
 
         params
     }
@@ -82,6 +107,15 @@ impl DataSinkProvider for MvtSinkProvider {
     fn transformer_options(&self) -> TransformerSettings {
         let mut settings: TransformerSettings = TransformerSettings::new();
         settings.insert(use_lod_config("min_lod", None));
+        settings.insert(vegetation_config(&["point"]));
+        settings.insert(underground_config());
+        settings.insert(split_bridge_and_tunnel_elements_config());
+        settings.insert(solar_attributes_config());
+        settings.insert(building_adjacency_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
+        settings.insert(large_attributes_config());
+        settings.insert(large_attribute_limit_config("4096"));
 
         settings
     }
@@ -91,11 +125,15 @@ impl DataSinkProvider for MvtSinkProvider {
         let transform_options = self.transformer_options();
         let min_z = get_parameter_value!(params, "min_z", Integer).unwrap() as u8;
         let max_z = get_parameter_value!(params, "max_z", Integer).unwrap() as u8;
+        let style_path = get_parameter_value!(params, "style", FileSystemPath);
+        let trace = get_parameter_value!(params, "trace", Boolean).unwrap();
 
         Box::<MvtSink>::new(MvtSink {
             output_path: output_path.as_ref().unwrap().into(),
             transform_settings: transform_options,
             mvt_options: MvtParams { min_z, max_z },
+            style_path: style_path.clone(),
+            trace,
         })
     }
 }
@@ -104,6 +142,10 @@ struct MvtSink {
     output_path: PathBuf,
     transform_settings: TransformerSettings,
     mvt_options: MvtParams,
+    /// Styling profile (the built-in one if not specified)
+    style_path: Option<PathBuf>,
+    /// Write the MVT feature IDs with the gml:ids and the source files (see the `trace` module)
+    trace: bool,
 }
 
 struct MvtParams {
@@ -114,6 +156,10 @@ struct MvtParams {
 #[derive(Serialize, Deserialize)]
 struct SlicedFeature<'a> {
     geometry: MultiPolygon2<'a>,
+    /// Points in the tile coordinates (0.0 - 1.0)
+    points: Vec<[f64; 2]>,
+    /// Tiny polygons not drawn in the sliced geometry, accumulated with the other features of the tile
+    tiny_polygons: TinyPolygons,
     properties: nusamai_citygml::object::Value,
 }
 
@@ -125,7 +171,7 @@ impl DataSink for MvtSink {
                 mode: transformer::LodFilterMode::Lowest,
                 ..Default::default()
             },
-            geom_stats: transformer::GeometryStatsSpec::MinMaxHeights,
+            geom_stats: transformer::GeometryStatsSpec::ExtrusionHeights,
             ..Default::default()
         };
 
@@ -136,63 +182,105 @@ impl DataSink for MvtSink {
         self.transform_settings.build(default_requirements)
     }
 
-    fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        if self.mvt_options.max_z < self.mvt_options.min_z {
+            return Err(PipelineError::Other(
+                "max_z must be greater than or equal to min_z".into(),
+            ));
+        }
+
+        let profile = StyleProfile::load(self.style_path.as_deref())?;
+        let trace = match self.trace {
+            true => {
+                let is_archive =
+                    is_pmtiles_path(&self.output_path) || is_mbtiles_path(&self.output_path);
+                let path = trace_path(&self.output_path, !is_archive);
+                Some(TraceWriter::create(&path)?)
+            }
+            false => None,
+        };
         let (sender_sliced, receiver_sliced) = mpsc::sync_channel(2000);
         let (sender_sorted, receiver_sorted) = mpsc::sync_channel(2000);
 
         let tile_id_conv = TileIdMethod::Hilbert;
+        // the extent of the features for the TileJSON
+        let bounds = Bounds::default();
 
         // TODO: refactoring
 
-        std::thread::scope(|s| {
-            // Slicing geometry along the tile boundaries
-            {
-                s.spawn(|| {
-                    if let Err(error) = geometry_slicing_stage(
-                        feedback,
-                        upstream,
-                        tile_id_conv,
-                        sender_sliced,
-                        &self.mvt_options,
-                    ) {
-                        feedback.fatal_error(error);
-                    }
-                });
-            }
-
-            // Sort features by tile_id (using external sorter)
-            {
-                s.spawn(move || {
-                    if let Err(error) =
-                        feature_sorting_stage(feedback, receiver_sliced, sender_sorted)
-                    {
-                        feedback.fatal_error(error);
-                    }
-                });
-            }
+        // the tiles written so far are removed if canceled
+        remove_on_cancel(&self.output_path, feedback, || {
+            std::thread::scope(|s| {
+                // Slicing geometry along the tile boundaries
+                {
+                    let trace = trace.as_ref();
+                    s.spawn(|| {
+                        if let Err(error) = geometry_slicing_stage(
+                            feedback,
+                            upstream,
+                            tile_id_conv,
+                            sender_sliced,
+                            &self.mvt_options,
+                            trace,
+                            &bounds,
+                        ) {
+                            feedback.fatal_error(error);
+                        }
+                    });
+                }
 
-            // Group sorted features and write them into MVT tiles
-            {
-                let output_path = &self.output_path;
-                s.spawn(move || {
-                    // Run in a separate thread pool to avoid deadlocks
-                    let pool = rayon::ThreadPoolBuilder::new()
-                        .use_current_thread()
-                        .build()
-                        .unwrap();
-                    pool.install(|| {
+                // Sort features by tile_id (using external sorter)
+                {
+                    s.spawn(move || {
                         if let Err(error) =
-                            tile_writing_stage(output_path, feedback, receiver_sorted, tile_id_conv)
+                            feature_sorting_stage(feedback, receiver_sliced, sender_sorted)
                         {
                             feedback.fatal_error(error);
                         }
-                    })
-                });
-            }
-        });
+                    });
+                }
+
+                // Group sorted features and write them into MVT tiles
+                {
+                    let output_path = &self.output_path;
+                    let mvt_options = &self.mvt_options;
+                    let profile = &profile;
+                    let bounds = &bounds;
+                    s.spawn(move || {
+                        // Run in a separate thread pool to avoid deadlocks
+                        let pool = rayon::ThreadPoolBuilder::new()
+                            .use_current_thread()
+                            .build()
+                            .unwrap();
+                        pool.install(|| {
+                            if let Err(error) = tile_writing_stage(
+                                output_path,
+                                feedback,
+                                receiver_sorted,
+                                tile_id_conv,
+                                mvt_options,
+                                profile,
+                                schema,
+                                bounds,
+                            ) {
+                                feedback.fatal_error(error);
+                            }
+                        })
+                    });
+                }
+            });
+            Ok(())
+        })?;
 
+        if let Some(trace) = trace {
+            trace.finish()?;
+        }
         Ok(())
     }
+
+    fn writes_manifest(&self) -> bool {
+        true
+    }
 }
 
 fn geometry_slicing_stage(
@@ -201,6 +289,8 @@ fn geometry_slicing_stage(
     tile_id_conv: TileIdMethod,
     sender_sliced: mpsc::SyncSender<(u64, Vec<u8>)>,
     mvt_options: &MvtParams,
+    trace: Option<&TraceWriter>,
+    bounds: &Bounds,
 ) -> Result<()> {
     let bincode_config = bincode::config::standard();
 
@@ -208,19 +298,55 @@ fn geometry_slicing_stage(
     upstream.into_iter().par_bridge().try_for_each(|parcel| {
         feedback.ensure_not_canceled()?;
 
-        let max_detail = 12; // 4096
+        if let (Some(trace), object::Value::Object(obj)) = (trace, &parcel.entity.root) {
+            if let Some(id) = obj.stereotype.id() {
+                trace.write(
+                    id,
+                    &obj.typename,
+                    &feature_id(id).to_string(),
+                    &source_path(&parcel.entity.base_url),
+                )?;
+            }
+        }
+
+        bounds.extend(&parcel.entity.geometry_store.read().unwrap().vertices);
+
         let buffer_pixels = 5;
         slice_cityobj_geoms(
             &parcel.entity,
             mvt_options.min_z,
             mvt_options.max_z,
-            max_detail,
+            MAX_DETAIL,
             buffer_pixels,
-            |(z, x, y), mpoly| {
+            |(z, x, y), mpoly, tiny_polygons| {
                 feedback.ensure_not_canceled()?;
 
                 let feature = SlicedFeature {
                     geometry: mpoly,
+                    points: Vec::new(),
+                    tiny_polygons,
+                    properties: parcel.entity.root.clone(),
+                };
+                let bytes = bincode::serde::encode_to_vec(&feature, bincode_config).unwrap();
+                let tile_id = tile_id_conv.zxy_to_id(z, x, y);
+                if sender_sliced.send((tile_id, bytes)).is_err() {
+                    return Err(PipelineError::Canceled);
+                };
+                Ok(())
+            },
+        )?;
+
+        slice_cityobj_points(
+            &parcel.entity,
+            mvt_options.min_z,
+            mvt_options.max_z,
+            |(z, x, y), points| {
+                feedback.ensure_not_canceled()?;
+
+                let feature = SlicedFeature {
+                    geometry: MultiPolygon2::new(),
+                    points,
+                    tiny_polygons: TinyPolygons::default(),
                     properties: parcel.entity.root.clone(),
                 };
                 let bytes = bincode::serde::encode_to_vec(&feature, bincode_config).unwrap();
@@ -235,7 +361,7 @@ fn geometry_slicing_stage(
     Ok(())
 }
 
-fn feature_sorting_stage(
+pub(crate) fn feature_sorting_stage(
     feedback: &Feedback,
     receiver_sliced: mpsc::Receiver<(u64, Vec<u8>)>,
     sender_sorted: mpsc::SyncSender<(u64, Vec<Vec<u8>>)>,
@@ -260,8 +386,11 @@ fn feature_sorting_stage(
             .map_ok(|(_, serialized_feats)| serialized_feats)
             .collect::<kv_extsort::Result<Vec<_>, _>>();
         match grouped {
-            Ok(serialized_feats) => {
+            Ok(mut serialized_feats) => {
                 feedback.ensure_not_canceled()?;
+                // The order of the features in a tile depends on the thread scheduling,
+                // so sort them to make the tile contents deterministic
+                serialized_feats.sort_unstable();
                 if sender_sorted.send((tile_id, serialized_feats)).is_err() {
                     return Err(PipelineError::Canceled);
                 }
@@ -285,16 +414,29 @@ fn feature_sorting_stage(
 struct LayerData {
     pub features: Vec<vector_tile::tile::Feature>,
     pub tags_enc: TagsEncoder,
+    /// Tiny polygons of the features not drawn yet
+    pub tiny_polygons: TinyPolygons,
 }
 
+/// Encodes the features of each tile into a MVT tile (a layer for each feature type, with the attributes as tags).
+///
+/// The tiles are gzip-compressed in the PMTiles archive and the MBTiles database, and written as they are into
+/// the directory (the compression is left to the server). The detail level is lowered while a compressed tile
+/// exceeds 500 KB.
 fn tile_writing_stage(
     output_path: &Path,
     feedback: &Feedback,
     receiver_sorted: mpsc::Receiver<(u64, Vec<Vec<u8>>)>,
     tile_id_conv: TileIdMethod,
+    mvt_options: &MvtParams,
+    profile: &StyleProfile,
+    schema: &Schema,
+    bounds: &Bounds,
 ) -> Result<()> {
-    let default_detail = 12;
+    let default_detail = MAX_DETAIL as i32;
     let min_detail = 9;
+    let output = TileOutput::create(output_path, TileType::Mvt, TileCompression::Gzip)?;
+    let layer_names = Mutex::new(BTreeSet::new());
 
     receiver_sorted
         .into_iter()
@@ -311,16 +453,12 @@ fn tile_writing_stage(
                 ));
             }
 
-            let path = output_path.join(Path::new(&format!("{zoom}/{x}/{y}.pbf")));
-            if let Some(dir) = path.parent() {
-                fs::create_dir_all(dir)?;
-            }
-
             for detail in (min_detail..=default_detail).rev() {
                 feedback.ensure_not_canceled()?;
 
                 // Make a MVT tile binary
-                let bytes = make_tile(detail, &serialized_feats)?;
+                let tile = make_tile(detail, &serialized_feats)?;
+                let bytes = tile.encode_to_vec();
 
                 // Retry with a lower detail level if the compressed tile size is too large
                 let compressed_size = {
@@ -339,27 +477,77 @@ fn tile_writing_stage(
                     continue;
                 }
 
+                let path = output.write_tile(tile_id, "pbf", &bytes)?;
                 feedback.info(format!(
                     "Writing a tile: {} ({} bytes, {} compressed)",
-                    &path.to_string_lossy(),
+                    path,
                     bytesize::to_string(bytes.len() as u64, true),
                     bytesize::to_string(compressed_size as u64, true),
                 ));
-                fs::write(&path, &bytes)?;
+                layer_names
+                    .lock()
+                    .unwrap()
+                    .extend(tile.layers.into_iter().map(|layer| layer.name));
                 break;
             }
 
             Ok::<(), PipelineError>(())
         })?;
 
+    let layer_names = layer_names.into_inner().unwrap();
+    let name = output_path.file_stem().map(|s| s.to_string_lossy());
+    let zoom_range = (mvt_options.min_z, mvt_options.max_z);
+    let metadata = if output.is_directory() {
+        let metadata = tilejson(
+            name.as_deref(),
+            schema,
+            &layer_names,
+            zoom_range,
+            bounds.get(),
+            Some("{z}/{x}/{y}.pbf"),
+        );
+        output.write_file(
+            TILEJSON_FILENAME,
+            &serde_json::to_vec_pretty(&metadata).unwrap(),
+        )?;
+        metadata
+    } else {
+        // (the bounds and the center of the archive and the database are derived from the tiles)
+        tilejson(
+            name.as_deref(),
+            schema,
+            &layer_names,
+            zoom_range,
+            None,
+            None,
+        )
+    };
+    output.finish(&metadata)?;
+
+    let style = maplibre::style_json(profile, schema, &layer_names, output_path, zoom_range);
+    let style_path = maplibre::style_path(output_path);
+    if let Some(dir) = style_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&style_path, serde_json::to_vec_pretty(&style).unwrap())?;
+    feedback.info(format!("Wrote the style: {}", style_path.display()));
+
     Ok(())
 }
 
-fn make_tile(default_detail: i32, serialized_feats: &[Vec<u8>]) -> Result<Vec<u8>> {
+/// Makes a MVT feature id (u64) by hashing the original feature id string
+fn feature_id(id: &str) -> u64 {
+    id.as_bytes()
+        .iter()
+        .fold(5381u64, |a, c| a.wrapping_mul(33) ^ *c as u64)
+}
+
+fn make_tile(default_detail: i32, serialized_feats: &[Vec<u8>]) -> Result<vector_tile::Tile> {
     let mut layers: HashMap<String, LayerData> = HashMap::new();
     let mut int_ring_buf = Vec::new();
     let mut int_ring_buf2 = Vec::new();
     let extent = 1 << default_detail;
+    let tiny_area = tiny_polygon_area(MAX_DETAIL);
     let bincode_config = bincode::config::standard();
 
     for serialized_feat in serialized_feats {
@@ -368,102 +556,59 @@ fn make_tile(default_detail: i32, serialized_feats: &[Vec<u8>]) -> Result<Vec<u8
                 PipelineError::Other(format!("Failed to deserialize a sliced feature: {:?}", err))
             })?;
 
-        let mpoly = feature.geometry;
-        let mut int_mpoly = MultiPolygon::<[i16; 2]>::new();
-
-        for poly in &mpoly {
-            for (ri, ring) in poly.rings().enumerate() {
-                int_ring_buf.clear();
-                int_ring_buf.extend(ring.into_iter().map(|[x, y]| {
-                    let x = (x * extent as f64 + 0.5) as i16;
-                    let y = (y * extent as f64 + 0.5) as i16;
-                    [x, y]
-                }));
-
-                // some simplification
-                {
-                    int_ring_buf2.clear();
-                    int_ring_buf2.push(int_ring_buf[0]);
-                    for c in int_ring_buf.windows(3) {
-                        let &[prev, curr, next] = c.try_into().unwrap();
-
-                        // Remove duplicate points
-                        if prev == curr {
-                            continue;
-                        }
-
-                        // Reject collinear points
-                        let [curr_x, curr_y] = curr;
-                        let [prev_x, prev_y] = prev;
-                        let [next_x, next_y] = next;
-                        if curr != next
-                            && ((next_y - prev_y) as i32 * (curr_x - prev_x) as i32).abs()
-                                == ((curr_y - prev_y) as i32 * (next_x - prev_x) as i32).abs()
-                        {
-                            continue;
-                        }
-
-                        int_ring_buf2.push(curr);
-                    }
-                    int_ring_buf2.push(*int_ring_buf.last().unwrap());
-                }
+        let layer = match &feature.properties {
+            object::Value::Object(obj) => layers.entry_ref(obj.typename.as_ref()).or_default(),
+            _ => layers.entry_ref("Unknown").or_default(),
+        };
 
-                match ri {
-                    0 => int_mpoly.add_exterior(int_ring_buf2.drain(..)),
-                    _ => int_mpoly.add_interior(int_ring_buf2.drain(..)),
-                }
+        let (geometry, geom_type) = if feature.points.is_empty() {
+            // Draw the tiny polygons accumulated in the layer as a square once they are dense enough
+            let mut mpoly = feature.geometry;
+            layer.tiny_polygons.merge(&feature.tiny_polygons);
+            if let Some(square) = layer
+                .tiny_polygons
+                .take_square(tiny_area, 1.0 / extent as f64)
+            {
+                mpoly.add_exterior(square);
             }
-        }
 
-        // encode geometry
-        let mut geom_enc = GeometryEncoder::new();
-        for poly in &int_mpoly {
-            let exterior = poly.exterior();
-            if exterior.signed_ring_area() > 0.0 {
-                geom_enc.add_ring(&exterior);
-                for interior in poly.interiors() {
-                    if interior.is_cw() {
-                        geom_enc.add_ring(&interior);
-                    }
-                }
-            }
-        }
-        let geometry = geom_enc.into_vec();
+            let geometry =
+                encode_multipolygon(&mpoly, extent, &mut int_ring_buf, &mut int_ring_buf2);
+            (geometry, vector_tile::tile::GeomType::Polygon)
+        } else {
+            let mut geom_enc = GeometryEncoder::new();
+            geom_enc.add_points(feature.points.iter().map(|&[x, y]| {
+                let x = (x * extent as f64 + 0.5) as i16;
+                let y = (y * extent as f64 + 0.5) as i16;
+                [x, y]
+            }));
+            (geom_enc.into_vec(), vector_tile::tile::GeomType::Point)
+        };
         if geometry.is_empty() {
             continue;
         }
 
         let mut id = None;
-        let layer = if let object::Value::Object(obj) = &feature.properties {
-            let layer = layers.entry_ref(obj.typename.as_ref()).or_default();
-
+        if let object::Value::Object(obj) = &feature.properties {
             // Encode attributes as MVT tags
             for (key, value) in &obj.attributes {
                 convert_properties(&mut layer.tags_enc, key, value);
             }
 
-            // Make a MVT feature id (u64) by hashing the original feature id string.
-            id = obj.stereotype.id().map(|id| {
-                id.as_bytes()
-                    .iter()
-                    .fold(5381u64, |a, c| a.wrapping_mul(33) ^ *c as u64)
-            });
-
-            layer
-        } else {
-            layers.entry_ref("Unknown").or_default()
-        };
+            id = obj.stereotype.id().map(feature_id);
+        }
 
         layer.features.push(vector_tile::tile::Feature {
             id,
             tags: layer.tags_enc.take_tags(),
-            r#type: Some(vector_tile::tile::GeomType::Polygon as i32),
+            r#type: Some(geom_type as i32),
             geometry,
         });
     }
 
     let layers = layers
         .into_iter()
+        .sorted_unstable_by(|(a, _), (b, _)| a.cmp(b))
         .flat_map(|(name, layer_data)| {
             if layer_data.features.is_empty() {
                 return None;
@@ -480,8 +625,73 @@ fn make_tile(default_detail: i32, serialized_feats: &[Vec<u8>]) -> Result<Vec<u8
         })
         .collect();
 
-    let tile = vector_tile::Tile { layers };
+    Ok(vector_tile::Tile { layers })
+}
+
+fn encode_multipolygon(
+    mpoly: &MultiPolygon2,
+    extent: u32,
+    int_ring_buf: &mut Vec<[i16; 2]>,
+    int_ring_buf2: &mut Vec<[i16; 2]>,
+) -> Vec<u32> {
+    let mut int_mpoly = MultiPolygon::<[i16; 2]>::new();
+
+    for poly in mpoly {
+        for (ri, ring) in poly.rings().enumerate() {
+            int_ring_buf.clear();
+            int_ring_buf.extend(ring.into_iter().map(|[x, y]| {
+                let x = (x * extent as f64 + 0.5) as i16;
+                let y = (y * extent as f64 + 0.5) as i16;
+                [x, y]
+            }));
+
+            // some simplification
+            {
+                int_ring_buf2.clear();
+                int_ring_buf2.push(int_ring_buf[0]);
+                for c in int_ring_buf.windows(3) {
+                    let &[prev, curr, next] = c.try_into().unwrap();
+
+                    // Remove duplicate points
+                    if prev == curr {
+                        continue;
+                    }
+
+                    // Reject collinear points
+                    let [curr_x, curr_y] = curr;
+                    let [prev_x, prev_y] = prev;
+                    let [next_x, next_y] = next;
+                    if curr != next
+                        && ((next_y - prev_y) as i32 * (curr_x - prev_x) as i32).abs()
+                            == ((curr_y - prev_y) as i32 * (next_x - prev_x) as i32).abs()
+                    {
+                        continue;
+                    }
 
-    let bytes = tile.encode_to_vec();
-    Ok(bytes)
+                    int_ring_buf2.push(curr);
+                }
+                int_ring_buf2.push(*int_ring_buf.last().unwrap());
+            }
+
+            match ri {
+                0 => int_mpoly.add_exterior(int_ring_buf2.drain(..)),
+                _ => int_mpoly.add_interior(int_ring_buf2.drain(..)),
+            }
+        }
+    }
+
+    // encode geometry
+    let mut geom_enc = GeometryEncoder::new();
+    for poly in &int_mpoly {
+        let exterior = poly.exterior();
+        if exterior.signed_ring_area() > 0.0 {
+            geom_enc.add_ring(&exterior);
+            for interior in poly.interiors() {
+                if interior.is_cw() {
+                    geom_enc.add_ring(&interior);
+                }
+            }
+        }
+    }
+    geom_enc.into_vec()
 }
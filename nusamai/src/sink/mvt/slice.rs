@@ -1,4 +1,7 @@
 //! Polygon slicing algorithm based on [geojson-vt](https://github.com/mapbox/geojson-vt).
+//!
+//! The polygons smaller than a few subpixels are not sliced, but their areas are accumulated for each tile
+//! and drawn as small squares where they are dense, as the 'tiny-polygon-reduction' of tippecanoe.
 
 use flatgeom::{LineString2, MultiPolygon2, Polygon2};
 use hashbrown::HashMap;
@@ -7,15 +10,84 @@ use nusamai_citygml::{
     object::{ObjectStereotype, Value},
 };
 use nusamai_plateau::Entity;
+use serde::{Deserialize, Serialize};
 use tinymvt::{webmercator::lnglat_to_web_mercator, TileZXY};
 
+/// Polygons smaller than this (in the square subpixels at the max detail) are regarded as tiny polygons
+const TINY_POLYGON_SUBPIXELS: f64 = 4.0;
+
+/// Area of a tiny polygon in the tile coordinates (1.0 is the whole tile)
+pub fn tiny_polygon_area(max_detail: u32) -> f64 {
+    TINY_POLYGON_SUBPIXELS / 4f64.powi(max_detail as i32)
+}
+
+/// Tiny polygons of a feature in a tile (the sum of the areas and the area-weighted center).
+///
+/// They are dropped individually, and the accumulated area is drawn as a square once it reaches the area
+/// of a tiny polygon, so that the dense clusters of tiny polygons remain visible at the low zoom levels.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct TinyPolygons {
+    /// Sum of the areas, in the tile coordinates
+    pub area: f64,
+    // area-weighted sum of the centers, in the tile coordinates
+    weighted_center: [f64; 2],
+}
+
+impl TinyPolygons {
+    pub fn is_empty(&self) -> bool {
+        self.area <= 0.0
+    }
+
+    pub fn add(&mut self, area: f64, [x, y]: [f64; 2]) {
+        self.area += area;
+        self.weighted_center[0] += x * area;
+        self.weighted_center[1] += y * area;
+    }
+
+    pub fn merge(&mut self, other: &TinyPolygons) {
+        self.area += other.area;
+        self.weighted_center[0] += other.weighted_center[0];
+        self.weighted_center[1] += other.weighted_center[1];
+    }
+
+    pub fn center(&self) -> [f64; 2] {
+        [
+            self.weighted_center[0] / self.area,
+            self.weighted_center[1] / self.area,
+        ]
+    }
+
+    /// Takes the accumulated polygons as a square if the area reaches `threshold`.
+    ///
+    /// The square has the accumulated area, but is at least `min_side` wide so that it is not lost
+    /// in the integer coordinates of the tile.
+    pub fn take_square(&mut self, threshold: f64, min_side: f64) -> Option<[[f64; 2]; 4]> {
+        if self.is_empty() || self.area < threshold {
+            return None;
+        }
+        let [cx, cy] = self.center();
+        let half = self.area.sqrt().max(min_side) / 2.0;
+        *self = Self::default();
+        // (the same orientation as the sliced exteriors)
+        Some([
+            [cx - half, cy - half],
+            [cx + half, cy - half],
+            [cx + half, cy + half],
+            [cx - half, cy + half],
+        ])
+    }
+}
+
+/// Slices the polygons of the city object into the tiles, in the tile coordinates (0.0 - 1.0).
+///
+/// `f` is called for each tile with the sliced polygons and the tiny polygons not drawn in the tile.
 pub fn slice_cityobj_geoms<E>(
     obj: &Entity,
     min_z: u8,
     max_z: u8,
     max_detail: u32,
     buffer_pixels: u32,
-    f: impl Fn(TileZXY, MultiPolygon2) -> Result<(), E>,
+    f: impl Fn(TileZXY, MultiPolygon2, TinyPolygons) -> Result<(), E>,
 ) -> Result<(), E> {
     assert!(
         max_z >= min_z,
@@ -28,6 +100,7 @@ pub fn slice_cityobj_geoms<E>(
     }
 
     let mut tiled_mpolys = HashMap::new();
+    let mut tiled_tiny_polys: HashMap<TileZXY, TinyPolygons> = HashMap::new();
 
     let extent = 1 << max_detail;
     let buffer = extent * buffer_pixels / 256;
@@ -61,10 +134,18 @@ pub fn slice_cityobj_geoms<E>(
 
                 // Slice for each zoom level
                 for zoom in min_z..=max_z {
-                    // Skip if the polygon is smaller than 4 square subpixels
-                    //
-                    // TODO: emulate the 'tiny-polygon-reduction' of tippecanoe
-                    if area * (4u64.pow(zoom as u32 + max_detail) as f64) < 4.0 {
+                    let z_scale = (1u32 << zoom) as f64;
+                    let tile_area = area * z_scale * z_scale;
+
+                    // Accumulate the polygons smaller than 4 square subpixels, instead of slicing them
+                    if tile_area < tiny_polygon_area(max_detail) {
+                        let (cx, cy) = ring_center(poly.exterior().iter());
+                        let (xi, yi) = ((cx * z_scale).floor(), (cy * z_scale).floor());
+                        let key = (zoom, (xi as i64).rem_euclid(1 << zoom) as u32, yi as u32);
+                        tiled_tiny_polys
+                            .entry(key)
+                            .or_default()
+                            .add(tile_area, [cx * z_scale - xi, cy * z_scale - yi]);
                         continue;
                     }
 
@@ -80,16 +161,81 @@ pub fn slice_cityobj_geoms<E>(
         }
     });
 
-    for ((z, x, y), mpoly) in tiled_mpolys {
-        if mpoly.is_empty() {
+    // The tiny polygons of the feature itself are drawn if they are dense enough, and the rest are
+    // passed to be accumulated with the other features in the tile
+    let threshold = tiny_polygon_area(max_detail);
+    let min_side = 1.0 / extent as f64;
+    for (tile, tiny_polys) in tiled_tiny_polys.iter_mut() {
+        let mpoly = tiled_mpolys.entry(*tile).or_insert_with(MultiPolygon2::new);
+        if let Some(square) = tiny_polys.take_square(threshold, min_side) {
+            mpoly.add_exterior(square);
+        }
+    }
+
+    for (tile, mpoly) in tiled_mpolys {
+        let tiny_polys = tiled_tiny_polys.remove(&tile).unwrap_or_default();
+        if mpoly.is_empty() && tiny_polys.is_empty() {
             continue;
         }
-        f((z, x, y), mpoly)?;
+        f(tile, mpoly, tiny_polys)?;
     }
 
     Ok(())
 
-    // TODO: linestring, point
+    // TODO: linestring
+}
+
+/// Assigns the points of the city object to the tiles, in the tile coordinates (0.0 - 1.0).
+pub fn slice_cityobj_points<E>(
+    obj: &Entity,
+    min_z: u8,
+    max_z: u8,
+    f: impl Fn(TileZXY, Vec<[f64; 2]>) -> Result<(), E>,
+) -> Result<(), E> {
+    let geom_store = obj.geometry_store.read().unwrap();
+    if geom_store.multipoint.is_empty() {
+        return Ok(());
+    }
+
+    let Value::Object(obj) = &obj.root else {
+        return Ok(());
+    };
+    let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype else {
+        return Ok(());
+    };
+
+    let mut tiled_points: HashMap<TileZXY, Vec<[f64; 2]>> = HashMap::new();
+    for entry in geometries.iter().filter(|g| g.ty == GeometryType::Point) {
+        for idx in geom_store
+            .multipoint
+            .iter_range(entry.pos as usize..(entry.pos + entry.len) as usize)
+        {
+            let [lng, lat, _height] = geom_store.vertices[idx as usize];
+            let (mx, my) = lnglat_to_web_mercator(lng, lat);
+
+            for zoom in min_z..=max_z {
+                let z_scale = (1u32 << zoom) as f64;
+                let (xi, yi) = ((mx * z_scale).floor(), (my * z_scale).floor());
+                tiled_points
+                    .entry((zoom, xi as u32, yi as u32))
+                    .or_default()
+                    .push([mx * z_scale - xi, my * z_scale - yi]);
+            }
+        }
+    }
+
+    for (tile, points) in tiled_points {
+        f(tile, points)?;
+    }
+
+    Ok(())
+}
+
+/// Center of the vertices of a ring (enough for the tiny polygons)
+fn ring_center(coords: impl Iterator<Item = [f64; 2]>) -> (f64, f64) {
+    let (sum_x, sum_y, n) =
+        coords.fold((0.0, 0.0, 0), |(sx, sy, n), [x, y]| (sx + x, sy + y, n + 1));
+    (sum_x / n.max(1) as f64, sum_y / n.max(1) as f64)
 }
 
 fn slice_polygon(
@@ -268,3 +414,38 @@ fn slice_polygon(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiny_polygons() {
+        let threshold = tiny_polygon_area(12);
+        let min_side = 1.0 / 4096.0;
+
+        // dropped while the accumulated area is smaller than a tiny polygon
+        let mut tiny_polys = TinyPolygons::default();
+        assert!(tiny_polys.is_empty());
+        tiny_polys.add(threshold * 0.4, [0.25, 0.5]);
+        assert!(tiny_polys.take_square(threshold, min_side).is_none());
+
+        let mut other = TinyPolygons::default();
+        other.add(threshold * 0.6, [0.75, 0.5]);
+        tiny_polys.merge(&other);
+        let center = tiny_polys.center();
+        assert!((center[0] - 0.55).abs() < 1e-9);
+        assert!((center[1] - 0.5).abs() < 1e-9);
+
+        // drawn as a square at the weighted center, and the accumulation is reset
+        let square = tiny_polys.take_square(threshold, min_side).unwrap();
+        assert!(tiny_polys.is_empty());
+        let side = square[1][0] - square[0][0];
+        assert!((side - threshold.sqrt()).abs() < 1e-12);
+        assert!(side >= min_side);
+
+        // the same orientation as the sliced exteriors (see `encode_multipolygon()`)
+        let ring = LineString2::from_raw(square.to_vec().into());
+        assert!(ring.signed_ring_area() > 0.0);
+    }
+}
@@ -0,0 +1,179 @@
+//! TileJSON describing the vector tiles
+//!
+//! Written as `metadata.json` in the tile directory (and stored as the metadata of the PMTiles archive and
+//! the MBTiles database), so that the tile servers and MapLibre can configure the source from it.
+
+use std::{collections::BTreeSet, sync::Mutex};
+
+use nusamai_citygml::schema::{Schema, TypeDef, TypeRef};
+use serde_json::{json, Map, Value};
+
+/// Filename of the TileJSON, written in the tile directory
+pub const TILEJSON_FILENAME: &str = "metadata.json";
+
+/// Extent of the features (longitude and latitude), accumulated while slicing (can be shared between the threads)
+#[derive(Default)]
+pub struct Bounds {
+    extent: Mutex<Option<[f64; 4]>>,
+}
+
+impl Bounds {
+    /// Extends the extent with the vertices (longitude, latitude, height) of a feature
+    pub fn extend(&self, vertices: &[[f64; 3]]) {
+        let Some(&[lng, lat, _]) = vertices.first() else {
+            return;
+        };
+        let mut local = [lng, lat, lng, lat];
+        for &[lng, lat, _] in vertices {
+            local[0] = local[0].min(lng);
+            local[1] = local[1].min(lat);
+            local[2] = local[2].max(lng);
+            local[3] = local[3].max(lat);
+        }
+
+        let mut extent = self.extent.lock().unwrap();
+        *extent = Some(match *extent {
+            Some(e) => [
+                e[0].min(local[0]),
+                e[1].min(local[1]),
+                e[2].max(local[2]),
+                e[3].max(local[3]),
+            ],
+            None => local,
+        });
+    }
+
+    pub fn get(&self) -> Option<[f64; 4]> {
+        *self.extent.lock().unwrap()
+    }
+}
+
+/// Type of an attribute in the `fields` of the vector layer
+fn field_type(type_ref: &TypeRef) -> Option<&'static str> {
+    match type_ref {
+        TypeRef::Integer | TypeRef::NonNegativeInteger | TypeRef::Double | TypeRef::Measure => {
+            Some("Number")
+        }
+        TypeRef::Boolean => Some("Boolean"),
+        // (the objects are not written into the tiles)
+        TypeRef::Named(_) => None,
+        _ => Some("String"),
+    }
+}
+
+/// Fields of the vector layer, from the attributes of the feature type in the schema
+fn fields(schema: &Schema, layer_name: &str) -> Map<String, Value> {
+    let Some(TypeDef::Feature(feature)) = schema.types.get(layer_name) else {
+        return Map::new();
+    };
+    feature
+        .attributes
+        .iter()
+        .filter(|(_, attr)| attr.max_occurs == Some(1))
+        .filter_map(|(name, attr)| Some((name.clone(), field_type(&attr.type_ref)?.into())))
+        .collect()
+}
+
+/// Makes the TileJSON 3.0.0 of the tiles.
+///
+/// `tiles` is the URL template of the tiles (relative to the TileJSON), if they are written into a directory.
+pub fn tilejson(
+    name: Option<&str>,
+    schema: &Schema,
+    layer_names: &BTreeSet<String>,
+    (min_z, max_z): (u8, u8),
+    bounds: Option<[f64; 4]>,
+    tiles: Option<&str>,
+) -> Value {
+    let vector_layers: Vec<_> = layer_names
+        .iter()
+        .map(|name| {
+            json!({
+                "id": name,
+                "fields": fields(schema, name),
+                "minzoom": min_z,
+                "maxzoom": max_z,
+            })
+        })
+        .collect();
+
+    let mut tilejson = json!({
+        "tilejson": "3.0.0",
+        "name": name,
+        "format": "pbf",
+        "scheme": "xyz",
+        "minzoom": min_z,
+        "maxzoom": max_z,
+        "vector_layers": vector_layers,
+    });
+    if let Some(tiles) = tiles {
+        tilejson["tiles"] = json!([tiles]);
+    }
+    if let Some(bounds @ [min_lng, min_lat, max_lng, max_lat]) = bounds {
+        tilejson["bounds"] = json!(bounds);
+        tilejson["center"] = json!([(min_lng + max_lng) / 2.0, (min_lat + max_lat) / 2.0, min_z]);
+    }
+    tilejson
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::schema::{Attribute, FeatureTypeDef};
+
+    use super::*;
+
+    #[test]
+    fn test_tilejson() {
+        let mut schema = Schema::default();
+        let mut feature = FeatureTypeDef::default();
+        for (name, type_ref, max_occurs) in [
+            ("gml:name", TypeRef::String, Some(1)),
+            ("bldg:measuredHeight", TypeRef::Measure, Some(1)),
+            ("uro:hasLift", TypeRef::Boolean, Some(1)),
+            (
+                "bldg:address",
+                TypeRef::Named("core:Address".into()),
+                Some(1),
+            ),
+            ("bldg:usage", TypeRef::Code, None),
+        ] {
+            let mut attr = Attribute::new(type_ref);
+            attr.max_occurs = max_occurs;
+            feature.attributes.insert(name.into(), attr);
+        }
+        schema
+            .types
+            .insert("bldg:Building".into(), TypeDef::Feature(feature));
+
+        let bounds = Bounds::default();
+        assert_eq!(bounds.get(), None);
+        bounds.extend(&[[139.7, 35.6, 10.0], [139.8, 35.7, 20.0]]);
+        bounds.extend(&[[139.6, 35.65, 0.0]]);
+        bounds.extend(&[]);
+
+        let layer_names = BTreeSet::from(["bldg:Building".to_string()]);
+        let tilejson = tilejson(
+            Some("tiles"),
+            &schema,
+            &layer_names,
+            (7, 15),
+            bounds.get(),
+            Some("{z}/{x}/{y}.pbf"),
+        );
+        assert_eq!(tilejson["tilejson"], "3.0.0");
+        assert_eq!(tilejson["tiles"], json!(["{z}/{x}/{y}.pbf"]));
+        assert_eq!(tilejson["bounds"], json!([139.6, 35.6, 139.8, 35.7]));
+        assert_eq!(tilejson["center"][2], 7);
+
+        let layer = &tilejson["vector_layers"][0];
+        assert_eq!(layer["id"], "bldg:Building");
+        assert_eq!(
+            layer["fields"],
+            json!({
+                "gml:name": "String",
+                "bldg:measuredHeight": "Number",
+                "uro:hasLift": "Boolean",
+            })
+        );
+    }
+}
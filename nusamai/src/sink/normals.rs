@@ -0,0 +1,209 @@
+//! Vertex normals of the triangulated polygons
+//!
+//! With the flat normals, all the vertices of a polygon have the normal of the polygon, so the surfaces are shaded
+//! as facets. With the smooth normals, the normals of the polygons of a feature sharing a position are averaged,
+//! except between the polygons meeting at more than [`CREASE_ANGLE`]. The curved surfaces (e.g. the TINs of the
+//! terrain and the curved roofs) are shaded smoothly, and the corners of the buildings stay sharp.
+//!
+//! The tangents are not generated, as the materials written by the sinks have no normal textures.
+
+use ahash::HashMap;
+use flatgeom::MultiPolygon;
+
+use crate::{
+    parameters::{ParameterDefinition, ParameterEntry, ParameterType, StringParameter},
+    pipeline::{PipelineError, Result},
+    sink::cesiumtiles::utils::calculate_normal,
+};
+
+/// The maximum angle (in degrees) between the polygons whose normals are averaged
+pub const CREASE_ANGLE: f64 = 30.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NormalMode {
+    /// The normal of the polygon
+    #[default]
+    Flat,
+    /// The normals averaged between the adjacent polygons of the feature
+    Smooth,
+}
+
+impl NormalMode {
+    /// Parses the option (`flat`, `smooth`)
+    pub fn negotiate(option: &str) -> Result<Self> {
+        match option {
+            "" | "flat" => Ok(Self::Flat),
+            "smooth" => Ok(Self::Smooth),
+            _ => Err(PipelineError::Other(format!(
+                "Unknown normals: {option} (expected flat or smooth)"
+            ))),
+        }
+    }
+}
+
+pub fn normals_parameter() -> ParameterDefinition {
+    ParameterDefinition {
+        key: "normals".into(),
+        entry: ParameterEntry {
+            description: "Vertex normals of the meshes: flat (the normal of each polygon) or smooth (averaged between the adjacent polygons)".into(),
+            required: false,
+            parameter: ParameterType::String(StringParameter {
+                value: Some("flat".into()),
+            }),
+            label: Some("頂点法線（flat, smooth）".into()),
+        },
+    }
+}
+
+/// Normals of the vertices of a feature
+#[derive(Default)]
+pub struct VertexNormals {
+    /// position -> normals of the polygons having a vertex at the position (only with the smooth normals)
+    polygon_normals: HashMap<[u64; 3], Vec<(f64, f64, f64)>>,
+}
+
+impl VertexNormals {
+    /// Collects the normals of the polygons of the feature (`[x, y, z, u, v]`)
+    pub fn new(mode: NormalMode, polygons: &MultiPolygon<'_, [f64; 5]>) -> Self {
+        let mut normals = Self::default();
+        if mode == NormalMode::Flat {
+            return normals;
+        }
+
+        for poly in polygons.iter() {
+            let Some(normal) = calculate_normal(poly.exterior().iter().map(|v| [v[0], v[1], v[2]]))
+            else {
+                continue;
+            };
+            for &[x, y, z, _, _] in poly.raw_coords() {
+                let entry = normals.polygon_normals.entry(key([x, y, z])).or_default();
+                // (a polygon touching the position several times is counted once)
+                if entry.last() != Some(&normal) {
+                    entry.push(normal);
+                }
+            }
+        }
+        normals
+    }
+
+    /// Normal of the vertex of the polygon whose normal is `polygon_normal`
+    ///
+    /// The vertices not found in the feature (e.g. moved by the simplification) have the normal of the polygon.
+    pub fn normal(&self, position: [f64; 3], polygon_normal: (f64, f64, f64)) -> (f64, f64, f64) {
+        let Some(normals) = self.polygon_normals.get(&key(position)) else {
+            return polygon_normal;
+        };
+
+        let min_cos = CREASE_ANGLE.to_radians().cos();
+        let dot = |(ax, ay, az): (f64, f64, f64), (bx, by, bz): (f64, f64, f64)| {
+            ax * bx + ay * by + az * bz
+        };
+        let (sx, sy, sz) = normals
+            .iter()
+            .filter(|&&n| dot(n, polygon_normal) >= min_cos)
+            .fold((0.0, 0.0, 0.0), |(sx, sy, sz), &(nx, ny, nz)| {
+                (sx + nx, sy + ny, sz + nz)
+            });
+
+        match (sx * sx + sy * sy + sz * sz).sqrt() {
+            d if d < 1e-30 => polygon_normal,
+            d => (sx / d, sy / d, sz / d),
+        }
+    }
+}
+
+fn key([x, y, z]: [f64; 3]) -> [u64; 3] {
+    // (+0.0 and -0.0 are the same position)
+    [x + 0.0, y + 0.0, z + 0.0].map(f64::to_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two faces of a shallow roof (10 degrees each), and a wall at the eave
+    fn polygons() -> MultiPolygon<'static, [f64; 5]> {
+        let h = 10f64.to_radians().tan();
+        let mut polygons = MultiPolygon::new();
+        polygons.add_exterior([
+            [0.0, 0.0, 0.0, 0.0, 0.0],
+            [1.0, 0.0, h, 0.0, 0.0],
+            [1.0, 1.0, h, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0, 0.0],
+        ]);
+        polygons.add_exterior([
+            [1.0, 0.0, h, 0.0, 0.0],
+            [2.0, 0.0, 0.0, 0.0, 0.0],
+            [2.0, 1.0, 0.0, 0.0, 0.0],
+            [1.0, 1.0, h, 0.0, 0.0],
+        ]);
+        polygons.add_exterior([
+            [0.0, 0.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, -1.0, 0.0, 0.0],
+            [0.0, 0.0, -1.0, 0.0, 0.0],
+        ]);
+        polygons
+    }
+
+    fn assert_close((ax, ay, az): (f64, f64, f64), (bx, by, bz): (f64, f64, f64)) {
+        assert!(
+            (ax - bx).abs() < 1e-9 && (ay - by).abs() < 1e-9 && (az - bz).abs() < 1e-9,
+            "{:?} != {:?}",
+            (ax, ay, az),
+            (bx, by, bz)
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(NormalMode::negotiate("").unwrap(), NormalMode::Flat);
+        assert_eq!(NormalMode::negotiate("smooth").unwrap(), NormalMode::Smooth);
+        assert!(NormalMode::negotiate("phong").is_err());
+    }
+
+    #[test]
+    fn test_flat() {
+        let polygons = polygons();
+        let normals = VertexNormals::new(NormalMode::Flat, &polygons);
+        let n = calculate_normal([[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]]).unwrap();
+        assert_eq!(normals.normal([0.0, 0.0, 0.0], n), n);
+    }
+
+    #[test]
+    fn test_smooth() {
+        let polygons = polygons();
+        let normals = VertexNormals::new(NormalMode::Smooth, &polygons);
+        let polygon_normals: Vec<_> = polygons
+            .iter()
+            .map(|poly| {
+                calculate_normal(poly.exterior().iter().map(|v| [v[0], v[1], v[2]])).unwrap()
+            })
+            .collect();
+
+        // the ridge between the roof faces is smoothed into the up direction
+        let h = 10f64.to_radians().tan();
+        assert_close(
+            normals.normal([1.0, 0.0, h], polygon_normals[0]),
+            (0.0, 0.0, 1.0),
+        );
+        assert_close(
+            normals.normal([1.0, 1.0, h], polygon_normals[1]),
+            (0.0, 0.0, 1.0),
+        );
+        // the eave keeps the normals of the roof and the wall
+        assert_close(
+            normals.normal([0.0, 0.0, 0.0], polygon_normals[0]),
+            polygon_normals[0],
+        );
+        assert_close(
+            normals.normal([0.0, 0.0, 0.0], polygon_normals[2]),
+            polygon_normals[2],
+        );
+        // the positions not in the feature
+        assert_close(
+            normals.normal([5.0, 5.0, 5.0], polygon_normals[1]),
+            polygon_normals[1],
+        );
+    }
+}
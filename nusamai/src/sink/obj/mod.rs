@@ -36,12 +36,18 @@ use nusamai_projection::cartesian::geodetic_to_geocentric;
 use crate::{
     get_parameter_value,
     parameters::*,
+    paths,
     pipeline::{Feedback, PipelineError, Receiver, Result},
     sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
-    transformer::{use_lod_config, TransformerSettings},
+    transformer::{surface_class_config, use_lod_config, TransformerSettings},
 };
 
+use super::cesiumtiles::utils::calculate_normal;
+use super::inplace::TransformInplaceExt;
+use super::normals::{normals_parameter, NormalMode, VertexNormals};
 use super::option::{limit_texture_resolution_parameter, output_parameter};
+use super::output::remove_on_cancel;
+use super::texture_report::TextureUsage;
 use super::texture_resolution::get_texture_downsample_scale_of_polygon;
 
 pub struct ObjSinkProvider {}
@@ -67,6 +73,7 @@ impl DataSinkProvider for ObjSinkProvider {
                 label: Some("オブジェクトを分割する".into()),
             },
         });
+        params.define(normals_parameter());
 
         params
     }
@@ -74,6 +81,7 @@ impl DataSinkProvider for ObjSinkProvider {
     fn transformer_options(&self) -> TransformerSettings {
         let mut settings: TransformerSettings = TransformerSettings::new();
         settings.insert(use_lod_config("max_lod", Some(&["textured_max_lod"])));
+        settings.insert(surface_class_config());
 
         settings
     }
@@ -84,12 +92,16 @@ impl DataSinkProvider for ObjSinkProvider {
             *get_parameter_value!(params, "limit_texture_resolution", Boolean);
         let transform_options = self.transformer_options();
         let is_split = get_parameter_value!(params, "split", Boolean).unwrap();
+        let normals = get_parameter_value!(params, "normals", String)
+            .clone()
+            .unwrap_or_default();
 
         Box::<ObjSink>::new(ObjSink {
             output_path: output_path.as_ref().unwrap().into(),
             transform_settings: transform_options,
             obj_options: ObjParams { is_split },
             limit_texture_resolution,
+            normals,
         })
     }
 }
@@ -99,6 +111,8 @@ pub struct ObjSink {
     transform_settings: TransformerSettings,
     obj_options: ObjParams,
     limit_texture_resolution: Option<bool>,
+    /// Vertex normals of the meshes (`flat`, `smooth`)
+    normals: String,
 }
 
 struct ObjParams {
@@ -167,6 +181,7 @@ pub type ObjMaterials = HashMap<MaterialKey, FeatureMaterial>;
 pub struct FeatureMesh {
     pub vertices: Vec<[f64; 3]>,
     pub uvs: Vec<[f64; 2]>,
+    pub normals: Vec<[f64; 3]>,
     pub primitives: HashMap<MaterialKey, Vec<u32>>,
 }
 
@@ -190,8 +205,24 @@ impl DataSink for ObjSink {
         self.transform_settings.build(default_requirements)
     }
 
-    fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        // the files written so far are removed if canceled
+        let output_path = self.output_path.clone();
+        remove_on_cancel(&output_path, feedback, || {
+            self.write_features(upstream, feedback, schema)
+        })
+    }
+}
+
+impl ObjSink {
+    fn write_features(
+        &self,
+        upstream: Receiver,
+        feedback: &Feedback,
+        _schema: &Schema,
+    ) -> Result<()> {
         let ellipsoid = nusamai_projection::ellipsoid::wgs84();
+        let normal_mode = NormalMode::negotiate(&self.normals)?;
 
         let classified_features: Mutex<ClassifiedFeatures> = Default::default();
 
@@ -321,6 +352,8 @@ impl DataSink for ObjSink {
             Ok::<(), PipelineError>(())
         });
 
+        // (the errors of the parallel loop above are not propagated)
+        feedback.ensure_not_canceled()?;
         let classified_features = classified_features.into_inner().unwrap();
 
         // Bounding volume for the entire dataset
@@ -350,6 +383,7 @@ impl DataSink for ObjSink {
         let _ = transform_matrix.inverse();
 
         // Create the information needed to output an OBJ file and write it to a file
+        let texture_usage = TextureUsage::new();
         classified_features
             .into_par_iter()
             .try_for_each(|(typename, mut features)| {
@@ -364,6 +398,8 @@ impl DataSink for ObjSink {
                 let mut max_width = 0;
                 let mut max_height = 0;
                 for feature in features.features.iter() {
+                    feedback.ensure_not_canceled()?;
+
                     for (_, orig_mat_id) in feature
                         .polygons
                         .iter()
@@ -372,8 +408,11 @@ impl DataSink for ObjSink {
                         let mat = feature.materials[*orig_mat_id as usize].clone();
                         let t = mat.base_texture.clone();
                         if let Some(base_texture) = t {
-                            let texture_uri = base_texture.uri.to_file_path().unwrap();
+                            let Some(texture_uri) = paths::url_to_path(&base_texture.uri) else {
+                                continue;
+                            };
                             let texture_size = texture_size_cache.get_or_insert(&texture_uri);
+                            texture_usage.add_source(&typename, &texture_uri);
                             max_width = max_width.max(texture_size.0);
                             max_height = max_height.max(texture_size.1);
                         }
@@ -393,17 +432,21 @@ impl DataSink for ObjSink {
 
                 // Coordinate transformation
                 {
+                    let mut scratch = Vec::new();
                     for feature in features.features.iter_mut() {
                         feedback.ensure_not_canceled()?;
 
                         feature
                             .polygons
-                            .transform_inplace(|&[lng, lat, height, u, v]| {
-                                let (x, y, z) =
-                                    geodetic_to_geocentric(&ellipsoid, lng, lat, height);
-                                let v_xyz = DVec4::new(x, z, -y, 1.0);
-                                let v_enu = transform_matrix * v_xyz;
-                                [v_enu[0], v_enu[1], v_enu[2], u, v]
+                            .transform_chunks_inplace(&mut scratch, |chunk| {
+                                for c in chunk {
+                                    let [lng, lat, height, u, v] = *c;
+                                    let (x, y, z) =
+                                        geodetic_to_geocentric(&ellipsoid, lng, lat, height);
+                                    let v_xyz = DVec4::new(x, z, -y, 1.0);
+                                    let v_enu = transform_matrix * v_xyz;
+                                    *c = [v_enu[0], v_enu[1], v_enu[2], u, v];
+                                }
                             });
                     }
                 }
@@ -429,6 +472,8 @@ impl DataSink for ObjSink {
 
                 // Load all textures into the Packer
                 for (feature_id, feature) in features.iter().enumerate() {
+                    feedback.ensure_not_canceled()?;
+
                     for (poly_count, (mat, poly)) in feature
                         .polygons
                         .iter()
@@ -452,7 +497,10 @@ impl DataSink for ObjSink {
                                 .map(|(_, _, _, u, v)| (*u, *v))
                                 .collect::<Vec<(f64, f64)>>();
 
-                            let texture_uri = base_texture.uri.to_file_path().unwrap();
+                            // textures not on the local file system are not packed (the material color is used)
+                            let Some(texture_uri) = paths::url_to_path(&base_texture.uri) else {
+                                continue;
+                            };
                             let texture_size = texture_size_cache.get_or_insert(&texture_uri);
 
                             let downsample_scale = if self.limit_texture_resolution.unwrap_or(false)
@@ -487,6 +535,7 @@ impl DataSink for ObjSink {
                 let packer = packer.into_inner().unwrap();
 
                 // Packing the loaded textures into an atlas
+                feedback.ensure_not_canceled()?;
                 let packed = packer.pack(placer);
 
                 let exporter = JpegAtlasExporter::default();
@@ -498,11 +547,16 @@ impl DataSink for ObjSink {
                 // Obtain the UV coordinates placed in the atlas by specifying the ID
                 //  and apply them to the original polygon
                 for (feature_id, feature) in features.iter().enumerate() {
+                    feedback.ensure_not_canceled()?;
+
                     let mut feature_mesh = FeatureMesh {
                         vertices: Vec::new(),
                         uvs: Vec::new(),
+                        normals: Vec::new(),
                         primitives: HashMap::new(),
                     };
+                    let normals = VertexNormals::new(normal_mode, &feature.polygons);
+
                     for (poly_count, (mut mat, mut poly)) in feature
                         .polygons
                         .iter()
@@ -512,41 +566,17 @@ impl DataSink for ObjSink {
                         })
                         .enumerate()
                     {
-                        let original_vertices = poly
-                            .raw_coords()
-                            .iter()
-                            .map(|[x, y, z, u, v]| (*x, *y, *z, *u, *v))
-                            .collect::<Vec<(f64, f64, f64, f64, f64)>>();
-
                         let texture_id =
                             generate_texture_id(&base_folder_name, feature_id, poly_count);
 
                         if let Some(info) = packed.get_texture_info(&texture_id) {
                             // Place the texture in the atlas
-                            let atlas_placed_uv_coords = info
-                                .placed_uv_coords
-                                .iter()
-                                .map(|(u, v)| ({ *u }, { *v }))
-                                .collect::<Vec<(f64, f64)>>();
-                            let updated_vertices = original_vertices
-                                .iter()
-                                .zip(atlas_placed_uv_coords.iter())
-                                .map(|((x, y, z, _, _), (u, v))| (*x, *y, *z, *u, *v))
-                                .collect::<Vec<(f64, f64, f64, f64, f64)>>();
-
-                            // Apply the UV coordinates placed in the atlas to the original polygon
-                            poly.transform_inplace(|&[x, y, z, _, _]| {
-                                let (u, v) = updated_vertices
-                                    .iter()
-                                    .find(|(x_, y_, z_, _, _)| {
-                                        (*x_ - x).abs() < 1e-6
-                                            && (*y_ - y).abs() < 1e-6
-                                            && (*z_ - z).abs() < 1e-6
-                                    })
-                                    .map(|(_, _, _, u, v)| (*u, *v))
-                                    .unwrap();
-                                [x, y, z, u, v]
-                            });
+                            // (the placed UVs are in the same order as the vertices of the polygon)
+                            debug_assert_eq!(info.placed_uv_coords.len(), poly.raw_coords().len());
+                            poly.zip_transform_inplace(
+                                &info.placed_uv_coords,
+                                |&[x, y, z, _, _], &(u, v)| [x, y, z, u, v],
+                            );
 
                             let atlas_file_name = info.atlas_id.to_string();
 
@@ -557,9 +587,12 @@ impl DataSink for ObjSink {
                             mat = material::Material {
                                 base_color: mat.base_color,
                                 base_texture: Some(material::Texture {
-                                    uri: Url::from_file_path(atlas_uri).unwrap(),
+                                    uri: paths::file_url(&atlas_uri).unwrap(),
                                 }),
                             };
+                        } else {
+                            // the texture was not packed, fall back to the material color
+                            mat.base_texture = None;
                         }
 
                         let poly_material = mat;
@@ -613,6 +646,10 @@ impl DataSink for ObjSink {
                         buf3d.clear();
                         buf3d.extend(poly.raw_coords().iter().map(|&[x, y, z, _, _]| [x, y, z]));
 
+                        // (the degenerate polygons face up)
+                        let polygon_normal = calculate_normal(buf3d[..num_outer].iter().copied())
+                            .unwrap_or((0.0, 1.0, 0.0));
+
                         // triangulate
                         if project3d_to_2d(&buf3d, num_outer, &mut buf2d) {
                             earcutter.earcut(
@@ -627,8 +664,10 @@ impl DataSink for ObjSink {
                                 .or_default()
                                 .extend(index_buf.iter().map(|&idx| {
                                     let [x, y, z, u, v] = poly.raw_coords()[idx as usize];
+                                    let (nx, ny, nz) = normals.normal([x, y, z], polygon_normal);
                                     feature_mesh.vertices.push([x, y, z]);
                                     feature_mesh.uvs.push([u, v]);
+                                    feature_mesh.normals.push([nx, ny, nz]);
                                     (feature_mesh.vertices.len() - 1) as u32
                                 }));
                         }
@@ -636,6 +675,8 @@ impl DataSink for ObjSink {
                     all_meshes.insert(feature.feature_id.clone(), feature_mesh);
                 }
 
+                feedback.ensure_not_canceled()?;
+
                 packed.export(
                     exporter,
                     &atlas_dir,
@@ -643,6 +684,7 @@ impl DataSink for ObjSink {
                     config.width,
                     config.height,
                 );
+                texture_usage.add_atlas_dir(&atlas_dir);
 
                 feedback.ensure_not_canceled()?;
 
@@ -656,6 +698,7 @@ impl DataSink for ObjSink {
 
                 Ok::<(), PipelineError>(())
             })?;
+        texture_usage.report(feedback);
 
         Ok(())
     }
@@ -36,6 +36,7 @@ fn write_obj(
 
     let mut all_vertices = Vec::new();
     let mut all_uvs = Vec::new();
+    let mut all_normals = Vec::new();
     let mut mesh_data = Vec::new();
 
     for (feature_id, mesh) in meshes {
@@ -44,6 +45,7 @@ fn write_obj(
 
         all_vertices.extend_from_slice(&mesh.vertices);
         all_uvs.extend_from_slice(&mesh.uvs);
+        all_normals.extend_from_slice(&mesh.normals);
 
         mesh_data.push((feature_id, mesh, vertex_offset, uv_offset));
     }
@@ -58,6 +60,9 @@ fn write_obj(
     for uv in &all_uvs {
         writeln!(obj_writer, "vt {} {}", uv[0], uv[1])?;
     }
+    for normal in &all_normals {
+        writeln!(obj_writer, "vn {} {} {}", normal[0], normal[1], normal[2])?;
+    }
 
     let face_data: Vec<String> = mesh_data
         .par_iter()
@@ -77,15 +82,19 @@ fn write_obj(
                     continue;
                 }
 
+                // (the vertices have their own normals, in the same order as the positions)
                 for index in indices.chunks(3) {
                     local_obj.push(format!(
-                        "f {}/{} {}/{} {}/{}",
+                        "f {}/{}/{} {}/{}/{} {}/{}/{}",
                         index[0] + 1 + *vertex_offset as u32,
                         index[0] + 1 + *uv_offset as u32,
+                        index[0] + 1 + *vertex_offset as u32,
                         index[1] + 1 + *vertex_offset as u32,
                         index[1] + 1 + *uv_offset as u32,
+                        index[1] + 1 + *vertex_offset as u32,
                         index[2] + 1 + *vertex_offset as u32,
-                        index[2] + 1 + *uv_offset as u32
+                        index[2] + 1 + *uv_offset as u32,
+                        index[2] + 1 + *vertex_offset as u32
                     ));
                 }
             }
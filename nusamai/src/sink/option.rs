@@ -1,3 +1,5 @@
+use nusamai_projection::crs::{EpsgCode, EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_WGS84_GEOGRAPHIC_3D};
+
 use crate::parameters::{
     BooleanParameter, FileSystemPathParameter, ParameterDefinition, ParameterEntry, ParameterType,
 };
@@ -30,3 +32,41 @@ pub fn limit_texture_resolution_parameter(default_value: bool) -> ParameterDefin
         },
     }
 }
+
+pub fn geoid_parameter() -> ParameterDefinition {
+    ParameterDefinition {
+        key: "geoid".into(),
+        entry: ParameterEntry {
+            description: "Convert the orthometric heights to the ellipsoidal heights (GSIGEO2011)"
+                .into(),
+            required: false,
+            parameter: ParameterType::Boolean(BooleanParameter { value: Some(true) }),
+            label: Some("標高を楕円体高に変換する（ジオイド補正）".into()),
+        },
+    }
+}
+
+/// The output CRS of the sinks writing the geocentric coordinates:
+/// WGS 84 with the ellipsoidal heights, or JGD2011 with the orthometric heights if the geoid correction is disabled.
+pub fn geographic_output_epsg(geoid: bool) -> EpsgCode {
+    match geoid {
+        true => EPSG_WGS84_GEOGRAPHIC_3D,
+        false => EPSG_JGD2011_GEOGRAPHIC_3D,
+    }
+}
+
+pub fn style_parameter() -> ParameterDefinition {
+    ParameterDefinition {
+        key: "style".into(),
+        entry: ParameterEntry {
+            description: "Styling profile (JSON). The built-in profile is used if not specified"
+                .into(),
+            required: false,
+            parameter: ParameterType::FileSystemPath(FileSystemPathParameter {
+                value: None,
+                must_exist: true,
+            }),
+            label: Some("スタイル定義ファイル（JSON）".into()),
+        },
+    }
+}
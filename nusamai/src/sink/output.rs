@@ -0,0 +1,289 @@
+//! Output files with optional compression (gzip or zstd)
+//!
+//! The sinks writing sequential files (CityJSONSeq, GeoJSONL, CSV, the entity cache) define
+//! [`compression_parameter`] and write through [`OutputWriter`], so that the compression is
+//! chosen in the same way for all of them.
+//!
+//! [`remove_on_cancel`] removes the incomplete output of any sink when the conversion is canceled.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+
+use crate::{
+    parameters::{ParameterDefinition, ParameterEntry, ParameterType, StringParameter},
+    pipeline::{Feedback, PipelineError, Result},
+};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Chooses the compression from the option (`none`, `gzip`, `zstd`).
+    /// If the option is `auto`, it follows the extension of the output path (`.gz`, `.zst`).
+    pub fn negotiate(option: &str, path: &Path) -> Result<Self> {
+        match option {
+            "" | "auto" => Ok(Self::from_path(path)),
+            "none" => Ok(Self::None),
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            _ => Err(PipelineError::Other(format!(
+                "Unknown compression: {option} (expected auto, none, gzip or zstd)"
+            ))),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("gz") => Self::Gzip,
+            Some(ext) if ext.eq_ignore_ascii_case("zst") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gz"),
+            Self::Zstd => Some("zst"),
+        }
+    }
+
+    /// Appends the extension of the compression (e.g. `bldg_Building.csv` -> `bldg_Building.csv.gz`)
+    /// unless the path already has it
+    pub fn apply_extension(&self, path: &Path) -> PathBuf {
+        match self.extension() {
+            Some(ext) if Self::from_path(path) != *self => {
+                let mut path = path.as_os_str().to_owned();
+                path.push(".");
+                path.push(ext);
+                path.into()
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+}
+
+pub fn compression_parameter() -> ParameterDefinition {
+    ParameterDefinition {
+        key: "compression".into(),
+        entry: ParameterEntry {
+            description: "Compression of the output files: auto (by the extension .gz/.zst), none, gzip, zstd".into(),
+            required: false,
+            parameter: ParameterType::String(StringParameter {
+                value: Some("auto".into()),
+            }),
+            label: Some("圧縮形式（auto, none, gzip, zstd）".into()),
+        },
+    }
+}
+
+enum Inner {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+/// A buffered output file, compressed as requested. [`OutputWriter::finish`] must be called at the end.
+pub struct OutputWriter {
+    inner: Inner,
+}
+
+impl OutputWriter {
+    pub fn create(path: &Path, compression: Compression) -> io::Result<Self> {
+        let file = BufWriter::with_capacity(BUFFER_SIZE, File::create(path)?);
+        let inner = match compression {
+            Compression::None => Inner::Plain(file),
+            Compression::Gzip => Inner::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => Inner::Zstd(zstd::Encoder::new(file, 0)?),
+        };
+        Ok(Self { inner })
+    }
+
+    /// Writes the end of the compressed stream and flushes the file
+    pub fn finish(self) -> io::Result<()> {
+        let mut file = match self.inner {
+            Inner::Plain(file) => file,
+            Inner::Gzip(encoder) => encoder.finish()?,
+            Inner::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            Inner::Plain(w) => w.write(buf),
+            Inner::Gzip(w) => w.write(buf),
+            Inner::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Plain(w) => w.write_all(buf),
+            Inner::Gzip(w) => w.write_all(buf),
+            Inner::Zstd(w) => w.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Plain(w) => w.flush(),
+            Inner::Gzip(w) => w.flush(),
+            Inner::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// Opens a file written by [`OutputWriter`], decompressing it if it is compressed (detected by the magic bytes)
+pub fn open_decompressed(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    let mut reader = BufReader::with_capacity(BUFFER_SIZE, File::open(path)?);
+    let head = reader.fill_buf()?;
+    if head.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else if head.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(zstd::Decoder::with_buffer(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Runs `write` and removes the output written so far if the conversion is canceled, not to leave
+/// incomplete data behind.
+///
+/// A file at `path` is removed in any case (it is overwritten by the sink), while a directory that
+/// already existed is kept since it may contain other files.
+pub fn remove_on_cancel<T>(
+    path: &Path,
+    feedback: &Feedback,
+    write: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let existing_dir = path.is_dir();
+    let result = write();
+    if feedback.is_canceled() || matches!(result, Err(PipelineError::Canceled)) {
+        let removed = if existing_dir {
+            Ok(false)
+        } else if path.is_dir() {
+            std::fs::remove_dir_all(path).map(|_| true)
+        } else if path.exists() {
+            std::fs::remove_file(path).map(|_| true)
+        } else {
+            Ok(false)
+        };
+        match removed {
+            Ok(true) => feedback.info(format!("Removed the incomplete output: {:?}", path)),
+            Ok(false) => {}
+            Err(err) => feedback.warn(format!(
+                "Failed to remove the incomplete output {:?}: {}",
+                path, err
+            )),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let path = Path::new("out/features.jsonl.zst");
+        assert_eq!(
+            Compression::negotiate("auto", path).unwrap(),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::negotiate("gzip", path).unwrap(),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::negotiate("auto", Path::new("out.jsonl")).unwrap(),
+            Compression::None
+        );
+        assert!(Compression::negotiate("lzma", path).is_err());
+
+        assert_eq!(
+            Compression::Zstd.apply_extension(path),
+            PathBuf::from("out/features.jsonl.zst")
+        );
+        assert_eq!(
+            Compression::Gzip.apply_extension(Path::new("bldg_Building.csv")),
+            PathBuf::from("bldg_Building.csv.gz")
+        );
+        assert_eq!(
+            Compression::None.apply_extension(Path::new("bldg_Building.csv")),
+            PathBuf::from("bldg_Building.csv")
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = "{\"type\":\"Feature\"}\n".repeat(100);
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let path = compression.apply_extension(&dir.path().join("features.jsonl"));
+            let mut writer = OutputWriter::create(&path, compression).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+            writer.finish().unwrap();
+
+            let size = std::fs::metadata(&path).unwrap().len() as usize;
+            match compression {
+                Compression::None => assert_eq!(size, content.len()),
+                _ => assert!(size < content.len()),
+            }
+
+            let mut decompressed = String::new();
+            open_decompressed(&path)
+                .unwrap()
+                .read_to_string(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, content);
+        }
+    }
+
+    #[test]
+    fn test_remove_on_cancel() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, feedback, canceller) = crate::pipeline::feedback::watcher();
+
+        // kept if the conversion completes
+        let file = dir.path().join("out.gpkg");
+        remove_on_cancel(&file, &feedback, || Ok(std::fs::write(&file, b"data")?)).unwrap();
+        assert!(file.exists());
+
+        // removed if the sink is canceled
+        let result = remove_on_cancel(&file, &feedback, || {
+            std::fs::write(&file, b"partial")?;
+            Err::<(), _>(PipelineError::Canceled)
+        });
+        assert!(matches!(result, Err(PipelineError::Canceled)));
+        assert!(!file.exists());
+
+        // the new directory is removed, but the existing one is kept
+        canceller.cancel();
+        let tiles = dir.path().join("tiles");
+        remove_on_cancel(&tiles, &feedback, || {
+            Ok(std::fs::create_dir_all(tiles.join("0/0"))?)
+        })
+        .unwrap();
+        assert!(!tiles.exists());
+        remove_on_cancel(dir.path(), &feedback, || Ok(())).unwrap();
+        assert!(dir.path().exists());
+    }
+}
@@ -0,0 +1,180 @@
+//! Handling of the output that already exists
+//!
+//! The policy is applied before a conversion starts, in the same way for all the sinks:
+//!
+//! - [`OverwritePolicy::Error`]: the conversion is not started if the output exists.
+//! - [`OverwritePolicy::Overwrite`]: the existing output is removed. A directory is cleared only if it is
+//!   empty or the output of a previous conversion (with the manifest), not to delete unrelated files.
+//! - [`OverwritePolicy::Merge`]: the features are added to the existing output. Supported by the outputs
+//!   written into a directory (the files with the same paths are replaced) and by the sinks having the
+//!   `update` option (GeoPackage). Not supported by the tiled outputs, as the tiles of the existing output
+//!   would be replaced by the new ones instead of being merged.
+
+use std::{fs, path::Path};
+
+use super::manifest::MANIFEST_FILENAME;
+use crate::{
+    parameters::Parameters,
+    pipeline::{PipelineError, Result},
+};
+
+/// The sinks writing the tiles (the same tiles of the existing output and the new one are not merged)
+const TILED_SINKS: &[&str] = &["3dtiles", "i3s", "mvt", "quantizedmesh", "terrain"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    #[default]
+    Error,
+    Overwrite,
+    Merge,
+}
+
+/// Applies the policy to the output path of the sink (`sink_id` is the `id_name` of the sink).
+///
+/// Returns the sink options to be added for the policy (e.g. `update=true` to merge into a GeoPackage).
+pub fn prepare_output(
+    output_path: &Path,
+    policy: OverwritePolicy,
+    sink_id: &str,
+    sink_params: &Parameters,
+) -> Result<Vec<(String, String)>> {
+    let has_update_option = sink_params.get("update").is_some();
+    let exists = output_path.exists() && !is_empty_dir(output_path);
+    match policy {
+        _ if !exists => Ok(Vec::new()),
+        OverwritePolicy::Error => Err(PipelineError::Other(format!(
+            "The output already exists: {:?} (use --overwrite to replace it, or --merge to add the features to it)",
+            output_path
+        ))),
+        OverwritePolicy::Overwrite if output_path.is_dir() => {
+            clear_previous_output(output_path)?;
+            Ok(Vec::new())
+        }
+        OverwritePolicy::Overwrite => {
+            fs::remove_file(output_path)?;
+            Ok(Vec::new())
+        }
+        OverwritePolicy::Merge if TILED_SINKS.contains(&sink_id) => Err(PipelineError::Other(format!(
+            "The features cannot be merged into the existing tiles: {:?} (convert into a new directory instead; the outputs of MVT and 3D Tiles can be combined with `nusamai merge`)",
+            output_path
+        ))),
+        OverwritePolicy::Merge if output_path.is_dir() => Ok(Vec::new()),
+        OverwritePolicy::Merge if has_update_option => Ok(vec![("update".into(), "true".into())]),
+        OverwritePolicy::Merge => Err(PipelineError::Other(format!(
+            "The features cannot be merged into the existing file of this format: {:?}",
+            output_path
+        ))),
+    }
+}
+
+/// Returns true if the path is a directory that is not empty and is not the output of a previous conversion
+/// (which is not cleared with [`OverwritePolicy::Overwrite`])
+pub fn is_unknown_directory(path: &Path) -> bool {
+    path.is_dir() && !is_empty_dir(path) && !path.join(MANIFEST_FILENAME).is_file()
+}
+
+fn is_empty_dir(path: &Path) -> bool {
+    fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none())
+}
+
+/// Removes the files listed in the manifest of the previous conversion, and the directories left empty
+fn clear_previous_output(dir: &Path) -> Result<()> {
+    let manifest_path = dir.join(MANIFEST_FILENAME);
+    let Ok(manifest) = fs::read(&manifest_path) else {
+        return Err(PipelineError::Other(format!(
+            "The output directory is not empty and is not the output of a previous conversion (no {}): {:?}",
+            MANIFEST_FILENAME, dir
+        )));
+    };
+    let manifest: serde_json::Value = serde_json::from_slice(&manifest).map_err(|err| {
+        PipelineError::Other(format!("Invalid manifest {:?}: {}", manifest_path, err))
+    })?;
+    let Some(files) = manifest["files"].as_object() else {
+        return Err(PipelineError::Other(format!(
+            "Invalid manifest {:?}: no files",
+            manifest_path
+        )));
+    };
+
+    for relpath in files.keys() {
+        // (the paths outside of the directory are ignored)
+        if relpath.split('/').any(|c| c == ".." || c.is_empty()) {
+            continue;
+        }
+        let path = dir.join(relpath);
+        if path.is_file() {
+            fs::remove_file(&path)?;
+        }
+        // remove the parent directories left empty
+        let mut parent = path.parent();
+        while let Some(p) = parent.filter(|p| *p != dir && is_empty_dir(p)) {
+            fs::remove_dir(p)?;
+            parent = p.parent();
+        }
+    }
+    fs::remove_file(&manifest_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{gpkg::GpkgSinkProvider, manifest::Manifest, DataSinkProvider};
+
+    #[test]
+    fn test_prepare_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = Parameters::new();
+
+        // nothing to do for a new output
+        let file = dir.path().join("out.gpkg");
+        for policy in [OverwritePolicy::Error, OverwritePolicy::Merge] {
+            assert!(prepare_output(&file, policy, "gpkg", &params)
+                .unwrap()
+                .is_empty());
+        }
+
+        fs::write(&file, b"{}").unwrap();
+        assert!(prepare_output(&file, OverwritePolicy::Error, "gpkg", &params).is_err());
+        assert!(prepare_output(&file, OverwritePolicy::Merge, "gpkg", &params).is_err());
+        prepare_output(&file, OverwritePolicy::Overwrite, "gpkg", &params).unwrap();
+        assert!(!file.exists());
+
+        // merging into a GeoPackage turns on the `update` option
+        let params = GpkgSinkProvider {}.sink_options();
+        fs::write(&file, b"").unwrap();
+        assert_eq!(
+            prepare_output(&file, OverwritePolicy::Merge, "gpkg", &params).unwrap(),
+            [("update".to_string(), "true".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_overwrite_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = Parameters::new();
+        let tiles = dir.path().join("tiles");
+        fs::create_dir_all(tiles.join("0/0")).unwrap();
+        fs::write(tiles.join("0/0/0.pbf"), b"a").unwrap();
+        fs::write(tiles.join("notes.txt"), b"kept").unwrap();
+
+        // an unknown directory is not cleared
+        assert!(is_unknown_directory(&tiles));
+        assert!(prepare_output(&tiles, OverwritePolicy::Overwrite, "geojson", &params).is_err());
+        assert!(tiles.join("0/0/0.pbf").exists());
+
+        // merged into the existing directory as it is, except the tiles
+        prepare_output(&tiles, OverwritePolicy::Merge, "geojson", &params).unwrap();
+        assert!(prepare_output(&tiles, OverwritePolicy::Merge, "mvt", &params).is_err());
+
+        // only the files of the previous conversion are removed
+        let manifest = Manifest::new();
+        manifest.add("0/0/0.pbf", b"a");
+        manifest.write(&tiles).unwrap();
+        assert!(!is_unknown_directory(&tiles));
+        prepare_output(&tiles, OverwritePolicy::Overwrite, "mvt", &params).unwrap();
+        assert!(!tiles.join("0").exists());
+        assert!(!tiles.join(MANIFEST_FILENAME).exists());
+        assert!(tiles.join("notes.txt").exists());
+    }
+}
@@ -0,0 +1,265 @@
+//! Columnar (Apache Arrow) encoding of the features, shared by the GeoParquet and Arrow IPC outputs
+
+use std::{collections::HashMap, sync::Arc};
+
+use arrow_array::{
+    builder::{BinaryBuilder, BooleanBuilder, Float64Builder, Int64Builder, StringBuilder},
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema as ArrowSchema, SchemaRef};
+use nusamai_citygml::{
+    object::{Map, Value},
+    schema::{Attribute, TypeDef, TypeRef},
+};
+
+/// Name of the geometry column
+pub const GEOMETRY_COLUMN: &str = "geometry";
+
+/// A row of a table: the feature ID, the attributes and the geometry (WKB, or WKT for CSV)
+pub struct Row<G = Vec<u8>> {
+    pub id: Option<String>,
+    pub attributes: Map,
+    pub geometry: Option<G>,
+}
+
+enum ColumnBuilder {
+    Utf8(StringBuilder),
+    Int64(Int64Builder),
+    Float64(Float64Builder),
+    Boolean(BooleanBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(attr: &Attribute) -> Self {
+        // multiple values are stored as JSON arrays
+        if attr.max_occurs != Some(1) {
+            return Self::Utf8(StringBuilder::new());
+        }
+        match attr.type_ref {
+            TypeRef::Integer | TypeRef::NonNegativeInteger => Self::Int64(Int64Builder::new()),
+            TypeRef::Double | TypeRef::Measure => Self::Float64(Float64Builder::new()),
+            TypeRef::Boolean => Self::Boolean(BooleanBuilder::new()),
+            _ => Self::Utf8(StringBuilder::new()),
+        }
+    }
+
+    fn data_type(&self) -> DataType {
+        match self {
+            Self::Utf8(_) => DataType::Utf8,
+            Self::Int64(_) => DataType::Int64,
+            Self::Float64(_) => DataType::Float64,
+            Self::Boolean(_) => DataType::Boolean,
+        }
+    }
+
+    fn append(&mut self, value: Option<&Value>) {
+        match self {
+            Self::Utf8(builder) => builder.append_option(value.and_then(value_to_string)),
+            Self::Int64(builder) => builder.append_option(value.and_then(|value| match value {
+                Value::Integer(i) => Some(*i),
+                Value::NonNegativeInteger(u) => i64::try_from(*u).ok(),
+                _ => None,
+            })),
+            Self::Float64(builder) => builder.append_option(value.and_then(|value| match value {
+                Value::Double(d) => Some(*d),
+                Value::Measure(m) => Some(m.value()),
+                Value::Integer(i) => Some(*i as f64),
+                _ => None,
+            })),
+            Self::Boolean(builder) => builder.append_option(value.and_then(|value| match value {
+                Value::Boolean(b) => Some(*b),
+                _ => None,
+            })),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Self::Utf8(builder) => Arc::new(builder.finish()),
+            Self::Int64(builder) => Arc::new(builder.finish()),
+            Self::Float64(builder) => Arc::new(builder.finish()),
+            Self::Boolean(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+/// The text of an attribute value (the arrays and the objects as JSON), also used for the CSV columns
+pub fn value_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Code(c) => Some(c.value().to_string()),
+        Value::Uri(u) => Some(u.value().to_string()),
+        Value::Date(d) => Some(d.to_string()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::NonNegativeInteger(u) => Some(u.to_string()),
+        Value::Double(d) => Some(d.to_string()),
+        Value::Measure(m) => Some(m.value().to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        Value::Point(_) => None,
+        Value::Array(_) | Value::Object(_) => Some(value.to_attribute_json().to_string()),
+    }
+}
+
+/// Accumulates the rows of a feature (or data) type into Arrow record batches
+pub struct ColumnarTable {
+    schema: SchemaRef,
+    id_builder: StringBuilder,
+    attributes: Vec<(String, ColumnBuilder)>,
+    geometry_builder: Option<BinaryBuilder>,
+    num_rows: usize,
+}
+
+impl ColumnarTable {
+    /// Prepares the columns from the type definition. `metadata` is attached to the Arrow schema.
+    pub fn new(typedef: Option<&TypeDef>, metadata: HashMap<String, String>) -> Self {
+        let (attributes, has_geometry) = match typedef {
+            Some(TypeDef::Feature(feature)) => (Some(&feature.attributes), true),
+            Some(TypeDef::Data(data)) => (Some(&data.attributes), false),
+            _ => (None, false),
+        };
+        let attributes: Vec<(String, ColumnBuilder)> = attributes
+            .into_iter()
+            .flatten()
+            .filter(|(name, attr)| {
+                name.as_str() != "id"
+                    && name.as_str() != GEOMETRY_COLUMN
+                    && !matches!(attr.type_ref, TypeRef::Point)
+            })
+            .map(|(name, attr)| (name.clone(), ColumnBuilder::new(attr)))
+            .collect();
+
+        let mut fields = vec![Field::new("id", DataType::Utf8, true)];
+        fields.extend(
+            attributes
+                .iter()
+                .map(|(name, builder)| Field::new(name, builder.data_type(), true)),
+        );
+        if has_geometry {
+            fields.push(Field::new(GEOMETRY_COLUMN, DataType::Binary, true));
+        }
+
+        Self {
+            schema: Arc::new(ArrowSchema::new(fields).with_metadata(metadata)),
+            id_builder: StringBuilder::new(),
+            attributes,
+            geometry_builder: has_geometry.then(BinaryBuilder::new),
+            num_rows: 0,
+        }
+    }
+
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.num_rows
+    }
+
+    pub fn append(&mut self, row: &Row) {
+        self.id_builder.append_option(row.id.as_deref());
+        for (name, builder) in self.attributes.iter_mut() {
+            builder.append(row.attributes.get(name));
+        }
+        if let Some(geometry_builder) = &mut self.geometry_builder {
+            geometry_builder.append_option(row.geometry.as_deref());
+        }
+        self.num_rows += 1;
+    }
+
+    /// Takes the accumulated rows as a record batch
+    pub fn take_batch(&mut self) -> Result<RecordBatch, ArrowError> {
+        let mut columns: Vec<ArrayRef> = vec![Arc::new(self.id_builder.finish())];
+        columns.extend(
+            self.attributes
+                .iter_mut()
+                .map(|(_, builder)| builder.finish()),
+        );
+        if let Some(geometry_builder) = &mut self.geometry_builder {
+            columns.push(Arc::new(geometry_builder.finish()));
+        }
+        self.num_rows = 0;
+        RecordBatch::try_new(self.schema.clone(), columns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{Array, Float64Array, Int64Array, StringArray};
+    use nusamai_citygml::schema::FeatureTypeDef;
+
+    use super::*;
+
+    #[test]
+    fn test_columnar_table() {
+        let mut feature = FeatureTypeDef::default();
+        feature
+            .attributes
+            .insert("name".into(), Attribute::new(TypeRef::String));
+        feature
+            .attributes
+            .insert("storeys".into(), Attribute::new(TypeRef::Integer));
+        feature
+            .attributes
+            .insert("height".into(), Attribute::new(TypeRef::Measure));
+        let typedef = TypeDef::Feature(feature);
+
+        let mut table = ColumnarTable::new(Some(&typedef), HashMap::new());
+
+        let mut attributes = Map::default();
+        attributes.insert("name".into(), Value::String("a".into()));
+        attributes.insert("storeys".into(), Value::Integer(3));
+        table.append(&Row {
+            id: Some("bldg_1".into()),
+            attributes,
+            geometry: Some(vec![1, 2, 3]),
+        });
+        table.append(&Row {
+            id: Some("bldg_2".into()),
+            attributes: Map::default(),
+            geometry: None,
+        });
+        assert_eq!(table.num_rows(), 2);
+
+        let batch = table.take_batch().unwrap();
+        assert_eq!(table.num_rows(), 0);
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.num_columns(), 5);
+
+        let ids = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(ids.value(1), "bldg_2");
+        let storeys = batch
+            .column_by_name("storeys")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(storeys.value(0), 3);
+        assert!(storeys.is_null(1));
+        let height = batch
+            .column_by_name("height")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert!(height.is_null(0));
+        assert!(batch.column_by_name(GEOMETRY_COLUMN).unwrap().is_null(1));
+    }
+
+    #[test]
+    fn test_value_to_string() {
+        assert_eq!(value_to_string(&Value::Integer(-3)).unwrap(), "-3");
+        assert_eq!(value_to_string(&Value::Boolean(true)).unwrap(), "true");
+        assert_eq!(
+            value_to_string(&Value::Array(vec![
+                Value::String("a".into()),
+                Value::Double(1.5)
+            ]))
+            .unwrap(),
+            r#"["a",1.5]"#
+        );
+    }
+}
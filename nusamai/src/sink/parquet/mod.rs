@@ -0,0 +1,384 @@
+//! GeoParquet / Arrow IPC sink
+//!
+//! Writes a table for each feature (or data) type. Both formats share the columnar encoding in the `columnar` module.
+
+pub(crate) mod columnar;
+mod projjson;
+
+use std::{collections::HashMap, fs::File, path::PathBuf, str::FromStr};
+
+use arrow_array::RecordBatch;
+use arrow_schema::SchemaRef;
+use columnar::{ColumnarTable, Row, GEOMETRY_COLUMN};
+use indexmap::IndexMap;
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::{Schema, TypeDef},
+    GeometryType,
+};
+use nusamai_gpkg::geometry::write_indexed_multipolygon_wkb;
+use nusamai_plateau::Entity;
+use nusamai_projection::crs::{EpsgCode, EPSG_WGS84_GEOGRAPHIC_2D, EPSG_WGS84_GEOGRAPHIC_3D};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::Compression,
+    file::{metadata::KeyValue, properties::WriterProperties},
+};
+use rayon::prelude::*;
+
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer,
+    transformer::{
+        building_adjacency_config, name_columns_config, prefix_config, solar_attributes_config,
+        underground_config, use_lod_config, TransformerSettings,
+    },
+};
+
+use super::option::output_parameter;
+
+/// Number of rows written to a file at once (the size of the Parquet row groups / Arrow record batches)
+pub(crate) const ROWS_PER_BATCH: usize = 8192;
+
+/// Output file format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnarFormat {
+    /// GeoParquet (.parquet)
+    Parquet,
+    /// Arrow IPC file, a.k.a. Feather V2 (.arrow)
+    Arrow,
+}
+
+impl ColumnarFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Parquet => "parquet",
+            Self::Arrow => "arrow",
+        }
+    }
+}
+
+impl FromStr for ColumnarFormat {
+    type Err = PipelineError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "parquet" => Ok(Self::Parquet),
+            "arrow" | "feather" => Ok(Self::Arrow),
+            _ => Err(PipelineError::Other(format!(
+                "Unknown format: {s} (expected 'parquet' or 'arrow')"
+            ))),
+        }
+    }
+}
+
+pub struct GeoParquetSinkProvider {}
+
+impl DataSinkProvider for GeoParquetSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "parquet".to_string(),
+            name: "GeoParquet / Arrow IPC".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "format".into(),
+            entry: ParameterEntry {
+                description: "Output format ('parquet' for GeoParquet, 'arrow' for Arrow IPC)"
+                    .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter {
+                    value: Some("parquet".into()),
+                }),
+                label: Some("出力形式 (parquet / arrow)".into()),
+            },
+        });
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(use_lod_config("max_lod", None));
+        settings.insert(underground_config());
+        settings.insert(solar_attributes_config());
+        settings.insert(building_adjacency_config());
+        settings.insert(prefix_config(&[]));
+        settings.insert(name_columns_config());
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let format = get_parameter_value!(params, "format", String);
+        let transform_settings = self.transformer_options();
+
+        Box::<GeoParquetSink>::new(GeoParquetSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            format: format.clone().unwrap_or_else(|| "parquet".into()),
+            transform_settings,
+        })
+    }
+}
+
+pub struct GeoParquetSink {
+    output_path: PathBuf,
+    /// `parquet` or `arrow` (validated when the sink runs)
+    format: String,
+    transform_settings: TransformerSettings,
+}
+
+impl DataSink for GeoParquetSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        let default_requirements = DataRequirements {
+            tree_flattening: transformer::TreeFlatteningSpec::Flatten {
+                feature: transformer::FeatureFlatteningOption::AllExceptThematicSurfaces,
+                data: transformer::DataFlatteningOption::TopLevelOnly,
+                object: transformer::ObjectFlatteningOption::None,
+            },
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        let format = ColumnarFormat::from_str(&self.format)?;
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+
+        let (ra, rb) = rayon::join(
+            || {
+                upstream
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(sender, |sender, parcel| {
+                        feedback.ensure_not_canceled()?;
+
+                        let Some((typename, row)) = entity_to_row(feedback, parcel.entity) else {
+                            return Ok(());
+                        };
+                        if sender.send((typename, row)).is_err() {
+                            return Err(PipelineError::Canceled);
+                        };
+                        Ok(())
+                    })
+            },
+            || {
+                std::fs::create_dir_all(&self.output_path)?;
+
+                let mut tables = IndexMap::<String, (ColumnarTable, BatchWriter)>::new();
+                for (typename, row) in receiver {
+                    feedback.ensure_not_canceled()?;
+
+                    if !tables.contains_key(&typename) {
+                        let path = self.output_path.join(format!(
+                            "{}.{}",
+                            typename.replace(':', "_"),
+                            format.extension()
+                        ));
+                        let (table, writer) =
+                            create_table(format, &path, schema.types.get(&typename), schema.epsg)?;
+                        tables.insert(typename.clone(), (table, writer));
+                    }
+
+                    let (table, writer) = tables.get_mut(&typename).unwrap();
+                    table.append(&row);
+                    if table.num_rows() >= ROWS_PER_BATCH {
+                        writer.write(&table.take_batch().map_err(arrow_error)?)?;
+                    }
+                }
+
+                for (_, (mut table, mut writer)) in tables {
+                    feedback.ensure_not_canceled()?;
+
+                    if table.num_rows() > 0 {
+                        writer.write(&table.take_batch().map_err(arrow_error)?)?;
+                    }
+                    writer.finish()?;
+                }
+
+                Ok::<(), PipelineError>(())
+            },
+        );
+
+        match ra {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+        match rb {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+
+        Ok(())
+    }
+}
+
+/// Converts an entity into a table row (with the WKB geometry). Returns `None` if the entity cannot be written.
+pub(crate) fn entity_to_row(feedback: &Feedback, entity: Entity) -> Option<(String, Row)> {
+    let encode_wkb = |vertices: &[[f64; 3]], mpoly: &flatgeom::MultiPolygon<u32>| {
+        let mut bytes = Vec::new();
+        write_indexed_multipolygon_wkb(&mut bytes, vertices, mpoly).ok()?;
+        Some(bytes)
+    };
+    entity_to_row_with(feedback, entity, Some(encode_wkb))
+}
+
+/// Converts an entity into a table row, with the geometry encoded by `encode_geometry` (not collected without it).
+///
+/// Shared by the sinks writing the flattened attributes into the columns (GeoParquet, Arrow IPC, DuckDB and CSV).
+pub(crate) fn entity_to_row_with<G>(
+    feedback: &Feedback,
+    entity: Entity,
+    encode_geometry: Option<impl FnOnce(&[[f64; 3]], &flatgeom::MultiPolygon<u32>) -> Option<G>>,
+) -> Option<(String, Row<G>)> {
+    let Value::Object(obj) = entity.root else {
+        return None;
+    };
+
+    match &obj.stereotype {
+        ObjectStereotype::Feature { id, geometries } => {
+            let geometry = encode_geometry.and_then(|encode| {
+                let geom_store = entity.geometry_store.read().unwrap();
+                let mut mpoly = flatgeom::MultiPolygon::new();
+                for entry in geometries {
+                    match entry.ty {
+                        GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle => {
+                            for idx_poly in geom_store
+                                .multipolygon
+                                .iter_range(entry.pos as usize..(entry.pos + entry.len) as usize)
+                            {
+                                mpoly.push(&idx_poly);
+                            }
+                        }
+                        // TODO: implement
+                        GeometryType::Curve | GeometryType::Point => {}
+                    }
+                }
+                match mpoly.is_empty() {
+                    true => None,
+                    false => encode(&geom_store.vertices, &mpoly),
+                }
+            });
+
+            Some((
+                obj.typename.to_string(),
+                Row {
+                    id: Some(id.clone()),
+                    attributes: obj.attributes,
+                    geometry,
+                },
+            ))
+        }
+        ObjectStereotype::Data => Some((
+            obj.typename.to_string(),
+            Row {
+                id: None,
+                attributes: obj.attributes,
+                geometry: None,
+            },
+        )),
+        ObjectStereotype::Object { id } => {
+            feedback.warn(format!(
+                "ObjectStereotype::Object is not supported yet: id = {}",
+                id
+            ));
+            None
+        }
+    }
+}
+
+/// The `geo` metadata of GeoParquet (https://geoparquet.org/releases/v1.1.0/)
+///
+/// `crs` is omitted for WGS 84 (which means OGC:CRS84, as the coordinates are always in the longitude-latitude order),
+/// and is null (unknown) for the CRS without the PROJJSON definition.
+fn geo_metadata(epsg: Option<EpsgCode>) -> String {
+    let mut column = serde_json::json!({
+        "encoding": "WKB",
+        "geometry_types": ["MultiPolygon Z"],
+    });
+    match epsg {
+        Some(EPSG_WGS84_GEOGRAPHIC_2D | EPSG_WGS84_GEOGRAPHIC_3D) => {}
+        Some(epsg) => column["crs"] = projjson::projjson(epsg).into(),
+        None => column["crs"] = serde_json::Value::Null,
+    }
+    serde_json::json!({
+        "version": "1.1.0",
+        "primary_column": GEOMETRY_COLUMN,
+        "columns": { GEOMETRY_COLUMN: column },
+    })
+    .to_string()
+}
+
+fn create_table(
+    format: ColumnarFormat,
+    path: &std::path::Path,
+    typedef: Option<&TypeDef>,
+    epsg: Option<EpsgCode>,
+) -> Result<(ColumnarTable, BatchWriter)> {
+    let geo = matches!(typedef, Some(TypeDef::Feature(_))).then(|| geo_metadata(epsg));
+
+    let file = File::create(path)?;
+    match format {
+        ColumnarFormat::Parquet => {
+            let table = ColumnarTable::new(typedef, HashMap::new());
+            let props = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .set_key_value_metadata(geo.map(|geo| vec![KeyValue::new("geo".into(), geo)]))
+                .build();
+            let writer =
+                ArrowWriter::try_new(file, table.schema(), Some(props)).map_err(parquet_error)?;
+            Ok((table, BatchWriter::Parquet(writer)))
+        }
+        ColumnarFormat::Arrow => {
+            // The Arrow IPC file carries the same `geo` metadata in its schema
+            let metadata = geo.map(|geo| ("geo".into(), geo)).into_iter().collect();
+            let table = ColumnarTable::new(typedef, metadata);
+            let schema: SchemaRef = table.schema();
+            let writer =
+                arrow_ipc::writer::FileWriter::try_new(file, &schema).map_err(arrow_error)?;
+            Ok((table, BatchWriter::Arrow(writer)))
+        }
+    }
+}
+
+/// Writes the record batches to a file of the chosen format
+enum BatchWriter {
+    Parquet(ArrowWriter<File>),
+    Arrow(arrow_ipc::writer::FileWriter<File>),
+}
+
+impl BatchWriter {
+    fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        match self {
+            Self::Parquet(writer) => writer.write(batch).map_err(parquet_error),
+            Self::Arrow(writer) => writer.write(batch).map_err(arrow_error),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Parquet(writer) => writer.close().map(|_| ()).map_err(parquet_error),
+            Self::Arrow(mut writer) => writer.finish().map_err(arrow_error),
+        }
+    }
+}
+
+pub(crate) fn arrow_error(err: arrow_schema::ArrowError) -> PipelineError {
+    PipelineError::Other(format!("Arrow error: {err}"))
+}
+
+fn parquet_error(err: parquet::errors::ParquetError) -> PipelineError {
+    PipelineError::Other(format!("Parquet error: {err}"))
+}
@@ -0,0 +1,163 @@
+//! PROJJSON definitions of the output CRSs, for the `crs` of the GeoParquet metadata (https://proj.org/specifications/projjson.html)
+
+use nusamai_projection::{
+    crs::{
+        EpsgCode, EPSG_JGD2011_GEOGRAPHIC_2D, EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_JGD2011_JPRECT_I,
+        EPSG_JGD2011_JPRECT_I_JGD2011_HEIGHT, EPSG_JGD2011_JPRECT_XIII_JGD2011_HEIGHT,
+        EPSG_JGD2011_JPRECT_XIX,
+    },
+    jprect::JPRZone,
+};
+use serde_json::{json, Value};
+
+const SCHEMA: &str = "https://proj.org/schemas/v0.7/projjson.schema.json";
+
+/// JGD2011 (vertical) height
+const EPSG_JGD2011_VERTICAL_HEIGHT: EpsgCode = 6695;
+
+/// The PROJJSON of the CRS, or None if it is not defined here
+pub fn projjson(epsg: EpsgCode) -> Option<Value> {
+    let mut crs = match epsg {
+        EPSG_JGD2011_GEOGRAPHIC_2D => jgd2011_geographic(),
+        EPSG_JGD2011_GEOGRAPHIC_3D => compound(
+            epsg,
+            "JGD2011 + JGD2011 (vertical) height",
+            jgd2011_geographic(),
+        ),
+        EPSG_JGD2011_JPRECT_I..=EPSG_JGD2011_JPRECT_XIX => jprect(&JPRZone::from_epsg(epsg)?),
+        EPSG_JGD2011_JPRECT_I_JGD2011_HEIGHT..=EPSG_JGD2011_JPRECT_XIII_JGD2011_HEIGHT => {
+            let horizontal = jprect(&JPRZone::from_epsg(epsg)?);
+            let name = format!(
+                "{} + JGD2011 (vertical) height",
+                horizontal["name"].as_str()?
+            );
+            compound(epsg, &name, horizontal)
+        }
+        _ => return None,
+    };
+    crs["$schema"] = SCHEMA.into();
+    Some(crs)
+}
+
+fn id(code: EpsgCode) -> Value {
+    json!({ "authority": "EPSG", "code": code })
+}
+
+fn axis(name: &str, abbreviation: &str, direction: &str, unit: &str) -> Value {
+    json!({ "name": name, "abbreviation": abbreviation, "direction": direction, "unit": unit })
+}
+
+fn jgd2011_geographic() -> Value {
+    json!({
+        "type": "GeographicCRS",
+        "name": "JGD2011",
+        "datum": {
+            "type": "GeodeticReferenceFrame",
+            "name": "Japanese Geodetic Datum 2011",
+            "ellipsoid": {
+                "name": "GRS 1980",
+                "semi_major_axis": 6378137,
+                "inverse_flattening": 298.257222101
+            },
+            "id": id(1128)
+        },
+        "coordinate_system": {
+            "subtype": "ellipsoidal",
+            "axis": [
+                axis("Geodetic latitude", "Lat", "north", "degree"),
+                axis("Geodetic longitude", "Lon", "east", "degree")
+            ]
+        },
+        "id": id(EPSG_JGD2011_GEOGRAPHIC_2D)
+    })
+}
+
+/// JGD2011 / Japan Plane Rectangular CS
+fn jprect(zone: &JPRZone) -> Value {
+    let params = zone.params();
+    let numeral = zone.zone_roman();
+    let parameter = |name: &str, value: f64, unit: &str, code: EpsgCode| json!({ "name": name, "value": value, "unit": unit, "id": id(code) });
+    json!({
+        "type": "ProjectedCRS",
+        "name": format!("JGD2011 / Japan Plane Rectangular CS {numeral}"),
+        "base_crs": jgd2011_geographic(),
+        "conversion": {
+            "name": format!("Japan Plane Rectangular CS zone {numeral}"),
+            "method": { "name": "Transverse Mercator", "id": id(9807) },
+            "parameters": [
+                parameter("Latitude of natural origin", params.lat0(), "degree", 8801),
+                parameter("Longitude of natural origin", params.lng0(), "degree", 8802),
+                parameter("Scale factor at natural origin", 0.9999, "unity", 8805),
+                parameter("False easting", 0.0, "metre", 8806),
+                parameter("False northing", 0.0, "metre", 8807)
+            ],
+            "id": id(17800 + zone.zone_number() as EpsgCode)
+        },
+        "coordinate_system": {
+            "subtype": "Cartesian",
+            "axis": [
+                axis("Northing", "X", "north", "metre"),
+                axis("Easting", "Y", "east", "metre")
+            ]
+        },
+        "id": id(zone.epsg_2011())
+    })
+}
+
+/// The horizontal CRS + JGD2011 (vertical) height
+fn compound(epsg: EpsgCode, name: &str, horizontal: Value) -> Value {
+    json!({
+        "type": "CompoundCRS",
+        "name": name,
+        "components": [
+            horizontal,
+            {
+                "type": "VerticalCRS",
+                "name": "JGD2011 (vertical) height",
+                "datum": {
+                    "type": "VerticalReferenceFrame",
+                    "name": "Japanese Geodetic Datum 2011 (vertical)",
+                    "id": id(1131)
+                },
+                "coordinate_system": {
+                    "subtype": "vertical",
+                    "axis": [axis("Gravity-related height", "H", "up", "metre")]
+                },
+                "id": id(EPSG_JGD2011_VERTICAL_HEIGHT)
+            }
+        ],
+        "id": id(epsg)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_projjson() {
+        let crs = projjson(EPSG_JGD2011_GEOGRAPHIC_3D).unwrap();
+        assert_eq!(crs["type"], "CompoundCRS");
+        assert_eq!(crs["$schema"], SCHEMA);
+        assert_eq!(crs["components"][0]["id"]["code"], 6668);
+        assert_eq!(crs["components"][1]["id"]["code"], 6695);
+
+        let crs = projjson(6677).unwrap();
+        assert_eq!(crs["name"], "JGD2011 / Japan Plane Rectangular CS IX");
+        assert_eq!(crs["conversion"]["id"]["code"], 17809);
+        assert_eq!(
+            crs["conversion"]["parameters"][1]["value"],
+            139.833_333_333_333_33
+        );
+
+        let crs = projjson(10174).unwrap();
+        assert_eq!(
+            crs["name"],
+            "JGD2011 / Japan Plane Rectangular CS XIII + JGD2011 (vertical) height"
+        );
+        assert_eq!(crs["components"][0]["id"]["code"], 6681);
+        assert!(crs["components"][0].get("$schema").is_none());
+
+        assert!(projjson(3857).is_none());
+    }
+}
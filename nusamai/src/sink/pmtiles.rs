@@ -0,0 +1,498 @@
+//! PMTiles (v3) archive writer
+//!
+//! Writes the tiles into a single-file archive instead of a `{z}/{x}/{y}` directory tree.
+//! See <https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md> for the format.
+//!
+//! The tile data are written to a temporary file as they arrive (in any order),
+//! and the directories are built when the archive is finished.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Seek, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use flate2::{write::GzEncoder, Compression as GzCompression};
+use sha2::{Digest, Sha256};
+
+use super::mvt::tileid::TileIdMethod;
+
+const MAGIC: &[u8; 7] = b"PMTiles";
+const VERSION: u8 = 3;
+const HEADER_SIZE: usize = 127;
+/// The header and the root directory must fit in the first 16 KiB of the archive
+const ROOT_DIR_MAX_SIZE: usize = 16384 - HEADER_SIZE;
+/// Minimum number of entries in a leaf directory
+const MIN_LEAF_SIZE: usize = 4096;
+
+/// Returns true if the path has the `.pmtiles` extension
+pub fn is_pmtiles_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("pmtiles"))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TileType {
+    Mvt = 1,
+    Png = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TileCompression {
+    None = 1,
+    Gzip = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+struct State {
+    /// Temporary file holding the tile data section
+    data: BufWriter<File>,
+    data_len: u64,
+    entries: Vec<Entry>,
+    /// (offset, length) of the tile contents, keyed by their hashes (to deduplicate identical tiles)
+    contents: HashMap<[u8; 32], (u64, u32)>,
+    min_zoom: u8,
+    max_zoom: u8,
+    /// [min_lon, min_lat, max_lon, max_lat]
+    bounds: [f64; 4],
+}
+
+/// Writes tiles into a PMTiles archive (can be shared between the writer threads)
+pub struct PmtilesWriter {
+    file: File,
+    tile_type: TileType,
+    tile_compression: TileCompression,
+    state: Mutex<State>,
+}
+
+impl PmtilesWriter {
+    /// Creates the archive file. The tiles given to `add_tile` are compressed with `tile_compression`.
+    pub fn create(
+        path: &Path,
+        tile_type: TileType,
+        tile_compression: TileCompression,
+    ) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::create(path)?;
+        let data = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => tempfile::tempfile_in(dir)?,
+            _ => tempfile::tempfile()?,
+        };
+        Ok(Self {
+            file,
+            tile_type,
+            tile_compression,
+            state: Mutex::new(State {
+                data: BufWriter::new(data),
+                data_len: 0,
+                entries: Vec::new(),
+                contents: HashMap::new(),
+                min_zoom: u8::MAX,
+                max_zoom: 0,
+                bounds: [f64::MAX, f64::MAX, f64::MIN, f64::MIN],
+            }),
+        })
+    }
+
+    /// Adds a tile (`tile_id` is the Hilbert tile ID of PMTiles). Identical tiles are stored only once.
+    pub fn add_tile(&self, tile_id: u64, content: &[u8]) -> io::Result<()> {
+        let content = match self.tile_compression {
+            TileCompression::None => content.to_vec(),
+            TileCompression::Gzip => gzip(content)?,
+        };
+        let hash: [u8; 32] = Sha256::digest(&content).into();
+        let (zoom, x, y) = TileIdMethod::Hilbert.id_to_zxy(tile_id);
+
+        let mut state = self.state.lock().unwrap();
+        let (offset, length) = match state.contents.get(&hash) {
+            Some(&found) => found,
+            None => {
+                let found = (state.data_len, content.len() as u32);
+                state.data.write_all(&content)?;
+                state.data_len += content.len() as u64;
+                state.contents.insert(hash, found);
+                found
+            }
+        };
+        state.entries.push(Entry {
+            tile_id,
+            offset,
+            length,
+            run_length: 1,
+        });
+
+        state.min_zoom = state.min_zoom.min(zoom);
+        state.max_zoom = state.max_zoom.max(zoom);
+        let [min_lon, min_lat, max_lon, max_lat] = tile_bounds(zoom, x, y);
+        let bounds = &mut state.bounds;
+        bounds[0] = bounds[0].min(min_lon);
+        bounds[1] = bounds[1].min(min_lat);
+        bounds[2] = bounds[2].max(max_lon);
+        bounds[3] = bounds[3].max(max_lat);
+        Ok(())
+    }
+
+    /// Writes the header, the directories and the metadata (JSON), and then the tile data.
+    pub fn finish(self, metadata: &serde_json::Value) -> io::Result<()> {
+        let state = self.state.into_inner().unwrap();
+        let mut data = state.data.into_inner().map_err(|err| err.into_error())?;
+
+        let entries = merge_runs(state.entries);
+        let addressed_tiles = entries.iter().map(|e| e.run_length as u64).sum();
+        let (root_dir, leaf_dirs) = build_directories(&entries)?;
+        let metadata = gzip(metadata.to_string().as_bytes())?;
+
+        let (min_zoom, max_zoom, bounds) = match entries.is_empty() {
+            true => (0, 0, [0.0; 4]),
+            false => (state.min_zoom, state.max_zoom, state.bounds),
+        };
+        let header = Header {
+            root_dir_offset: HEADER_SIZE as u64,
+            root_dir_length: root_dir.len() as u64,
+            metadata_offset: (HEADER_SIZE + root_dir.len()) as u64,
+            metadata_length: metadata.len() as u64,
+            leaf_dirs_offset: (HEADER_SIZE + root_dir.len() + metadata.len()) as u64,
+            leaf_dirs_length: leaf_dirs.len() as u64,
+            tile_data_offset: (HEADER_SIZE + root_dir.len() + metadata.len() + leaf_dirs.len())
+                as u64,
+            tile_data_length: state.data_len,
+            addressed_tiles,
+            tile_entries: entries.len() as u64,
+            tile_contents: state.contents.len() as u64,
+            tile_compression: self.tile_compression,
+            tile_type: self.tile_type,
+            min_zoom,
+            max_zoom,
+            bounds,
+        };
+
+        let mut writer = BufWriter::new(self.file);
+        writer.write_all(&header.to_bytes())?;
+        writer.write_all(&root_dir)?;
+        writer.write_all(&metadata)?;
+        writer.write_all(&leaf_dirs)?;
+        data.rewind()?;
+        io::copy(&mut data, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+struct Header {
+    root_dir_offset: u64,
+    root_dir_length: u64,
+    metadata_offset: u64,
+    metadata_length: u64,
+    leaf_dirs_offset: u64,
+    leaf_dirs_length: u64,
+    tile_data_offset: u64,
+    tile_data_length: u64,
+    addressed_tiles: u64,
+    tile_entries: u64,
+    tile_contents: u64,
+    tile_compression: TileCompression,
+    tile_type: TileType,
+    min_zoom: u8,
+    max_zoom: u8,
+    /// [min_lon, min_lat, max_lon, max_lat]
+    bounds: [f64; 4],
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        for v in [
+            self.root_dir_offset,
+            self.root_dir_length,
+            self.metadata_offset,
+            self.metadata_length,
+            self.leaf_dirs_offset,
+            self.leaf_dirs_length,
+            self.tile_data_offset,
+            self.tile_data_length,
+            self.addressed_tiles,
+            self.tile_entries,
+            self.tile_contents,
+        ] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.push(0); // not clustered (the tiles are written in the order of completion)
+        buf.push(TileCompression::Gzip as u8); // internal compression
+        buf.push(self.tile_compression as u8);
+        buf.push(self.tile_type as u8);
+        buf.push(self.min_zoom);
+        buf.push(self.max_zoom);
+        let [min_lon, min_lat, max_lon, max_lat] = self.bounds;
+        for v in [min_lon, min_lat, max_lon, max_lat] {
+            buf.extend_from_slice(&e7(v).to_le_bytes());
+        }
+        buf.push(self.min_zoom); // center zoom
+        buf.extend_from_slice(&e7((min_lon + max_lon) / 2.0).to_le_bytes());
+        buf.extend_from_slice(&e7((min_lat + max_lat) / 2.0).to_le_bytes());
+        debug_assert_eq!(buf.len(), HEADER_SIZE);
+        buf
+    }
+}
+
+fn e7(degrees: f64) -> i32 {
+    (degrees * 10_000_000.0).round() as i32
+}
+
+/// [min_lon, min_lat, max_lon, max_lat] of a Web Mercator tile
+pub(super) fn tile_bounds(zoom: u8, x: u32, y: u32) -> [f64; 4] {
+    let n = (1u64 << zoom) as f64;
+    let lon = |x: f64| x / n * 360.0 - 180.0;
+    let lat = |y: f64| {
+        (std::f64::consts::PI * (1.0 - 2.0 * y / n))
+            .sinh()
+            .atan()
+            .to_degrees()
+    };
+    [
+        lon(x as f64),
+        lat(y as f64 + 1.0),
+        lon(x as f64 + 1.0),
+        lat(y as f64),
+    ]
+}
+
+/// Sorts the entries by the tile IDs, and merges consecutive tiles with the same content into runs.
+fn merge_runs(mut entries: Vec<Entry>) -> Vec<Entry> {
+    entries.sort_unstable_by_key(|e| e.tile_id);
+    let mut merged: Vec<Entry> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if let Some(last) = merged.last_mut() {
+            if last.offset == entry.offset
+                && last.length == entry.length
+                && last.tile_id + last.run_length as u64 == entry.tile_id
+            {
+                last.run_length += 1;
+                continue;
+            }
+        }
+        merged.push(entry);
+    }
+    merged
+}
+
+/// Builds the (compressed) root directory and leaf directories.
+/// The entries are split into leaf directories only if the root directory would be too large.
+fn build_directories(entries: &[Entry]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let root = serialize_directory(entries)?;
+    if root.len() <= ROOT_DIR_MAX_SIZE {
+        return Ok((root, Vec::new()));
+    }
+
+    let mut leaf_size = (entries.len() / 3500).max(MIN_LEAF_SIZE);
+    loop {
+        let mut leaf_dirs = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_directory(chunk)?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaf_dirs.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0, // points to a leaf directory
+            });
+            leaf_dirs.extend(leaf);
+        }
+        let root = serialize_directory(&root_entries)?;
+        if root.len() <= ROOT_DIR_MAX_SIZE {
+            return Ok((root, leaf_dirs));
+        }
+        leaf_size += leaf_size / 5;
+    }
+}
+
+/// Serializes the directory entries (column-oriented varints), compressed with gzip
+fn serialize_directory(entries: &[Entry]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, entries.len() as u64);
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut buf, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut buf, entry.run_length as u64);
+    }
+    for entry in entries {
+        write_varint(&mut buf, entry.length as u64);
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        // 0 means "immediately after the previous entry"
+        match i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+            true => write_varint(&mut buf, 0),
+            false => write_varint(&mut buf, entry.offset + 1),
+        }
+    }
+    gzip(&buf)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(super) fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn read_varint(buf: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = buf[0];
+            *buf = &buf[1..];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    fn deserialize_directory(compressed: &[u8]) -> Vec<Entry> {
+        let mut bytes = Vec::new();
+        GzDecoder::new(compressed).read_to_end(&mut bytes).unwrap();
+        let buf = &mut bytes.as_slice();
+
+        let n = read_varint(buf) as usize;
+        let mut entries = vec![
+            Entry {
+                tile_id: 0,
+                offset: 0,
+                length: 0,
+                run_length: 0
+            };
+            n
+        ];
+        let mut last_id = 0;
+        for e in entries.iter_mut() {
+            last_id += read_varint(buf);
+            e.tile_id = last_id;
+        }
+        for e in entries.iter_mut() {
+            e.run_length = read_varint(buf) as u32;
+        }
+        for e in entries.iter_mut() {
+            e.length = read_varint(buf) as u32;
+        }
+        for i in 0..n {
+            entries[i].offset = match read_varint(buf) {
+                0 => entries[i - 1].offset + entries[i - 1].length as u64,
+                v => v - 1,
+            };
+        }
+        entries
+    }
+
+    fn u64_at(bytes: &[u8], pos: usize) -> u64 {
+        u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_write_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.pmtiles");
+        assert!(is_pmtiles_path(&path));
+
+        let writer = PmtilesWriter::create(&path, TileType::Png, TileCompression::None).unwrap();
+        // added out of order; tiles 2 and 3 share the same content
+        writer.add_tile(3, b"same").unwrap();
+        writer.add_tile(0, b"root").unwrap();
+        writer.add_tile(2, b"same").unwrap();
+        writer.finish(&serde_json::json!({"name": "test"})).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[0..7], MAGIC);
+        assert_eq!(bytes[7], VERSION);
+        assert_eq!(u64_at(&bytes, 8), HEADER_SIZE as u64);
+        assert_eq!(u64_at(&bytes, 72), 3); // addressed tiles
+        assert_eq!(u64_at(&bytes, 80), 2); // tile entries
+        assert_eq!(u64_at(&bytes, 88), 2); // tile contents
+        assert_eq!(bytes[99], TileType::Png as u8);
+        assert_eq!((bytes[100], bytes[101]), (0, 1)); // min/max zoom
+
+        let root_length = u64_at(&bytes, 16) as usize;
+        let entries = deserialize_directory(&bytes[HEADER_SIZE..HEADER_SIZE + root_length]);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].tile_id, entries[0].run_length), (0, 1));
+        assert_eq!((entries[1].tile_id, entries[1].run_length), (2, 2));
+
+        let data_offset = u64_at(&bytes, 56) as usize;
+        let tile = &entries[1];
+        let start = data_offset + tile.offset as usize;
+        assert_eq!(&bytes[start..start + tile.length as usize], b"same");
+
+        let (metadata_offset, metadata_length) =
+            (u64_at(&bytes, 24) as usize, u64_at(&bytes, 32) as usize);
+        let mut metadata = String::new();
+        GzDecoder::new(&bytes[metadata_offset..metadata_offset + metadata_length])
+            .read_to_string(&mut metadata)
+            .unwrap();
+        assert_eq!(metadata, r#"{"name":"test"}"#);
+    }
+
+    #[test]
+    fn test_leaf_directories() {
+        // many tiles with distinct contents do not fit in the root directory
+        let entries: Vec<Entry> = (0..100_000u64)
+            .map(|i| Entry {
+                tile_id: i * 3,
+                offset: i * 7919 % 1_000_003,
+                length: (i % 1000) as u32 + 1,
+                run_length: 1,
+            })
+            .collect();
+        let (root, leaves) = build_directories(&entries).unwrap();
+        assert!(root.len() <= ROOT_DIR_MAX_SIZE);
+        assert!(!leaves.is_empty());
+
+        let root_entries = deserialize_directory(&root);
+        assert!(root_entries.iter().all(|e| e.run_length == 0));
+        let leaf = &root_entries[1];
+        let leaf_entries = deserialize_directory(
+            &leaves[leaf.offset as usize..(leaf.offset + leaf.length as u64) as usize],
+        );
+        assert_eq!(leaf_entries[0].tile_id, leaf.tile_id);
+        assert_eq!(leaf_entries[0], entries[(leaf.tile_id / 3) as usize]);
+    }
+
+    #[test]
+    fn test_tile_bounds() {
+        let [min_lon, min_lat, max_lon, max_lat] = tile_bounds(0, 0, 0);
+        assert_eq!((min_lon, max_lon), (-180.0, 180.0));
+        assert!((max_lat - 85.0511287798).abs() < 1e-6);
+        assert!((min_lat + 85.0511287798).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,203 @@
+//! Routable network (nodes and edges) made from the road centerlines
+
+use std::collections::HashMap;
+
+/// A centerline (a linestring of `tran:lod0Network`) of a road
+pub struct Centerline {
+    pub road_id: String,
+    pub function: Option<String>,
+    pub width: Option<f64>,
+    pub coords: Vec<[f64; 3]>,
+}
+
+/// An end or a junction of the centerlines
+pub struct Node {
+    pub coord: [f64; 3],
+    /// Number of the edges connected to the node
+    pub degree: usize,
+}
+
+/// A part of a centerline between two nodes
+pub struct Edge {
+    pub id: String,
+    pub road_id: String,
+    /// Index of the start node (starting from 0)
+    pub source: usize,
+    /// Index of the end node
+    pub target: usize,
+    pub function: Option<String>,
+    pub width: Option<f64>,
+    /// Length on the horizontal plane (m)
+    pub length: f64,
+    pub coords: Vec<[f64; 3]>,
+}
+
+#[derive(Default)]
+pub struct RoadNetwork {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+/// Converts the coordinates to meters on the horizontal plane
+#[derive(Clone, Copy)]
+pub struct Metric {
+    /// Whether the coordinates are (longitude, latitude) in degrees
+    pub geographic: bool,
+}
+
+impl Metric {
+    fn scale(&self, coord: &[f64; 3]) -> (f64, f64) {
+        match self.geographic {
+            // (equirectangular approximation, enough for the snapping and the lengths of the roads)
+            true => (111_320.0 * coord[1].to_radians().cos(), 110_574.0),
+            false => (1.0, 1.0),
+        }
+    }
+
+    fn distance(&self, a: &[f64; 3], b: &[f64; 3]) -> f64 {
+        let (sx, sy) = self.scale(a);
+        ((b[0] - a[0]) * sx).hypot((b[1] - a[1]) * sy)
+    }
+
+    /// Cell of the grid of `tolerance` meters, used to identify the coincident vertices
+    fn cell(&self, coord: &[f64; 3], tolerance: f64) -> (i64, i64) {
+        let (sx, sy) = self.scale(coord);
+        (
+            (coord[0] * sx / tolerance).round() as i64,
+            (coord[1] * sy / tolerance).round() as i64,
+        )
+    }
+}
+
+impl RoadNetwork {
+    /// Builds the network from the centerlines.
+    ///
+    /// The vertices in the same grid cell of `tolerance` meters are regarded as the same point.
+    /// The centerlines are split at their ends and at the vertices shared with the other centerlines.
+    pub fn build(centerlines: &[Centerline], metric: Metric, tolerance: f64) -> Self {
+        let cells: Vec<Vec<(i64, i64)>> = centerlines
+            .iter()
+            .map(|line| {
+                let mut cells: Vec<(i64, i64)> = line
+                    .coords
+                    .iter()
+                    .map(|c| metric.cell(c, tolerance))
+                    .collect();
+                cells.dedup();
+                cells
+            })
+            .collect();
+
+        // the number of the centerlines passing through each cell
+        let mut counts = HashMap::<(i64, i64), usize>::new();
+        for line_cells in &cells {
+            let mut seen = line_cells.clone();
+            seen.sort_unstable();
+            seen.dedup();
+            for cell in seen {
+                *counts.entry(cell).or_default() += 1;
+            }
+        }
+
+        let mut network = RoadNetwork::default();
+        let mut node_indices = HashMap::<(i64, i64), usize>::new();
+        let mut node_of = |network: &mut RoadNetwork, cell: (i64, i64), coord: [f64; 3]| {
+            *node_indices.entry(cell).or_insert_with(|| {
+                network.nodes.push(Node { coord, degree: 0 });
+                network.nodes.len() - 1
+            })
+        };
+
+        for line in centerlines {
+            // the vertices with the duplicates removed
+            let mut vertices: Vec<((i64, i64), [f64; 3])> = Vec::with_capacity(line.coords.len());
+            for coord in &line.coords {
+                let cell = metric.cell(coord, tolerance);
+                if vertices.last().map(|(c, _)| *c) != Some(cell) {
+                    vertices.push((cell, *coord));
+                }
+            }
+            if vertices.len() < 2 {
+                continue;
+            }
+
+            let last = vertices.len() - 1;
+            let (first_cell, first_coord) = vertices[0];
+            let mut source = node_of(&mut network, first_cell, first_coord);
+            let mut coords = vec![first_coord];
+            let mut length = 0.0;
+            let mut num_edges = 0;
+            for (i, (cell, coord)) in vertices.iter().enumerate().skip(1) {
+                length += metric.distance(coords.last().unwrap(), coord);
+                coords.push(*coord);
+                if i != last && counts[cell] < 2 {
+                    continue;
+                }
+
+                let target = node_of(&mut network, *cell, *coord);
+                network.nodes[source].degree += 1;
+                network.nodes[target].degree += 1;
+                num_edges += 1;
+                network.edges.push(Edge {
+                    id: format!("{}_{num_edges}", line.road_id),
+                    road_id: line.road_id.clone(),
+                    source,
+                    target,
+                    function: line.function.clone(),
+                    width: line.width,
+                    length,
+                    coords: std::mem::replace(&mut coords, vec![*coord]),
+                });
+                source = target;
+                length = 0.0;
+            }
+        }
+
+        network
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn centerline(road_id: &str, coords: &[[f64; 2]]) -> Centerline {
+        Centerline {
+            road_id: road_id.into(),
+            function: Some("市区町村道".into()),
+            width: Some(6.5),
+            coords: coords.iter().map(|[x, y]| [*x, *y, 0.0]).collect(),
+        }
+    }
+
+    #[test]
+    fn test_build_network() {
+        let metric = Metric { geographic: false };
+        // a road crossing the middle of another road, and a road connected at the end
+        let centerlines = vec![
+            centerline("road_1", &[[0.0, 0.0], [10.0, 0.0], [20.0, 0.0]]),
+            centerline("road_2", &[[10.0, -10.0], [10.0, 0.0], [10.0, 10.0]]),
+            centerline("road_3", &[[20.001, 0.0], [30.0, 0.0]]),
+        ];
+        let network = RoadNetwork::build(&centerlines, metric, 0.01);
+
+        // (0,0), (10,0), (20,0), (10,-10), (10,10), (30,0)
+        assert_eq!(network.nodes.len(), 6);
+        // road_1 and road_2 are split at the crossing
+        assert_eq!(network.edges.len(), 5);
+        assert_eq!(network.nodes[1].degree, 4);
+        assert_eq!(network.nodes[2].degree, 2);
+
+        let edge = &network.edges[0];
+        assert_eq!((edge.source, edge.target), (0, 1));
+        assert_eq!(edge.length, 10.0);
+        assert_eq!(edge.width, Some(6.5));
+        assert_eq!(network.edges[4].source, network.edges[1].target);
+
+        // the lengths in degrees are converted into meters
+        let metric = Metric { geographic: true };
+        let centerlines = vec![centerline("road_1", &[[139.0, 35.0], [139.0, 35.001]])];
+        let network = RoadNetwork::build(&centerlines, metric, 0.01);
+        assert!((network.edges[0].length - 110.574).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,444 @@
+//! Road network sink
+//!
+//! Makes a routable network from the centerlines of the roads (`tran:lod0Network` of `tran:Road`),
+//! and writes the nodes (`road_nodes`) and the edges (`road_edges`) as GeoPackage tables or GeoJSON files.
+//! The edges refer to the nodes with `source` and `target`, and have the function, the width and the length of the road.
+
+mod graph;
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use graph::{Centerline, Metric, RoadNetwork};
+use indexmap::IndexMap;
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+    GeometryType,
+};
+use nusamai_gpkg::{
+    geometry::{write_linestring, write_point},
+    table::{ColumnInfo, TableInfo},
+    GpkgHandler,
+};
+use nusamai_plateau::Entity;
+use nusamai_projection::crs::{
+    EPSG_JGD2011_GEOGRAPHIC_2D, EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_WGS84_GEOGRAPHIC_2D,
+    EPSG_WGS84_GEOGRAPHIC_3D,
+};
+use rayon::prelude::*;
+
+use super::{option::output_parameter, style::matches_attribute_name};
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer::{self, prefix_config, LodFilterMode, LodMask, TransformerSettings},
+};
+
+const NODES_TABLE_NAME: &str = "road_nodes";
+const EDGES_TABLE_NAME: &str = "road_edges";
+
+pub struct RoadNetworkSinkProvider {}
+
+impl DataSinkProvider for RoadNetworkSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "roadnetwork".to_string(),
+            name: "Road Network (GeoPackage / GeoJSON)".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "tolerance".into(),
+            entry: ParameterEntry {
+                description: "Distance (m) within which the vertices are connected as a node"
+                    .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter {
+                    value: Some("0.01".into()),
+                }),
+                label: Some("ノードとして接続する距離の許容値（m）".into()),
+            },
+        });
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(prefix_config(&[]));
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let tolerance = get_parameter_value!(params, "tolerance", String);
+        let transform_settings = self.transformer_options();
+
+        Box::<RoadNetworkSink>::new(RoadNetworkSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            tolerance: tolerance.clone().unwrap_or_else(|| "0.01".into()),
+            transform_settings,
+        })
+    }
+}
+
+pub struct RoadNetworkSink {
+    output_path: PathBuf,
+    /// in meters (validated when the sink runs)
+    tolerance: String,
+    transform_settings: TransformerSettings,
+}
+
+impl DataSink for RoadNetworkSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        // the centerlines (LOD0) are kept even if the roads have the higher LODs
+        let default_requirements = DataRequirements {
+            tree_flattening: transformer::TreeFlatteningSpec::Flatten {
+                feature: transformer::FeatureFlatteningOption::AllExceptThematicSurfaces,
+                data: transformer::DataFlatteningOption::None,
+                object: transformer::ObjectFlatteningOption::None,
+            },
+            lod_filter: transformer::LodFilterSpec {
+                mask: LodMask::all(),
+                mode: LodFilterMode::All,
+            },
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, schema: &Schema) -> Result<()> {
+        let tolerance = match self.tolerance.trim().parse::<f64>() {
+            Ok(tolerance) if tolerance > 0.0 => tolerance,
+            _ => {
+                return Err(PipelineError::Other(format!(
+                    "Invalid tolerance: {} (expected a positive number in meters)",
+                    self.tolerance
+                )))
+            }
+        };
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+
+        let (ra, rb) = rayon::join(
+            || {
+                upstream
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(sender, |sender, parcel| {
+                        feedback.ensure_not_canceled()?;
+
+                        let Some(centerlines) = entity_to_centerlines(&parcel.entity) else {
+                            return Ok(());
+                        };
+                        if sender.send(centerlines).is_err() {
+                            return Err(PipelineError::Canceled);
+                        };
+                        Ok(())
+                    })
+            },
+            || {
+                let mut centerlines = Vec::new();
+                let mut num_skipped = 0;
+                for lines in receiver {
+                    feedback.ensure_not_canceled()?;
+                    if lines.is_empty() {
+                        num_skipped += 1;
+                    }
+                    centerlines.extend(lines);
+                }
+                if num_skipped > 0 {
+                    feedback.warn(format!(
+                        "{num_skipped} roads without the centerlines (tran:lod0Network) are skipped"
+                    ));
+                }
+
+                // (the entities arrive in random order)
+                centerlines.sort_by(|a, b| a.road_id.cmp(&b.road_id));
+
+                let metric = Metric {
+                    geographic: schema.epsg.is_some_and(|epsg| {
+                        matches!(
+                            epsg,
+                            EPSG_WGS84_GEOGRAPHIC_2D
+                                | EPSG_WGS84_GEOGRAPHIC_3D
+                                | EPSG_JGD2011_GEOGRAPHIC_2D
+                                | EPSG_JGD2011_GEOGRAPHIC_3D
+                        )
+                    }),
+                };
+                let network = RoadNetwork::build(&centerlines, metric, tolerance);
+                feedback.info(format!(
+                    "Road network: {} nodes, {} edges",
+                    network.nodes.len(),
+                    network.edges.len()
+                ));
+
+                let is_gpkg = self
+                    .output_path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("gpkg"));
+                if is_gpkg {
+                    let runtime = tokio::runtime::Runtime::new().unwrap();
+                    runtime.block_on(write_gpkg(
+                        &self.output_path,
+                        &network,
+                        schema.epsg.unwrap_or(0),
+                    ))
+                } else {
+                    write_geojson(&self.output_path, &network)
+                }
+            },
+        );
+
+        match ra {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+        match rb {
+            Ok(_) | Err(PipelineError::Canceled) => {}
+            Err(error) => feedback.fatal_error(error),
+        }
+
+        Ok(())
+    }
+}
+
+/// Extracts the centerlines of a road. Returns `None` if the entity is not a road.
+fn entity_to_centerlines(entity: &Entity) -> Option<Vec<Centerline>> {
+    let Value::Object(obj) = &entity.root else {
+        return None;
+    };
+    if obj.typename != "tran:Road" {
+        return None;
+    }
+    let ObjectStereotype::Feature { id, geometries } = &obj.stereotype else {
+        return None;
+    };
+
+    let function = obj
+        .attributes
+        .iter()
+        .find(|(key, _)| matches_attribute_name(key, "tran:function"))
+        .and_then(|(_, value)| first_text(value));
+    let width = obj
+        .attributes
+        .iter()
+        .filter(|(key, _)| matches_attribute_name(key, "uro:roadStructureAttribute"))
+        .find_map(|(_, value)| find_number(value, "uro:width"));
+
+    let geom_store = entity.geometry_store.read().unwrap();
+    let mut centerlines = Vec::new();
+    for entry in geometries {
+        if entry.ty != GeometryType::Curve || entry.lod != 0 {
+            continue;
+        }
+        let range = entry.pos as usize..(entry.pos + entry.len) as usize;
+        for line in geom_store.multilinestring.iter_range(range) {
+            centerlines.push(Centerline {
+                road_id: id.clone(),
+                function: function.clone(),
+                width,
+                coords: line
+                    .raw_coords()
+                    .iter()
+                    .map(|&idx| geom_store.vertices[idx as usize])
+                    .collect(),
+            });
+        }
+    }
+    Some(centerlines)
+}
+
+/// The first value as a text (the name of a code)
+fn first_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Code(code) => Some(code.value().to_string()),
+        Value::Array(values) => values.first().and_then(first_text),
+        _ => None,
+    }
+}
+
+/// Finds the first number of the attribute in the attribute tree
+fn find_number(value: &Value, name: &str) -> Option<f64> {
+    match value {
+        Value::Object(obj) => obj.attributes.iter().find_map(|(key, value)| match value {
+            Value::Measure(m) if matches_attribute_name(key, name) => Some(m.value()),
+            Value::Double(d) if matches_attribute_name(key, name) => Some(*d),
+            _ => find_number(value, name),
+        }),
+        Value::Array(values) => values.iter().find_map(|value| find_number(value, name)),
+        _ => None,
+    }
+}
+
+fn column(name: &str, data_type: &str) -> ColumnInfo {
+    ColumnInfo {
+        name: name.into(),
+        data_type: data_type.into(),
+        mime_type: None,
+    }
+}
+
+fn gpkg_error(err: nusamai_gpkg::GpkgError) -> PipelineError {
+    PipelineError::Other(err.to_string())
+}
+
+/// Writes the `road_nodes` and `road_edges` tables. The nodes are referred by their `fid`.
+async fn write_gpkg(path: &Path, network: &RoadNetwork, srs_id: u16) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let mut handler = GpkgHandler::from_str(&format!("file:{}", path.to_string_lossy()))
+        .await
+        .map_err(gpkg_error)?;
+    let mut tx = handler.begin().await.map_err(gpkg_error)?;
+
+    let nodes_table = TableInfo {
+        name: NODES_TABLE_NAME.into(),
+        has_geometry: true,
+        columns: vec![column("degree", "INTEGER")],
+    };
+    let edges_table = TableInfo {
+        name: EDGES_TABLE_NAME.into(),
+        has_geometry: true,
+        columns: vec![
+            column("road_id", "TEXT"),
+            column("source", "INTEGER"),
+            column("target", "INTEGER"),
+            column("function", "TEXT"),
+            column("width", "REAL"),
+            column("length", "REAL"),
+        ],
+    };
+    for (table, geometry_type) in [(&nodes_table, "POINT"), (&edges_table, "LINESTRING")] {
+        tx.add_table(table, srs_id).await.map_err(gpkg_error)?;
+        tx.set_geometry_type(&table.name, geometry_type)
+            .await
+            .map_err(gpkg_error)?;
+    }
+
+    let mut bbox = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for (i, node) in network.nodes.iter().enumerate() {
+        let [x, y, _] = node.coord;
+        bbox = (bbox.0.min(x), bbox.1.min(y), bbox.2.max(x), bbox.3.max(y));
+
+        let mut bytes = Vec::new();
+        write_point(&mut bytes, node.coord, srs_id as i32)?;
+        let attributes = IndexMap::from([("degree".to_string(), node.degree.to_string())]);
+        tx.insert_feature(NODES_TABLE_NAME, &(i + 1).to_string(), &bytes, &attributes)
+            .await
+            .map_err(gpkg_error)?;
+    }
+
+    for edge in &network.edges {
+        let mut bytes = Vec::new();
+        write_linestring(&mut bytes, &edge.coords, srs_id as i32)?;
+        let mut attributes = IndexMap::from([
+            ("road_id".to_string(), edge.road_id.clone()),
+            ("source".to_string(), (edge.source + 1).to_string()),
+            ("target".to_string(), (edge.target + 1).to_string()),
+            ("length".to_string(), edge.length.to_string()),
+        ]);
+        if let Some(function) = &edge.function {
+            attributes.insert("function".into(), function.clone());
+        }
+        if let Some(width) = edge.width {
+            attributes.insert("width".into(), width.to_string());
+        }
+        tx.insert_feature(EDGES_TABLE_NAME, &edge.id, &bytes, &attributes)
+            .await
+            .map_err(gpkg_error)?;
+    }
+
+    if !network.nodes.is_empty() {
+        // (the edges end at the nodes, but may bend outside of them)
+        for edge in &network.edges {
+            for [x, y, _] in &edge.coords {
+                bbox = (
+                    bbox.0.min(*x),
+                    bbox.1.min(*y),
+                    bbox.2.max(*x),
+                    bbox.3.max(*y),
+                );
+            }
+        }
+        for table_name in [NODES_TABLE_NAME, EDGES_TABLE_NAME] {
+            tx.update_bbox(table_name, bbox).await.map_err(gpkg_error)?;
+        }
+    }
+
+    tx.commit().await.map_err(gpkg_error)
+}
+
+/// Writes `road_nodes.geojson` and `road_edges.geojson` into the directory. The nodes are referred by their `id`.
+fn write_geojson(dir: &Path, network: &RoadNetwork) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    let nodes = network.nodes.iter().enumerate().map(|(i, node)| {
+        let mut properties = geojson::JsonObject::new();
+        properties.insert("degree".into(), node.degree.into());
+        geojson::Feature {
+            bbox: None,
+            geometry: Some(geojson::Geometry::new(geojson::Value::Point(
+                node.coord.to_vec(),
+            ))),
+            id: Some(geojson::feature::Id::Number((i + 1).into())),
+            properties: Some(properties),
+            foreign_members: None,
+        }
+    });
+    write_feature_collection(&dir.join(format!("{NODES_TABLE_NAME}.geojson")), nodes)?;
+
+    let edges = network.edges.iter().map(|edge| {
+        let mut properties = geojson::JsonObject::new();
+        properties.insert("road_id".into(), edge.road_id.clone().into());
+        properties.insert("source".into(), (edge.source + 1).into());
+        properties.insert("target".into(), (edge.target + 1).into());
+        properties.insert("function".into(), edge.function.clone().into());
+        properties.insert("width".into(), edge.width.into());
+        properties.insert("length".into(), edge.length.into());
+        geojson::Feature {
+            bbox: None,
+            geometry: Some(geojson::Geometry::new(geojson::Value::LineString(
+                edge.coords.iter().map(|c| c.to_vec()).collect(),
+            ))),
+            id: Some(geojson::feature::Id::String(edge.id.clone())),
+            properties: Some(properties),
+            foreign_members: None,
+        }
+    });
+    write_feature_collection(&dir.join(format!("{EDGES_TABLE_NAME}.geojson")), edges)
+}
+
+fn write_feature_collection(
+    path: &Path,
+    features: impl Iterator<Item = geojson::Feature>,
+) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[")?;
+    for (i, feature) in features.enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut writer, &feature).map_err(std::io::Error::from)?;
+    }
+    writer.write_all(b"]}\n")?;
+    writer.flush()?;
+    Ok(())
+}
@@ -1,12 +1,13 @@
-//! Serde sink (demo)
+//! Serde sink
 //!
-//! This is a demo sink that serializes the city objects with serde.
+//! Serializes the parsed entities with serde (bincode + zstd) so that the expensive CityGML
+//! parsing can be done once and other sinks can be run later from the output with
+//! [`crate::source::serde::SerdeSource`].
+//!
+//! File layout: `MAGIC` (8 bytes), then repeated `[u32 LE size][zstd compressed bincode entity]`.
+//! The whole file may additionally be compressed with gzip or zstd (see [`super::output`]).
 
-use std::{
-    fs::File,
-    io::{BufWriter, Write},
-    path::PathBuf,
-};
+use std::{io::Write, path::PathBuf};
 
 use nusamai_citygml::schema::Schema;
 use rayon::prelude::*;
@@ -19,7 +20,16 @@ use crate::{
     transformer::TransformerSettings,
 };
 
-use super::option::output_parameter;
+use super::{
+    option::output_parameter,
+    output::{compression_parameter, Compression, OutputWriter},
+};
+
+/// Magic bytes at the beginning of the entity cache file (includes the format version)
+pub const MAGIC: &[u8; 8] = b"NUSAMAI\x02";
+
+/// Compression level of zstd for each entity (the cache is intermediate, so the speed is preferred)
+const ZSTD_LEVEL: i32 = 1;
 
 pub struct SerdeSinkProvider {}
 
@@ -34,6 +44,7 @@ impl DataSinkProvider for SerdeSinkProvider {
     fn sink_options(&self) -> Parameters {
         let mut params = Parameters::new();
         params.define(output_parameter());
+        params.define(compression_parameter());
 
         params
     }
@@ -46,9 +57,13 @@ impl DataSinkProvider for SerdeSinkProvider {
 
     fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
         let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let compression = get_parameter_value!(params, "compression", String)
+            .clone()
+            .unwrap_or_default();
 
         Box::<SerdeSink>::new(SerdeSink {
             output_path: output_path.as_ref().unwrap().into(),
+            compression,
             ..Default::default()
         })
     }
@@ -57,13 +72,18 @@ impl DataSinkProvider for SerdeSinkProvider {
 #[derive(Default)]
 pub struct SerdeSink {
     output_path: PathBuf,
+    /// Compression option (`auto` follows the extension of the output path)
+    compression: String,
     features_written: usize,
     bytes_written: usize,
 }
 
 impl DataSink for SerdeSink {
     fn make_requirements(&mut self, _: TransformerSettings) -> DataRequirements {
+        // Store the entities as parsed, so that they can be transformed for any sink later.
         DataRequirements {
+            use_appearance: true,
+            passthrough: true,
             ..Default::default()
         }
     }
@@ -71,6 +91,7 @@ impl DataSink for SerdeSink {
     fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
         let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
         let bincode_config = bincode::config::standard();
+        let compression = Compression::negotiate(&self.compression, &self.output_path)?;
 
         let (ra, rb) = rayon::join(
             || {
@@ -83,7 +104,8 @@ impl DataSink for SerdeSink {
                         buf.clear();
                         bincode::serde::encode_into_std_write(parcel.entity, buf, bincode_config)
                             .unwrap();
-                        if sender.send(lz4_flex::compress_prepend_size(buf)).is_err() {
+                        let compressed = zstd::bulk::compress(buf, ZSTD_LEVEL)?;
+                        if sender.send(compressed).is_err() {
                             return Err(PipelineError::Canceled);
                         };
                         Ok(())
@@ -93,8 +115,8 @@ impl DataSink for SerdeSink {
             },
             || {
                 // Write to file
-                let mut writer =
-                    BufWriter::with_capacity(1024 * 1024, File::create(&self.output_path)?);
+                let mut writer = OutputWriter::create(&self.output_path, compression)?;
+                writer.write_all(MAGIC)?;
                 for compressed in receiver {
                     feedback.ensure_not_canceled()?;
 
@@ -106,6 +128,7 @@ impl DataSink for SerdeSink {
                     self.features_written += 1;
                     self.bytes_written += 4 + compressed.len();
                 }
+                writer.finish()?;
                 feedback.info(format!(
                     "Wrote {} features ({} bytes)",
                     self.features_written, self.bytes_written
@@ -0,0 +1,172 @@
+//! Minimal GeoTIFF writer (uncompressed 8-bit RGB, geographic CRS)
+
+use std::io::{self, Write};
+
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_DOUBLE: u16 = 12;
+
+/// Georeferencing of a raster in a geographic CRS
+#[derive(Debug, Clone, Copy)]
+pub struct GeoTransform {
+    /// EPSG code of the geographic 2D CRS (e.g. 6668 for JGD2011)
+    pub epsg: u16,
+    /// Longitude and latitude of the upper-left corner of the raster
+    pub origin: [f64; 2],
+    /// Width and height of a pixel in degrees
+    pub pixel_size: [f64; 2],
+}
+
+impl GeoTransform {
+    /// Contents of the ESRI world file (e.g. `.pgw`), which refers to the center of the upper-left pixel
+    pub fn world_file(&self) -> String {
+        let [x, y] = self.origin;
+        let [sx, sy] = self.pixel_size;
+        format!("{sx}\n0\n0\n{}\n{}\n{}\n", -sy, x + sx / 2.0, y - sy / 2.0)
+    }
+}
+
+struct IfdEntry {
+    tag: u16,
+    ty: u16,
+    count: u32,
+    /// Little-endian bytes of the values
+    value: Vec<u8>,
+}
+
+impl IfdEntry {
+    fn shorts(tag: u16, values: &[u16]) -> Self {
+        Self {
+            tag,
+            ty: TYPE_SHORT,
+            count: values.len() as u32,
+            value: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+
+    fn long(tag: u16, value: u32) -> Self {
+        Self {
+            tag,
+            ty: TYPE_LONG,
+            count: 1,
+            value: value.to_le_bytes().to_vec(),
+        }
+    }
+
+    fn doubles(tag: u16, values: &[f64]) -> Self {
+        Self {
+            tag,
+            ty: TYPE_DOUBLE,
+            count: values.len() as u32,
+            value: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+}
+
+/// Writes an RGB image (`rgb.len() == width * height * 3`) as a single-strip GeoTIFF.
+pub fn write_geotiff<W: Write>(
+    writer: &mut W,
+    width: u32,
+    height: u32,
+    rgb: &[u8],
+    transform: &GeoTransform,
+) -> io::Result<()> {
+    debug_assert_eq!(rgb.len(), (width * height * 3) as usize);
+    let [x, y] = transform.origin;
+    let [sx, sy] = transform.pixel_size;
+
+    #[rustfmt::skip]
+    let geo_keys = [
+        1, 1, 0, 3, // version, revision, number of keys
+        1024, 0, 1, 2, // GTModelType: geographic
+        1025, 0, 1, 1, // GTRasterType: PixelIsArea
+        2048, 0, 1, transform.epsg, // GeographicType
+    ];
+
+    // (sorted by the tags)
+    let mut entries = vec![
+        IfdEntry::long(256, width),                            // ImageWidth
+        IfdEntry::long(257, height),                           // ImageLength
+        IfdEntry::shorts(258, &[8, 8, 8]),                     // BitsPerSample
+        IfdEntry::shorts(259, &[1]),                           // Compression: none
+        IfdEntry::shorts(262, &[2]),                           // PhotometricInterpretation: RGB
+        IfdEntry::long(273, 0),                                // StripOffsets (set below)
+        IfdEntry::shorts(277, &[3]),                           // SamplesPerPixel
+        IfdEntry::long(278, height),                           // RowsPerStrip
+        IfdEntry::long(279, rgb.len() as u32),                 // StripByteCounts
+        IfdEntry::shorts(284, &[1]),                           // PlanarConfiguration: chunky
+        IfdEntry::doubles(33550, &[sx, sy, 0.0]),              // ModelPixelScale
+        IfdEntry::doubles(33922, &[0.0, 0.0, 0.0, x, y, 0.0]), // ModelTiepoint
+        IfdEntry::shorts(34735, &geo_keys),                    // GeoKeyDirectory
+    ];
+
+    let ifd_offset = 8;
+    let ifd_size = 2 + entries.len() * 12 + 4;
+    // values longer than 4 bytes are placed after the IFD
+    let mut data_offset = ifd_offset + ifd_size;
+    let mut extra_size = 0;
+    for entry in &entries {
+        if entry.value.len() > 4 {
+            extra_size += entry.value.len();
+        }
+    }
+    let image_offset = data_offset + extra_size;
+    if let Some(strip_offsets) = entries.iter_mut().find(|entry| entry.tag == 273) {
+        strip_offsets.value = (image_offset as u32).to_le_bytes().to_vec();
+    }
+
+    let mut header = Vec::with_capacity(image_offset);
+    header.extend_from_slice(b"II");
+    header.extend_from_slice(&42u16.to_le_bytes());
+    header.extend_from_slice(&(ifd_offset as u32).to_le_bytes());
+    header.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    let mut extra = Vec::with_capacity(extra_size);
+    for entry in &entries {
+        header.extend_from_slice(&entry.tag.to_le_bytes());
+        header.extend_from_slice(&entry.ty.to_le_bytes());
+        header.extend_from_slice(&entry.count.to_le_bytes());
+        if entry.value.len() > 4 {
+            header.extend_from_slice(&(data_offset as u32).to_le_bytes());
+            extra.extend_from_slice(&entry.value);
+            data_offset += entry.value.len();
+        } else {
+            let mut value = [0u8; 4];
+            value[..entry.value.len()].copy_from_slice(&entry.value);
+            header.extend_from_slice(&value);
+        }
+    }
+    header.extend_from_slice(&0u32.to_le_bytes()); // no more IFDs
+    header.extend_from_slice(&extra);
+    debug_assert_eq!(header.len(), image_offset);
+
+    writer.write_all(&header)?;
+    writer.write_all(rgb)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_geotiff() {
+        let transform = GeoTransform {
+            epsg: 6668,
+            origin: [139.0, 36.0],
+            pixel_size: [0.5, 0.25],
+        };
+        let rgb = [10, 20, 30, 40, 50, 60];
+        let mut buf = Vec::new();
+        write_geotiff(&mut buf, 2, 1, &rgb, &transform).unwrap();
+
+        assert_eq!(&buf[0..4], b"II\x2a\x00");
+        assert!(buf.ends_with(&rgb));
+
+        // readable by a standard TIFF decoder
+        let image = image::load_from_memory_with_format(&buf, image::ImageFormat::Tiff).unwrap();
+        assert_eq!((image.width(), image.height()), (2, 1));
+        assert_eq!(image.to_rgb8().into_raw(), rgb);
+
+        assert_eq!(transform.world_file(), "0.5\n0\n0\n-0.25\n139.25\n35.875\n");
+    }
+}
@@ -0,0 +1,535 @@
+//! Shadow raster sink
+//!
+//! Renders the heights of the buildings (and the other features) into a hillshade image with the shadows cast
+//! by the sun at the given date and time. This is a quick look for urban planning presentations, not a precise
+//! sunlight analysis: the terrain is not considered, and each feature stands on the ground at its lowest point.
+
+mod geotiff;
+mod sun;
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use earcut::Earcut;
+use geotiff::{write_geotiff, GeoTransform};
+use image::{codecs::png::PngEncoder, ExtendedColorType, ImageEncoder};
+use nusamai_citygml::{
+    geometry::{GeometryStore, GeometryType},
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+};
+use nusamai_projection::crs::{EPSG_JGD2011_GEOGRAPHIC_2D, EPSG_JGD2011_GEOGRAPHIC_3D};
+use rayon::prelude::*;
+use sun::{parse_datetime, sun_position};
+
+use super::{option::output_parameter, terrain::raster::rasterize_pixels};
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer,
+    transformer::{use_lod_config, TransformerSettings},
+};
+
+const RELIEF_FEATURE: &str = "dem:ReliefFeature";
+/// Default date and time (noon of the winter solstice, when the shadows are the longest)
+const DEFAULT_DATETIME: &str = "2024-12-21T12:00:00+09:00";
+const METERS_PER_DEGREE: f64 = 111_320.0;
+/// Maximum width and height of the raster in pixels
+const MAX_RASTER_SIZE: u32 = 16384;
+/// Maximum length of the shadows drawn outside the extent of the features (meters)
+const MAX_SHADOW_LENGTH: f64 = 1000.0;
+/// Pixels higher than this (meters above the ground) are drawn as buildings
+const MIN_OBJECT_HEIGHT: f32 = 0.5;
+
+/// Triangle in the geographic coordinates with the height above the ground
+type Triangle = [[f64; 3]; 3];
+
+pub struct ShadowSinkProvider {}
+
+impl DataSinkProvider for ShadowSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "shadow".to_string(),
+            name: "Shadow Raster (PNG / GeoTIFF)".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "datetime".into(),
+            entry: ParameterEntry {
+                description: "Date and time of the sun position (e.g. 2024-12-21T12:00:00+09:00)"
+                    .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter {
+                    value: Some(DEFAULT_DATETIME.into()),
+                }),
+                label: Some("日時（太陽の位置）".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "resolution".into(),
+            entry: ParameterEntry {
+                description: "Size of a pixel in meters".into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: Some(1),
+                    min: Some(1),
+                    max: Some(100),
+                }),
+                label: Some("解像度（m/ピクセル）".into()),
+            },
+        });
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        let mut settings: TransformerSettings = TransformerSettings::new();
+        settings.insert(use_lod_config("max_lod", None));
+
+        settings
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let datetime = get_parameter_value!(params, "datetime", String);
+        let resolution = get_parameter_value!(params, "resolution", Integer).unwrap();
+        let transform_settings = self.transformer_options();
+
+        Box::<ShadowSink>::new(ShadowSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            datetime: datetime.clone().unwrap_or_else(|| DEFAULT_DATETIME.into()),
+            resolution: resolution as f64,
+            transform_settings,
+        })
+    }
+}
+
+pub struct ShadowSink {
+    output_path: PathBuf,
+    /// Date and time of the sun position (validated when the sink runs)
+    datetime: String,
+    /// Size of a pixel in meters
+    resolution: f64,
+    transform_settings: TransformerSettings,
+}
+
+impl DataSink for ShadowSink {
+    fn make_requirements(&mut self, properties: TransformerSettings) -> DataRequirements {
+        let default_requirements = DataRequirements {
+            output_epsg: EPSG_JGD2011_GEOGRAPHIC_3D,
+            key_value: transformer::KeyValueSpec::None,
+            ..Default::default()
+        };
+
+        for config in properties.configs.iter() {
+            let _ = &self.transform_settings.update_transformer(config.clone());
+        }
+
+        self.transform_settings.build(default_requirements)
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
+        let datetime = parse_datetime(&self.datetime).ok_or_else(|| {
+            PipelineError::Other(format!(
+                "Invalid date and time: {} (expected e.g. {DEFAULT_DATETIME})",
+                self.datetime
+            ))
+        })?;
+
+        let triangles = Mutex::new(Vec::new());
+        let result = upstream.into_iter().par_bridge().try_for_each(|parcel| {
+            feedback.ensure_not_canceled()?;
+            let entity_triangles = entity_triangles(&parcel.entity);
+            triangles.lock().unwrap().extend(entity_triangles);
+            Ok::<(), PipelineError>(())
+        });
+        match result {
+            Ok(_) => {}
+            Err(PipelineError::Canceled) => return Ok(()),
+            Err(error) => return Err(error),
+        }
+        let triangles = triangles.into_inner().unwrap();
+        if triangles.is_empty() {
+            feedback.warn("No features to render".into());
+            return Ok(());
+        }
+
+        let extent = Extent::of(&triangles);
+        let center = [
+            (extent.min[0] + extent.max[0]) / 2.0,
+            (extent.min[1] + extent.max[1]) / 2.0,
+        ];
+        let (azimuth, elevation) = sun_position(datetime, center[0], center[1]);
+        feedback.info(format!(
+            "Sun position at {datetime}: azimuth {azimuth:.1}°, elevation {elevation:.1}°"
+        ));
+        if elevation <= 0.0 {
+            return Err(PipelineError::Other(format!(
+                "The sun is below the horizon at {datetime}"
+            )));
+        }
+
+        let raster = Raster::new(&extent, center[1], self.resolution, elevation)?;
+        feedback.info(format!(
+            "Rendering a {}x{} raster ({} m/pixel)",
+            raster.width, raster.height, self.resolution
+        ));
+
+        let mut heights = vec![f32::NAN; raster.len()];
+        rasterize_pixels(
+            triangles.iter().map(|triangle| raster.to_pixels(triangle)),
+            raster.width,
+            raster.height,
+            &mut heights,
+        );
+        drop(triangles);
+        feedback.ensure_not_canceled()?;
+
+        let shadows = cast_shadows(&heights, &raster, azimuth, elevation);
+        feedback.ensure_not_canceled()?;
+        let shades = hillshade(&heights, &raster, azimuth, elevation);
+        let rgb: Vec<u8> = heights
+            .iter()
+            .zip(shades)
+            .zip(shadows)
+            .flat_map(|((&height, shade), shadow)| colorize(height, shade, shadow))
+            .collect();
+
+        self.write_image(&raster, &rgb)
+    }
+}
+
+impl ShadowSink {
+    /// Writes a GeoTIFF for `.tif`/`.tiff` outputs, or a PNG with a world file (`.pgw`) otherwise
+    fn write_image(&self, raster: &Raster, rgb: &[u8]) -> Result<()> {
+        if let Some(dir) = self.output_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let is_tiff = self
+            .output_path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("tif") || ext.eq_ignore_ascii_case("tiff"));
+
+        let mut writer = BufWriter::new(File::create(&self.output_path)?);
+        if is_tiff {
+            write_geotiff(
+                &mut writer,
+                raster.width,
+                raster.height,
+                rgb,
+                &raster.transform,
+            )?;
+        } else {
+            PngEncoder::new(&mut writer)
+                .write_image(rgb, raster.width, raster.height, ExtendedColorType::Rgb8)
+                .map_err(|err| PipelineError::Other(format!("Failed to encode PNG: {}", err)))?;
+            std::fs::write(
+                self.output_path.with_extension("pgw"),
+                raster.transform.world_file(),
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Triangulates the polygons of the entity. The heights are measured from the lowest vertex of the entity.
+fn entity_triangles(entity: &nusamai_plateau::Entity) -> Vec<Triangle> {
+    let Value::Object(obj) = &entity.root else {
+        return vec![];
+    };
+    if obj.typename == RELIEF_FEATURE {
+        return vec![];
+    }
+
+    let geom_store = entity.geometry_store.read().unwrap();
+    let mut triangles = Vec::new();
+    collect_triangles(&geom_store, &entity.root, &mut triangles);
+
+    let base = triangles
+        .iter()
+        .flatten()
+        .map(|v| v[2])
+        .fold(f64::MAX, f64::min);
+    for v in triangles.iter_mut().flatten() {
+        v[2] -= base;
+    }
+    triangles
+}
+
+fn collect_triangles(geom_store: &GeometryStore, value: &Value, out: &mut Vec<Triangle>) {
+    match value {
+        Value::Object(obj) => {
+            if let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype {
+                let mut earcutter = Earcut::new();
+                let mut buf2d: Vec<[f64; 2]> = Vec::new();
+                let mut index_buf: Vec<u32> = Vec::new();
+
+                for entry in geometries {
+                    if !matches!(
+                        entry.ty,
+                        GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+                    ) {
+                        continue;
+                    }
+                    for poly in geom_store
+                        .multipolygon
+                        .iter_range(entry.pos as usize..(entry.pos + entry.len) as usize)
+                    {
+                        // triangulate in the plan view (vertical polygons are degenerated and dropped)
+                        let indices = poly.raw_coords();
+                        buf2d.clear();
+                        buf2d.extend(indices.iter().map(|&idx| {
+                            let [x, y, _] = geom_store.vertices[idx as usize];
+                            [x, y]
+                        }));
+                        earcutter.earcut(
+                            buf2d.iter().cloned(),
+                            poly.hole_indices(),
+                            &mut index_buf,
+                        );
+                        out.extend(index_buf.chunks_exact(3).map(|tri| {
+                            [0, 1, 2]
+                                .map(|i| geom_store.vertices[indices[tri[i] as usize] as usize])
+                        }));
+                    }
+                }
+            }
+            for (_, value) in &obj.attributes {
+                collect_triangles(geom_store, value, out);
+            }
+        }
+        Value::Array(arr) => {
+            for value in arr {
+                collect_triangles(geom_store, value, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Extent of the triangles: [lng, lat, height]
+struct Extent {
+    min: [f64; 3],
+    max: [f64; 3],
+}
+
+impl Extent {
+    fn of(triangles: &[Triangle]) -> Self {
+        let mut extent = Self {
+            min: [f64::MAX; 3],
+            max: [f64::MIN; 3],
+        };
+        for v in triangles.iter().flatten() {
+            for ((min, max), &x) in extent.min.iter_mut().zip(&mut extent.max).zip(v) {
+                *min = min.min(x);
+                *max = max.max(x);
+            }
+        }
+        extent
+    }
+}
+
+struct Raster {
+    width: u32,
+    height: u32,
+    /// Size of a pixel in meters
+    resolution: f64,
+    transform: GeoTransform,
+}
+
+impl Raster {
+    /// Covers the extent with a margin for the shadows
+    fn new(extent: &Extent, lat: f64, resolution: f64, elevation: f64) -> Result<Self> {
+        let pixel_size = [
+            resolution / (METERS_PER_DEGREE * lat.to_radians().cos()),
+            resolution / METERS_PER_DEGREE,
+        ];
+        let shadow_length =
+            (extent.max[2].max(0.0) / elevation.to_radians().tan()).min(MAX_SHADOW_LENGTH);
+        let margin = (shadow_length / resolution).ceil() + 1.0;
+
+        let width = ((extent.max[0] - extent.min[0]) / pixel_size[0]).ceil() + margin * 2.0;
+        let height = ((extent.max[1] - extent.min[1]) / pixel_size[1]).ceil() + margin * 2.0;
+        if width > MAX_RASTER_SIZE as f64 || height > MAX_RASTER_SIZE as f64 {
+            return Err(PipelineError::Other(format!(
+                "The raster is too large ({width}x{height} pixels). Use a larger resolution."
+            )));
+        }
+
+        Ok(Self {
+            width: width as u32,
+            height: height as u32,
+            resolution,
+            transform: GeoTransform {
+                epsg: EPSG_JGD2011_GEOGRAPHIC_2D,
+                origin: [
+                    extent.min[0] - margin * pixel_size[0],
+                    extent.max[1] + margin * pixel_size[1],
+                ],
+                pixel_size,
+            },
+        })
+    }
+
+    fn len(&self) -> usize {
+        (self.width * self.height) as usize
+    }
+
+    fn to_pixels(&self, triangle: &Triangle) -> [[f32; 3]; 3] {
+        let [x0, y0] = self.transform.origin;
+        let [sx, sy] = self.transform.pixel_size;
+        triangle.map(|[x, y, h]| [((x - x0) / sx) as f32, ((y0 - y) / sy) as f32, h as f32])
+    }
+}
+
+/// Height above the ground (0 where there is no data)
+fn height_at(heights: &[f32], raster: &Raster, col: i64, row: i64) -> f32 {
+    let col = col.clamp(0, raster.width as i64 - 1);
+    let row = row.clamp(0, raster.height as i64 - 1);
+    let h = heights[(row * raster.width as i64 + col) as usize];
+    if h.is_nan() {
+        0.0
+    } else {
+        h
+    }
+}
+
+/// Marches from each pixel toward the sun, and returns whether the pixels are in the shadows.
+fn cast_shadows(heights: &[f32], raster: &Raster, azimuth: f64, elevation: f64) -> Vec<bool> {
+    let max_height = heights
+        .iter()
+        .filter(|h| !h.is_nan())
+        .fold(0.0f32, |a, &b| a.max(b)) as f64;
+    // direction to the sun in the pixel coordinates (rows go southward)
+    let (dx, dy) = (azimuth.to_radians().sin(), -azimuth.to_radians().cos());
+    // rise of the ray per pixel
+    let rise = elevation.to_radians().tan() * raster.resolution;
+    let (width, height) = (raster.width as f64, raster.height as f64);
+
+    let mut shadows = vec![false; raster.len()];
+    shadows
+        .par_chunks_mut(raster.width as usize)
+        .enumerate()
+        .for_each(|(row, out)| {
+            for (col, shadow) in out.iter_mut().enumerate() {
+                let h0 = height_at(heights, raster, col as i64, row as i64) as f64;
+                let mut step = 1.0;
+                loop {
+                    let ray_height = h0 + rise * step;
+                    if ray_height >= max_height {
+                        break;
+                    }
+                    let x = col as f64 + 0.5 + dx * step;
+                    let y = row as f64 + 0.5 + dy * step;
+                    if x < 0.0 || y < 0.0 || x >= width || y >= height {
+                        break;
+                    }
+                    if height_at(heights, raster, x as i64, y as i64) as f64 > ray_height {
+                        *shadow = true;
+                        break;
+                    }
+                    step += 1.0;
+                }
+            }
+        });
+    shadows
+}
+
+/// Illumination of the surfaces (0.0 - 1.0), by the standard hillshade algorithm
+fn hillshade(heights: &[f32], raster: &Raster, azimuth: f64, elevation: f64) -> Vec<f32> {
+    let zenith = (90.0 - elevation).to_radians();
+    let azimuth_math = (360.0 - azimuth + 90.0).rem_euclid(360.0).to_radians();
+    let cell = 8.0 * raster.resolution;
+
+    (0..raster.len())
+        .into_par_iter()
+        .map(|i| {
+            let (col, row) = (
+                (i % raster.width as usize) as i64,
+                (i / raster.width as usize) as i64,
+            );
+            let z = |dc: i64, dr: i64| height_at(heights, raster, col + dc, row + dr) as f64;
+            let dzdx = ((z(1, -1) + 2.0 * z(1, 0) + z(1, 1))
+                - (z(-1, -1) + 2.0 * z(-1, 0) + z(-1, 1)))
+                / cell;
+            let dzdy = ((z(-1, 1) + 2.0 * z(0, 1) + z(1, 1))
+                - (z(-1, -1) + 2.0 * z(0, -1) + z(1, -1)))
+                / cell;
+            let slope = dzdx.hypot(dzdy).atan();
+            let aspect = dzdy.atan2(-dzdx);
+            let shade = zenith.cos() * slope.cos()
+                + zenith.sin() * slope.sin() * (azimuth_math - aspect).cos();
+            shade.clamp(0.0, 1.0) as f32
+        })
+        .collect()
+}
+
+fn colorize(height: f32, shade: f32, shadow: bool) -> [u8; 3] {
+    if height.is_nan() || height < MIN_OBJECT_HEIGHT {
+        // ground
+        return match shadow {
+            true => [150, 156, 172],
+            false => [245, 244, 240],
+        };
+    }
+    let v = (90.0 + 150.0 * shade) * if shadow { 0.7 } else { 1.0 };
+    [v as u8, (v * 0.97) as u8, (v * 0.92) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 10m tower at the center of a 21x21 raster (1m/pixel)
+    fn tower() -> (Raster, Vec<f32>) {
+        let raster = Raster {
+            width: 21,
+            height: 21,
+            resolution: 1.0,
+            transform: GeoTransform {
+                epsg: EPSG_JGD2011_GEOGRAPHIC_2D,
+                origin: [0.0, 0.0],
+                pixel_size: [1.0, 1.0],
+            },
+        };
+        let mut heights = vec![f32::NAN; raster.len()];
+        heights[10 * 21 + 10] = 10.0;
+        (raster, heights)
+    }
+
+    #[test]
+    fn shadow_direction() {
+        let (raster, heights) = tower();
+        // sun in the south at 45°: the shadow extends 10m to the north
+        let shadows = cast_shadows(&heights, &raster, 180.0, 45.0);
+        let shadowed = |col: usize, row: usize| shadows[row * 21 + col];
+        assert!(shadowed(10, 9));
+        assert!(shadowed(10, 1));
+        assert!(!shadowed(10, 11));
+        assert!(!shadowed(9, 9));
+        assert!(!shadowed(10, 10));
+
+        // sun in the east: the shadow extends to the west
+        let shadows = cast_shadows(&heights, &raster, 90.0, 45.0);
+        assert!(shadows[10 * 21 + 5]);
+        assert!(!shadows[10 * 21 + 15]);
+    }
+
+    #[test]
+    fn hillshade_flat() {
+        let (raster, heights) = tower();
+        let shades = hillshade(&heights, &raster, 180.0, 30.0);
+        // flat ground is lit by cos(zenith)
+        assert!((shades[0] - 0.5).abs() < 1e-6);
+    }
+}
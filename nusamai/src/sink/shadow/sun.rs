@@ -0,0 +1,99 @@
+//! Position of the sun
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone};
+
+/// Japan Standard Time, assumed for the date/times without offsets
+const JST_OFFSET_SECS: i32 = 9 * 3600;
+
+/// Parses a date/time such as `2024-12-21T10:00:00+09:00`.
+/// The offset can be omitted (defaults to JST), and so can the seconds.
+pub fn parse_datetime(s: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+        return Some(datetime);
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M"))
+        .ok()?;
+    FixedOffset::east_opt(JST_OFFSET_SECS)?
+        .from_local_datetime(&naive)
+        .single()
+}
+
+/// Computes the azimuth (degrees, clockwise from north) and the elevation (degrees) of the sun
+/// at the location (longitude and latitude in degrees).
+///
+/// Based on the low-precision formulas of the Astronomical Almanac (accurate to about 0.1°).
+pub fn sun_position(datetime: DateTime<FixedOffset>, lng: f64, lat: f64) -> (f64, f64) {
+    // days since J2000.0
+    let n = datetime.timestamp() as f64 / 86400.0 + 2440587.5 - 2451545.0;
+
+    let mean_longitude = (280.460 + 0.9856474 * n).rem_euclid(360.0);
+    let mean_anomaly = (357.528 + 0.9856003 * n).rem_euclid(360.0).to_radians();
+    let ecliptic_longitude =
+        (mean_longitude + 1.915 * mean_anomaly.sin() + 0.020 * (2.0 * mean_anomaly).sin())
+            .to_radians();
+    let obliquity = (23.439 - 0.0000004 * n).to_radians();
+
+    let right_ascension = (obliquity.cos() * ecliptic_longitude.sin())
+        .atan2(ecliptic_longitude.cos())
+        .to_degrees();
+    let declination = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+
+    // Greenwich mean sidereal time (degrees)
+    let gmst = (280.46061837 + 360.98564736629 * n).rem_euclid(360.0);
+    let hour_angle = (gmst + lng - right_ascension).to_radians();
+
+    let lat = lat.to_radians();
+    let elevation =
+        (lat.sin() * declination.sin() + lat.cos() * declination.cos() * hour_angle.cos()).asin();
+    let azimuth = (-hour_angle.sin())
+        .atan2(declination.tan() * lat.cos() - lat.sin() * hour_angle.cos())
+        .to_degrees()
+        .rem_euclid(360.0);
+
+    (azimuth, elevation.to_degrees())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKYO: (f64, f64) = (139.7671, 35.6812);
+
+    #[test]
+    fn test_parse_datetime() {
+        let expected = parse_datetime("2024-12-21T10:00:00+09:00").unwrap();
+        assert_eq!(parse_datetime("2024-12-21T10:00"), Some(expected));
+        assert_eq!(parse_datetime("2024-12-21T01:00:00Z"), Some(expected));
+        assert_eq!(parse_datetime("2024/12/21"), None);
+    }
+
+    #[test]
+    fn test_sun_position() {
+        // winter solstice, around the solar noon
+        let (azimuth, elevation) = sun_position(
+            parse_datetime("2024-12-21T11:40").unwrap(),
+            TOKYO.0,
+            TOKYO.1,
+        );
+        assert!((azimuth - 180.0).abs() < 2.0, "{azimuth}");
+        assert!((elevation - 30.9).abs() < 0.5, "{elevation}");
+
+        // summer solstice, morning
+        let (azimuth, elevation) = sun_position(
+            parse_datetime("2024-06-21T09:00").unwrap(),
+            TOKYO.0,
+            TOKYO.1,
+        );
+        assert!((85.0..100.0).contains(&azimuth), "{azimuth}");
+        assert!((45.0..55.0).contains(&elevation), "{elevation}");
+
+        // night
+        let (_, elevation) = sun_position(
+            parse_datetime("2024-06-21T23:00").unwrap(),
+            TOKYO.0,
+            TOKYO.1,
+        );
+        assert!(elevation < 0.0);
+    }
+}
@@ -1,22 +1,35 @@
 use chrono::Datelike;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use nusamai_citygml::{
     object::{Map, Value},
     schema::{DataTypeDef, FeatureTypeDef, TypeDef, TypeRef},
 };
 use shapefile::dbase::{self, Date, FieldValue, Record};
 
-pub fn make_table_builder(
-    typedef: &TypeDef,
-) -> (dbase::TableWriterBuilder, HashMap<String, FieldValue>) {
+/// Maximum length of the DBF field names in bytes
+const MAX_FIELD_NAME_LEN: usize = 10;
+
+/// DBF fields made from the attributes of a type
+#[derive(Default)]
+pub struct Fields {
+    /// Default values for the attributes that are not present
+    pub defaults: HashMap<String, FieldValue>,
+    /// Field names in the DBF file for the attribute names that are too long
+    pub renamed: HashMap<String, String>,
+}
+
+pub fn make_table_builder(typedef: &TypeDef) -> (dbase::TableWriterBuilder, Fields) {
     let mut builder = dbase::TableWriterBuilder::new();
     let mut defaults = HashMap::new();
+    let mut renamed = HashMap::new();
+    let mut used_names = HashSet::new();
 
     let attributes = match typedef {
         TypeDef::Feature(FeatureTypeDef { attributes, .. }) => {
             let key = "id";
             builder = builder.add_character_field(key.try_into().unwrap(), 255);
             defaults.insert(key.into(), FieldValue::Character(None));
+            used_names.insert(key.to_uppercase());
             attributes
         }
         TypeDef::Data(DataTypeDef { attributes, .. }) => attributes,
@@ -24,11 +37,15 @@ pub fn make_table_builder(
     };
 
     for (field_name, attr) in attributes {
-        let Ok(name) = field_name.as_str().try_into() else {
+        let dbf_name = dbf_field_name(field_name, &mut used_names);
+        let Ok(name) = dbf_name.as_str().try_into() else {
             log::error!("Field name '{}' cannot be used in Shapefile", field_name);
             continue;
         };
-        let key = field_name.to_string();
+        if &dbf_name != field_name {
+            renamed.insert(field_name.to_string(), dbf_name.clone());
+        }
+        let key = dbf_name;
 
         match attr.type_ref {
             TypeRef::String | TypeRef::Code | TypeRef::URI | TypeRef::JsonString(_) => {
@@ -44,15 +61,17 @@ pub fn make_table_builder(
                 defaults.insert(key, FieldValue::Numeric(None));
             }
             TypeRef::Boolean => {
-                builder = builder.add_character_field(name, 6);
-                defaults.insert(key, FieldValue::Character(None));
+                builder = builder.add_logical_field(name);
+                defaults.insert(key, FieldValue::Logical(None));
             }
             TypeRef::Date => {
                 builder = builder.add_date_field(name);
                 defaults.insert(key, FieldValue::Date(None));
             }
             TypeRef::DateTime => {
-                // todo
+                // DBF has no datetime type, so it is stored as an ISO 8601 string
+                builder = builder.add_character_field(name, 32);
+                defaults.insert(key, FieldValue::Character(None));
             }
             TypeRef::Point => {
                 // todo
@@ -66,23 +85,44 @@ pub fn make_table_builder(
         }
     }
 
-    (builder, defaults)
+    (builder, Fields { defaults, renamed })
+}
+
+/// Makes a unique DBF field name that fits in 10 bytes. Field names are case-insensitive in DBF.
+fn dbf_field_name(name: &str, used_names: &mut HashSet<String>) -> String {
+    let mut candidate = truncate_on_char_boundary(name, MAX_FIELD_NAME_LEN).to_string();
+    let mut n = 1;
+    while used_names.contains(&candidate.to_uppercase()) {
+        let suffix = format!("_{n}");
+        let base = truncate_on_char_boundary(name, MAX_FIELD_NAME_LEN - suffix.len());
+        candidate = format!("{base}{suffix}");
+        n += 1;
+    }
+    used_names.insert(candidate.to_uppercase());
+    candidate
+}
+
+fn truncate_on_char_boundary(s: &str, max_len: usize) -> &str {
+    let mut end = s.len().min(max_len);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
 }
 
-pub fn attributes_to_record(
-    attributes: Map,
-    fields_default: &HashMap<String, FieldValue>,
-) -> Record {
+pub fn attributes_to_record(attributes: Map, fields: &Fields) -> Record {
     let mut record = dbase::Record::default();
 
-    // Fill in with default values for attributes that are not present
-    for (name, default) in fields_default {
-        if !attributes.contains_key(name) {
-            record.insert(name.to_string(), default.clone());
-        }
+    // Fill in with default values first, which are overwritten by the present attributes
+    for (name, default) in &fields.defaults {
+        record.insert(name.to_string(), default.clone());
     }
 
     for (attr_name, attr_value) in attributes {
+        let attr_name = match fields.renamed.get(&attr_name) {
+            Some(name) => name.clone(),
+            None => attr_name,
+        };
         match attr_value {
             Value::String(s) => {
                 // Shapefile cannot store string longer than 254 bytes
@@ -112,13 +152,7 @@ pub fn attributes_to_record(
                 record.insert(attr_name, FieldValue::Numeric(Some(m.value())));
             }
             Value::Boolean(b) => {
-                record.insert(
-                    attr_name,
-                    FieldValue::Character(Some(match b {
-                        true => "true".to_string(),
-                        false => "false".to_string(),
-                    })),
-                );
+                record.insert(attr_name, FieldValue::Logical(Some(b)));
             }
             Value::Uri(u) => {
                 record.insert(
@@ -163,3 +197,25 @@ fn trim_string_bytes(s: String, n: usize) -> String {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dbf_field_name() {
+        let mut used_names = HashSet::from(["ID".to_string()]);
+        assert_eq!(dbf_field_name("name", &mut used_names), "name");
+        assert_eq!(
+            dbf_field_name("buildingHeight", &mut used_names),
+            "buildingHe"
+        );
+        assert_eq!(
+            dbf_field_name("buildingHeightLevel", &mut used_names),
+            "building_1"
+        );
+        assert_eq!(dbf_field_name("Id", &mut used_names), "Id_1");
+        // multi-byte characters are not split
+        assert_eq!(dbf_field_name("建物利用現況", &mut used_names), "建物利");
+    }
+}
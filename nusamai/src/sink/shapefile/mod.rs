@@ -28,7 +28,10 @@ use crate::{
     pipeline::{Feedback, PipelineError, Receiver, Result},
     sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
     transformer,
-    transformer::{use_lod_config, TransformerSettings},
+    transformer::{
+        building_adjacency_config, name_columns_config, prefix_config, solar_attributes_config,
+        underground_config, use_lod_config, TransformerSettings,
+    },
 };
 
 use super::option::output_parameter;
@@ -52,6 +55,11 @@ impl DataSinkProvider for ShapefileSinkProvider {
     fn transformer_options(&self) -> TransformerSettings {
         let mut settings: TransformerSettings = TransformerSettings::new();
         settings.insert(use_lod_config("max_lod", None));
+        settings.insert(underground_config());
+        settings.insert(solar_attributes_config());
+        settings.insert(building_adjacency_config());
+        settings.insert(prefix_config(&["keep"]));
+        settings.insert(name_columns_config());
 
         settings
     }
@@ -148,7 +156,12 @@ impl DataSink for ShapefileSink {
                             ))
                         })?;
 
-                        let (table_builder, fields_default) = make_table_builder(typedef);
+                        let (table_builder, fields) = make_table_builder(typedef);
+                        for (name, dbf_name) in &fields.renamed {
+                            feedback.info(format!(
+                                "{typename}: field '{name}' is renamed to '{dbf_name}' (DBF field names are limited to 10 bytes)"
+                            ));
+                        }
 
                         // Create all the files needed for the shapefile to be complete (.shp, .shx, .dbf)
                         std::fs::create_dir_all(&self.output_path)?;
@@ -173,7 +186,7 @@ impl DataSink for ShapefileSink {
 
                             // Write each feature
                             for (shape, attributes) in features {
-                                let record = attributes_to_record(attributes, &fields_default);
+                                let record = attributes_to_record(attributes, &fields);
 
                                 match shape {
                                     shapefile::Shape::PolygonZ(polygon) => {
@@ -215,6 +228,9 @@ impl DataSink for ShapefileSink {
                             }
                         }
 
+                        // The attributes are encoded in UTF-8
+                        std::fs::write(shp_path.with_extension("cpg"), "UTF-8")?;
+
                         // If this type has no geometry (i.e. Data or Object stereotype)
                         if has_no_geometry {
                             // Remove dummy .shp and .shx and write a NullShape file.
@@ -0,0 +1,402 @@
+//! Styling profiles shared by the sinks
+//!
+//! A profile defines the fill color, the stroke color and the line width per feature type,
+//! optionally varied by the value of an attribute (e.g. `bldg:usage` of the buildings, `tran:function` of the roads).
+//! The sinks convert the same definition into their own styles (KML `<Style>`, CZML materials,
+//! MapLibre style JSON for MVT, SLD in the `layer_styles` table of GeoPackage), so that the outputs look consistent.
+//!
+//! The definition file is a JSON like `default.json`, which is also the built-in profile:
+//!
+//! ```json
+//! {
+//!   "default": { "fill": "#ccccccff", "stroke": "#808080", "width": 1.0 },
+//!   "types": {
+//!     "bldg:Building": {
+//!       "fill": "#e0e0e0",
+//!       "by": "bldg:usage",
+//!       "values": { "商業施設": { "fill": "#ff8c66" } }
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! The colors are `#rrggbb` or `#rrggbbaa`. The values are compared with the names of the codes
+//! (what the sinks write as the attribute values), or with the codes themselves.
+
+use std::{fs, path::Path};
+
+use indexmap::IndexMap;
+use nusamai_citygml::object::{Map, Object, Value};
+use serde::Deserialize;
+
+use crate::pipeline::{PipelineError, Result};
+
+/// The built-in profile
+const DEFAULT_PROFILE: &str = include_str!("default.json");
+
+/// Used for the properties defined neither in the profile nor for the feature type
+const FALLBACK_STYLE: Style = Style {
+    fill: Color::rgb(0xcc, 0xcc, 0xcc),
+    stroke: Color::rgb(0x80, 0x80, 0x80),
+    width: 1.0,
+};
+
+/// RGBA color
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b, a: 255 }
+    }
+
+    /// Parses `#rrggbb` or `#rrggbbaa`
+    pub fn parse(s: &str) -> Option<Self> {
+        let hex = s.strip_prefix('#')?;
+        if !hex.is_ascii() || !(hex.len() == 6 || hex.len() == 8) {
+            return None;
+        }
+        let byte = |i: usize| -> Option<u8> { u8::from_str_radix(hex.get(i..i + 2)?, 16).ok() };
+        Some(Self {
+            r: byte(0)?,
+            g: byte(2)?,
+            b: byte(4)?,
+            a: if hex.len() == 8 { byte(6)? } else { 255 },
+        })
+    }
+
+    pub fn rgba(&self) -> [u8; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+
+    /// `#rrggbb` (without the alpha)
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+
+    /// Alpha in 0.0 - 1.0
+    pub fn opacity(&self) -> f64 {
+        self.a as f64 / 255.0
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        Self::parse(&s).ok_or_else(|| format!("invalid color (expected #rrggbb or #rrggbbaa): {s}"))
+    }
+}
+
+/// Properties given in the definition (the missing ones are inherited)
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StyleProps {
+    pub fill: Option<Color>,
+    pub stroke: Option<Color>,
+    /// Line width in pixels
+    pub width: Option<f64>,
+}
+
+/// Resolved style of a feature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Style {
+    pub fill: Color,
+    pub stroke: Color,
+    /// Line width in pixels
+    pub width: f64,
+}
+
+impl Style {
+    fn apply(self, props: &StyleProps) -> Self {
+        Self {
+            fill: props.fill.unwrap_or(self.fill),
+            stroke: props.stroke.unwrap_or(self.stroke),
+            width: props.width.unwrap_or(self.width),
+        }
+    }
+}
+
+/// Style definition of a feature type
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TypeStyle {
+    #[serde(flatten)]
+    pub base: StyleProps,
+    /// Attribute whose value selects the style (e.g. `bldg:usage`)
+    pub by: Option<String>,
+    /// Styles per the attribute value
+    #[serde(default)]
+    pub values: IndexMap<String, StyleProps>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StyleProfile {
+    #[serde(default)]
+    pub default: StyleProps,
+    /// Styles per feature type (e.g. `bldg:Building`)
+    #[serde(default)]
+    pub types: IndexMap<String, TypeStyle>,
+}
+
+/// Styles of a feature type, to be converted into the rules of the style formats
+pub struct TypeRules<'a> {
+    /// Style of the features matching none of the values
+    pub base: Style,
+    /// Attribute name in the definition (e.g. `bldg:usage`)
+    pub attribute: Option<&'a str>,
+    /// Attribute values and their styles
+    pub values: Vec<(&'a str, Style)>,
+}
+
+impl StyleProfile {
+    pub fn builtin() -> Self {
+        serde_json::from_str(DEFAULT_PROFILE).expect("the built-in profile should be valid")
+    }
+
+    /// Loads the definition file, or returns the built-in profile if no path is given
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::builtin());
+        };
+        let text = fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(|err| {
+            PipelineError::Other(format!(
+                "Invalid style definition {}: {err}",
+                path.display()
+            ))
+        })
+    }
+
+    /// Style of the feature types not in the profile
+    pub fn base_style(&self) -> Style {
+        FALLBACK_STYLE.apply(&self.default)
+    }
+
+    pub fn type_rules(&self, typename: &str) -> TypeRules<'_> {
+        let base = self.base_style();
+        let Some(type_style) = self.types.get(typename) else {
+            return TypeRules {
+                base,
+                attribute: None,
+                values: Vec::new(),
+            };
+        };
+        let base = base.apply(&type_style.base);
+        TypeRules {
+            base,
+            attribute: type_style.by.as_deref(),
+            values: type_style
+                .values
+                .iter()
+                .map(|(value, props)| (value.as_str(), base.apply(props)))
+                .collect(),
+        }
+    }
+
+    /// Style applied to the object
+    pub fn style_of(&self, obj: &Object) -> Style {
+        let (type_idx, value_idx) = self.find_rule(obj);
+        let Some((_, type_style)) = type_idx.and_then(|idx| self.types.get_index(idx)) else {
+            return self.base_style();
+        };
+        let style = self.base_style().apply(&type_style.base);
+        match value_idx.and_then(|idx| type_style.values.get_index(idx)) {
+            Some((_, props)) => style.apply(props),
+            None => style,
+        }
+    }
+
+    /// Identifier of the style applied to the object (for the formats referring to the shared styles, like KML)
+    pub fn style_id(&self, obj: &Object) -> String {
+        let (type_idx, value_idx) = self.find_rule(obj);
+        style_id(type_idx, value_idx)
+    }
+
+    /// All the styles with their identifiers
+    pub fn styles(&self) -> Vec<(String, Style)> {
+        let mut styles = vec![(style_id(None, None), self.base_style())];
+        for (type_idx, typename) in self.types.keys().enumerate() {
+            let rules = self.type_rules(typename);
+            styles.push((style_id(Some(type_idx), None), rules.base));
+            for (value_idx, (_, style)) in rules.values.into_iter().enumerate() {
+                styles.push((style_id(Some(type_idx), Some(value_idx)), style));
+            }
+        }
+        styles
+    }
+
+    /// Finds the indices of the feature type and the value matching the object
+    fn find_rule(&self, obj: &Object) -> (Option<usize>, Option<usize>) {
+        let Some((type_idx, _, type_style)) = self.types.get_full(obj.typename.as_ref()) else {
+            return (None, None);
+        };
+        let value_idx = type_style
+            .by
+            .as_deref()
+            .and_then(|name| find_attribute(&obj.attributes, name))
+            .and_then(|value| {
+                type_style
+                    .values
+                    .keys()
+                    .position(|expected| value_matches(value, expected))
+            });
+        (Some(type_idx), value_idx)
+    }
+}
+
+fn style_id(type_idx: Option<usize>, value_idx: Option<usize>) -> String {
+    match (type_idx, value_idx) {
+        (None, _) => "style_default".to_string(),
+        (Some(t), None) => format!("style_{t}"),
+        (Some(t), Some(v)) => format!("style_{t}_{v}"),
+    }
+}
+
+/// Whether the (possibly renamed) attribute name corresponds to the name in the definition.
+///
+/// The prefix may have been kept (`bldg:usage`), removed (`usage`) or replaced (`bldg_usage`) by the transformer.
+pub fn matches_attribute_name(key: &str, name: &str) -> bool {
+    if key == name {
+        return true;
+    }
+    let Some((prefix, local_name)) = name.split_once(':') else {
+        return false;
+    };
+    key == local_name
+        || key
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix('_'))
+            .is_some_and(|rest| rest == local_name)
+}
+
+/// Finds the name of the attribute among the (renamed) names, e.g. the columns in the schema
+pub fn resolve_attribute_name<'a>(
+    keys: impl IntoIterator<Item = &'a String>,
+    name: &str,
+) -> Option<&'a str> {
+    let keys: Vec<&String> = keys.into_iter().collect();
+    keys.iter()
+        .find(|key| key.as_str() == name)
+        .or_else(|| keys.iter().find(|key| matches_attribute_name(key, name)))
+        .map(|key| key.as_str())
+}
+
+fn find_attribute<'a>(attributes: &'a Map, name: &str) -> Option<&'a Value> {
+    let key = resolve_attribute_name(attributes.keys(), name)?;
+    attributes.get(key)
+}
+
+fn value_matches(value: &Value, expected: &str) -> bool {
+    match value {
+        Value::String(s) => s == expected,
+        Value::Code(c) => c.value() == expected || c.code() == expected,
+        Value::Integer(i) => i.to_string() == expected,
+        Value::NonNegativeInteger(u) => u.to_string() == expected,
+        Value::Array(values) => values.iter().any(|v| value_matches(v, expected)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::{object::ObjectStereotype, Code};
+
+    use super::*;
+
+    fn building(key: &str, usage: Value) -> Object {
+        Object {
+            typename: "bldg:Building".into(),
+            stereotype: ObjectStereotype::Feature {
+                id: "bldg_1".into(),
+                geometries: Vec::new(),
+            },
+            attributes: Map::from_iter([(key.to_string(), usage)]),
+        }
+    }
+
+    #[test]
+    fn test_color() {
+        let color = Color::parse("#ff8000").unwrap();
+        assert_eq!(color.rgba(), [255, 128, 0, 255]);
+        assert_eq!(color.hex(), "#ff8000");
+        assert_eq!(Color::parse("#ff800080").unwrap().a, 128);
+        assert!(Color::parse("ff8000").is_none());
+        assert!(Color::parse("#ff80").is_none());
+        assert!(Color::parse("#gg8000").is_none());
+    }
+
+    #[test]
+    fn test_builtin() {
+        let profile = StyleProfile::builtin();
+        let rules = profile.type_rules("bldg:Building");
+        assert_eq!(rules.attribute, Some("bldg:usage"));
+        assert!(!rules.values.is_empty());
+        assert_eq!(
+            profile.type_rules("unknown:Type").base,
+            profile.base_style()
+        );
+    }
+
+    #[test]
+    fn test_style_of() {
+        let profile: StyleProfile = serde_json::from_str(
+            r##"{
+                "default": { "fill": "#101010", "width": 2 },
+                "types": {
+                    "bldg:Building": {
+                        "stroke": "#202020",
+                        "by": "bldg:usage",
+                        "values": {
+                            "住宅": { "fill": "#303030" },
+                            "商業施設": { "fill": "#404040", "width": 3 }
+                        }
+                    }
+                }
+            }"##,
+        )
+        .unwrap();
+
+        // code name, with the prefix removed
+        let obj = building(
+            "usage",
+            Value::Code(Code::new("商業施設".into(), "412".into())),
+        );
+        let style = profile.style_of(&obj);
+        assert_eq!(style.fill, Color::rgb(0x40, 0x40, 0x40));
+        assert_eq!(style.stroke, Color::rgb(0x20, 0x20, 0x20));
+        assert_eq!(style.width, 3.0);
+        assert_eq!(profile.style_id(&obj), "style_0_1");
+
+        // string, with the prefix replaced
+        let obj = building("bldg_usage", Value::String("住宅".into()));
+        assert_eq!(profile.style_of(&obj).fill, Color::rgb(0x30, 0x30, 0x30));
+        assert_eq!(profile.style_id(&obj), "style_0_0");
+
+        // no matching value
+        let obj = building("bldg:usage", Value::String("工場".into()));
+        let style = profile.style_of(&obj);
+        assert_eq!(style.fill, Color::rgb(0x10, 0x10, 0x10));
+        assert_eq!(style.width, 2.0);
+        assert_eq!(profile.style_id(&obj), "style_0");
+
+        assert_eq!(profile.styles().len(), 4);
+        assert!(serde_json::from_str::<StyleProfile>(r#"{"default": {"fill": "red"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_resolve_attribute_name() {
+        let keys = ["gml:id".to_string(), "uro_buildingID".to_string()];
+        assert_eq!(
+            resolve_attribute_name(&keys, "uro:buildingID"),
+            Some("uro_buildingID")
+        );
+        assert_eq!(resolve_attribute_name(&keys, "bldg:usage"), None);
+        assert!(matches_attribute_name("usage", "bldg:usage"));
+        assert!(!matches_attribute_name("bldgusage", "bldg:usage"));
+    }
+}
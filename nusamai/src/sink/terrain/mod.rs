@@ -0,0 +1,519 @@
+//! Terrain tiles sinks
+//!
+//! Converts the TIN reliefs (`dem:ReliefFeature`) into Terrain-RGB PNG tiles,
+//! or into Cesium quantized-mesh tiles (`layer.json` and `{z}/{x}/{y}.terrain`).
+//! The attributes are not used at all, so this is much lighter than converting reliefs with the other sinks.
+
+mod quantized_mesh;
+pub(super) mod raster;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+};
+
+use hashbrown::HashMap;
+use image::{codecs::png::PngEncoder, ExtendedColorType, ImageEncoder};
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+    GeometryType,
+};
+use raster::{encode_terrain_rgb, rasterize, slice_triangle, TileTriangle, TILE_SIZE};
+use rayon::prelude::*;
+use tinymvt::{webmercator::lnglat_to_web_mercator, TileZXY};
+
+use super::{
+    manifest::Manifest,
+    mvt::{feature_sorting_stage, tileid::TileIdMethod},
+    option::output_parameter,
+    pmtiles::{TileCompression, TileType},
+    tile_output::TileOutput,
+};
+use crate::{
+    get_parameter_value,
+    parameters::*,
+    pipeline::{Feedback, PipelineError, Receiver, Result},
+    sink::{DataRequirements, DataSink, DataSinkProvider, SinkInfo},
+    transformer,
+    transformer::TransformerSettings,
+};
+
+const RELIEF_FEATURE: &str = "dem:ReliefFeature";
+
+pub struct TerrainSinkProvider {}
+
+impl DataSinkProvider for TerrainSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "terrain".to_string(),
+            name: "Terrain (Terrain-RGB)".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "min_z".into(),
+            entry: ParameterEntry {
+                description: "Minumum zoom level".into(),
+                required: true,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: Some(8),
+                    min: Some(0),
+                    max: Some(20),
+                }),
+                label: Some("最小ズームレベル".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "max_z".into(),
+            entry: ParameterEntry {
+                description: "Maximum zoom level".into(),
+                required: true,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: Some(15),
+                    min: Some(0),
+                    max: Some(20),
+                }),
+                label: Some("最大ズームレベル".into()),
+            },
+        });
+
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        TransformerSettings::new()
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let min_z = get_parameter_value!(params, "min_z", Integer).unwrap() as u8;
+        let max_z = get_parameter_value!(params, "max_z", Integer).unwrap() as u8;
+
+        Box::<TerrainSink>::new(TerrainSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            min_z,
+            max_z,
+        })
+    }
+}
+
+struct TerrainSink {
+    output_path: PathBuf,
+    min_z: u8,
+    max_z: u8,
+}
+
+impl DataSink for TerrainSink {
+    fn make_requirements(&mut self, _properties: TransformerSettings) -> DataRequirements {
+        DataRequirements {
+            // keep the orthometric heights
+            output_epsg: nusamai_projection::crs::EPSG_JGD2011_GEOGRAPHIC_3D,
+            key_value: transformer::KeyValueSpec::None,
+            ..Default::default()
+        }
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
+        if self.max_z < self.min_z {
+            return Err(PipelineError::Other(
+                "max_z must be greater than or equal to min_z".into(),
+            ));
+        }
+
+        let (sender_sliced, receiver_sliced) = mpsc::sync_channel(2000);
+        let (sender_sorted, receiver_sorted) = mpsc::sync_channel(2000);
+
+        let tile_id_conv = TileIdMethod::Hilbert;
+
+        std::thread::scope(|s| {
+            // Slicing triangles along the tile boundaries
+            s.spawn(|| {
+                let (min_z, max_z) = (self.min_z, self.max_z);
+                let slice = |triangle: [[f64; 3]; 3], out: &mut HashMap<_, _>| {
+                    let triangle = triangle.map(|[lng, lat, height]| {
+                        let (mx, my) = lnglat_to_web_mercator(lng, lat);
+                        [mx, my, height]
+                    });
+                    slice_triangle(&triangle, min_z, max_z, out);
+                };
+                if let Err(error) = triangle_slicing_stage(
+                    feedback,
+                    upstream,
+                    |zxy| tile_id_conv.zxy_to_id(zxy.0, zxy.1, zxy.2),
+                    sender_sliced,
+                    slice,
+                ) {
+                    feedback.fatal_error(error);
+                }
+            });
+
+            // Sort triangles by tile_id (using external sorter)
+            s.spawn(move || {
+                if let Err(error) = feature_sorting_stage(feedback, receiver_sliced, sender_sorted)
+                {
+                    feedback.fatal_error(error);
+                }
+            });
+
+            // Rasterize the grouped triangles into tiles
+            let output_path = &self.output_path;
+            s.spawn(move || {
+                // Run in a separate thread pool to avoid deadlocks
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .use_current_thread()
+                    .build()
+                    .unwrap();
+                pool.install(|| {
+                    if let Err(error) = tile_writing_stage(output_path, feedback, receiver_sorted) {
+                        feedback.fatal_error(error);
+                    }
+                })
+            });
+        });
+
+        Ok(())
+    }
+
+    fn writes_manifest(&self) -> bool {
+        true
+    }
+}
+
+pub struct QuantizedMeshSinkProvider {}
+
+impl DataSinkProvider for QuantizedMeshSinkProvider {
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            id_name: "quantizedmesh".to_string(),
+            name: "Terrain (Quantized Mesh)".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        let mut params = Parameters::new();
+        params.define(output_parameter());
+        params.define(ParameterDefinition {
+            key: "max_z".into(),
+            entry: ParameterEntry {
+                description: "Maximum zoom level".into(),
+                required: true,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: Some(15),
+                    min: Some(0),
+                    max: Some(20),
+                }),
+                label: Some("最大ズームレベル".into()),
+            },
+        });
+
+        params
+    }
+
+    fn transformer_options(&self) -> TransformerSettings {
+        TransformerSettings::new()
+    }
+
+    fn create(&self, params: &Parameters) -> Box<dyn DataSink> {
+        let output_path = get_parameter_value!(params, "@output", FileSystemPath);
+        let max_z = get_parameter_value!(params, "max_z", Integer).unwrap() as u8;
+
+        Box::<QuantizedMeshSink>::new(QuantizedMeshSink {
+            output_path: output_path.as_ref().unwrap().into(),
+            max_z,
+        })
+    }
+}
+
+struct QuantizedMeshSink {
+    output_path: PathBuf,
+    max_z: u8,
+}
+
+impl DataSink for QuantizedMeshSink {
+    fn make_requirements(&mut self, _properties: TransformerSettings) -> DataRequirements {
+        DataRequirements {
+            // CesiumJS expects the ellipsoidal heights
+            output_epsg: nusamai_projection::crs::EPSG_WGS84_GEOGRAPHIC_3D,
+            key_value: transformer::KeyValueSpec::None,
+            ..Default::default()
+        }
+    }
+
+    fn run(&mut self, upstream: Receiver, feedback: &Feedback, _schema: &Schema) -> Result<()> {
+        let (sender_sliced, receiver_sliced) = mpsc::sync_channel(2000);
+        let (sender_sorted, receiver_sorted) = mpsc::sync_channel(2000);
+
+        std::thread::scope(|s| {
+            // Slicing triangles along the tile boundaries
+            s.spawn(|| {
+                let max_z = self.max_z;
+                let slice = |triangle: [[f64; 3]; 3], out: &mut HashMap<_, _>| {
+                    quantized_mesh::slice_triangle(&triangle, max_z, out);
+                };
+                if let Err(error) = triangle_slicing_stage(
+                    feedback,
+                    upstream,
+                    quantized_mesh::tile_id,
+                    sender_sliced,
+                    slice,
+                ) {
+                    feedback.fatal_error(error);
+                }
+            });
+
+            // Sort triangles by tile_id (using external sorter)
+            s.spawn(move || {
+                if let Err(error) = feature_sorting_stage(feedback, receiver_sliced, sender_sorted)
+                {
+                    feedback.fatal_error(error);
+                }
+            });
+
+            // Make meshes from the grouped triangles
+            let output_path = &self.output_path;
+            s.spawn(move || {
+                // Run in a separate thread pool to avoid deadlocks
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .use_current_thread()
+                    .build()
+                    .unwrap();
+                pool.install(|| {
+                    if let Err(error) = mesh_writing_stage(output_path, feedback, receiver_sorted) {
+                        feedback.fatal_error(error);
+                    }
+                })
+            });
+        });
+
+        Ok(())
+    }
+
+    fn writes_manifest(&self) -> bool {
+        true
+    }
+}
+
+/// Slices the triangles (longitude, latitude and height) of the reliefs with `slice`,
+/// and sends them grouped by the tiles (identified by `tile_id`)
+fn triangle_slicing_stage(
+    feedback: &Feedback,
+    upstream: Receiver,
+    tile_id: impl Fn(TileZXY) -> u64 + Sync,
+    sender_sliced: mpsc::SyncSender<(u64, Vec<u8>)>,
+    slice: impl Fn([[f64; 3]; 3], &mut HashMap<TileZXY, Vec<TileTriangle>>) + Sync,
+) -> Result<()> {
+    let bincode_config = bincode::config::standard();
+    let skipped = AtomicUsize::new(0);
+
+    upstream.into_iter().par_bridge().try_for_each(|parcel| {
+        feedback.ensure_not_canceled()?;
+
+        let entity = parcel.entity;
+        let Value::Object(obj) = &entity.root else {
+            return Ok(());
+        };
+        if obj.typename != RELIEF_FEATURE {
+            skipped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype else {
+            return Ok(());
+        };
+
+        let geom_store = entity.geometry_store.read().unwrap();
+        let mut tiled_triangles: HashMap<_, Vec<TileTriangle>> = HashMap::new();
+        for entry in geometries {
+            if !matches!(
+                entry.ty,
+                GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+            ) {
+                continue;
+            }
+            for poly in geom_store
+                .multipolygon
+                .iter_range(entry.pos as usize..(entry.pos + entry.len) as usize)
+            {
+                let ring: Vec<[f64; 3]> = poly
+                    .exterior()
+                    .iter()
+                    .map(|idx| geom_store.vertices[idx as usize])
+                    .collect();
+                // TIN patches are triangles, and a fan is enough for the other (convex) patches
+                for i in 1..ring.len().saturating_sub(1) {
+                    slice([ring[0], ring[i], ring[i + 1]], &mut tiled_triangles);
+                }
+            }
+        }
+
+        for (zxy, triangles) in tiled_triangles {
+            feedback.ensure_not_canceled()?;
+            let bytes = bincode::serde::encode_to_vec(&triangles, bincode_config).unwrap();
+            if sender_sliced.send((tile_id(zxy), bytes)).is_err() {
+                return Err(PipelineError::Canceled);
+            }
+        }
+        Ok(())
+    })?;
+
+    let skipped = skipped.into_inner();
+    if skipped > 0 {
+        feedback.warn(format!(
+            "{skipped} features other than {RELIEF_FEATURE} were skipped"
+        ));
+    }
+    Ok(())
+}
+
+fn tile_writing_stage(
+    output_path: &Path,
+    feedback: &Feedback,
+    receiver_sorted: mpsc::Receiver<(u64, Vec<Vec<u8>>)>,
+) -> Result<()> {
+    let bincode_config = bincode::config::standard();
+    // PNG tiles are already compressed
+    let output = TileOutput::create(output_path, TileType::Png, TileCompression::None)?;
+
+    receiver_sorted
+        .into_iter()
+        .par_bridge()
+        .try_for_each(|(tile_id, serialized_triangles)| {
+            feedback.ensure_not_canceled()?;
+
+            let mut heights = vec![f32::NAN; (TILE_SIZE * TILE_SIZE) as usize];
+            for bytes in &serialized_triangles {
+                let (triangles, _): (Vec<TileTriangle>, _) =
+                    bincode::serde::decode_from_slice(bytes, bincode_config).map_err(|err| {
+                        PipelineError::Other(format!("Failed to deserialize triangles: {:?}", err))
+                    })?;
+                rasterize(&triangles, &mut heights);
+            }
+            if heights.iter().all(|h| h.is_nan()) {
+                return Ok(());
+            }
+
+            let mut png = Vec::new();
+            PngEncoder::new(&mut png)
+                .write_image(
+                    &encode_terrain_rgb(&heights),
+                    TILE_SIZE,
+                    TILE_SIZE,
+                    ExtendedColorType::Rgb8,
+                )
+                .map_err(|err| PipelineError::Other(format!("Failed to encode PNG: {}", err)))?;
+
+            let path = output.write_tile(tile_id, "png", &png)?;
+            feedback.info(format!("Writing a tile: {path}"));
+
+            Ok::<(), PipelineError>(())
+        })?;
+
+    output.finish(&serde_json::json!({
+        "name": output_path.file_stem().map(|s| s.to_string_lossy()),
+        "format": "png",
+        "encoding": "mapbox",
+    }))?;
+
+    Ok(())
+}
+
+fn mesh_writing_stage(
+    output_path: &Path,
+    feedback: &Feedback,
+    receiver_sorted: mpsc::Receiver<(u64, Vec<Vec<u8>>)>,
+) -> Result<()> {
+    let bincode_config = bincode::config::standard();
+    let grid_len = (quantized_mesh::GRID_SIZE * quantized_mesh::GRID_SIZE) as usize;
+    let manifest = Manifest::new();
+    // available tiles of each zoom level
+    let available = Mutex::new(BTreeMap::<u8, BTreeSet<(u32, u32)>>::new());
+
+    receiver_sorted
+        .into_iter()
+        .par_bridge()
+        .try_for_each(|(tile_id, serialized_triangles)| {
+            feedback.ensure_not_canceled()?;
+
+            let mut heights = vec![f32::NAN; grid_len];
+            for bytes in &serialized_triangles {
+                let (triangles, _): (Vec<TileTriangle>, _) =
+                    bincode::serde::decode_from_slice(bytes, bincode_config).map_err(|err| {
+                        PipelineError::Other(format!("Failed to deserialize triangles: {:?}", err))
+                    })?;
+                quantized_mesh::sample_grid(&triangles, &mut heights);
+            }
+
+            let (z, x, y) = quantized_mesh::tile_from_id(tile_id);
+            let path = write_mesh_tile(output_path, &manifest, (z, x, y), &heights)?;
+            available
+                .lock()
+                .unwrap()
+                .entry(z)
+                .or_default()
+                .insert((x, y));
+            feedback.info(format!("Writing a tile: {path}"));
+
+            Ok::<(), PipelineError>(())
+        })?;
+
+    // CesiumJS needs the two tiles of zoom level 0 and the ancestors of all the tiles,
+    // so flat tiles are written where there are no reliefs
+    let mut available = available.into_inner().unwrap();
+    let flat = vec![f32::NAN; grid_len];
+    let max_z = available.keys().max().copied().unwrap_or(0);
+    for z in (0..=max_z).rev() {
+        let required: BTreeSet<(u32, u32)> = match z {
+            0 => BTreeSet::from([(0, 0), (1, 0)]),
+            _ => available
+                .get(&(z + 1))
+                .into_iter()
+                .flatten()
+                .map(|&(x, y)| (x / 2, y / 2))
+                .collect(),
+        };
+        let tiles = available.entry(z).or_default();
+        for (x, y) in required {
+            if tiles.insert((x, y)) {
+                write_mesh_tile(output_path, &manifest, (z, x, y), &flat)?;
+            }
+        }
+    }
+
+    let name = output_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    std::fs::write(
+        output_path.join("layer.json"),
+        serde_json::to_vec_pretty(&quantized_mesh::layer_json(&name, &available)).unwrap(),
+    )?;
+    manifest.write(output_path)?;
+
+    Ok(())
+}
+
+/// Writes a `{z}/{x}/{y}.terrain` file. Returns the path for logging.
+fn write_mesh_tile(
+    output_path: &Path,
+    manifest: &Manifest,
+    zxy: TileZXY,
+    heights: &[f32],
+) -> Result<String> {
+    let (z, x, y) = zxy;
+    let tile_path = format!("{z}/{x}/{y}.terrain");
+    let content = quantized_mesh::encode_tile(zxy, heights);
+    let path = output_path.join(&tile_path);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, &content)?;
+    manifest.add(&tile_path, &content);
+    Ok(path.to_string_lossy().into_owned())
+}
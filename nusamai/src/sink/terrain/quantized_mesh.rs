@@ -0,0 +1,438 @@
+//! Cesium quantized-mesh terrain tiles
+//!
+//! The tiles follow the geographic (EPSG:4326) TMS tiling scheme of CesiumJS: two tiles at zoom level 0,
+//! and `y` counted from the south. Each tile is a regular grid of [`GRID_SIZE`] x [`GRID_SIZE`] vertices
+//! whose heights are sampled from the TIN reliefs. The format is described in
+//! <https://github.com/CesiumGS/quantized-mesh>.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use hashbrown::HashMap;
+use nusamai_projection::{cartesian::geodetic_to_geocentric, ellipsoid::wgs84};
+use tinymvt::TileZXY;
+
+use super::raster::{rasterize_pixels, TileTriangle};
+use crate::sink::mvt::tileid::TileIdMethod;
+
+/// Number of the vertices along each side of a tile
+pub const GRID_SIZE: u32 = 65;
+
+const MAX_QUANTIZED: f64 = 32767.;
+
+/// Id of a tile, for sorting the tiles with the external sorter.
+///
+/// A geographic tiling has twice as many columns as rows, so the tile is identified in the square grid of the next zoom level.
+pub fn tile_id((z, x, y): TileZXY) -> u64 {
+    TileIdMethod::Hilbert.zxy_to_id(z + 1, x, y)
+}
+
+pub fn tile_from_id(tile_id: u64) -> TileZXY {
+    let (z, x, y) = TileIdMethod::Hilbert.id_to_zxy(tile_id);
+    (z - 1, x, y)
+}
+
+/// Size of a tile at the zoom level in degrees
+fn tile_degrees(zoom: u8) -> f64 {
+    180. / (1u64 << zoom) as f64
+}
+
+/// Assigns a triangle (longitude, latitude and height) to the tiles of each zoom level.
+///
+/// Like [`super::raster::slice_triangle`], a triangle is assigned to a tile only when it covers a vertex of the grid
+/// of the tile, so the triangles smaller than the grid spacing are dropped at lower zoom levels.
+pub fn slice_triangle(
+    triangle: &[[f64; 3]; 3],
+    max_z: u8,
+    out: &mut HashMap<TileZXY, Vec<TileTriangle>>,
+) {
+    let cells = (GRID_SIZE - 1) as f64;
+    for zoom in 0..=max_z {
+        let step = tile_degrees(zoom) / cells;
+        // in the grid coordinates from the south-west corner of the world
+        let points = triangle.map(|[lng, lat, _]| [(lng + 180.) / step, (lat + 90.) / step]);
+
+        let (min_x, max_x) = min_max(points.iter().map(|p| p[0]));
+        let (min_y, max_y) = min_max(points.iter().map(|p| p[1]));
+        let (first_col, last_col) = (min_x.ceil().max(0.), max_x.floor());
+        let (first_row, last_row) = (min_y.ceil().max(0.), max_y.floor());
+        if first_col > last_col || first_row > last_row {
+            continue;
+        }
+
+        // the vertices on the boundaries are shared by the adjacent tiles
+        let num_cols = 2u32 << zoom;
+        let num_rows = 1u32 << zoom;
+        let tiles = |first: f64, last: f64, num: u32| {
+            let first = ((first / cells).ceil() as u32).saturating_sub(1);
+            let last = ((last / cells).floor() as u32).min(num - 1);
+            first..=last
+        };
+        for ty in tiles(first_row, last_row, num_rows) {
+            for tx in tiles(first_col, last_col, num_cols) {
+                let origin = [(tx as f64) * cells, (ty as f64) * cells];
+                let tile_triangle = std::array::from_fn(|i| {
+                    [
+                        ((points[i][0] - origin[0]) / cells) as f32,
+                        ((points[i][1] - origin[1]) / cells) as f32,
+                        triangle[i][2] as f32,
+                    ]
+                });
+                out.entry((zoom, tx, ty)).or_default().push(tile_triangle);
+            }
+        }
+    }
+}
+
+/// Samples the heights at the vertices of the grid (from the south-west corner, row by row). NaN for no data.
+pub fn sample_grid<'a>(triangles: impl IntoIterator<Item = &'a TileTriangle>, heights: &mut [f32]) {
+    let cells = (GRID_SIZE - 1) as f32;
+    // the vertices are at the centers of the "pixels"
+    rasterize_pixels(
+        triangles
+            .into_iter()
+            .map(|triangle| triangle.map(|[x, y, h]| [x * cells + 0.5, y * cells + 0.5, h])),
+        GRID_SIZE,
+        GRID_SIZE,
+        heights,
+    );
+}
+
+/// Encodes the grid of the heights (ellipsoidal heights in meters) as a quantized-mesh tile.
+///
+/// The vertices without data are at the height 0, the same as the default ellipsoid terrain of CesiumJS.
+pub fn encode_tile((z, x, y): TileZXY, heights: &[f32]) -> Vec<u8> {
+    debug_assert_eq!(heights.len(), (GRID_SIZE * GRID_SIZE) as usize);
+
+    let heights: Vec<f64> = heights
+        .iter()
+        .map(|&h| if h.is_nan() { 0. } else { h as f64 })
+        .collect();
+    let (min_height, max_height) = min_max(heights.iter().copied());
+
+    // positions of the vertices
+    let size = tile_degrees(z);
+    let (west, south) = (x as f64 * size - 180., y as f64 * size - 90.);
+    let cells = (GRID_SIZE - 1) as f64;
+    let ellipsoid = wgs84();
+    let positions: Vec<[f64; 3]> = heights
+        .iter()
+        .enumerate()
+        .map(|(i, &h)| {
+            let (row, col) = (i as u32 / GRID_SIZE, i as u32 % GRID_SIZE);
+            let lng = west + size * col as f64 / cells;
+            let lat = south + size * row as f64 / cells;
+            let (x, y, z) = geodetic_to_geocentric(&ellipsoid, lng, lat, h);
+            [x, y, z]
+        })
+        .collect();
+    let (center, radius) = bounding_sphere(&positions);
+    let occlusion_point = horizon_occlusion_point(&positions, center, &ellipsoid);
+
+    let mut buf = Vec::with_capacity(88 + positions.len() * 6 + 128 * 128 * 12);
+    // header
+    for v in center {
+        buf.extend(v.to_le_bytes());
+    }
+    buf.extend((min_height as f32).to_le_bytes());
+    buf.extend((max_height as f32).to_le_bytes());
+    for v in center {
+        buf.extend(v.to_le_bytes());
+    }
+    buf.extend(radius.to_le_bytes());
+    for v in occlusion_point {
+        buf.extend(v.to_le_bytes());
+    }
+
+    // triangles (counter-clockwise) of the grid
+    let mut indices = Vec::with_capacity(((GRID_SIZE - 1) * (GRID_SIZE - 1) * 6) as usize);
+    for row in 0..GRID_SIZE - 1 {
+        for col in 0..GRID_SIZE - 1 {
+            let sw = row * GRID_SIZE + col;
+            let (se, nw) = (sw + 1, sw + GRID_SIZE);
+            let ne = nw + 1;
+            indices.extend([sw, se, ne, sw, ne, nw]);
+        }
+    }
+
+    // the high-water mark encoding needs the vertices in the order of their first use
+    let vertex_count = GRID_SIZE * GRID_SIZE;
+    let mut order = Vec::with_capacity(vertex_count as usize);
+    let mut remap = vec![u32::MAX; vertex_count as usize];
+    for idx in indices.iter_mut() {
+        if remap[*idx as usize] == u32::MAX {
+            remap[*idx as usize] = order.len() as u32;
+            order.push(*idx);
+        }
+        *idx = remap[*idx as usize];
+    }
+
+    // vertices
+    buf.extend(vertex_count.to_le_bytes());
+    let quantize = |v: f64| (v * MAX_QUANTIZED).round() as i32;
+    let us = order
+        .iter()
+        .map(|i| quantize((i % GRID_SIZE) as f64 / cells));
+    let vs = order
+        .iter()
+        .map(|i| quantize((i / GRID_SIZE) as f64 / cells));
+    let hs = order.iter().map(|&i| {
+        if max_height > min_height {
+            quantize((heights[i as usize] - min_height) / (max_height - min_height))
+        } else {
+            0
+        }
+    });
+    encode_deltas(us, &mut buf);
+    encode_deltas(vs, &mut buf);
+    encode_deltas(hs, &mut buf);
+
+    // 16-bit indices since there are less than 65536 vertices
+    buf.extend((indices.len() as u32 / 3).to_le_bytes());
+    encode_high_water_mark(&indices, &mut buf);
+
+    // vertices on the edges: west, south, east and north
+    let edges: [Vec<u32>; 4] = [
+        (0..GRID_SIZE).map(|row| row * GRID_SIZE).collect(),
+        (0..GRID_SIZE).collect(),
+        (0..GRID_SIZE)
+            .map(|row| row * GRID_SIZE + GRID_SIZE - 1)
+            .collect(),
+        (0..GRID_SIZE)
+            .map(|col| (GRID_SIZE - 1) * GRID_SIZE + col)
+            .collect(),
+    ];
+    for edge in edges {
+        buf.extend((edge.len() as u32).to_le_bytes());
+        for idx in edge {
+            buf.extend((remap[idx as usize] as u16).to_le_bytes());
+        }
+    }
+
+    buf
+}
+
+/// Zigzag-encoded deltas of the quantized values
+fn encode_deltas(values: impl Iterator<Item = i32>, buf: &mut Vec<u8>) {
+    let mut prev = 0;
+    for value in values {
+        let delta = value - prev;
+        let zigzag = ((delta << 1) ^ (delta >> 31)) as u16;
+        buf.extend(zigzag.to_le_bytes());
+        prev = value;
+    }
+}
+
+/// High-water mark encoding of the indices
+fn encode_high_water_mark(indices: &[u32], buf: &mut Vec<u8>) {
+    let mut highest = 0;
+    for &idx in indices {
+        let code = highest - idx;
+        buf.extend((code as u16).to_le_bytes());
+        if code == 0 {
+            highest += 1;
+        }
+    }
+}
+
+fn bounding_sphere(positions: &[[f64; 3]]) -> ([f64; 3], f64) {
+    let mut min = [f64::MAX; 3];
+    let mut max = [f64::MIN; 3];
+    for p in positions {
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+    }
+    let center = std::array::from_fn(|i| (min[i] + max[i]) / 2.);
+    let radius = positions
+        .iter()
+        .map(|p| distance(p, &center))
+        .fold(0., f64::max);
+    (center, radius)
+}
+
+/// The point (in the ellipsoid-scaled coordinates) which is below the horizon only when the whole tile is,
+/// computed in the same way as `EllipsoidalOccluder` of CesiumJS
+fn horizon_occlusion_point(
+    positions: &[[f64; 3]],
+    center: [f64; 3],
+    ellipsoid: &nusamai_projection::ellipsoid::Ellipsoid,
+) -> [f64; 3] {
+    let radii = [ellipsoid.a(), ellipsoid.a(), ellipsoid.b()];
+    let scaled = |p: &[f64; 3]| -> [f64; 3] { std::array::from_fn(|i| p[i] / radii[i]) };
+    let direction = normalize(scaled(&center));
+
+    let mut max_magnitude = 1.0_f64;
+    for p in positions {
+        let p = scaled(p);
+        let magnitude_squared = dot(&p, &p);
+        let magnitude = magnitude_squared.sqrt();
+        let to_point = p.map(|v| v / magnitude);
+
+        let cos_alpha = dot(&to_point, &direction);
+        let sin_alpha = norm(&cross(&to_point, &direction));
+        let cos_beta = 1. / magnitude;
+        let sin_beta = (magnitude_squared - 1.).max(0.).sqrt() * cos_beta;
+        let denominator = cos_alpha * cos_beta - sin_alpha * sin_beta;
+        if denominator > 0. {
+            max_magnitude = max_magnitude.max(1. / denominator);
+        }
+    }
+    direction.map(|v| v * max_magnitude)
+}
+
+/// `layer.json` of the tileset, with the available tiles of each zoom level
+pub fn layer_json(name: &str, tiles: &BTreeMap<u8, BTreeSet<(u32, u32)>>) -> serde_json::Value {
+    let max_z = tiles.keys().max().copied().unwrap_or(0);
+    let available: Vec<Vec<serde_json::Value>> = (0..=max_z)
+        .map(|z| {
+            // the consecutive tiles in each row
+            let mut ranges: Vec<serde_json::Value> = Vec::new();
+            let mut rows: Vec<(u32, u32)> = tiles
+                .get(&z)
+                .into_iter()
+                .flatten()
+                .map(|&(x, y)| (y, x))
+                .collect();
+            rows.sort_unstable();
+            let mut run: Option<(u32, u32, u32)> = None;
+            for (y, x) in rows {
+                run = match run {
+                    Some((start_x, end_x, run_y)) if run_y == y && end_x + 1 == x => {
+                        Some((start_x, x, y))
+                    }
+                    _ => {
+                        ranges.extend(run.map(range_json));
+                        Some((x, x, y))
+                    }
+                };
+            }
+            ranges.extend(run.map(range_json));
+            ranges
+        })
+        .collect();
+
+    serde_json::json!({
+        "tilejson": "2.1.0",
+        "name": name,
+        "version": "1.0.0",
+        "format": "quantized-mesh-1.0",
+        "scheme": "tms",
+        "tiles": ["{z}/{x}/{y}.terrain?v={version}"],
+        "projection": "EPSG:4326",
+        "bounds": [-180, -90, 180, 90],
+        "minzoom": 0,
+        "maxzoom": max_z,
+        "available": available,
+    })
+}
+
+fn range_json((start_x, end_x, y): (u32, u32, u32)) -> serde_json::Value {
+    serde_json::json!({ "startX": start_x, "startY": y, "endX": end_x, "endY": y })
+}
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: &[f64; 3], b: &[f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn norm(a: &[f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: [f64; 3]) -> [f64; 3] {
+    let n = norm(&a);
+    a.map(|v| v / n)
+}
+
+fn distance(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    norm(&[a[0] - b[0], a[1] - b[1], a[2] - b[2]])
+}
+
+fn min_max(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values.fold((f64::MAX, f64::MIN), |(min, max), v| {
+        (min.min(v), max.max(v))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(buf: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn read_u16(buf: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap())
+    }
+
+    #[test]
+    fn slice_and_encode() {
+        // two triangles covering a 0.01 degree square, sloping along the longitude
+        let (x0, y0, x1, y1) = (139.70, 35.60, 139.71, 35.61);
+        let triangles = [
+            [[x0, y0, 10.], [x1, y0, 20.], [x1, y1, 20.]],
+            [[x0, y0, 10.], [x1, y1, 20.], [x0, y1, 10.]],
+        ];
+        let mut tiles = HashMap::new();
+        for triangle in &triangles {
+            slice_triangle(triangle, 14, &mut tiles);
+        }
+        // too small for the grid of the lowest zoom levels
+        assert!(!tiles.keys().any(|(z, _, _)| *z == 0));
+        let (&tile, tile_triangles) = tiles.iter().max_by_key(|(zxy, _)| zxy.0).unwrap();
+        assert_eq!(tile.0, 14);
+        assert_eq!(tile_from_id(tile_id(tile)), tile);
+
+        let mut heights = vec![f32::NAN; (GRID_SIZE * GRID_SIZE) as usize];
+        sample_grid(tile_triangles, &mut heights);
+        assert!(heights
+            .iter()
+            .filter(|h| !h.is_nan())
+            .all(|h| (10.0..=20.0).contains(h)));
+
+        let bytes = encode_tile(tile, &heights);
+        let vertex_count = read_u32(&bytes, 88);
+        assert_eq!(vertex_count, GRID_SIZE * GRID_SIZE);
+        // u of the first two vertices: 0 and the zigzag-encoded delta
+        assert_eq!(read_u16(&bytes, 92), 0);
+        assert_eq!(read_u16(&bytes, 94), (32767 / 64 + 1) * 2);
+
+        let indices_pos = 92 + vertex_count as usize * 6;
+        let triangle_count = read_u32(&bytes, indices_pos);
+        assert_eq!(triangle_count, 64 * 64 * 2);
+        let edges_pos = indices_pos + 4 + triangle_count as usize * 6;
+        assert_eq!(read_u32(&bytes, edges_pos), GRID_SIZE);
+        assert_eq!(bytes.len(), edges_pos + 4 * (4 + GRID_SIZE as usize * 2));
+    }
+
+    #[test]
+    fn high_water_mark() {
+        let mut buf = Vec::new();
+        encode_high_water_mark(&[0, 1, 2, 0, 2, 3], &mut buf);
+        let codes: Vec<u16> = (0..6).map(|i| read_u16(&buf, i * 2)).collect();
+        assert_eq!(codes, [0, 0, 0, 3, 1, 0]);
+    }
+
+    #[test]
+    fn available_ranges() {
+        let mut tiles = BTreeMap::new();
+        tiles.insert(0, BTreeSet::from([(0, 0), (1, 0)]));
+        tiles.insert(1, BTreeSet::from([(3, 1), (2, 1), (0, 0)]));
+        let layer = layer_json("terrain", &tiles);
+        assert_eq!(layer["maxzoom"], 1);
+        assert_eq!(
+            layer["available"][0],
+            serde_json::json!([{ "startX": 0, "startY": 0, "endX": 1, "endY": 0 }])
+        );
+        assert_eq!(layer["available"][1].as_array().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,184 @@
+//! Rasterization of TIN reliefs into heightmap tiles
+
+use hashbrown::HashMap;
+use tinymvt::TileZXY;
+
+/// Width and height of a heightmap tile in pixels
+pub const TILE_SIZE: u32 = 256;
+
+/// Triangle in the tile coordinates (x, y: 0.0 - 1.0) with the heights
+pub type TileTriangle = [[f32; 3]; 3];
+
+/// Assigns a triangle (in the normalized Web Mercator coordinates with the heights) to the tiles of each zoom level.
+///
+/// A triangle is assigned to a tile only when it covers the center of a pixel in the tile,
+/// so the triangles smaller than a pixel are dropped at lower zoom levels.
+pub fn slice_triangle(
+    triangle: &[[f64; 3]; 3],
+    min_z: u8,
+    max_z: u8,
+    out: &mut HashMap<TileZXY, Vec<TileTriangle>>,
+) {
+    for zoom in min_z..=max_z {
+        let scale = (1u64 << zoom) as f64 * TILE_SIZE as f64;
+        let pixels = triangle.map(|[x, y, _]| [x * scale, y * scale]);
+
+        // range of the pixels whose centers are within the bounding box
+        let (min_x, max_x) = min_max(pixels.iter().map(|p| p[0]));
+        let (min_y, max_y) = min_max(pixels.iter().map(|p| p[1]));
+        let first_col = (min_x - 0.5).ceil();
+        let last_col = (max_x - 0.5).floor().min(scale - 1.);
+        let first_row = (min_y - 0.5).ceil();
+        let last_row = (max_y - 0.5).floor().min(scale - 1.);
+        if first_col > last_col || first_row > last_row {
+            continue;
+        }
+
+        let tile_cols = (first_col.max(0.) as u32 / TILE_SIZE)..=(last_col as u32 / TILE_SIZE);
+        let tile_rows = (first_row.max(0.) as u32 / TILE_SIZE)..=(last_row as u32 / TILE_SIZE);
+        for ty in tile_rows {
+            for tx in tile_cols.clone() {
+                let origin = [(tx * TILE_SIZE) as f64, (ty * TILE_SIZE) as f64];
+                let tile_triangle = std::array::from_fn(|i| {
+                    [
+                        ((pixels[i][0] - origin[0]) / TILE_SIZE as f64) as f32,
+                        ((pixels[i][1] - origin[1]) / TILE_SIZE as f64) as f32,
+                        triangle[i][2] as f32,
+                    ]
+                });
+                out.entry((zoom, tx, ty)).or_default().push(tile_triangle);
+            }
+        }
+    }
+}
+
+/// Rasterizes the triangles into the heights of the pixels (NaN for no data).
+///
+/// The heights are interpolated at the center of each pixel. Where the triangles overlap, the highest one is used.
+pub fn rasterize<'a>(triangles: impl IntoIterator<Item = &'a TileTriangle>, heights: &mut [f32]) {
+    let size = TILE_SIZE as f32;
+    rasterize_pixels(
+        triangles
+            .into_iter()
+            .map(|triangle| triangle.map(|[x, y, h]| [x * size, y * size, h])),
+        TILE_SIZE,
+        TILE_SIZE,
+        heights,
+    );
+}
+
+/// Same as `rasterize`, but the triangles are in the pixel coordinates of a `width` x `height` raster.
+pub fn rasterize_pixels(
+    triangles: impl IntoIterator<Item = TileTriangle>,
+    width: u32,
+    height: u32,
+    heights: &mut [f32],
+) {
+    debug_assert_eq!(heights.len(), (width * height) as usize);
+
+    for [a, b, c] in triangles {
+        let area = edge(a, b, c);
+        if area == 0. {
+            continue;
+        }
+
+        let (min_x, max_x) = min_max([a[0], b[0], c[0]].into_iter().map(f64::from));
+        let (min_y, max_y) = min_max([a[1], b[1], c[1]].into_iter().map(f64::from));
+        let cols = (min_x - 0.5).ceil().max(0.) as u32
+            ..=(max_x - 0.5).floor().min(width as f64 - 1.) as u32;
+        let rows = (min_y - 0.5).ceil().max(0.) as u32
+            ..=(max_y - 0.5).floor().min(height as f64 - 1.) as u32;
+
+        for row in rows {
+            for col in cols.clone() {
+                let p = [col as f32 + 0.5, row as f32 + 0.5, 0.];
+                // barycentric coordinates (valid for both windings)
+                let wa = edge(b, c, p) / area;
+                let wb = edge(c, a, p) / area;
+                let wc = 1. - wa - wb;
+                const EPS: f32 = -1e-5;
+                if wa < EPS || wb < EPS || wc < EPS {
+                    continue;
+                }
+
+                let h = wa * a[2] + wb * b[2] + wc * c[2];
+                let pixel = &mut heights[(row * width + col) as usize];
+                if pixel.is_nan() || *pixel < h {
+                    *pixel = h;
+                }
+            }
+        }
+    }
+}
+
+/// Encodes the heights in the Terrain-RGB format (`height = -10000 + (R * 256 * 256 + G * 256 + B) * 0.1`).
+///
+/// Pixels without data are encoded as 0 meters.
+pub fn encode_terrain_rgb(heights: &[f32]) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(heights.len() * 3);
+    for &h in heights {
+        let h = if h.is_nan() { 0. } else { h as f64 };
+        let value = ((h + 10000.) * 10.).round().clamp(0., 16_777_215.) as u32;
+        rgb.extend([(value >> 16) as u8, (value >> 8) as u8, value as u8]);
+    }
+    rgb
+}
+
+fn edge(a: [f32; 3], b: [f32; 3], p: [f32; 3]) -> f32 {
+    (b[0] - a[0]) * (p[1] - a[1]) - (b[1] - a[1]) * (p[0] - a[0])
+}
+
+fn min_max(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values.fold((f64::MAX, f64::MIN), |(min, max), v| {
+        (min.min(v), max.max(v))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rasterize_tile() {
+        // two triangles covering the tile (1, 1) at zoom level 1, sloping along the x-axis
+        let (x0, y0, x1, y1) = (0.5, 0.5, 1.0, 1.0);
+        let triangles = [
+            [[x0, y0, 10.], [x1, y0, 20.], [x1, y1, 20.]],
+            [[x0, y0, 10.], [x1, y1, 20.], [x0, y1, 10.]],
+        ];
+        let mut tiles = HashMap::new();
+        for triangle in &triangles {
+            slice_triangle(triangle, 0, 1, &mut tiles);
+        }
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(tiles[&(1, 1, 1)].len(), 2);
+        assert_eq!(tiles[&(0, 0, 0)].len(), 2);
+
+        let mut heights = vec![f32::NAN; (TILE_SIZE * TILE_SIZE) as usize];
+        rasterize(&tiles[&(1, 1, 1)], &mut heights);
+        assert!(heights.iter().all(|h| (10.0..=20.0).contains(h)));
+        assert!((heights[0] - 10.0).abs() < 0.1);
+        assert!((heights[(TILE_SIZE - 1) as usize] - 20.0).abs() < 0.1);
+
+        // only the lower right quarter is covered at zoom level 0
+        let mut heights = vec![f32::NAN; (TILE_SIZE * TILE_SIZE) as usize];
+        rasterize(&tiles[&(0, 0, 0)], &mut heights);
+        assert!(heights[0].is_nan());
+        assert!(!heights[heights.len() - 1].is_nan());
+    }
+
+    #[test]
+    fn tiny_triangles_are_dropped() {
+        let triangle = [[0.5, 0.5, 0.], [0.5 + 1e-9, 0.5, 0.], [0.5, 0.5 + 1e-9, 0.]];
+        let mut tiles = HashMap::new();
+        slice_triangle(&triangle, 0, 10, &mut tiles);
+        assert!(tiles.is_empty());
+    }
+
+    #[test]
+    fn terrain_rgb() {
+        assert_eq!(encode_terrain_rgb(&[0.]), vec![1, 134, 160]);
+        assert_eq!(encode_terrain_rgb(&[f32::NAN]), vec![1, 134, 160]);
+        assert_eq!(encode_terrain_rgb(&[-10000.]), vec![0, 0, 0]);
+    }
+}
@@ -0,0 +1,483 @@
+//! Atlas compositing with wgpu
+//!
+//! The source images are uploaded with the mipmaps generated on the GPU, and each placed texture is drawn into the
+//! atlas as a quad sampling its source trilinearly, so the downsampling is done by the texture sampler.
+
+use std::collections::{BTreeMap, HashMap};
+
+use image::RgbaImage;
+
+use super::{placement_quad, PlacedTexture, Quad};
+
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+const SHADER: &str = r#"
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@location(0) position: vec2<f32>, @location(1) uv: vec2<f32>) -> VertexOutput {
+    var out: VertexOutput;
+    out.position = vec4<f32>(position, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+@group(0) @binding(0) var source: texture_2d<f32>;
+@group(0) @binding(1) var source_sampler: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(source, source_sampler, in.uv);
+}
+"#;
+
+/// A vertex of a quad: the position in the clip space and the texture coordinates of the source
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct Vertex {
+    position: [f32; 2],
+    uv: [f32; 2],
+}
+
+pub struct GpuCompositor {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    max_size: u32,
+    adapter_name: String,
+}
+
+impl GpuCompositor {
+    /// Opens the GPU (None if there is no adapter, or only a software one unless `allow_software`)
+    pub fn new(allow_software: bool) -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            compatible_surface: None,
+        }))?;
+        let info = adapter.get_info();
+        if info.device_type == wgpu::DeviceType::Cpu && !allow_software {
+            log::info!("Skipped the software GPU adapter: {}", info.name);
+            return None;
+        }
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("atlas"),
+                required_features: wgpu::Features::empty(),
+                required_limits: adapter.limits(),
+                memory_hints: wgpu::MemoryHints::default(),
+            },
+            None,
+        ))
+        .map_err(|err| log::warn!("Failed to open the GPU device: {err}"))
+        .ok()?;
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("atlas"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("atlas"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("atlas"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("atlas"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: Default::default(),
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2],
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: Default::default(),
+                targets: &[Some(FORMAT.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("atlas"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Some(Self {
+            max_size: device.limits().max_texture_dimension_2d,
+            device,
+            queue,
+            pipeline,
+            bind_group_layout,
+            sampler,
+            adapter_name: info.name,
+        })
+    }
+
+    pub fn adapter_name(&self) -> &str {
+        &self.adapter_name
+    }
+
+    /// Composites the textures into the atlases of the size (atlas id -> image)
+    pub fn composite(
+        &self,
+        textures: &[PlacedTexture],
+        width: u32,
+        height: u32,
+    ) -> Result<BTreeMap<String, RgbaImage>, String> {
+        if width > self.max_size || height > self.max_size {
+            return Err(format!(
+                "The atlas is too large for the GPU: {width}x{height}"
+            ));
+        }
+
+        // atlas id -> source -> quads
+        let mut atlases: BTreeMap<&str, BTreeMap<&std::path::Path, Vec<Quad>>> = BTreeMap::new();
+        for texture in textures {
+            let quad = placement_quad(texture, width, height).ok_or_else(|| {
+                format!(
+                    "The texture of {:?} is not placed by scaling",
+                    texture.source
+                )
+            })?;
+            atlases
+                .entry(&texture.atlas_id)
+                .or_default()
+                .entry(&texture.source)
+                .or_default()
+                .push(quad);
+        }
+
+        let mut sources = HashMap::new();
+        for source in atlases.values().flat_map(|quads| quads.keys()) {
+            if !sources.contains_key(source) {
+                let image = image::open(source)
+                    .map_err(|err| format!("Failed to open {:?}: {err}", source))?
+                    .to_rgba8();
+                sources.insert(*source, self.upload(&image)?);
+            }
+        }
+
+        atlases
+            .into_iter()
+            .map(|(atlas_id, quads)| {
+                let image = self.draw_atlas(&quads, &sources, width, height)?;
+                Ok((atlas_id.to_string(), image))
+            })
+            .collect()
+    }
+
+    /// Uploads the image, and generates the mipmaps
+    fn upload(&self, image: &RgbaImage) -> Result<wgpu::Texture, String> {
+        let (width, height) = image.dimensions();
+        if width > self.max_size || height > self.max_size {
+            return Err(format!(
+                "The texture is too large for the GPU: {width}x{height}"
+            ));
+        }
+        let mip_level_count = 32 - width.max(height).leading_zeros();
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("source"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            image.as_raw(),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * width),
+                rows_per_image: Some(height),
+            },
+            texture.size(),
+        );
+
+        // each level is drawn from the previous one
+        let full_quad = quad_vertices(&Quad {
+            atlas: [0.0, 0.0, 1.0, 1.0],
+            source: [0.0, 0.0, 1.0, 1.0],
+        });
+        let vertex_buffer = self.vertex_buffer(&full_quad);
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        for level in 1..mip_level_count {
+            let view = |level| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    base_mip_level: level,
+                    mip_level_count: Some(1),
+                    ..Default::default()
+                })
+            };
+            let bind_group = self.bind_group(&view(level - 1));
+            let target = view(level);
+            let mut pass = render_pass(&mut encoder, &target);
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+            pass.draw(0..full_quad.len() as u32, 0..1);
+        }
+        self.queue.submit(Some(encoder.finish()));
+        Ok(texture)
+    }
+
+    fn draw_atlas(
+        &self,
+        quads: &BTreeMap<&std::path::Path, Vec<Quad>>,
+        sources: &HashMap<&std::path::Path, wgpu::Texture>,
+        width: u32,
+        height: u32,
+    ) -> Result<RgbaImage, String> {
+        let atlas = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("atlas"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let target = atlas.create_view(&Default::default());
+
+        let mut vertices = Vec::new();
+        let mut ranges = Vec::new();
+        for (source, quads) in quads {
+            let start = vertices.len() as u32;
+            vertices.extend(quads.iter().flat_map(quad_vertices));
+            ranges.push((source, start..vertices.len() as u32));
+        }
+        let vertex_buffer = self.vertex_buffer(&vertices);
+        let bind_groups: Vec<_> = ranges
+            .iter()
+            .map(|(source, _)| self.bind_group(&sources[*source].create_view(&Default::default())))
+            .collect();
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = render_pass(&mut encoder, &target);
+            pass.set_pipeline(&self.pipeline);
+            pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+            for ((_, range), bind_group) in ranges.into_iter().zip(&bind_groups) {
+                pass.set_bind_group(0, bind_group, &[]);
+                pass.draw(range, 0..1);
+            }
+        }
+
+        // read back (the rows of a copy are aligned to 256 bytes)
+        let padded_row = (4 * width).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: padded_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            atlas.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            atlas.size(),
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .map_err(|err| err.to_string())?
+            .map_err(|err| format!("Failed to read the atlas from the GPU: {err}"))?;
+
+        let mut pixels = Vec::with_capacity((4 * width * height) as usize);
+        for row in slice.get_mapped_range().chunks(padded_row as usize) {
+            pixels.extend_from_slice(&row[..(4 * width) as usize]);
+        }
+        buffer.unmap();
+        Ok(RgbaImage::from_raw(width, height, pixels).unwrap())
+    }
+
+    fn vertex_buffer(&self, vertices: &[Vertex]) -> wgpu::Buffer {
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("quads"),
+            size: std::mem::size_of_val(vertices).max(1) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.queue
+            .write_buffer(&buffer, 0, bytemuck::cast_slice(vertices));
+        buffer
+    }
+
+    fn bind_group(&self, view: &wgpu::TextureView) -> wgpu::BindGroup {
+        self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        })
+    }
+}
+
+fn render_pass<'a>(
+    encoder: &'a mut wgpu::CommandEncoder,
+    target: &'a wgpu::TextureView,
+) -> wgpu::RenderPass<'a> {
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: None,
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: target,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                store: wgpu::StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    })
+}
+
+/// The two triangles of a quad (the UVs have the origin at the bottom-left, the textures at the top-left)
+fn quad_vertices(quad: &Quad) -> [Vertex; 6] {
+    let [a_min_u, a_min_v, a_max_u, a_max_v] = quad.atlas;
+    let [s_min_u, s_min_v, s_max_u, s_max_v] = quad.source;
+    let vertex = |(au, av): (f32, f32), (su, sv): (f32, f32)| Vertex {
+        position: [2.0 * au - 1.0, 2.0 * av - 1.0],
+        uv: [su, 1.0 - sv],
+    };
+    let bottom_left = vertex((a_min_u, a_min_v), (s_min_u, s_min_v));
+    let bottom_right = vertex((a_max_u, a_min_v), (s_max_u, s_min_v));
+    let top_right = vertex((a_max_u, a_max_v), (s_max_u, s_max_v));
+    let top_left = vertex((a_min_u, a_max_v), (s_min_u, s_max_v));
+    [
+        bottom_left,
+        bottom_right,
+        top_right,
+        bottom_left,
+        top_right,
+        top_left,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_composite() {
+        // (skipped without a GPU, including a software one)
+        let Some(compositor) = GpuCompositor::new(true) else {
+            return;
+        };
+
+        // the left half is red and the right half is blue
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.png");
+        RgbaImage::from_fn(64, 64, |x, _| match x < 32 {
+            true => image::Rgba([255, 0, 0, 255]),
+            false => image::Rgba([0, 0, 255, 255]),
+        })
+        .save(&source)
+        .unwrap();
+
+        // the right half is downsampled into the 16x16 pixels at the bottom-left of the atlas
+        let textures = [PlacedTexture {
+            source: source.clone(),
+            atlas_id: "0".into(),
+            uv_coords: vec![(0.5, 0.0), (1.0, 0.0), (1.0, 1.0), (0.5, 1.0)],
+            placed_uv_coords: vec![(0.0, 0.0), (0.25, 0.0), (0.25, 0.5), (0.0, 0.5)],
+        }];
+        let atlases = compositor.composite(&textures, 64, 32).unwrap();
+        let atlas = &atlases["0"];
+        assert_eq!(atlas.dimensions(), (64, 32));
+        assert_eq!(atlas.get_pixel(8, 24).0, [0, 0, 255, 255]);
+        // (outside the placed texture)
+        assert_eq!(atlas.get_pixel(40, 8).0, [0, 0, 0, 0]);
+
+        let rotated = [PlacedTexture {
+            placed_uv_coords: vec![(0.0, 0.5), (0.0, 0.0), (0.25, 0.0), (0.25, 0.5)],
+            ..textures[0].clone()
+        }];
+        assert!(compositor.composite(&rotated, 64, 32).is_err());
+    }
+}
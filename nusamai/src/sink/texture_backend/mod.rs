@@ -0,0 +1,259 @@
+//! Backends of the texture operations of the atlases (the downsampling and the compositing)
+//!
+//! The CPU backend is the exporter of `atlas-packer`. The GPU backend (built with the `gpu` feature) draws the
+//! placed textures into the atlases with wgpu, and falls back to the CPU for the atlases it cannot handle
+//! (e.g. larger than the GPU supports) or if no GPU is available. The images are decoded on the CPU in either case.
+
+#[cfg(feature = "gpu")]
+mod gpu;
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    parameters::{ParameterDefinition, ParameterEntry, ParameterType, StringParameter},
+    pipeline::{PipelineError, Result},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextureBackend {
+    #[default]
+    Cpu,
+    Gpu,
+}
+
+impl TextureBackend {
+    /// Parses the option (`cpu`, `gpu`)
+    pub fn negotiate(option: &str) -> Result<Self> {
+        match option {
+            "" | "cpu" => Ok(Self::Cpu),
+            "gpu" => Ok(Self::Gpu),
+            _ => Err(PipelineError::Other(format!(
+                "Unknown texture backend: {option} (expected cpu or gpu)"
+            ))),
+        }
+    }
+}
+
+pub fn texture_backend_parameter() -> ParameterDefinition {
+    ParameterDefinition {
+        key: "texture_backend".into(),
+        entry: ParameterEntry {
+            description: "Backend of the downsampling and the compositing of the atlas images: cpu or gpu (falls back to cpu if not available)".into(),
+            required: false,
+            parameter: ParameterType::String(StringParameter {
+                value: Some("cpu".into()),
+            }),
+            label: Some("テクスチャ処理（cpu, gpu）".into()),
+        },
+    }
+}
+
+/// A texture placed in an atlas
+#[derive(Debug, Clone)]
+pub struct PlacedTexture {
+    pub source: PathBuf,
+    pub atlas_id: String,
+    /// The UV coordinates of the polygon in the source image
+    pub uv_coords: Vec<(f64, f64)>,
+    /// The UV coordinates of the polygon in the atlas
+    pub placed_uv_coords: Vec<(f64, f64)>,
+}
+
+/// Corresponding rectangles `[min_u, min_v, max_u, max_v]` in the atlas and the source image
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+struct Quad {
+    atlas: [f32; 4],
+    source: [f32; 4],
+}
+
+/// The rectangle drawn into the atlas for the placed texture (the bounding box of the UVs, expanded by half a pixel
+/// not to leave the edges), or None if the texture is not placed by scaling and translating its bounding box
+#[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+fn placement_quad(texture: &PlacedTexture, width: u32, height: u32) -> Option<Quad> {
+    if texture.uv_coords.len() != texture.placed_uv_coords.len() || texture.uv_coords.is_empty() {
+        return None;
+    }
+    // (the source is cropped to the bounding box of the clamped UVs)
+    let uv_coords: Vec<_> = texture
+        .uv_coords
+        .iter()
+        .map(|&(u, v)| (u.clamp(0.0, 1.0), v.clamp(0.0, 1.0)))
+        .collect();
+    let bbox = |coords: &[(f64, f64)]| {
+        coords.iter().fold(
+            [f64::MAX, f64::MAX, f64::MIN, f64::MIN],
+            |[min_u, min_v, max_u, max_v], &(u, v)| {
+                [min_u.min(u), min_v.min(v), max_u.max(u), max_v.max(v)]
+            },
+        )
+    };
+    let source = bbox(&uv_coords);
+    let atlas = bbox(&texture.placed_uv_coords);
+
+    // source = offset + scale * atlas, in each axis
+    let axis = |i: usize, size: u32| {
+        let (atlas_range, source_range) = (atlas[i + 2] - atlas[i], source[i + 2] - source[i]);
+        let scale = match atlas_range > 0.0 {
+            true => source_range / atlas_range,
+            false => 0.0,
+        };
+        let tolerance = 1.5 / size as f64;
+        let consistent = uv_coords
+            .iter()
+            .zip(&texture.placed_uv_coords)
+            .all(|(uv, placed)| {
+                let (uv, placed) = match i {
+                    0 => (uv.0, placed.0),
+                    _ => (uv.1, placed.1),
+                };
+                let expected = atlas[i] + (uv - source[i]) / scale.max(f64::MIN_POSITIVE);
+                scale == 0.0 || (expected - placed).abs() <= tolerance
+            });
+        let margin = 0.5 / size as f64;
+        consistent.then(|| {
+            (
+                [atlas[i] - margin, atlas[i + 2] + margin],
+                [source[i] - margin * scale, source[i + 2] + margin * scale],
+            )
+        })
+    };
+    let (atlas_u, source_u) = axis(0, width)?;
+    let (atlas_v, source_v) = axis(1, height)?;
+    Some(Quad {
+        atlas: [atlas_u[0], atlas_v[0], atlas_u[1], atlas_v[1]].map(|v| v as f32),
+        source: [source_u[0], source_v[0], source_u[1], source_v[1]].map(|v| v as f32),
+    })
+}
+
+/// Writes the atlas images with the backend of the sink
+pub enum AtlasCompositor {
+    Cpu,
+    #[cfg(feature = "gpu")]
+    Gpu(gpu::GpuCompositor),
+}
+
+impl AtlasCompositor {
+    pub fn new(backend: TextureBackend) -> Self {
+        match backend {
+            TextureBackend::Cpu => Self::Cpu,
+            #[cfg(feature = "gpu")]
+            TextureBackend::Gpu => match gpu::GpuCompositor::new(false) {
+                Some(compositor) => {
+                    log::info!("Compositing the atlases on {}", compositor.adapter_name());
+                    Self::Gpu(compositor)
+                }
+                None => {
+                    log::warn!("No GPU is available; the atlases are composited on the CPU");
+                    Self::Cpu
+                }
+            },
+            #[cfg(not(feature = "gpu"))]
+            TextureBackend::Gpu => {
+                log::warn!(
+                    "Built without the `gpu` feature; the atlases are composited on the CPU"
+                );
+                Self::Cpu
+            }
+        }
+    }
+
+    /// Writes the atlases (`{dir}/{atlas_id}.{extension}`) of the textures on the GPU.
+    ///
+    /// Returns false if they are to be exported on the CPU instead.
+    #[cfg_attr(not(feature = "gpu"), allow(unused_variables))]
+    pub fn write_atlases(
+        &self,
+        dir: &Path,
+        extension: &str,
+        textures: &[PlacedTexture],
+        width: u32,
+        height: u32,
+    ) -> bool {
+        match self {
+            Self::Cpu => false,
+            #[cfg(feature = "gpu")]
+            Self::Gpu(compositor) => {
+                let written = compositor
+                    .composite(textures, width, height)
+                    .and_then(|atlases| {
+                        atlases.into_iter().try_for_each(|(atlas_id, image)| {
+                            let path = dir.join(atlas_id).with_extension(extension);
+                            image
+                                .save(&path)
+                                .map_err(|err| format!("Failed to write {:?}: {err}", path))
+                        })
+                    });
+                match written {
+                    Ok(()) => true,
+                    Err(err) => {
+                        log::warn!("{err}; the atlases are composited on the CPU");
+                        false
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(uv_coords: &[(f64, f64)], placed_uv_coords: &[(f64, f64)]) -> PlacedTexture {
+        PlacedTexture {
+            source: "source.png".into(),
+            atlas_id: "0".into(),
+            uv_coords: uv_coords.to_vec(),
+            placed_uv_coords: placed_uv_coords.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_placement_quad() {
+        // the bounding box (0.5, 0.25)-(1.0, 0.75) is placed at (0, 0)-(0.25, 0.5) of a 100x100 atlas
+        let uvs = [(0.5, 0.25), (1.0, 0.25), (1.0, 0.75)];
+        let placed = [(0.0, 0.0), (0.25, 0.0), (0.25, 0.5)];
+        let quad = placement_quad(&texture(&uvs, &placed), 100, 100).unwrap();
+        let expected_atlas = [-0.005, -0.005, 0.255, 0.505];
+        let expected_source = [0.49, 0.245, 1.01, 0.755];
+        for (actual, expected) in quad.atlas.iter().zip(expected_atlas) {
+            assert!((actual - expected).abs() < 1e-6, "{:?}", quad);
+        }
+        for (actual, expected) in quad.source.iter().zip(expected_source) {
+            assert!((actual - expected).abs() < 1e-6, "{:?}", quad);
+        }
+
+        // (clamped to the source image)
+        let wrapped = [(0.5, 0.25), (1.5, 0.25), (1.5, 0.75)];
+        assert_eq!(
+            placement_quad(&texture(&wrapped, &placed), 100, 100),
+            Some(quad)
+        );
+
+        // rotated or flipped
+        let rotated = [(0.0, 0.5), (0.0, 0.0), (0.25, 0.0)];
+        assert!(placement_quad(&texture(&uvs, &rotated), 100, 100).is_none());
+        let flipped = [(0.0, 0.5), (0.25, 0.5), (0.25, 0.0)];
+        assert!(placement_quad(&texture(&uvs, &flipped), 100, 100).is_none());
+
+        assert!(placement_quad(&texture(&uvs, &placed[..2]), 100, 100).is_none());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(TextureBackend::negotiate("").unwrap(), TextureBackend::Cpu);
+        assert_eq!(
+            TextureBackend::negotiate("gpu").unwrap(),
+            TextureBackend::Gpu
+        );
+        assert!(TextureBackend::negotiate("cuda").is_err());
+    }
+
+    #[test]
+    fn test_cpu_fallback() {
+        let compositor = AtlasCompositor::new(TextureBackend::Cpu);
+        let textures = [texture(&[(0.0, 0.0)], &[(0.0, 0.0)])];
+        assert!(!compositor.write_atlases(Path::new("."), "webp", &textures, 64, 64));
+    }
+}
@@ -0,0 +1,474 @@
+//! GPU texture compression of the atlas images (KTX2 with Basis Universal)
+//!
+//! The atlas images are exported as usual, and then transcoded into KTX2 files (`.ktx2`) by
+//! [`compress_atlas_dir`]. The glTF writers embed them with the `KHR_texture_basisu` extension.
+//!
+//! The encoder writes `.basis` files, which are rewrapped into the KTX2 container here:
+//! ETC1S with the BasisLZ supercompression, and UASTC without supercompression.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use basis_universal::{
+    BasisTextureFormat, ColorSpace, Compressor, CompressorParams, ETC1S_QUALITY_DEFAULT,
+    UASTC_QUALITY_DEFAULT,
+};
+
+use crate::{
+    parameters::{ParameterDefinition, ParameterEntry, ParameterType, StringParameter},
+    pipeline::{PipelineError, Result},
+};
+
+pub const KTX2_EXTENSION: &str = "ktx2";
+
+const KTX2_IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+const SUPERCOMPRESSION_NONE: u32 = 0;
+const SUPERCOMPRESSION_BASIS_LZ: u32 = 1;
+const DF_MODEL_ETC1S: u8 = 163;
+const DF_MODEL_UASTC: u8 = 166;
+const DF_PRIMARIES_BT709: u8 = 1;
+const DF_TRANSFER_SRGB: u8 = 2;
+const DF_CHANNEL_RGB: u8 = 0;
+const DF_CHANNEL_RGBA: u8 = 3;
+const DF_CHANNEL_AAA: u8 = 15;
+const UASTC_BLOCK_SIZE: usize = 16;
+
+const BASIS_SIGNATURE: u16 = 0x4273; // ('B' << 8) | 's'
+const BASIS_HEADER_SIZE: usize = 77;
+const BASIS_SLICE_DESC_SIZE: usize = 23;
+const BASIS_HEADER_FLAG_HAS_ALPHA_SLICES: u16 = 4;
+const BASIS_SLICE_FLAG_HAS_ALPHA: u8 = 1;
+const BASIS_TEX_FORMAT_UASTC: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextureCompression {
+    /// The atlas images are written as they are exported (WebP, JPEG)
+    #[default]
+    None,
+    /// Small files and GPU memory, for the photographic textures
+    Etc1s,
+    /// Higher quality with larger files
+    Uastc,
+}
+
+impl TextureCompression {
+    /// Parses the option (`none`, `etc1s`, `uastc`)
+    pub fn negotiate(option: &str) -> Result<Self> {
+        match option {
+            "" | "none" => Ok(Self::None),
+            "etc1s" => Ok(Self::Etc1s),
+            "uastc" => Ok(Self::Uastc),
+            _ => Err(PipelineError::Other(format!(
+                "Unknown texture compression: {option} (expected none, etc1s or uastc)"
+            ))),
+        }
+    }
+
+    /// Extension of the atlas images referred by the materials
+    pub fn atlas_extension<'a>(&self, exported: &'a str) -> &'a str {
+        match self {
+            Self::None => exported,
+            Self::Etc1s | Self::Uastc => KTX2_EXTENSION,
+        }
+    }
+}
+
+pub fn texture_compression_parameter() -> ParameterDefinition {
+    ParameterDefinition {
+        key: "texture_compression".into(),
+        entry: ParameterEntry {
+            description: "GPU texture compression of the atlas images: none, etc1s or uastc (KTX2 with KHR_texture_basisu)".into(),
+            required: false,
+            parameter: ParameterType::String(StringParameter {
+                value: Some("none".into()),
+            }),
+            label: Some("テクスチャの圧縮（none, etc1s, uastc）".into()),
+        },
+    }
+}
+
+/// Transcodes the exported atlas images (`*.{exported_ext}`) in the directory into KTX2 files, and removes the originals
+pub fn compress_atlas_dir(
+    dir: &Path,
+    exported_ext: &str,
+    compression: TextureCompression,
+) -> io::Result<()> {
+    if compression == TextureCompression::None {
+        return Ok(());
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == exported_ext));
+    for path in paths {
+        let image = image::open(&path)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+            .to_rgba8();
+        let ktx2 = encode_ktx2(&image, compression)?;
+        fs::write(path.with_extension(KTX2_EXTENSION), ktx2)?;
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+/// Encodes the image (with the mipmaps) into a KTX2 file
+pub fn encode_ktx2(
+    image: &image::RgbaImage,
+    compression: TextureCompression,
+) -> io::Result<Vec<u8>> {
+    let format = match compression {
+        TextureCompression::Uastc => BasisTextureFormat::UASTC4x4,
+        _ => BasisTextureFormat::ETC1S,
+    };
+
+    let mut params = CompressorParams::new();
+    params.set_basis_format(format);
+    params.set_color_space(ColorSpace::Srgb);
+    params.set_generate_mipmaps(true);
+    params.set_etc1s_quality_level(ETC1S_QUALITY_DEFAULT);
+    params.set_uastc_quality_level(UASTC_QUALITY_DEFAULT);
+    params.set_print_status_to_stdout(false);
+    params
+        .source_image_mut(0)
+        .init(image.as_raw(), image.width(), image.height(), 4);
+
+    // (the atlases of the tiles are already encoded in parallel)
+    let mut compressor = Compressor::new(1);
+    // SAFETY: `params` outlives the compression
+    unsafe {
+        if !compressor.init(&params) {
+            return Err(invalid_data(
+                "Failed to initialize the Basis Universal encoder",
+            ));
+        }
+        compressor
+            .process()
+            .map_err(|err| invalid_data(format!("Basis Universal encoding failed: {err:?}")))?;
+    }
+    basis_to_ktx2(compressor.basis_file())
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+struct BasisSlice {
+    image_index: u32,
+    level_index: u8,
+    flags: u8,
+    orig_width: u32,
+    orig_height: u32,
+    file_ofs: usize,
+    file_size: usize,
+}
+
+/// Little-endian unsigned integer of `len` bytes at `ofs`
+fn read_uint(data: &[u8], ofs: usize, len: usize) -> io::Result<u64> {
+    let bytes = data
+        .get(ofs..ofs + len)
+        .ok_or_else(|| invalid_data("Truncated .basis file"))?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| (value << 8) | byte as u64))
+}
+
+fn read_range(data: &[u8], ofs: u64, len: u64) -> io::Result<&[u8]> {
+    data.get(ofs as usize..(ofs + len) as usize)
+        .ok_or_else(|| invalid_data("Truncated .basis file"))
+}
+
+/// Rewraps a `.basis` file (a single image with its mipmaps) into a KTX2 container
+fn basis_to_ktx2(basis: &[u8]) -> io::Result<Vec<u8>> {
+    if basis.len() < BASIS_HEADER_SIZE || read_uint(basis, 0, 2)? as u16 != BASIS_SIGNATURE {
+        return Err(invalid_data("Not a .basis file"));
+    }
+    let total_slices = read_uint(basis, 14, 3)? as usize;
+    let is_uastc = read_uint(basis, 20, 1)? as u8 == BASIS_TEX_FORMAT_UASTC;
+    let has_alpha = read_uint(basis, 21, 2)? as u16 & BASIS_HEADER_FLAG_HAS_ALPHA_SLICES != 0;
+    let slice_desc_ofs = read_uint(basis, 65, 4)? as usize;
+
+    let mut slices = Vec::with_capacity(total_slices);
+    for i in 0..total_slices {
+        let ofs = slice_desc_ofs + i * BASIS_SLICE_DESC_SIZE;
+        slices.push(BasisSlice {
+            image_index: read_uint(basis, ofs, 3)? as u32,
+            level_index: read_uint(basis, ofs + 3, 1)? as u8,
+            flags: read_uint(basis, ofs + 4, 1)? as u8,
+            orig_width: read_uint(basis, ofs + 5, 2)? as u32,
+            orig_height: read_uint(basis, ofs + 7, 2)? as u32,
+            file_ofs: read_uint(basis, ofs + 13, 4)? as usize,
+            file_size: read_uint(basis, ofs + 17, 4)? as usize,
+        });
+    }
+    slices.retain(|slice| slice.image_index == 0);
+    let level_count = slices
+        .iter()
+        .map(|slice| slice.level_index as usize + 1)
+        .max()
+        .ok_or_else(|| invalid_data("No images in the .basis file"))?;
+    let base = slices
+        .iter()
+        .find(|slice| slice.level_index == 0)
+        .ok_or_else(|| invalid_data("No base level in the .basis file"))?;
+    let (width, height) = (base.orig_width, base.orig_height);
+
+    // (the RGB slice and the alpha slice) of each level
+    let empty: &[u8] = &[];
+    let mut levels = vec![(empty, empty); level_count];
+    for slice in &slices {
+        let data = read_range(basis, slice.file_ofs as u64, slice.file_size as u64)?;
+        let level = &mut levels[slice.level_index as usize];
+        match !is_uastc && slice.flags & BASIS_SLICE_FLAG_HAS_ALPHA != 0 {
+            true => level.1 = data,
+            false => level.0 = data,
+        }
+    }
+
+    let dfd = match is_uastc {
+        true => data_format_descriptor(
+            DF_MODEL_UASTC,
+            UASTC_BLOCK_SIZE as u8,
+            &[(
+                0,
+                127,
+                match has_alpha {
+                    true => DF_CHANNEL_RGBA,
+                    false => DF_CHANNEL_RGB,
+                },
+            )],
+        ),
+        false => {
+            let mut samples = vec![(0, 63, DF_CHANNEL_RGB)];
+            if has_alpha {
+                samples.push((64, 63, DF_CHANNEL_AAA));
+            }
+            data_format_descriptor(DF_MODEL_ETC1S, 0, &samples)
+        }
+    };
+
+    let sgd = match is_uastc {
+        true => Vec::new(),
+        false => {
+            let endpoints = read_range(basis, read_uint(basis, 41, 4)?, read_uint(basis, 45, 3)?)?;
+            let selectors = read_range(basis, read_uint(basis, 50, 4)?, read_uint(basis, 54, 3)?)?;
+            let tables = read_range(basis, read_uint(basis, 57, 4)?, read_uint(basis, 61, 4)?)?;
+            let extended = read_range(basis, read_uint(basis, 69, 4)?, read_uint(basis, 73, 4)?)?;
+
+            let mut sgd = Vec::new();
+            put_u16(&mut sgd, read_uint(basis, 39, 2)? as u16); // endpointCount
+            put_u16(&mut sgd, read_uint(basis, 48, 2)? as u16); // selectorCount
+            put_u32(&mut sgd, endpoints.len() as u32);
+            put_u32(&mut sgd, selectors.len() as u32);
+            put_u32(&mut sgd, tables.len() as u32);
+            put_u32(&mut sgd, extended.len() as u32);
+            for (rgb, alpha) in &levels {
+                put_u32(&mut sgd, 0); // imageFlags (not a P-frame)
+                put_u32(&mut sgd, 0);
+                put_u32(&mut sgd, rgb.len() as u32);
+                put_u32(
+                    &mut sgd,
+                    if alpha.is_empty() {
+                        0
+                    } else {
+                        rgb.len() as u32
+                    },
+                );
+                put_u32(&mut sgd, alpha.len() as u32);
+            }
+            sgd.extend_from_slice(endpoints);
+            sgd.extend_from_slice(selectors);
+            sgd.extend_from_slice(tables);
+            sgd.extend_from_slice(extended);
+            sgd
+        }
+    };
+
+    // identifier, header, index, and level index
+    let dfd_offset = 12 + 36 + 32 + 24 * level_count;
+    let mut sgd_offset = dfd_offset + dfd.len();
+    if !sgd.is_empty() {
+        sgd_offset = sgd_offset.next_multiple_of(8);
+    }
+    let level_alignment = match is_uastc {
+        true => UASTC_BLOCK_SIZE,
+        false => 1,
+    };
+
+    // the levels are stored from the smallest one
+    let mut level_index = vec![(0, 0); level_count];
+    let mut offset = sgd_offset + sgd.len();
+    for (i, (rgb, alpha)) in levels.iter().enumerate().rev() {
+        offset = offset.next_multiple_of(level_alignment);
+        let length = rgb.len() + alpha.len();
+        level_index[i] = (offset, length);
+        offset += length;
+    }
+
+    let mut ktx2 = Vec::with_capacity(offset);
+    ktx2.extend_from_slice(&KTX2_IDENTIFIER);
+    put_u32(&mut ktx2, 0); // vkFormat: VK_FORMAT_UNDEFINED
+    put_u32(&mut ktx2, 1); // typeSize
+    put_u32(&mut ktx2, width);
+    put_u32(&mut ktx2, height);
+    put_u32(&mut ktx2, 0); // pixelDepth
+    put_u32(&mut ktx2, 0); // layerCount
+    put_u32(&mut ktx2, 1); // faceCount
+    put_u32(&mut ktx2, level_count as u32);
+    put_u32(
+        &mut ktx2,
+        match is_uastc {
+            true => SUPERCOMPRESSION_NONE,
+            false => SUPERCOMPRESSION_BASIS_LZ,
+        },
+    );
+    put_u32(&mut ktx2, dfd_offset as u32);
+    put_u32(&mut ktx2, dfd.len() as u32);
+    put_u32(&mut ktx2, 0); // kvdByteOffset
+    put_u32(&mut ktx2, 0); // kvdByteLength
+    put_u64(
+        &mut ktx2,
+        if sgd.is_empty() { 0 } else { sgd_offset as u64 },
+    );
+    put_u64(&mut ktx2, sgd.len() as u64);
+    for &(offset, length) in &level_index {
+        put_u64(&mut ktx2, offset as u64);
+        put_u64(&mut ktx2, length as u64);
+        // uncompressedByteLength (unknown with the supercompression)
+        put_u64(&mut ktx2, if is_uastc { length as u64 } else { 0 });
+    }
+    ktx2.extend_from_slice(&dfd);
+    ktx2.resize(sgd_offset, 0);
+    ktx2.extend_from_slice(&sgd);
+    for (i, (rgb, alpha)) in levels.iter().enumerate().rev() {
+        ktx2.resize(level_index[i].0, 0);
+        ktx2.extend_from_slice(rgb);
+        ktx2.extend_from_slice(alpha);
+    }
+    Ok(ktx2)
+}
+
+/// Data Format Descriptor with a basic descriptor block of the samples (bit offset, bit length, channel)
+fn data_format_descriptor(color_model: u8, bytes_plane0: u8, samples: &[(u16, u8, u8)]) -> Vec<u8> {
+    let block_size = 24 + 16 * samples.len();
+    let mut dfd = Vec::with_capacity(4 + block_size);
+    put_u32(&mut dfd, (4 + block_size) as u32); // dfdTotalSize
+    put_u32(&mut dfd, 0); // vendorId (Khronos) and descriptorType (basic)
+    put_u16(&mut dfd, 2); // versionNumber
+    put_u16(&mut dfd, block_size as u16);
+    dfd.extend_from_slice(&[color_model, DF_PRIMARIES_BT709, DF_TRANSFER_SRGB, 0]);
+    dfd.extend_from_slice(&[3, 3, 0, 0]); // 4x4 blocks
+    dfd.extend_from_slice(&[bytes_plane0, 0, 0, 0, 0, 0, 0, 0]);
+    for &(bit_offset, bit_length, channel) in samples {
+        put_u16(&mut dfd, bit_offset);
+        dfd.push(bit_length);
+        dfd.push(channel);
+        dfd.extend_from_slice(&[0, 0, 0, 0]); // samplePosition
+        put_u32(&mut dfd, 0); // sampleLower
+        put_u32(&mut dfd, u32::MAX); // sampleUpper
+    }
+    dfd
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(data: &[u8], ofs: usize) -> u32 {
+        read_uint(data, ofs, 4).unwrap() as u32
+    }
+
+    fn u64_at(data: &[u8], ofs: usize) -> u64 {
+        read_uint(data, ofs, 8).unwrap()
+    }
+
+    fn checker(size: u32) -> image::RgbaImage {
+        image::RgbaImage::from_fn(size, size, |x, y| match (x / 4 + y / 4) % 2 {
+            0 => image::Rgba([255, 0, 0, 255]),
+            _ => image::Rgba([0, 0, 255, 255]),
+        })
+    }
+
+    #[test]
+    fn test_encode_ktx2() {
+        for (compression, scheme) in [
+            (TextureCompression::Etc1s, SUPERCOMPRESSION_BASIS_LZ),
+            (TextureCompression::Uastc, SUPERCOMPRESSION_NONE),
+        ] {
+            let ktx2 = encode_ktx2(&checker(16), compression).unwrap();
+            assert_eq!(ktx2[..12], KTX2_IDENTIFIER);
+            assert_eq!(u32_at(&ktx2, 12), 0); // vkFormat
+            assert_eq!(u32_at(&ktx2, 20), 16); // pixelWidth
+            assert_eq!(u32_at(&ktx2, 24), 16); // pixelHeight
+            assert_eq!(u32_at(&ktx2, 40), 5); // levelCount (16, 8, 4, 2, 1)
+            assert_eq!(u32_at(&ktx2, 44), scheme);
+
+            // the levels are in the file, from the smallest one
+            let mut prev_offset = u64::MAX;
+            for level in 0..5 {
+                let offset = u64_at(&ktx2, 80 + 24 * level);
+                let length = u64_at(&ktx2, 80 + 24 * level + 8);
+                assert!(length > 0);
+                assert!(offset + length <= ktx2.len() as u64);
+                assert!(offset < prev_offset);
+                prev_offset = offset;
+            }
+            let base_offset = u64_at(&ktx2, 80);
+            let base_length = u64_at(&ktx2, 88);
+            assert_eq!(base_offset + base_length, ktx2.len() as u64);
+            if compression == TextureCompression::Uastc {
+                // 4x4 blocks of 16 bytes
+                assert_eq!(base_length, 16 * 16);
+                assert_eq!(base_offset % 16, 0);
+            } else {
+                let sgd_offset = u64_at(&ktx2, 64);
+                let sgd_length = u64_at(&ktx2, 72);
+                assert_eq!(sgd_offset % 8, 0);
+                assert!(sgd_length > 20 + 20 * 5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compress_atlas_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        checker(8).save(dir.path().join("0.png")).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        compress_atlas_dir(dir.path(), "png", TextureCompression::Etc1s).unwrap();
+        assert!(!dir.path().join("0.png").exists());
+        assert!(dir.path().join("0.ktx2").exists());
+        assert!(dir.path().join("notes.txt").exists());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            TextureCompression::negotiate("none").unwrap(),
+            TextureCompression::None
+        );
+        assert_eq!(
+            TextureCompression::negotiate("uastc").unwrap(),
+            TextureCompression::Uastc
+        );
+        assert!(TextureCompression::negotiate("bc7").is_err());
+        assert_eq!(TextureCompression::Etc1s.atlas_extension("webp"), "ktx2");
+        assert_eq!(TextureCompression::None.atlas_extension("webp"), "webp");
+    }
+}
@@ -0,0 +1,178 @@
+//! Statistics of the textures used by the textured sinks
+//!
+//! The sinks packing the textures into atlases record the source images and the generated atlas pages,
+//! and report the totals, the compression ratio and the largest source images of each feature type,
+//! so that the downsampling options (e.g. `limit_texture_resolution`) can be tuned with the data.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::pipeline::Feedback;
+
+/// Number of the largest source images reported for each feature type
+const TOP_N: usize = 5;
+
+/// Collects the texture statistics (can be shared between the writer threads)
+#[derive(Default)]
+pub struct TextureUsage {
+    // size of each source image by the feature type
+    sources: Mutex<BTreeMap<String, HashMap<PathBuf, u64>>>,
+    // size of each atlas page (the directories may be shared by the types, so they are identified by the paths)
+    atlases: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl TextureUsage {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Records a source image used by a feature of the type (recorded once for each image and type)
+    pub fn add_source(&self, typename: &str, path: &Path) {
+        let mut sources = self.sources.lock().unwrap();
+        let images = sources.entry(typename.to_string()).or_default();
+        if !images.contains_key(path) {
+            let size = std::fs::metadata(path).map_or(0, |m| m.len());
+            images.insert(path.to_path_buf(), size);
+        }
+    }
+
+    /// Records the atlas pages exported into the directory (after the export)
+    pub fn add_atlas_dir(&self, dir: &Path) {
+        let mut atlases = self.atlases.lock().unwrap();
+        collect_files(dir, &mut atlases);
+    }
+
+    /// Logs the statistics. Does nothing if no textures are used.
+    pub fn report(&self, feedback: &Feedback) {
+        for line in self.summary() {
+            feedback.info(line);
+        }
+    }
+
+    fn summary(&self) -> Vec<String> {
+        let sources = self.sources.lock().unwrap();
+        let atlases = self.atlases.lock().unwrap();
+        if sources.is_empty() {
+            return Vec::new();
+        }
+
+        // the images shared by the types are counted once in the total
+        let unique: HashMap<&PathBuf, u64> = sources
+            .values()
+            .flat_map(|images| images.iter().map(|(path, size)| (path, *size)))
+            .collect();
+        let source_bytes: u64 = unique.values().sum();
+        let atlas_bytes: u64 = atlases.values().sum();
+
+        let mut lines = vec![format!(
+            "Textures: {} source images ({}) packed into {} atlas pages ({}, {:.1}% of the sources)",
+            unique.len(),
+            format_bytes(source_bytes),
+            atlases.len(),
+            format_bytes(atlas_bytes),
+            match source_bytes {
+                0 => 0.,
+                _ => atlas_bytes as f64 / source_bytes as f64 * 100.,
+            }
+        )];
+
+        for (typename, images) in sources.iter() {
+            let mut images: Vec<(&PathBuf, u64)> =
+                images.iter().map(|(path, size)| (path, *size)).collect();
+            images.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+            let total: u64 = images.iter().map(|(_, size)| size).sum();
+            lines.push(format!(
+                "Textures of {typename}: {} source images ({})",
+                images.len(),
+                format_bytes(total)
+            ));
+            for (path, size) in images.iter().take(TOP_N) {
+                lines.push(format!("  {} ({})", path.display(), format_bytes(*size)));
+            }
+        }
+        lines
+    }
+}
+
+/// Collects the sizes of the files under `dir`
+fn collect_files(dir: &Path, files: &mut HashMap<PathBuf, u64>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            let size = entry.metadata().map_or(0, |m| m.len());
+            files.insert(path, size);
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_texture_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, size: usize| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, vec![0u8; size]).unwrap();
+            path
+        };
+        let wall = write("wall.jpg", 3000);
+        let roof = write("roof.jpg", 1000);
+        let atlas_dir = dir.path().join("atlas");
+        std::fs::create_dir_all(atlas_dir.join("0/0/0")).unwrap();
+        std::fs::write(atlas_dir.join("0/0/0/0.webp"), vec![0u8; 1000]).unwrap();
+
+        let usage = TextureUsage::new();
+        assert!(usage.summary().is_empty());
+
+        usage.add_source("bldg:Building", &wall);
+        usage.add_source("bldg:Building", &wall);
+        usage.add_source("bldg:Building", &roof);
+        usage.add_source("brid:Bridge", &wall);
+        usage.add_atlas_dir(&atlas_dir);
+        usage.add_atlas_dir(&atlas_dir);
+
+        let lines = usage.summary();
+        assert_eq!(
+            lines[0],
+            "Textures: 2 source images (3.9 KiB) packed into 1 atlas pages (1000 B, 25.0% of the sources)"
+        );
+        assert_eq!(
+            lines[1],
+            "Textures of bldg:Building: 2 source images (3.9 KiB)"
+        );
+        // the largest first
+        assert!(lines[2].contains("wall.jpg"));
+        assert!(lines[3].contains("roof.jpg"));
+        assert_eq!(
+            lines[4],
+            "Textures of brid:Bridge: 1 source images (2.9 KiB)"
+        );
+
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
@@ -52,14 +52,23 @@ fn get_distance_par_pixel(vertices: &[(f64, f64, f64)], pixel_coords: &[(u32, u3
 pub fn get_texture_downsample_scale_of_polygon(
     vertices: &[(f64, f64, f64, f64, f64)], // (x, y, z, u, v)
     texture_size: (u32, u32),
+) -> f64 {
+    get_texture_downsample_scale_for_meter_per_pixel(vertices, texture_size, MIN_METER_PER_PIXEL)
+}
+
+/// Obtain the downsample scale to limit the distance per pixel to `min_meter_per_pixel` or less.
+pub fn get_texture_downsample_scale_for_meter_per_pixel(
+    vertices: &[(f64, f64, f64, f64, f64)], // (x, y, z, u, v)
+    texture_size: (u32, u32),
+    min_meter_per_pixel: f64,
 ) -> f64 {
     let uv_coords = vertices.iter().map(|v| (v.3, v.4)).collect::<Vec<_>>();
     let pixel_coords = uv_to_pixel_coords(&uv_coords, texture_size.0, texture_size.1);
     let vertices = vertices.iter().map(|v| (v.0, v.1, v.2)).collect::<Vec<_>>();
     let pixel_per_distance = get_distance_par_pixel(&vertices, &pixel_coords);
 
-    if pixel_per_distance < MIN_METER_PER_PIXEL {
-        1.0 / (MIN_METER_PER_PIXEL / pixel_per_distance)
+    if pixel_per_distance < min_meter_per_pixel {
+        1.0 / (min_meter_per_pixel / pixel_per_distance)
     } else {
         1.0
     }
@@ -0,0 +1,100 @@
+//! Destination of the tiles written by the tiled sinks
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    manifest::Manifest,
+    mbtiles::{is_mbtiles_path, MbtilesWriter},
+    mvt::tileid::TileIdMethod,
+    pmtiles::{is_pmtiles_path, PmtilesWriter, TileCompression, TileType},
+};
+use crate::pipeline::Result;
+
+/// Writes the tiles into a `{z}/{x}/{y}.{ext}` directory tree (with the manifest),
+/// into a single PMTiles archive if the output path has the `.pmtiles` extension,
+/// or into an MBTiles database if it has the `.mbtiles` extension.
+pub enum TileOutput {
+    Directory { path: PathBuf, manifest: Manifest },
+    Archive(PmtilesWriter),
+    Database(MbtilesWriter),
+}
+
+impl TileOutput {
+    /// `compression` is applied to the tiles in the PMTiles archive and the MBTiles database only
+    pub fn create(
+        output_path: &Path,
+        tile_type: TileType,
+        compression: TileCompression,
+    ) -> Result<Self> {
+        if is_pmtiles_path(output_path) {
+            Ok(Self::Archive(PmtilesWriter::create(
+                output_path,
+                tile_type,
+                compression,
+            )?))
+        } else if is_mbtiles_path(output_path) {
+            Ok(Self::Database(MbtilesWriter::create(
+                output_path,
+                compression,
+            )?))
+        } else {
+            Ok(Self::Directory {
+                path: output_path.to_path_buf(),
+                manifest: Manifest::new(),
+            })
+        }
+    }
+
+    /// Writes a tile (can be called from multiple threads). Returns the path or the URI of the tile for logging.
+    pub fn write_tile(&self, tile_id: u64, extension: &str, content: &[u8]) -> Result<String> {
+        let (zoom, x, y) = TileIdMethod::Hilbert.id_to_zxy(tile_id);
+        let tile_path = format!("{zoom}/{x}/{y}.{extension}");
+        match self {
+            Self::Directory { path, manifest } => {
+                let path = path.join(&tile_path);
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::write(&path, content)?;
+                manifest.add(&tile_path, content);
+                Ok(path.to_string_lossy().into_owned())
+            }
+            Self::Archive(writer) => {
+                writer.add_tile(tile_id, content)?;
+                Ok(format!("pmtiles://{tile_path}"))
+            }
+            Self::Database(writer) => {
+                writer.add_tile(zoom, x, y, content)?;
+                Ok(format!("mbtiles://{tile_path}"))
+            }
+        }
+    }
+
+    /// Writes a file other than the tiles into the directory (recorded in the manifest).
+    /// Does nothing for the archive and the database, which store the metadata in themselves.
+    pub fn write_file(&self, relpath: &str, content: &[u8]) -> Result<()> {
+        if let Self::Directory { path, manifest } = self {
+            fs::create_dir_all(path)?;
+            fs::write(path.join(relpath), content)?;
+            manifest.add(relpath, content);
+        }
+        Ok(())
+    }
+
+    pub fn is_directory(&self) -> bool {
+        matches!(self, Self::Directory { .. })
+    }
+
+    /// Writes the manifest, or finishes the archive (or the database) with the metadata
+    pub fn finish(self, metadata: &serde_json::Value) -> Result<()> {
+        match self {
+            Self::Directory { path, manifest } => manifest.write(&path)?,
+            Self::Archive(writer) => writer.finish(metadata)?,
+            Self::Database(writer) => writer.finish(metadata)?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! Tracing the output records back to the CityGML elements
+//!
+//! The sinks supporting the `trace` option record the original `gml:id`, the key generated for the record
+//! (e.g. the `fid` of GeoPackage, the feature ID of MVT) and the path of the source file for every feature.
+//! GeoPackage stores them in a table, and the other sinks write them into a CSV file with [`TraceWriter`].
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use url::Url;
+
+use crate::{
+    parameters::{BooleanParameter, ParameterDefinition, ParameterEntry, ParameterType},
+    pipeline::{PipelineError, Result},
+};
+
+pub fn trace_parameter() -> ParameterDefinition {
+    ParameterDefinition {
+        key: "trace".into(),
+        entry: ParameterEntry {
+            description: "Record the gml:id, the generated key and the source file of each feature"
+                .into(),
+            required: false,
+            parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+            label: Some("地物のID・出力時のキー・元ファイルの対応表を出力する".into()),
+        },
+    }
+}
+
+/// Path of the source file (or the URL if it is not a local file)
+pub fn source_path(base_url: &Url) -> String {
+    match base_url.to_file_path() {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => base_url.to_string(),
+    }
+}
+
+/// Path of the CSV file: `ids.csv` in the output directory, or `{name}.ids.csv` next to the output file
+pub fn trace_path(output_path: &Path, is_directory: bool) -> PathBuf {
+    if is_directory {
+        output_path.join("ids.csv")
+    } else {
+        output_path.with_extension("ids.csv")
+    }
+}
+
+fn map_csv_error(err: csv::Error) -> PipelineError {
+    match err.into_kind() {
+        csv::ErrorKind::Io(err) => PipelineError::IoError(err),
+        kind => PipelineError::Other(format!("{kind:?}")),
+    }
+}
+
+/// Writes the `gml_id,type,key,source` rows (can be called from multiple threads)
+pub struct TraceWriter {
+    writer: Mutex<csv::Writer<BufWriter<File>>>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::create(path)?;
+        let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+        writer
+            .write_record(["gml_id", "type", "key", "source"])
+            .map_err(map_csv_error)?;
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub fn write(&self, gml_id: &str, typename: &str, key: &str, source: &str) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .write_record([gml_id, typename, key, source])
+            .map_err(map_csv_error)
+    }
+
+    pub fn finish(self) -> Result<()> {
+        let writer = self.writer.into_inner().unwrap();
+        writer
+            .into_inner()
+            .map_err(|err| PipelineError::IoError(err.into_error()))?
+            .flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = trace_path(&dir.path().join("out.pmtiles"), false);
+        assert_eq!(path.file_name().unwrap(), "out.ids.csv");
+
+        let writer = TraceWriter::create(&path).unwrap();
+        writer
+            .write(
+                "bldg_1",
+                "bldg:Building",
+                "123",
+                "/data/53394525_bldg_6697.gml",
+            )
+            .unwrap();
+        writer.finish().unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            "gml_id,type,key,source\nbldg_1,bldg:Building,123,/data/53394525_bldg_6697.gml\n"
+        );
+
+        let url = Url::parse("https://example.com/udx/bldg/a.gml").unwrap();
+        assert_eq!(source_path(&url), "https://example.com/udx/bldg/a.gml");
+    }
+}
@@ -1,33 +1,129 @@
 //! CityGML (.gml) Source Provider
 
 use std::{
-    fs,
+    collections::{BTreeMap, BTreeSet},
     io::BufRead,
     path::{Path, PathBuf},
     sync::RwLock,
 };
 
-use nusamai_citygml::{CityGmlElement, CityGmlReader, Envelope, ParseError, SubTreeReader};
-use nusamai_plateau::{appearance::AppearanceStore, models, Entity};
+use nusamai_citygml::{
+    object::{Map, Object, ObjectStereotype, Value},
+    schema::{Attribute, DataTypeDef, Schema, TypeDef, TypeRef},
+    CityGmlElement, CityGmlReader, Envelope, ParseError, SubTreeReader,
+};
+use nusamai_plateau::{appearance::AppearanceStore, codelist::CodelistSource, models, Entity};
 use rayon::prelude::*;
 use url::Url;
 
 use crate::{
-    parameters::Parameters,
+    get_parameter_value,
+    parameters::*,
+    paths,
     pipeline::{self, Feedback, Parcel, PipelineError, Sender},
-    source::{DataSource, DataSourceProvider, SourceInfo},
+    source::{
+        dataset::{self, DatasetTag, IdNamespace},
+        filter::FileFilter,
+        remote, DataSource, DataSourceProvider, SourceInfo,
+    },
 };
 
+/// Typename of the membership records emitted when `group_table` is enabled
+const GROUP_MEMBER_TYPENAME: &str = "grp:GroupMember";
+
+/// Attribute holding the vintage (the year of the dataset) when `year` is given
+pub const YEAR_ATTRIBUTE: &str = "year";
+
 pub struct CityGmlSourceProvider {
     // FIXME: Use the configuration mechanism
     pub filenames: Vec<PathBuf>,
 }
 
 impl DataSourceProvider for CityGmlSourceProvider {
-    fn create(&self, _params: &Parameters) -> Box<dyn DataSource> {
+    fn create(&self, params: &Parameters) -> Box<dyn DataSource> {
+        let resolve_groups = get_parameter_value!(params, "resolve_groups", Boolean).unwrap();
+        let group_table = get_parameter_value!(params, "group_table", Boolean).unwrap();
+        let city_codes = get_parameter_value!(params, "city_code", String)
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|code| code.trim().to_string())
+            .filter(|code| !code.is_empty())
+            .collect();
+        let year = *get_parameter_value!(params, "year", Integer);
+        let id_namespace = get_parameter_value!(params, "id_namespace", String)
+            .clone()
+            .unwrap_or_default();
+        let dataset_attribute = get_parameter_value!(params, "dataset_attribute", Boolean).unwrap();
+        let file_filter = FileFilter::new(
+            get_parameter_value!(params, "mesh_code", String)
+                .as_deref()
+                .unwrap_or_default(),
+            get_parameter_value!(params, "file_pattern", String)
+                .as_deref()
+                .unwrap_or_default(),
+        );
+
+        // the dataset roots are replaced with the files of the selected packages
+        let packages = dataset::parse_packages(
+            get_parameter_value!(params, "packages", String)
+                .as_deref()
+                .unwrap_or_default(),
+        );
+        let filenames =
+            dataset::expand_dataset_roots(&self.filenames, &packages).unwrap_or_else(|err| {
+                log::warn!("Failed to read the dataset directory: {}", err);
+                self.filenames.clone()
+            });
+
+        // the codelists not found next to the source files are looked up in this order
+        let mut codelist_sources = Vec::new();
+        if let Some(dir) = get_parameter_value!(params, "codelist_dir", FileSystemPath) {
+            codelist_sources.push(CodelistSource::Directory(dir.clone()));
+        }
+        let roots: BTreeSet<&Path> = filenames
+            .iter()
+            .filter_map(|path| dataset::dataset_root_of(path))
+            .collect();
+        for root in roots {
+            let dir = root.join("codelists");
+            if paths::extended(&dir).is_dir() {
+                codelist_sources.push(CodelistSource::Directory(dir));
+            }
+            for version in dataset::iur_versions(root) {
+                if !dataset::SUPPORTED_IUR_VERSIONS.contains(&version.as_str()) {
+                    log::warn!(
+                        "The dataset {:?} uses the unsupported i-UR {} schema (the uro attributes may be missing)",
+                        root,
+                        version
+                    );
+                }
+            }
+        }
+        if let Some(url) = get_parameter_value!(params, "codelist_url", String) {
+            match Url::parse(url) {
+                Ok(url) => codelist_sources.push(CodelistSource::Remote(url)),
+                Err(err) => log::warn!("Invalid codelist URL {:?}: {}", url, err),
+            }
+        }
+        if get_parameter_value!(params, "codelist_fallback", Boolean).unwrap() {
+            codelist_sources.push(CodelistSource::Embedded);
+        }
+
         Box::new(CityGmlSource {
-            filenames: self.filenames.clone(),
+            filenames,
             appearance_parsing: false,
+            group_options: GroupOptions {
+                resolve_groups,
+                group_table,
+            },
+            city_codes,
+            file_filter,
+            year,
+            id_namespace,
+            dataset_attribute,
+            codelist_sources,
+            downloader: Default::default(),
         })
     }
 
@@ -38,13 +134,172 @@ impl DataSourceProvider for CityGmlSourceProvider {
     }
 
     fn sink_options(&self) -> Parameters {
-        Parameters::default()
+        let mut params = Parameters::new();
+        params.define(ParameterDefinition {
+            key: "resolve_groups".into(),
+            entry: ParameterEntry {
+                description: "Attach CityObjectGroup ids and roles to the member features".into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("グループの所属情報を付与する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "group_table".into(),
+            entry: ParameterEntry {
+                description: "Emit a record for each CityObjectGroup membership".into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("グループの所属テーブルを出力する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "city_code".into(),
+            entry: ParameterEntry {
+                description:
+                    "Process only the given municipalities (comma-separated city codes, e.g. 13104)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter { value: None }),
+                label: Some("市区町村コード".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "mesh_code".into(),
+            entry: ParameterEntry {
+                description:
+                    "Process only the files of the given meshes (comma-separated mesh codes, e.g. 53394525,533946)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter { value: None }),
+                label: Some("メッシュコード".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "file_pattern".into(),
+            entry: ParameterEntry {
+                description:
+                    "Process only the files whose names match the patterns (comma-separated, e.g. 5339*_bldg_*.gml)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter { value: None }),
+                label: Some("ファイル名のパターン".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "packages".into(),
+            entry: ParameterEntry {
+                description:
+                    "Packages to process in the dataset directories (comma-separated, e.g. bldg,tran)"
+                        .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter { value: None }),
+                label: Some("データセットのパッケージ".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "year".into(),
+            entry: ParameterEntry {
+                description: "Attach the year of the dataset (vintage) to the features".into(),
+                required: false,
+                parameter: ParameterType::Integer(IntegerParameter {
+                    value: None,
+                    min: Some(1900),
+                    max: Some(2999),
+                }),
+                label: Some("データセットの年度".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "id_namespace".into(),
+            entry: ParameterEntry {
+                description:
+                    "Make the gml:ids unique among the datasets: none, prefix (dataset:id) or hash"
+                        .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter {
+                    value: Some("none".into()),
+                }),
+                label: Some("データセットごとのIDの区別（none, prefix, hash）".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "dataset_attribute".into(),
+            entry: ParameterEntry {
+                description: "Attach the name of the dataset to the features".into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("データセット名を付与する".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "codelist_dir".into(),
+            entry: ParameterEntry {
+                description: "Directory to look up the codelists not found next to the input files"
+                    .into(),
+                required: false,
+                parameter: ParameterType::FileSystemPath(FileSystemPathParameter {
+                    value: None,
+                    must_exist: true,
+                }),
+                label: Some("コードリストのフォルダ".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "codelist_url".into(),
+            entry: ParameterEntry {
+                description: "Base URL to download the codelists not found next to the input files"
+                    .into(),
+                required: false,
+                parameter: ParameterType::String(StringParameter { value: None }),
+                label: Some("コードリストのURL".into()),
+            },
+        });
+        params.define(ParameterDefinition {
+            key: "codelist_fallback".into(),
+            entry: ParameterEntry {
+                description: "Use the built-in common codelists if not found elsewhere".into(),
+                required: false,
+                parameter: ParameterType::Boolean(BooleanParameter { value: Some(false) }),
+                label: Some("内蔵のコードリストを使用する".into()),
+            },
+        });
+        params
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct GroupOptions {
+    /// Attach `groupIds` and `groupRoles` to the members of CityObjectGroups
+    resolve_groups: bool,
+    /// Emit a `grp:GroupMember` data record for each membership
+    group_table: bool,
+}
+
+impl GroupOptions {
+    fn is_enabled(&self) -> bool {
+        self.resolve_groups || self.group_table
     }
 }
 
 pub struct CityGmlSource {
     filenames: Vec<PathBuf>,
     appearance_parsing: bool,
+    group_options: GroupOptions,
+    /// Municipalities to process. Empty means all.
+    city_codes: Vec<String>,
+    /// Selection of the files by the mesh codes and the file names (or the error in the parameters)
+    file_filter: Result<FileFilter, String>,
+    /// Vintage attached to the features as the `year` attribute
+    year: Option<i64>,
+    /// How the gml:ids are made unique among the datasets (`none`, `prefix` or `hash`)
+    id_namespace: String,
+    /// Attach the name of the dataset to the features as the `dataset` attribute
+    dataset_attribute: bool,
+    /// Where to look up the codelists missing next to the source files
+    codelist_sources: Vec<CodelistSource>,
+    /// Downloads the input files given as URLs
+    downloader: remote::Downloader,
 }
 
 impl DataSource for CityGmlSource {
@@ -52,40 +307,170 @@ impl DataSource for CityGmlSource {
         self.appearance_parsing = value;
     }
 
+    fn transform_schema(&self, schema: &mut Schema) {
+        if self.dataset_attribute {
+            for ty in schema.types.values_mut() {
+                if let TypeDef::Feature(typedef) = ty {
+                    typedef.attributes.insert(
+                        dataset::DATASET_ATTRIBUTE.into(),
+                        Attribute::new(TypeRef::String),
+                    );
+                }
+            }
+        }
+
+        if self.year.is_some() {
+            for ty in schema.types.values_mut() {
+                if let TypeDef::Feature(typedef) = ty {
+                    typedef
+                        .attributes
+                        .insert(YEAR_ATTRIBUTE.into(), Attribute::new(TypeRef::Integer));
+                }
+            }
+        }
+
+        if self.group_options.resolve_groups {
+            for ty in schema.types.values_mut() {
+                if let TypeDef::Feature(typedef) = ty {
+                    for key in ["groupIds", "groupRoles"] {
+                        typedef.attributes.insert(
+                            key.into(),
+                            Attribute {
+                                type_ref: TypeRef::String,
+                                min_occurs: 0,
+                                max_occurs: None,
+                                original_name: None,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        if self.group_options.group_table {
+            let mut typedef = DataTypeDef::default();
+            for key in ["groupId", "memberId", "role"] {
+                typedef
+                    .attributes
+                    .insert(key.into(), Attribute::new(TypeRef::String));
+            }
+            schema
+                .types
+                .insert(GROUP_MEMBER_TYPENAME.into(), TypeDef::Data(typedef));
+        }
+    }
+
     fn run(&mut self, downstream: Sender, feedback: &Feedback) -> pipeline::Result<()> {
-        let code_resolver = nusamai_plateau::codelist::Resolver::new();
+        // (the remote codelists are downloaded into the cache like the input files, with retries)
+        let downloader = self.downloader.clone();
+        let download_feedback = feedback.clone();
+        let code_resolver =
+            nusamai_plateau::codelist::Resolver::with_sources(self.codelist_sources.clone())
+                .with_fetcher(move |url| {
+                    let path = downloader
+                        .fetch(url, &download_feedback)
+                        .map_err(std::io::Error::other)?;
+                    Ok(paths::extended(&path))
+                });
+        let file_filter = self
+            .file_filter
+            .as_ref()
+            .map_err(|err| PipelineError::Other(err.clone()))?;
+        let id_namespace = IdNamespace::negotiate(&self.id_namespace)?;
 
         self.filenames.par_iter().try_for_each(|filename| {
             feedback.ensure_not_canceled()?;
 
+            if !file_filter.matches(filename) {
+                feedback.info(format!(
+                    "Skipping a file not matching the mesh codes or the file name patterns: {:?}",
+                    filename
+                ));
+                return Ok(());
+            }
+
+            if !self.city_codes.is_empty() {
+                if let Some(code) = city_code_from_path(filename) {
+                    if !self.city_codes.contains(&code) {
+                        feedback.info(format!("Skipping a file of other city: {:?}", filename));
+                        return Ok(());
+                    }
+                }
+            }
+
+            // the remote files are parsed with their URLs as the base URL of the textures and the codelists
+            let (path, source_url) = match remote::remote_url(filename) {
+                Some(url) => (self.downloader.fetch(&url, feedback)?, url),
+                None => {
+                    let source_url =
+                        paths::file_url(&paths::canonicalize(filename)?).ok_or_else(|| {
+                            PipelineError::Other(format!(
+                                "Invalid path of the input file: {:?}",
+                                filename
+                            ))
+                        })?;
+                    (filename.clone(), source_url)
+                }
+            };
+
             feedback.info(format!("Parsing CityGML file: {:?} ...", filename));
-            let file = std::fs::File::open(filename)?;
+            let file = std::fs::File::open(paths::extended(&path))?;
             let reader = std::io::BufReader::with_capacity(1024 * 1024, file);
             let mut xml_reader = quick_xml::NsReader::from_reader(reader);
-            let source_url = Url::from_file_path(fs::canonicalize(Path::new(filename))?).unwrap();
+
+            let dataset_tag =
+                (id_namespace != IdNamespace::None || self.dataset_attribute).then(|| DatasetTag {
+                    name: dataset::dataset_name_of(filename).unwrap_or_default(),
+                    id_namespace,
+                    attribute: self.dataset_attribute,
+                });
 
             let context = nusamai_citygml::ParseContext::new(source_url.clone(), &code_resolver);
             let mut citygml_reader = CityGmlReader::new(context);
 
             let mut st = citygml_reader.start_root(&mut xml_reader)?;
-            match toplevel_dispatcher(&mut st, &downstream, feedback, self.appearance_parsing) {
+            match toplevel_dispatcher(
+                &mut st,
+                &downstream,
+                feedback,
+                self.appearance_parsing,
+                self.group_options,
+                &self.city_codes,
+                self.year,
+                dataset_tag.as_ref(),
+            ) {
                 Ok(_) => Ok::<(), PipelineError>(()),
                 Err(ParseError::Canceled) => Err(PipelineError::Canceled),
                 Err(e) => Err(e.into()),
             }
         })?;
 
+        let unresolved = code_resolver.unresolved();
+        if !unresolved.is_empty() {
+            feedback.warn(format!(
+                "Codelists not found (the codes are output as they are): {}",
+                unresolved.join(", ")
+            ));
+        }
+
         Ok(())
     }
 }
 
 // TODO: Move this to nusamai-plateau ?
+#[allow(clippy::too_many_arguments)]
 fn toplevel_dispatcher<R: BufRead>(
     st: &mut SubTreeReader<R>,
     downstream: &Sender,
     feedback: &Feedback,
     parse_appearances: bool,
+    group_options: GroupOptions,
+    city_codes: &[String],
+    year: Option<i64>,
+    dataset_tag: Option<&DatasetTag>,
 ) -> Result<(), ParseError> {
+    // entities are held until the end of the file when they need information from other entities
+    let deferred = parse_appearances || group_options.is_enabled();
     let mut entities = Vec::new();
     let mut global_appearances = AppearanceStore::default();
     let mut envelope = Envelope::default();
@@ -109,7 +494,24 @@ fn toplevel_dispatcher<R: BufRead>(
                 cityobj.parse(st)?;
                 let geometry_store = st.collect_geometries(envelope.crs_uri.clone());
 
-                if let Some(root) = cityobj.into_object() {
+                if let Some(mut root) = cityobj.into_object() {
+                    if !city_codes.is_empty() {
+                        if let Some(code) = find_city_code(&root) {
+                            if !city_codes.iter().any(|c| c == code) {
+                                return Ok(());
+                            }
+                        }
+                    }
+
+                    if let (Some(year), Value::Object(obj)) = (year, &mut root) {
+                        obj.attributes
+                            .insert(YEAR_ATTRIBUTE.into(), Value::Integer(year));
+                    }
+                    // (namespaced after resolving the groups, which refer to the original ids)
+                    if let (Some(tag), false) = (dataset_tag, deferred) {
+                        tag.apply(&mut root);
+                    }
+
                     let entity = Entity {
                         root,
                         base_url: url::Url::parse("file:///dummy").unwrap(),
@@ -117,8 +519,8 @@ fn toplevel_dispatcher<R: BufRead>(
                         appearance_store: Default::default(), // TODO: from local appearances
                     };
 
-                    if parse_appearances {
-                        // store the entity to bind the appearance later
+                    if deferred {
+                        // store the entity to bind the appearance or groups later
                         entities.push(entity);
                     } else {
                         // send the entity immediately
@@ -150,14 +552,38 @@ fn toplevel_dispatcher<R: BufRead>(
         }
     })?;
 
-    if parse_appearances {
-        for entity in entities {
+    if deferred {
+        let memberships = match group_options.is_enabled() {
+            true => collect_group_memberships(&entities),
+            false => Default::default(),
+        };
+
+        if group_options.group_table {
+            for mut entity in group_member_entities(&memberships) {
+                if let Some(tag) = dataset_tag {
+                    tag.apply(&mut entity.root);
+                }
+                if downstream.send(Parcel { entity }).is_err() {
+                    feedback.cancel();
+                    return Ok(());
+                }
+            }
+        }
+
+        for mut entity in entities {
             if feedback.is_canceled() {
                 return Err(ParseError::Canceled);
             }
 
+            if group_options.resolve_groups && !memberships.is_empty() {
+                attach_group_memberships(&mut entity.root, &memberships);
+            }
+            if let Some(tag) = dataset_tag {
+                tag.apply(&mut entity.root);
+            }
+
             // merge global appearances into the entity's local appearance store
-            {
+            if parse_appearances {
                 let geom_store = entity.geometry_store.read().unwrap();
                 entity.appearance_store.write().unwrap().merge_global(
                     &mut global_appearances,
@@ -176,6 +602,161 @@ fn toplevel_dispatcher<R: BufRead>(
     Ok(())
 }
 
+/// Extracts the city code from the PLATEAU package directory name (e.g. `13104_shinjuku-ku_city_2023_citygml_1_op`)
+fn city_code_from_path(path: &Path) -> Option<String> {
+    let path = paths::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path.ancestors().skip(1).find_map(|dir| {
+        let name = dir.file_name()?.to_str()?;
+        let (code, _) = name.split_once('_')?;
+        (code.len() == 5 && code.bytes().all(|b| b.is_ascii_digit())).then(|| code.to_string())
+    })
+}
+
+/// Extracts the year of the dataset from the PLATEAU package directory name (e.g. `2023` of `13104_shinjuku-ku_city_2023_citygml_1_op`)
+pub fn year_from_path(path: &Path) -> Option<u16> {
+    let path = paths::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path.ancestors().skip(1).find_map(|dir| {
+        let name = dir.file_name()?.to_str()?;
+        let parts: Vec<&str> = name.split('_').collect();
+        parts.windows(2).find_map(|w| match w {
+            [year, "citygml"] if year.len() == 4 => year.parse().ok(),
+            _ => None,
+        })
+    })
+}
+
+/// Finds the city code (`uro:city`) in the attributes of the city object
+fn find_city_code(value: &Value) -> Option<&str> {
+    match value {
+        Value::Object(obj) => {
+            if let Some(Value::Code(code)) = obj.attributes.get("uro:city") {
+                return Some(code.code());
+            }
+            obj.attributes.values().find_map(find_city_code)
+        }
+        Value::Array(arr) => arr.iter().find_map(find_city_code),
+        _ => None,
+    }
+}
+
+/// A group that a city object belongs to
+struct GroupMembership {
+    group_id: String,
+    role: Option<String>,
+}
+
+/// Collects `grp:groupMember` references of the CityObjectGroups, keyed by the member's gml:id.
+/// (sorted by the member's gml:id, so that the `grp:GroupMember` records are written in the same order every time)
+fn collect_group_memberships(entities: &[Entity]) -> BTreeMap<String, Vec<GroupMembership>> {
+    let mut memberships: BTreeMap<String, Vec<GroupMembership>> = BTreeMap::new();
+
+    for entity in entities {
+        let Value::Object(group) = &entity.root else {
+            continue;
+        };
+        if group.typename != "grp:CityObjectGroup" {
+            continue;
+        }
+        let Some(group_id) = group.stereotype.id() else {
+            continue;
+        };
+        let Some(Value::Array(members)) = group.attributes.get("grp:groupMember") else {
+            continue;
+        };
+
+        for member in members {
+            let Value::Object(member) = member else {
+                continue;
+            };
+            let Some(Value::String(href)) = member.attributes.get("href") else {
+                continue;
+            };
+            // "#bldg_xxx" or "other.gml#bldg_xxx"
+            let member_id = href.rsplit_once('#').map_or(href.as_str(), |(_, id)| id);
+            let role = match member.attributes.get("role") {
+                Some(Value::String(role)) => Some(role.clone()),
+                _ => None,
+            };
+            memberships
+                .entry(member_id.to_string())
+                .or_default()
+                .push(GroupMembership {
+                    group_id: group_id.to_string(),
+                    role,
+                });
+        }
+    }
+
+    memberships
+}
+
+/// Attaches `groupIds` and `groupRoles` to every object (including nested ones) referenced by a group.
+fn attach_group_memberships(
+    value: &mut Value,
+    memberships: &BTreeMap<String, Vec<GroupMembership>>,
+) {
+    match value {
+        Value::Object(obj) => {
+            for child in obj.attributes.values_mut() {
+                attach_group_memberships(child, memberships);
+            }
+            let Some(groups) = obj.stereotype.id().and_then(|id| memberships.get(id)) else {
+                return;
+            };
+            obj.attributes.insert(
+                "groupIds".into(),
+                Value::Array(
+                    groups
+                        .iter()
+                        .map(|g| Value::String(g.group_id.clone()))
+                        .collect(),
+                ),
+            );
+            obj.attributes.insert(
+                "groupRoles".into(),
+                Value::Array(
+                    groups
+                        .iter()
+                        .map(|g| Value::String(g.role.clone().unwrap_or_default()))
+                        .collect(),
+                ),
+            );
+        }
+        Value::Array(arr) => {
+            for v in arr.iter_mut() {
+                attach_group_memberships(v, memberships);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Creates a `grp:GroupMember` data entity for each membership.
+fn group_member_entities(
+    memberships: &BTreeMap<String, Vec<GroupMembership>>,
+) -> impl Iterator<Item = Entity> + '_ {
+    memberships.iter().flat_map(|(member_id, groups)| {
+        groups.iter().map(move |group| {
+            let mut attributes = Map::default();
+            attributes.insert("groupId".into(), Value::String(group.group_id.clone()));
+            attributes.insert("memberId".into(), Value::String(member_id.clone()));
+            if let Some(role) = &group.role {
+                attributes.insert("role".into(), Value::String(role.clone()));
+            }
+            Entity {
+                root: Value::Object(Object {
+                    typename: GROUP_MEMBER_TYPENAME.into(),
+                    stereotype: ObjectStereotype::Data,
+                    attributes,
+                }),
+                base_url: url::Url::parse("file:///dummy").unwrap(),
+                geometry_store: Default::default(),
+                appearance_store: Default::default(),
+            }
+        })
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::mpsc::sync_channel;
@@ -192,7 +773,7 @@ mod tests {
                     "../nusamai-plateau/tests/data/yokosuka-shi/udx/bldg/52397519_bldg_6697_op.gml",
                 )],
             };
-            let mut source = source_provider.create(&Parameters::default());
+            let mut source = source_provider.create(&source_provider.sink_options());
             source.set_appearance_parsing(use_appearance);
             let (_, feedback, _) = feedback::watcher();
 
@@ -215,4 +796,176 @@ mod tests {
             });
         }
     }
+
+    #[test]
+    fn resolve_group_memberships() {
+        let make_entity = |typename: &'static str, id: &str, attributes: Map| Entity {
+            root: Value::Object(Object {
+                typename: typename.into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: id.into(),
+                    geometries: Default::default(),
+                },
+                attributes,
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: Default::default(),
+            appearance_store: Default::default(),
+        };
+
+        let member = |href: &str, role: Option<&str>| {
+            let mut attributes = Map::default();
+            attributes.insert("href".into(), Value::String(href.into()));
+            if let Some(role) = role {
+                attributes.insert("role".into(), Value::String(role.into()));
+            }
+            Value::Object(Object {
+                typename: "grp:_CityObjectOrRef".into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: String::new(),
+                    geometries: Default::default(),
+                },
+                attributes,
+            })
+        };
+
+        let mut group_attrs = Map::default();
+        group_attrs.insert(
+            "grp:groupMember".into(),
+            Value::Array(vec![
+                member("#bldg_2", None),
+                member("#bldg_1", Some("floor1")),
+            ]),
+        );
+        let entities = vec![
+            make_entity("grp:CityObjectGroup", "grp_1", group_attrs),
+            make_entity("bldg:Building", "bldg_1", Map::default()),
+            make_entity("bldg:Building", "bldg_3", Map::default()),
+        ];
+
+        let memberships = collect_group_memberships(&entities);
+        assert_eq!(memberships.len(), 2);
+        let member_ids: Vec<_> = group_member_entities(&memberships)
+            .map(|entity| {
+                let Value::Object(obj) = entity.root else {
+                    unreachable!();
+                };
+                obj.attributes["memberId"].clone()
+            })
+            .collect();
+        assert_eq!(
+            member_ids,
+            vec![
+                Value::String("bldg_1".into()),
+                Value::String("bldg_2".into())
+            ]
+        );
+
+        let mut entities = entities.into_iter().skip(1);
+        let mut building = entities.next().unwrap();
+        attach_group_memberships(&mut building.root, &memberships);
+        let Value::Object(obj) = &building.root else {
+            unreachable!();
+        };
+        assert_eq!(
+            obj.attributes["groupIds"],
+            Value::Array(vec![Value::String("grp_1".into())])
+        );
+        assert_eq!(
+            obj.attributes["groupRoles"],
+            Value::Array(vec![Value::String("floor1".into())])
+        );
+
+        let mut other = entities.next().unwrap();
+        attach_group_memberships(&mut other.root, &memberships);
+        let Value::Object(obj) = &other.root else {
+            unreachable!();
+        };
+        assert!(!obj.attributes.contains_key("groupIds"));
+    }
+
+    #[test]
+    fn filter_by_city_code() {
+        assert_eq!(
+            city_code_from_path(Path::new(
+                "/data/13104_shinjuku-ku_city_2023_citygml_1_op/udx/bldg/53394525_bldg_6697_op.gml"
+            )),
+            Some("13104".to_string())
+        );
+        assert_eq!(
+            city_code_from_path(Path::new(
+                "../nusamai-plateau/tests/data/yokosuka-shi/udx/bldg/52397519_bldg_6697_op.gml"
+            )),
+            None
+        );
+
+        let count_entities = |city_code: &str| {
+            let (sender, receiver) = sync_channel(100);
+            let source_provider = CityGmlSourceProvider {
+                filenames: vec![PathBuf::from(
+                    "../nusamai-plateau/tests/data/yokosuka-shi/udx/bldg/52397519_bldg_6697_op.gml",
+                )],
+            };
+            let mut params = source_provider.sink_options();
+            params
+                .update_values_with_str(&[("city_code".into(), city_code.into())])
+                .unwrap();
+            let mut source = source_provider.create(&params);
+            let (_, feedback, _) = feedback::watcher();
+            std::thread::scope(|scope| {
+                scope.spawn(move || source.run(sender, &feedback).unwrap());
+                receiver.iter().count()
+            })
+        };
+        assert!(count_entities("14201") > 0);
+        assert!(count_entities("13104, 14201") > 0);
+        assert_eq!(count_entities("13104"), 0);
+    }
+
+    #[test]
+    fn attach_year() {
+        assert_eq!(
+            year_from_path(Path::new(
+                "/data/13104_shinjuku-ku_city_2023_citygml_1_op/udx/bldg/53394525_bldg_6697_op.gml"
+            )),
+            Some(2023)
+        );
+        assert_eq!(
+            year_from_path(Path::new(
+                "../nusamai-plateau/tests/data/yokosuka-shi/udx/bldg/52397519_bldg_6697_op.gml"
+            )),
+            None
+        );
+
+        let (sender, receiver) = sync_channel(100);
+        let source_provider = CityGmlSourceProvider {
+            filenames: vec![PathBuf::from(
+                "../nusamai-plateau/tests/data/yokosuka-shi/udx/bldg/52397519_bldg_6697_op.gml",
+            )],
+        };
+        let mut params = source_provider.sink_options();
+        params
+            .update_values_with_str(&[("year".into(), "2022".into())])
+            .unwrap();
+        let mut source = source_provider.create(&params);
+
+        let mut schema = Schema::default();
+        models::TopLevelCityObject::collect_schema(&mut schema);
+        source.transform_schema(&mut schema);
+        let Some(TypeDef::Feature(building)) = schema.types.get("bldg:Building") else {
+            panic!("bldg:Building is not a feature type");
+        };
+        assert!(building.attributes.contains_key(YEAR_ATTRIBUTE));
+
+        let (_, feedback, _) = feedback::watcher();
+        std::thread::scope(|scope| {
+            scope.spawn(move || source.run(sender, &feedback).unwrap());
+            for parcel in receiver {
+                let Value::Object(obj) = &parcel.entity.root else {
+                    unreachable!();
+                };
+                assert_eq!(obj.attributes[YEAR_ATTRIBUTE], Value::Integer(2022));
+            }
+        });
+    }
 }
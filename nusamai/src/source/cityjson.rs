@@ -0,0 +1,1105 @@
+//! CityJSON (.json) and CityJSONSeq (.jsonl) Source Provider
+//!
+//! Each city object without a parent becomes an entity, with its children (e.g. the building parts) nested in the
+//! attributes. The semantic surfaces of the geometries become the boundary surfaces (e.g. `bldg:RoofSurface` in
+//! `bldg:boundedBy`), as parsed from CityGML. The appearances (materials and textures) are not read.
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use hashbrown::HashSet;
+use indexmap::IndexSet;
+use nusamai_citygml::{
+    object::{Map, Object, ObjectStereotype, Value},
+    schema::{self, Attribute, DataTypeDef, Schema, TypeDef, TypeRef},
+    GeometryRef, GeometryRefs, GeometryStore, GeometryType,
+};
+use nusamai_plateau::Entity;
+use nusamai_projection::crs::*;
+use rayon::prelude::*;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::{
+    parameters::Parameters,
+    pipeline::{self, Feedback, Parcel, PipelineError, Sender},
+    sink::output::open_decompressed,
+    source::{DataSource, DataSourceProvider, SourceInfo},
+};
+
+/// Keys of the semantic surfaces that are not their attributes
+const SEMANTIC_SURFACE_KEYS: [&str; 3] = ["type", "parent", "children"];
+
+/// Checks if the file is a CityJSON or CityJSONSeq file (by the extension, possibly compressed with gzip or zstd)
+pub fn is_city_json(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let name = name.to_ascii_lowercase();
+    let name = name
+        .strip_suffix(".gz")
+        .or_else(|| name.strip_suffix(".zst"))
+        .unwrap_or(&name);
+    name.ends_with(".json") || name.ends_with(".jsonl")
+}
+
+pub struct CityJsonSourceProvider {
+    pub filenames: Vec<PathBuf>,
+}
+
+impl DataSourceProvider for CityJsonSourceProvider {
+    fn create(&self, _params: &Parameters) -> Box<dyn DataSource> {
+        Box::new(CityJsonSource {
+            filenames: self.filenames.clone(),
+        })
+    }
+
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            name: "CityJSON".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        Parameters::default()
+    }
+}
+
+pub struct CityJsonSource {
+    filenames: Vec<PathBuf>,
+}
+
+impl DataSource for CityJsonSource {
+    fn set_appearance_parsing(&mut self, _value: bool) {
+        // the appearances are not read
+    }
+
+    fn transform_schema(&self, schema: &mut Schema) {
+        // The attributes are not defined in advance, so they are collected from the files
+        for filename in &self.filenames {
+            let result = open_decompressed(filename)
+                .map_err(PipelineError::from)
+                .and_then(|reader| collect_schema(BufReader::new(reader), schema));
+            if let Err(err) = result {
+                log::warn!("Failed to read the attributes of {:?}: {}", filename, err);
+            }
+        }
+    }
+
+    fn run(&mut self, downstream: Sender, feedback: &Feedback) -> pipeline::Result<()> {
+        self.filenames.par_iter().try_for_each(|filename| {
+            feedback.ensure_not_canceled()?;
+
+            feedback.info(format!("Parsing CityJSON file: {:?} ...", filename));
+            let reader = BufReader::new(open_decompressed(filename)?);
+            read_entities(reader, |entity| {
+                feedback.ensure_not_canceled()?;
+                if downstream.send(Parcel { entity }).is_err() {
+                    return Err(PipelineError::Canceled);
+                }
+                Ok(())
+            })
+            .map_err(|err| match err {
+                PipelineError::Other(msg) => {
+                    PipelineError::Other(format!("{:?}: {}", filename, msg))
+                }
+                err => err,
+            })
+        })
+    }
+}
+
+/// Reads the entities from a CityJSON or CityJSONSeq, calling `f` for each entity.
+pub fn read_entities<R: BufRead>(
+    reader: R,
+    mut f: impl FnMut(Entity) -> pipeline::Result<()>,
+) -> pipeline::Result<()> {
+    read_documents(reader, |context, document| {
+        document.to_entities(context, &mut f)
+    })
+}
+
+/// Adds the city object types and their attributes found in a CityJSON or CityJSONSeq to the schema
+pub fn collect_schema<R: BufRead>(reader: R, schema: &mut Schema) -> pipeline::Result<()> {
+    read_documents(reader, |_, document| {
+        for cityobj in document.city_objects.values() {
+            let typename = typename(&cityobj.ty);
+            add_feature_type(schema, &typename, cityobj.attributes.iter());
+
+            let prefix = prefix_of(&typename);
+            let semantic_surfaces = cityobj
+                .geometry
+                .iter()
+                .filter_map(|geom| geom.semantics.as_ref())
+                .flat_map(|semantics| &semantics.surfaces);
+            for surface in semantic_surfaces {
+                if let Some(ty) = surface.get("type").and_then(JsonValue::as_str) {
+                    let typename = qualified(prefix, ty.trim_start_matches('+'));
+                    add_feature_type(schema, &typename, surface_attributes(surface));
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+fn parse_error(msg: impl std::fmt::Display) -> PipelineError {
+    PipelineError::Other(format!("Invalid CityJSON: {msg}"))
+}
+
+/// Reads the CityJSON object, or the header and the `CityJSONFeature`s of a CityJSONSeq
+fn read_documents<R: BufRead>(
+    mut reader: R,
+    mut f: impl FnMut(&Context, Document) -> pipeline::Result<()>,
+) -> pipeline::Result<()> {
+    let mut first_line = String::new();
+    reader.read_line(&mut first_line)?;
+    let mut header: Document = match serde_json::from_str(&first_line) {
+        // the header of a CityJSONSeq (or a CityJSON in a line)
+        Ok(header) => header,
+        // a CityJSON spanning lines
+        Err(_) => serde_json::from_reader(first_line.as_bytes().chain(&mut reader))
+            .map_err(parse_error)?,
+    };
+    if header.ty != "CityJSON" {
+        return Err(parse_error(format!("unexpected type {:?}", header.ty)));
+    }
+
+    let context = Context::new(&mut header)?;
+    f(&context, header)?;
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let feature: Document = serde_json::from_str(&line).map_err(parse_error)?;
+        if feature.ty != "CityJSONFeature" {
+            return Err(parse_error(format!("unexpected type {:?}", feature.ty)));
+        }
+        f(&context, feature)?;
+    }
+
+    Ok(())
+}
+
+/// A CityJSON object, or a line of CityJSONSeq
+#[derive(Deserialize)]
+struct Document {
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(default)]
+    transform: Option<Transform>,
+    #[serde(default)]
+    metadata: Option<Metadata>,
+    #[serde(rename = "CityObjects", default)]
+    city_objects: indexmap::IndexMap<String, CityObject>,
+    #[serde(default)]
+    vertices: Vec<[f64; 3]>,
+    #[serde(rename = "geometry-templates", default)]
+    geometry_templates: Option<GeometryTemplates>,
+}
+
+#[derive(Deserialize)]
+struct Transform {
+    scale: [f64; 3],
+    translate: [f64; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            scale: [1.0; 3],
+            translate: [0.0; 3],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    #[serde(rename = "referenceSystem", default)]
+    reference_system: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GeometryTemplates {
+    templates: Vec<Geometry>,
+    #[serde(rename = "vertices-templates")]
+    vertices_templates: Vec<[f64; 3]>,
+}
+
+#[derive(Deserialize)]
+struct CityObject {
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(default)]
+    attributes: serde_json::Map<String, JsonValue>,
+    #[serde(default)]
+    geometry: Vec<Geometry>,
+    #[serde(default)]
+    children: Vec<String>,
+    #[serde(default)]
+    parents: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Geometry {
+    #[serde(rename = "type")]
+    ty: String,
+    /// A number (CityJSON 1.0) or a string (e.g. `"2.2"`)
+    #[serde(default)]
+    lod: Option<JsonValue>,
+    #[serde(default)]
+    boundaries: JsonValue,
+    #[serde(default)]
+    semantics: Option<Semantics>,
+    /// Index of the template (GeometryInstance)
+    #[serde(default)]
+    template: Option<usize>,
+    /// Row-major 4x4 matrix (GeometryInstance)
+    #[serde(rename = "transformationMatrix", default)]
+    transformation_matrix: Option<[f64; 16]>,
+}
+
+#[derive(Deserialize)]
+struct Semantics {
+    #[serde(default)]
+    surfaces: Vec<serde_json::Map<String, JsonValue>>,
+    /// Index of the semantic surface of each surface (nested as the boundaries)
+    #[serde(default)]
+    values: JsonValue,
+}
+
+/// Properties shared by the documents of a file (the header of CityJSONSeq)
+struct Context {
+    epsg: EpsgCode,
+    /// CityJSON has the x (easting or longitude) first, while the pipeline takes the axis order of the CRS as in
+    /// CityGML (e.g. latitude first)
+    swap_xy: bool,
+    transform: Transform,
+    templates: Option<GeometryTemplates>,
+}
+
+impl Context {
+    fn new(header: &mut Document) -> pipeline::Result<Self> {
+        let reference_system = header
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.reference_system.as_deref());
+        let (epsg, swap_xy) = input_crs(reference_system)?;
+        Ok(Self {
+            epsg,
+            swap_xy,
+            transform: header.transform.take().unwrap_or_default(),
+            templates: header.geometry_templates.take(),
+        })
+    }
+}
+
+/// The EPSG code of the geometry store (and whether to swap x and y) for the reference system of CityJSON
+fn input_crs(reference_system: Option<&str>) -> pipeline::Result<(EpsgCode, bool)> {
+    let Some(uri) = reference_system else {
+        return Err(parse_error("the reference system is not given"));
+    };
+    // e.g. "https://www.opengis.net/def/crs/EPSG/0/6677" or "urn:ogc:def:crs:EPSG::6677"
+    let code: EpsgCode = uri
+        .rsplit(['/', ':'])
+        .next()
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| parse_error(format!("unknown reference system {:?}", uri)))?;
+
+    match code {
+        EPSG_JGD2011_GEOGRAPHIC_2D | EPSG_JGD2011_GEOGRAPHIC_3D => {
+            Ok((EPSG_JGD2011_GEOGRAPHIC_3D, true))
+        }
+        EPSG_JGD2011_JPRECT_I..=EPSG_JGD2011_JPRECT_XIII => Ok((
+            code - EPSG_JGD2011_JPRECT_I + EPSG_JGD2011_JPRECT_I_JGD2011_HEIGHT,
+            true,
+        )),
+        EPSG_JGD2011_JPRECT_I_JGD2011_HEIGHT..=EPSG_JGD2011_JPRECT_XIII_JGD2011_HEIGHT => {
+            Ok((code, true))
+        }
+        // (longitude first in the pipeline)
+        EPSG_WGS84_GEOGRAPHIC_2D | EPSG_WGS84_GEOGRAPHIC_3D => {
+            Ok((EPSG_WGS84_GEOGRAPHIC_3D, false))
+        }
+        _ => Err(PipelineError::Other(format!(
+            "Unsupported reference system of CityJSON: EPSG:{code}"
+        ))),
+    }
+}
+
+impl Document {
+    fn to_entities(
+        &self,
+        context: &Context,
+        f: &mut impl FnMut(Entity) -> pipeline::Result<()>,
+    ) -> pipeline::Result<()> {
+        for (id, cityobj) in &self.city_objects {
+            // (the children are nested in their parents)
+            if cityobj
+                .parents
+                .iter()
+                .any(|parent| self.city_objects.contains_key(parent))
+            {
+                continue;
+            }
+
+            let mut builder = EntityBuilder {
+                context,
+                document: self,
+                vertices: Default::default(),
+                store: Default::default(),
+            };
+            let mut visited = HashSet::from_iter([id.as_str()]);
+            let root = builder.object(id, cityobj, &mut visited)?;
+            f(builder.into_entity(root))?;
+        }
+        Ok(())
+    }
+}
+
+/// The coordinates referred to by the boundaries
+enum VertexSource<'a> {
+    /// The vertices of the document
+    Document(&'a [[f64; 3]], &'a Transform),
+    /// The vertices of a geometry template placed by a GeometryInstance
+    Template {
+        vertices: &'a [[f64; 3]],
+        matrix: &'a [f64; 16],
+        reference: [f64; 3],
+    },
+}
+
+impl VertexSource<'_> {
+    fn get(&self, idx: usize) -> pipeline::Result<[f64; 3]> {
+        match self {
+            VertexSource::Document(vertices, transform) => vertices.get(idx).map(|v| {
+                std::array::from_fn(|i| v[i] * transform.scale[i] + transform.translate[i])
+            }),
+            VertexSource::Template {
+                vertices,
+                matrix,
+                reference,
+            } => vertices.get(idx).map(|v| {
+                std::array::from_fn(|i| {
+                    let row = &matrix[i * 4..i * 4 + 4];
+                    row[0] * v[0] + row[1] * v[1] + row[2] * v[2] + row[3] + reference[i]
+                })
+            }),
+        }
+        .ok_or_else(|| parse_error(format!("vertex index {idx} out of range")))
+    }
+}
+
+/// Builds an entity (and its geometry store) from a city object and its descendants
+struct EntityBuilder<'a> {
+    context: &'a Context,
+    document: &'a Document,
+    vertices: IndexSet<[u64; 3], ahash::RandomState>,
+    store: GeometryStore,
+}
+
+impl<'a> EntityBuilder<'a> {
+    fn object(
+        &mut self,
+        id: &str,
+        cityobj: &'a CityObject,
+        visited: &mut HashSet<&'a str>,
+    ) -> pipeline::Result<Object> {
+        let typename = typename(&cityobj.ty);
+        let prefix = prefix_of(&typename);
+        let mut attributes: Map = cityobj
+            .attributes
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), attribute_value(key, value)?)))
+            .collect();
+
+        let (context, document) = (self.context, self.document);
+        let vertices = VertexSource::Document(&document.vertices, &context.transform);
+        let mut geometries = Vec::new();
+        let mut surfaces = Vec::new();
+        for geom in &cityobj.geometry {
+            self.add_geometry(geom, &vertices, prefix, &mut geometries, &mut surfaces)?;
+        }
+        if !surfaces.is_empty() {
+            attributes.insert(qualified(prefix, "boundedBy"), Value::Array(surfaces));
+        }
+
+        for child_id in &cityobj.children {
+            let Some((child_id, child)) = document.city_objects.get_key_value(child_id) else {
+                continue;
+            };
+            // (a cyclic reference)
+            if !visited.insert(child_id) {
+                continue;
+            }
+            let child = self.object(child_id, child, visited)?;
+            let values = attributes
+                .entry(child_property(&child.typename).to_string())
+                .or_insert_with(|| Value::Array(Vec::new()));
+            if let Value::Array(values) = values {
+                values.push(Value::Object(child));
+            }
+        }
+
+        Ok(Object {
+            typename: typename.into(),
+            stereotype: ObjectStereotype::Feature {
+                id: id.to_string(),
+                geometries,
+            },
+            attributes,
+        })
+    }
+
+    fn add_geometry(
+        &mut self,
+        geom: &'a Geometry,
+        vertices: &VertexSource<'_>,
+        prefix: Option<&str>,
+        refs: &mut GeometryRefs,
+        surfaces: &mut Vec<Value>,
+    ) -> pipeline::Result<()> {
+        let lod = parse_lod(geom.lod.as_ref());
+        let (ty, depth) = match geom.ty.as_str() {
+            "MultiPoint" => {
+                let pos = self.store.multipoint.len();
+                for idx in indices(&geom.boundaries)? {
+                    let idx = self.vertex_index(vertices.get(idx)?);
+                    self.store.multipoint.push(idx);
+                }
+                refs.push(GeometryRef {
+                    ty: GeometryType::Point,
+                    lod,
+                    pos: pos as u32,
+                    len: (self.store.multipoint.len() - pos) as u32,
+                });
+                return Ok(());
+            }
+            "MultiLineString" => {
+                let JsonValue::Array(lines) = &geom.boundaries else {
+                    return Err(parse_error("invalid boundaries of MultiLineString"));
+                };
+                let pos = self.store.multilinestring.len();
+                for line in lines {
+                    let line = indices(line)?
+                        .into_iter()
+                        .map(|idx| Ok(self.vertex_index(vertices.get(idx)?)))
+                        .collect::<pipeline::Result<Vec<_>>>()?;
+                    self.store.multilinestring.add_linestring(line);
+                }
+                refs.push(GeometryRef {
+                    ty: GeometryType::Curve,
+                    lod,
+                    pos: pos as u32,
+                    len: (self.store.multilinestring.len() - pos) as u32,
+                });
+                return Ok(());
+            }
+            "MultiSurface" | "CompositeSurface" => (GeometryType::Surface, 1),
+            "Solid" => (GeometryType::Solid, 2),
+            "MultiSolid" | "CompositeSolid" => (GeometryType::Solid, 3),
+            "GeometryInstance" => {
+                return self.add_geometry_instance(geom, vertices, prefix, refs, surfaces);
+            }
+            other => return Err(parse_error(format!("unknown geometry type {other}"))),
+        };
+
+        let semantics = geom.semantics.as_ref();
+        let mut flat_surfaces = Vec::new();
+        collect_surfaces(
+            &geom.boundaries,
+            semantics.map(|semantics| &semantics.values),
+            depth,
+            &mut flat_surfaces,
+        )?;
+
+        // The surfaces without the semantics are of the object itself, and the others are of the boundary surfaces
+        let mut groups: std::collections::BTreeMap<Option<usize>, Vec<&JsonValue>> =
+            Default::default();
+        for (rings, semantic) in flat_surfaces {
+            let semantic = semantic.filter(|&idx| {
+                semantics
+                    .and_then(|semantics| semantics.surfaces.get(idx))
+                    .is_some_and(|surface| surface.contains_key("type"))
+            });
+            groups.entry(semantic).or_default().push(rings);
+        }
+
+        for (semantic, group) in groups {
+            let pos = self.store.multipolygon.len();
+            for rings in group {
+                self.add_polygon(rings, vertices)?;
+            }
+            let len = self.store.multipolygon.len() - pos;
+            if len == 0 {
+                continue;
+            }
+
+            let Some(surface) = semantic.and_then(|idx| semantics?.surfaces.get(idx)) else {
+                refs.push(GeometryRef {
+                    ty,
+                    lod,
+                    pos: pos as u32,
+                    len: len as u32,
+                });
+                continue;
+            };
+            let ty = surface
+                .get("type")
+                .and_then(JsonValue::as_str)
+                .unwrap_or_default();
+            surfaces.push(Value::Object(Object {
+                typename: qualified(prefix, ty.trim_start_matches('+')).into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: String::new(),
+                    geometries: vec![GeometryRef {
+                        ty: GeometryType::Surface,
+                        lod,
+                        pos: pos as u32,
+                        len: len as u32,
+                    }],
+                },
+                attributes: surface_attributes(surface)
+                    .filter_map(|(key, value)| Some((key.clone(), attribute_value(key, value)?)))
+                    .collect(),
+            }));
+        }
+        Ok(())
+    }
+
+    fn add_geometry_instance(
+        &mut self,
+        geom: &'a Geometry,
+        vertices: &VertexSource<'_>,
+        prefix: Option<&str>,
+        refs: &mut GeometryRefs,
+        surfaces: &mut Vec<Value>,
+    ) -> pipeline::Result<()> {
+        let context = self.context;
+        let (Some(templates), Some(template), Some(matrix)) = (
+            context.templates.as_ref(),
+            geom.template,
+            geom.transformation_matrix.as_ref(),
+        ) else {
+            return Err(parse_error("GeometryInstance without the template"));
+        };
+        let Some(template) = templates.templates.get(template) else {
+            return Err(parse_error(format!(
+                "template index {template} out of range"
+            )));
+        };
+        if template.ty == "GeometryInstance" {
+            return Err(parse_error("nested GeometryInstance"));
+        }
+        let Some(&reference) = indices(&geom.boundaries)?.first() else {
+            return Err(parse_error("GeometryInstance without the reference point"));
+        };
+
+        let template_vertices = VertexSource::Template {
+            vertices: &templates.vertices_templates,
+            matrix,
+            reference: vertices.get(reference)?,
+        };
+        self.add_geometry(template, &template_vertices, prefix, refs, surfaces)
+    }
+
+    /// Adds a surface (the exterior ring and the interior rings)
+    fn add_polygon(
+        &mut self,
+        rings: &JsonValue,
+        vertices: &VertexSource<'_>,
+    ) -> pipeline::Result<()> {
+        let JsonValue::Array(rings) = rings else {
+            return Err(parse_error("invalid boundaries of a surface"));
+        };
+        for (i, ring) in rings.iter().enumerate() {
+            let mut ring = indices(ring)?
+                .into_iter()
+                .map(|idx| Ok(self.vertex_index(vertices.get(idx)?)))
+                .collect::<pipeline::Result<Vec<_>>>()?;
+            if ring.len() < 3 {
+                match i {
+                    0 => return Ok(()),
+                    _ => continue,
+                }
+            }
+            // (the rings are closed as parsed from CityGML)
+            ring.push(ring[0]);
+
+            match i {
+                0 => self.store.multipolygon.add_exterior(ring),
+                _ => self.store.multipolygon.add_interior(ring),
+            }
+            self.store.ring_ids.push(None);
+        }
+        Ok(())
+    }
+
+    fn vertex_index(&mut self, v: [f64; 3]) -> u32 {
+        let v = match self.context.swap_xy {
+            true => [v[1], v[0], v[2]],
+            false => v,
+        };
+        let (index, _) = self.vertices.insert_full(v.map(f64::to_bits));
+        index as u32
+    }
+
+    fn into_entity(mut self, root: Object) -> Entity {
+        self.store.epsg = self.context.epsg;
+        self.store.vertices = self
+            .vertices
+            .iter()
+            .map(|bits| bits.map(f64::from_bits))
+            .collect();
+        Entity {
+            root: Value::Object(root),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(self.store).into(),
+            appearance_store: Default::default(),
+        }
+    }
+}
+
+/// Flattens the (multi-)solids into the surfaces with the indices of their semantic surfaces
+fn collect_surfaces<'b>(
+    boundaries: &'b JsonValue,
+    values: Option<&JsonValue>,
+    depth: usize,
+    out: &mut Vec<(&'b JsonValue, Option<usize>)>,
+) -> pipeline::Result<()> {
+    if depth == 0 {
+        let semantic = values.and_then(JsonValue::as_u64).map(|idx| idx as usize);
+        out.push((boundaries, semantic));
+        return Ok(());
+    }
+    let JsonValue::Array(items) = boundaries else {
+        return Err(parse_error("invalid boundaries"));
+    };
+    for (i, item) in items.iter().enumerate() {
+        collect_surfaces(
+            item,
+            values.and_then(|values| values.get(i)),
+            depth - 1,
+            out,
+        )?;
+    }
+    Ok(())
+}
+
+fn indices(value: &JsonValue) -> pipeline::Result<Vec<usize>> {
+    let JsonValue::Array(values) = value else {
+        return Err(parse_error("invalid boundaries"));
+    };
+    values
+        .iter()
+        .map(|idx| {
+            idx.as_u64()
+                .map(|idx| idx as usize)
+                .ok_or_else(|| parse_error("invalid vertex index"))
+        })
+        .collect()
+}
+
+/// The integer part of the LoD (e.g. 2 of `"2.2"`)
+fn parse_lod(lod: Option<&JsonValue>) -> u8 {
+    match lod {
+        Some(JsonValue::Number(lod)) => lod.as_f64().unwrap_or_default() as u8,
+        Some(JsonValue::String(lod)) => lod
+            .split('.')
+            .next()
+            .and_then(|lod| lod.parse().ok())
+            .unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+/// Name of the CityGML type of a CityJSON city object type (the extensions, e.g. `+UndergroundBuilding`, have no prefix)
+fn typename(ty: &str) -> String {
+    if let Some(name) = ty.strip_prefix('+') {
+        return name.to_string();
+    }
+    let (prefix, name) = match ty {
+        "TINRelief" => ("dem", "ReliefFeature"),
+        "TunnelHollowSpace" => ("tun", "HollowSpace"),
+        "BridgeConstructiveElement" => ("brid", "BridgeConstructionElement"),
+        _ if ty.starts_with("Building") => ("bldg", ty),
+        _ if ty.starts_with("Bridge") => ("brid", ty),
+        _ if ty.starts_with("Tunnel") => ("tun", ty),
+        "CityFurniture" => ("frn", ty),
+        "CityObjectGroup" => ("grp", ty),
+        "GenericCityObject" => ("gen", ty),
+        "LandUse" => ("luse", ty),
+        "OtherConstruction" => ("con", ty),
+        "PlantCover" | "SolitaryVegetationObject" => ("veg", ty),
+        "WaterBody" => ("wtr", ty),
+        "Road" | "Railway" | "Waterway" | "TransportSquare" => ("tran", ty),
+        _ => return ty.to_string(),
+    };
+    format!("{prefix}:{name}")
+}
+
+fn prefix_of(typename: &str) -> Option<&str> {
+    typename.split_once(':').map(|(prefix, _)| prefix)
+}
+
+fn qualified(prefix: Option<&str>, name: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("{prefix}:{name}"),
+        None => name.to_string(),
+    }
+}
+
+/// The attribute holding the child city objects
+fn child_property(typename: &str) -> &'static str {
+    match typename {
+        "bldg:BuildingPart" => "bldg:consistsOfBuildingPart",
+        "brid:BridgePart" => "brid:consistsOfBridgePart",
+        "tun:TunnelPart" => "tun:consistsOfTunnelPart",
+        _ => "children",
+    }
+}
+
+fn surface_attributes(
+    surface: &serde_json::Map<String, JsonValue>,
+) -> impl Iterator<Item = (&String, &JsonValue)> {
+    surface
+        .iter()
+        .filter(|(key, _)| !SEMANTIC_SURFACE_KEYS.contains(&key.as_str()))
+}
+
+/// Name of the type of a JSON object in the attributes (its `type`, or the name of the attribute)
+fn data_typename<'b>(key: &'b str, map: &'b serde_json::Map<String, JsonValue>) -> &'b str {
+    map.get("type").and_then(JsonValue::as_str).unwrap_or(key)
+}
+
+/// Converts a CityJSON attribute (None for null)
+fn attribute_value(key: &str, value: &JsonValue) -> Option<Value> {
+    Some(match value {
+        JsonValue::Null => return None,
+        JsonValue::Bool(b) => Value::Boolean(*b),
+        JsonValue::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => Value::Integer(i),
+            (_, Some(u)) => Value::NonNegativeInteger(u),
+            _ => Value::Double(n.as_f64().unwrap_or_default()),
+        },
+        JsonValue::String(s) => Value::String(s.clone()),
+        JsonValue::Array(values) => Value::Array(
+            values
+                .iter()
+                .filter_map(|value| attribute_value(key, value))
+                .collect(),
+        ),
+        JsonValue::Object(map) => Value::Object(Object {
+            typename: data_typename(key, map).to_string().into(),
+            stereotype: ObjectStereotype::Data,
+            attributes: map
+                .iter()
+                .filter(|(key, _)| key.as_str() != "type")
+                .filter_map(|(key, value)| Some((key.clone(), attribute_value(key, value)?)))
+                .collect(),
+        }),
+    })
+}
+
+/// The type of a CityJSON attribute, defining the types of the JSON objects in the schema
+fn attribute_type(key: &str, value: &JsonValue, schema: &mut Schema) -> Option<Attribute> {
+    let type_ref = match value {
+        JsonValue::Null => return None,
+        JsonValue::Bool(_) => TypeRef::Boolean,
+        JsonValue::Number(n) if n.is_f64() => TypeRef::Double,
+        JsonValue::Number(_) => TypeRef::Integer,
+        JsonValue::String(_) => TypeRef::String,
+        JsonValue::Array(values) => {
+            let attr = values
+                .iter()
+                .find_map(|value| attribute_type(key, value, schema))?;
+            return Some(Attribute {
+                max_occurs: None,
+                ..attr
+            });
+        }
+        JsonValue::Object(map) => {
+            let typename = data_typename(key, map).to_string();
+            let attributes: Vec<_> = map
+                .iter()
+                .filter(|(key, _)| key.as_str() != "type")
+                .filter_map(|(key, value)| Some((key, attribute_type(key, value, schema)?)))
+                .collect();
+            if let TypeDef::Data(typedef) = schema
+                .types
+                .entry(typename.clone())
+                .or_insert_with(|| TypeDef::Data(DataTypeDef::default()))
+            {
+                for (key, attr) in attributes {
+                    merge_attribute(&mut typedef.attributes, key, attr);
+                }
+            }
+            TypeRef::Named(typename)
+        }
+    };
+    Some(Attribute::new(type_ref))
+}
+
+fn add_feature_type<'b>(
+    schema: &mut Schema,
+    typename: &str,
+    attributes: impl Iterator<Item = (&'b String, &'b JsonValue)>,
+) {
+    let attributes: Vec<_> = attributes
+        .filter_map(|(key, value)| Some((key, attribute_type(key, value, schema)?)))
+        .collect();
+    let TypeDef::Feature(typedef) = schema
+        .types
+        .entry(typename.to_string())
+        .or_insert_with(|| TypeDef::Feature(Default::default()))
+    else {
+        return;
+    };
+    for (key, attr) in attributes {
+        merge_attribute(&mut typedef.attributes, key, attr);
+    }
+}
+
+/// Adds the attribute, widening the type of an existing one to hold both
+fn merge_attribute(attributes: &mut schema::Map, key: &str, attr: Attribute) {
+    let Some(existing) = attributes.get_mut(key) else {
+        attributes.insert(key.to_string(), attr);
+        return;
+    };
+    if existing.type_ref == TypeRef::Integer && attr.type_ref == TypeRef::Double {
+        existing.type_ref = TypeRef::Double;
+    }
+    if attr.max_occurs.is_none() {
+        existing.max_occurs = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CITY_JSON: &str = r#"{
+  "type": "CityJSON",
+  "version": "2.0",
+  "transform": { "scale": [0.001, 0.001, 0.001], "translate": [-10000.0, -30000.0, 0.0] },
+  "metadata": { "referenceSystem": "https://www.opengis.net/def/crs/EPSG/0/6677" },
+  "CityObjects": {
+    "bldg_1": {
+      "type": "Building",
+      "attributes": { "measuredHeight": 6.5, "storeys": 2, "address": { "locality": "新宿区" }, "note": null },
+      "children": ["part_1"],
+      "geometry": [{
+        "type": "MultiSurface",
+        "lod": "0",
+        "boundaries": [[[0, 1, 2, 3]]]
+      }]
+    },
+    "part_1": {
+      "type": "BuildingPart",
+      "parents": ["bldg_1"],
+      "geometry": [{
+        "type": "Solid",
+        "lod": "2.2",
+        "boundaries": [[[[0, 1, 2, 3]], [[4, 5, 6, 7]], [[0, 1, 5, 4]]]],
+        "semantics": {
+          "surfaces": [{ "type": "GroundSurface" }, { "type": "RoofSurface", "slope": 10.5 }],
+          "values": [[0, 1, null]]
+        }
+      }]
+    },
+    "tree_1": {
+      "type": "SolitaryVegetationObject",
+      "geometry": [{
+        "type": "GeometryInstance",
+        "template": 0,
+        "boundaries": [8],
+        "transformationMatrix": [2, 0, 0, 0, 0, 2, 0, 0, 0, 0, 2, 0, 0, 0, 0, 1]
+      }]
+    }
+  },
+  "geometry-templates": {
+    "templates": [{ "type": "MultiPoint", "lod": "1", "boundaries": [0, 1] }],
+    "vertices-templates": [[0.0, 0.0, 0.0], [0.0, 0.0, 1.0]]
+  },
+  "vertices": [
+    [0, 0, 0], [1000, 0, 0], [1000, 1000, 0], [0, 1000, 0],
+    [0, 0, 3000], [1000, 0, 3000], [1000, 1000, 3000], [0, 1000, 3000],
+    [5000, 5000, 0]
+  ]
+}"#;
+
+    fn read(text: &str) -> Vec<Entity> {
+        let mut entities = Vec::new();
+        read_entities(text.as_bytes(), |entity| {
+            entities.push(entity);
+            Ok(())
+        })
+        .unwrap();
+        entities
+    }
+
+    #[test]
+    fn test_read_city_json() {
+        let entities = read(CITY_JSON);
+        assert_eq!(entities.len(), 2);
+
+        let Value::Object(building) = &entities[0].root else {
+            unreachable!();
+        };
+        assert_eq!(building.typename, "bldg:Building");
+        assert_eq!(building.stereotype.id(), Some("bldg_1"));
+        assert_eq!(building.attributes["measuredHeight"], Value::Double(6.5));
+        assert_eq!(building.attributes["storeys"], Value::Integer(2));
+        assert!(!building.attributes.contains_key("note"));
+        let Value::Object(address) = &building.attributes["address"] else {
+            panic!("address is not an object");
+        };
+        assert_eq!(address.typename, "address");
+
+        // the building part and its boundary surfaces
+        let Value::Array(parts) = &building.attributes["bldg:consistsOfBuildingPart"] else {
+            panic!("the building part is not nested");
+        };
+        let Value::Object(part) = &parts[0] else {
+            unreachable!();
+        };
+        let ObjectStereotype::Feature { geometries, .. } = &part.stereotype else {
+            unreachable!();
+        };
+        assert_eq!(
+            geometries,
+            &[GeometryRef {
+                ty: GeometryType::Solid,
+                lod: 2,
+                pos: 1,
+                len: 1,
+            }]
+        );
+        let Value::Array(surfaces) = &part.attributes["bldg:boundedBy"] else {
+            panic!("the semantic surfaces are not the boundary surfaces");
+        };
+        let Value::Object(roof) = &surfaces[1] else {
+            unreachable!();
+        };
+        assert_eq!(roof.typename, "bldg:RoofSurface");
+        assert_eq!(roof.attributes["slope"], Value::Double(10.5));
+
+        // the coordinates are transformed, in the axis order of the CRS, and the rings are closed
+        let store = entities[0].geometry_store.read().unwrap();
+        assert_eq!(store.epsg, EPSG_JGD2011_JPRECT_IX_JGD2011_HEIGHT);
+        assert_eq!(store.multipolygon.len(), 4);
+        let ring: Vec<_> = store
+            .multipolygon
+            .iter()
+            .next()
+            .unwrap()
+            .exterior()
+            .iter()
+            .collect();
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring[0], ring[4]);
+        assert_eq!(store.vertices[ring[1] as usize], [-30000.0, -9999.0, 0.0]);
+
+        // the geometry template is placed at the reference point
+        let Value::Object(tree) = &entities[1].root else {
+            unreachable!();
+        };
+        assert_eq!(tree.typename, "veg:SolitaryVegetationObject");
+        let store = entities[1].geometry_store.read().unwrap();
+        assert_eq!(
+            store.vertices,
+            [[-29995.0, -9995.0, 0.0], [-29995.0, -9995.0, 2.0]]
+        );
+    }
+
+    #[test]
+    fn test_read_city_json_seq() {
+        let lines = [
+            r#"{"type":"CityJSON","version":"2.0","transform":{"scale":[1.0,1.0,1.0],"translate":[0.0,0.0,0.0]},"metadata":{"referenceSystem":"https://www.opengis.net/def/crs/EPSG/0/6697"},"CityObjects":{},"vertices":[]}"#,
+            r#"{"type":"CityJSONFeature","id":"a","CityObjects":{"a":{"type":"Road","geometry":[{"type":"MultiLineString","lod":"0","boundaries":[[0,1]]}]}},"vertices":[[139,35,0],[140,36,0]]}"#,
+            "",
+            r#"{"type":"CityJSONFeature","id":"b","CityObjects":{"b":{"type":"+Dock"}},"vertices":[]}"#,
+        ];
+        let entities = read(&lines.join("\n"));
+        assert_eq!(entities.len(), 2);
+
+        let Value::Object(road) = &entities[0].root else {
+            unreachable!();
+        };
+        assert_eq!(road.typename, "tran:Road");
+        let store = entities[0].geometry_store.read().unwrap();
+        assert_eq!(store.epsg, EPSG_JGD2011_GEOGRAPHIC_3D);
+        // (latitude first)
+        assert_eq!(store.vertices, [[35.0, 139.0, 0.0], [36.0, 140.0, 0.0]]);
+        assert_eq!(store.multilinestring.len(), 1);
+
+        let Value::Object(dock) = &entities[1].root else {
+            unreachable!();
+        };
+        assert_eq!(dock.typename, "Dock");
+
+        // the lines are not CityJSONFeatures
+        let text = [lines[0], lines[0]].join("\n");
+        assert!(read_entities(text.as_bytes(), |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_collect_schema() {
+        let mut schema = Schema::default();
+        collect_schema(CITY_JSON.as_bytes(), &mut schema).unwrap();
+
+        let Some(TypeDef::Feature(building)) = schema.types.get("bldg:Building") else {
+            panic!("bldg:Building is not a feature type");
+        };
+        assert_eq!(
+            building.attributes["measuredHeight"].type_ref,
+            TypeRef::Double
+        );
+        assert_eq!(building.attributes["storeys"].type_ref, TypeRef::Integer);
+        assert_eq!(
+            building.attributes["address"].type_ref,
+            TypeRef::Named("address".into())
+        );
+        assert!(!building.attributes.contains_key("note"));
+        assert!(matches!(
+            schema.types.get("address"),
+            Some(TypeDef::Data(_))
+        ));
+
+        let Some(TypeDef::Feature(roof)) = schema.types.get("bldg:RoofSurface") else {
+            panic!("bldg:RoofSurface is not a feature type");
+        };
+        assert_eq!(roof.attributes["slope"].type_ref, TypeRef::Double);
+        assert!(schema.types.contains_key("veg:SolitaryVegetationObject"));
+    }
+
+    #[test]
+    fn test_input_crs() {
+        assert_eq!(
+            input_crs(Some("https://www.opengis.net/def/crs/EPSG/0/6677")).unwrap(),
+            (EPSG_JGD2011_JPRECT_IX_JGD2011_HEIGHT, true)
+        );
+        assert_eq!(
+            input_crs(Some("urn:ogc:def:crs:EPSG::6668")).unwrap(),
+            (EPSG_JGD2011_GEOGRAPHIC_3D, true)
+        );
+        assert_eq!(
+            input_crs(Some("https://www.opengis.net/def/crs/EPSG/0/4979")).unwrap(),
+            (EPSG_WGS84_GEOGRAPHIC_3D, false)
+        );
+        assert!(input_crs(Some("https://www.opengis.net/def/crs/EPSG/0/7415")).is_err());
+        assert!(input_crs(None).is_err());
+    }
+
+    #[test]
+    fn test_is_city_json() {
+        assert!(is_city_json(Path::new("data/bldg.city.json")));
+        assert!(is_city_json(Path::new("data/bldg.city.jsonl.gz")));
+        assert!(!is_city_json(Path::new("data/bldg.gml")));
+    }
+}
@@ -0,0 +1,441 @@
+//! Directory structure of the PLATEAU datasets
+//!
+//! A dataset (e.g. `13104_shinjuku-ku_city_2023_citygml_1_op`) has the CityGML files under `udx/<package>/` (some
+//! packages such as `fld` have subdirectories), the codelists under `codelists/` and the XML schemas under
+//! `schemas/`. The root directory of a dataset can be given as an input path instead of the globs of the files.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use nusamai_citygml::object::{ObjectStereotype, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    paths,
+    pipeline::{PipelineError, Result},
+};
+
+/// Attribute holding the name of the dataset when `dataset_attribute` is enabled
+pub const DATASET_ATTRIBUTE: &str = "dataset";
+
+/// The versions of the i-UR (`uro`) schema supported by the parser
+pub const SUPPORTED_IUR_VERSIONS: &[&str] = &["1.4", "1.5", "2.0", "3.0", "3.1"];
+
+/// Whether the directory is the root of a dataset (has the `udx` directory)
+pub fn is_dataset_root(path: &Path) -> bool {
+    paths::extended(&path.join("udx")).is_dir()
+}
+
+/// The root of the dataset containing the file (the parent of the `udx` directory)
+pub fn dataset_root_of(path: &Path) -> Option<&Path> {
+    path.ancestors()
+        .skip(1)
+        .find(|dir| dir.file_name().is_some_and(|name| name == "udx"))
+        .and_then(Path::parent)
+}
+
+/// The CityGML files of the dataset by package (e.g. `bldg` -> the files under `udx/bldg/`)
+pub fn discover_packages(root: &Path) -> io::Result<BTreeMap<String, Vec<PathBuf>>> {
+    let mut packages = BTreeMap::new();
+    for entry in std::fs::read_dir(paths::extended(&root.join("udx")))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let package = entry.file_name().to_string_lossy().into_owned();
+        let mut files = Vec::new();
+        collect_gml_files(&root.join("udx").join(&package), &mut files)?;
+        if !files.is_empty() {
+            files.sort();
+            packages.insert(package, files);
+        }
+    }
+    Ok(packages)
+}
+
+fn collect_gml_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(paths::extended(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            collect_gml_files(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gml"))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the dataset roots in the input paths with the CityGML files of the packages (all the packages if
+/// `packages` is empty). The other paths are kept as they are.
+pub fn expand_dataset_roots(
+    filenames: &[PathBuf],
+    packages: &[String],
+) -> io::Result<Vec<PathBuf>> {
+    let mut expanded = Vec::new();
+    for path in filenames {
+        if !is_dataset_root(path) {
+            expanded.push(path.clone());
+            continue;
+        }
+
+        let discovered = discover_packages(path)?;
+        for package in packages {
+            if !discovered.contains_key(package) {
+                log::warn!(
+                    "Package {} is not found in the dataset {:?} (available: {})",
+                    package,
+                    path,
+                    discovered.keys().cloned().collect::<Vec<_>>().join(", ")
+                );
+            }
+        }
+        let selected: Vec<_> = discovered
+            .into_iter()
+            .filter(|(package, _)| packages.is_empty() || packages.contains(package))
+            .collect();
+        log::info!(
+            "Dataset {:?}: {} files of {}",
+            path,
+            selected.iter().map(|(_, files)| files.len()).sum::<usize>(),
+            selected
+                .iter()
+                .map(|(package, _)| package.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        expanded.extend(selected.into_iter().flat_map(|(_, files)| files));
+    }
+    Ok(expanded)
+}
+
+/// The versions of the i-UR schemas in the dataset (`schemas/iur/uro/<version>/`)
+pub fn iur_versions(root: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(paths::extended(&root.join("schemas/iur/uro"))) else {
+        return Vec::new();
+    };
+    let mut versions: Vec<_> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|ty| ty.is_dir()))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    versions.sort();
+    versions
+}
+
+/// The name of the dataset of the file (the root directory, or the directory of the file if not in a dataset)
+pub fn dataset_name_of(path: &Path) -> Option<String> {
+    let path = paths::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    dataset_root_of(&path)
+        .or_else(|| path.parent())
+        .and_then(|dir| dir.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// How the gml:ids are made unique among the datasets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdNamespace {
+    /// The ids as they are
+    #[default]
+    None,
+    /// `{dataset}:{id}`
+    Prefix,
+    /// The hash of the dataset and the id (32 hex digits)
+    Hash,
+}
+
+impl IdNamespace {
+    /// Parses the option (`none`, `prefix`, `hash`)
+    pub fn negotiate(option: &str) -> Result<Self> {
+        match option {
+            "" | "none" => Ok(Self::None),
+            "prefix" => Ok(Self::Prefix),
+            "hash" => Ok(Self::Hash),
+            _ => Err(PipelineError::Other(format!(
+                "Unknown id_namespace: {option} (expected none, prefix or hash)"
+            ))),
+        }
+    }
+
+    fn apply(&self, dataset: &str, id: &str) -> String {
+        match self {
+            _ if id.is_empty() => String::new(),
+            Self::None => id.to_string(),
+            Self::Prefix => format!("{dataset}:{id}"),
+            Self::Hash => Sha256::digest(format!("{dataset}:{id}").as_bytes())[..16]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+        }
+    }
+}
+
+/// Marks the entities with the dataset they come from
+#[derive(Debug, Clone)]
+pub struct DatasetTag {
+    pub name: String,
+    pub id_namespace: IdNamespace,
+    /// Whether to attach the name of the dataset to the features
+    pub attribute: bool,
+}
+
+impl DatasetTag {
+    /// Namespaces the ids of the objects and the references to them (`href`, and the ids of the groups), and
+    /// attaches the dataset attribute to the feature.
+    pub fn apply(&self, root: &mut Value) {
+        if self.id_namespace != IdNamespace::None {
+            self.namespace_ids(root);
+        }
+        if let Value::Object(obj) = root {
+            if self.attribute && matches!(obj.stereotype, ObjectStereotype::Feature { .. }) {
+                obj.attributes
+                    .insert(DATASET_ATTRIBUTE.into(), Value::String(self.name.clone()));
+            }
+        }
+    }
+
+    fn namespace_ids(&self, value: &mut Value) {
+        match value {
+            Value::Object(obj) => {
+                if let ObjectStereotype::Feature { id, .. } | ObjectStereotype::Object { id } =
+                    &mut obj.stereotype
+                {
+                    *id = self.id_namespace.apply(&self.name, id);
+                }
+                for (key, value) in obj.attributes.iter_mut() {
+                    match (key.as_str(), value) {
+                        // "#bldg_xxx" or "other.gml#bldg_xxx"
+                        ("href", Value::String(href)) => {
+                            if let Some((base, id)) = href.rsplit_once('#') {
+                                *href =
+                                    format!("{base}#{}", self.id_namespace.apply(&self.name, id));
+                            }
+                        }
+                        // (the records and the attributes of the group memberships)
+                        ("groupId" | "memberId", Value::String(id)) => {
+                            *id = self.id_namespace.apply(&self.name, id);
+                        }
+                        ("groupIds", Value::Array(ids)) => {
+                            for id in ids {
+                                if let Value::String(id) = id {
+                                    *id = self.id_namespace.apply(&self.name, id);
+                                }
+                            }
+                        }
+                        (_, value) => self.namespace_ids(value),
+                    }
+                }
+            }
+            Value::Array(arr) => {
+                for value in arr {
+                    self.namespace_ids(value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parses the comma-separated package names (e.g. `bldg,tran`)
+pub fn parse_packages(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|package| package.trim().to_string())
+        .filter(|package| !package.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::object::{Map, Object};
+
+    use super::*;
+
+    fn dataset() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for path in [
+            "udx/bldg/53394525_bldg_6697_op.gml",
+            "udx/bldg/53394526_bldg_6697_op.gml",
+            "udx/tran/533945_tran_6697_op.gml",
+            "udx/fld/pref/arakawa_L1/533945_fld_6697_l1_op.gml",
+            "udx/fld/pref/arakawa_L1/readme.txt",
+            "codelists/Building_usage.xml",
+            "schemas/iur/uro/3.0/urbanObject.xsd",
+        ] {
+            std::fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            std::fs::write(root.join(path), "").unwrap();
+        }
+        std::fs::create_dir_all(root.join("udx/empty")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_discover_packages() {
+        let dir = dataset();
+        let root = dir.path();
+        assert!(is_dataset_root(root));
+        assert!(!is_dataset_root(&root.join("udx")));
+
+        let packages = discover_packages(root).unwrap();
+        assert_eq!(packages.keys().collect::<Vec<_>>(), ["bldg", "fld", "tran"]);
+        assert_eq!(packages["bldg"].len(), 2);
+        assert_eq!(
+            packages["fld"],
+            [root.join("udx/fld/pref/arakawa_L1/533945_fld_6697_l1_op.gml")]
+        );
+
+        assert_eq!(iur_versions(root), ["3.0"]);
+        assert_eq!(dataset_root_of(&packages["fld"][0]), Some(root));
+        assert_eq!(dataset_root_of(Path::new("/data/bldg/a.gml")), None);
+    }
+
+    fn building(id: &str, attributes: Vec<(&str, Value)>) -> Value {
+        Value::Object(Object {
+            typename: "bldg:Building".into(),
+            stereotype: ObjectStereotype::Feature {
+                id: id.to_string(),
+                geometries: Default::default(),
+            },
+            attributes: Map::from_iter(
+                attributes
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value)),
+            ),
+        })
+    }
+
+    #[test]
+    fn test_dataset_tag() {
+        let tag = DatasetTag {
+            name: "13104_shinjuku-ku_city_2023_citygml_1_op".into(),
+            id_namespace: IdNamespace::Prefix,
+            attribute: true,
+        };
+        let mut root = building(
+            "bldg_1",
+            vec![
+                (
+                    "bldg:consistsOfBuildingPart",
+                    building("part_1", Vec::new()),
+                ),
+                (
+                    "groupIds",
+                    Value::Array(vec![Value::String("grp_1".into())]),
+                ),
+                (
+                    "bldg:address",
+                    Value::Object(Object {
+                        typename: "core:Address".into(),
+                        stereotype: ObjectStereotype::Data,
+                        attributes: Map::from_iter([(
+                            "href".to_string(),
+                            Value::String("other.gml#addr_1".into()),
+                        )]),
+                    }),
+                ),
+            ],
+        );
+        tag.apply(&mut root);
+
+        let Value::Object(obj) = &root else {
+            unreachable!()
+        };
+        assert_eq!(
+            obj.stereotype.id(),
+            Some("13104_shinjuku-ku_city_2023_citygml_1_op:bldg_1")
+        );
+        assert_eq!(
+            obj.attributes[DATASET_ATTRIBUTE],
+            Value::String(tag.name.clone())
+        );
+        let Value::Object(part) = &obj.attributes["bldg:consistsOfBuildingPart"] else {
+            unreachable!()
+        };
+        assert_eq!(
+            part.stereotype.id(),
+            Some("13104_shinjuku-ku_city_2023_citygml_1_op:part_1")
+        );
+        // (the descendant features do not get the attribute)
+        assert!(!part.attributes.contains_key(DATASET_ATTRIBUTE));
+        assert_eq!(
+            obj.attributes["groupIds"],
+            Value::Array(vec![Value::String(
+                "13104_shinjuku-ku_city_2023_citygml_1_op:grp_1".into()
+            )])
+        );
+        let Value::Object(address) = &obj.attributes["bldg:address"] else {
+            unreachable!()
+        };
+        assert_eq!(
+            address.attributes["href"],
+            Value::String("other.gml#13104_shinjuku-ku_city_2023_citygml_1_op:addr_1".into())
+        );
+    }
+
+    #[test]
+    fn test_id_namespace() {
+        assert_eq!(IdNamespace::negotiate("").unwrap(), IdNamespace::None);
+        assert_eq!(IdNamespace::negotiate("hash").unwrap(), IdNamespace::Hash);
+        assert!(IdNamespace::negotiate("uuid").is_err());
+
+        let hash = IdNamespace::Hash.apply("13104_2023", "bldg_1");
+        assert_eq!(hash.len(), 32);
+        assert_eq!(hash, IdNamespace::Hash.apply("13104_2023", "bldg_1"));
+        assert_ne!(hash, IdNamespace::Hash.apply("13104_2022", "bldg_1"));
+        assert_eq!(IdNamespace::None.apply("13104_2023", "bldg_1"), "bldg_1");
+        // (the objects without ids)
+        assert_eq!(IdNamespace::Prefix.apply("13104_2023", ""), "");
+    }
+
+    #[test]
+    fn test_dataset_name_of() {
+        let dir = dataset();
+        let root = dir.path();
+        let name = root.file_name().unwrap().to_str().unwrap();
+        assert_eq!(
+            dataset_name_of(&root.join("udx/fld/pref/arakawa_L1/533945_fld_6697_l1_op.gml"))
+                .as_deref(),
+            Some(name)
+        );
+        assert_eq!(
+            dataset_name_of(Path::new(
+                "https://example.com/13104_shinjuku-ku_city_2023_citygml_1_op/udx/bldg/53394525_bldg_6697_op.gml"
+            ))
+            .as_deref(),
+            Some("13104_shinjuku-ku_city_2023_citygml_1_op")
+        );
+        assert_eq!(
+            dataset_name_of(Path::new("/data/shinjuku/a.gml")).as_deref(),
+            Some("shinjuku")
+        );
+    }
+
+    #[test]
+    fn test_expand_dataset_roots() {
+        let dir = dataset();
+        let root = dir.path();
+        let other = PathBuf::from("/data/a.gml");
+        let filenames = [root.to_path_buf(), other.clone()];
+
+        let all = expand_dataset_roots(&filenames, &[]).unwrap();
+        assert_eq!(all.len(), 5);
+        assert_eq!(all.last(), Some(&other));
+
+        let selected = expand_dataset_roots(&filenames, &parse_packages("bldg, tran,")).unwrap();
+        assert_eq!(
+            selected,
+            [
+                root.join("udx/bldg/53394525_bldg_6697_op.gml"),
+                root.join("udx/bldg/53394526_bldg_6697_op.gml"),
+                root.join("udx/tran/533945_tran_6697_op.gml"),
+                other,
+            ]
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Selection of the input files by the mesh codes and the file names
+//!
+//! The PLATEAU files are named after the mesh (e.g. `53394525_bldg_6697_op.gml` is of the 3次メッシュ `53394525`),
+//! so the files can be selected before parsing them.
+
+use std::path::Path;
+
+/// Filter of the input files (all the files pass if empty)
+#[derive(Debug, Default)]
+pub struct FileFilter {
+    /// The 1次/2次/3次 (or finer) mesh codes
+    mesh_codes: Vec<String>,
+    /// The patterns of the file names (e.g. `5339*_bldg_*.gml`)
+    patterns: Vec<glob::Pattern>,
+}
+
+impl FileFilter {
+    /// Makes a filter from the comma-separated mesh codes and file name patterns
+    pub fn new(mesh_codes: &str, patterns: &str) -> Result<Self, String> {
+        let mesh_codes = split_list(mesh_codes)
+            .map(|code| match code.bytes().all(|b| b.is_ascii_digit()) {
+                true => Ok(code.to_string()),
+                false => Err(format!("Invalid mesh code: {code}")),
+            })
+            .collect::<Result<_, _>>()?;
+        let patterns = split_list(patterns)
+            .map(|pattern| {
+                glob::Pattern::new(pattern)
+                    .map_err(|err| format!("Invalid file name pattern {pattern:?}: {err}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            mesh_codes,
+            patterns,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mesh_codes.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Whether the file is to be processed.
+    ///
+    /// A file matches a mesh code if either of the meshes contains the other (e.g. the file of the 2次メッシュ
+    /// `533945` matches `53394525`, and the files of `533945xx` match `533945`). The files without a mesh code in the
+    /// name do not match any mesh code.
+    pub fn matches(&self, path: &Path) -> bool {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            return self.is_empty();
+        };
+        if !self.mesh_codes.is_empty() {
+            let Some(file_code) = mesh_code_from_file_name(name) else {
+                return false;
+            };
+            if !self
+                .mesh_codes
+                .iter()
+                .any(|code| file_code.starts_with(code.as_str()) || code.starts_with(file_code))
+            {
+                return false;
+            }
+        }
+        self.patterns.is_empty() || self.patterns.iter().any(|pattern| pattern.matches(name))
+    }
+}
+
+fn split_list(s: &str) -> impl Iterator<Item = &str> {
+    s.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+/// The mesh code at the beginning of the file name (e.g. `53394525` of `53394525_bldg_6697_op.gml`)
+fn mesh_code_from_file_name(name: &str) -> Option<&str> {
+    let (code, _) = name.split_once('_')?;
+    // 1次 (4 digits), 2次 (6), 3次 (8), 1/2 (9), 1/4 (10) and 1/8 (11) メッシュ; not the city codes (5 digits)
+    (code.bytes().all(|b| b.is_ascii_digit()) && matches!(code.len(), 4 | 6 | 8..=11))
+        .then_some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mesh_codes() {
+        let filter = FileFilter::new("53394525, 533946", "").unwrap();
+        for (name, expected) in [
+            ("53394525_bldg_6697_op.gml", true),
+            ("53394526_bldg_6697_op.gml", false),
+            ("53394611_bldg_6697_op.gml", true),
+            ("533945_tran_6697_op.gml", true),
+            ("5339_dem_6697_op.gml", true),
+            ("13104_urf_6668_op.gml", false),
+            ("bldg.gml", false),
+        ] {
+            assert_eq!(
+                filter.matches(Path::new("udx/bldg").join(name).as_path()),
+                expected,
+                "{name}"
+            );
+        }
+        assert!(FileFilter::new("5339-45", "").is_err());
+    }
+
+    #[test]
+    fn test_patterns() {
+        let filter = FileFilter::new("", "5339*_bldg_*.gml,*_tran_*").unwrap();
+        assert!(filter.matches(Path::new("/data/udx/bldg/53394525_bldg_6697_op.gml")));
+        assert!(filter.matches(Path::new("/data/udx/tran/523945_tran_6697_op.gml")));
+        assert!(!filter.matches(Path::new("/data/udx/bldg/52394525_bldg_6697_op.gml")));
+        // (only the file name is matched)
+        assert!(!FileFilter::new("", "udx/*")
+            .unwrap()
+            .matches(Path::new("udx/a.gml")));
+        assert!(FileFilter::new("", "[").is_err());
+
+        let both = FileFilter::new("53394525", "*_bldg_*").unwrap();
+        assert!(both.matches(Path::new("53394525_bldg_6697_op.gml")));
+        assert!(!both.matches(Path::new("53394525_frn_6697_op.gml")));
+
+        let empty = FileFilter::new("", "").unwrap();
+        assert!(empty.is_empty());
+        assert!(empty.matches(Path::new("anything.gml")));
+    }
+}
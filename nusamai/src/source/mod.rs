@@ -1,6 +1,14 @@
 //! Input data sources (mainly CityGML)
 
 pub mod citygml;
+pub mod cityjson;
+pub mod dataset;
+pub mod filter;
+pub mod remote;
+pub mod sampling;
+pub mod serde;
+
+use nusamai_citygml::schema::Schema;
 
 use crate::{
     parameters::Parameters,
@@ -28,4 +36,7 @@ pub trait DataSource: Send {
 
     /// Set whether to parse appearances
     fn set_appearance_parsing(&mut self, _value: bool);
+
+    /// Add the attributes and types that this source produces on top of the CityGML models
+    fn transform_schema(&self, _schema: &mut Schema) {}
 }
@@ -0,0 +1,300 @@
+//! Downloading the input files given as URLs
+//!
+//! The input paths can be `http:` or `https:` URLs (e.g. of the mirrors of the G空間情報センター). Each file is
+//! downloaded once into the directory of the intermediate files and parsed from there, with its URL as the base
+//! URL. The textures and the codelists referenced by the relative paths are then downloaded from the same server.
+
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{
+    paths,
+    pipeline::{Feedback, PipelineError, Result},
+    transformer::transform::decoded_file_name,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Timeout of each read (not of the whole download, as the files can be large)
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_ATTEMPTS: u32 = 4;
+/// Delay before the first retry (doubled for each retry)
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Progress is reported at every 10 % of the file, or every this many bytes if the size is unknown
+const PROGRESS_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// URL of an input path given as an `http:` or `https:` URL
+pub fn remote_url(path: &Path) -> Option<Url> {
+    let path = path.to_str()?;
+    if !(path.starts_with("http://") || path.starts_with("https://")) {
+        return None;
+    }
+    Url::parse(path).ok()
+}
+
+/// Downloads the remote files into the cache directory
+#[derive(Clone)]
+pub struct Downloader {
+    agent: ureq::Agent,
+    cache_dir: PathBuf,
+    retry_delay: Duration,
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Self::with_cache_dir(crate::workdir::work_dir().join("downloads"))
+    }
+}
+
+/// Failure of a download attempt
+struct Failure {
+    error: PipelineError,
+    /// Whether the failure may be temporary (e.g. a network error or `503 Service Unavailable`)
+    retryable: bool,
+}
+
+impl Failure {
+    fn temporary(error: impl ToString) -> Self {
+        Self {
+            error: PipelineError::Other(error.to_string()),
+            retryable: true,
+        }
+    }
+
+    fn permanent(error: impl Into<PipelineError>) -> Self {
+        Self {
+            error: error.into(),
+            retryable: false,
+        }
+    }
+}
+
+impl Downloader {
+    pub fn with_cache_dir(cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout_read(READ_TIMEOUT)
+                .build(),
+            cache_dir: cache_dir.into(),
+            retry_delay: RETRY_DELAY,
+        }
+    }
+
+    /// Returns the local path of the file, downloading it unless it is already in the cache.
+    ///
+    /// The temporary failures are retried with increasing delays.
+    pub fn fetch(&self, url: &Url, feedback: &Feedback) -> Result<PathBuf> {
+        let path = self.cache_path(url);
+        if paths::extended(&path).is_file() {
+            feedback.info(format!("Using the downloaded file of {url}"));
+            return Ok(path);
+        }
+
+        let mut attempt = 1;
+        let mut delay = self.retry_delay;
+        loop {
+            feedback.info(format!("Downloading {url} ..."));
+            match self.download(url, &path, feedback) {
+                Ok(()) => return Ok(path),
+                Err(Failure {
+                    error,
+                    retryable: true,
+                }) if attempt < MAX_ATTEMPTS => {
+                    feedback.warn(format!(
+                        "Failed to download {url} (retrying in {} s): {error}",
+                        delay.as_secs()
+                    ));
+                    std::thread::sleep(delay);
+                    feedback.ensure_not_canceled()?;
+                    attempt += 1;
+                    delay *= 2;
+                }
+                Err(Failure {
+                    error: PipelineError::Canceled,
+                    ..
+                }) => return Err(PipelineError::Canceled),
+                Err(Failure { error, .. }) => {
+                    return Err(PipelineError::Other(format!(
+                        "Failed to download {url}: {error}"
+                    )))
+                }
+            }
+        }
+    }
+
+    fn download(
+        &self,
+        url: &Url,
+        path: &Path,
+        feedback: &Feedback,
+    ) -> std::result::Result<(), Failure> {
+        let response = self
+            .agent
+            .get(url.as_str())
+            .call()
+            .map_err(|err| match err {
+                ureq::Error::Status(code, _) => Failure {
+                    error: PipelineError::Other(format!("HTTP status {code}")),
+                    retryable: code == 429 || code >= 500,
+                },
+                ureq::Error::Transport(err) => Failure::temporary(err),
+            })?;
+        let total: Option<u64> = response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok());
+
+        // write to a temporary file first, so that an interrupted download is not taken as cached
+        let dir = paths::extended(path.parent().unwrap());
+        std::fs::create_dir_all(&dir).map_err(Failure::permanent)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(&dir).map_err(Failure::permanent)?;
+
+        let mut reader = response.into_reader();
+        let mut buf = vec![0; 256 * 1024];
+        let mut received: u64 = 0;
+        let mut next_report = report_interval(total);
+        loop {
+            feedback.ensure_not_canceled().map_err(Failure::permanent)?;
+            let len = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(Failure::temporary(err)),
+            };
+            tmp.write_all(&buf[..len]).map_err(Failure::permanent)?;
+            received += len as u64;
+
+            if received >= next_report {
+                feedback.info(progress_message(url, received, total));
+                let interval = report_interval(total);
+                next_report = (received / interval + 1) * interval;
+            }
+        }
+        if let Some(total) = total.filter(|&total| total != received) {
+            return Err(Failure::temporary(format!(
+                "the connection was closed at {received} of {total} bytes"
+            )));
+        }
+
+        tmp.persist(paths::extended(path))
+            .map_err(|err| Failure::permanent(err.error))?;
+        Ok(())
+    }
+
+    /// `{cache_dir}/{hash of the URL}/{decoded file name}`, keeping the original file name
+    fn cache_path(&self, url: &Url) -> PathBuf {
+        let hash = Sha256::digest(url.as_str().as_bytes());
+        let dir = hash[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        self.cache_dir.join(dir).join(decoded_file_name(url))
+    }
+}
+
+fn report_interval(total: Option<u64>) -> u64 {
+    match total {
+        Some(total) => (total / 10).max(1),
+        None => PROGRESS_INTERVAL,
+    }
+}
+
+fn progress_message(url: &Url, received: u64, total: Option<u64>) -> String {
+    match total {
+        Some(total) => format!(
+            "Downloading {url}: {} / {} ({} %)",
+            bytesize::to_string(received, true),
+            bytesize::to_string(total, true),
+            received * 100 / total.max(1)
+        ),
+        None => format!("Downloading {url}: {}", bytesize::to_string(received, true)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    /// Serves the responses in order, one for each connection
+    fn serve(responses: Vec<&'static str>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/udx/bldg/53394525_bldg_6697_op.gml",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                // skip the request headers
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    fn downloader(dir: &Path) -> Downloader {
+        Downloader {
+            retry_delay: Duration::from_millis(10),
+            ..Downloader::with_cache_dir(dir)
+        }
+    }
+
+    #[test]
+    fn test_remote_url() {
+        assert!(remote_url(Path::new("https://example.com/udx/bldg/a.gml")).is_some());
+        assert!(remote_url(Path::new("http://example.com/a.gml")).is_some());
+        assert!(remote_url(Path::new("/data/udx/bldg/a.gml")).is_none());
+        assert!(remote_url(Path::new("C:\\data\\a.gml")).is_none());
+    }
+
+    #[test]
+    fn test_fetch_with_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\n<gml>",
+        ]);
+        let downloader = downloader(dir.path());
+        let (watcher, feedback, _) = feedback::watcher();
+        let path = downloader.fetch(&url, &feedback).unwrap();
+        assert_eq!(path.file_name().unwrap(), "53394525_bldg_6697_op.gml");
+        assert_eq!(std::fs::read(&path).unwrap(), b"<gml>");
+
+        // the second fetch uses the cache (the server is gone)
+        assert_eq!(downloader.fetch(&url, &feedback).unwrap(), path);
+
+        drop(feedback);
+        let messages: Vec<_> = watcher.into_iter().map(|msg| msg.message).collect();
+        assert!(messages.iter().any(|msg| msg.contains("retrying")));
+        assert!(messages.iter().any(|msg| msg.contains("100 %")));
+    }
+
+    #[test]
+    fn test_fetch_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = serve(vec![
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ]);
+        let (_, feedback, _) = feedback::watcher();
+        // (not retried)
+        assert!(downloader(dir.path()).fetch(&url, &feedback).is_err());
+        assert!(!downloader(dir.path()).cache_path(&url).exists());
+    }
+}
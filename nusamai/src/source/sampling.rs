@@ -0,0 +1,252 @@
+//! Sampling of the features for quick previews
+//!
+//! Passes only a subset of the features of the wrapped source, to try the options of the sinks on a
+//! small output before converting the whole dataset. The subset is chosen by the hashes of the feature
+//! ids, so the same features are selected every time regardless of the order of parsing.
+
+use std::{cmp::Ordering, collections::BinaryHeap, sync::mpsc};
+
+use nusamai_citygml::{object::Value, schema::Schema};
+
+use crate::{
+    pipeline::{Feedback, Parcel, PipelineError, Receiver, Result, Sender},
+    source::DataSource,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sampling {
+    /// Ratio of the features to pass (0.0 - 1.0)
+    pub ratio: Option<f64>,
+    /// Maximum number of the features to pass
+    pub limit: Option<usize>,
+}
+
+impl Sampling {
+    pub fn is_enabled(&self) -> bool {
+        self.ratio.is_some() || self.limit.is_some()
+    }
+
+    fn accepts(&self, hash: u64) -> bool {
+        match self.ratio {
+            // (the upper 53 bits as a fraction in [0, 1))
+            Some(ratio) => ((hash >> 11) as f64 / (1u64 << 53) as f64) < ratio,
+            None => true,
+        }
+    }
+}
+
+/// Key of a feature for the sampling (the hash of the id, and the id to break the ties)
+type SampleKey = (u64, String);
+
+/// Feature held to be selected by the limit (ordered by the key only)
+struct Candidate {
+    key: SampleKey,
+    parcel: Parcel,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// Wraps a source to pass only the sampled features.
+///
+/// The entities without ids (e.g. the records of the group memberships) are always passed. With a limit,
+/// the features with the smallest hashes are kept until the source finishes, so all the input files are
+/// still parsed.
+pub struct SampledSource {
+    inner: Box<dyn DataSource>,
+    sampling: Sampling,
+}
+
+impl SampledSource {
+    pub fn new(inner: Box<dyn DataSource>, sampling: Sampling) -> Self {
+        Self { inner, sampling }
+    }
+}
+
+impl DataSource for SampledSource {
+    fn run(&mut self, downstream: Sender, feedback: &Feedback) -> Result<()> {
+        let (sender, receiver) = mpsc::sync_channel(1000);
+        let inner = &mut self.inner;
+        let sampling = &self.sampling;
+        std::thread::scope(|scope| {
+            let handle = scope.spawn(move || inner.run(sender, feedback));
+            let result = forward(sampling, receiver, &downstream, feedback);
+            // (the source stops when the receiver is dropped on an error)
+            let inner_result = handle.join().unwrap();
+            inner_result.and(result)
+        })
+    }
+
+    fn set_appearance_parsing(&mut self, value: bool) {
+        self.inner.set_appearance_parsing(value);
+    }
+
+    fn transform_schema(&self, schema: &mut Schema) {
+        self.inner.transform_schema(schema);
+    }
+}
+
+fn forward(
+    sampling: &Sampling,
+    receiver: Receiver,
+    downstream: &Sender,
+    feedback: &Feedback,
+) -> Result<()> {
+    let send = |parcel: Parcel| downstream.send(parcel).map_err(|_| PipelineError::Canceled);
+
+    let mut total = 0;
+    let mut passed = 0;
+    let mut held = BinaryHeap::new();
+    for parcel in receiver {
+        feedback.ensure_not_canceled()?;
+
+        let Some(key) = sample_key(&parcel) else {
+            send(parcel)?;
+            continue;
+        };
+        total += 1;
+        if !sampling.accepts(key.0) {
+            continue;
+        }
+        match sampling.limit {
+            Some(limit) => {
+                held.push(Candidate { key, parcel });
+                if held.len() > limit {
+                    held.pop();
+                }
+            }
+            None => {
+                passed += 1;
+                send(parcel)?;
+            }
+        }
+    }
+
+    for candidate in held.into_sorted_vec() {
+        passed += 1;
+        send(candidate.parcel)?;
+    }
+
+    feedback.info(format!("Sampled {passed} of {total} features"));
+    Ok(())
+}
+
+fn sample_key(parcel: &Parcel) -> Option<SampleKey> {
+    let Value::Object(obj) = &parcel.entity.root else {
+        return None;
+    };
+    let id = obj.stereotype.id()?;
+    Some((id_hash(id.as_bytes()), id.to_string()))
+}
+
+/// Hash of a feature id: FNV-1a with the finalizer of MurmurHash3 to spread the similar ids
+/// (stable across the platforms and the versions, unlike the hasher of std)
+fn id_hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::{
+        object::{Object, ObjectStereotype},
+        GeometryStore,
+    };
+    use nusamai_plateau::Entity;
+
+    use super::*;
+    use crate::pipeline::feedback::watcher;
+
+    /// Emits the features with the given ids
+    struct IdSource {
+        ids: Vec<String>,
+    }
+
+    impl DataSource for IdSource {
+        fn run(&mut self, sink: Sender, _feedback: &Feedback) -> Result<()> {
+            for id in &self.ids {
+                let entity = Entity {
+                    root: Value::Object(Object {
+                        typename: "bldg:Building".into(),
+                        attributes: Default::default(),
+                        stereotype: ObjectStereotype::Feature {
+                            id: id.clone(),
+                            geometries: Default::default(),
+                        },
+                    }),
+                    base_url: url::Url::parse("file:///dummy").unwrap(),
+                    geometry_store: RwLock::new(GeometryStore::default()).into(),
+                    appearance_store: Default::default(),
+                };
+                sink.send(Parcel { entity }).unwrap();
+            }
+            Ok(())
+        }
+
+        fn set_appearance_parsing(&mut self, _value: bool) {}
+    }
+
+    fn sampled_ids(ids: &[String], sampling: Sampling) -> Vec<String> {
+        let (_watcher, feedback, _canceller) = watcher();
+        let mut source = SampledSource::new(Box::new(IdSource { ids: ids.to_vec() }), sampling);
+        let (sender, receiver) = mpsc::sync_channel(ids.len());
+        source.run(sender, &feedback).unwrap();
+        let mut ids: Vec<String> = receiver
+            .into_iter()
+            .map(|parcel| sample_key(&parcel).unwrap().1)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_sampling() {
+        let ids: Vec<String> = (0..1000).map(|i| format!("bldg_{i}")).collect();
+        let mut reversed = ids.clone();
+        reversed.reverse();
+
+        // about the ratio, and the same features regardless of the order
+        let sampling = Sampling {
+            ratio: Some(0.1),
+            limit: None,
+        };
+        let sampled = sampled_ids(&ids, sampling);
+        assert!((50..150).contains(&sampled.len()));
+        assert_eq!(sampled, sampled_ids(&reversed, sampling));
+
+        // the limit selects a subset of the sampled features
+        let limited = Sampling {
+            ratio: Some(0.1),
+            limit: Some(10),
+        };
+        let selected = sampled_ids(&ids, limited);
+        assert_eq!(selected.len(), 10);
+        assert!(selected.iter().all(|id| sampled.contains(id)));
+        assert_eq!(selected, sampled_ids(&reversed, limited));
+    }
+}
@@ -0,0 +1,173 @@
+//! Serde Source Provider
+//!
+//! Reads the entities written by the serde sink ([`crate::sink::serde`]).
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use nusamai_plateau::Entity;
+use rayon::prelude::*;
+
+use crate::{
+    parameters::Parameters,
+    pipeline::{self, Feedback, Parcel, PipelineError, Sender},
+    sink::{output::open_decompressed, serde::MAGIC},
+    source::{DataSource, DataSourceProvider, SourceInfo},
+};
+
+/// Checks if the file is an entity cache written by the serde sink (possibly compressed with gzip or zstd)
+pub fn is_entity_cache(path: &Path) -> bool {
+    let mut magic = [0; MAGIC.len()];
+    match open_decompressed(path) {
+        Ok(mut reader) => reader.read_exact(&mut magic).is_ok() && &magic == MAGIC,
+        Err(_) => false,
+    }
+}
+
+pub struct SerdeSourceProvider {
+    pub filenames: Vec<PathBuf>,
+}
+
+impl DataSourceProvider for SerdeSourceProvider {
+    fn create(&self, _params: &Parameters) -> Box<dyn DataSource> {
+        Box::new(SerdeSource {
+            filenames: self.filenames.clone(),
+        })
+    }
+
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            name: "Serde (bincode)".to_string(),
+        }
+    }
+
+    fn sink_options(&self) -> Parameters {
+        Parameters::default()
+    }
+}
+
+pub struct SerdeSource {
+    filenames: Vec<PathBuf>,
+}
+
+impl DataSource for SerdeSource {
+    fn set_appearance_parsing(&mut self, _value: bool) {
+        // appearances are stored in the cache as they were parsed
+    }
+
+    fn run(&mut self, downstream: Sender, feedback: &Feedback) -> pipeline::Result<()> {
+        self.filenames.par_iter().try_for_each(|filename| {
+            feedback.ensure_not_canceled()?;
+
+            feedback.info(format!("Reading entities: {:?} ...", filename));
+            let reader = open_decompressed(filename)?;
+            read_entities(reader, |entity| {
+                feedback.ensure_not_canceled()?;
+                if downstream.send(Parcel { entity }).is_err() {
+                    return Err(PipelineError::Canceled);
+                }
+                Ok(())
+            })
+        })
+    }
+}
+
+/// Reads the entities from the entity cache, calling `f` for each entity.
+pub fn read_entities<R: Read>(
+    mut reader: R,
+    mut f: impl FnMut(Entity) -> pipeline::Result<()>,
+) -> pipeline::Result<()> {
+    let bincode_config = bincode::config::standard();
+
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(PipelineError::Other(
+            "Not an entity cache file (or unsupported version)".into(),
+        ));
+    }
+
+    let mut size_buf = [0; 4];
+    let mut compressed = Vec::new();
+    loop {
+        match reader.read_exact(&mut size_buf) {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        compressed.resize(u32::from_le_bytes(size_buf) as usize, 0);
+        reader.read_exact(&mut compressed)?;
+
+        let buf = zstd::decode_all(compressed.as_slice())
+            .map_err(|err| PipelineError::Other(format!("Failed to decompress: {:?}", err)))?;
+        let (entity, _): (Entity, _) = bincode::serde::decode_from_slice(&buf, bincode_config)
+            .map_err(|err| {
+                PipelineError::Other(format!("Failed to deserialize an entity: {:?}", err))
+            })?;
+        f(entity)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use nusamai_citygml::{Code, Value};
+
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let bincode_config = bincode::config::standard();
+
+        let mut data = Vec::new();
+        data.write_all(MAGIC).unwrap();
+        for i in 0..3 {
+            let entity = Entity {
+                root: Value::Integer(i),
+                base_url: url::Url::parse("file:///dummy").unwrap(),
+                geometry_store: Default::default(),
+                appearance_store: Default::default(),
+            };
+            let buf = bincode::serde::encode_to_vec(entity, bincode_config).unwrap();
+            let compressed = zstd::bulk::compress(&buf, 0).unwrap();
+            data.write_all(&(compressed.len() as u32).to_le_bytes())
+                .unwrap();
+            data.write_all(&compressed).unwrap();
+        }
+
+        let mut values = Vec::new();
+        read_entities(data.as_slice(), |entity| {
+            values.push(entity.root);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            values,
+            vec![Value::Integer(0), Value::Integer(1), Value::Integer(2)]
+        );
+
+        assert!(read_entities(&b"NOTCACHE"[..], |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn code_roundtrip() {
+        // (bincode is not self-describing, so the optional fields must always be written)
+        let value = Value::Array(vec![
+            Value::Code(Code::new("建物".into(), "1".into())),
+            Value::Code(
+                Code::new("業務施設".into(), "401".into())
+                    .with_code_space(Some("../../codelists/Building_usage.xml".into())),
+            ),
+            Value::Integer(7),
+        ]);
+        let buf = bincode::serde::encode_to_vec(&value, bincode::config::standard()).unwrap();
+        let (decoded, _): (Value, _) =
+            bincode::serde::decode_from_slice(&buf, bincode::config::standard()).unwrap();
+        assert_eq!(decoded, value);
+    }
+}
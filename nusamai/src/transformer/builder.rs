@@ -1,10 +1,11 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use nusamai_citygml::schema::Schema;
+use nusamai_plateau::Entity;
 use nusamai_projection::{crs, vshift::Jgd2011ToWgs84};
 
 use super::{transform::*, Transform};
-use crate::{sink::DataRequirements, transformer};
+use crate::{pipeline::Feedback, sink::DataRequirements, transformer};
 
 pub struct Request {
     pub output_epsg: crs::EpsgCode,
@@ -16,12 +17,33 @@ pub struct Request {
     pub key_value: KeyValueSpec,
     pub lod_filter: LodFilterSpec,
     pub geom_stats: GeometryStatsSpec,
+    pub vegetation: Option<VegetationShape>,
+    pub underground: Option<UndergroundMode>,
+    pub surface_class: Option<SurfaceClassMode>,
+    pub solar_attributes: bool,
+    pub building_adjacency: bool,
+    pub prefix: PrefixPolicy,
+    pub name_columns: bool,
+    pub code_values: CodeValueMode,
+    pub large_attributes: LargeAttributeSpec,
+    /// Directory of the side files of the large attribute values (see `large_attribute_dir()`)
+    pub large_attribute_dir: Option<PathBuf>,
+    pub axis_order: AxisOrder,
+    pub passthrough: bool,
 }
 
 impl Request {
     pub fn set_mapping_rules(&mut self, rules: Option<transformer::MappingRules>) {
         self.mapping_rules = rules;
     }
+
+    pub fn set_large_attribute_dir(&mut self, dir: Option<PathBuf>) {
+        self.large_attribute_dir = dir;
+    }
+
+    pub fn set_axis_order(&mut self, order: AxisOrder) {
+        self.axis_order = order;
+    }
 }
 
 impl From<DataRequirements> for Request {
@@ -36,6 +58,18 @@ impl From<DataRequirements> for Request {
             key_value: req.key_value,
             lod_filter: req.lod_filter,
             geom_stats: req.geom_stats,
+            vegetation: req.vegetation,
+            underground: req.underground,
+            surface_class: req.surface_class,
+            solar_attributes: req.solar_attributes,
+            building_adjacency: req.building_adjacency,
+            prefix: req.prefix,
+            name_columns: req.name_columns,
+            code_values: req.code_values,
+            large_attributes: req.large_attributes,
+            large_attribute_dir: None,
+            axis_order: Default::default(),
+            passthrough: req.passthrough,
         }
     }
 }
@@ -88,6 +122,8 @@ pub enum KeyValueSpec {
 pub enum GeometryStatsSpec {
     None,
     MinMaxHeights,
+    /// MinMaxHeights, and `height`, `min_height` and `ground_elevation` for 2.5D extrusion
+    ExtrusionHeights,
 }
 
 pub trait TransformBuilder: Send + Sync {
@@ -96,18 +132,125 @@ pub trait TransformBuilder: Send + Sync {
     fn transform_schema(&self, schema: &mut Schema) {
         self.build().transform_schema(schema);
     }
+
+    /// Builds the transforms split at the point where the entities are held until all of them arrive
+    /// (for the analyses over all the entities, e.g. the adjacency of the buildings).
+    ///
+    /// Returns `None` if there is no such analysis, and then `build()` is used instead.
+    fn build_stages(&self) -> Option<(Box<dyn Transform>, Box<dyn Transform>)> {
+        None
+    }
+
+    /// Holds the entity (output of the first stage) for the analyses, or returns it back
+    fn collect(&self, entity: Entity) -> Option<Entity> {
+        Some(entity)
+    }
+
+    /// Returns the held entities after the analyses, to be passed to the second stage
+    fn release(&self, _feedback: &Feedback) -> Vec<Entity> {
+        Vec::new()
+    }
+
+    /// Called once after all the entities are transformed (e.g. to report the aggregated warnings)
+    fn finish(&self, _feedback: &Feedback) {}
 }
 
 pub struct NusamaiTransformBuilder {
     request: transformer::Request,
     jgd2wgs: Arc<Jgd2011ToWgs84>,
+    // shared by the renamers, so that the collisions found in the schema apply to all the entities
+    rename_state: Arc<RenameState>,
+    // shared by the appearance transforms, so that each texture image is located (or downloaded) once
+    texture_sources: Arc<TextureSources>,
+    // holds the buildings until all of them are collected
+    adjacency: Option<BuildingAdjacency>,
+    // counts of the large attribute values, reported at the end
+    large_attribute_stats: Arc<LargeAttributeStats>,
+    // count of the features whose axes were swapped, reported at the end
+    axis_order_stats: Arc<AxisOrderStats>,
 }
 
 impl TransformBuilder for NusamaiTransformBuilder {
     fn build(&self) -> Box<dyn Transform> {
+        if self.request.passthrough {
+            return Box::new(IdentityTransform {});
+        }
+
+        let mut transforms = SerialTransform::default();
+        transforms.push(self.build_first_stage());
+        transforms.push(self.build_second_stage());
+        Box::new(transforms)
+    }
+
+    fn transform_schema(&self, schema: &mut Schema) {
+        if self.request.passthrough {
+            return;
+        }
+        self.build_first_stage().transform_schema(schema);
+        if let Some(adjacency) = &self.adjacency {
+            adjacency.transform_schema(schema);
+        }
+        self.build_second_stage().transform_schema(schema);
+    }
+
+    fn build_stages(&self) -> Option<(Box<dyn Transform>, Box<dyn Transform>)> {
+        self.adjacency
+            .as_ref()
+            .map(|_| (self.build_first_stage(), self.build_second_stage()))
+    }
+
+    fn collect(&self, entity: Entity) -> Option<Entity> {
+        match &self.adjacency {
+            Some(adjacency) => adjacency.collect(entity),
+            None => Some(entity),
+        }
+    }
+
+    fn release(&self, feedback: &Feedback) -> Vec<Entity> {
+        match &self.adjacency {
+            Some(adjacency) => adjacency.release(feedback),
+            None => Vec::new(),
+        }
+    }
+
+    fn finish(&self, feedback: &Feedback) {
+        self.texture_sources.report(feedback);
+        self.large_attribute_stats.report(feedback);
+        self.axis_order_stats.report(feedback);
+    }
+}
+
+impl NusamaiTransformBuilder {
+    pub fn new(req: transformer::Request) -> Self {
+        let adjacency = (req.building_adjacency && !req.passthrough).then(Default::default);
+        Self {
+            request: req,
+            jgd2wgs: Jgd2011ToWgs84::default().into(),
+            rename_state: Default::default(),
+            texture_sources: Default::default(),
+            adjacency,
+            large_attribute_stats: Default::default(),
+            axis_order_stats: Default::default(),
+        }
+    }
+
+    /// The transforms up to the analyses of the geometries (with the original field names)
+    fn build_first_stage(&self) -> Box<dyn Transform> {
         let mut transforms = SerialTransform::default();
         // TODO: build transformation based on config file
 
+        // Fix the axis order of the input coordinates before they are transformed
+        transforms.push(Box::new(AxisOrderTransform::new(
+            self.request.axis_order,
+            self.axis_order_stats.clone(),
+        )));
+
+        // Adjust the heights of the underground structures on the source heights (before the projection converts them,
+        // e.g. adding the geoid heights for WGS 84), and before taking the stats
+        if let Some(mode) = self.request.underground {
+            transforms.push(Box::new(UndergroundTransform::new(mode)));
+        }
+
         // Transform the coordinate system
         transforms.push(Box::new(ProjectionTransform::new(
             self.jgd2wgs.clone(),
@@ -119,15 +262,62 @@ impl TransformBuilder for NusamaiTransformBuilder {
             GeometryStatsSpec::MinMaxHeights => {
                 transforms.push(Box::<GeometryStatsTransform>::default());
             }
+            GeometryStatsSpec::ExtrusionHeights => {
+                transforms.push(Box::new(GeometryStatsTransform::with_extrusion_heights()));
+            }
         }
 
-        // Apply appearance to geometries
+        // Resolve the appearance of the polygons, and apply the default appearance to the rest
         if self.request.apply_appearance {
+            transforms.push(Box::new(ResolveAppearanceTransform::new(
+                self.texture_sources.clone(),
+            )));
             transforms.push(Box::new(ApplyAppearanceTransform::new()));
         }
 
+        transforms.push(Box::new(FilterLodTransform::new(
+            self.request.lod_filter.mask,
+            self.request.lod_filter.mode,
+        )));
+
+        // Classify the polygons of the selected LOD into roofs, walls and grounds
+        if let Some(mode) = self.request.surface_class {
+            transforms.push(Box::new(ClassifySurfacesTransform::new(mode)));
+        }
+
+        // Analyze the roofs (including the classified ones) before they are merged or flattened
+        if self.request.solar_attributes {
+            transforms.push(Box::<SolarAttributesTransform>::default());
+        }
+
+        // Simplify the trees after the LOD is selected
+        if let Some(shape) = self.request.vegetation {
+            transforms.push(Box::new(SimplifyVegetationTransform::new(shape)));
+        }
+
+        Box::new(transforms)
+    }
+
+    /// The transforms renaming and restructuring the features for the sink
+    fn build_second_stage(&self) -> Box<dyn Transform> {
+        let mut transforms = SerialTransform::default();
+
+        // Add the `_code` columns before they are renamed
+        if self.request.code_values != CodeValueMode::Label {
+            transforms.push(Box::new(CodeValueTransform::new(self.request.code_values)));
+        }
+
+        // Expand the names before they are renamed
+        if self.request.name_columns {
+            transforms.push(Box::<NameColumnsTransform>::default());
+        }
+
+        // Rename the fields after the transforms above which refer to the prefixed names (e.g. `veg:height`)
         transforms.push({
-            let mut renamer = Box::<EditFieldNamesTransform>::default();
+            let mut renamer = Box::new(EditFieldNamesTransform::with_state(
+                self.rename_state.clone(),
+            ));
+            renamer.set_prefix_policy(self.request.prefix);
             if self.request.shorten_names_for_shapefile {
                 renamer.load_default_map_for_shape();
             }
@@ -139,11 +329,6 @@ impl TransformBuilder for NusamaiTransformBuilder {
             renamer
         });
 
-        transforms.push(Box::new(FilterLodTransform::new(
-            self.request.lod_filter.mask,
-            self.request.lod_filter.mode,
-        )));
-
         match self.request.tree_flattening {
             TreeFlatteningSpec::None => {}
             TreeFlatteningSpec::Flatten {
@@ -182,15 +367,15 @@ impl TransformBuilder for NusamaiTransformBuilder {
             }
         }
 
-        Box::new(transforms)
-    }
-}
-
-impl NusamaiTransformBuilder {
-    pub fn new(req: transformer::Request) -> Self {
-        Self {
-            request: req,
-            jgd2wgs: Jgd2011ToWgs84::default().into(),
+        // Check the sizes of the values after the objects and arrays are jsonified
+        if self.request.large_attributes.mode != LargeAttributeMode::Keep {
+            transforms.push(Box::new(LargeAttributeTransform::new(
+                self.request.large_attributes,
+                self.request.large_attribute_dir.clone(),
+                self.large_attribute_stats.clone(),
+            )));
         }
+
+        Box::new(transforms)
     }
 }
@@ -18,7 +18,9 @@ pub use selection::*;
 pub use setting::*;
 use thiserror::Error;
 pub use transform::{
-    DataFlatteningOption, FeatureFlatteningOption, LodFilterMode, LodMask, ObjectFlatteningOption,
+    large_attribute_dir, AxisOrder, CodeValueMode, DataFlatteningOption, FeatureFlatteningOption,
+    LargeAttributeMode, LargeAttributeSpec, LodFilterMode, LodMask, ObjectFlatteningOption,
+    PrefixPolicy, SurfaceClassMode, UndergroundMode, VegetationShape,
 };
 
 use crate::pipeline::{Feedback, Parcel, Receiver, Result, Sender};
@@ -2,7 +2,7 @@ use hashbrown::HashMap;
 use serde::{Deserialize, Serialize};
 
 /// Rules specified by the user in a JSON file
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MappingRules {
     pub rename: RenameRules,
 }
@@ -14,11 +14,68 @@ impl<T: TransformBuilder> MultiThreadTransformer<T> {
     pub fn new(builder: T) -> Self {
         Self { builder }
     }
+
+    /// Runs the first stage for all the entities while the builder holds some of them,
+    /// then runs the second stage for the held entities after the analyses.
+    fn run_stages(
+        &self,
+        upstream: Receiver,
+        downstream: Sender,
+        feedback: &Feedback,
+    ) -> Result<()> {
+        upstream.into_iter().par_bridge().try_for_each_init(
+            || {
+                let (first, second) = self.builder.build_stages().unwrap();
+                (first, second, Vec::default(), Vec::default())
+            },
+            |(first, second, held, buf), parcel| {
+                feedback.ensure_not_canceled()?;
+
+                first.transform(feedback, parcel.entity, held);
+                for entity in held.drain(..) {
+                    if let Some(entity) = self.builder.collect(entity) {
+                        second.transform(feedback, entity, buf);
+                    }
+                }
+
+                for entity in buf.drain(..) {
+                    if downstream.send(Parcel { entity }).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            },
+        )?;
+
+        feedback.ensure_not_canceled()?;
+        let released = self.builder.release(feedback);
+
+        released.into_par_iter().try_for_each_init(
+            || (self.builder.build_stages().unwrap().1, Vec::default()),
+            |(second, buf), entity| {
+                feedback.ensure_not_canceled()?;
+
+                second.transform(feedback, entity, buf);
+                for entity in buf.drain(..) {
+                    if downstream.send(Parcel { entity }).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
 }
 
 impl<T: TransformBuilder> Transformer for MultiThreadTransformer<T> {
     fn run(&self, upstream: Receiver, downstream: Sender, feedback: &Feedback) -> Result<()> {
-        upstream.into_iter().par_bridge().try_for_each_init(
+        if self.builder.build_stages().is_some() {
+            let result = self.run_stages(upstream, downstream, feedback);
+            self.builder.finish(feedback);
+            return result;
+        }
+
+        let result = upstream.into_iter().par_bridge().try_for_each_init(
             || (self.builder.build(), Vec::default()),
             |(transform, buf), parcel| {
                 feedback.ensure_not_canceled()?;
@@ -33,6 +90,8 @@ impl<T: TransformBuilder> Transformer for MultiThreadTransformer<T> {
                 }
                 Ok(())
             },
-        )
+        );
+        self.builder.finish(feedback);
+        result
     }
 }
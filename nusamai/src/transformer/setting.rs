@@ -14,6 +14,153 @@ pub fn use_lod_config(default_value: &str, exclude: Option<&[&str]>) -> Transfor
     }
 }
 
+/// How to output the solitary vegetation objects. `shapes` are the available simplified shapes ("point", "billboard").
+pub fn vegetation_config(shapes: &[&str]) -> TransformerConfig {
+    let mut options = vec![("メッシュ", "mesh")];
+    options.extend(
+        [("点", "point"), ("ビルボード", "billboard")]
+            .into_iter()
+            .filter(|(_, value)| shapes.contains(value)),
+    );
+    TransformerConfig {
+        key: "vegetation".to_string(),
+        label: "単独木の出力形状".to_string(),
+        parameter: transformer::ParameterType::Selection(Selection::new(options, "mesh")),
+    }
+}
+
+/// How to handle the negative heights of the underground structures (tunnels, underground malls, pipes, etc.)
+pub fn underground_config() -> TransformerConfig {
+    TransformerConfig {
+        key: "underground".to_string(),
+        label: "地下構造物の高さ".to_string(),
+        parameter: transformer::ParameterType::Selection(Selection::new(
+            vec![
+                ("そのまま", "keep"),
+                ("0m未満を0mにする", "clamp"),
+                ("最下点が0mになるよう移動", "offset"),
+            ],
+            "keep",
+        )),
+    }
+}
+
+/// Classification of the polygons of the features without semantic surfaces (e.g. LOD1 buildings)
+pub fn surface_class_config() -> TransformerConfig {
+    TransformerConfig {
+        key: "surface_class".to_string(),
+        label: "屋根・壁・底面の分類（LOD1など）".to_string(),
+        parameter: transformer::ParameterType::Selection(Selection::new(
+            vec![
+                ("分類しない", "none"),
+                ("屋根・壁・底面に分類", "tag"),
+                ("屋根のみ出力", "roof_only"),
+            ],
+            "none",
+        )),
+    }
+}
+
+/// Whether to add the area, slope and azimuth of the roofs (for the screening of the rooftop solar potential)
+pub fn solar_attributes_config() -> TransformerConfig {
+    TransformerConfig {
+        key: "solar_attributes".to_string(),
+        label: "屋根の面積・傾斜・方位を属性に追加（太陽光発電ポテンシャル）".to_string(),
+        parameter: transformer::ParameterType::Boolean(false),
+    }
+}
+
+/// Whether to add the ids of the adjacent buildings and the length of the shared walls
+pub fn building_adjacency_config() -> TransformerConfig {
+    TransformerConfig {
+        key: "building_adjacency".to_string(),
+        label: "隣接する建築物のIDと共有壁の長さを属性に追加".to_string(),
+        parameter: transformer::ParameterType::Boolean(false),
+    }
+}
+
+/// Whether to output the sub-elements of bridges and tunnels as separate features
+/// (for the sinks that merge the child features into the root)
+pub fn split_bridge_and_tunnel_elements_config() -> TransformerConfig {
+    TransformerConfig {
+        key: "split_bridge_and_tunnel_elements".to_string(),
+        label: "橋梁・トンネルの部材を個別の地物として出力".to_string(),
+        parameter: transformer::ParameterType::Boolean(false),
+    }
+}
+
+/// How to handle the namespace prefixes of the field names (e.g. `bldg:measuredHeight`).
+/// `exclude` are the unavailable options ("keep", "underscore").
+pub fn prefix_config(exclude: &[&str]) -> TransformerConfig {
+    let options = [
+        ("接頭辞を除去（measuredHeight）", "strip"),
+        ("そのまま（bldg:measuredHeight）", "keep"),
+        ("「:」を「_」に置換（bldg_measuredHeight）", "underscore"),
+    ]
+    .into_iter()
+    .filter(|(_, value)| !exclude.contains(value))
+    .collect();
+    TransformerConfig {
+        key: "prefix".to_string(),
+        label: "属性名の名前空間接頭辞".to_string(),
+        parameter: transformer::ParameterType::Selection(Selection::new(options, "strip")),
+    }
+}
+
+/// Whether to output the multiple `gml:name` entries as the `name`, `name_2`, ... columns (instead of an array)
+pub fn name_columns_config() -> TransformerConfig {
+    TransformerConfig {
+        key: "name_columns".to_string(),
+        label: "複数の名称（gml:name）を列に展開（name, name_2, ...）".to_string(),
+        parameter: transformer::ParameterType::Boolean(false),
+    }
+}
+
+/// How to output the values of the codes (e.g. `bldg:usage`): the labels of the codelists, the raw codes, or both
+pub fn code_values_config() -> TransformerConfig {
+    TransformerConfig {
+        key: "code_values".to_string(),
+        label: "コード値の出力".to_string(),
+        parameter: transformer::ParameterType::Selection(Selection::new(
+            vec![
+                ("ラベル（業務施設）", "label"),
+                ("コード（401）", "code"),
+                ("ラベルとコード（_code列を追加）", "both"),
+            ],
+            "label",
+        )),
+    }
+}
+
+/// How to handle the attribute values larger than the limit (`large_attribute_limit`)
+pub fn large_attributes_config() -> TransformerConfig {
+    TransformerConfig {
+        key: "large_attributes".to_string(),
+        label: "サイズの大きい属性値の扱い".to_string(),
+        parameter: transformer::ParameterType::Selection(Selection::new(
+            vec![
+                ("そのまま出力", "keep"),
+                ("上限で切り詰める", "truncate"),
+                ("別ファイルに書き出す", "externalize"),
+                ("エラーにする", "fail"),
+            ],
+            "keep",
+        )),
+    }
+}
+
+/// Maximum size of an attribute value in bytes. `default_value` is one of the options, chosen for the format.
+pub fn large_attribute_limit_config(default_value: &str) -> TransformerConfig {
+    TransformerConfig {
+        key: "large_attribute_limit".to_string(),
+        label: "属性値のサイズの上限".to_string(),
+        parameter: transformer::ParameterType::Selection(Selection::new(
+            vec![("4KB", "4096"), ("64KB", "65536"), ("1MB", "1048576")],
+            default_value,
+        )),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ParameterType {
     String(String),
@@ -75,8 +222,31 @@ impl TransformerSettings {
                 ParameterType::String(_value) => {
                     // TODO: Processing for String types.
                 }
-                ParameterType::Boolean(_value) => {
-                    // TODO: Processing for Boolean types.
+                ParameterType::Boolean(value) => {
+                    if config.key == "solar_attributes" {
+                        data_requirements.solar_attributes = *value;
+                    }
+                    if config.key == "building_adjacency" {
+                        data_requirements.building_adjacency = *value;
+                    }
+                    if config.key == "name_columns" {
+                        data_requirements.name_columns = *value;
+                    }
+                    if config.key == "split_bridge_and_tunnel_elements"
+                        && *value
+                        && matches!(
+                            data_requirements.tree_flattening,
+                            transformer::TreeFlatteningSpec::None
+                        )
+                    {
+                        data_requirements.tree_flattening =
+                            transformer::TreeFlatteningSpec::Flatten {
+                                feature:
+                                    transformer::FeatureFlatteningOption::BridgeAndTunnelElements,
+                                data: transformer::DataFlatteningOption::None,
+                                object: transformer::ObjectFlatteningOption::None,
+                            };
+                    }
                 }
                 ParameterType::Integer(_value) => {
                     // TODO: Processing for Integer types.
@@ -113,6 +283,55 @@ impl TransformerSettings {
                             _ => {}
                         }
                     }
+                    if config.key == "vegetation" {
+                        data_requirements.vegetation = match value.selected_value.as_str() {
+                            "point" => Some(transformer::VegetationShape::Point),
+                            "billboard" => Some(transformer::VegetationShape::Billboard),
+                            _ => None,
+                        };
+                    }
+                    if config.key == "underground" {
+                        data_requirements.underground = match value.selected_value.as_str() {
+                            "clamp" => Some(transformer::UndergroundMode::Clamp),
+                            "offset" => Some(transformer::UndergroundMode::Offset),
+                            _ => Some(transformer::UndergroundMode::Keep),
+                        };
+                    }
+                    if config.key == "surface_class" {
+                        data_requirements.surface_class = match value.selected_value.as_str() {
+                            "tag" => Some(transformer::SurfaceClassMode::Tag),
+                            "roof_only" => Some(transformer::SurfaceClassMode::RoofOnly),
+                            _ => None,
+                        };
+                    }
+                    if config.key == "prefix" {
+                        data_requirements.prefix = match value.selected_value.as_str() {
+                            "keep" => transformer::PrefixPolicy::Keep,
+                            "underscore" => transformer::PrefixPolicy::Underscore,
+                            _ => transformer::PrefixPolicy::Strip,
+                        };
+                    }
+                    if config.key == "code_values" {
+                        data_requirements.code_values = match value.selected_value.as_str() {
+                            "code" => transformer::CodeValueMode::Code,
+                            "both" => transformer::CodeValueMode::Both,
+                            _ => transformer::CodeValueMode::Label,
+                        };
+                    }
+                    if config.key == "large_attributes" {
+                        data_requirements.large_attributes.mode =
+                            match value.selected_value.as_str() {
+                                "truncate" => transformer::LargeAttributeMode::Truncate,
+                                "externalize" => transformer::LargeAttributeMode::Externalize,
+                                "fail" => transformer::LargeAttributeMode::Fail,
+                                _ => transformer::LargeAttributeMode::Keep,
+                            };
+                    }
+                    if config.key == "large_attribute_limit" {
+                        if let Ok(limit) = value.selected_value.parse() {
+                            data_requirements.large_attributes.limit = limit;
+                        }
+                    }
                 }
             }
         }
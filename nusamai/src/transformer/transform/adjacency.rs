@@ -0,0 +1,383 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use nusamai_citygml::{
+    geometry::{GeometryStore, GeometryType},
+    object::{ObjectStereotype, Value},
+    schema::{Attribute, Schema, TypeDef, TypeRef},
+};
+use nusamai_plateau::Entity;
+
+use super::surface_class::horizontal_scale;
+use crate::pipeline::Feedback;
+
+const BUILDING: &str = "bldg:Building";
+
+/// Maximum horizontal distance (m) between the edges regarded as a shared wall
+const TOLERANCE: f64 = 0.1;
+/// Minimum length (m) of the shared walls to regard the buildings as adjacent (e.g. excludes the touching corners)
+const MIN_SHARED_LENGTH: f64 = 0.5;
+
+/// Detects the buildings touching each other (e.g. row houses) from their footprints.
+///
+/// Unlike the other transforms, this needs all the buildings at once: the runner hands them over with
+/// [`BuildingAdjacency::collect`] and receives them back from [`BuildingAdjacency::release`] after all the
+/// entities are transformed. The held buildings are kept in memory until then.
+///
+/// The footprint is the horizontal projection of the edges of the polygons of the selected LOD, and the
+/// edges of two buildings lying on the same line within 10 cm are regarded as a shared wall.
+///
+/// Attributes of the buildings:
+/// - `neighbor_ids`: `gml:id` of the adjacent buildings
+/// - `shared_wall_length`: total length of the walls shared with the adjacent buildings (m)
+///
+/// The horizontal coordinates must be in meters or degrees (geographic CRS).
+#[derive(Default)]
+pub struct BuildingAdjacency {
+    buildings: Mutex<Vec<(Footprint, Entity)>>,
+}
+
+impl BuildingAdjacency {
+    /// Holds the entity if it is a building, or returns it back
+    pub fn collect(&self, entity: Entity) -> Option<Entity> {
+        let Value::Object(obj) = &entity.root else {
+            return Some(entity);
+        };
+        if obj.typename != BUILDING {
+            return Some(entity);
+        }
+
+        let footprint = {
+            let geom_store = entity.geometry_store.read().unwrap();
+            Footprint::new(&geom_store, &entity.root)
+        };
+        self.buildings.lock().unwrap().push((footprint, entity));
+        None
+    }
+
+    /// Returns the held buildings with the adjacency attributes
+    pub fn release(&self, feedback: &Feedback) -> Vec<Entity> {
+        let buildings = std::mem::take(&mut *self.buildings.lock().unwrap());
+        let footprints: Vec<&Footprint> = buildings.iter().map(|(fp, _)| fp).collect();
+        let adjacency = find_adjacency(&footprints, feedback);
+
+        buildings
+            .into_iter()
+            .zip(adjacency)
+            .map(|((_, mut entity), neighbors)| {
+                let Value::Object(obj) = &mut entity.root else {
+                    unreachable!()
+                };
+                let mut ids: Vec<&str> = neighbors
+                    .iter()
+                    .map(|(idx, _)| footprints[*idx].id.as_str())
+                    .collect();
+                ids.sort_unstable();
+                let ids = ids
+                    .into_iter()
+                    .map(|id| Value::String(id.to_string()))
+                    .collect();
+                let length: f64 = neighbors.iter().map(|(_, length)| length).sum();
+                obj.attributes
+                    .insert("neighbor_ids".to_string(), Value::Array(ids));
+                obj.attributes
+                    .insert("shared_wall_length".to_string(), Value::Double(length));
+                entity
+            })
+            .collect()
+    }
+
+    pub fn transform_schema(&self, schema: &mut Schema) {
+        let Some(TypeDef::Feature(feature)) = schema.types.get_mut(BUILDING) else {
+            return;
+        };
+        feature.attributes.insert(
+            "neighbor_ids".to_string(),
+            Attribute {
+                max_occurs: None,
+                ..Attribute::new(TypeRef::String)
+            },
+        );
+        feature.attributes.insert(
+            "shared_wall_length".to_string(),
+            Attribute::new(TypeRef::Double),
+        );
+    }
+}
+
+/// Horizontal edges of a building
+struct Footprint {
+    id: String,
+    segments: Vec<[[f64; 2]; 2]>,
+    /// [min_x, min_y, max_x, max_y]
+    bbox: [f64; 4],
+    /// Meters per unit of the coordinates
+    scale: [f64; 2],
+}
+
+impl Footprint {
+    fn new(geom_store: &GeometryStore, root: &Value) -> Self {
+        let scale = horizontal_scale(geom_store);
+        let mut footprint = Footprint {
+            id: match root {
+                Value::Object(obj) => obj.id().unwrap_or_default().to_string(),
+                _ => String::new(),
+            },
+            segments: Vec::new(),
+            bbox: [f64::MAX, f64::MAX, f64::MIN, f64::MIN],
+            scale,
+        };
+        let mut seen = HashSet::new();
+        footprint.add_edges(geom_store, root, &mut seen);
+        footprint
+    }
+
+    /// Adds the edges of the polygons of the object and its descendants (e.g. building parts, boundary surfaces)
+    fn add_edges(
+        &mut self,
+        geom_store: &GeometryStore,
+        value: &Value,
+        seen: &mut HashSet<[(i64, i64); 2]>,
+    ) {
+        match value {
+            Value::Object(obj) => {
+                if let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype {
+                    for geom in geometries {
+                        if !matches!(
+                            geom.ty,
+                            GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+                        ) {
+                            continue;
+                        }
+                        for poly in geom_store
+                            .multipolygon
+                            .iter_range(geom.pos as usize..(geom.pos + geom.len) as usize)
+                        {
+                            for ring in poly.rings() {
+                                let ring: Vec<u32> = ring.iter().collect();
+                                for (i, &idx) in ring.iter().enumerate() {
+                                    let next = ring[(i + 1) % ring.len()];
+                                    self.add_segment(
+                                        geom_store.vertices[idx as usize],
+                                        geom_store.vertices[next as usize],
+                                        seen,
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+                for (_, value) in obj.attributes.iter() {
+                    self.add_edges(geom_store, value, seen);
+                }
+            }
+            Value::Array(arr) => {
+                for value in arr {
+                    self.add_edges(geom_store, value, seen);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add_segment(&mut self, a: [f64; 3], b: [f64; 3], seen: &mut HashSet<[(i64, i64); 2]>) {
+        // the same edges in the plan (e.g. the bottom and the top of a wall) are added once
+        let [sx, sy] = self.scale;
+        let key = |v: [f64; 3]| {
+            (
+                (v[0] * sx / TOLERANCE).round() as i64,
+                (v[1] * sy / TOLERANCE).round() as i64,
+            )
+        };
+        let (ka, kb) = (key(a), key(b));
+        if ka == kb || !seen.insert(if ka < kb { [ka, kb] } else { [kb, ka] }) {
+            return;
+        }
+
+        for v in [a, b] {
+            self.bbox[0] = self.bbox[0].min(v[0]);
+            self.bbox[1] = self.bbox[1].min(v[1]);
+            self.bbox[2] = self.bbox[2].max(v[0]);
+            self.bbox[3] = self.bbox[3].max(v[1]);
+        }
+        self.segments.push([[a[0], a[1]], [b[0], b[1]]]);
+    }
+
+    /// Length (m) of the edges of this footprint lying along the edges of the other
+    fn shared_length(&self, other: &Footprint) -> f64 {
+        let [sx, sy] = self.scale;
+        let mut total = 0.0;
+        let mut intervals = Vec::new();
+        for [a0, a1] in &self.segments {
+            // in meters, relative to the start of the edge
+            let local = |p: &[f64; 2]| [(p[0] - a0[0]) * sx, (p[1] - a0[1]) * sy];
+            let [dx, dy] = local(a1);
+            let len = dx.hypot(dy);
+            let (ux, uy) = (dx / len, dy / len);
+
+            intervals.clear();
+            for [b0, b1] in &other.segments {
+                let (q0, q1) = (local(b0), local(b1));
+                let off_line = |q: [f64; 2]| (ux * q[1] - uy * q[0]).abs() > TOLERANCE;
+                if off_line(q0) || off_line(q1) {
+                    continue;
+                }
+                let (t0, t1) = (ux * q0[0] + uy * q0[1], ux * q1[0] + uy * q1[1]);
+                let (start, end) = (t0.min(t1).max(0.0), t0.max(t1).min(len));
+                if end > start {
+                    intervals.push((start, end));
+                }
+            }
+
+            // the union of the overlapping parts
+            intervals.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let mut covered_to = 0.0_f64;
+            for &(start, end) in &intervals {
+                if end > covered_to {
+                    total += end - start.max(covered_to);
+                    covered_to = end;
+                }
+            }
+        }
+        total
+    }
+}
+
+/// Finds the adjacent buildings and the shared lengths for each building
+fn find_adjacency(footprints: &[&Footprint], feedback: &Feedback) -> Vec<Vec<(usize, f64)>> {
+    let mut adjacency = vec![Vec::new(); footprints.len()];
+
+    // sweep along the x-axis to find the overlapping bounding boxes
+    let mut order: Vec<usize> = (0..footprints.len())
+        .filter(|&i| !footprints[i].segments.is_empty())
+        .collect();
+    order.sort_by(|&a, &b| footprints[a].bbox[0].total_cmp(&footprints[b].bbox[0]));
+
+    for (n, &i) in order.iter().enumerate() {
+        if feedback.is_canceled() {
+            break;
+        }
+        let a = footprints[i];
+        let [mx, my] = a.scale.map(|s| TOLERANCE / s);
+        for &j in &order[n + 1..] {
+            let b = footprints[j];
+            if b.bbox[0] > a.bbox[2] + mx {
+                break;
+            }
+            if b.bbox[1] > a.bbox[3] + my || b.bbox[3] < a.bbox[1] - my {
+                continue;
+            }
+
+            let length = a.shared_length(b);
+            if length >= MIN_SHARED_LENGTH {
+                adjacency[i].push((j, length));
+                adjacency[j].push((i, length));
+            }
+        }
+    }
+    adjacency
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::{
+        geometry::GeometryRef,
+        object::{Map, Object},
+    };
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    /// A building with the LOD0 footprint of the rectangle (in meters)
+    fn building(id: &str, [x0, y0, x1, y1]: [f64; 4]) -> Entity {
+        let mut geoms = GeometryStore {
+            epsg: 6677,
+            vertices: vec![[x0, y0, 0.], [x1, y0, 0.], [x1, y1, 0.], [x0, y1, 0.]],
+            ..Default::default()
+        };
+        geoms.multipolygon.add_exterior([0, 1, 2, 3]);
+
+        Entity {
+            root: Value::Object(Object {
+                typename: BUILDING.into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: id.into(),
+                    geometries: vec![GeometryRef {
+                        ty: GeometryType::Surface,
+                        lod: 0,
+                        pos: 0,
+                        len: 1,
+                    }],
+                },
+                attributes: Map::default(),
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(geoms).into(),
+            appearance_store: Default::default(),
+        }
+    }
+
+    #[test]
+    fn row_houses() {
+        let (_, feedback, _) = feedback::watcher();
+        let adjacency = BuildingAdjacency::default();
+
+        // three row houses sharing walls of 8m, and a detached house
+        assert!(adjacency.collect(building("a", [0., 0., 6., 8.])).is_none());
+        assert!(adjacency
+            .collect(building("b", [6.05, 0., 12., 8.]))
+            .is_none());
+        assert!(adjacency
+            .collect(building("c", [12., 2., 18., 10.]))
+            .is_none());
+        assert!(adjacency
+            .collect(building("d", [30., 0., 36., 8.]))
+            .is_none());
+
+        let mut buildings = adjacency.release(&feedback);
+        buildings.sort_by_key(|entity| match &entity.root {
+            Value::Object(obj) => obj.id().unwrap().to_string(),
+            _ => unreachable!(),
+        });
+        let attributes = |idx: usize| {
+            let Value::Object(obj) = &buildings[idx].root else {
+                unreachable!()
+            };
+            let Value::Array(ids) = &obj.attributes["neighbor_ids"] else {
+                unreachable!()
+            };
+            let ids: Vec<String> = ids
+                .iter()
+                .map(|v| match v {
+                    Value::String(s) => s.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            let Value::Double(length) = obj.attributes["shared_wall_length"] else {
+                unreachable!()
+            };
+            (ids, length)
+        };
+
+        let (ids, length) = attributes(0);
+        assert_eq!(ids, ["b"]);
+        assert!((length - 8.0).abs() < 1e-9);
+        let (ids, length) = attributes(1);
+        assert_eq!(ids, ["a", "c"]);
+        assert!((length - 14.0).abs() < 1e-9);
+        let (ids, length) = attributes(2);
+        assert_eq!(ids, ["b"]);
+        assert!((length - 6.0).abs() < 1e-9);
+        let (ids, length) = attributes(3);
+        assert!(ids.is_empty());
+        assert_eq!(length, 0.0);
+
+        // the other entities are not held
+        let mut entity = building("e", [0., 0., 1., 1.]);
+        if let Value::Object(obj) = &mut entity.root {
+            obj.typename = "tran:Road".into();
+        }
+        assert!(adjacency.collect(entity).is_some());
+    }
+}
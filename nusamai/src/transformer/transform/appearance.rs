@@ -1,116 +1,112 @@
 //! Apply appearance to geometries
 
+use std::sync::Arc;
+
+use ahash::HashMap;
 use feedback::Feedback;
 use flatgeom::MultiPolygon;
-use nusamai_citygml::schema::Schema;
-use nusamai_plateau::Entity;
+use nusamai_citygml::{
+    geometry::{GeometryStore, GeometryType},
+    object::{ObjectStereotype, Value},
+    schema::Schema,
+    Color,
+};
+use nusamai_plateau::{
+    appearance::{AppearanceStore, Material, Texture, Theme},
+    Entity,
+};
+use url::Url;
 
+use super::TextureSources;
 use crate::{pipeline::feedback, transformer::Transform};
 
-#[derive(Default)]
-pub struct ApplyAppearanceTransform {}
+/// Maximum number of the image URLs kept by a [`ResolveAppearanceTransform`]
+const MAX_CACHED_URLS: usize = 4096;
 
-impl Transform for ApplyAppearanceTransform {
+/// Resolves the materials and the textures of the polygons (of the primary theme) into the geometry store.
+///
+/// This is separated from [`ApplyAppearanceTransform`] as its own stage, because the features of a file
+/// usually refer to the same few images (thousands of polygons to a `ParameterizedTexture`). The local URLs
+/// of the images are cached by each transform, so they are looked up in the shared [`TextureSources`] only
+/// once for the features of a file.
+pub struct ResolveAppearanceTransform {
+    texture_sources: Arc<TextureSources>,
+    // the local URL of each image (None if not available) resolved by this transform
+    cache: HashMap<Url, Option<Url>>,
+}
+
+impl Transform for ResolveAppearanceTransform {
     fn transform(&mut self, feedback: &Feedback, entity: Entity, out: &mut Vec<Entity>) {
         {
-            let app = entity.appearance_store.read().unwrap();
-            let theme = {
-                app.themes
-                    .get("rgbTexture")
-                    .or_else(|| app.themes.get("FMETheme"))
-            };
+            let mut app = entity.appearance_store.write().unwrap();
+            let available = self.localize(feedback, &mut app.textures);
+            let theme = primary_theme(&app).map(|name| &app.themes[name]);
 
             let mut geoms = entity.geometry_store.write().unwrap();
+            let mut resolved = resolve_theme(feedback, theme, &geoms);
+            self.texture_sources
+                .drop_unavailable(&available, &mut resolved.textures);
+            geoms.polygon_materials = resolved.materials;
+            geoms.polygon_textures = resolved.textures;
+            geoms.polygon_uvs = resolved.uvs;
+        }
+        out.push(entity);
+    }
 
-            if let Some(theme) = theme {
-                // find and apply materials
-                {
-                    let mut poly_materials = vec![None; geoms.multipolygon.len()];
-
-                    for surface in &geoms.surface_spans {
-                        if let Some(&mat) = theme.surface_id_to_material.get(&surface.id) {
-                            for idx in surface.start..surface.end {
-                                poly_materials[idx as usize] = Some(mat);
-                            }
-                        }
-                    }
+    fn transform_schema(&self, _schema: &mut Schema) {
+        // do nothing
+    }
+}
 
-                    // apply materials to polygons
-                    geoms.polygon_materials = poly_materials;
-                }
+impl ResolveAppearanceTransform {
+    /// Creates a transform sharing the resolved texture images with the other threads
+    pub fn new(texture_sources: Arc<TextureSources>) -> Self {
+        Self {
+            texture_sources,
+            cache: Default::default(),
+        }
+    }
 
-                // find and apply textures
-                {
-                    let mut ring_id_iter = geoms.ring_ids.iter();
-                    let mut poly_textures = Vec::with_capacity(geoms.multipolygon.len());
-                    let mut poly_uvs = MultiPolygon::new();
-
-                    for poly in &geoms.multipolygon {
-                        for (i, ring) in poly.rings().enumerate() {
-                            let tex = ring_id_iter
-                                .next()
-                                .unwrap()
-                                .and_then(|ring_id| theme.ring_id_to_texture.get(&ring_id));
-
-                            let mut add_dummy_texture = || {
-                                let uv = [[0.0, 0.0]].into_iter().cycle().take(ring.len() + 1);
-                                if i == 0 {
-                                    poly_textures.push(None);
-                                    poly_uvs.add_exterior(uv);
-                                } else {
-                                    poly_uvs.add_interior(uv);
-                                }
-                            };
-
-                            match tex {
-                                Some((idx, uv)) if ring.len() == uv.len() => {
-                                    // texture found
-                                    if i == 0 {
-                                        poly_textures.push(Some(*idx));
-                                        poly_uvs.add_exterior(uv.iter_closed());
-                                    } else {
-                                        poly_uvs.add_interior(uv.iter_closed());
-                                    }
-                                }
-                                Some((_, uv)) if uv.len() != ring.len() => {
-                                    // invalid texture found
-                                    feedback.warn(format!(
-                                        "Length of UVs does not match length of ring: {:?} {:?}",
-                                        ring, uv
-                                    ));
-                                    add_dummy_texture();
-                                }
-                                _ => {
-                                    // no texture found
-                                    add_dummy_texture();
-                                }
-                            };
+    /// Replaces the image URLs of the textures with the local ones, and returns whether each texture is available
+    fn localize(&mut self, feedback: &Feedback, textures: &mut [Texture]) -> Vec<bool> {
+        textures
+            .iter_mut()
+            .map(|texture| {
+                let local = match self.cache.get(&texture.image_url) {
+                    Some(local) => local.clone(),
+                    None => {
+                        // (the images of the previous files are rarely referred to again)
+                        if self.cache.len() >= MAX_CACHED_URLS {
+                            self.cache.clear();
                         }
+                        let local = self.texture_sources.resolve(feedback, &texture.image_url);
+                        self.cache.insert(texture.image_url.clone(), local.clone());
+                        local
                     }
-
-                    // apply textures to polygons
-                    debug_assert_eq!(poly_textures.len(), geoms.multipolygon.len());
-                    debug_assert_eq!(poly_uvs.len(), geoms.multipolygon.len());
-                    geoms.polygon_textures = poly_textures;
-                    geoms.polygon_uvs = poly_uvs;
-                }
-            } else {
-                // set 'null' appearance if no theme found
-                geoms.polygon_materials = vec![None; geoms.multipolygon.len()];
-                geoms.polygon_textures = vec![None; geoms.multipolygon.len()];
-                let mut poly_uvs = MultiPolygon::new();
-                for poly in &geoms.multipolygon {
-                    for (i, ring) in poly.rings().enumerate() {
-                        let uv = [[0.0, 0.0]].into_iter().cycle().take(ring.len() + 1);
-                        if i == 0 {
-                            poly_uvs.add_exterior(uv);
-                        } else {
-                            poly_uvs.add_interior(uv);
-                        }
+                };
+                match local {
+                    Some(url) => {
+                        texture.image_url = url;
+                        true
                     }
+                    None => false,
                 }
-                geoms.polygon_uvs = poly_uvs;
-            }
+            })
+            .collect()
+    }
+}
+
+/// Applies the default appearance to the polygons without any (after [`ResolveAppearanceTransform`])
+#[derive(Default)]
+pub struct ApplyAppearanceTransform {}
+
+impl Transform for ApplyAppearanceTransform {
+    fn transform(&mut self, _feedback: &Feedback, entity: Entity, out: &mut Vec<Entity>) {
+        {
+            // water and glass surfaces without any appearance are rendered as translucent
+            let mut app = entity.appearance_store.write().unwrap();
+            let mut geoms = entity.geometry_store.write().unwrap();
+            assign_translucent_materials(&entity.root, &mut app, &mut geoms, &mut [None; 2]);
         }
 
         out.push(entity);
@@ -126,3 +122,305 @@ impl ApplyAppearanceTransform {
         Default::default()
     }
 }
+
+/// Themes used as the main appearance, in order of preference
+const PRIMARY_THEMES: [&str; 2] = ["rgbTexture", "FMETheme"];
+
+/// Name of the theme applied to the geometries by `ApplyAppearanceTransform`
+pub fn primary_theme(app: &AppearanceStore) -> Option<&'static str> {
+    PRIMARY_THEMES
+        .into_iter()
+        .find(|name| app.themes.contains_key(*name))
+}
+
+/// Materials, textures and texture UVs of the polygons in a theme
+pub struct ResolvedAppearance {
+    pub materials: Vec<Option<u32>>,
+    pub textures: Vec<Option<u32>>,
+    pub uvs: MultiPolygon<'static, [f64; 2]>,
+}
+
+/// Resolves the appearance of all polygons in the geometry store with a theme ('null' appearance if `theme` is None).
+///
+/// This is also used by the sinks that output the other themes as alternatives (e.g. glTF material variants).
+pub fn resolve_theme(
+    feedback: &Feedback,
+    theme: Option<&Theme>,
+    geoms: &GeometryStore,
+) -> ResolvedAppearance {
+    // find materials
+    let mut materials = vec![None; geoms.multipolygon.len()];
+    if let Some(theme) = theme {
+        for surface in &geoms.surface_spans {
+            if let Some(&mat) = theme.surface_id_to_material.get(&surface.id) {
+                for idx in surface.start..surface.end {
+                    materials[idx as usize] = Some(mat);
+                }
+            }
+        }
+    }
+
+    // find textures
+    let mut ring_id_iter = geoms.ring_ids.iter();
+    let mut textures = Vec::with_capacity(geoms.multipolygon.len());
+    let mut uvs = MultiPolygon::new();
+
+    for poly in &geoms.multipolygon {
+        for (i, ring) in poly.rings().enumerate() {
+            let ring_id = ring_id_iter.next().unwrap();
+            let tex = theme.and_then(|theme| {
+                ring_id.and_then(|ring_id| theme.ring_id_to_texture.get(&ring_id))
+            });
+
+            let mut add_dummy_texture = || {
+                let uv = [[0.0, 0.0]].into_iter().cycle().take(ring.len() + 1);
+                if i == 0 {
+                    textures.push(None);
+                    uvs.add_exterior(uv);
+                } else {
+                    uvs.add_interior(uv);
+                }
+            };
+
+            match tex {
+                Some((idx, uv)) if ring.len() == uv.len() => {
+                    // texture found
+                    if i == 0 {
+                        textures.push(Some(*idx));
+                        uvs.add_exterior(uv.iter_closed());
+                    } else {
+                        uvs.add_interior(uv.iter_closed());
+                    }
+                }
+                Some((_, uv)) if uv.len() != ring.len() => {
+                    // invalid texture found
+                    feedback.warn(format!(
+                        "Length of UVs does not match length of ring: {:?} {:?}",
+                        ring, uv
+                    ));
+                    add_dummy_texture();
+                }
+                _ => {
+                    // no texture found
+                    add_dummy_texture();
+                }
+            };
+        }
+    }
+
+    debug_assert_eq!(textures.len(), geoms.multipolygon.len());
+    debug_assert_eq!(uvs.len(), geoms.multipolygon.len());
+    ResolvedAppearance {
+        materials,
+        textures,
+        uvs,
+    }
+}
+
+/// Kinds of surfaces that are usually translucent in the real world
+#[derive(Clone, Copy)]
+enum TranslucentSurface {
+    Water = 0,
+    Glass = 1,
+}
+
+impl TranslucentSurface {
+    fn from_typename(typename: &str) -> Option<Self> {
+        match typename {
+            "wtr:WaterBody" | "wtr:WaterSurface" => Some(Self::Water),
+            "bldg:Window" | "brid:Window" | "tun:Window" => Some(Self::Glass),
+            _ => None,
+        }
+    }
+
+    fn material(self) -> Material {
+        match self {
+            Self::Water => Material {
+                diffuse_color: Color::new(0.25, 0.45, 0.65),
+                transparency: 0.4,
+                ..Default::default()
+            },
+            Self::Glass => Material {
+                diffuse_color: Color::new(0.6, 0.75, 0.85),
+                transparency: 0.6,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Assigns translucent materials to the polygons of water and glass surfaces that have neither material nor texture.
+fn assign_translucent_materials(
+    value: &Value,
+    app: &mut AppearanceStore,
+    geoms: &mut GeometryStore,
+    mat_indices: &mut [Option<u32>; 2],
+) {
+    match value {
+        Value::Object(obj) => {
+            if let (ObjectStereotype::Feature { geometries, .. }, Some(kind)) = (
+                &obj.stereotype,
+                TranslucentSurface::from_typename(&obj.typename),
+            ) {
+                for geom in geometries {
+                    if !matches!(
+                        geom.ty,
+                        GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+                    ) {
+                        continue;
+                    }
+                    for idx in geom.pos as usize..(geom.pos + geom.len) as usize {
+                        if geoms.polygon_materials[idx].is_some()
+                            || geoms.polygon_textures[idx].is_some()
+                        {
+                            continue;
+                        }
+                        let mat_idx = *mat_indices[kind as usize].get_or_insert_with(|| {
+                            app.materials.push(kind.material());
+                            app.materials.len() as u32 - 1
+                        });
+                        geoms.polygon_materials[idx] = Some(mat_idx);
+                    }
+                }
+            }
+            for child in obj.attributes.values() {
+                assign_translucent_materials(child, app, geoms, mat_indices);
+            }
+        }
+        Value::Array(arr) => {
+            for v in arr {
+                assign_translucent_materials(v, app, geoms, mat_indices);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::{
+        geometry::GeometryRef,
+        object::{Map, Object},
+    };
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    #[test]
+    fn translucent_water_surface() {
+        let mut geoms = GeometryStore::default();
+        geoms.vertices = vec![[0., 0., 0.], [1., 0., 0.], [1., 1., 0.]];
+        for _ in 0..2 {
+            geoms.multipolygon.add_exterior([0, 1, 2]);
+            geoms.ring_ids.push(None);
+        }
+
+        let feature = |typename: &'static str, geometries: Vec<GeometryRef>, attributes: Map| {
+            Value::Object(Object {
+                typename: typename.into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: typename.into(),
+                    geometries,
+                },
+                attributes,
+            })
+        };
+        let surface = |pos| GeometryRef {
+            ty: GeometryType::Surface,
+            lod: 2,
+            pos,
+            len: 1,
+        };
+
+        let mut attributes = Map::default();
+        attributes.insert(
+            "wtr:boundedBy".into(),
+            Value::Array(vec![
+                feature("wtr:WaterSurface", vec![surface(0)], Default::default()),
+                feature(
+                    "wtr:WaterGroundSurface",
+                    vec![surface(1)],
+                    Default::default(),
+                ),
+            ]),
+        );
+        let root = feature("wtr:WaterBody", vec![], attributes);
+
+        let entity = Entity {
+            root,
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(geoms).into(),
+            appearance_store: Default::default(),
+        };
+
+        let (_, feedback, _) = feedback::watcher();
+        let mut resolved = Vec::new();
+        ResolveAppearanceTransform::new(Default::default()).transform(
+            &feedback,
+            entity,
+            &mut resolved,
+        );
+        let mut out = Vec::new();
+        ApplyAppearanceTransform::new().transform(&feedback, resolved.pop().unwrap(), &mut out);
+
+        let entity = out.pop().unwrap();
+        let geoms = entity.geometry_store.read().unwrap();
+        let app = entity.appearance_store.read().unwrap();
+        let mat = &app.materials[geoms.polygon_materials[0].unwrap() as usize];
+        assert!(mat.base_color()[3] < 1.0);
+        assert_eq!(geoms.polygon_materials[1], None);
+    }
+
+    #[test]
+    fn primary_and_other_themes() {
+        let mut app = AppearanceStore::default();
+        assert_eq!(primary_theme(&app), None);
+        app.themes.insert("FMETheme".into(), Theme::default());
+        assert_eq!(primary_theme(&app), Some("FMETheme"));
+        app.themes.insert("rgbTexture".into(), Theme::default());
+        assert_eq!(primary_theme(&app), Some("rgbTexture"));
+
+        // a theme without any appearance resolves to the null appearance
+        let mut geoms = GeometryStore::default();
+        geoms.vertices = vec![[0., 0., 0.], [1., 0., 0.], [1., 1., 0.]];
+        geoms.multipolygon.add_exterior([0, 1, 2]);
+        geoms.ring_ids.push(None);
+        let (_, feedback, _) = feedback::watcher();
+        let resolved = resolve_theme(&feedback, app.themes.get("FMETheme"), &geoms);
+        assert_eq!(resolved.materials, vec![None]);
+        assert_eq!(resolved.textures, vec![None]);
+        assert_eq!(resolved.uvs.len(), 1);
+    }
+
+    #[test]
+    fn cached_texture_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let found = dir.path().join("found.jpg");
+        std::fs::write(&found, b"").unwrap();
+        let found = Url::from_file_path(&found).unwrap();
+        let missing = Url::from_file_path(dir.path().join("missing.jpg")).unwrap();
+
+        let sources = Arc::new(TextureSources::with_cache_dir(dir.path().join("cache")));
+        let mut transform = ResolveAppearanceTransform::new(sources.clone());
+        let (_, feedback, _) = feedback::watcher();
+
+        // the features of a file refer to the same images
+        for _ in 0..3 {
+            let mut textures = vec![
+                Texture {
+                    image_url: found.clone(),
+                },
+                Texture {
+                    image_url: missing.clone(),
+                },
+            ];
+            let available = transform.localize(&feedback, &mut textures);
+            assert_eq!(available, vec![true, false]);
+            assert_eq!(textures[0].image_url, found);
+        }
+        assert_eq!(transform.cache.len(), 2);
+        assert_eq!(sources.counts(), (1, 0));
+    }
+}
@@ -1,4 +1,12 @@
-use hashbrown::HashMap;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use hashbrown::{HashMap, HashSet};
 use indexmap::IndexMap;
 use nusamai_citygml::{
     object::{Map, Value},
@@ -9,11 +17,24 @@ use nusamai_plateau::Entity;
 
 use crate::{pipeline::Feedback, transformer::Transform};
 
+/// How to handle the namespace prefixes of the field names (e.g. `bldg:` of `bldg:measuredHeight`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PrefixPolicy {
+    /// Keep the prefixes (`bldg:measuredHeight`)
+    Keep,
+    /// Remove the prefixes (`measuredHeight`)
+    #[default]
+    Strip,
+    /// Replace `:` with `_` (`bldg_measuredHeight`)
+    Underscore,
+}
+
 /// Transform to edit field names
 ///
 /// The current implementation performs the following operations:
 ///
-/// - Remove the namespace prefix from the field names (e.g., `"ns:foo"` -> `"foo"`)
+/// - Keep, remove or replace the namespace prefix of the field names according to the `PrefixPolicy`
+///   (e.g., `"ns:foo"` -> `"foo"`)
 /// - Rename the field names for Shapefile according to the dictionary (when the option is enabled)
 /// - Rename the field names given the rules by the user
 ///
@@ -21,12 +42,51 @@ use crate::{pipeline::Feedback, transformer::Transform};
 /// - Exact match: Rename if the key matches exactly (e.g., `{"ns:foo": "bar"}`)
 /// - General match: Rename for any namespace prefix (e.g., `{"*:foo": "bar"}`)
 ///   Note that the exact match takes precedence over the general match.
+///
+/// When the prefixes are removed, fields of a type may end up with the same name (e.g., `uro:class` and `bldg:class`).
+/// Such collisions are detected when the schema is transformed, and the colliding fields get `ns_foo` names instead.
+/// The renamed fields and the collisions are reported once when the first entity is transformed.
 #[derive(Default, Clone)]
 pub struct EditFieldNamesTransform {
     // Exact string match dictionary
     exact_rename_map: HashMap<String, String>,
     // general suffix match dictionary - the stored keys are the string after the prefix "*:"
     general_rename_map: HashMap<String, String>,
+    prefix_policy: PrefixPolicy,
+    // shared with the transforms built from the same builder
+    state: Arc<RenameState>,
+}
+
+/// Results of the schema transformation, shared with the transforms for the entities
+#[derive(Default)]
+pub struct RenameState {
+    /// Original names that would collide with the other fields (they get `ns_foo` names)
+    collisions: RwLock<HashSet<String>>,
+    /// Renamed fields (original name -> new name)
+    renamed: Mutex<BTreeMap<String, String>>,
+    /// Collisions that cannot be resolved (e.g. by the rules given by the user)
+    warnings: Mutex<Vec<String>>,
+    reported: AtomicBool,
+}
+
+impl RenameState {
+    fn report(&self, feedback: &Feedback) {
+        if self.reported.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let renamed = self.renamed.lock().unwrap();
+        if !renamed.is_empty() {
+            let list = renamed
+                .iter()
+                .map(|(before, after)| format!("{before} -> {after}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            feedback.info(format!("Renamed {} field names: {list}", renamed.len()));
+        }
+        for warning in self.warnings.lock().unwrap().iter() {
+            feedback.warn(warning.clone());
+        }
+    }
 }
 
 impl EditFieldNamesTransform {
@@ -34,6 +94,18 @@ impl EditFieldNamesTransform {
         Self::default()
     }
 
+    /// Shares the state with the other transforms (the schema is transformed by one of them)
+    pub fn with_state(state: Arc<RenameState>) -> Self {
+        Self {
+            state,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_prefix_policy(&mut self, policy: PrefixPolicy) {
+        self.prefix_policy = policy;
+    }
+
     pub fn load_default_map_for_shape(&mut self) {
         const SHAPE_DICT: &str = include_str!("./shp_field_dict.json");
         let map: HashMap<String, String> =
@@ -60,18 +132,61 @@ impl EditFieldNamesTransform {
 }
 
 impl Transform for EditFieldNamesTransform {
-    fn transform(&mut self, _feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
+    fn transform(&mut self, feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
+        self.state.report(feedback);
         self.edit_tree(&mut entity.root);
         out.push(entity);
     }
 
     fn transform_schema(&self, schema: &mut Schema) {
+        // Detect the collisions first
+        {
+            let mut collisions = self.state.collisions.write().unwrap();
+            let mut warnings = self.state.warnings.lock().unwrap();
+            for (typename, ty) in &schema.types {
+                let attrs = match ty {
+                    TypeDef::Data(data) => &data.attributes,
+                    TypeDef::Feature(feat) => &feat.attributes,
+                    TypeDef::Property(_) => continue,
+                };
+                let mut groups: IndexMap<String, Vec<(&str, bool)>> = IndexMap::default();
+                for key in attrs.keys() {
+                    let (new_name, by_rule) = self.rename_without_collisions(key);
+                    groups.entry(new_name).or_default().push((key, by_rule));
+                }
+                for (new_name, members) in groups {
+                    if members.len() < 2 {
+                        continue;
+                    }
+                    // The fields renamed by the rules keep their names
+                    let by_rule = members.iter().filter(|(_, by_rule)| *by_rule).count();
+                    collisions.extend(
+                        members
+                            .iter()
+                            .filter(|(key, by_rule)| !by_rule && key.contains(':'))
+                            .map(|(key, _)| key.to_string()),
+                    );
+                    if by_rule > 1 {
+                        let names: Vec<_> = members.iter().map(|(key, _)| *key).collect();
+                        warnings.push(format!(
+                            "Field names collide in {typename}: {} are renamed to {new_name}",
+                            names.join(", ")
+                        ));
+                    }
+                }
+            }
+        }
+
         let drain_to_new_attrs = |attrs: &mut schema::Map| {
             let mut new_attrs = IndexMap::default();
+            let mut renamed = self.state.renamed.lock().unwrap();
             for (key, mut value) in attrs.drain(..) {
                 let new_name = self.rename(&key);
+                if new_name != key {
+                    renamed.insert(key.clone(), new_name.clone());
+                }
                 value.original_name = Some(key.clone());
-                new_attrs.insert(new_name.to_string(), value);
+                new_attrs.insert(new_name, value);
             }
             new_attrs
         };
@@ -91,29 +206,38 @@ impl Transform for EditFieldNamesTransform {
 }
 
 impl EditFieldNamesTransform {
-    fn rename<'a>(&'a self, name: &'a str) -> &'a str {
+    fn rename(&self, name: &str) -> String {
+        if let Some((prefix, local_name)) = name.split_once(':') {
+            if self.state.collisions.read().unwrap().contains(name) {
+                return format!("{prefix}_{local_name}");
+            }
+        }
+        self.rename_without_collisions(name).0
+    }
+
+    /// Returns the new name, and whether it is given by the rules (or by the prefix policy)
+    fn rename_without_collisions(&self, name: &str) -> (String, bool) {
         // Lookup and rename: exact match
         if let Some(new_key) = self.exact_rename_map.get(name) {
-            return new_key.as_ref();
+            return (new_key.clone(), true);
         }
 
-        name.find(':')
-            .map(|pos| {
-                let key = &name[pos + 1..]; // remove the namespace prefix
-
-                if let Some(new_key) = self.general_rename_map.get(key) {
-                    return new_key.as_ref();
-                }
-
-                // If the namespace is removed, it will conflict with the global "id" column (= "gml:id").
-                // Therefore, don't remove the namespace prefix
-                if key == "id" {
-                    return name;
-                }
+        let Some((prefix, local_name)) = name.split_once(':') else {
+            return (name.to_string(), false);
+        };
+        if let Some(new_key) = self.general_rename_map.get(local_name) {
+            return (new_key.clone(), true);
+        }
 
-                key
-            })
-            .unwrap_or(name)
+        let new_name = match self.prefix_policy {
+            PrefixPolicy::Keep => name.to_string(),
+            // If the namespace is removed, it will conflict with the global "id" column (= "gml:id").
+            // Therefore, don't remove the namespace prefix
+            PrefixPolicy::Strip if local_name == "id" => name.to_string(),
+            PrefixPolicy::Strip => local_name.to_string(),
+            PrefixPolicy::Underscore => format!("{prefix}_{local_name}"),
+        };
+        (new_name, false)
     }
 
     fn edit_tree(&self, value: &mut Value) {
@@ -123,7 +247,7 @@ impl EditFieldNamesTransform {
                 for (key, mut value) in obj.attributes.drain(..) {
                     self.edit_tree(&mut value);
                     let new_name = self.rename(&key);
-                    new_attrs.insert(new_name.to_string(), value);
+                    new_attrs.insert(new_name, value);
                 }
                 obj.attributes = new_attrs;
             }
@@ -175,4 +299,65 @@ mod tests {
         assert_eq!(transform.rename("bldg:class"), "class");
         assert_eq!(transform.rename("*use:class"), "土地利用区分");
     }
+
+    #[test]
+    fn test_prefix_policy() {
+        let mut transform = EditFieldNamesTransform::new();
+        let mut map = HashMap::new();
+        map.insert("*:class".to_string(), "分類".to_string());
+        transform.extend_rename_map(map);
+
+        transform.set_prefix_policy(PrefixPolicy::Keep);
+        assert_eq!(
+            transform.rename("bldg:measuredHeight"),
+            "bldg:measuredHeight"
+        );
+        assert_eq!(transform.rename("bldg:class"), "分類");
+
+        transform.set_prefix_policy(PrefixPolicy::Underscore);
+        assert_eq!(
+            transform.rename("bldg:measuredHeight"),
+            "bldg_measuredHeight"
+        );
+        assert_eq!(transform.rename("uro:id"), "uro_id");
+
+        transform.set_prefix_policy(PrefixPolicy::Strip);
+        assert_eq!(transform.rename("bldg:measuredHeight"), "measuredHeight");
+        assert_eq!(transform.rename("uro:id"), "uro:id");
+    }
+
+    #[test]
+    fn test_collisions() {
+        use nusamai_citygml::schema::{Attribute, FeatureTypeDef, TypeRef};
+
+        let state = Arc::new(RenameState::default());
+        let transform = EditFieldNamesTransform::with_state(state.clone());
+
+        let mut feature = FeatureTypeDef::default();
+        for name in ["bldg:class", "uro:class", "bldg:usage"] {
+            feature
+                .attributes
+                .insert(name.into(), Attribute::new(TypeRef::String));
+        }
+        let mut schema = Schema::default();
+        schema
+            .types
+            .insert("bldg:Building".into(), TypeDef::Feature(feature));
+        transform.transform_schema(&mut schema);
+
+        let TypeDef::Feature(feature) = &schema.types["bldg:Building"] else {
+            unreachable!()
+        };
+        let names: Vec<_> = feature.attributes.keys().collect();
+        assert_eq!(names, ["bldg_class", "uro_class", "usage"]);
+
+        // The transforms built afterwards rename the fields in the same way
+        let other = EditFieldNamesTransform::with_state(state.clone());
+        assert_eq!(other.rename("uro:class"), "uro_class");
+        assert_eq!(other.rename("bldg:usage"), "usage");
+        assert_eq!(
+            state.renamed.lock().unwrap().get("bldg:class").unwrap(),
+            "bldg_class"
+        );
+    }
 }
@@ -0,0 +1,236 @@
+//! Handling of the attribute values too large for the sinks
+//!
+//! The flattened `uro` trees can become huge JSON strings, which hit the limits of the formats (e.g. the
+//! tag sizes of the vector tiles, the row sizes of SQLite) unpredictably. The string values larger than
+//! the limit are truncated, moved into the side files, or stop the conversion.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use nusamai_citygml::{object::Value, schema::Schema};
+use nusamai_plateau::Entity;
+
+use crate::{
+    pipeline::{Feedback, PipelineError},
+    sink::manifest::sha256_hex,
+    transformer::Transform,
+};
+
+/// Appended to the truncated values
+const TRUNCATION_MARKER: &str = "…";
+/// Number of hex digits of the content hash used as the name of the side file
+const HASH_DIGITS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LargeAttributeMode {
+    /// Output the values as they are
+    #[default]
+    Keep,
+    /// Cut the values at the limit (at a character boundary)
+    Truncate,
+    /// Write the values into the side files, and replace them with the relative paths of the files
+    Externalize,
+    /// Stop the conversion
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LargeAttributeSpec {
+    pub mode: LargeAttributeMode,
+    /// Maximum size of a string value in bytes
+    pub limit: usize,
+}
+
+impl Default for LargeAttributeSpec {
+    fn default() -> Self {
+        Self {
+            mode: LargeAttributeMode::Keep,
+            limit: 64 * 1024,
+        }
+    }
+}
+
+/// Directory of the side files of the output (e.g. `city_attributes/` next to `city.gpkg`)
+pub fn large_attribute_dir(output_path: &Path) -> PathBuf {
+    let name = output_path
+        .file_stem()
+        .map_or("output".into(), |stem| stem.to_string_lossy());
+    output_path.with_file_name(format!("{name}_attributes"))
+}
+
+/// Counts of the handled values (shared by the transforms of the threads)
+#[derive(Default)]
+pub struct LargeAttributeStats {
+    truncated: AtomicUsize,
+    externalized: AtomicUsize,
+}
+
+impl LargeAttributeStats {
+    pub fn report(&self, feedback: &Feedback) {
+        let truncated = self.truncated.load(Ordering::Relaxed);
+        if truncated > 0 {
+            feedback.warn(format!("{truncated} large attribute values were truncated"));
+        }
+        let externalized = self.externalized.load(Ordering::Relaxed);
+        if externalized > 0 {
+            feedback.info(format!(
+                "{externalized} large attribute values were written into the side files"
+            ));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LargeAttributeTransform {
+    spec: LargeAttributeSpec,
+    // directory of the side files (for `Externalize`)
+    dir: Option<PathBuf>,
+    stats: Arc<LargeAttributeStats>,
+}
+
+impl LargeAttributeTransform {
+    pub fn new(
+        spec: LargeAttributeSpec,
+        dir: Option<PathBuf>,
+        stats: Arc<LargeAttributeStats>,
+    ) -> Self {
+        Self { spec, dir, stats }
+    }
+
+    fn handle(&self, name: &str, value: &mut String) -> Result<(), PipelineError> {
+        match self.spec.mode {
+            LargeAttributeMode::Keep => {}
+            LargeAttributeMode::Truncate => {
+                truncate(value, self.spec.limit);
+                self.stats.truncated.fetch_add(1, Ordering::Relaxed);
+            }
+            LargeAttributeMode::Externalize => {
+                let Some(dir) = &self.dir else {
+                    return Err(PipelineError::Other(
+                        "The directory of the side files of the large attributes is not set".into(),
+                    ));
+                };
+                *value = externalize(dir, value)?;
+                self.stats.externalized.fetch_add(1, Ordering::Relaxed);
+            }
+            LargeAttributeMode::Fail => {
+                return Err(PipelineError::Other(format!(
+                    "The value of the attribute '{}' is too large: {} bytes (limit: {} bytes)",
+                    name,
+                    value.len(),
+                    self.spec.limit
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transform for LargeAttributeTransform {
+    fn transform(&mut self, feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
+        if let Value::Object(obj) = &mut entity.root {
+            for (name, value) in obj.attributes.iter_mut() {
+                let Value::String(s) = value else {
+                    continue;
+                };
+                if s.len() <= self.spec.limit {
+                    continue;
+                }
+                if let Err(err) = self.handle(name, s) {
+                    feedback.fatal_error(err);
+                    return;
+                }
+            }
+        }
+        out.push(entity);
+    }
+
+    fn transform_schema(&self, _schema: &mut Schema) {
+        // the values remain strings
+    }
+}
+
+/// Cuts the value so that it (with the marker) fits in `limit` bytes
+fn truncate(value: &mut String, limit: usize) {
+    let mut end = limit.saturating_sub(TRUNCATION_MARKER.len());
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+    value.push_str(TRUNCATION_MARKER);
+}
+
+/// Writes the value into a file named by its hash, and returns the path relative to the parent of `dir`
+fn externalize(dir: &Path, value: &str) -> Result<String, PipelineError> {
+    let hash = sha256_hex(value.as_bytes());
+    let filename = format!("{}.txt", &hash[..HASH_DIGITS]);
+    let path = dir.join(&filename);
+    // (the same values are written once)
+    if !path.exists() {
+        fs::create_dir_all(dir)?;
+        fs::write(&path, value)?;
+    }
+    let dirname = dir
+        .file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().into());
+    Ok(format!("{dirname}/{filename}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        let mut value = "あいうえお".to_string();
+        // (3 bytes for each character and the marker)
+        truncate(&mut value, 10);
+        assert_eq!(value, "あい…");
+        assert!(value.len() <= 10);
+
+        let mut value = "abcdef".to_string();
+        truncate(&mut value, 4);
+        assert_eq!(value, "a…");
+    }
+
+    #[test]
+    fn test_large_attributes() {
+        let dir = tempfile::tempdir().unwrap();
+        let side_dir = large_attribute_dir(&dir.path().join("city.gpkg"));
+        assert_eq!(side_dir, dir.path().join("city_attributes"));
+
+        let stats = Arc::new(LargeAttributeStats::default());
+        let spec = |mode| LargeAttributeSpec { mode, limit: 8 };
+        let large = "x".repeat(100);
+
+        let mut value = large.clone();
+        let transform = LargeAttributeTransform::new(
+            spec(LargeAttributeMode::Externalize),
+            Some(side_dir.clone()),
+            stats.clone(),
+        );
+        transform.handle("uro:attr", &mut value).unwrap();
+        assert!(value.starts_with("city_attributes/"));
+        assert_eq!(fs::read_to_string(dir.path().join(&value)).unwrap(), large);
+
+        let transform =
+            LargeAttributeTransform::new(spec(LargeAttributeMode::Fail), None, stats.clone());
+        assert!(transform.handle("uro:attr", &mut large.clone()).is_err());
+
+        // externalizing without the directory is an error
+        let transform = LargeAttributeTransform::new(
+            spec(LargeAttributeMode::Externalize),
+            None,
+            stats.clone(),
+        );
+        assert!(transform.handle("uro:attr", &mut large.clone()).is_err());
+
+        assert_eq!(stats.externalized.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,158 @@
+//! Tolerance for the axis order of the input coordinates
+//!
+//! The geographic CRSs of CityGML (EPSG:6697, EPSG:4979) have the latitude first, but some exports of
+//! the non-PLATEAU tools write the longitude first, and the features end up rotated into the ocean.
+//! Such coordinates are detected by the first axis out of the range of the latitude, or the axes can be
+//! swapped explicitly (e.g. for the plane rectangular coordinates, which cannot be detected).
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use nusamai_citygml::schema::Schema;
+use nusamai_plateau::Entity;
+use nusamai_projection::crs::{EpsgCode, EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_WGS84_GEOGRAPHIC_3D};
+
+use crate::{pipeline::Feedback, transformer::Transform};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AxisOrder {
+    /// Swap the axes of the geographic coordinates detected to be in the longitude-latitude order
+    #[default]
+    Auto,
+    /// Always swap the first two axes
+    Swap,
+}
+
+/// Count of the features whose axes were swapped by the detection (shared by the transforms of the threads)
+#[derive(Default)]
+pub struct AxisOrderStats {
+    swapped: AtomicUsize,
+}
+
+impl AxisOrderStats {
+    pub fn report(&self, feedback: &Feedback) {
+        let swapped = self.swapped.load(Ordering::Relaxed);
+        if swapped > 0 {
+            feedback.warn(format!(
+                "The coordinates of {swapped} features were in the longitude-latitude order, and their axes were swapped"
+            ));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AxisOrderTransform {
+    order: AxisOrder,
+    stats: Arc<AxisOrderStats>,
+}
+
+impl AxisOrderTransform {
+    pub fn new(order: AxisOrder, stats: Arc<AxisOrderStats>) -> Self {
+        Self { order, stats }
+    }
+}
+
+impl Transform for AxisOrderTransform {
+    fn transform(&mut self, _feedback: &Feedback, entity: Entity, out: &mut Vec<Entity>) {
+        {
+            let mut geom_store = entity.geometry_store.write().unwrap();
+            let swap = match self.order {
+                AxisOrder::Swap => true,
+                AxisOrder::Auto => {
+                    let detected =
+                        is_geographic(geom_store.epsg) && is_lng_lat_order(&geom_store.vertices);
+                    if detected {
+                        self.stats.swapped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    detected
+                }
+            };
+            if swap {
+                geom_store.vertices.iter_mut().for_each(|v| v.swap(0, 1));
+            }
+        }
+        out.push(entity);
+    }
+
+    fn transform_schema(&self, _schema: &mut Schema) {
+        // do nothing
+    }
+}
+
+fn is_geographic(epsg: EpsgCode) -> bool {
+    matches!(epsg, EPSG_JGD2011_GEOGRAPHIC_3D | EPSG_WGS84_GEOGRAPHIC_3D)
+}
+
+/// Whether the geographic coordinates (expected in the latitude-longitude order) have the longitude first
+fn is_lng_lat_order(vertices: &[[f64; 3]]) -> bool {
+    vertices.iter().any(|v| v[0].abs() > 90.0) && vertices.iter().all(|v| v[1].abs() <= 90.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::{object::Object, GeometryStore, Value};
+
+    use super::*;
+    use crate::pipeline::feedback::watcher;
+
+    fn transform_vertices(order: AxisOrder, epsg: EpsgCode, vertices: Vec<[f64; 3]>) -> [f64; 3] {
+        let (_watcher, feedback, _canceller) = watcher();
+        let entity = Entity {
+            root: Value::Object(Object {
+                typename: "bldg:Building".into(),
+                attributes: Default::default(),
+                stereotype: nusamai_citygml::object::ObjectStereotype::Feature {
+                    id: "bldg_1".into(),
+                    geometries: Default::default(),
+                },
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(GeometryStore {
+                epsg,
+                vertices,
+                ..Default::default()
+            })
+            .into(),
+            appearance_store: Default::default(),
+        };
+
+        let mut transform = AxisOrderTransform::new(order, Default::default());
+        let mut out = Vec::new();
+        transform.transform(&feedback, entity, &mut out);
+        let geom_store = out[0].geometry_store.read().unwrap();
+        geom_store.vertices[0]
+    }
+
+    #[test]
+    fn test_axis_order() {
+        let lat_lng = vec![[35.68, 139.76, 10.0], [35.69, 139.77, 10.0]];
+        let lng_lat = vec![[139.76, 35.68, 10.0], [139.77, 35.69, 10.0]];
+
+        // the longitude-latitude order is detected
+        for epsg in [EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_WGS84_GEOGRAPHIC_3D] {
+            assert_eq!(
+                transform_vertices(AxisOrder::Auto, epsg, lat_lng.clone()),
+                [35.68, 139.76, 10.0]
+            );
+            assert_eq!(
+                transform_vertices(AxisOrder::Auto, epsg, lng_lat.clone()),
+                [35.68, 139.76, 10.0]
+            );
+        }
+
+        // the plane rectangular coordinates are swapped only explicitly
+        let xy = vec![[-35000.0, 120000.0, 10.0]];
+        assert_eq!(
+            transform_vertices(AxisOrder::Auto, 6677, xy.clone()),
+            [-35000.0, 120000.0, 10.0]
+        );
+        assert_eq!(
+            transform_vertices(AxisOrder::Swap, 6677, xy),
+            [120000.0, -35000.0, 10.0]
+        );
+    }
+}
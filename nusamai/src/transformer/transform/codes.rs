@@ -0,0 +1,311 @@
+use nusamai_citygml::{
+    object::{Map, Object, Value},
+    schema::{Attribute, Schema, TypeDef, TypeRef},
+    Code,
+};
+use nusamai_plateau::Entity;
+
+use crate::{pipeline::Feedback, transformer::Transform};
+
+/// Suffix of the columns of the raw codes (e.g. `bldg:usage_code`)
+pub const CODE_COLUMN_SUFFIX: &str = "_code";
+
+/// `gml:name` is also a code (with the language as the `codeSpace`), but its value is the name itself
+const NAME_KEY: &str = "gml:name";
+
+/// How to output the values of the codes (e.g. `bldg:usage`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodeValueMode {
+    /// The labels resolved with the codelists (e.g. `業務施設`)
+    #[default]
+    Label,
+    /// The raw codes (e.g. `401`)
+    Code,
+    /// The labels, and the raw codes in the additional columns with the `_code` suffix
+    Both,
+}
+
+/// Replaces the labels of the codes with the raw codes, or adds the raw codes next to the labels
+#[derive(Clone)]
+pub struct CodeValueTransform {
+    mode: CodeValueMode,
+}
+
+impl CodeValueTransform {
+    pub fn new(mode: CodeValueMode) -> Self {
+        Self { mode }
+    }
+}
+
+impl Transform for CodeValueTransform {
+    fn transform(&mut self, _feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
+        convert_codes(&mut entity.root, self.mode);
+        out.push(entity);
+    }
+
+    fn transform_schema(&self, schema: &mut Schema) {
+        if self.mode != CodeValueMode::Both {
+            return;
+        }
+        for ty in schema.types.values_mut() {
+            let attributes = match ty {
+                TypeDef::Feature(feature) => &mut feature.attributes,
+                TypeDef::Data(data) => &mut data.attributes,
+                TypeDef::Property(_) => continue,
+            };
+
+            let mut expanded = nusamai_citygml::schema::Map::default();
+            for (key, attr) in attributes.drain(..) {
+                let code_column = (attr.type_ref == TypeRef::Code && key != NAME_KEY).then(|| {
+                    let column = Attribute {
+                        type_ref: TypeRef::String,
+                        original_name: None,
+                        ..attr.clone()
+                    };
+                    (code_column_name(&key), column)
+                });
+                expanded.insert(key, attr);
+                if let Some((key, attr)) = code_column {
+                    expanded.insert(key, attr);
+                }
+            }
+            *attributes = expanded;
+        }
+    }
+}
+
+fn code_column_name(key: &str) -> String {
+    format!("{key}{CODE_COLUMN_SUFFIX}")
+}
+
+fn convert_codes(value: &mut Value, mode: CodeValueMode) {
+    match value {
+        Value::Object(obj) => {
+            for value in obj.attributes.values_mut() {
+                convert_codes(value, mode);
+            }
+            convert_object_codes(obj, mode);
+        }
+        Value::Array(arr) => {
+            for value in arr.iter_mut() {
+                convert_codes(value, mode);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn convert_object_codes(obj: &mut Object, mode: CodeValueMode) {
+    match mode {
+        CodeValueMode::Label => {}
+        CodeValueMode::Code => {
+            for (key, value) in obj.attributes.iter_mut() {
+                if key != NAME_KEY {
+                    replace_with_codes(value);
+                }
+            }
+        }
+        CodeValueMode::Both => {
+            let mut attributes = Map::default();
+            for (key, value) in obj.attributes.drain(..) {
+                let codes = match key == NAME_KEY {
+                    true => None,
+                    false => raw_codes(&value),
+                };
+                let code_key = code_column_name(&key);
+                attributes.insert(key, value);
+                if let Some(codes) = codes {
+                    attributes.insert(code_key, codes);
+                }
+            }
+            obj.attributes = attributes;
+        }
+    }
+}
+
+fn replace_with_codes(value: &mut Value) {
+    match value {
+        Value::Code(code) => {
+            *code = Code::new(code.code().into(), code.code().into())
+                .with_code_space(code.code_space().map(String::from));
+        }
+        Value::Array(arr) => {
+            for value in arr.iter_mut() {
+                replace_with_codes(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The raw codes of a code or an array of the codes
+fn raw_codes(value: &Value) -> Option<Value> {
+    match value {
+        Value::Code(code) => Some(Value::String(code.code().into())),
+        Value::Array(arr) if !arr.is_empty() && arr.iter().all(|v| matches!(v, Value::Code(_))) => {
+            Some(Value::Array(arr.iter().filter_map(raw_codes).collect()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::{object::ObjectStereotype, schema::FeatureTypeDef};
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    fn code(value: &str, code: &str) -> Value {
+        Value::Code(Code::new(value.into(), code.into()))
+    }
+
+    fn building() -> Entity {
+        let mut detail = Map::default();
+        detail.insert("uro:buildingStructureType".into(), code("木造", "610"));
+        let mut attributes = Map::default();
+        attributes.insert(NAME_KEY.into(), Value::Array(vec![code("庁舎", "庁舎")]));
+        attributes.insert("bldg:usage".into(), code("業務施設", "401"));
+        attributes.insert(
+            "bldg:roofType".into(),
+            Value::Array(vec![code("陸屋根", "1"), code("切妻", "2")]),
+        );
+        attributes.insert(
+            "uro:buildingDetailAttribute".into(),
+            Value::Array(vec![Value::Object(Object {
+                typename: "uro:BuildingDetailAttribute".into(),
+                stereotype: ObjectStereotype::Data,
+                attributes: detail,
+            })]),
+        );
+        Entity {
+            root: Value::Object(Object {
+                typename: "bldg:Building".into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: "bldg_1".into(),
+                    geometries: Default::default(),
+                },
+                attributes,
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: Default::default(),
+            appearance_store: Default::default(),
+        }
+    }
+
+    fn transform(mode: CodeValueMode) -> Object {
+        let (_, feedback, _) = feedback::watcher();
+        let mut out = Vec::new();
+        CodeValueTransform::new(mode).transform(&feedback, building(), &mut out);
+        let Value::Object(obj) = out.pop().unwrap().root else {
+            unreachable!();
+        };
+        obj
+    }
+
+    fn detail(obj: &Object) -> &Object {
+        let Some(Value::Array(details)) = obj.attributes.get("uro:buildingDetailAttribute") else {
+            unreachable!();
+        };
+        let Value::Object(detail) = &details[0] else {
+            unreachable!();
+        };
+        detail
+    }
+
+    #[test]
+    fn test_label() {
+        let obj = transform(CodeValueMode::Label);
+        assert_eq!(obj.attributes["bldg:usage"], code("業務施設", "401"));
+        assert!(!obj.attributes.contains_key("bldg:usage_code"));
+    }
+
+    #[test]
+    fn test_code() {
+        let obj = transform(CodeValueMode::Code);
+        assert_eq!(obj.attributes["bldg:usage"], code("401", "401"));
+        assert_eq!(
+            obj.attributes["bldg:roofType"],
+            Value::Array(vec![code("1", "1"), code("2", "2")])
+        );
+        assert_eq!(
+            detail(&obj).attributes["uro:buildingStructureType"],
+            code("610", "610")
+        );
+    }
+
+    #[test]
+    fn test_both() {
+        let obj = transform(CodeValueMode::Both);
+        let keys: Vec<_> = obj.attributes.keys().map(String::as_str).collect();
+        assert_eq!(
+            keys,
+            vec![
+                NAME_KEY,
+                "bldg:usage",
+                "bldg:usage_code",
+                "bldg:roofType",
+                "bldg:roofType_code",
+                "uro:buildingDetailAttribute",
+            ]
+        );
+        assert_eq!(obj.attributes["bldg:usage"], code("業務施設", "401"));
+        assert_eq!(
+            obj.attributes["bldg:usage_code"],
+            Value::String("401".into())
+        );
+        assert_eq!(
+            obj.attributes["bldg:roofType_code"],
+            Value::Array(vec![Value::String("1".into()), Value::String("2".into())])
+        );
+        assert_eq!(
+            detail(&obj).attributes["uro:buildingStructureType_code"],
+            Value::String("610".into())
+        );
+    }
+
+    #[test]
+    fn test_both_schema() {
+        let mut attributes = nusamai_citygml::schema::Map::default();
+        attributes.insert(NAME_KEY.into(), Attribute::new(TypeRef::Code));
+        attributes.insert("bldg:usage".into(), Attribute::new(TypeRef::Code));
+        attributes.insert(
+            "bldg:roofType".into(),
+            Attribute {
+                max_occurs: None,
+                ..Attribute::new(TypeRef::Code)
+            },
+        );
+        attributes.insert("bldg:class".into(), Attribute::new(TypeRef::String));
+        let mut schema = Schema::default();
+        schema.types.insert(
+            "bldg:Building".into(),
+            TypeDef::Feature(FeatureTypeDef {
+                attributes,
+                additional_attributes: false,
+            }),
+        );
+
+        CodeValueTransform::new(CodeValueMode::Both).transform_schema(&mut schema);
+        let TypeDef::Feature(feature) = &schema.types["bldg:Building"] else {
+            unreachable!();
+        };
+        let keys: Vec<_> = feature.attributes.keys().map(String::as_str).collect();
+        assert_eq!(
+            keys,
+            vec![
+                NAME_KEY,
+                "bldg:usage",
+                "bldg:usage_code",
+                "bldg:roofType",
+                "bldg:roofType_code",
+                "bldg:class",
+            ]
+        );
+        assert_eq!(
+            feature.attributes["bldg:roofType_code"].type_ref,
+            TypeRef::String
+        );
+        assert_eq!(feature.attributes["bldg:roofType_code"].max_occurs, None);
+    }
+}
@@ -24,6 +24,9 @@ pub enum FeatureFlatteningOption {
     AllExceptThematicSurfaces,
     /// Flatten all features
     All,
+    /// Flatten only the sub-elements of bridges and tunnels (e.g. `brid:BridgeConstructionElement`),
+    /// and leave the other child features in the tree
+    BridgeAndTunnelElements,
 }
 
 /// Flattening option for the "data" stereotype
@@ -94,10 +97,17 @@ impl Transform for FlattenTreeTransform {
     }
 
     fn transform_schema(&self, schema: &mut Schema) {
-        for ty in schema.types.values_mut() {
+        for (typename, ty) in schema.types.iter_mut() {
             match ty {
                 TypeDef::Feature(typedef) => {
-                    if !matches!(self.feature, FeatureFlatteningOption::None) {
+                    let has_parent = match self.feature {
+                        FeatureFlatteningOption::None => false,
+                        FeatureFlatteningOption::BridgeAndTunnelElements => {
+                            is_bridge_or_tunnel_element(typename)
+                        }
+                        _ => true,
+                    };
+                    if has_parent {
                         typedef.attributes.insert(
                             "parentId".into(),
                             Attribute {
@@ -262,10 +272,11 @@ impl FlattenTreeTransform {
                 FeatureFlatteningOption::None => false,
                 FeatureFlatteningOption::All => true,
                 FeatureFlatteningOption::AllExceptThematicSurfaces => {
-                    !obj.typename.ends_with("Surface")
-                        && !obj.typename.ends_with(":Window")
-                        && !obj.typename.ends_with(":Door")
-                        && !obj.typename.ends_with("TrafficArea")
+                    !is_thematic_surface(&obj.typename)
+                }
+                FeatureFlatteningOption::BridgeAndTunnelElements => {
+                    // the root feature is always emitted
+                    parent.is_none() || is_bridge_or_tunnel_element(&obj.typename)
                 }
             },
             ObjectStereotype::Data => match self.data {
@@ -288,6 +299,19 @@ impl FlattenTreeTransform {
     }
 }
 
+pub(super) fn is_thematic_surface(typename: &str) -> bool {
+    typename.ends_with("Surface")
+        || typename.ends_with(":Window")
+        || typename.ends_with(":Door")
+        || typename.ends_with("TrafficArea")
+}
+
+/// Sub-elements of bridges and tunnels such as `brid:BridgeConstructionElement` and `tun:TunnelInstallation`
+fn is_bridge_or_tunnel_element(typename: &str) -> bool {
+    (typename.starts_with("brid:") || typename.starts_with("tun:"))
+        && !is_thematic_surface(typename)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -458,6 +482,66 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_flatten_bridge_elements() {
+        let child = |typename: &str, id: &str| {
+            Value::Object(Object {
+                typename: typename.to_string().into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: id.into(),
+                    geometries: Vec::default(),
+                },
+                attributes: Map::default(),
+            })
+        };
+        let mut attributes = Map::default();
+        attributes.insert(
+            "brid:outerBridgeConstruction".into(),
+            Value::Array(vec![child("brid:BridgeConstructionElement", "elem_1")]),
+        );
+        attributes.insert(
+            "brid:boundedBy".into(),
+            Value::Array(vec![child("brid:WallSurface", "wall_1")]),
+        );
+        let root = Value::Object(Object {
+            typename: "brid:Bridge".into(),
+            stereotype: ObjectStereotype::Feature {
+                id: "bridge_1".into(),
+                geometries: Vec::default(),
+            },
+            attributes,
+        });
+
+        let transform = FlattenTreeTransform::with_options(
+            FeatureFlatteningOption::BridgeAndTunnelElements,
+            DataFlatteningOption::None,
+            ObjectFlatteningOption::None,
+        );
+        let geom_store = Arc::new(RwLock::new(GeometryStore::default()));
+        let appearance_store = Arc::new(RwLock::new(AppearanceStore::default()));
+        let mut out: Vec<Entity> = vec![];
+        transform.flatten_entity(root, &geom_store, &appearance_store, &mut out, &None);
+
+        assert_eq!(out.len(), 2);
+        let Value::Object(elem) = &out[0].root else {
+            unreachable!()
+        };
+        assert_eq!(elem.typename, "brid:BridgeConstructionElement");
+        assert_eq!(
+            elem.attributes.get("parentId").unwrap(),
+            &Value::String("bridge_1".into())
+        );
+        // the thematic surfaces remain in the bridge
+        let Value::Object(bridge) = &out[1].root else {
+            unreachable!()
+        };
+        assert_eq!(bridge.typename, "brid:Bridge");
+        assert!(bridge.attributes.contains_key("brid:boundedBy"));
+        assert!(!bridge
+            .attributes
+            .contains_key("brid:outerBridgeConstruction"));
+    }
+
     #[test]
     fn test_flatten_entity_nested_features() {
         // Prepare test entity hierarchy
@@ -1,14 +1,94 @@
 use nusamai_citygml::{
-    object::{ObjectStereotype, Value},
+    geometry::GeometryStore,
+    object::{Object, ObjectStereotype, Value},
     schema::{FeatureTypeDef, Schema, TypeDef, TypeRef},
 };
 use nusamai_plateau::Entity;
 
+use super::{flatten::is_thematic_surface, vegetation::extent};
 use crate::{pipeline::Feedback, transformer::Transform};
 
-/// Jsonify all objects and arrays in the entity.
+/// Adds the height range of the geometries (`minHeight`, `maxHeight`) to the features.
+///
+/// The root feature has the range of all the vertices of the entity, and the nested features (e.g. the building parts,
+/// except the thematic surfaces) have the range of their own geometries.
 #[derive(Clone, Default)]
-pub struct GeometryStatsTransform {}
+pub struct GeometryStatsTransform {
+    /// Add attributes for 2.5D extrusion (e.g. MapLibre's fill-extrusion)
+    extrusion_heights: bool,
+}
+
+impl GeometryStatsTransform {
+    /// Also adds `height`, `min_height` and `ground_elevation` for 2.5D extrusion.
+    ///
+    /// The heights are relative to the ground elevation, which is the lowest vertex of the entity.
+    pub fn with_extrusion_heights() -> Self {
+        Self {
+            extrusion_heights: true,
+        }
+    }
+
+    /// Adds the attributes to the feature with the height range `[min, max]` of its geometries
+    fn add_attributes(&self, obj: &mut Object, [min_h, max_h]: [f64; 2], ground_elevation: f64) {
+        obj.attributes
+            .insert("maxHeight".to_string(), Value::Double(max_h));
+        obj.attributes
+            .insert("minHeight".to_string(), Value::Double(min_h));
+
+        if self.extrusion_heights {
+            let min_height = min_h - ground_elevation;
+            // prefer the measured height to the extent of the geometry
+            let height = match obj.attributes.get("bldg:measuredHeight") {
+                Some(Value::Measure(m)) if m.value() > 0.0 => min_height + m.value(),
+                _ => max_h - ground_elevation,
+            };
+            obj.attributes
+                .insert("height".to_string(), Value::Double(height));
+            obj.attributes
+                .insert("min_height".to_string(), Value::Double(min_height));
+            obj.attributes.insert(
+                "ground_elevation".to_string(),
+                Value::Double(ground_elevation),
+            );
+        }
+    }
+
+    /// Adds the attributes to the nested features (except the thematic surfaces) with the range of their geometries
+    fn add_nested_attributes(
+        &self,
+        value: &mut Value,
+        geom_store: &GeometryStore,
+        ground_elevation: f64,
+    ) {
+        match value {
+            Value::Object(obj) => {
+                let range = match &obj.stereotype {
+                    ObjectStereotype::Feature { geometries, .. }
+                        if !is_thematic_surface(&obj.typename) =>
+                    {
+                        extent(geom_store, geometries)
+                    }
+                    _ => None,
+                };
+                if let Some([min, max]) = range {
+                    self.add_attributes(obj, [min[2], max[2]], ground_elevation);
+                }
+                for value in obj.attributes.values_mut() {
+                    self.add_nested_attributes(value, geom_store, ground_elevation);
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.add_nested_attributes(value, geom_store, ground_elevation);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Attributes for 2.5D extrusion
+const EXTRUSION_ATTRIBUTES: [&str; 3] = ["height", "min_height", "ground_elevation"];
 
 impl Transform for GeometryStatsTransform {
     fn transform(&mut self, _feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
@@ -22,24 +102,23 @@ impl Transform for GeometryStatsTransform {
             return;
         };
 
-        let mut max_h = f64::MIN;
-        let mut min_h = f64::MAX;
         {
             let geom_store = entity.geometry_store.read().unwrap();
+            let mut max_h = f64::MIN;
+            let mut min_h = f64::MAX;
             geom_store.vertices.iter().for_each(|&v| {
                 let [_lng, _lat, h] = v;
                 max_h = max_h.max(h);
                 min_h = min_h.min(h);
             });
-        }
 
-        if max_h != f64::MIN {
-            obj.attributes
-                .insert("maxHeight".to_string(), Value::Double(max_h));
-        }
-        if min_h != f64::MAX {
-            obj.attributes
-                .insert("minHeight".to_string(), Value::Double(min_h));
+            if min_h != f64::MAX {
+                // (the lowest vertex of the entity is the ground of the nested features too)
+                self.add_attributes(obj, [min_h, max_h], min_h);
+                for value in obj.attributes.values_mut() {
+                    self.add_nested_attributes(value, &geom_store, min_h);
+                }
+            }
         }
 
         out.push(entity);
@@ -57,9 +136,143 @@ impl Transform for GeometryStatsTransform {
                         "minHeight".to_string(),
                         nusamai_citygml::schema::Attribute::new(TypeRef::Double),
                     );
+                    if self.extrusion_heights {
+                        for name in EXTRUSION_ATTRIBUTES {
+                            attributes.insert(
+                                name.to_string(),
+                                nusamai_citygml::schema::Attribute::new(TypeRef::Double),
+                            );
+                        }
+                    }
                 }
                 TypeDef::Data(_) | TypeDef::Property(_) => {}
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::{
+        geometry::{GeometryRef, GeometryType},
+        object::Map,
+        values::Measure,
+    };
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    /// A building on the ground at 10m (up to 25m), with a part from 20m to 40m
+    fn building() -> Entity {
+        let mut geoms = GeometryStore {
+            epsg: 6697,
+            vertices: vec![
+                [139.0, 35.0, 10.0],
+                [139.1, 35.0, 10.0],
+                [139.1, 35.0, 25.0],
+                [139.0, 35.0, 20.0],
+                [139.1, 35.0, 20.0],
+                [139.1, 35.0, 40.0],
+            ],
+            ..Default::default()
+        };
+        geoms.multipolygon.add_exterior([0, 1, 2]);
+        geoms.multipolygon.add_exterior([3, 4, 5]);
+
+        let geometries = |pos: u32| {
+            vec![GeometryRef {
+                ty: GeometryType::Solid,
+                lod: 1,
+                pos,
+                len: 1,
+            }]
+        };
+        let mut part_attributes = Map::default();
+        part_attributes.insert(
+            "bldg:measuredHeight".into(),
+            Value::Measure(Measure::new(15.0)),
+        );
+        let part = Value::Object(Object {
+            typename: "bldg:BuildingPart".into(),
+            stereotype: ObjectStereotype::Feature {
+                id: "part_1".into(),
+                geometries: geometries(1),
+            },
+            attributes: part_attributes,
+        });
+        let mut attributes = Map::default();
+        attributes.insert(
+            "bldg:consistsOfBuildingPart".into(),
+            Value::Array(vec![part]),
+        );
+
+        Entity {
+            root: Value::Object(Object {
+                typename: "bldg:Building".into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: "bldg_1".into(),
+                    geometries: geometries(0),
+                },
+                attributes,
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(geoms).into(),
+            appearance_store: Default::default(),
+        }
+    }
+
+    fn double(obj: &Object, name: &str) -> f64 {
+        let Some(Value::Double(v)) = obj.attributes.get(name) else {
+            panic!("{name} is not found");
+        };
+        *v
+    }
+
+    fn transform(mut transform: GeometryStatsTransform) -> Object {
+        let (_, feedback, _) = feedback::watcher();
+        let mut out = Vec::new();
+        transform.transform(&feedback, building(), &mut out);
+        let Value::Object(obj) = out.pop().unwrap().root else {
+            unreachable!()
+        };
+        obj
+    }
+
+    #[test]
+    fn test_min_max_heights() {
+        let obj = transform(GeometryStatsTransform::default());
+        assert_eq!(double(&obj, "minHeight"), 10.0);
+        assert_eq!(double(&obj, "maxHeight"), 40.0);
+        assert!(!obj.attributes.contains_key("height"));
+
+        let Value::Array(parts) = &obj.attributes["bldg:consistsOfBuildingPart"] else {
+            unreachable!()
+        };
+        let Value::Object(part) = &parts[0] else {
+            unreachable!()
+        };
+        assert_eq!(double(part, "minHeight"), 20.0);
+        assert_eq!(double(part, "maxHeight"), 40.0);
+    }
+
+    #[test]
+    fn test_extrusion_heights() {
+        let obj = transform(GeometryStatsTransform::with_extrusion_heights());
+        assert_eq!(double(&obj, "ground_elevation"), 10.0);
+        assert_eq!(double(&obj, "min_height"), 0.0);
+        assert_eq!(double(&obj, "height"), 30.0);
+
+        // the part is raised from the ground, with its measured height
+        let Value::Array(parts) = &obj.attributes["bldg:consistsOfBuildingPart"] else {
+            unreachable!()
+        };
+        let Value::Object(part) = &parts[0] else {
+            unreachable!()
+        };
+        assert_eq!(double(part, "ground_elevation"), 10.0);
+        assert_eq!(double(part, "min_height"), 10.0);
+        assert_eq!(double(part, "height"), 25.0);
+    }
+}
@@ -1,24 +1,44 @@
+mod adjacency;
 mod appearance;
 mod attrname;
+mod attrsize;
+mod axisorder;
+mod codes;
 mod dots;
 pub mod flatten;
 mod geommerge;
 mod geomstats;
 mod jsonify;
 mod lods;
+mod names;
 mod projection;
+mod solar;
+mod surface_class;
+mod texture_sources;
+mod underground;
+mod vegetation;
 
+pub use adjacency::*;
 pub use appearance::*;
 pub use attrname::*;
+pub use attrsize::*;
+pub use axisorder::*;
+pub use codes::*;
 pub use dots::*;
 pub use flatten::*;
 pub use geommerge::*;
 pub use geomstats::*;
 pub use jsonify::*;
 pub use lods::*;
+pub use names::*;
 use nusamai_citygml::schema::Schema;
 use nusamai_plateau::Entity;
 pub use projection::*;
+pub use solar::*;
+pub use surface_class::*;
+pub use texture_sources::*;
+pub use underground::*;
+pub use vegetation::*;
 
 use super::Transform;
 use crate::pipeline::Feedback;
@@ -0,0 +1,200 @@
+use nusamai_citygml::{
+    object::{Map, Object, Value},
+    schema::{Attribute, Schema, TypeDef},
+};
+use nusamai_plateau::Entity;
+
+use crate::{pipeline::Feedback, transformer::Transform};
+
+const NAME_KEY: &str = "gml:name";
+
+/// Maximum number of the name columns (`name`, `name_2`, ..., `name_5`)
+pub const MAX_NAME_COLUMNS: usize = 5;
+
+/// Expands the `gml:name` array into the single-valued columns `gml:name`, `gml:name_2`, ...
+/// in the original order, for the sinks that cannot hold arrays in their columns.
+#[derive(Default, Clone)]
+pub struct NameColumnsTransform {
+    warned: bool,
+}
+
+impl Transform for NameColumnsTransform {
+    fn transform(&mut self, feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
+        let mut dropped = 0;
+        expand_names(&mut entity.root, &mut dropped);
+        if dropped > 0 && !self.warned {
+            self.warned = true;
+            feedback.warn(format!(
+                "Some features have more than {MAX_NAME_COLUMNS} gml:name entries, and the rest of them are not output"
+            ));
+        }
+        out.push(entity);
+    }
+
+    fn transform_schema(&self, schema: &mut Schema) {
+        for ty in schema.types.values_mut() {
+            let attributes = match ty {
+                TypeDef::Feature(feature) => &mut feature.attributes,
+                TypeDef::Data(data) => &mut data.attributes,
+                TypeDef::Property(_) => continue,
+            };
+            let Some(attr) = attributes.get(NAME_KEY) else {
+                continue;
+            };
+            let column = Attribute {
+                min_occurs: 0,
+                max_occurs: Some(1),
+                ..attr.clone()
+            };
+
+            let mut expanded = nusamai_citygml::schema::Map::default();
+            for (key, attr) in attributes.drain(..) {
+                if key != NAME_KEY {
+                    expanded.insert(key, attr);
+                    continue;
+                }
+                for n in 1..=MAX_NAME_COLUMNS {
+                    expanded.insert(column_name(n), column.clone());
+                }
+            }
+            *attributes = expanded;
+        }
+    }
+}
+
+/// `gml:name`, `gml:name_2`, `gml:name_3`, ... (`n` starts from 1)
+fn column_name(n: usize) -> String {
+    match n {
+        1 => NAME_KEY.to_string(),
+        n => format!("{NAME_KEY}_{n}"),
+    }
+}
+
+fn expand_names(value: &mut Value, dropped: &mut usize) {
+    match value {
+        Value::Object(obj) => {
+            for value in obj.attributes.values_mut() {
+                expand_names(value, dropped);
+            }
+            expand_object_names(obj, dropped);
+        }
+        Value::Array(arr) => {
+            for value in arr.iter_mut() {
+                expand_names(value, dropped);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn expand_object_names(obj: &mut Object, dropped: &mut usize) {
+    let Some(Value::Array(names)) = obj.attributes.get(NAME_KEY) else {
+        return;
+    };
+    let mut names = names.clone().into_iter();
+
+    let mut attributes = Map::default();
+    for (key, value) in obj.attributes.drain(..) {
+        if key != NAME_KEY {
+            attributes.insert(key, value);
+            continue;
+        }
+        for (n, name) in (1..=MAX_NAME_COLUMNS).zip(names.by_ref()) {
+            attributes.insert(column_name(n), name);
+        }
+    }
+    *dropped += names.len();
+    obj.attributes = attributes;
+}
+
+#[cfg(test)]
+mod tests {
+    use nusamai_citygml::{object::ObjectStereotype, schema::TypeRef, Code};
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    fn name(value: &str, code_space: Option<&str>) -> Value {
+        Value::Code(
+            Code::new(value.into(), value.into()).with_code_space(code_space.map(String::from)),
+        )
+    }
+
+    #[test]
+    fn test_expand_names() {
+        let mut attributes = Map::default();
+        attributes.insert("gml:description".into(), Value::String("desc".into()));
+        attributes.insert(
+            NAME_KEY.into(),
+            Value::Array(vec![name("東京都庁", Some("ja")), name("Tokyo", None)]),
+        );
+        attributes.insert("bldg:class".into(), Value::String("class".into()));
+        let entity = Entity {
+            root: Value::Object(Object {
+                typename: "bldg:Building".into(),
+                attributes,
+                stereotype: ObjectStereotype::Feature {
+                    id: "bldg_1".into(),
+                    geometries: Default::default(),
+                },
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: Default::default(),
+            appearance_store: Default::default(),
+        };
+
+        let (_, feedback, _) = feedback::watcher();
+        let mut out = Vec::new();
+        NameColumnsTransform::default().transform(&feedback, entity, &mut out);
+
+        let Value::Object(obj) = &out[0].root else {
+            unreachable!();
+        };
+        let keys: Vec<_> = obj.attributes.keys().map(String::as_str).collect();
+        assert_eq!(
+            keys,
+            ["gml:description", "gml:name", "gml:name_2", "bldg:class"]
+        );
+        assert_eq!(obj.attributes["gml:name"], name("東京都庁", Some("ja")));
+        assert_eq!(obj.attributes["gml:name_2"], name("Tokyo", None));
+    }
+
+    #[test]
+    fn test_expand_names_schema() {
+        let mut feature = nusamai_citygml::schema::FeatureTypeDef::default();
+        feature.attributes.insert(
+            NAME_KEY.into(),
+            Attribute {
+                max_occurs: None,
+                ..Attribute::new(TypeRef::Code)
+            },
+        );
+        feature
+            .attributes
+            .insert("bldg:class".into(), Attribute::new(TypeRef::Code));
+        let mut schema = Schema::default();
+        schema
+            .types
+            .insert("bldg:Building".into(), TypeDef::Feature(feature));
+
+        NameColumnsTransform::default().transform_schema(&mut schema);
+
+        let TypeDef::Feature(feature) = &schema.types["bldg:Building"] else {
+            unreachable!();
+        };
+        let keys: Vec<_> = feature.attributes.keys().map(String::as_str).collect();
+        assert_eq!(
+            keys,
+            [
+                "gml:name",
+                "gml:name_2",
+                "gml:name_3",
+                "gml:name_4",
+                "gml:name_5",
+                "bldg:class"
+            ]
+        );
+        assert_eq!(feature.attributes["gml:name_2"].max_occurs, Some(1));
+        assert_eq!(feature.attributes["gml:name_2"].type_ref, TypeRef::Code);
+    }
+}
@@ -0,0 +1,345 @@
+use nusamai_citygml::{
+    geometry::{GeometryRef, GeometryStore, GeometryType},
+    object::{Object, ObjectStereotype, Value},
+    schema::{Attribute, Schema, TypeDef, TypeRef},
+};
+use nusamai_plateau::Entity;
+
+use super::surface_class::{horizontal_scale, ring_normal};
+use crate::{pipeline::Feedback, transformer::Transform};
+
+const BUILDING_TYPES: [&str; 2] = ["bldg:Building", "bldg:BuildingPart"];
+const ROOF_SURFACE: &str = "bldg:RoofSurface";
+
+/// Roofs with gentler slopes than this (degrees) are regarded as flat, and have no azimuth
+const FLAT_MAX_SLOPE: f64 = 10.0;
+/// Roofs steeper than this (degrees) are not suitable for solar panels
+const SUITABLE_MAX_SLOPE: f64 = 60.0;
+/// Sloped roofs facing this range of azimuths (degrees, clockwise from north) are suitable for solar panels
+const SUITABLE_AZIMUTHS: std::ops::RangeInclusive<f64> = 90.0..=270.0;
+
+/// Computes the area, slope and azimuth of the roof polygons (`bldg:RoofSurface`),
+/// and aggregates them per building for the screening of the rooftop solar potential.
+///
+/// Attributes of the roof surfaces:
+/// - `area`: area (m²)
+/// - `slope`: area-weighted mean slope (degrees)
+/// - `azimuth`: area-weighted mean azimuth of the sloped polygons (degrees, clockwise from north)
+///
+/// Attributes of the buildings (and the building parts):
+/// - `roof_area`, `roof_slope`, `roof_azimuth`: the same as above for all the roofs
+/// - `roof_suitable_area`: area of the flat roofs and the roofs facing east to west through south (m²)
+///
+/// The horizontal coordinates must be in meters or degrees (geographic CRS).
+#[derive(Clone, Default)]
+pub struct SolarAttributesTransform {}
+
+impl Transform for SolarAttributesTransform {
+    fn transform(&mut self, _feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
+        {
+            let geom_store = entity.geometry_store.read().unwrap();
+            let scale = horizontal_scale(&geom_store);
+            process_tree(&geom_store, scale, &mut entity.root);
+        }
+        out.push(entity);
+    }
+
+    fn transform_schema(&self, schema: &mut Schema) {
+        for (typename, ty) in schema.types.iter_mut() {
+            let TypeDef::Feature(feature) = ty else {
+                continue;
+            };
+            let names: &[&str] = if BUILDING_TYPES.contains(&typename.as_str()) {
+                &[
+                    "roof_area",
+                    "roof_suitable_area",
+                    "roof_slope",
+                    "roof_azimuth",
+                ]
+            } else if typename == ROOF_SURFACE {
+                &["area", "slope", "azimuth"]
+            } else {
+                continue;
+            };
+            for name in names {
+                feature
+                    .attributes
+                    .insert(name.to_string(), Attribute::new(TypeRef::Double));
+            }
+        }
+    }
+}
+
+/// Area-weighted statistics of the roof polygons
+#[derive(Default, Debug)]
+struct RoofStats {
+    area: f64,
+    suitable_area: f64,
+    slope_sum: f64,
+    sloped_area: f64,
+    /// Sum of the unit vectors of the azimuths weighted by the areas
+    azimuth_vec: [f64; 2],
+}
+
+impl RoofStats {
+    fn add_polygon(&mut self, normal: [f64; 3]) {
+        let [mut nx, mut ny, mut nz] = normal;
+        let norm = (nx * nx + ny * ny + nz * nz).sqrt();
+        if norm == 0.0 {
+            return;
+        }
+        if nz < 0.0 {
+            // the orientation of the ring is reversed
+            (nx, ny, nz) = (-nx, -ny, -nz);
+        }
+        let area = norm / 2.0;
+        let slope = (nz / norm).clamp(-1.0, 1.0).acos().to_degrees();
+
+        self.area += area;
+        self.slope_sum += slope * area;
+        if slope < FLAT_MAX_SLOPE {
+            self.suitable_area += area;
+            return;
+        }
+
+        let azimuth = azimuth(nx, ny);
+        self.sloped_area += area;
+        self.azimuth_vec[0] += azimuth.to_radians().sin() * area;
+        self.azimuth_vec[1] += azimuth.to_radians().cos() * area;
+        if slope <= SUITABLE_MAX_SLOPE && SUITABLE_AZIMUTHS.contains(&azimuth) {
+            self.suitable_area += area;
+        }
+    }
+
+    fn merge(&mut self, other: &RoofStats) {
+        self.area += other.area;
+        self.suitable_area += other.suitable_area;
+        self.slope_sum += other.slope_sum;
+        self.sloped_area += other.sloped_area;
+        self.azimuth_vec[0] += other.azimuth_vec[0];
+        self.azimuth_vec[1] += other.azimuth_vec[1];
+    }
+
+    fn slope(&self) -> Option<f64> {
+        (self.area > 0.0).then(|| self.slope_sum / self.area)
+    }
+
+    /// None if there are no sloped roofs or they have no dominant direction
+    fn azimuth(&self) -> Option<f64> {
+        let [east, north] = self.azimuth_vec;
+        (east.hypot(north) > self.sloped_area * 1e-6).then(|| azimuth(east, north))
+    }
+}
+
+/// Azimuth of the horizontal vector (degrees, clockwise from north)
+fn azimuth(east: f64, north: f64) -> f64 {
+    east.atan2(north).to_degrees().rem_euclid(360.0)
+}
+
+fn process_tree(geom_store: &GeometryStore, scale: [f64; 2], value: &mut Value) {
+    match value {
+        Value::Object(obj) => {
+            if BUILDING_TYPES.contains(&obj.typename.as_ref()) {
+                process_building(geom_store, scale, obj);
+            }
+            for (_, value) in obj.attributes.iter_mut() {
+                process_tree(geom_store, scale, value);
+            }
+        }
+        Value::Array(arr) => {
+            for value in arr.iter_mut() {
+                process_tree(geom_store, scale, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn process_building(geom_store: &GeometryStore, scale: [f64; 2], building: &mut Object) {
+    let mut building_stats = RoofStats::default();
+
+    if let Some(Value::Array(surfaces)) = building.attributes.get_mut("bldg:boundedBy") {
+        for surface in surfaces.iter_mut() {
+            let Value::Object(surface) = surface else {
+                continue;
+            };
+            if surface.typename != ROOF_SURFACE {
+                continue;
+            }
+            let ObjectStereotype::Feature { geometries, .. } = &surface.stereotype else {
+                continue;
+            };
+
+            let stats = roof_stats(geom_store, scale, geometries);
+            if stats.area == 0.0 {
+                continue;
+            }
+            insert_stats(surface, &stats, "");
+            building_stats.merge(&stats);
+        }
+    }
+
+    if building_stats.area > 0.0 {
+        insert_stats(building, &building_stats, "roof_");
+        building.attributes.insert(
+            "roof_suitable_area".to_string(),
+            Value::Double(building_stats.suitable_area),
+        );
+    }
+}
+
+fn roof_stats(
+    geom_store: &GeometryStore,
+    scale: [f64; 2],
+    geometries: &[GeometryRef],
+) -> RoofStats {
+    let mut stats = RoofStats::default();
+    for geom in geometries {
+        if !matches!(
+            geom.ty,
+            GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+        ) {
+            continue;
+        }
+        for poly in geom_store
+            .multipolygon
+            .iter_range(geom.pos as usize..(geom.pos + geom.len) as usize)
+        {
+            // the interior rings are expected to have the opposite orientation
+            let mut normal = [0.0; 3];
+            for ring in poly.rings() {
+                let n = ring_normal(&geom_store.vertices, ring.iter(), scale);
+                for (sum, v) in normal.iter_mut().zip(n) {
+                    *sum += v;
+                }
+            }
+            stats.add_polygon(normal);
+        }
+    }
+    stats
+}
+
+fn insert_stats(obj: &mut Object, stats: &RoofStats, prefix: &str) {
+    obj.attributes
+        .insert(format!("{prefix}area"), Value::Double(stats.area));
+    if let Some(slope) = stats.slope() {
+        obj.attributes
+            .insert(format!("{prefix}slope"), Value::Double(slope));
+    }
+    if let Some(azimuth) = stats.azimuth() {
+        obj.attributes
+            .insert(format!("{prefix}azimuth"), Value::Double(azimuth));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::object::Map;
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    /// A gable roof (10m x 10m in plan, ridge running east-west, 5m high)
+    fn building() -> Entity {
+        let mut geoms = GeometryStore {
+            epsg: 6677,
+            vertices: vec![
+                [0., 0., 0.],
+                [10., 0., 0.],
+                [10., 5., 5.],
+                [0., 5., 5.],
+                [10., 10., 0.],
+                [0., 10., 0.],
+            ],
+            ..Default::default()
+        };
+        // south-facing
+        geoms.multipolygon.add_exterior([0, 1, 2, 3]);
+        // north-facing
+        geoms.multipolygon.add_exterior([3, 2, 4, 5]);
+
+        let roof = |id: &str, pos: u32| {
+            Value::Object(Object {
+                typename: ROOF_SURFACE.into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: id.into(),
+                    geometries: vec![GeometryRef {
+                        ty: GeometryType::Surface,
+                        lod: 2,
+                        pos,
+                        len: 1,
+                    }],
+                },
+                attributes: Map::default(),
+            })
+        };
+        let mut attributes = Map::default();
+        attributes.insert(
+            "bldg:boundedBy".into(),
+            Value::Array(vec![roof("south", 0), roof("north", 1)]),
+        );
+
+        Entity {
+            root: Value::Object(Object {
+                typename: "bldg:Building".into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: "bldg_1".into(),
+                    geometries: vec![],
+                },
+                attributes,
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(geoms).into(),
+            appearance_store: Default::default(),
+        }
+    }
+
+    fn double(obj: &Object, name: &str) -> f64 {
+        let Some(Value::Double(v)) = obj.attributes.get(name) else {
+            panic!("{name} is not found");
+        };
+        *v
+    }
+
+    #[test]
+    fn gable_roof() {
+        let (_, feedback, _) = feedback::watcher();
+        let mut out = Vec::new();
+        SolarAttributesTransform::default().transform(&feedback, building(), &mut out);
+
+        let Value::Object(obj) = &out[0].root else {
+            unreachable!()
+        };
+        let Value::Array(surfaces) = &obj.attributes["bldg:boundedBy"] else {
+            unreachable!()
+        };
+        let Value::Object(south) = &surfaces[0] else {
+            unreachable!()
+        };
+        let Value::Object(north) = &surfaces[1] else {
+            unreachable!()
+        };
+
+        let area = 10.0 * 50f64.sqrt();
+        assert!((double(south, "area") - area).abs() < 1e-9);
+        assert!((double(south, "slope") - 45.0).abs() < 1e-9);
+        assert!((double(south, "azimuth") - 180.0).abs() < 1e-9);
+        assert!(double(north, "azimuth").abs() < 1e-9);
+
+        assert!((double(obj, "roof_area") - area * 2.0).abs() < 1e-9);
+        assert!((double(obj, "roof_suitable_area") - area).abs() < 1e-9);
+        assert!((double(obj, "roof_slope") - 45.0).abs() < 1e-9);
+        // the azimuths cancel each other out
+        assert!(!obj.attributes.contains_key("roof_azimuth"));
+    }
+
+    #[test]
+    fn azimuths() {
+        assert_eq!(azimuth(0., 1.), 0.);
+        assert_eq!(azimuth(1., 0.), 90.);
+        assert_eq!(azimuth(0., -1.), 180.);
+        assert_eq!(azimuth(-1., 0.), 270.);
+    }
+}
@@ -0,0 +1,464 @@
+use flatgeom::MultiPolygon;
+use nusamai_citygml::{
+    geometry::{GeometryRef, GeometryStore, GeometryType},
+    object::{Map, Object, ObjectStereotype, Value},
+    schema::Schema,
+    Color,
+};
+use nusamai_plateau::{
+    appearance::{AppearanceStore, Material},
+    Entity,
+};
+use nusamai_projection::crs::{EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_WGS84_GEOGRAPHIC_3D};
+
+use crate::{pipeline::Feedback, transformer::Transform};
+
+/// Features that can be bounded by roof/wall/ground surfaces
+const CLASSIFIABLE_TYPES: [&str; 6] = [
+    "bldg:Building",
+    "bldg:BuildingPart",
+    "brid:Bridge",
+    "brid:BridgePart",
+    "tun:Tunnel",
+    "tun:TunnelPart",
+];
+
+/// Polygons whose normals are closer to the horizontal than this (|z| of the unit normal) are walls
+const WALL_MAX_NORMAL_Z: f64 = 0.2;
+
+/// How to use the classified surfaces
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SurfaceClassMode {
+    /// Add the roof, wall and ground surfaces as the thematic surfaces of the feature
+    Tag,
+    /// Keep only the roof surfaces
+    RoofOnly,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SurfaceClass {
+    Roof,
+    Wall,
+    Ground,
+}
+
+impl SurfaceClass {
+    const ALL: [SurfaceClass; 3] = [Self::Roof, Self::Wall, Self::Ground];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Roof => "RoofSurface",
+            Self::Wall => "WallSurface",
+            Self::Ground => "GroundSurface",
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Self::Roof => "roof",
+            Self::Wall => "wall",
+            Self::Ground => "ground",
+        }
+    }
+
+    /// Default color for the polygons without materials
+    fn color(&self) -> Color {
+        match self {
+            Self::Roof => Color::new(0.7, 0.4, 0.35),
+            Self::Wall => Color::new(0.9, 0.9, 0.88),
+            Self::Ground => Color::new(0.45, 0.45, 0.45),
+        }
+    }
+}
+
+/// Classifies the polygons of the features without semantic surfaces (e.g. LOD1 buildings)
+/// into roofs, walls and grounds by their normal directions.
+///
+/// The classified polygons are added to the feature as `{prefix}:boundedBy` thematic surfaces
+/// (e.g. `bldg:RoofSurface`), so that they can be filtered and styled like the surfaces of LOD2 data.
+#[derive(Clone)]
+pub struct ClassifySurfacesTransform {
+    mode: SurfaceClassMode,
+}
+
+impl ClassifySurfacesTransform {
+    pub fn new(mode: SurfaceClassMode) -> Self {
+        Self { mode }
+    }
+}
+
+impl Transform for ClassifySurfacesTransform {
+    fn transform(&mut self, _feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
+        {
+            let mut geom_store = entity.geometry_store.write().unwrap();
+            let mut app = entity.appearance_store.write().unwrap();
+            let mut class_materials = [None; 3];
+            let mut ctx = Context {
+                mode: self.mode,
+                geom_store: &mut geom_store,
+                app: &mut app,
+                class_materials: &mut class_materials,
+            };
+            classify_tree(&mut ctx, &mut entity.root);
+        }
+        out.push(entity);
+    }
+
+    fn transform_schema(&self, _schema: &mut Schema) {
+        // The thematic surface types are already defined in the schema
+    }
+}
+
+struct Context<'a> {
+    mode: SurfaceClassMode,
+    geom_store: &'a mut GeometryStore,
+    app: &'a mut AppearanceStore,
+    /// Materials added for each class (see `SurfaceClass::color`)
+    class_materials: &'a mut [Option<u32>; 3],
+}
+
+fn classify_tree(ctx: &mut Context, value: &mut Value) {
+    match value {
+        Value::Object(obj) => {
+            classify_object(ctx, obj);
+            for (_, value) in obj.attributes.iter_mut() {
+                classify_tree(ctx, value);
+            }
+        }
+        Value::Array(arr) => {
+            for value in arr.iter_mut() {
+                classify_tree(ctx, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn classify_object(ctx: &mut Context, obj: &mut Object) {
+    if !CLASSIFIABLE_TYPES.contains(&obj.typename.as_ref()) {
+        return;
+    }
+    let Some((prefix, _)) = obj.typename.split_once(':') else {
+        return;
+    };
+    let bounded_by = format!("{prefix}:boundedBy");
+    if obj.attributes.contains_key(&bounded_by) {
+        // the feature already has the semantic surfaces
+        return;
+    }
+    let ObjectStereotype::Feature { id, geometries } = &mut obj.stereotype else {
+        return;
+    };
+    if !geometries.iter().any(|geom| is_polygonal(geom.ty)) {
+        return;
+    }
+
+    let geom_store = &mut *ctx.geom_store;
+    let with_appearance = geom_store.polygon_materials.len() == geom_store.multipolygon.len();
+    let ring_offsets = ring_offsets(&geom_store.multipolygon);
+    let scale = horizontal_scale(geom_store);
+
+    // geometries of the surfaces of each class
+    let mut surfaces: [Vec<GeometryRef>; 3] = Default::default();
+    let mut new_polygons = MultiPolygon::new();
+    let mut new_uvs = MultiPolygon::new();
+    let mut new_ring_ids = Vec::new();
+    let mut new_materials = Vec::new();
+    let mut new_textures = Vec::new();
+    let base = geom_store.multipolygon.len() as u32;
+
+    let (polygonal, others): (Vec<_>, Vec<_>) =
+        geometries.drain(..).partition(|geom| is_polygonal(geom.ty));
+    *geometries = others;
+
+    for (class_idx, class) in SurfaceClass::ALL.iter().enumerate() {
+        if ctx.mode == SurfaceClassMode::RoofOnly && *class != SurfaceClass::Roof {
+            continue;
+        }
+        for geom in &polygonal {
+            let pos = base + new_polygons.len() as u32;
+            for idx in geom.pos..geom.pos + geom.len {
+                let poly = geom_store.multipolygon.get(idx as usize);
+                if classify_polygon(&geom_store.vertices, poly.exterior().iter(), scale) != *class {
+                    continue;
+                }
+                new_polygons.push(&poly);
+                let ring_start = ring_offsets[idx as usize];
+                let num_rings = poly.rings().count();
+                match geom_store.ring_ids.get(ring_start..ring_start + num_rings) {
+                    Some(ring_ids) => new_ring_ids.extend_from_slice(ring_ids),
+                    None => new_ring_ids.extend(std::iter::repeat(None).take(num_rings)),
+                }
+                if with_appearance {
+                    let material = geom_store.polygon_materials[idx as usize];
+                    let texture = geom_store.polygon_textures[idx as usize];
+                    let material = match (ctx.mode, material, texture) {
+                        (SurfaceClassMode::Tag, None, None) => {
+                            Some(*ctx.class_materials[class_idx].get_or_insert_with(|| {
+                                ctx.app.materials.push(Material {
+                                    diffuse_color: class.color(),
+                                    ..Default::default()
+                                });
+                                ctx.app.materials.len() as u32 - 1
+                            }))
+                        }
+                        _ => material,
+                    };
+                    new_materials.push(material);
+                    new_textures.push(texture);
+                    new_uvs.push(&geom_store.polygon_uvs.get(idx as usize));
+                }
+            }
+            let len = base + new_polygons.len() as u32 - pos;
+            if len > 0 {
+                surfaces[class_idx].push(GeometryRef {
+                    ty: GeometryType::Surface,
+                    lod: geom.lod,
+                    pos,
+                    len,
+                });
+            }
+        }
+    }
+
+    for poly in &new_polygons {
+        geom_store.multipolygon.push(&poly);
+    }
+    geom_store.ring_ids.extend(new_ring_ids);
+    if with_appearance {
+        for uv in &new_uvs {
+            geom_store.polygon_uvs.push(&uv);
+        }
+        geom_store.polygon_materials.extend(new_materials);
+        geom_store.polygon_textures.extend(new_textures);
+    }
+
+    let children: Vec<Value> = SurfaceClass::ALL
+        .iter()
+        .zip(surfaces)
+        .filter(|(_, geometries)| !geometries.is_empty())
+        .map(|(class, geometries)| {
+            Value::Object(Object {
+                typename: format!("{prefix}:{}", class.name()).into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: format!("{id}_{}", class.suffix()),
+                    geometries,
+                },
+                attributes: Map::default(),
+            })
+        })
+        .collect();
+    if !children.is_empty() {
+        obj.attributes.insert(bounded_by, Value::Array(children));
+    }
+}
+
+fn is_polygonal(ty: GeometryType) -> bool {
+    matches!(
+        ty,
+        GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle
+    )
+}
+
+/// Index of the first ring of each polygon (in `ring_ids`)
+fn ring_offsets(mpoly: &MultiPolygon<u32>) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(mpoly.len());
+    let mut offset = 0;
+    for poly in mpoly {
+        offsets.push(offset);
+        offset += poly.rings().count();
+    }
+    offsets
+}
+
+/// Scale factors to convert the horizontal coordinates into meters (approximately)
+pub(super) fn horizontal_scale(geom_store: &GeometryStore) -> [f64; 2] {
+    match geom_store.epsg {
+        EPSG_JGD2011_GEOGRAPHIC_3D | EPSG_WGS84_GEOGRAPHIC_3D => {
+            let lat = geom_store.vertices.first().map_or(0.0, |v| v[1]);
+            [111_320.0 * lat.to_radians().cos(), 111_320.0]
+        }
+        _ => [1.0, 1.0],
+    }
+}
+
+/// Normal vector of the ring by Newell's method (in the scaled coordinates).
+/// The length of the vector is twice the area of the ring.
+pub(super) fn ring_normal(
+    vertices: &[[f64; 3]],
+    ring: impl Iterator<Item = u32>,
+    [sx, sy]: [f64; 2],
+) -> [f64; 3] {
+    let ring: Vec<[f64; 3]> = ring
+        .map(|idx| {
+            let [x, y, z] = vertices[idx as usize];
+            [x * sx, y * sy, z]
+        })
+        .collect();
+    let mut normal = [0.0; 3];
+    for i in 0..ring.len() {
+        let [x0, y0, z0] = ring[i];
+        let [x1, y1, z1] = ring[(i + 1) % ring.len()];
+        normal[0] += (y0 - y1) * (z0 + z1);
+        normal[1] += (z0 - z1) * (x0 + x1);
+        normal[2] += (x0 - x1) * (y0 + y1);
+    }
+    normal
+}
+
+/// Classifies the polygon by its normal, assuming the counter-clockwise (outward) orientation
+fn classify_polygon(
+    vertices: &[[f64; 3]],
+    ring: impl Iterator<Item = u32>,
+    scale: [f64; 2],
+) -> SurfaceClass {
+    let normal = ring_normal(vertices, ring, scale);
+    let norm = (normal[0].powi(2) + normal[1].powi(2) + normal[2].powi(2)).sqrt();
+    if norm == 0.0 {
+        // degenerate polygon
+        return SurfaceClass::Wall;
+    }
+
+    let nz = normal[2] / norm;
+    if nz > WALL_MAX_NORMAL_Z {
+        SurfaceClass::Roof
+    } else if nz < -WALL_MAX_NORMAL_Z {
+        SurfaceClass::Ground
+    } else {
+        SurfaceClass::Wall
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    /// A LOD1 box (2m x 2m x 3m)
+    fn lod1_building() -> Entity {
+        let mut geoms = GeometryStore {
+            epsg: 6677,
+            vertices: vec![
+                [0., 0., 0.],
+                [2., 0., 0.],
+                [2., 2., 0.],
+                [0., 2., 0.],
+                [0., 0., 3.],
+                [2., 0., 3.],
+                [2., 2., 3.],
+                [0., 2., 3.],
+            ],
+            ..Default::default()
+        };
+        for ring in [
+            [0, 3, 2, 1], // bottom
+            [4, 5, 6, 7], // top
+            [0, 1, 5, 4],
+            [1, 2, 6, 5],
+            [2, 3, 7, 6],
+            [3, 0, 4, 7],
+        ] {
+            geoms.multipolygon.add_exterior(ring);
+            geoms.ring_ids.push(None);
+        }
+
+        Entity {
+            root: Value::Object(Object {
+                typename: "bldg:Building".into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: "bldg_1".into(),
+                    geometries: vec![GeometryRef {
+                        ty: GeometryType::Solid,
+                        lod: 1,
+                        pos: 0,
+                        len: 6,
+                    }],
+                },
+                attributes: Map::default(),
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(geoms).into(),
+            appearance_store: Default::default(),
+        }
+    }
+
+    fn transform(mode: SurfaceClassMode) -> Entity {
+        let (_, feedback, _) = feedback::watcher();
+        let mut out = Vec::new();
+        ClassifySurfacesTransform::new(mode).transform(&feedback, lod1_building(), &mut out);
+        out.pop().unwrap()
+    }
+
+    fn surfaces(entity: &Entity) -> Vec<(String, String, u32)> {
+        let Value::Object(obj) = &entity.root else {
+            unreachable!()
+        };
+        let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype else {
+            unreachable!()
+        };
+        assert!(geometries.is_empty());
+        let Value::Array(children) = &obj.attributes["bldg:boundedBy"] else {
+            unreachable!()
+        };
+        children
+            .iter()
+            .map(|child| {
+                let Value::Object(child) = child else {
+                    unreachable!()
+                };
+                let ObjectStereotype::Feature { id, geometries } = &child.stereotype else {
+                    unreachable!()
+                };
+                (
+                    child.typename.to_string(),
+                    id.clone(),
+                    geometries.iter().map(|g| g.len).sum(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn classify_box() {
+        let entity = transform(SurfaceClassMode::Tag);
+        assert_eq!(
+            surfaces(&entity),
+            vec![
+                ("bldg:RoofSurface".into(), "bldg_1_roof".into(), 1),
+                ("bldg:WallSurface".into(), "bldg_1_wall".into(), 4),
+                ("bldg:GroundSurface".into(), "bldg_1_ground".into(), 1),
+            ]
+        );
+        let geoms = entity.geometry_store.read().unwrap();
+        assert_eq!(geoms.multipolygon.len(), 12);
+        assert_eq!(geoms.ring_ids.len(), 12);
+    }
+
+    #[test]
+    fn roof_only() {
+        let entity = transform(SurfaceClassMode::RoofOnly);
+        assert_eq!(
+            surfaces(&entity),
+            vec![("bldg:RoofSurface".into(), "bldg_1_roof".into(), 1)]
+        );
+    }
+
+    #[test]
+    fn skip_features_with_surfaces() {
+        let mut entity = lod1_building();
+        let Value::Object(obj) = &mut entity.root else {
+            unreachable!()
+        };
+        obj.attributes
+            .insert("bldg:boundedBy".into(), Value::Array(vec![]));
+
+        let (_, feedback, _) = feedback::watcher();
+        let mut out = Vec::new();
+        ClassifySurfacesTransform::new(SurfaceClassMode::Tag)
+            .transform(&feedback, entity, &mut out);
+        let geoms = out[0].geometry_store.read().unwrap();
+        assert_eq!(geoms.multipolygon.len(), 6);
+    }
+}
@@ -0,0 +1,288 @@
+//! Locating the texture images referenced by the appearances
+
+use std::{
+    io::Read,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use ahash::HashMap;
+use nusamai_plateau::appearance::Texture;
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{paths, pipeline::Feedback};
+
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_IMAGE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Resolves the texture images to the files on the local file system.
+///
+/// - `file:` URLs are used as they are if the file exists.
+/// - `http:` and `https:` URLs are downloaded once into the cache directory, and replaced with the URLs of the cached files.
+/// - Other URLs (e.g. `data:`) are not supported.
+///
+/// Therefore, the sinks can take the image URLs of the transformed entities as local file paths.
+/// The polygons whose images are not available fall back to their material color (or the default color),
+/// and the unavailable images are reported at once by [`TextureSources::report`].
+pub struct TextureSources {
+    cache_dir: PathBuf,
+    // the local URL of each image (None if not available), resolved once for each URL
+    resolved: Mutex<HashMap<Url, Arc<OnceLock<Option<Url>>>>>,
+    dropped_polygons: AtomicUsize,
+}
+
+impl Default for TextureSources {
+    fn default() -> Self {
+        Self::with_cache_dir(crate::workdir::work_dir().join("textures"))
+    }
+}
+
+impl TextureSources {
+    pub fn with_cache_dir(cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            cache_dir: cache_dir.into(),
+            resolved: Default::default(),
+            dropped_polygons: Default::default(),
+        }
+    }
+
+    /// Replaces the image URLs of the textures with the local ones.
+    ///
+    /// Returns whether each texture is available.
+    pub fn localize(&self, feedback: &Feedback, textures: &mut [Texture]) -> Vec<bool> {
+        textures
+            .iter_mut()
+            .map(|texture| match self.resolve(feedback, &texture.image_url) {
+                Some(url) => {
+                    texture.image_url = url;
+                    true
+                }
+                None => false,
+            })
+            .collect()
+    }
+
+    /// Removes the unavailable textures from the polygons
+    pub fn drop_unavailable(&self, available: &[bool], polygon_textures: &mut [Option<u32>]) {
+        let mut dropped = 0;
+        for texture in polygon_textures.iter_mut() {
+            let Some(idx) = *texture else {
+                continue;
+            };
+            if !available.get(idx as usize).copied().unwrap_or(false) {
+                *texture = None;
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            self.dropped_polygons.fetch_add(dropped, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the local URL of an image (None if not available)
+    pub fn resolve(&self, feedback: &Feedback, url: &Url) -> Option<Url> {
+        let cell = self
+            .resolved
+            .lock()
+            .unwrap()
+            .entry(url.clone())
+            .or_default()
+            .clone();
+        // other threads referring to the same image wait for the download here
+        cell.get_or_init(|| match url.scheme() {
+            "file" => paths::url_to_path(url)
+                .is_some_and(|path| path.is_file())
+                .then(|| url.clone()),
+            "http" | "https" => match self.download(url) {
+                Ok(path) => paths::file_url(&path),
+                Err(err) => {
+                    feedback.debug(format!("Failed to download texture {url}: {err}"));
+                    None
+                }
+            },
+            _ => {
+                feedback.debug(format!("Unsupported texture URL: {url}"));
+                None
+            }
+        })
+        .clone()
+    }
+
+    fn download(&self, url: &Url) -> std::io::Result<PathBuf> {
+        let path = self.cache_path(url);
+        if paths::extended(&path).is_file() {
+            return Ok(path);
+        }
+
+        let response = ureq::get(url.as_str())
+            .timeout(DOWNLOAD_TIMEOUT)
+            .call()
+            .map_err(std::io::Error::other)?;
+        let mut content = Vec::new();
+        response
+            .into_reader()
+            .take(MAX_IMAGE_SIZE)
+            .read_to_end(&mut content)?;
+
+        // write to a temporary file first, so that an interrupted download is not taken as cached
+        let dir = paths::extended(path.parent().unwrap());
+        std::fs::create_dir_all(&dir)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(&dir)?;
+        std::io::Write::write_all(&mut tmp, &content)?;
+        tmp.persist(paths::extended(&path))
+            .map_err(|err| err.error)?;
+        Ok(path)
+    }
+
+    /// `{cache_dir}/{hash of the URL}/{decoded file name}`, keeping the original file name (e.g. Japanese names)
+    fn cache_path(&self, url: &Url) -> PathBuf {
+        let hash = Sha256::digest(url.as_str().as_bytes());
+        let dir = hash[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        self.cache_dir.join(dir).join(decoded_file_name(url))
+    }
+
+    /// Number of the unavailable images and the polygons which fell back to the material color
+    pub fn counts(&self) -> (usize, usize) {
+        let images = self
+            .resolved
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|cell| matches!(cell.get(), Some(None)))
+            .count();
+        (images, self.dropped_polygons.load(Ordering::Relaxed))
+    }
+
+    /// Sends a warning summarizing the unavailable images, if any
+    pub fn report(&self, feedback: &Feedback) {
+        let (images, polygons) = self.counts();
+        if images == 0 {
+            return;
+        }
+        let examples = {
+            let resolved = self.resolved.lock().unwrap();
+            let mut unavailable = resolved
+                .iter()
+                .filter(|(_, cell)| matches!(cell.get(), Some(None)))
+                .map(|(url, _)| display_url(url))
+                .collect::<Vec<_>>();
+            unavailable.sort_unstable();
+            unavailable.truncate(3);
+            unavailable.join(", ")
+        };
+        feedback.warn(format!(
+            "{images} texture image(s) were not found or could not be downloaded, and {polygons} polygon(s) are rendered with the material color instead (e.g. {examples})"
+        ));
+    }
+}
+
+/// The last path segment of the URL with the percent-encoding decoded (`画像.jpg` for `.../%E7%94%BB%E5%83%8F.jpg`)
+pub fn decoded_file_name(url: &Url) -> String {
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().to_string())
+        .unwrap_or_default();
+    // keep the name safe as a single path component
+    let name = name.replace(['/', '\\'], "_");
+    if name.is_empty() || name == "." || name == ".." {
+        "image".to_string()
+    } else {
+        name
+    }
+}
+
+/// URL for the messages, showing the local path as it is
+fn display_url(url: &Url) -> String {
+    match url.to_file_path() {
+        Ok(path) => path.display().to_string(),
+        Err(_) => percent_decode_str(url.as_str())
+            .decode_utf8_lossy()
+            .to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::feedback;
+
+    fn texture(url: Url) -> Texture {
+        Texture { image_url: url }
+    }
+
+    #[test]
+    fn missing_texture_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let found = dir.path().join("found.jpg");
+        std::fs::write(&found, b"").unwrap();
+
+        let mut textures = vec![
+            texture(Url::from_file_path(&found).unwrap()),
+            texture(Url::from_file_path(dir.path().join("missing.jpg")).unwrap()),
+            texture(Url::parse("data:image/png;base64,AAAA").unwrap()),
+        ];
+
+        let sources = TextureSources::with_cache_dir(dir.path().join("cache"));
+        let (watcher, feedback, _) = feedback::watcher();
+        let available = sources.localize(&feedback, &mut textures);
+        assert_eq!(available, vec![true, false, false]);
+
+        let mut polygon_textures = vec![Some(0), Some(1), None, Some(1), Some(2)];
+        sources.drop_unavailable(&available, &mut polygon_textures);
+        assert_eq!(polygon_textures, vec![Some(0), None, None, None, None]);
+        assert_eq!(sources.counts(), (2, 3));
+
+        sources.report(&feedback);
+        drop(feedback);
+        let messages = watcher.into_iter().collect::<Vec<_>>();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].message.contains("missing.jpg"));
+    }
+
+    #[test]
+    fn cached_remote_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = TextureSources::with_cache_dir(dir.path());
+
+        // an image already in the cache is used without downloading
+        let url = Url::parse("https://example.com/udx/bldg/%E7%94%BB%E5%83%8F.jpg").unwrap();
+        let cached = sources.cache_path(&url);
+        assert_eq!(cached.file_name().unwrap(), "画像.jpg");
+        std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        std::fs::write(&cached, b"").unwrap();
+
+        let mut textures = vec![texture(url)];
+        let (_, feedback, _) = feedback::watcher();
+        assert_eq!(sources.localize(&feedback, &mut textures), vec![true]);
+        assert_eq!(textures[0].image_url.to_file_path().unwrap(), cached);
+    }
+
+    #[test]
+    fn japanese_file_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("テクスチャ").join("画像 1.jpg");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"").unwrap();
+
+        // the image URI in the CityGML is resolved against the URL of the source file
+        let base = Url::from_file_path(dir.path().join("bldg.gml")).unwrap();
+        for uri in [
+            "テクスチャ/画像 1.jpg",
+            "%E3%83%86%E3%82%AF%E3%82%B9%E3%83%81%E3%83%A3/%E7%94%BB%E5%83%8F%201.jpg",
+        ] {
+            let url = base.join(uri).unwrap();
+            assert_eq!(decoded_file_name(&url), "画像 1.jpg");
+            assert_eq!(url.to_file_path().unwrap(), path);
+        }
+    }
+}
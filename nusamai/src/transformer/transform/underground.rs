@@ -0,0 +1,190 @@
+use nusamai_citygml::{
+    object::{ObjectStereotype, Value},
+    schema::{Attribute, Schema, TypeDef, TypeRef},
+};
+use nusamai_plateau::Entity;
+
+use crate::{pipeline::Feedback, transformer::Transform};
+
+/// Underground structures other than the tunnels (`tun:*`)
+const UNDERGROUND_TYPES: [&str; 13] = [
+    "uro:UndergroundBuilding",
+    "uro:Appurtenance",
+    "uro:Cable",
+    "uro:Duct",
+    "uro:ElectricityCable",
+    "uro:Handhole",
+    "uro:Manhole",
+    "uro:OilGasChemicalsPipe",
+    "uro:Pipe",
+    "uro:SewerPipe",
+    "uro:TelecommunicationsCable",
+    "uro:ThermalPipe",
+    "uro:WaterPipe",
+];
+
+fn is_underground_type(typename: &str) -> bool {
+    typename.starts_with("tun:") || UNDERGROUND_TYPES.contains(&typename)
+}
+
+/// How to handle the heights of the underground structures
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UndergroundMode {
+    /// Keep the heights as they are (may be negative)
+    Keep,
+    /// Raise the vertices below 0 m to 0 m
+    Clamp,
+    /// Shift the whole structure up so that its lowest point is at 0 m
+    Offset,
+}
+
+/// Tags the underground structures with the `underground` attribute,
+/// and adjusts their negative heights for the outputs that cannot handle them (e.g. 2.5D extrusion).
+#[derive(Clone)]
+pub struct UndergroundTransform {
+    mode: UndergroundMode,
+}
+
+impl UndergroundTransform {
+    pub fn new(mode: UndergroundMode) -> Self {
+        Self { mode }
+    }
+}
+
+impl Transform for UndergroundTransform {
+    fn transform(&mut self, _feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
+        let Value::Object(obj) = &entity.root else {
+            out.push(entity);
+            return;
+        };
+        if !is_underground_type(&obj.typename) {
+            out.push(entity);
+            return;
+        }
+
+        entity.root.traverse_object_mut(|obj| {
+            if matches!(obj.stereotype, ObjectStereotype::Feature { .. })
+                && is_underground_type(&obj.typename)
+            {
+                obj.attributes
+                    .insert("underground".to_string(), Value::Boolean(true));
+            }
+        });
+
+        {
+            let mut geom_store = entity.geometry_store.write().unwrap();
+            match self.mode {
+                UndergroundMode::Keep => {}
+                UndergroundMode::Clamp => {
+                    for v in geom_store.vertices.iter_mut() {
+                        v[2] = v[2].max(0.0);
+                    }
+                }
+                UndergroundMode::Offset => {
+                    let min_h = geom_store
+                        .vertices
+                        .iter()
+                        .fold(f64::MAX, |min, v| min.min(v[2]));
+                    if min_h < 0.0 {
+                        for v in geom_store.vertices.iter_mut() {
+                            v[2] -= min_h;
+                        }
+                    }
+                }
+            }
+        }
+
+        out.push(entity);
+    }
+
+    fn transform_schema(&self, schema: &mut Schema) {
+        for (typename, ty) in schema.types.iter_mut() {
+            if let TypeDef::Feature(feature) = ty {
+                if is_underground_type(typename) {
+                    feature
+                        .attributes
+                        .insert("underground".to_string(), Attribute::new(TypeRef::Boolean));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::{
+        object::{Map, Object},
+        GeometryStore,
+    };
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    fn entity(typename: &str, heights: &[f64]) -> Entity {
+        Entity {
+            root: Value::Object(Object {
+                typename: typename.to_string().into(),
+                attributes: Map::default(),
+                stereotype: ObjectStereotype::Feature {
+                    id: "id".into(),
+                    geometries: Default::default(),
+                },
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(GeometryStore {
+                vertices: heights.iter().map(|&h| [139.0, 35.0, h]).collect(),
+                ..Default::default()
+            })
+            .into(),
+            appearance_store: Default::default(),
+        }
+    }
+
+    fn transform(mode: UndergroundMode, entity: Entity) -> (Value, Vec<f64>) {
+        let (_, feedback, _) = feedback::watcher();
+        let mut out = Vec::new();
+        UndergroundTransform::new(mode).transform(&feedback, entity, &mut out);
+        let entity = out.pop().unwrap();
+        let heights = entity
+            .geometry_store
+            .read()
+            .unwrap()
+            .vertices
+            .iter()
+            .map(|v| v[2])
+            .collect();
+        (entity.root, heights)
+    }
+
+    #[test]
+    fn underground_heights() {
+        let (root, heights) = transform(UndergroundMode::Keep, entity("tun:Tunnel", &[-5., 3.]));
+        assert_eq!(heights, vec![-5., 3.]);
+        let Value::Object(obj) = root else {
+            unreachable!()
+        };
+        assert_eq!(obj.attributes["underground"], Value::Boolean(true));
+
+        let (_, heights) = transform(UndergroundMode::Clamp, entity("uro:Pipe", &[-5., 3.]));
+        assert_eq!(heights, vec![0., 3.]);
+
+        let (_, heights) = transform(
+            UndergroundMode::Offset,
+            entity("uro:UndergroundBuilding", &[-5., 3.]),
+        );
+        assert_eq!(heights, vec![0., 8.]);
+    }
+
+    #[test]
+    fn aboveground_features_are_untouched() {
+        let (root, heights) =
+            transform(UndergroundMode::Clamp, entity("bldg:Building", &[-5., 3.]));
+        assert_eq!(heights, vec![-5., 3.]);
+        let Value::Object(obj) = root else {
+            unreachable!()
+        };
+        assert!(!obj.attributes.contains_key("underground"));
+    }
+}
@@ -0,0 +1,323 @@
+use nusamai_citygml::{
+    geometry::{GeometryRef, GeometryStore, GeometryType},
+    object::{ObjectStereotype, Value},
+    schema::{Attribute, Schema, TypeDef, TypeRef},
+    Color,
+};
+use nusamai_plateau::{appearance::Material, Entity};
+use nusamai_projection::crs::{EPSG_JGD2011_GEOGRAPHIC_3D, EPSG_WGS84_GEOGRAPHIC_3D};
+
+use crate::{pipeline::Feedback, transformer::Transform};
+
+const SOLITARY_VEGETATION_OBJECT: &str = "veg:SolitaryVegetationObject";
+
+/// Simplified shape of the solitary vegetation objects
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VegetationShape {
+    /// A point at the base of the tree (for 2D maps, billboards and instancing)
+    Point,
+    /// Two crossed vertical quads sized by the height and the crown diameter
+    Billboard,
+}
+
+/// Replaces the detailed meshes of `veg:SolitaryVegetationObject` with a simple shape,
+/// and adds the `height` and `species` attributes for styling.
+#[derive(Clone)]
+pub struct SimplifyVegetationTransform {
+    shape: VegetationShape,
+}
+
+impl SimplifyVegetationTransform {
+    pub fn new(shape: VegetationShape) -> Self {
+        Self { shape }
+    }
+}
+
+impl Transform for SimplifyVegetationTransform {
+    fn transform(&mut self, _feedback: &Feedback, mut entity: Entity, out: &mut Vec<Entity>) {
+        let Value::Object(obj) = &mut entity.root else {
+            out.push(entity);
+            return;
+        };
+        if obj.typename != SOLITARY_VEGETATION_OBJECT {
+            out.push(entity);
+            return;
+        }
+        let ObjectStereotype::Feature { geometries, .. } = &mut obj.stereotype else {
+            out.push(entity);
+            return;
+        };
+        let Some(lod) = geometries.iter().map(|g| g.lod).max() else {
+            out.push(entity);
+            return;
+        };
+
+        let mut geom_store = entity.geometry_store.write().unwrap();
+        let Some([min, max]) = extent(&geom_store, geometries) else {
+            drop(geom_store);
+            out.push(entity);
+            return;
+        };
+        let base = [(min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0, min[2]];
+
+        // prefer the attributes to the extent of the mesh
+        let height = match obj.attributes.get("veg:height") {
+            Some(Value::Measure(m)) if m.value() > 0.0 => m.value(),
+            _ => max[2] - min[2],
+        };
+        let crown_diameter = match obj.attributes.get("veg:crownDiameter") {
+            Some(Value::Measure(m)) if m.value() > 0.0 => m.value(),
+            _ => height / 2.0,
+        };
+
+        *geometries = match self.shape {
+            VegetationShape::Point => vec![add_point(&mut geom_store, base, lod)],
+            VegetationShape::Billboard => {
+                let mut app = entity.appearance_store.write().unwrap();
+                let mat_idx = (geom_store.polygon_materials.len() == geom_store.multipolygon.len())
+                    .then(|| {
+                        app.materials.push(Material {
+                            diffuse_color: Color::new(0.3, 0.5, 0.25),
+                            ..Default::default()
+                        });
+                        app.materials.len() as u32 - 1
+                    });
+                vec![add_billboard(
+                    &mut geom_store,
+                    base,
+                    height,
+                    crown_diameter / 2.0,
+                    lod,
+                    mat_idx,
+                )]
+            }
+        };
+
+        obj.attributes
+            .insert("height".to_string(), Value::Double(height));
+        if let Some(Value::Code(species)) = obj.attributes.get("veg:species") {
+            let species = species.value().to_string();
+            obj.attributes
+                .insert("species".to_string(), Value::String(species));
+        }
+
+        drop(geom_store);
+        out.push(entity);
+    }
+
+    fn transform_schema(&self, schema: &mut Schema) {
+        if let Some(TypeDef::Feature(feature)) = schema.types.get_mut(SOLITARY_VEGETATION_OBJECT) {
+            feature
+                .attributes
+                .insert("height".to_string(), Attribute::new(TypeRef::Double));
+            feature
+                .attributes
+                .insert("species".to_string(), Attribute::new(TypeRef::String));
+        }
+    }
+}
+
+/// Extent of the vertices used by the geometries: `[min, max]`
+pub(super) fn extent(
+    geom_store: &GeometryStore,
+    geometries: &[GeometryRef],
+) -> Option<[[f64; 3]; 2]> {
+    let mut extent: Option<[[f64; 3]; 2]> = None;
+    let mut update = |v: [f64; 3]| {
+        let [min, max] = extent.get_or_insert([v, v]);
+        for i in 0..3 {
+            min[i] = min[i].min(v[i]);
+            max[i] = max[i].max(v[i]);
+        }
+    };
+    for geom in geometries {
+        let range = geom.pos as usize..(geom.pos + geom.len) as usize;
+        match geom.ty {
+            GeometryType::Solid | GeometryType::Surface | GeometryType::Triangle => {
+                for poly in geom_store.multipolygon.iter_range(range) {
+                    for &idx in poly.raw_coords().iter() {
+                        update(geom_store.vertices[idx as usize]);
+                    }
+                }
+            }
+            GeometryType::Curve => {
+                for ls in geom_store.multilinestring.iter_range(range) {
+                    for &idx in ls.raw_coords().iter() {
+                        update(geom_store.vertices[idx as usize]);
+                    }
+                }
+            }
+            GeometryType::Point => {
+                for idx in geom_store.multipoint.iter_range(range) {
+                    update(geom_store.vertices[idx as usize]);
+                }
+            }
+        }
+    }
+    extent
+}
+
+fn add_point(geom_store: &mut GeometryStore, pos: [f64; 3], lod: u8) -> GeometryRef {
+    geom_store.vertices.push(pos);
+    let geom_ref = GeometryRef {
+        ty: GeometryType::Point,
+        lod,
+        pos: geom_store.multipoint.len() as u32,
+        len: 1,
+    };
+    geom_store
+        .multipoint
+        .push(geom_store.vertices.len() as u32 - 1);
+    geom_ref
+}
+
+/// Adds two crossed vertical quads (both faces of each) centered at the base point.
+fn add_billboard(
+    geom_store: &mut GeometryStore,
+    [x, y, z]: [f64; 3],
+    height: f64,
+    radius: f64,
+    lod: u8,
+    mat_idx: Option<u32>,
+) -> GeometryRef {
+    // the radius in the units of the horizontal axes
+    let (rx, ry) = match geom_store.epsg {
+        EPSG_JGD2011_GEOGRAPHIC_3D | EPSG_WGS84_GEOGRAPHIC_3D => {
+            let deg_lat = radius / 111_320.0;
+            (deg_lat / y.to_radians().cos(), deg_lat)
+        }
+        _ => (radius, radius),
+    };
+
+    let pos = geom_store.multipolygon.len() as u32;
+    let with_appearance = geom_store.polygon_materials.len() == geom_store.multipolygon.len();
+    for [dx, dy] in [[rx, 0.], [0., ry], [-rx, 0.], [0., -ry]] {
+        let start = geom_store.vertices.len() as u32;
+        geom_store.vertices.extend([
+            [x - dx, y - dy, z],
+            [x + dx, y + dy, z],
+            [x + dx, y + dy, z + height],
+            [x - dx, y - dy, z + height],
+        ]);
+        geom_store
+            .multipolygon
+            .add_exterior([start, start + 1, start + 2, start + 3]);
+        geom_store.ring_ids.push(None);
+        if with_appearance {
+            geom_store.polygon_materials.push(mat_idx);
+            geom_store.polygon_textures.push(None);
+            geom_store
+                .polygon_uvs
+                .add_exterior([[0., 0.], [1., 0.], [1., 1.], [0., 1.]]);
+        }
+    }
+
+    GeometryRef {
+        ty: GeometryType::Surface,
+        lod,
+        pos,
+        len: 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use nusamai_citygml::{
+        object::{Map, Object},
+        values::Measure,
+    };
+
+    use super::*;
+    use crate::pipeline::feedback;
+
+    fn tree() -> Entity {
+        let mut geoms = GeometryStore {
+            epsg: 6677,
+            vertices: vec![[0., 0., 10.], [2., 0., 10.], [2., 2., 13.], [0., 2., 14.]],
+            ..Default::default()
+        };
+        geoms.multipolygon.add_exterior([0, 1, 2, 3]);
+        geoms.ring_ids.push(None);
+
+        let mut attributes = Map::default();
+        attributes.insert("veg:height".into(), Value::Measure(Measure::new(6.0)));
+        Entity {
+            root: Value::Object(Object {
+                typename: SOLITARY_VEGETATION_OBJECT.into(),
+                stereotype: ObjectStereotype::Feature {
+                    id: "tree".into(),
+                    geometries: vec![GeometryRef {
+                        ty: GeometryType::Solid,
+                        lod: 2,
+                        pos: 0,
+                        len: 1,
+                    }],
+                },
+                attributes,
+            }),
+            base_url: url::Url::parse("file:///dummy").unwrap(),
+            geometry_store: RwLock::new(geoms).into(),
+            appearance_store: Default::default(),
+        }
+    }
+
+    #[test]
+    fn vegetation_to_point() {
+        let (_, feedback, _) = feedback::watcher();
+        let mut out = Vec::new();
+        SimplifyVegetationTransform::new(VegetationShape::Point).transform(
+            &feedback,
+            tree(),
+            &mut out,
+        );
+
+        let entity = &out[0];
+        let Value::Object(obj) = &entity.root else {
+            unreachable!()
+        };
+        let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype else {
+            unreachable!()
+        };
+        assert_eq!(geometries.len(), 1);
+        assert_eq!(geometries[0].ty, GeometryType::Point);
+        assert_eq!(geometries[0].lod, 2);
+
+        let geoms = entity.geometry_store.read().unwrap();
+        let idx = geoms.multipoint.iter().next().unwrap();
+        assert_eq!(geoms.vertices[idx as usize], [1., 1., 10.]);
+        assert_eq!(obj.attributes["height"], Value::Double(6.0));
+    }
+
+    #[test]
+    fn vegetation_to_billboard() {
+        let (_, feedback, _) = feedback::watcher();
+        let mut out = Vec::new();
+        SimplifyVegetationTransform::new(VegetationShape::Billboard).transform(
+            &feedback,
+            tree(),
+            &mut out,
+        );
+
+        let entity = &out[0];
+        let Value::Object(obj) = &entity.root else {
+            unreachable!()
+        };
+        let ObjectStereotype::Feature { geometries, .. } = &obj.stereotype else {
+            unreachable!()
+        };
+        assert_eq!(geometries.len(), 1);
+        assert_eq!((geometries[0].pos, geometries[0].len), (1, 4));
+
+        let geoms = entity.geometry_store.read().unwrap();
+        let quad = geoms.multipolygon.get(1);
+        let top = quad
+            .exterior()
+            .iter()
+            .map(|idx| geoms.vertices[idx as usize][2])
+            .fold(f64::MIN, f64::max);
+        // the height attribute is used instead of the extent of the mesh
+        assert_eq!(top, 16.0);
+    }
+}
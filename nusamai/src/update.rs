@@ -0,0 +1,138 @@
+//! State of the input files for incremental updates
+//!
+//! A state file records the content hashes of the input files converted in a previous run,
+//! so that the next run can re-convert only the files that were added or changed.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use flate2::Crc;
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::{self, PipelineError};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpdateState {
+    /// Content hash (CRC-32 and size) of each input file
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+/// Changes of the input files from the previous state
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    /// Files that were added or modified since the previous run
+    pub changed: Vec<PathBuf>,
+    /// Files that no longer exist in the input
+    pub removed: Vec<PathBuf>,
+}
+
+impl UpdateState {
+    /// Computes the state of the given input files.
+    pub fn compute(filenames: &[PathBuf]) -> pipeline::Result<Self> {
+        let mut files = BTreeMap::new();
+        for filename in filenames {
+            files.insert(state_key(filename), hash_file(filename)?);
+        }
+        Ok(Self { files })
+    }
+
+    /// Loads the state file. Returns an empty state if the file does not exist yet.
+    pub fn load(path: &Path) -> pipeline::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let reader = BufReader::new(File::open(path)?);
+        serde_json::from_reader(reader)
+            .map_err(|err| PipelineError::Other(format!("Invalid state file: {}", err)))
+    }
+
+    pub fn save(&self, path: &Path) -> pipeline::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)
+            .map_err(|err| PipelineError::Other(format!("Failed to write state file: {}", err)))
+    }
+
+    /// Compares with the state of the previous run.
+    pub fn changes_from(&self, prev: &UpdateState) -> Changes {
+        Changes {
+            changed: self
+                .files
+                .iter()
+                .filter(|(path, hash)| prev.files.get(*path) != Some(hash))
+                .map(|(path, _)| path.clone())
+                .collect(),
+            removed: prev
+                .files
+                .keys()
+                .filter(|path| !self.files.contains_key(*path))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Uses the absolute path when possible, so that the state does not depend on the working directory
+fn state_key(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn hash_file(path: &Path) -> pipeline::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut crc = Crc::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buf[..n]);
+    }
+    Ok(format!("{:08x}-{}", crc.sum(), crc.amount()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.gml");
+        let b = dir.path().join("b.gml");
+        std::fs::write(&a, "aaa").unwrap();
+        std::fs::write(&b, "bbb").unwrap();
+
+        let state = UpdateState::compute(&[a.clone(), b.clone()]).unwrap();
+        let state_path = dir.path().join("state.json");
+        state.save(&state_path).unwrap();
+        let prev = UpdateState::load(&state_path).unwrap();
+        assert_eq!(prev, state);
+
+        // nothing has changed
+        let changes = UpdateState::compute(&[a.clone(), b.clone()])
+            .unwrap()
+            .changes_from(&prev);
+        assert_eq!(changes, Changes::default());
+
+        // `a` is modified, `b` is removed and `c` is added
+        let c = dir.path().join("c.gml");
+        std::fs::write(&a, "AAA").unwrap();
+        std::fs::write(&c, "ccc").unwrap();
+        let changes = UpdateState::compute(&[a.clone(), c.clone()])
+            .unwrap()
+            .changes_from(&prev);
+        assert_eq!(
+            changes.changed,
+            vec![a.canonicalize().unwrap(), c.canonicalize().unwrap()]
+        );
+        assert_eq!(changes.removed, vec![b.canonicalize().unwrap()]);
+
+        // the state file of the first run does not exist yet
+        let empty = UpdateState::load(&dir.path().join("missing.json")).unwrap();
+        assert!(empty.files.is_empty());
+    }
+}
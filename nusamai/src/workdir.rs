@@ -0,0 +1,136 @@
+//! Directory of the intermediate files
+//!
+//! The chunks of the external sort, the scratch files of the sinks (e.g. the atlases before being
+//! embedded into glb) and the downloaded texture images are written under a single directory, so that
+//! it can be moved to a disk with enough space (`--tmpdir` of the command line).
+//!
+//! The temporary files are removed as soon as they are no longer used, and the ones left behind by
+//! interrupted runs are removed when the next conversion is prepared.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+
+use crate::pipeline::{PipelineError, Result};
+
+const DIR_NAME: &str = "nusamai";
+/// Prefix of the temporary files and directories created through `tempfile`
+const TEMPFILE_PREFIX: &str = ".tmp";
+/// Temporary files older than this are regarded as left behind by an interrupted run
+const STALE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// A conversion is not started if the free space of the directory is less than this
+pub const MIN_FREE_SPACE: u64 = 512 * 1024 * 1024;
+
+static WORK_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Free space of the directory of the intermediate files
+pub struct DiskSpace {
+    pub dir: PathBuf,
+    pub available: u64,
+}
+
+impl DiskSpace {
+    /// Whether the free space is less than the expected size of the intermediate files
+    pub fn is_short_for(&self, expected_bytes: u64) -> bool {
+        self.available < expected_bytes
+    }
+}
+
+/// Directory of the intermediate files (`nusamai` under the system temporary directory if not prepared)
+pub fn work_dir() -> PathBuf {
+    WORK_DIR.get().cloned().unwrap_or_else(default_dir)
+}
+
+fn default_dir() -> PathBuf {
+    std::env::temp_dir().join(DIR_NAME)
+}
+
+/// Prepares the directory of the intermediate files before starting a conversion.
+///
+/// Creates the directory (the default one if `dir` is None), removes the stale temporary files in it,
+/// checks the free space, and routes the temporary files created through `tempfile` into it.
+/// The directory is global to the process, so it cannot be changed once a conversion has been prepared.
+pub fn prepare(dir: Option<&Path>) -> Result<DiskSpace> {
+    let dir = dir.map(Path::to_path_buf).unwrap_or_else(default_dir);
+    let active = WORK_DIR.get_or_init(|| dir.clone());
+    if *active != dir {
+        return Err(PipelineError::Other(format!(
+            "The directory of the intermediate files is already set to {:?}",
+            active
+        )));
+    }
+
+    fs::create_dir_all(&dir)?;
+    remove_stale_files(&dir, STALE_AGE);
+
+    let available = fs2::available_space(&dir)?;
+    if available < MIN_FREE_SPACE {
+        return Err(PipelineError::Other(format!(
+            "Not enough free space for the intermediate files in {:?}: {} available, {} required",
+            dir,
+            bytesize::to_string(available, true),
+            bytesize::to_string(MIN_FREE_SPACE, true)
+        )));
+    }
+
+    // (fails only if already overridden with the same directory)
+    let _ = tempfile::env::override_temp_dir(&dir);
+
+    Ok(DiskSpace { dir, available })
+}
+
+/// Removes the temporary files and directories older than `age` (left behind by interrupted runs)
+fn remove_stale_files(dir: &Path, age: Duration) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let now = SystemTime::now();
+    for entry in entries.flatten() {
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(TEMPFILE_PREFIX)
+        {
+            continue;
+        }
+        let Ok(modified) = entry.metadata().and_then(|m| m.modified()) else {
+            continue;
+        };
+        if now.duration_since(modified).unwrap_or_default() < age {
+            continue;
+        }
+        let path = entry.path();
+        let _ = match path.is_dir() {
+            true => fs::remove_dir_all(&path),
+            false => fs::remove_file(&path),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_stale_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".tmpA1b2C3/atlas")).unwrap();
+        fs::write(dir.path().join(".tmpX9y8Z7"), b"chunk").unwrap();
+        fs::create_dir_all(dir.path().join("textures")).unwrap();
+
+        // recent files are kept (they may be used by another running conversion)
+        remove_stale_files(dir.path(), STALE_AGE);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        // the other files (e.g. the texture cache) are always kept
+        remove_stale_files(dir.path(), Duration::ZERO);
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["textures"]);
+    }
+}
@@ -0,0 +1,540 @@
+//! Support for the end-to-end tests
+//!
+//! - [`SyntheticDataset`] generates small CityGML files (in the PLATEAU folder structure) programmatically,
+//!   so that the sinks and the transformers can be tested without shipping large sample data.
+//! - [`run_conversion`] runs the whole pipeline (CityGML source, transformer and a sink) on the files.
+//! - [`assert_golden`] compares a digest of the output with the golden file in `tests/golden/`.
+//!   Run the tests with `UPDATE_GOLDEN=1` to (re)generate the golden files after an intended change.
+
+#![allow(dead_code)]
+
+mod summary;
+
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::Once,
+};
+
+use nusamai::{
+    sink::DataSinkProvider,
+    source::{citygml::CityGmlSourceProvider, DataSourceProvider},
+    transformer::{
+        MultiThreadTransformer, NusamaiTransformBuilder, TransformBuilder, TransformerSettings,
+    },
+};
+use nusamai_citygml::CityGmlElement;
+use nusamai_plateau::models::TopLevelCityObject;
+
+static INIT: Once = Once::new();
+
+/// Origin of the generated features (lat, lng), in the 3rd mesh 53394525
+const ORIGIN: (f64, f64) = (35.6875, 139.6875);
+/// Size of a feature and the spacing between them, in degrees
+const FEATURE_SIZE: f64 = 0.0001;
+const FEATURE_SPACING: f64 = 0.0003;
+const MESHCODE: &str = "53394525";
+
+const CITYGML_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<core:CityModel xmlns:core="http://www.opengis.net/citygml/2.0" xmlns:bldg="http://www.opengis.net/citygml/building/2.0" xmlns:tran="http://www.opengis.net/citygml/transportation/2.0" xmlns:frn="http://www.opengis.net/citygml/cityfurniture/2.0" xmlns:dem="http://www.opengis.net/citygml/relief/2.0" xmlns:app="http://www.opengis.net/citygml/appearance/2.0" xmlns:gen="http://www.opengis.net/citygml/generics/2.0" xmlns:gml="http://www.opengis.net/gml" xmlns:xlink="http://www.w3.org/1999/xlink">
+"#;
+
+/// Feature types the generator can write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureKind {
+    /// `bldg:Building` (LOD0 footprint, LOD1 solid, LOD2 thematic surfaces)
+    Building,
+    /// `tran:Road` (LOD1 surface)
+    Road,
+    /// `frn:CityFurniture` (LOD1 geometry)
+    CityFurniture,
+    /// `dem:ReliefFeature` (LOD1 TIN)
+    Relief,
+}
+
+impl FeatureKind {
+    /// Package name in the PLATEAU folder structure (`udx/{package}`)
+    fn package(self) -> &'static str {
+        match self {
+            FeatureKind::Building => "bldg",
+            FeatureKind::Road => "tran",
+            FeatureKind::CityFurniture => "frn",
+            FeatureKind::Relief => "dem",
+        }
+    }
+}
+
+/// Generator of a small CityGML dataset
+pub struct SyntheticDataset {
+    kinds: Vec<FeatureKind>,
+    lods: Vec<u8>,
+    count: usize,
+    textures: bool,
+    missing_ids: bool,
+}
+
+impl Default for SyntheticDataset {
+    fn default() -> Self {
+        Self {
+            kinds: vec![FeatureKind::Building],
+            lods: vec![0, 1, 2],
+            count: 3,
+            textures: false,
+            missing_ids: false,
+        }
+    }
+}
+
+impl SyntheticDataset {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn kinds(mut self, kinds: &[FeatureKind]) -> Self {
+        self.kinds = kinds.to_vec();
+        self
+    }
+
+    /// LODs written for the buildings (the other types have only LOD1)
+    pub fn lods(mut self, lods: &[u8]) -> Self {
+        self.lods = lods.to_vec();
+        self
+    }
+
+    /// Number of the features of each type
+    pub fn count(mut self, count: usize) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Texture the roofs of the LOD2 buildings (the images are written next to the CityGML file)
+    pub fn textures(mut self, textures: bool) -> Self {
+        self.textures = textures;
+        self
+    }
+
+    /// Omit the `gml:id` of the last feature of each type
+    pub fn missing_ids(mut self, missing_ids: bool) -> Self {
+        self.missing_ids = missing_ids;
+        self
+    }
+
+    /// Writes the CityGML files into `dir/udx/{package}/` and returns their paths
+    pub fn write(&self, dir: &Path) -> Vec<PathBuf> {
+        self.kinds
+            .iter()
+            .map(|&kind| {
+                let package_dir = dir.join("udx").join(kind.package());
+                std::fs::create_dir_all(&package_dir).unwrap();
+                let name = format!("{MESHCODE}_{}_6697_op", kind.package());
+                let path = package_dir.join(format!("{name}.gml"));
+                std::fs::write(&path, self.document(kind, &name)).unwrap();
+
+                if kind == FeatureKind::Building && self.textures && self.lods.contains(&2) {
+                    let image_dir = package_dir.join(format!("{name}_appearance"));
+                    std::fs::create_dir_all(&image_dir).unwrap();
+                    for i in 0..self.count {
+                        let color = image::Rgb([(40 * i % 256) as u8, 120, 200]);
+                        image::RgbImage::from_pixel(8, 8, color)
+                            .save(image_dir.join(format!("roof_{i}.png")))
+                            .unwrap();
+                    }
+                }
+                path
+            })
+            .collect()
+    }
+
+    /// The CityGML document of a feature type
+    pub fn document(&self, kind: FeatureKind, name: &str) -> String {
+        let mut doc = String::from(CITYGML_HEADER);
+        let (lat, lng) = ORIGIN;
+        let extent = FEATURE_SPACING * self.count as f64;
+        writeln!(
+            doc,
+            r#"<gml:boundedBy><gml:Envelope srsName="http://www.opengis.net/def/crs/EPSG/0/6697" srsDimension="3"><gml:lowerCorner>{lat} {lng} 0</gml:lowerCorner><gml:upperCorner>{} {} 100</gml:upperCorner></gml:Envelope></gml:boundedBy>"#,
+            lat + extent,
+            lng + extent
+        )
+        .unwrap();
+
+        for i in 0..self.count {
+            let id = match self.missing_ids && i + 1 == self.count {
+                true => String::new(),
+                false => format!(r#" gml:id="{}_{i}""#, kind.package()),
+            };
+            doc.push_str("<core:cityObjectMember>\n");
+            match kind {
+                FeatureKind::Building => self.write_building(&mut doc, i, &id),
+                FeatureKind::Road => write_road(&mut doc, i, &id),
+                FeatureKind::CityFurniture => write_city_furniture(&mut doc, i, &id),
+                FeatureKind::Relief => write_relief(&mut doc, i, &id),
+            }
+            doc.push_str("</core:cityObjectMember>\n");
+        }
+
+        if kind == FeatureKind::Building && self.textures && self.lods.contains(&2) {
+            doc.push_str(
+                "<app:appearanceMember><app:Appearance><app:theme>rgbTexture</app:theme>\n",
+            );
+            for i in 0..self.count {
+                writeln!(
+                    doc,
+                    r##"<app:surfaceDataMember><app:ParameterizedTexture><app:imageURI>{name}_appearance/roof_{i}.png</app:imageURI><app:mimeType>image/png</app:mimeType><app:target uri="#poly_{i}_roof"><app:TexCoordList><app:textureCoordinates ring="#ring_{i}_roof">0 0 1 0 1 1 0 1 0 0</app:textureCoordinates></app:TexCoordList></app:target></app:ParameterizedTexture></app:surfaceDataMember>"##
+                )
+                .unwrap();
+            }
+            doc.push_str("</app:Appearance></app:appearanceMember>\n");
+        }
+
+        doc.push_str("</core:CityModel>\n");
+        doc
+    }
+
+    fn write_building(&self, doc: &mut String, i: usize, id: &str) {
+        let corners = corners(i, FEATURE_SIZE);
+        let height = 10.0 + i as f64;
+        writeln!(doc, "<bldg:Building{id}>").unwrap();
+        writeln!(doc, "<gml:name>建物{i}</gml:name>").unwrap();
+        writeln!(
+            doc,
+            r#"<gen:stringAttribute name="note"><gen:value>synthetic {i}</gen:value></gen:stringAttribute>"#
+        )
+        .unwrap();
+        writeln!(
+            doc,
+            r#"<bldg:usage codeSpace="../../codelists/Building_usage.xml">401</bldg:usage>"#
+        )
+        .unwrap();
+        writeln!(
+            doc,
+            r#"<bldg:measuredHeight uom="m">{height:.1}</bldg:measuredHeight>"#
+        )
+        .unwrap();
+
+        if self.lods.contains(&0) {
+            writeln!(
+                doc,
+                "<bldg:lod0FootPrint><gml:MultiSurface><gml:surfaceMember>{}</gml:surfaceMember></gml:MultiSurface></bldg:lod0FootPrint>",
+                polygon(&ring(&corners, 0.0), None)
+            )
+            .unwrap();
+        }
+        if self.lods.contains(&1) {
+            doc.push_str("<bldg:lod1Solid><gml:Solid><gml:exterior><gml:CompositeSurface>");
+            for face in box_faces(&corners, 0.0, height) {
+                write!(
+                    doc,
+                    "<gml:surfaceMember>{}</gml:surfaceMember>",
+                    polygon(&face, None)
+                )
+                .unwrap();
+            }
+            doc.push_str("</gml:CompositeSurface></gml:exterior></gml:Solid></bldg:lod1Solid>\n");
+        }
+        if self.lods.contains(&2) {
+            let faces = box_faces(&corners, 0.0, height);
+            let (ground, rest) = faces.split_first().unwrap();
+            let (roof, walls) = rest.split_first().unwrap();
+            let surfaces = [("GroundSurface", "ground", ground)]
+                .into_iter()
+                .chain([("RoofSurface", "roof", roof)])
+                .chain(walls.iter().map(|wall| ("WallSurface", "wall", wall)));
+            for (j, (typename, label, face)) in surfaces.enumerate() {
+                let poly_id = match label {
+                    "roof" => format!("{i}_roof"),
+                    _ => format!("{i}_{label}_{j}"),
+                };
+                writeln!(
+                    doc,
+                    "<bldg:boundedBy><bldg:{typename}><bldg:lod2MultiSurface><gml:MultiSurface><gml:surfaceMember>{}</gml:surfaceMember></gml:MultiSurface></bldg:lod2MultiSurface></bldg:{typename}></bldg:boundedBy>",
+                    polygon(face, Some(&poly_id))
+                )
+                .unwrap();
+            }
+        }
+        doc.push_str("</bldg:Building>\n");
+    }
+}
+
+fn write_road(doc: &mut String, i: usize, id: &str) {
+    let (lat, lng) = position(i);
+    let corners = [
+        (lat - FEATURE_SIZE, lng),
+        (lat - FEATURE_SIZE, lng + FEATURE_SPACING),
+        (lat - FEATURE_SIZE / 2.0, lng + FEATURE_SPACING),
+        (lat - FEATURE_SIZE / 2.0, lng),
+    ];
+    writeln!(doc, "<tran:Road{id}>").unwrap();
+    writeln!(
+        doc,
+        r#"<tran:function codeSpace="../../codelists/Road_function.xml">1</tran:function>"#
+    )
+    .unwrap();
+    writeln!(
+        doc,
+        "<tran:lod1MultiSurface><gml:MultiSurface><gml:surfaceMember>{}</gml:surfaceMember></gml:MultiSurface></tran:lod1MultiSurface>",
+        polygon(&ring(&corners, 0.0), None)
+    )
+    .unwrap();
+    doc.push_str("</tran:Road>\n");
+}
+
+fn write_city_furniture(doc: &mut String, i: usize, id: &str) {
+    let corners = corners(i, FEATURE_SIZE / 10.0);
+    writeln!(doc, "<frn:CityFurniture{id}>").unwrap();
+    writeln!(
+        doc,
+        r#"<frn:function codeSpace="../../codelists/CityFurniture_function.xml">4800</frn:function>"#
+    )
+    .unwrap();
+    doc.push_str("<frn:lod1Geometry><gml:MultiSurface>");
+    for face in box_faces(&corners, 0.0, 2.0) {
+        write!(
+            doc,
+            "<gml:surfaceMember>{}</gml:surfaceMember>",
+            polygon(&face, None)
+        )
+        .unwrap();
+    }
+    doc.push_str("</gml:MultiSurface></frn:lod1Geometry>\n");
+    doc.push_str("</frn:CityFurniture>\n");
+}
+
+/// A strip of the TIN along the diagonal (sloping up to the north-east), covering the i-th feature
+fn write_relief(doc: &mut String, i: usize, id: &str) {
+    let (lat, lng) = position(i);
+    let height = |lat: f64, lng: f64| 10.0 + 1e4 * (lat - ORIGIN.0 + lng - ORIGIN.1);
+    let point = |(lat, lng): (f64, f64)| [lat, lng, height(lat, lng)];
+    let (sw, se, ne, nw) = (
+        point((lat, lng)),
+        point((lat, lng + FEATURE_SPACING)),
+        point((lat + FEATURE_SPACING, lng + FEATURE_SPACING)),
+        point((lat + FEATURE_SPACING, lng)),
+    );
+    writeln!(doc, "<dem:ReliefFeature{id}>").unwrap();
+    doc.push_str("<dem:lod>1</dem:lod>\n");
+    doc.push_str("<dem:reliefComponent><dem:TINRelief><dem:lod>1</dem:lod><dem:tin><gml:TriangulatedSurface><gml:trianglePatches>");
+    for triangle in [[sw, se, ne, sw], [sw, ne, nw, sw]] {
+        let pos_list = triangle
+            .iter()
+            .map(|[lat, lng, z]| format!("{lat:.9} {lng:.9} {z:.3}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(
+            doc,
+            "<gml:Triangle><gml:exterior><gml:LinearRing><gml:posList>{pos_list}</gml:posList></gml:LinearRing></gml:exterior></gml:Triangle>"
+        )
+        .unwrap();
+    }
+    doc.push_str("</gml:trianglePatches></gml:TriangulatedSurface></dem:tin></dem:TINRelief></dem:reliefComponent>\n");
+    doc.push_str("</dem:ReliefFeature>\n");
+}
+
+/// Position (lat, lng) of the i-th feature (on a diagonal, not to overlap with each other)
+fn position(i: usize) -> (f64, f64) {
+    let offset = FEATURE_SPACING * i as f64;
+    (ORIGIN.0 + offset, ORIGIN.1 + offset)
+}
+
+/// The corners (lat, lng) of a square in the counter-clockwise order
+fn corners(i: usize, size: f64) -> [(f64, f64); 4] {
+    let (lat, lng) = position(i);
+    [
+        (lat, lng),
+        (lat, lng + size),
+        (lat + size, lng + size),
+        (lat + size, lng),
+    ]
+}
+
+/// A closed ring at the height
+fn ring(corners: &[(f64, f64)], z: f64) -> Vec<[f64; 3]> {
+    corners
+        .iter()
+        .chain(corners.first())
+        .map(|&(lat, lng)| [lat, lng, z])
+        .collect()
+}
+
+/// The faces of a box: the ground (facing down), the roof and the walls
+fn box_faces(corners: &[(f64, f64); 4], bottom: f64, top: f64) -> Vec<Vec<[f64; 3]>> {
+    let reversed: Vec<_> = corners.iter().rev().copied().collect();
+    let mut faces = vec![ring(&reversed, bottom), ring(corners, top)];
+    for k in 0..corners.len() {
+        let (a, b) = (corners[k], corners[(k + 1) % corners.len()]);
+        faces.push(vec![
+            [a.0, a.1, bottom],
+            [b.0, b.1, bottom],
+            [b.0, b.1, top],
+            [a.0, a.1, top],
+            [a.0, a.1, bottom],
+        ]);
+    }
+    faces
+}
+
+/// A `gml:Polygon` (with the ids `poly_{id}` and `ring_{id}` if given, to be the targets of the textures)
+fn polygon(ring: &[[f64; 3]], id: Option<&str>) -> String {
+    let pos_list = ring
+        .iter()
+        .map(|[lat, lng, z]| format!("{lat:.9} {lng:.9} {z:.3}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    match id {
+        Some(id) => format!(
+            r#"<gml:Polygon gml:id="poly_{id}"><gml:exterior><gml:LinearRing gml:id="ring_{id}"><gml:posList>{pos_list}</gml:posList></gml:LinearRing></gml:exterior></gml:Polygon>"#
+        ),
+        None => format!(
+            "<gml:Polygon><gml:exterior><gml:LinearRing><gml:posList>{pos_list}</gml:posList></gml:LinearRing></gml:exterior></gml:Polygon>"
+        ),
+    }
+}
+
+/// Runs the pipeline from the CityGML files to the sink, and returns the feedback messages
+pub fn run_conversion<S: DataSinkProvider>(
+    sink_provider: S,
+    filenames: &[PathBuf],
+    output: &Path,
+    sink_options: &[(&str, &str)],
+) -> Vec<String> {
+    INIT.call_once(|| {
+        if std::env::var("RUST_LOG").is_err() {
+            std::env::set_var("RUST_LOG", "error")
+        }
+        pretty_env_logger::init();
+        // the features are written in a stable order with a single worker thread
+        let _ = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build_global();
+    });
+
+    let source_provider = CityGmlSourceProvider {
+        filenames: filenames.to_vec(),
+    };
+
+    let mut sink = {
+        let mut sink_params = sink_provider.sink_options();
+        let mut options: Vec<(String, String)> = sink_options
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        options.push(("@output".into(), output.to_string_lossy().into_owned()));
+        sink_params.update_values_with_str(&options).unwrap();
+        sink_params.validate().unwrap();
+        sink_provider.create(&sink_params)
+    };
+
+    let requirements = sink.make_requirements(TransformerSettings::new());
+    let mut source = source_provider.create(&source_provider.sink_options());
+    source.set_appearance_parsing(requirements.use_appearance);
+
+    let (transformer, schema) = {
+        let transform_builder = NusamaiTransformBuilder::new(requirements.into());
+        let mut schema = nusamai_citygml::schema::Schema::default();
+        TopLevelCityObject::collect_schema(&mut schema);
+        source.transform_schema(&mut schema);
+        transform_builder.transform_schema(&mut schema);
+        let transformer = Box::new(MultiThreadTransformer::new(transform_builder));
+        (transformer, schema)
+    };
+
+    let (handle, watcher, canceller) =
+        nusamai::pipeline::run(source, transformer, sink, schema.into());
+    let messages: Vec<String> = watcher.into_iter().map(|msg| msg.message).collect();
+    handle.join().unwrap();
+
+    // should not be canceled
+    assert!(!canceller.is_canceled(), "canceled: {:?}", messages);
+    messages
+}
+
+/// Extensions of the output files compared by their contents (the others by their summaries)
+const TEXT_EXTENSIONS: &[&str] = &[
+    "csv", "czml", "dxf", "geojson", "gml", "json", "jsonl", "kml", "mtl", "obj", "pgw",
+];
+
+/// A text summarizing the output (a file or a directory): the relative paths of the files, the
+/// contents of the text files, and the summaries of the binary files
+pub fn digest(output: &Path) -> String {
+    let mut files = Vec::new();
+    if output.is_dir() {
+        collect_files(output, &mut files);
+    } else {
+        files.push(output.to_path_buf());
+    }
+    files.sort();
+
+    let mut digest = String::new();
+    for path in files {
+        let name = match output.is_dir() {
+            true => path.strip_prefix(output).unwrap(),
+            false => Path::new(path.file_name().unwrap()),
+        };
+        let name = name.to_string_lossy().replace('\\', "/");
+        writeln!(digest, "== {name}").unwrap();
+
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        if TEXT_EXTENSIONS.contains(&extension) {
+            let content = std::fs::read_to_string(&path).unwrap();
+            // not to update the golden files on every release
+            let content = content.replace(env!("CARGO_PKG_VERSION"), "{VERSION}");
+            digest.push_str(&content);
+            if !content.ends_with('\n') {
+                digest.push('\n');
+            }
+        } else {
+            digest.push_str(&summary::summarize(&path, extension));
+        }
+    }
+    digest
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Compares the digest of the output with `tests/golden/{name}.txt`.
+///
+/// The golden file is (re)written only if `UPDATE_GOLDEN` is set; a missing golden file is a failure.
+pub fn assert_golden(name: &str, output: &Path) {
+    let actual = digest(output);
+    let golden_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.txt"));
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(golden_path.parent().unwrap()).unwrap();
+        std::fs::write(&golden_path, &actual).unwrap();
+        return;
+    }
+    if !golden_path.exists() {
+        panic!(
+            "the golden file {:?} does not exist (run with UPDATE_GOLDEN=1 to create it)",
+            golden_path
+        );
+    }
+
+    let expected = std::fs::read_to_string(&golden_path).unwrap();
+    if expected != actual {
+        let line = expected
+            .lines()
+            .zip(actual.lines())
+            .position(|(e, a)| e != a)
+            .unwrap_or(expected.lines().count().min(actual.lines().count()));
+        panic!(
+            "the output differs from {:?} at line {} (run with UPDATE_GOLDEN=1 if it is intended)\nexpected: {:?}\nactual:   {:?}",
+            golden_path,
+            line + 1,
+            expected.lines().nth(line),
+            actual.lines().nth(line)
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Summaries of the binary output files for the golden files
+//!
+//! The formats with timestamps, random ids or unstable page layouts (SQLite, DuckDB, LAS, DBF, etc.) are decoded
+//! and summarized (tables and row counts, point counts and bounding boxes, etc.); the others are hashed.
+
+use std::{fmt::Write as _, io::Read, path::Path};
+
+use sha2::{Digest, Sha256};
+
+/// The summary of a binary file (lines ending with a newline)
+pub fn summarize(path: &Path, extension: &str) -> String {
+    let bytes = std::fs::read(path).unwrap();
+    match extension {
+        "gpkg" | "mbtiles" => sqlite_summary(path),
+        "duckdb" => duckdb_summary(path),
+        "parquet" => parquet_summary(path),
+        "glb" => glb_summary(&bytes),
+        "png" | "jpg" | "jpeg" | "webp" | "tif" | "tiff" => image_summary(path),
+        "slpk" => slpk_summary(path),
+        "las" => las_summary(&bytes),
+        "ply" => ply_summary(&bytes),
+        "dbf" => dbf_summary(bytes),
+        _ => format!("{} bytes {}\n", bytes.len(), sha256(&bytes)),
+    }
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(bytes))
+}
+
+/// The tables with their row counts, and the extents in `gpkg_contents`
+fn sqlite_summary(path: &Path) -> String {
+    use sqlx::Connection;
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let url = format!("sqlite://{}?mode=ro", path.to_str().unwrap());
+        let mut conn = sqlx::SqliteConnection::connect(&url).await.unwrap();
+        let tables: Vec<String> = sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+        )
+        .fetch_all(&mut conn)
+        .await
+        .unwrap();
+
+        let mut summary = String::new();
+        for table in tables {
+            let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM \"{table}\""))
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            writeln!(summary, "table {table}: {count} rows").unwrap();
+        }
+
+        let extents: Vec<(String, Option<f64>, Option<f64>, Option<f64>, Option<f64>)> =
+            sqlx::query_as(
+                "SELECT table_name, min_x, min_y, max_x, max_y FROM gpkg_contents ORDER BY table_name",
+            )
+            .fetch_all(&mut conn)
+            .await
+            .unwrap_or_default();
+        for (table, min_x, min_y, max_x, max_y) in extents {
+            let extent = [min_x, min_y, max_x, max_y].map(|v| match v {
+                Some(v) => format!("{v:.6}"),
+                None => "-".into(),
+            });
+            writeln!(summary, "extent {table}: {}", extent.join(" ")).unwrap();
+        }
+        summary
+    })
+}
+
+/// The tables with their row counts
+fn duckdb_summary(path: &Path) -> String {
+    let conn = duckdb::Connection::open(path).unwrap();
+    // (the geometry columns need the spatial extension if it was available to the sink)
+    let _ = conn.execute_batch("LOAD spatial");
+    let mut stmt = conn
+        .prepare("SELECT table_name FROM information_schema.tables ORDER BY table_name")
+        .unwrap();
+    let tables: Vec<String> = stmt
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    let mut summary = String::new();
+    for table in tables {
+        let count: i64 = conn
+            .query_row(&format!("SELECT COUNT(*) FROM \"{table}\""), [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        writeln!(summary, "table {table}: {count} rows").unwrap();
+    }
+    summary
+}
+
+/// The row count, the columns and the key-value metadata (including the GeoParquet `geo` metadata)
+fn parquet_summary(path: &Path) -> String {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let reader = SerializedFileReader::new(std::fs::File::open(path).unwrap()).unwrap();
+    let metadata = reader.metadata().file_metadata();
+    let mut summary = format!("{} rows\n", metadata.num_rows());
+    for column in metadata.schema_descr().columns() {
+        writeln!(summary, "column {}", column.path().string()).unwrap();
+    }
+    for kv in metadata.key_value_metadata().into_iter().flatten() {
+        // (the serialized Arrow schema is redundant with the columns)
+        if kv.key != "ARROW:schema" {
+            writeln!(
+                summary,
+                "metadata {}: {}",
+                kv.key,
+                kv.value.as_deref().unwrap_or("")
+            )
+            .unwrap();
+        }
+    }
+    summary
+}
+
+/// The JSON chunk as it is, and the hash of the binary chunk
+fn glb_summary(bytes: &[u8]) -> String {
+    let json_length = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+    let json = std::str::from_utf8(&bytes[20..20 + json_length]).unwrap();
+    let json = json
+        .trim_end()
+        .replace(env!("CARGO_PKG_VERSION"), "{VERSION}");
+    let bin = bytes.get(20 + json_length + 8..).unwrap_or_default();
+    format!("{json}\nbin {} bytes {}\n", bin.len(), sha256(bin))
+}
+
+/// The size and the hash of the pixels (not to depend on the encoder settings)
+fn image_summary(path: &Path) -> String {
+    let image = image::open(path).unwrap();
+    format!(
+        "{}x{} {:?} {}\n",
+        image.width(),
+        image.height(),
+        image.color(),
+        sha256(image.as_bytes())
+    )
+}
+
+/// The entries with the hashes of their (decompressed) contents
+fn slpk_summary(path: &Path) -> String {
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(path).unwrap()).unwrap();
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        if file.name().ends_with(".gz") {
+            let mut decompressed = Vec::new();
+            flate2::read::MultiGzDecoder::new(&content[..])
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            content = decompressed;
+        }
+        entries.push(format!("{} {}", file.name(), sha256(&content)));
+    }
+    entries.sort();
+    entries.iter().map(|entry| format!("{entry}\n")).collect()
+}
+
+/// The point count, the bounding box and the hash of the point records (not of the header with the creation date)
+fn las_summary(bytes: &[u8]) -> String {
+    let u32_at = |pos: usize| u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+    let f64_at = |pos: usize| f64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+    let offset_to_points = u32_at(96) as usize;
+    // (max x, min x, max y, min y, max z, min z)
+    let bbox: Vec<String> = (0..6)
+        .map(|i| format!("{:.3}", f64_at(179 + i * 8)))
+        .collect();
+    format!(
+        "{} points\nbbox {}\npoints {}\n",
+        u32_at(107),
+        bbox.join(" "),
+        sha256(&bytes[offset_to_points..])
+    )
+}
+
+/// The header (text) as it is, and the hash of the body
+fn ply_summary(bytes: &[u8]) -> String {
+    const END_HEADER: &[u8] = b"end_header\n";
+    let header_end = bytes
+        .windows(END_HEADER.len())
+        .position(|window| window == END_HEADER)
+        .unwrap()
+        + END_HEADER.len();
+    let header = String::from_utf8_lossy(&bytes[..header_end]);
+    let body = &bytes[header_end..];
+    format!("{header}body {} bytes {}\n", body.len(), sha256(body))
+}
+
+/// The record count and the hash without the date of the last update
+fn dbf_summary(mut bytes: Vec<u8>) -> String {
+    let count = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    bytes[1..4].fill(0);
+    format!("{count} records {}\n", sha256(&bytes))
+}
@@ -0,0 +1,244 @@
+//! The sinks not covered here:
+//!
+//! - `roadnetwork`: the CityGML parser does not read curves (`tran:lod0Network`) yet, so there are no centerlines.
+//! - `minecraft`, `serde` and `noop`: not the conversion outputs for the users (and the Minecraft worlds are too large).
+
+mod common;
+
+use common::{assert_golden, run_conversion, FeatureKind, SyntheticDataset};
+use nusamai::sink::{self, DataSinkProvider};
+
+/// Converts the default synthetic dataset (buildings of all the LODs, roads and city furniture)
+fn convert<S: DataSinkProvider>(sink_provider: S, name: &str, output_name: &str) {
+    let dataset = SyntheticDataset::new().kinds(&[
+        FeatureKind::Building,
+        FeatureKind::Road,
+        FeatureKind::CityFurniture,
+    ]);
+    convert_dataset(sink_provider, dataset, name, output_name, &[]);
+}
+
+/// Converts the dataset into `output/{output_name}`, and compares the whole `output` directory with the golden file
+/// (to include the files written next to the output file, e.g. the world file of a PNG)
+fn convert_dataset<S: DataSinkProvider>(
+    sink_provider: S,
+    dataset: SyntheticDataset,
+    name: &str,
+    output_name: &str,
+    sink_options: &[(&str, &str)],
+) {
+    let dir = tempfile::tempdir().unwrap();
+    let filenames = dataset.write(&dir.path().join("input"));
+    let output_dir = dir.path().join("output");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    run_conversion(
+        sink_provider,
+        &filenames,
+        &output_dir.join(output_name),
+        sink_options,
+    );
+    assert_golden(name, &output_dir);
+}
+
+#[test]
+fn synthetic_dataset_document() {
+    let dataset = SyntheticDataset::new()
+        .count(2)
+        .textures(true)
+        .missing_ids(true);
+    let doc = dataset.document(FeatureKind::Building, "53394525_bldg_6697_op");
+    assert!(doc.contains(r#"<bldg:Building gml:id="bldg_0">"#));
+    // the last feature has no id
+    assert!(!doc.contains(r#"gml:id="bldg_1""#));
+    assert_eq!(doc.matches("<bldg:Building").count(), 2);
+    assert!(doc.contains("<bldg:lod0FootPrint>"));
+    assert!(doc.contains("<bldg:lod1Solid>"));
+    assert_eq!(doc.matches("<bldg:RoofSurface>").count(), 2);
+    assert!(doc.contains("53394525_bldg_6697_op_appearance/roof_1.png"));
+
+    let doc = SyntheticDataset::new()
+        .lods(&[1])
+        .document(FeatureKind::Building, "53394525_bldg_6697_op");
+    assert!(!doc.contains("<bldg:lod0FootPrint>"));
+    assert!(!doc.contains("<bldg:boundedBy>"));
+}
+
+#[test]
+fn e2e_geojson() {
+    convert(sink::geojson::GeoJsonSinkProvider {}, "geojson", "geojson");
+}
+
+#[test]
+fn e2e_csv() {
+    convert(sink::csv::CsvSinkProvider {}, "csv", "csv");
+}
+
+#[test]
+fn e2e_cityjson() {
+    convert(
+        sink::cityjson::CityJsonSinkProvider {},
+        "cityjson",
+        "city.json",
+    );
+}
+
+#[test]
+fn e2e_czml() {
+    convert(sink::czml::CzmlSinkProvider {}, "czml", "city.czml");
+}
+
+#[test]
+fn e2e_kml() {
+    convert(sink::kml::KmlSinkProvider {}, "kml", "kml");
+}
+
+#[test]
+fn e2e_citygml() {
+    convert(sink::citygml::CityGmlSinkProvider {}, "citygml", "city.gml");
+}
+
+#[test]
+fn e2e_gpkg() {
+    convert(sink::gpkg::GpkgSinkProvider {}, "gpkg", "city.gpkg");
+}
+
+#[test]
+fn e2e_shapefile() {
+    convert(
+        sink::shapefile::ShapefileSinkProvider {},
+        "shapefile",
+        "shapefile",
+    );
+}
+
+#[test]
+fn e2e_parquet() {
+    convert(
+        sink::parquet::GeoParquetSinkProvider {},
+        "parquet",
+        "parquet",
+    );
+}
+
+#[test]
+fn e2e_mvt() {
+    convert(sink::mvt::MvtSinkProvider {}, "mvt", "mvt");
+}
+
+#[test]
+fn e2e_gltf() {
+    convert(sink::gltf::GltfSinkProvider {}, "gltf", "gltf");
+}
+
+#[test]
+fn e2e_obj() {
+    convert(sink::obj::ObjSinkProvider {}, "obj", "obj");
+}
+
+#[test]
+fn e2e_3dtiles() {
+    convert(
+        sink::cesiumtiles::CesiumTilesSinkProvider {},
+        "3dtiles",
+        "3dtiles",
+    );
+}
+
+#[test]
+fn e2e_i3s() {
+    convert(sink::i3s::I3sSinkProvider {}, "i3s", "city.slpk");
+}
+
+#[test]
+fn e2e_dxf() {
+    convert(sink::dxf::DxfSinkProvider {}, "dxf", "city.dxf");
+}
+
+#[test]
+fn e2e_fbx() {
+    convert(sink::fbx::FbxSinkProvider {}, "fbx", "city.fbx");
+}
+
+#[test]
+fn e2e_ply() {
+    convert(sink::ply::StanfordPlySinkProvider {}, "ply", "city.ply");
+}
+
+#[test]
+fn e2e_las() {
+    convert(sink::las::LasSinkProvider {}, "las", "city.las");
+}
+
+#[test]
+fn e2e_duckdb() {
+    convert(sink::duckdb::DuckDbSinkProvider {}, "duckdb", "city.duckdb");
+}
+
+#[test]
+fn e2e_shadow() {
+    convert_dataset(
+        sink::shadow::ShadowSinkProvider {},
+        SyntheticDataset::new(),
+        "shadow",
+        "shadow.png",
+        &[
+            ("datetime", "2024-12-21T12:00:00+09:00"),
+            ("resolution", "2"),
+        ],
+    );
+}
+
+#[test]
+fn e2e_terrain_rgb() {
+    convert_dataset(
+        sink::terrain::TerrainSinkProvider {},
+        SyntheticDataset::new().kinds(&[FeatureKind::Relief]),
+        "terrain_rgb",
+        "terrain",
+        &[("min_z", "14"), ("max_z", "15")],
+    );
+}
+
+#[test]
+fn e2e_quantized_mesh() {
+    convert_dataset(
+        sink::terrain::QuantizedMeshSinkProvider {},
+        SyntheticDataset::new().kinds(&[FeatureKind::Relief]),
+        "quantized_mesh",
+        "terrain",
+        &[("max_z", "15")],
+    );
+}
+
+#[test]
+fn e2e_3dtiles_textures() {
+    let dir = tempfile::tempdir().unwrap();
+    let filenames = SyntheticDataset::new()
+        .lods(&[2])
+        .textures(true)
+        .write(&dir.path().join("input"));
+    let output = dir.path().join("3dtiles");
+    run_conversion(
+        sink::cesiumtiles::CesiumTilesSinkProvider {},
+        &filenames,
+        &output,
+        &[],
+    );
+    assert_golden("3dtiles_textures", &output);
+}
+
+#[test]
+fn e2e_missing_ids() {
+    let dir = tempfile::tempdir().unwrap();
+    let filenames = SyntheticDataset::new()
+        .missing_ids(true)
+        .write(&dir.path().join("input"));
+    let output = dir.path().join("geojson");
+    run_conversion(
+        sink::geojson::GeoJsonSinkProvider {},
+        &filenames,
+        &output,
+        &[],
+    );
+    assert_golden("geojson_missing_ids", &output);
+}
@@ -105,6 +105,11 @@ fn run_geojson_sink() {
     );
 }
 
+#[test]
+fn run_cityjson_sink() {
+    simple_run_sink(sink::cityjson::CityJsonSinkProvider {}, "/dev/null".into());
+}
+
 #[test]
 fn run_gpkg_sink() {
     simple_run_sink(sink::gpkg::GpkgSinkProvider {}, "sqlite::memory:".into());
@@ -115,6 +120,38 @@ fn run_mvt_sink() {
     simple_run_sink(sink::mvt::MvtSinkProvider {}, "/tmp/nusamai/mvt/".into());
 }
 
+#[test]
+fn run_mvt_pmtiles_sink() {
+    simple_run_sink(
+        sink::mvt::MvtSinkProvider {},
+        "/tmp/nusamai/mvt.pmtiles".into(),
+    );
+}
+
+#[test]
+fn run_mvt_mbtiles_sink() {
+    simple_run_sink(
+        sink::mvt::MvtSinkProvider {},
+        "/tmp/nusamai/mvt.mbtiles".into(),
+    );
+}
+
+#[test]
+fn run_terrain_sink() {
+    simple_run_sink(
+        sink::terrain::TerrainSinkProvider {},
+        "/tmp/nusamai/terrain/".into(),
+    );
+}
+
+#[test]
+fn run_quantizedmesh_sink() {
+    simple_run_sink(
+        sink::terrain::QuantizedMeshSinkProvider {},
+        "/tmp/nusamai/quantizedmesh/".into(),
+    );
+}
+
 #[test]
 fn run_shapefile_sink() {
     simple_run_sink(
@@ -136,6 +173,83 @@ fn run_3dtiles_sink() {
     );
 }
 
+#[test]
+fn run_parquet_sink() {
+    simple_run_sink(
+        sink::parquet::GeoParquetSinkProvider {},
+        "/tmp/nusamai/parquet".into(),
+    );
+}
+
+#[test]
+fn run_csv_sink() {
+    simple_run_sink(sink::csv::CsvSinkProvider {}, "/tmp/nusamai/csv".into());
+}
+
+#[test]
+fn run_shadow_sink() {
+    simple_run_sink(
+        sink::shadow::ShadowSinkProvider {},
+        "/tmp/nusamai/shadow.png".into(),
+    );
+}
+
+#[test]
+fn run_i3s_sink() {
+    simple_run_sink(
+        sink::i3s::I3sSinkProvider {},
+        "/tmp/nusamai/i3s.slpk".into(),
+    );
+}
+
+#[test]
+fn run_las_sink() {
+    simple_run_sink(
+        sink::las::LasSinkProvider {},
+        "/tmp/nusamai/points.laz".into(),
+    );
+}
+
+#[test]
+fn run_fbx_sink() {
+    simple_run_sink(
+        sink::fbx::FbxSinkProvider {},
+        "/tmp/nusamai/city.fbx".into(),
+    );
+}
+
+#[test]
+fn run_dxf_sink() {
+    simple_run_sink(
+        sink::dxf::DxfSinkProvider {},
+        "/tmp/nusamai/city.dxf".into(),
+    );
+}
+
+#[test]
+fn run_citygml_sink() {
+    simple_run_sink(
+        sink::citygml::CityGmlSinkProvider {},
+        "/tmp/nusamai/city.gml".into(),
+    );
+}
+
+#[test]
+fn run_duckdb_sink() {
+    simple_run_sink(
+        sink::duckdb::DuckDbSinkProvider {},
+        "/tmp/nusamai/city.duckdb".into(),
+    );
+}
+
+#[test]
+fn run_roadnetwork_sink() {
+    simple_run_sink(
+        sink::roadnetwork::RoadNetworkSinkProvider {},
+        "/tmp/nusamai/roadnetwork.gpkg".into(),
+    );
+}
+
 #[test]
 fn run_kml_sink() {
     simple_run_sink(sink::kml::KmlSinkProvider {}, "/tmp/nusamai/kml".into());